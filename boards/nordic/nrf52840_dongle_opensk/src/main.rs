@@ -45,14 +45,10 @@ pub mod io;
 
 const VENDOR_ID: u16 = 0x1915; // Nordic Semiconductor
 const PRODUCT_ID: u16 = 0x521f; // nRF52840 Dongle (PCA10059)
-static STRINGS: &'static [&'static str] = &[
-    // Manufacturer
-    "Nordic Semiconductor ASA",
-    // Product
-    "OpenSK",
-    // Serial number
-    "v1.0",
-];
+
+// USB strings. The serial number is the unique identifier of the chip.
+const MANUFACTURER: &'static str = "Nordic Semiconductor ASA";
+const PRODUCT: &'static str = "OpenSK";
 
 // State for loading and holding applications.
 // How should the kernel respond when a process faults.
@@ -337,13 +333,17 @@ pub unsafe fn reset_handler() {
     );
 
     // Configure USB controller
+    let serial_number =
+        nrf52840::ficr::FICR_INSTANCE.device_id_str(static_init!([u8; 16], [0; 16]));
+    let strings = static_init!([&'static str; 3], [MANUFACTURER, PRODUCT, serial_number]);
     let usb = components::usb_ctap::UsbCtapComponent::new(
         board_kernel,
         &nrf52840_peripherals.usbd,
         capsules::usb::usbc_client::MAX_CTRL_PACKET_SIZE_NRF52840,
+        capsules::usb::usbc_ctap_hid::DEFAULT_INTERRUPT_INTERVAL,
         VENDOR_ID,
         PRODUCT_ID,
-        STRINGS,
+        strings,
     )
     .finalize(components::usb_ctap_component_buf!(nrf52840::usbd::Usbd));
 
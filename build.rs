@@ -18,13 +18,16 @@ use openssl::{bn, ec, nid};
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
+use std::process::Command;
 use std::{env, fs};
 use uuid::Uuid;
 
 fn main() {
     const UPGRADE_FILE: &str = "crypto_data/opensk_upgrade_pub.pem";
+    const STORE_SECRET_FILE: &str = "crypto_data/opensk_store_secret.bin";
     println!("cargo:rerun-if-changed=crypto_data/aaguid.txt");
     println!("cargo:rerun-if-changed={}", UPGRADE_FILE);
+    println!("cargo:rerun-if-changed={}", STORE_SECRET_FILE);
     println!("cargo:rerun-if-changed=layout.ld");
     println!("cargo:rerun-if-changed=nrf52840_layout.ld");
     println!("cargo:rerun-if-changed=nrf52840_layout_a.ld");
@@ -54,4 +57,62 @@ fn main() {
     let upgrade_pubkey_path = Path::new(&out_dir).join("opensk_upgrade_pubkey.bin");
     let mut upgrade_pub_bin_file = File::create(&upgrade_pubkey_path).unwrap();
     upgrade_pub_bin_file.write_all(&raw_bytes).unwrap();
+
+    // Bake the firmware version into the image: the package version, followed by the git commit,
+    // or zeros when not building from a checkout.
+    rerun_if_git_head_changed();
+    let mut version_bytes = Vec::new();
+    for part in ["MAJOR", "MINOR", "PATCH"].iter() {
+        let number: u16 = env::var(format!("CARGO_PKG_VERSION_{}", part))
+            .unwrap()
+            .parse()
+            .unwrap();
+        version_bytes.extend_from_slice(&number.to_be_bytes());
+    }
+    version_bytes.extend_from_slice(&git_hash().unwrap_or([0; 20]));
+    let version_path = Path::new(&out_dir).join("opensk_firmware_version.bin");
+    let mut version_bin_file = File::create(&version_path).unwrap();
+    version_bin_file.write_all(&version_bytes).unwrap();
+
+    // The store encryption secret must stay the same across firmware updates, or the encrypted
+    // entries of the store can't be read anymore.
+    let store_secret = fs::read(STORE_SECRET_FILE).unwrap();
+    assert_eq!(store_secret.len(), 32);
+    let store_secret_path = Path::new(&out_dir).join("opensk_store_secret.bin");
+    let mut store_secret_bin_file = File::create(&store_secret_path).unwrap();
+    store_secret_bin_file.write_all(&store_secret).unwrap();
+}
+
+/// Reruns when HEAD moves, either to another branch or to another commit on the same branch.
+fn rerun_if_git_head_changed() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    let head = fs::read_to_string(".git/HEAD").unwrap_or_default();
+    let mut refs = vec![String::from(".git/packed-refs")];
+    if let Some(reference) = head.trim().strip_prefix("ref: ") {
+        refs.push(format!(".git/{}", reference));
+    }
+    // Cargo always reruns for missing paths, and a branch may only be in the packed refs.
+    for path in refs.iter().filter(|path| Path::new(path).exists()) {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+}
+
+fn git_hash() -> Option<[u8; 20]> {
+    let output = Command::new("git")
+        .args(&["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let hex = String::from_utf8(output.stdout).ok()?;
+    let hex = hex.trim();
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut hash = [0; 20];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(hash)
 }
@@ -753,6 +753,7 @@ class OpenSKInstaller:
         argparse.Namespace(
             batch=False,
             certificate=self.args.config_cert,
+            intermediate_certificates=[],
             priv_key=self.args.config_pkey,
             lock=self.args.lock_device,
             use_vendor_hid="vendor_hid" in self.args.features,
@@ -1123,6 +1124,16 @@ if __name__ == "__main__":
             "of variable-time arithmetic for computations over secret key."),
   )
 
+  main_parser.add_argument(
+      "--rng-health-check",
+      action="append_const",
+      const="rng_health_check",
+      dest="features",
+      help=("Health tests the hardware entropy source as specified in "
+            "NIST SP 800-90B and uses it to seed an HMAC_DRBG, instead of "
+            "using the raw output of the Tock rng driver."),
+  )
+
   main_parser.add_argument(
       "--disable-check-patches",
       action="store_false",
@@ -1175,6 +1186,14 @@ if __name__ == "__main__":
       const="crypto_bench",
       help=("Compiles and installs the crypto_bench example that benchmarks "
             "the performance of the cryptographic algorithms on the board."))
+  apps_group.add_argument(
+      "--aes_cross_check",
+      dest="application",
+      action="store_const",
+      const="aes_cross_check",
+      help=("Compiles and installs the aes_cross_check example that checks "
+            "that the AES hardware engine agrees with the software "
+            "implementation."))
   apps_group.add_argument(
       "--measure_stack",
       dest="application",
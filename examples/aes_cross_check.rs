@@ -0,0 +1,76 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_std]
+
+extern crate alloc;
+extern crate lang_items;
+
+use core::fmt::Write;
+use ctap2::api::aes::{Aes256, SoftwareAes256, BLOCK_SIZE};
+use ctap2::env::tock::{HardwareEngine, TockAes256};
+use libtock_drivers::aes;
+use libtock_drivers::console::Console;
+
+libtock_core::stack_size! {0x1000}
+
+/// Checks that the AES hardware engine and the software implementation agree.
+fn main() {
+    let mut console = Console::new();
+    if aes::is_available().is_err() {
+        writeln!(console, "No AES hardware engine.").unwrap();
+        return;
+    }
+
+    let key = [0xC2; 32];
+    let iv = [0x5A; BLOCK_SIZE];
+    let mut software = SoftwareAes256;
+    let mut hardware = TockAes256::new(HardwareEngine::new());
+    let mut failures = 0;
+    for num_blocks in 0..=aes::MAX_LENGTH / BLOCK_SIZE + 1 {
+        let mut plaintext = [0; aes::MAX_LENGTH + BLOCK_SIZE];
+        for (i, byte) in plaintext.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let plaintext = &plaintext[..num_blocks * BLOCK_SIZE];
+
+        // Encrypted by hardware, decrypted by software.
+        let mut buffer = [0; aes::MAX_LENGTH + BLOCK_SIZE];
+        let buffer = &mut buffer[..plaintext.len()];
+        buffer.copy_from_slice(plaintext);
+        hardware.cbc_encrypt(&key, &iv, buffer);
+        let mut expected = [0; aes::MAX_LENGTH + BLOCK_SIZE];
+        let expected = &mut expected[..plaintext.len()];
+        expected.copy_from_slice(plaintext);
+        software.cbc_encrypt(&key, &iv, expected);
+        let same_ciphertext = buffer == expected;
+        software.cbc_decrypt(&key, &iv, buffer);
+        let software_decrypts = buffer == plaintext;
+
+        // Encrypted by software, decrypted by hardware.
+        hardware.cbc_decrypt(&key, &iv, expected);
+        let hardware_decrypts = expected == plaintext;
+
+        let passed = same_ciphertext && software_decrypts && hardware_decrypts;
+        failures += !passed as usize;
+        writeln!(
+            console,
+            "{} blocks: {}",
+            num_blocks,
+            if passed { "passed" } else { "FAILED" }
+        )
+        .unwrap();
+    }
+    writeln!(console, "AES cross-check done with {} failures.", failures).unwrap();
+}
@@ -0,0 +1,104 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::util::Block16;
+use crate::aes256::EncryptionKey;
+use byteorder::{BigEndian, ByteOrder};
+
+/// Encrypts or decrypts a byte slice in counter mode.
+///
+/// The nonce is the first counter block. Its last 4 bytes are incremented as a big-endian
+/// integer (modulo 2^32) for each following block. The byte slice can have any length, a
+/// trailing partial block only consumes the first bytes of its keystream block.
+///
+/// Since counter mode is its own inverse, this function is also used for decryption.
+pub fn ctr_encrypt(key: &EncryptionKey, mut nonce: Block16, bytes: &mut [u8]) {
+    for block in bytes.chunks_mut(16) {
+        let mut keystream = nonce;
+        key.encrypt_block(&mut keystream);
+        for (byte, key_byte) in block.iter_mut().zip(keystream.iter()) {
+            *byte ^= key_byte;
+        }
+        let counter = BigEndian::read_u32(&nonce[12..]);
+        BigEndian::write_u32(&mut nonce[12..], counter.wrapping_add(1));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::aes256;
+    use super::*;
+    use arrayref::array_ref;
+    use rng256::{Rng256, ThreadRng256};
+
+    fn test_key() -> aes256::EncryptionKey {
+        aes256::EncryptionKey::new(&[
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b,
+            0x1c, 0x1d, 0x1e, 0x1f,
+        ])
+    }
+
+    fn reference_keystream(key: &aes256::EncryptionKey, nonce: Block16, len: usize) -> Vec<u8> {
+        let mut keystream = Vec::new();
+        let mut counter = BigEndian::read_u32(&nonce[12..]);
+        while keystream.len() < len {
+            let mut block = nonce;
+            BigEndian::write_u32(&mut block[12..], counter);
+            key.encrypt_block(&mut block);
+            keystream.extend_from_slice(&block);
+            counter = counter.wrapping_add(1);
+        }
+        keystream.truncate(len);
+        keystream
+    }
+
+    #[test]
+    fn test_ctr_encrypt_decrypt() {
+        let mut rng = ThreadRng256 {};
+        let key = test_key();
+        for len in 0..100 {
+            let random = rng.gen_uniform_u8x32();
+            let nonce = *array_ref!(random, 0, 16);
+            let plaintext: Vec<u8> = (0..len).map(|_| rng.gen_uniform_u8x32()[0]).collect();
+            let mut buffer = plaintext.clone();
+            ctr_encrypt(&key, nonce, &mut buffer);
+            let keystream = reference_keystream(&key, nonce, len);
+            let expected: Vec<u8> = plaintext
+                .iter()
+                .zip(keystream.iter())
+                .map(|(p, k)| p ^ k)
+                .collect();
+            assert_eq!(buffer, expected);
+            ctr_encrypt(&key, nonce, &mut buffer);
+            assert_eq!(buffer, plaintext);
+        }
+    }
+
+    #[test]
+    fn test_ctr_counter_wraps() {
+        let key = test_key();
+        let mut nonce = [0x42; 16];
+        nonce[12..].copy_from_slice(&[0xFF; 4]);
+        let mut buffer = [0x00; 40];
+        ctr_encrypt(&key, nonce, &mut buffer);
+        assert_eq!(buffer.to_vec(), reference_keystream(&key, nonce, 40));
+
+        let mut wrapped_nonce = [0x42; 16];
+        wrapped_nonce[12..].copy_from_slice(&[0x00; 4]);
+        let mut second_block = [0x00; 16];
+        ctr_encrypt(&key, wrapped_nonce, &mut second_block);
+        assert_eq!(&buffer[16..32], &second_block);
+    }
+}
@@ -36,7 +36,7 @@ impl PointP256 {
     // Although this point is not "valid" on the curve (as it doesn't have an order of N), it is
     // useful for tests.
     #[cfg(test)]
-    const INFINITY: PointP256 = PointP256 {
+    pub const INFINITY: PointP256 = PointP256 {
         x: GFP256::ZERO,
         y: GFP256::ZERO,
     };
@@ -92,7 +92,6 @@ impl PointP256 {
     }
 
     /** Useful getters **/
-    #[cfg(test)]
     pub fn is_valid_vartime(&self) -> bool {
         GFP256::is_valid_point_vartime(&self.x, &self.y)
     }
@@ -16,10 +16,18 @@ use super::ec::exponent256::NonZeroExponentP256;
 use super::ec::int256;
 use super::ec::int256::Int256;
 use super::ec::point::PointP256;
+#[cfg(test)]
+use super::util::ToOption;
 use rng256::Rng256;
 
 pub const NBYTES: usize = int256::NBYTES;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EcdhError {
+    /// The public key or the exchanged point is not a valid point on the curve.
+    InvalidPoint,
+}
+
 pub struct SecKey {
     a: NonZeroExponentP256,
 }
@@ -60,6 +68,13 @@ impl SecKey {
         // - https://www.secg.org/sec1-v2.pdf
     }
 
+    #[cfg(test)]
+    fn from_bytes(bytes: &[u8; NBYTES]) -> Option<SecKey> {
+        NonZeroExponentP256::from_int_checked(Int256::from_bin(bytes))
+            .to_option()
+            .map(|a| SecKey { a })
+    }
+
     /// Performs the handshake using the Diffie Hellman key agreement.
     ///
     /// This function generates the Z in the PIN protocol v1 specification.
@@ -72,6 +87,24 @@ impl SecKey {
     }
 }
 
+/// Computes the ECDH shared secret, i.e. the x-coordinate of the exchanged point.
+///
+/// Both the peer's public key and the resulting point are checked to be valid points on the curve,
+/// which excludes the point at infinity. These checks only depend on public values. The scalar
+/// multiplication itself runs in constant time with respect to the private key.
+pub fn shared_secret(our_private: &SecKey, their_public: &PubKey) -> Result<[u8; 32], EcdhError> {
+    if !their_public.p.is_valid_vartime() {
+        return Err(EcdhError::InvalidPoint);
+    }
+    let p = our_private.exchange_raw(their_public);
+    if !p.is_valid_vartime() {
+        return Err(EcdhError::InvalidPoint);
+    }
+    let mut x = [0; 32];
+    p.getx().to_int().to_bin(&mut x);
+    Ok(x)
+}
+
 impl PubKey {
     #[cfg(test)]
     fn from_bytes_uncompressed(bytes: &[u8]) -> Option<PubKey> {
@@ -152,5 +185,81 @@ mod test {
         }
     }
 
-    // TODO: tests with invalid public shares.
+    #[test]
+    fn test_shared_secret_rfc5903() {
+        // Test vector from RFC 5903, section 8.1.
+        let sk_i = SecKey::from_bytes(&array_from_hex(
+            "C88F01F510D9AC3F70A292DAA2316DE544E9AAB8AFE84049C62A9C57862D1433",
+        ))
+        .unwrap();
+        let sk_r = SecKey::from_bytes(&array_from_hex(
+            "C6EF9C5D78AE012A011164ACB397CE2088685D8F06BF9BE0B283AB46476BEE53",
+        ))
+        .unwrap();
+        let pk_i = pub_key_from_hex(
+            "DAD0B65394221CF9B051E1FECA5787D098DFE637FC90B9EF945D0C3772581180",
+            "5271A0461CDB8252D61F1C456FA3E59AB1F45B33ACCF5F58389E0577B8990BB3",
+        );
+        let pk_r = pub_key_from_hex(
+            "D12DFB5289C8D4F81208B70270398C342296970A0BCCB74C736FC7554494BF63",
+            "56FBF3CA366CC23E8157854C13C58D6AAC23F046ADA30F8353E74F33039872AB",
+        );
+        assert_eq!(sk_i.genpk(), pk_i);
+        assert_eq!(sk_r.genpk(), pk_r);
+
+        let expected =
+            hex::decode("D6840F6B42F6EDAFD13116E0E12565202FEF8E9ECE7DCE03812464D04B9442DE")
+                .unwrap();
+        assert_eq!(shared_secret(&sk_i, &pk_r).unwrap().to_vec(), expected);
+        assert_eq!(shared_secret(&sk_r, &pk_i).unwrap().to_vec(), expected);
+    }
+
+    #[test]
+    fn test_shared_secret_cavp() {
+        // First P-256 test vector from the NIST CAVP ECC CDH primitive tests.
+        let sk = SecKey::from_bytes(&array_from_hex(
+            "7d7dc5f71eb29ddaf80d6214632eeae03d9058af1fb6d22ed80badb62bc1a534",
+        ))
+        .unwrap();
+        let pk = pub_key_from_hex(
+            "700c48f77f56584c5cc632ca65640db91b6bacce3a4df6b42ce7cc838833d287",
+            "db71e509e3fd9b060ddb20ba5c51dcc5948d46fbf640dfe0441782cab85fa4ac",
+        );
+        assert_eq!(
+            shared_secret(&sk, &pk).unwrap().to_vec(),
+            hex::decode("46fc62106420ff012e54a434fbdd2d25ccc5852060561e68040dd7778997bd7b")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_shared_secret_matches_exchange_x() {
+        let mut rng = ThreadRng256 {};
+        let sk_a = SecKey::gensk(&mut rng);
+        let sk_b = SecKey::gensk(&mut rng);
+        assert_eq!(
+            shared_secret(&sk_a, &sk_b.genpk()),
+            Ok(sk_a.exchange_x(&sk_b.genpk()))
+        );
+    }
+
+    #[test]
+    fn test_shared_secret_invalid_point() {
+        let mut rng = ThreadRng256 {};
+        let sk = SecKey::gensk(&mut rng);
+        let mut pk = sk.genpk();
+        // The point at infinity is not on the curve.
+        pk.p = PointP256::INFINITY;
+        assert_eq!(shared_secret(&sk, &pk), Err(EcdhError::InvalidPoint));
+    }
+
+    fn array_from_hex(hex: &str) -> [u8; NBYTES] {
+        let mut bytes = [0; NBYTES];
+        bytes.copy_from_slice(&hex::decode(hex).unwrap());
+        bytes
+    }
+
+    fn pub_key_from_hex(x: &str, y: &str) -> PubKey {
+        PubKey::from_coordinates(&array_from_hex(x), &array_from_hex(y)).unwrap()
+    }
 }
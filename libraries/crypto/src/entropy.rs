@@ -0,0 +1,305 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Health tested entropy conditioning.
+//!
+//! Raw samples from an entropy source go through the continuous health tests of NIST SP 800-90B
+//! (section 4.4) before seeding an HMAC_DRBG with SHA-256, as specified in NIST SP 800-90A.
+
+use super::hmac::hmac_256;
+use super::sha256::Sha256;
+use alloc::vec::Vec;
+use rng256::Rng256;
+
+/// Claimed min-entropy of the source, in bits per byte sample.
+///
+/// This is a conservative claim. All cutoffs below derive from it, with a false positive
+/// probability of 2^-20 per test.
+const MIN_ENTROPY_PER_SAMPLE: usize = 1;
+
+/// Number of identical consecutive samples that fails the repetition count test.
+const REPETITION_COUNT_CUTOFF: usize = 1 + 20 / MIN_ENTROPY_PER_SAMPLE;
+
+/// Window size of the adaptive proportion test for non-binary samples.
+const ADAPTIVE_PROPORTION_WINDOW: usize = 512;
+
+/// Occurrences of the first sample in a window that fails the adaptive proportion test.
+const ADAPTIVE_PROPORTION_CUTOFF: usize = 311;
+
+/// Number of samples tested and discarded before the first seed.
+const STARTUP_SAMPLES: usize = 1024;
+
+/// Number of samples used to seed the DRBG.
+///
+/// This is 256 bits of entropy plus a 128 bits nonce at the claimed min-entropy.
+const SEED_SAMPLES: usize = 384 / MIN_ENTROPY_PER_SAMPLE;
+
+/// Number of generate calls before the DRBG is reseeded.
+const RESEED_INTERVAL: usize = 1024;
+
+const HASH_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthTestFailure {
+    /// The same sample was repeated too many times.
+    RepetitionCount,
+    /// A sample was too frequent in a window.
+    AdaptiveProportion,
+}
+
+/// Continuous health tests over a stream of samples.
+#[derive(Default)]
+pub struct HealthTests {
+    repetition_sample: Option<u8>,
+    repetition_count: usize,
+    proportion_sample: u8,
+    proportion_count: usize,
+    proportion_index: usize,
+}
+
+impl HealthTests {
+    pub fn new() -> HealthTests {
+        HealthTests::default()
+    }
+
+    /// Runs both tests on the next sample.
+    ///
+    /// After a failure, the tests should be restarted with fresh state.
+    pub fn check(&mut self, sample: u8) -> Result<(), HealthTestFailure> {
+        if self.repetition_sample == Some(sample) {
+            self.repetition_count += 1;
+            if self.repetition_count >= REPETITION_COUNT_CUTOFF {
+                return Err(HealthTestFailure::RepetitionCount);
+            }
+        } else {
+            self.repetition_sample = Some(sample);
+            self.repetition_count = 1;
+        }
+
+        if self.proportion_index == 0 {
+            self.proportion_sample = sample;
+            self.proportion_count = 1;
+        } else if self.proportion_sample == sample {
+            self.proportion_count += 1;
+            if self.proportion_count >= ADAPTIVE_PROPORTION_CUTOFF {
+                return Err(HealthTestFailure::AdaptiveProportion);
+            }
+        }
+        self.proportion_index = (self.proportion_index + 1) % ADAPTIVE_PROPORTION_WINDOW;
+        Ok(())
+    }
+}
+
+/// HMAC_DRBG seeded from a health tested entropy source.
+///
+/// Bytes are only generated from seeds that passed the health tests. If a test fails, the seed is
+/// discarded and the source is read again, which blocks for as long as the source is unhealthy.
+pub struct HealthCheckedRng256<R: Rng256> {
+    source: R,
+    tests: HealthTests,
+    started: bool,
+    key: [u8; HASH_SIZE],
+    value: [u8; HASH_SIZE],
+    reseed_counter: usize,
+}
+
+impl<R: Rng256> HealthCheckedRng256<R> {
+    /// Wraps a raw entropy source. It is only read when bytes are first requested.
+    pub fn new(source: R) -> Self {
+        HealthCheckedRng256 {
+            source,
+            tests: HealthTests::new(),
+            started: false,
+            key: [0x00; HASH_SIZE],
+            value: [0x01; HASH_SIZE],
+            reseed_counter: RESEED_INTERVAL,
+        }
+    }
+
+    /// Reseeds the DRBG if the entropy source passes the health tests.
+    ///
+    /// The first call also runs the start-up tests.
+    pub fn try_reseed(&mut self) -> Result<(), HealthTestFailure> {
+        if !self.started {
+            let mut samples = [0; 64];
+            for _ in 0..STARTUP_SAMPLES / samples.len() {
+                self.collect(&mut samples)?;
+            }
+            self.started = true;
+        }
+        let mut seed = [0; SEED_SAMPLES];
+        self.collect(&mut seed)?;
+        self.update(&seed);
+        self.reseed_counter = 0;
+        Ok(())
+    }
+
+    /// Fills the buffer with health tested samples.
+    fn collect(&mut self, samples: &mut [u8]) -> Result<(), HealthTestFailure> {
+        self.source.fill_bytes(samples);
+        for &sample in samples.iter() {
+            if let Err(failure) = self.tests.check(sample) {
+                self.tests = HealthTests::new();
+                return Err(failure);
+            }
+        }
+        Ok(())
+    }
+
+    /// Implements the HMAC_DRBG update function.
+    fn update(&mut self, provided_data: &[u8]) {
+        let mut contents = Vec::with_capacity(HASH_SIZE + 1 + provided_data.len());
+        for round in 0..2 {
+            contents.clear();
+            contents.extend_from_slice(&self.value);
+            contents.push(round);
+            contents.extend_from_slice(provided_data);
+            self.key = hmac_256::<Sha256>(&self.key, &contents);
+            self.value = hmac_256::<Sha256>(&self.key, &self.value);
+            if provided_data.is_empty() {
+                break;
+            }
+        }
+    }
+
+    /// Implements the HMAC_DRBG generate function, reseeding if needed.
+    fn generate(&mut self, buf: &mut [u8]) {
+        if self.reseed_counter >= RESEED_INTERVAL {
+            while self.try_reseed().is_err() {}
+        }
+        for chunk in buf.chunks_mut(HASH_SIZE) {
+            self.value = hmac_256::<Sha256>(&self.key, &self.value);
+            chunk.copy_from_slice(&self.value[..chunk.len()]);
+        }
+        self.update(&[]);
+        self.reseed_counter += 1;
+    }
+}
+
+impl<R: Rng256> Rng256 for HealthCheckedRng256<R> {
+    fn fill_bytes(&mut self, buf: &mut [u8]) {
+        self.generate(buf);
+    }
+
+    fn gen_uniform_u8x32(&mut self) -> [u8; 32] {
+        let mut result = [0; 32];
+        self.generate(&mut result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Entropy source that cycles through a fixed sequence of samples.
+    struct SequenceSource {
+        samples: Vec<u8>,
+        index: usize,
+    }
+
+    impl SequenceSource {
+        fn new(samples: Vec<u8>) -> Self {
+            SequenceSource { samples, index: 0 }
+        }
+    }
+
+    impl Rng256 for SequenceSource {
+        fn fill_bytes(&mut self, buf: &mut [u8]) {
+            for byte in buf.iter_mut() {
+                *byte = self.samples[self.index];
+                self.index = (self.index + 1) % self.samples.len();
+            }
+        }
+
+        fn gen_uniform_u8x32(&mut self) -> [u8; 32] {
+            let mut result = [0; 32];
+            self.fill_bytes(&mut result);
+            result
+        }
+    }
+
+    fn counting_samples() -> Vec<u8> {
+        (0..=255).collect()
+    }
+
+    #[test]
+    fn test_health_tests_accept_varied_samples() {
+        let mut tests = HealthTests::new();
+        for i in 0..10 * ADAPTIVE_PROPORTION_WINDOW {
+            assert_eq!(tests.check((i * 7) as u8), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_repetition_count_rejects_stuck_samples() {
+        let mut tests = HealthTests::new();
+        assert_eq!(tests.check(0x00), Ok(()));
+        for _ in 1..REPETITION_COUNT_CUTOFF {
+            assert_eq!(tests.check(0x42), Ok(()));
+        }
+        assert_eq!(tests.check(0x42), Err(HealthTestFailure::RepetitionCount));
+    }
+
+    #[test]
+    fn test_adaptive_proportion_rejects_biased_samples() {
+        // The sample 0x42 is 2/3 of the window, but never repeats more than twice.
+        let mut tests = HealthTests::new();
+        let mut result = Ok(());
+        for i in 0..ADAPTIVE_PROPORTION_WINDOW {
+            let sample = if i % 3 == 2 { i as u8 } else { 0x42 };
+            result = tests.check(sample);
+            if result.is_err() {
+                break;
+            }
+        }
+        assert_eq!(result, Err(HealthTestFailure::AdaptiveProportion));
+    }
+
+    #[test]
+    fn test_rng_rejects_stuck_source() {
+        let mut rng = HealthCheckedRng256::new(SequenceSource::new(vec![0x42]));
+        assert_eq!(rng.try_reseed(), Err(HealthTestFailure::RepetitionCount));
+        assert_eq!(rng.try_reseed(), Err(HealthTestFailure::RepetitionCount));
+        assert!(!rng.started);
+    }
+
+    #[test]
+    fn test_rng_generates_after_healthy_seed() {
+        let mut rng = HealthCheckedRng256::new(SequenceSource::new(counting_samples()));
+        let first = rng.gen_uniform_u8x32();
+        let second = rng.gen_uniform_u8x32();
+        assert_ne!(first, second);
+        assert!(rng.started);
+        assert_eq!(rng.reseed_counter, 2);
+
+        // The output is deterministic given the source.
+        let mut other_rng = HealthCheckedRng256::new(SequenceSource::new(counting_samples()));
+        assert_eq!(other_rng.gen_uniform_u8x32(), first);
+        let mut long_output = [0; 45];
+        other_rng.fill_bytes(&mut long_output);
+        assert_eq!(long_output[..32], second);
+    }
+
+    #[test]
+    fn test_rng_reseeds() {
+        let mut rng = HealthCheckedRng256::new(SequenceSource::new(counting_samples()));
+        for _ in 0..RESEED_INTERVAL {
+            rng.gen_uniform_u8x32();
+        }
+        assert_eq!(rng.reseed_counter, RESEED_INTERVAL);
+        rng.gen_uniform_u8x32();
+        assert_eq!(rng.reseed_counter, 1);
+    }
+}
@@ -12,10 +12,49 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::hmac::hmac_256;
+use super::hmac::{hmac_256, hmac_256_variable_key};
+use super::sha256::Sha256;
 use super::Hash256;
+use alloc::vec::Vec;
 
 const HASH_SIZE: usize = 32;
+/// The maximum output length of HKDF, as specified in RFC 5869.
+const MAX_OUTPUT_LENGTH: usize = 255 * HASH_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HkdfError {
+    /// The requested output is longer than 255 hash blocks.
+    OutputTooLong,
+}
+
+/// Computes the HKDF-SHA256 as specified in RFC 5869.
+///
+/// # Arguments
+///
+/// * `salt` - Optional salt value, an empty salt is a block of zeros
+/// * `ikm` - Input keying material
+/// * `info` - Optional context and application specific information
+/// * `okm` - Output keying material, its length is the requested output length
+///
+/// Returns an error if `okm` is longer than 255 * 32 bytes.
+pub fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8], okm: &mut [u8]) -> Result<(), HkdfError> {
+    if okm.len() > MAX_OUTPUT_LENGTH {
+        return Err(HkdfError::OutputTooLong);
+    }
+    // An empty salt is padded with zeros to the HMAC key length.
+    let prk = hmac_256_variable_key::<Sha256>(salt, ikm);
+    let mut t = Vec::with_capacity(HASH_SIZE + info.len() + 1);
+    for (i, chunk) in okm.chunks_mut(HASH_SIZE).enumerate() {
+        t.extend_from_slice(info);
+        // The output length check guarantees that the counter fits in a byte.
+        t.push(i as u8 + 1);
+        let block = hmac_256::<Sha256>(&prk, &t);
+        chunk.copy_from_slice(&block[..chunk.len()]);
+        t.clear();
+        t.extend_from_slice(&block);
+    }
+    Ok(())
+}
 
 /// Computes the HKDF with empty salt and 256 bit (one block) output.
 ///
@@ -44,6 +83,73 @@ mod test {
     use super::*;
     use arrayref::array_ref;
 
+    #[test]
+    fn test_hkdf_sha256_rfc5869_vectors() {
+        // Test case 1 from RFC 5869.
+        let ikm = [0x0b; 22];
+        let salt: Vec<u8> = (0x00..=0x0c).collect();
+        let info: Vec<u8> = (0xf0..=0xf9).collect();
+        let mut okm = [0; 42];
+        assert_eq!(hkdf_sha256(&salt, &ikm, &info, &mut okm), Ok(()));
+        assert_eq!(
+            okm.to_vec(),
+            hex::decode(
+                "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf\
+                 34007208d5b887185865"
+            )
+            .unwrap()
+        );
+
+        // Test case 2 from RFC 5869, with inputs longer than the block size.
+        let ikm: Vec<u8> = (0x00..=0x4f).collect();
+        let salt: Vec<u8> = (0x60..=0xaf).collect();
+        let info: Vec<u8> = (0xb0..=0xff).collect();
+        let mut okm = [0; 82];
+        assert_eq!(hkdf_sha256(&salt, &ikm, &info, &mut okm), Ok(()));
+        assert_eq!(
+            okm.to_vec(),
+            hex::decode(
+                "b11e398dc80327a1c8e7f78c596a49344f012eda2d4efad8a050cc4c19afa97c\
+                 59045a99cac7827271cb41c65e590e09da3275600c2f09b8367793a9aca3db71\
+                 cc30c58179ec3e87c14c01d5c1f3434f1d87"
+            )
+            .unwrap()
+        );
+
+        // Test case 3 from RFC 5869, with zero-length salt and info.
+        let ikm = [0x0b; 22];
+        let mut okm = [0; 42];
+        assert_eq!(hkdf_sha256(&[], &ikm, &[], &mut okm), Ok(()));
+        assert_eq!(
+            okm.to_vec(),
+            hex::decode(
+                "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d\
+                 9d201395faa4b61a96c8"
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_hkdf_sha256_output_length() {
+        let mut okm = [0; 0];
+        assert_eq!(hkdf_sha256(&[], b"ikm", b"info", &mut okm), Ok(()));
+        let mut okm = vec![0; MAX_OUTPUT_LENGTH];
+        assert_eq!(hkdf_sha256(&[], b"ikm", b"info", &mut okm), Ok(()));
+        let mut okm = vec![0; MAX_OUTPUT_LENGTH + 1];
+        assert_eq!(
+            hkdf_sha256(&[], b"ikm", b"info", &mut okm),
+            Err(HkdfError::OutputTooLong)
+        );
+    }
+
+    #[test]
+    fn test_hkdf_sha256_matches_empty_salt_256() {
+        let mut okm = [0; HASH_SIZE];
+        assert_eq!(hkdf_sha256(&[], b"ikm", b"info", &mut okm), Ok(()));
+        assert_eq!(okm, hkdf_empty_salt_256::<Sha256>(b"ikm", b"info"));
+    }
+
     #[test]
     fn test_hkdf_empty_salt_256_sha256_vectors() {
         // Test vectors generated by pycryptodome using:
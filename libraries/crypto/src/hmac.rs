@@ -12,9 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use super::util::ct_eq;
 use super::Hash256;
 use arrayref::array_ref;
-use subtle::ConstantTimeEq;
 
 const BLOCK_SIZE: usize = 64;
 const HASH_SIZE: usize = 32;
@@ -25,7 +25,7 @@ where
     H: Hash256,
 {
     let expected_mac = hmac_256::<H>(key, contents);
-    bool::from(expected_mac.ct_eq(mac))
+    ct_eq(&expected_mac, mac)
 }
 
 // FIDO2's PIN verification is just matching the first 16 bytes of the HMAC
@@ -39,7 +39,7 @@ where
     H: Hash256,
 {
     let expected_mac = hmac_256::<H>(key, contents);
-    bool::from(array_ref![expected_mac, 0, 16].ct_eq(pin))
+    ct_eq(array_ref![expected_mac, 0, 16], pin)
 }
 
 pub fn hmac_256<H>(key: &[u8; KEY_SIZE], contents: &[u8]) -> [u8; HASH_SIZE]
@@ -49,7 +49,31 @@ where
     H::hmac(key, contents)
 }
 
+/// Computes the HMAC for keys of any length.
+///
+/// Keys longer than the block size are hashed first, as specified in RFC 2104.
+pub fn hmac_256_variable_key<H>(key: &[u8], contents: &[u8]) -> [u8; HASH_SIZE]
+where
+    H: Hash256,
+{
+    if key.len() <= KEY_SIZE {
+        // Short keys are padded with zeros, so they can use the fixed size implementation.
+        let mut padded_key = [0; KEY_SIZE];
+        padded_key[..key.len()].copy_from_slice(key);
+        hmac_256::<H>(&padded_key, contents)
+    } else {
+        software_hmac::<H>(key, contents)
+    }
+}
+
 pub(crate) fn software_hmac_256<H>(key: &[u8; KEY_SIZE], contents: &[u8]) -> [u8; HASH_SIZE]
+where
+    H: Hash256,
+{
+    software_hmac::<H>(key, contents)
+}
+
+fn software_hmac<H>(key: &[u8], contents: &[u8]) -> [u8; HASH_SIZE]
 where
     H: Hash256,
 {
@@ -73,7 +97,7 @@ where
     ohasher.finalize()
 }
 
-fn xor_pads(ipad: &mut [u8; BLOCK_SIZE], opad: &mut [u8; BLOCK_SIZE], key: &[u8; KEY_SIZE]) {
+fn xor_pads(ipad: &mut [u8; BLOCK_SIZE], opad: &mut [u8; BLOCK_SIZE], key: &[u8]) {
     for (i, k) in key.iter().enumerate() {
         ipad[i] ^= k;
         opad[i] ^= k;
@@ -130,6 +154,38 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_hmac_256_variable_key() {
+        // Short keys are equivalent to their zero padded version.
+        let mut padded_key = [0; KEY_SIZE];
+        padded_key[..3].copy_from_slice(b"key");
+        assert_eq!(
+            hmac_256_variable_key::<Sha256>(b"key", b"contents"),
+            hmac_256::<Sha256>(&padded_key, b"contents")
+        );
+        // Test case 6 from RFC 4231, with a key longer than the block size.
+        assert_eq!(
+            hmac_256_variable_key::<Sha256>(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )
+            .to_vec(),
+            hex::decode("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54")
+                .unwrap()
+        );
+        // Key between the hash and block size, generated with the following Python script:
+        //
+        // import hashlib
+        // import hmac
+        // print(hmac.new(bytes(range(48)), b"contents", hashlib.sha256).hexdigest())
+        let key: Vec<u8> = (0..48).collect();
+        assert_eq!(
+            hmac_256_variable_key::<Sha256>(&key, b"contents").to_vec(),
+            hex::decode("bb3cb95bfcb2d36f3f8f0ffaf23a8e92010226c27944b65bd0cc057b776e62ab")
+                .unwrap()
+        );
+    }
+
     #[test]
     fn test_hash_sha256_for_various_lengths() {
         // This test makes sure that the key hashing and hash padding are implemented properly.
@@ -21,9 +21,11 @@ extern crate arrayref;
 
 pub mod aes256;
 pub mod cbc;
+pub mod ctr;
 mod ec;
 pub mod ecdh;
 pub mod ecdsa;
+pub mod entropy;
 pub mod hkdf;
 pub mod hmac;
 pub mod hybrid;
@@ -277,6 +277,18 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_update_byte_at_a_time() {
+        for len in 0..300 {
+            let input: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut h = Sha256::new();
+            for byte in input.iter() {
+                h.update(core::slice::from_ref(byte));
+            }
+            assert_eq!(h.finalize(), Sha256::hash(&input));
+        }
+    }
+
     #[test]
     fn test_hash_for_various_lengths() {
         // This test makes sure that the padding is implemented properly.
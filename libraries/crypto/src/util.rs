@@ -12,6 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{compiler_fence, Ordering};
+use subtle::ConstantTimeEq;
 #[cfg(test)]
 use subtle::CtOption;
 
@@ -24,6 +29,80 @@ pub fn xor_block_16(block: &mut Block16, mask: &Block16) {
     }
 }
 
+/// Compares two byte slices for equality.
+///
+/// This comparison is timing-safe: its running time only depends on the length of the slices,
+/// never on their content. Slices of different lengths are rejected immediately.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    bool::from(a.ct_eq(b))
+}
+
+/// Overwrites secret material with zeros.
+///
+/// The writes are volatile, so the compiler can't elide them even if the value is never read again,
+/// for example right before it is dropped.
+pub trait Zeroize {
+    fn zeroize(&mut self);
+}
+
+impl Zeroize for [u8] {
+    fn zeroize(&mut self) {
+        for byte in self.iter_mut() {
+            // The pointer comes from a mutable reference, so it is valid and aligned.
+            unsafe { ptr::write_volatile(byte, 0) };
+        }
+        // Later accesses must not be reordered before the zeroization.
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+impl<const N: usize> Zeroize for [u8; N] {
+    fn zeroize(&mut self) {
+        self[..].zeroize();
+    }
+}
+
+/// Only the elements are cleared, not the spare capacity of the vector.
+///
+/// Avoid reallocations of vectors holding secrets, as they leave copies on the heap.
+impl Zeroize for Vec<u8> {
+    fn zeroize(&mut self) {
+        self[..].zeroize();
+    }
+}
+
+/// Wraps a secret to zeroize it when it is dropped.
+pub struct Zeroizing<T: Zeroize>(T);
+
+impl<T: Zeroize> Zeroizing<T> {
+    pub fn new(value: T) -> Self {
+        Zeroizing(value)
+    }
+}
+
+impl<T: Zeroize> Deref for Zeroizing<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> DerefMut for Zeroizing<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> Drop for Zeroizing<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
 #[cfg(test)]
 pub trait ToOption<T> {
     fn to_option(self) -> Option<T>;
@@ -39,3 +118,41 @@ impl<T> ToOption<T> for CtOption<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::mem::ManuallyDrop;
+
+    #[test]
+    fn test_ct_eq() {
+        assert!(ct_eq(&[], &[]));
+        assert!(ct_eq(&[0x01, 0x02, 0x03], &[0x01, 0x02, 0x03]));
+        assert!(!ct_eq(&[0x01, 0x02, 0x03], &[0x01, 0x02, 0x04]));
+        assert!(!ct_eq(&[0x01, 0x02, 0x03], &[0x00, 0x02, 0x03]));
+        assert!(!ct_eq(&[0x01, 0x02, 0x03], &[0x01, 0x02]));
+        assert!(!ct_eq(&[], &[0x00]));
+    }
+
+    #[test]
+    fn test_zeroize() {
+        let mut array = [0x55; 32];
+        array.zeroize();
+        assert_eq!(array, [0x00; 32]);
+        let mut vector = vec![0x55; 20];
+        vector.zeroize();
+        assert_eq!(vector, vec![0x00; 20]);
+    }
+
+    #[test]
+    fn test_zeroizing_drop() {
+        let mut secret = ManuallyDrop::new(Zeroizing::new([0x55; 32]));
+        assert_eq!(**secret, [0x55; 32]);
+        let secret_ptr: *mut Zeroizing<[u8; 32]> = &mut *secret;
+        // The memory outlives the drop, so its content can still be inspected.
+        unsafe {
+            ptr::drop_in_place(secret_ptr);
+            assert_eq!((*secret_ptr).0, [0x00; 32]);
+        }
+    }
+}
@@ -32,6 +32,12 @@ pub enum StorageError {
     /// Arguments are out of bounds.
     OutOfBounds,
 
+    /// Another operation is still in progress.
+    ///
+    /// The storage is left unchanged. This happens for example when the storage is accessed from
+    /// an interrupt while an operation is waiting for its completion.
+    Busy,
+
     /// Implementation-specific error.
     CustomError,
 }
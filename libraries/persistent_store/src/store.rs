@@ -31,6 +31,7 @@ use alloc::vec::Vec;
 use core::borrow::Borrow;
 use core::cmp::{max, min, Ordering};
 use core::convert::TryFrom;
+use core::ops::Range;
 #[cfg(feature = "std")]
 use std::collections::HashSet;
 
@@ -61,6 +62,13 @@ pub enum StoreError {
     /// idempotent.
     StorageError,
 
+    /// The storage is busy with another operation.
+    ///
+    /// The storage operation was rejected without being performed. However, it may have been part
+    /// of a larger store operation, so the store should be [recovered](Store::recover) before doing
+    /// any other operation, like for [`StoreError::StorageError`].
+    Busy,
+
     /// Storage is invalid.
     ///
     /// The storage should be erased and the store [recovered](Store::recover). The store would be
@@ -72,6 +80,7 @@ impl From<StorageError> for StoreError {
     fn from(error: StorageError) -> StoreError {
         match error {
             StorageError::CustomError => StoreError::StorageError,
+            StorageError::Busy => StoreError::Busy,
             // The store always calls the storage correctly.
             StorageError::NotAligned | StorageError::OutOfBounds => unreachable!(),
         }
@@ -260,6 +269,17 @@ impl<S: Storage> Store<S> {
         )))
     }
 
+    /// Iterates over the entries whose key is in the given range.
+    ///
+    /// Like [`Self::iter`], only live entries are yielded. Errors are always yielded since their
+    /// key is unknown.
+    pub fn iter_range<'a>(&'a self, range: Range<usize>) -> StoreResult<StoreIter<'a>> {
+        Ok(Box::new(self.iter()?.filter(move |handle| match handle {
+            Ok(handle) => range.contains(&handle.get_key()),
+            Err(_) => true,
+        })))
+    }
+
     /// Returns the current and total capacity in words.
     ///
     /// The capacity represents the size of what is stored.
@@ -284,6 +304,29 @@ impl<S: Storage> Store<S> {
         Ok(StoreRatio { used, total })
     }
 
+    /// Returns how many times each page has been erased.
+    ///
+    /// The counts are read from the page headers, so gathering them doesn't write to the storage.
+    /// Each count is at most [`Storage::max_page_erases`], which bounds the remaining life of the
+    /// storage. A page that has been erased but not yet used again already counts this erase.
+    pub fn page_erases(&self) -> StoreResult<Vec<usize>> {
+        let tail = self.tail()?;
+        let tail_page = tail.page(&self.format);
+        let tail_cycle = tail.cycle(&self.format);
+        (0..self.format.num_pages())
+            .map(|page| {
+                let cycle = match self.parse_init(page)? {
+                    WordState::Valid(init) => init.cycle,
+                    // The page will be used when the tail reaches it, in the current cycle if it
+                    // is ahead of the tail, and in the next cycle otherwise.
+                    _ if page >= tail_page => tail_cycle,
+                    _ => tail_cycle + 1,
+                };
+                Ok(cycle as usize)
+            })
+            .collect()
+    }
+
     /// Applies a sequence of updates as a single transaction.
     ///
     /// # Errors
@@ -382,6 +425,30 @@ impl<S: Storage> Store<S> {
         Ok(())
     }
 
+    /// Returns how many words can be written before a compaction is needed.
+    ///
+    /// Updates that don't fit in the slack first compact one or more pages, which can take a
+    /// while. Use [`Store::compact_if_idle`] to compact ahead of time.
+    pub fn slack(&self) -> StoreResult<usize> {
+        Ok(self.immediate_capacity()? as usize)
+    }
+
+    /// Compacts one page if the slack is below `threshold` words.
+    ///
+    /// This is meant to be called while the store is idle, such that later updates don't need to
+    /// compact. At most one page is compacted, so the duration is bounded. Nothing is done if
+    /// compacting can't increase the slack, because all the free capacity is already immediate.
+    ///
+    /// Returns whether a page was compacted.
+    pub fn compact_if_idle(&mut self, threshold: usize) -> StoreResult<bool> {
+        let slack = self.slack()?;
+        if slack >= threshold || self.capacity()?.remaining() <= slack {
+            return Ok(false);
+        }
+        self.compact()?;
+        Ok(true)
+    }
+
     /// Recovers a possible interrupted operation.
     ///
     /// If the storage is completely erased, it is initialized.
@@ -1426,6 +1493,87 @@ mod tests {
         assert_eq!(driver.store().capacity().unwrap().remaining(), 18);
     }
 
+    #[test]
+    fn compact_if_idle_ok() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+
+        // Don't compact if there is nothing to gain.
+        assert_eq!(driver.store().slack().unwrap(), 39);
+        assert_eq!(driver.store_mut().compact_if_idle(40), Ok(false));
+        assert_eq!(driver.store().head().unwrap().get(), 0);
+
+        // Fill the store and remove some entries.
+        for key in 0..4 {
+            driver.insert(key, &[0x38; 28]).unwrap();
+        }
+        driver.remove(0).unwrap();
+        driver.remove(2).unwrap();
+        driver.check().unwrap();
+        assert_eq!(driver.store().slack().unwrap(), 7);
+
+        // Don't compact if the slack is above the threshold.
+        assert_eq!(driver.store_mut().compact_if_idle(7), Ok(false));
+        assert_eq!(driver.store().head().unwrap().get(), 0);
+
+        // Compact one page otherwise.
+        assert_eq!(driver.store_mut().compact_if_idle(8), Ok(true));
+        driver.check().unwrap();
+        assert_eq!(driver.store().head().unwrap().get(), 16);
+        assert_eq!(driver.store().slack().unwrap(), 14);
+        assert_eq!(driver.store().capacity().unwrap().remaining(), 18);
+    }
+
+    #[test]
+    fn page_erases_ok() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        let num_pages = driver.store().format().num_pages() as usize;
+        assert_eq!(driver.store().page_erases().unwrap(), vec![0; num_pages]);
+
+        // Overwrite the same entry to cause compactions.
+        for i in 0..50 {
+            driver.insert(0, &[i as u8; 28]).unwrap();
+        }
+        driver.check().unwrap();
+        let store = driver.store();
+        let page_erases = store.page_erases().unwrap();
+        for (page, &erases) in page_erases.iter().enumerate() {
+            assert_eq!(erases, store.storage().get_page_erases(page));
+        }
+
+        // Pages are compacted as the tail moves forward, and the tail is at most the whole storage
+        // ahead of the last compacted page. Each insert writes at least 8 words.
+        let virt_page_size = store.format().virt_page_size() as usize;
+        let used = store.lifetime().unwrap().used();
+        assert!(used >= 50 * 8);
+        let total: usize = page_erases.iter().sum();
+        assert!(total <= used / virt_page_size);
+        assert!(total + num_pages >= used / virt_page_size);
+        // Pages are erased in turn.
+        let min = *page_erases.iter().min().unwrap();
+        assert!(page_erases.iter().all(|&erases| erases <= min + 1));
+    }
+
+    #[test]
+    fn iter_range_ok() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        for key in 0..12 {
+            driver.insert(key, &[]).unwrap();
+        }
+        driver.remove(5).unwrap();
+        driver.insert(7, &[0x5c]).unwrap();
+        driver.check().unwrap();
+
+        let mut keys = driver
+            .store()
+            .iter_range(3..9)
+            .unwrap()
+            .map(|handle| handle.unwrap().get_key())
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        assert_eq!(keys, [3, 4, 6, 7, 8]);
+        assert_eq!(driver.store().iter_range(12..20).unwrap().count(), 0);
+    }
+
     #[test]
     fn reboot_ok() {
         let mut driver = MINIMAL.new_driver().power_on().unwrap();
@@ -40,6 +40,8 @@ echo "Running Clippy lints..."
 cargo clippy --all-targets --features std -- -A clippy::new_without_default -D warnings
 cargo clippy --all-targets --features std,with_ctap1,ed25519,vendor_hid -- -A clippy::new_without_default -D warnings
 cargo clippy --all-targets --features std,with_ctap1,with_nfc,ed25519,vendor_hid -- -A clippy::new_without_default -D warnings
+cargo clippy --all-targets --no-default-features --features std -- -A clippy::new_without_default -D warnings
+cargo clippy --all-targets --features std,log,debug_ctap -- -A clippy::new_without_default -D warnings
 
 echo "Building sha256sum tool..."
 cargo build --manifest-path third_party/tock/tools/sha256sum/Cargo.toml
@@ -59,6 +61,7 @@ cargo check --release --target=thumbv7em-none-eabi --features debug_allocations
 cargo check --release --target=thumbv7em-none-eabi --features verbose
 cargo check --release --target=thumbv7em-none-eabi --features debug_ctap,with_ctap1
 cargo check --release --target=thumbv7em-none-eabi --features debug_ctap,with_ctap1,vendor_hid,ed25519,panic_console,debug_allocations,verbose
+cargo check --release --target=thumbv7em-none-eabi --no-default-features
 
 echo "Checking that examples build properly..."
 cargo check --release --target=thumbv7em-none-eabi --examples
@@ -124,9 +127,15 @@ then
   cd ../..
   cargo test --features std
 
-  echo "Running unit tests on the desktop (release mode + CTAP1 + Vendor HID)..."
-  cargo test --release --features std,with_ctap1,vendor_hid
+  echo "Running unit tests on the desktop (release mode + CTAP1 + Vendor HID + NFC)..."
+  cargo test --release --features std,with_ctap1,vendor_hid,with_nfc
 
-  echo "Running unit tests on the desktop (debug mode + CTAP1)..."
-  cargo test --features std,with_ctap1,vendor_hid
+  echo "Running unit tests on the desktop (debug mode + CTAP1 + Vendor HID + NFC)..."
+  cargo test --features std,with_ctap1,vendor_hid,with_nfc
+
+  echo "Running unit tests on the desktop (debug mode + reduced footprint)..."
+  cargo test --no-default-features --features std
+
+  echo "Running unit tests on the desktop (debug mode + log + debug_ctap)..."
+  cargo test --features std,log,debug_ctap
 fi
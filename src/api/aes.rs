@@ -0,0 +1,109 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crypto::aes256::{DecryptionKey, EncryptionKey};
+use crypto::cbc::{cbc_decrypt, cbc_encrypt};
+
+/// Size of an AES block in bytes.
+pub const BLOCK_SIZE: usize = 16;
+
+/// AES-256 in CBC mode, possibly backed by a hardware engine.
+///
+/// Implementations are interchangeable: data encrypted with one of them decrypts identically with
+/// any other, in particular with [`SoftwareAes256`].
+pub trait Aes256 {
+    /// Encrypts blocks in place.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `blocks` is not a multiple of [`BLOCK_SIZE`].
+    fn cbc_encrypt(&mut self, key: &[u8; 32], iv: &[u8; BLOCK_SIZE], blocks: &mut [u8]);
+
+    /// Decrypts blocks in place.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `blocks` is not a multiple of [`BLOCK_SIZE`].
+    fn cbc_decrypt(&mut self, key: &[u8; 32], iv: &[u8; BLOCK_SIZE], blocks: &mut [u8]);
+}
+
+/// Implementation from the crypto library.
+#[derive(Default)]
+pub struct SoftwareAes256;
+
+impl Aes256 for SoftwareAes256 {
+    fn cbc_encrypt(&mut self, key: &[u8; 32], iv: &[u8; BLOCK_SIZE], blocks: &mut [u8]) {
+        assert_eq!(blocks.len() % BLOCK_SIZE, 0);
+        cbc_encrypt(&EncryptionKey::new(key), *iv, blocks);
+    }
+
+    fn cbc_decrypt(&mut self, key: &[u8; 32], iv: &[u8; BLOCK_SIZE], blocks: &mut [u8]) {
+        assert_eq!(blocks.len() % BLOCK_SIZE, 0);
+        let decryption_key = DecryptionKey::new(&EncryptionKey::new(key));
+        cbc_decrypt(&decryption_key, *iv, blocks);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::test::TestEnv;
+    use crate::env::Env;
+
+    // Test vector CBC-AES256 from NIST SP 800-38A, section F.2.5.
+    const KEY: [u8; 32] = [
+        0x60, 0x3d, 0xeb, 0x10, 0x15, 0xca, 0x71, 0xbe, 0x2b, 0x73, 0xae, 0xf0, 0x85, 0x7d, 0x77,
+        0x81, 0x1f, 0x35, 0x2c, 0x07, 0x3b, 0x61, 0x08, 0xd7, 0x2d, 0x98, 0x10, 0xa3, 0x09, 0x14,
+        0xdf, 0xf4,
+    ];
+    const IV: [u8; 16] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f,
+    ];
+    const PLAINTEXT: [u8; 32] = [
+        0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17,
+        0x2a, 0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac, 0x45, 0xaf,
+        0x8e, 0x51,
+    ];
+    const CIPHERTEXT: [u8; 32] = [
+        0xf5, 0x8c, 0x4c, 0x04, 0xd6, 0xe5, 0xf1, 0xba, 0x77, 0x9e, 0xab, 0xfb, 0x5f, 0x7b, 0xfb,
+        0xd6, 0x9c, 0xfc, 0x4e, 0x96, 0x7e, 0xdb, 0x80, 0x8d, 0x67, 0x9f, 0x77, 0x7b, 0xc6, 0x70,
+        0x2c, 0x7d,
+    ];
+
+    #[test]
+    fn test_software_matches_test_vector() {
+        let mut blocks = PLAINTEXT;
+        SoftwareAes256.cbc_encrypt(&KEY, &IV, &mut blocks);
+        assert_eq!(blocks, CIPHERTEXT);
+        SoftwareAes256.cbc_decrypt(&KEY, &IV, &mut blocks);
+        assert_eq!(blocks, PLAINTEXT);
+    }
+
+    #[test]
+    fn test_env_matches_test_vector() {
+        let mut env = TestEnv::new();
+        let mut blocks = PLAINTEXT;
+        env.aes().cbc_encrypt(&KEY, &IV, &mut blocks);
+        assert_eq!(blocks, CIPHERTEXT);
+        env.aes().cbc_decrypt(&KEY, &IV, &mut blocks);
+        assert_eq!(blocks, PLAINTEXT);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_partial_block_panics() {
+        SoftwareAes256.cbc_encrypt(&KEY, &IV, &mut [0; 15]);
+    }
+}
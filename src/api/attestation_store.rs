@@ -1,6 +1,10 @@
 use alloc::vec::Vec;
-use persistent_store::{StoreError, StoreUpdate};
+use core::convert::TryFrom;
+use core::ops::Range;
+use persistent_store::{StoreError, StoreResult, StoreUpdate};
 
+use crate::api::key_store;
+use crate::api::store_encryption::EncryptedStore;
 use crate::env::Env;
 
 /// Identifies an attestation.
@@ -14,7 +18,10 @@ pub enum Id {
 pub struct Attestation {
     /// ECDSA private key (big-endian).
     pub private_key: [u8; 32],
+    /// DER certificate of the attestation key.
     pub certificate: Vec<u8>,
+    /// DER certificates completing the chain, starting with the issuer of `certificate`.
+    pub intermediate_certificates: Vec<Vec<u8>>,
 }
 
 /// Stores enterprise or batch attestations.
@@ -27,6 +34,9 @@ pub trait AttestationStore {
 
     /// Sets the attestation for a given id.
     ///
+    /// Intermediate certificates always replace the stored chain. An empty list removes it, as does
+    /// removing the attestation.
+    ///
     /// This function may not be supported.
     fn set(&mut self, id: &Id, attestation: Option<&Attestation>) -> Result<(), Error>;
 }
@@ -43,50 +53,152 @@ pub enum Error {
 /// Keys of the environment store reserved for the attestation store.
 pub const STORAGE_KEYS: &[usize] = &[1, 2];
 
+/// Keys of the environment store reserved for the intermediate certificates.
+///
+/// The certificates are stored as a single value split in fragments, each certificate prefixed
+/// with its length as 2 bytes big-endian.
+pub const CHAIN_STORAGE_KEYS: Range<usize> = 4..8;
+
+/// Returns the attestation stored by [`helper_set`].
+///
+/// The attestation is encrypted with the keys that persist a reset. Attestations stored in
+/// plaintext by previous versions are encrypted when first read.
 pub fn helper_get(env: &mut impl Env) -> Result<Option<Attestation>, Error> {
-    let private_key = env.store().find(PRIVATE_KEY_STORAGE_KEY)?;
-    let certificate = env.store().find(CERTIFICATE_STORAGE_KEY)?;
-    let (private_key, certificate) = match (private_key, certificate) {
-        (Some(x), Some(y)) => (x, y),
-        (None, None) => return Ok(None),
+    let mut store = EncryptedStore::new_persistent(env)?;
+    let (private_key, certificate, chain) = match read_entries(&mut store) {
+        Err(StoreError::InvalidStorage) => {
+            encrypt_plaintext_entries(&mut store)?;
+            read_entries(&mut store)?
+        }
+        entries => entries?,
+    };
+    let (private_key, certificate) = match (private_key, certificate, &chain) {
+        (Some(x), Some(y), _) => (x, y),
+        (None, None, None) => return Ok(None),
         _ => return Err(Error::Internal),
     };
     if private_key.len() != 32 {
         return Err(Error::Internal);
     }
+    let intermediate_certificates = match chain {
+        None => Vec::new(),
+        Some(chain) => decode_chain(&chain)?,
+    };
     Ok(Some(Attestation {
         private_key: *array_ref![private_key, 0, 32],
         certificate,
+        intermediate_certificates,
     }))
 }
 
+/// Stores an attestation, encrypted with the keys that persist a reset.
 pub fn helper_set(env: &mut impl Env, attestation: Option<&Attestation>) -> Result<(), Error> {
-    let updates = match attestation {
-        None => [
-            StoreUpdate::Remove {
+    let max_value_length = env.store().max_value_length();
+    let mut store = EncryptedStore::new_persistent(env)?;
+    let mut updates = Vec::with_capacity(STORAGE_KEYS.len() + CHAIN_STORAGE_KEYS.len());
+    let chain = match attestation {
+        None => {
+            updates.push(StoreUpdate::Remove {
                 key: PRIVATE_KEY_STORAGE_KEY,
-            },
-            StoreUpdate::Remove {
+            });
+            updates.push(StoreUpdate::Remove {
                 key: CERTIFICATE_STORAGE_KEY,
-            },
-        ],
-        Some(attestation) => [
-            StoreUpdate::Insert {
+            });
+            Vec::new()
+        }
+        Some(attestation) => {
+            updates.push(StoreUpdate::Insert {
                 key: PRIVATE_KEY_STORAGE_KEY,
-                value: &attestation.private_key[..],
-            },
-            StoreUpdate::Insert {
+                value: store.encrypt(PRIVATE_KEY_STORAGE_KEY, &attestation.private_key),
+            });
+            updates.push(StoreUpdate::Insert {
                 key: CERTIFICATE_STORAGE_KEY,
-                value: &attestation.certificate[..],
-            },
-        ],
+                value: store.encrypt(CERTIFICATE_STORAGE_KEY, &attestation.certificate),
+            });
+            let chain = encode_chain(&attestation.intermediate_certificates)?;
+            store.encrypt_fragments(&CHAIN_STORAGE_KEYS, &chain)
+        }
     };
+    // The chain is always rewritten with the leaf, so an empty chain removes all fragments.
+    let mut chunks = chain.chunks(max_value_length);
+    for key in CHAIN_STORAGE_KEYS {
+        updates.push(match chunks.next() {
+            Some(value) => StoreUpdate::Insert {
+                key,
+                value: value.to_vec(),
+            },
+            None => StoreUpdate::Remove { key },
+        });
+    }
+    if chunks.next().is_some() {
+        // The chain does not fit in the reserved keys.
+        return Err(Error::NoSupport);
+    }
     Ok(env.store().transaction(&updates)?)
 }
 
 const PRIVATE_KEY_STORAGE_KEY: usize = STORAGE_KEYS[0];
 const CERTIFICATE_STORAGE_KEY: usize = STORAGE_KEYS[1];
 
+type Entries = (Option<Vec<u8>>, Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// Reads the private key, the certificate and the chain.
+fn read_entries<E: Env>(store: &mut EncryptedStore<E>) -> StoreResult<Entries> {
+    Ok((
+        store.find(PRIVATE_KEY_STORAGE_KEY)?,
+        store.find(CERTIFICATE_STORAGE_KEY)?,
+        store.read_fragments(&CHAIN_STORAGE_KEYS)?,
+    ))
+}
+
+/// Encrypts the entries that were stored in plaintext by previous versions.
+///
+/// Values are only encrypted if they are valid plaintext for their entry, so that tampered values
+/// are still rejected.
+fn encrypt_plaintext_entries<E: Env>(store: &mut EncryptedStore<E>) -> StoreResult<()> {
+    store.encrypt_plaintext(PRIVATE_KEY_STORAGE_KEY, |value| value.len() == 32)?;
+    // DER certificates start with a sequence.
+    store.encrypt_plaintext(CERTIFICATE_STORAGE_KEY, |value| {
+        value.first() == Some(&0x30)
+    })?;
+    store.encrypt_plaintext_fragments(&CHAIN_STORAGE_KEYS, |value| decode_chain(value).is_ok())
+}
+
+/// Concatenates certificates, each prefixed with its length.
+fn encode_chain(certificates: &[Vec<u8>]) -> Result<Vec<u8>, Error> {
+    let mut chain = Vec::new();
+    for certificate in certificates {
+        let length = u16::try_from(certificate.len()).map_err(|_| Error::NoSupport)?;
+        chain.extend_from_slice(&length.to_be_bytes());
+        chain.extend_from_slice(certificate);
+    }
+    Ok(chain)
+}
+
+/// Splits the output of [`encode_chain`] back into certificates.
+fn decode_chain(mut chain: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let mut certificates = Vec::new();
+    while !chain.is_empty() {
+        if chain.len() < 2 {
+            return Err(Error::Internal);
+        }
+        let length = u16::from_be_bytes([chain[0], chain[1]]) as usize;
+        chain = &chain[2..];
+        if chain.len() < length {
+            return Err(Error::Internal);
+        }
+        certificates.push(chain[..length].to_vec());
+        chain = &chain[length..];
+    }
+    Ok(certificates)
+}
+
+impl From<key_store::Error> for Error {
+    fn from(_: key_store::Error) -> Self {
+        Error::Internal
+    }
+}
+
 impl From<StoreError> for Error {
     fn from(error: StoreError) -> Self {
         match error {
@@ -94,7 +206,124 @@ impl From<StoreError> for Error {
             | StoreError::NoCapacity
             | StoreError::NoLifetime
             | StoreError::InvalidStorage => Error::Internal,
-            StoreError::StorageError => Error::Storage,
+            StoreError::StorageError | StoreError::Busy => Error::Storage,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::test::TestEnv;
+    use persistent_store::fragment;
+
+    fn create_attestation(intermediate_certificates: Vec<Vec<u8>>) -> Attestation {
+        Attestation {
+            private_key: [0x41; 32],
+            certificate: vec![0xdd; 20],
+            intermediate_certificates,
         }
     }
+
+    #[test]
+    fn test_chain_round_trip() {
+        let mut env = TestEnv::new();
+        assert_eq!(helper_get(&mut env), Ok(None));
+        // The first certificate spans multiple fragments.
+        let attestation = create_attestation(vec![vec![0x11; 1500], vec![], vec![0x22; 30]]);
+        helper_set(&mut env, Some(&attestation)).unwrap();
+        assert_eq!(helper_get(&mut env), Ok(Some(attestation)));
+
+        // Shorter chains free the unused fragments.
+        let attestation = create_attestation(vec![vec![0x33; 30]]);
+        helper_set(&mut env, Some(&attestation)).unwrap();
+        assert_eq!(helper_get(&mut env), Ok(Some(attestation)));
+        for key in CHAIN_STORAGE_KEYS.skip(1) {
+            assert_eq!(env.store().find(key), Ok(None));
+        }
+
+        // Removing the attestation removes the chain.
+        helper_set(&mut env, None).unwrap();
+        assert_eq!(helper_get(&mut env), Ok(None));
+        for key in CHAIN_STORAGE_KEYS {
+            assert_eq!(env.store().find(key), Ok(None));
+        }
+    }
+
+    #[test]
+    fn test_provision_twice_without_chain() {
+        let mut env = TestEnv::new();
+        let attestation = create_attestation(vec![vec![0x11; 1500], vec![0x22; 30]]);
+        helper_set(&mut env, Some(&attestation)).unwrap();
+
+        // Provisioning a new leaf without a chain must not keep the old intermediates.
+        let mut attestation = create_attestation(vec![]);
+        attestation.private_key = [0x42; 32];
+        helper_set(&mut env, Some(&attestation)).unwrap();
+        assert_eq!(helper_get(&mut env), Ok(Some(attestation)));
+        for key in CHAIN_STORAGE_KEYS {
+            assert_eq!(env.store().find(key), Ok(None));
+        }
+    }
+
+    #[test]
+    fn test_chain_too_long() {
+        let mut env = TestEnv::new();
+        let attestation = create_attestation(vec![vec![0x11; 1000]; 5]);
+        assert_eq!(
+            helper_set(&mut env, Some(&attestation)),
+            Err(Error::NoSupport)
+        );
+        assert_eq!(helper_get(&mut env), Ok(None));
+    }
+
+    #[test]
+    fn test_attestation_encrypted_at_rest() {
+        let mut env = TestEnv::new();
+        let attestation = create_attestation(vec![vec![0x11; 30]]);
+        helper_set(&mut env, Some(&attestation)).unwrap();
+        let private_key = env.store().find(PRIVATE_KEY_STORAGE_KEY).unwrap().unwrap();
+        assert!(!private_key.windows(32).any(|window| window == [0x41; 32]));
+        let chain = fragment::read(env.store(), &CHAIN_STORAGE_KEYS)
+            .unwrap()
+            .unwrap();
+        assert!(!chain.windows(30).any(|window| window == [0x11; 30]));
+
+        // A modified value is not accepted.
+        let mut certificate = env.store().find(CERTIFICATE_STORAGE_KEY).unwrap().unwrap();
+        certificate[1] ^= 0x01;
+        env.store()
+            .insert(CERTIFICATE_STORAGE_KEY, &certificate)
+            .unwrap();
+        assert_eq!(helper_get(&mut env), Err(Error::Internal));
+    }
+
+    #[test]
+    fn test_encrypt_plaintext_attestation() {
+        let mut env = TestEnv::new();
+        let mut attestation = create_attestation(vec![vec![0x30; 30]]);
+        attestation.certificate = vec![0x30; 20];
+        let chain = encode_chain(&attestation.intermediate_certificates).unwrap();
+        env.store()
+            .insert(PRIVATE_KEY_STORAGE_KEY, &attestation.private_key)
+            .unwrap();
+        env.store()
+            .insert(CERTIFICATE_STORAGE_KEY, &attestation.certificate)
+            .unwrap();
+        fragment::write(env.store(), &CHAIN_STORAGE_KEYS, &chain).unwrap();
+
+        assert_eq!(helper_get(&mut env), Ok(Some(attestation)));
+        let private_key = env.store().find(PRIVATE_KEY_STORAGE_KEY).unwrap().unwrap();
+        assert_ne!(private_key, vec![0x41; 32]);
+        assert_ne!(
+            fragment::read(env.store(), &CHAIN_STORAGE_KEYS).unwrap(),
+            Some(chain)
+        );
+    }
+
+    #[test]
+    fn test_decode_chain_invalid() {
+        assert_eq!(decode_chain(&[0x00]), Err(Error::Internal));
+        assert_eq!(decode_chain(&[0x00, 0x02, 0x11]), Err(Error::Internal));
+    }
 }
@@ -0,0 +1,62 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::clock::{instant_from_ms, CtapInstant};
+
+/// Monotonic clock measuring time since boot.
+///
+/// CTAP timeouts, such as the lifetime of the pinUvAuthToken, are measured with it.
+pub trait Clock {
+    /// Returns the milliseconds elapsed since boot.
+    ///
+    /// The value never decreases, even when the underlying hardware counter wraps around.
+    fn now_ms(&mut self) -> u64;
+
+    /// Returns the milliseconds elapsed since `start_ms`, an earlier result of `now_ms`.
+    fn elapsed_ms(&mut self, start_ms: u64) -> u64 {
+        self.now_ms().saturating_sub(start_ms)
+    }
+
+    /// Returns the current instant of the CTAP timers.
+    fn now(&mut self) -> CtapInstant {
+        instant_from_ms(self.now_ms())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::TEST_CLOCK_FREQUENCY_HZ;
+    use crate::env::test::TestEnv;
+    use crate::env::Env;
+
+    #[test]
+    fn test_elapsed_ms() {
+        let mut env = TestEnv::new();
+        let start_ms = env.clock().now_ms();
+        env.clock().advance_ms(1500);
+        assert_eq!(env.clock().elapsed_ms(start_ms), 1500);
+        assert_eq!(env.clock().elapsed_ms(start_ms + 2000), 0);
+    }
+
+    #[test]
+    fn test_now() {
+        let mut env = TestEnv::new();
+        env.clock().set_ms(2000);
+        assert_eq!(
+            env.clock().now(),
+            CtapInstant::new(2 * TEST_CLOCK_FREQUENCY_HZ as u64)
+        );
+    }
+}
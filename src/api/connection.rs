@@ -13,6 +13,8 @@
 // limitations under the License.
 
 use crate::clock::ClockInt;
+#[cfg(feature = "with_nfc")]
+use alloc::vec::Vec;
 use embedded_time::duration::Milliseconds;
 use libtock_drivers::usb_ctap_hid::UsbEndpoint;
 
@@ -22,7 +24,29 @@ pub enum SendOrRecvStatus {
     Received(UsbEndpoint),
 }
 
-pub struct SendOrRecvError;
+/// Failure of the transport.
+///
+/// Environments map their own error codes to these kinds. There is no success kind, so an error
+/// always converts to a failing CTAP status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendOrRecvError {
+    /// The transport is still processing a previous packet.
+    Busy,
+    /// The transport rejected the parameters of the call.
+    InvalidParameter,
+    /// The transport rejected the length of the packet.
+    InvalidLength,
+    /// The transfer was canceled.
+    Canceled,
+    /// The other side did not acknowledge the transfer.
+    NoAck,
+    /// The transport could not allocate its buffers.
+    NoMemory,
+    /// The transport is missing, powered off, or used by another app.
+    Unavailable,
+    /// Any other failure.
+    Other,
+}
 
 pub type SendOrRecvResult = Result<SendOrRecvStatus, SendOrRecvError>;
 
@@ -33,3 +57,19 @@ pub trait HidConnection {
         timeout: Milliseconds<ClockInt>,
     ) -> SendOrRecvResult;
 }
+
+/// Exchanges ISO 7816-4 APDUs with an NFC reader.
+///
+/// Devices without NFC never receive a command, which is the default.
+#[cfg(feature = "with_nfc")]
+pub trait NfcTransport {
+    /// Returns the next command APDU, if a reader sent one.
+    fn receive_apdu(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Sends the response APDU to the last command.
+    fn send_apdu(&mut self, _apdu: &[u8]) -> Result<(), SendOrRecvError> {
+        Err(SendOrRecvError::Unavailable)
+    }
+}
@@ -17,7 +17,9 @@
 //! If you adapt them, make sure to run the tests before flashing the firmware.
 //! Our deploy script enforces the invariants.
 
+use crate::api::signature_counter::SignatureCounterStrategy;
 use crate::ctap::data_formats::{CredentialProtectionPolicy, EnterpriseAttestationMode};
+use crate::ctap::key_material;
 use alloc::string::String;
 use alloc::vec::Vec;
 
@@ -26,12 +28,44 @@ pub trait Customization {
     // Constants for adjusting privacy and protection levels.
     // ###########################################################################
 
+    /// Identifies the authenticator model.
+    ///
+    /// The AAGUID is part of the attested credential data and of getInfo. RPs
+    /// use it to look up the authenticator in metadata, so each deployment
+    /// should use its own value.
+    ///
+    /// # Invariant
+    ///
+    /// - The AAGUID must not be all zeros if enterprise attestation is enabled.
+    fn aaguid(&self) -> &'static [u8; key_material::AAGUID_LENGTH];
+
+    /// Public key that firmware upgrades must be signed with.
+    ///
+    /// The key is an uncompressed P-256 point. Before a staged upgrade is
+    /// marked bootable, the ECDSA signature in its metadata is verified with
+    /// this key. Bundles that fail verification are invalidated.
+    fn upgrade_public_key(&self) -> &'static [u8; key_material::UPGRADE_PUBLIC_KEY_LENGTH];
+
     /// Removes support for PIN protocol v1.
     ///
     /// We support PIN protocol v2, "intended to aid FIPS certification".
     /// To certify, you might want to remove support for v1 using this customization.
     fn allows_pin_protocol_v1(&self) -> bool;
 
+    /// Removes support for U2F (CTAP1).
+    ///
+    /// Some deployments don't want to support legacy U2F relying parties. Setting this to false
+    /// rejects all CTAP1 commands and removes U2F_V2 from the versions in GetInfo. It has no
+    /// effect if the `with_ctap1` feature is disabled.
+    fn allows_ctap1(&self) -> bool;
+
+    /// Allows dumping credential metadata with a vendor command.
+    ///
+    /// The dump lists the RP ID, creation order and signature counter of each credential, after a
+    /// user presence check. It contains no key or user information, but reveals which accounts
+    /// exist, so it should stay disabled in production.
+    fn allows_credential_dump(&self) -> bool;
+
     /// Changes the default level for the credProtect extension.
     ///
     /// You can change this value to one of the following for more privacy:
@@ -86,6 +120,18 @@ pub trait Customization {
     /// Calling toggleAlwaysUv is preferred over enforcing alwaysUv here.
     fn enforce_always_uv(&self) -> bool;
 
+    /// Requires user presence for every assertion.
+    ///
+    /// When setting to true, getAssertion handles all requests as if the up
+    /// option was true, including the alwaysUv checks. It waits for a touch
+    /// and sets the UP flag in the authenticator data. Silent assertions with
+    /// up set to false are then impossible.
+    ///
+    /// Some deployments don't want relying parties to probe for credentials
+    /// without the user noticing. Platforms that expect silent requests to
+    /// succeed without interaction will time out waiting for the touch.
+    fn always_require_up(&self) -> bool;
+
     /// Allows usage of enterprise attestation.
     ///
     /// # Invariant
@@ -137,7 +183,7 @@ pub trait Customization {
     /// 1024 is the default mentioned in the authenticatorLargeBlobs commands.
     /// Larger values are preferred, as that allows more parameters in commands.
     /// If long commands are too unreliable on your hardware, consider decreasing
-    /// this value.
+    /// this value. Longer CTAP2 commands are rejected with CTAP2_ERR_LIMIT_EXCEEDED.
     fn max_msg_size(&self) -> usize;
 
     /// Sets the number of consecutive failed PINs before blocking interaction.
@@ -161,6 +207,8 @@ pub trait Customization {
     /// responsibility to safely generate and store the key material. Also, the
     /// batches must have size of at least 100k authenticators before using new key
     /// material.
+    /// If no batch attestation is provisioned, makeCredential falls back to self
+    /// attestation.
     /// U2F is unaffected by this setting.
     ///
     /// https://www.w3.org/TR/webauthn/#attestation
@@ -168,15 +216,21 @@ pub trait Customization {
 
     /// Enables or disables signature counters.
     ///
-    /// The signature counter is currently implemented as a global counter.
+    /// https://www.w3.org/TR/webauthn/#signature-counter
+    fn use_signature_counter(&self) -> bool;
+
+    /// Chooses between a global signature counter and per-credential counters.
+    ///
     /// The specification strongly suggests to have per-credential counters.
     /// Implementing those means you can't have an infinite amount of server-side
     /// credentials anymore. Also, since counters need frequent writes on the
-    /// persistent storage, we might need a flash friendly implementation. This
-    /// solution is a compromise to be compatible with U2F and not wasting storage.
+    /// persistent storage, we might need a flash friendly implementation. The
+    /// global counter is a compromise to be compatible with U2F and not wasting
+    /// storage.
     ///
-    /// https://www.w3.org/TR/webauthn/#signature-counter
-    fn use_signature_counter(&self) -> bool;
+    /// Per-credential counters are limited in number and only given to
+    /// resident credentials. Other credentials share the global counter.
+    fn signature_counter_strategy(&self) -> SignatureCounterStrategy;
 
     // ###########################################################################
     // Constants for performance optimization or adapting to different hardware.
@@ -194,14 +248,18 @@ pub trait Customization {
     ///   be persisted in the credential ID.
     fn max_cred_blob_length(&self) -> usize;
 
-    /// Limits the number of considered entries in credential lists.
+    /// Limits the number of entries in credential lists.
     ///
     /// # Invariant
     ///
-    /// - This value, if present, must be at least 1 (more is preferred).
+    /// - This value must be present and at least 1 (more is preferred).
     ///
     /// Depending on your memory, you can use Some(n) to limit request sizes in
     /// MakeCredential and GetAssertion. This affects allowList and excludeList.
+    /// Longer lists are rejected with CTAP2_ERR_LIMIT_EXCEEDED while parsing,
+    /// before any credential is looked up. An unbounded list would let a single
+    /// request allocate and look up credentials until memory or the watchdog
+    /// runs out, so None is not a valid choice.
     fn max_credential_count_in_list(&self) -> Option<usize>;
 
     /// Limits the size of largeBlobs the authenticator stores.
@@ -247,52 +305,107 @@ pub trait Customization {
     /// With P=20 and K=150, we have I=2M which is enough for 500 increments per day
     /// for 10 years.
     fn max_supported_resident_keys(&self) -> usize;
+
+    /// Sets how long the device may hang before the watchdog resets it, in milliseconds.
+    ///
+    /// # Invariant
+    ///
+    /// - The timeout, if present, must be at least 3 seconds.
+    ///
+    /// The main loop kicks the watchdog at least once per second while idle,
+    /// and user presence checks kick it with each keepalive. The timeout must
+    /// fit the slowest command in between. Flash writes dominate, and their
+    /// worst case follows from the nRF52840 product specification: erasing a
+    /// page takes at most 85 ms and writing a word at most 41 µs, so rewriting
+    /// a 4 kB page takes at most 85 ms + 1024 × 41 µs ≈ 127 ms.
+    ///
+    /// - A store update compacts at most N - 1 pages, copying at most one page
+    ///   each. With the 20 pages of the store, that is 19 × 127 ms ≈ 2.4 s.
+    /// - A firmware upgrade command carries at most `max_msg_size` bytes,
+    ///   which span at most 3 pages, so it writes for 3 × 127 ms ≈ 0.4 s.
+    ///
+    /// The minimum covers the worst-case compaction. The default of 10 seconds
+    /// leaves more than 7 seconds on top of it for the key generation and
+    /// signatures of a command.
+    /// Use None to disable the watchdog. Devices without a watchdog ignore
+    /// this value.
+    fn watchdog_timeout_ms(&self) -> Option<usize>;
 }
 
 #[derive(Clone)]
 pub struct CustomizationImpl {
+    pub aaguid: &'static [u8; key_material::AAGUID_LENGTH],
+    pub upgrade_public_key: &'static [u8; key_material::UPGRADE_PUBLIC_KEY_LENGTH],
     pub allows_pin_protocol_v1: bool,
+    pub allows_ctap1: bool,
+    pub allows_credential_dump: bool,
     pub default_cred_protect: Option<CredentialProtectionPolicy>,
     pub default_min_pin_length: u8,
     pub default_min_pin_length_rp_ids: &'static [&'static str],
     pub enforce_always_uv: bool,
+    pub always_require_up: bool,
     pub enterprise_attestation_mode: Option<EnterpriseAttestationMode>,
     pub enterprise_rp_id_list: &'static [&'static str],
     pub max_msg_size: usize,
     pub max_pin_retries: u8,
     pub use_batch_attestation: bool,
     pub use_signature_counter: bool,
+    pub signature_counter_strategy: SignatureCounterStrategy,
     pub max_cred_blob_length: usize,
     pub max_credential_count_in_list: Option<usize>,
     pub max_large_blob_array_size: usize,
     pub max_rp_ids_length: usize,
     pub max_supported_resident_keys: usize,
+    pub watchdog_timeout_ms: Option<usize>,
 }
 
 pub const DEFAULT_CUSTOMIZATION: CustomizationImpl = CustomizationImpl {
+    aaguid: key_material::AAGUID,
+    upgrade_public_key: key_material::UPGRADE_PUBLIC_KEY,
     allows_pin_protocol_v1: true,
+    allows_ctap1: true,
+    allows_credential_dump: false,
     default_cred_protect: None,
     default_min_pin_length: 4,
     default_min_pin_length_rp_ids: &[],
     enforce_always_uv: false,
+    always_require_up: false,
     enterprise_attestation_mode: None,
     enterprise_rp_id_list: &[],
     max_msg_size: 7609,
     max_pin_retries: 8,
     use_batch_attestation: false,
     use_signature_counter: true,
+    signature_counter_strategy: SignatureCounterStrategy::Global,
     max_cred_blob_length: 32,
-    max_credential_count_in_list: None,
+    max_credential_count_in_list: Some(20),
     max_large_blob_array_size: 2048,
     max_rp_ids_length: 8,
     max_supported_resident_keys: 150,
+    watchdog_timeout_ms: Some(10_000),
 };
 
 impl Customization for CustomizationImpl {
+    fn aaguid(&self) -> &'static [u8; key_material::AAGUID_LENGTH] {
+        self.aaguid
+    }
+
+    fn upgrade_public_key(&self) -> &'static [u8; key_material::UPGRADE_PUBLIC_KEY_LENGTH] {
+        self.upgrade_public_key
+    }
+
     fn allows_pin_protocol_v1(&self) -> bool {
         self.allows_pin_protocol_v1
     }
 
+    fn allows_ctap1(&self) -> bool {
+        self.allows_ctap1
+    }
+
+    fn allows_credential_dump(&self) -> bool {
+        self.allows_credential_dump
+    }
+
     fn default_cred_protect(&self) -> Option<CredentialProtectionPolicy> {
         self.default_cred_protect
     }
@@ -312,6 +425,10 @@ impl Customization for CustomizationImpl {
         self.enforce_always_uv
     }
 
+    fn always_require_up(&self) -> bool {
+        self.always_require_up
+    }
+
     fn enterprise_attestation_mode(&self) -> Option<EnterpriseAttestationMode> {
         self.enterprise_attestation_mode
     }
@@ -344,6 +461,10 @@ impl Customization for CustomizationImpl {
         self.use_signature_counter
     }
 
+    fn signature_counter_strategy(&self) -> SignatureCounterStrategy {
+        self.signature_counter_strategy
+    }
+
     fn max_cred_blob_length(&self) -> usize {
         self.max_cred_blob_length
     }
@@ -363,6 +484,10 @@ impl Customization for CustomizationImpl {
     fn max_supported_resident_keys(&self) -> usize {
         self.max_supported_resident_keys
     }
+
+    fn watchdog_timeout_ms(&self) -> Option<usize> {
+        self.watchdog_timeout_ms
+    }
 }
 
 #[cfg(feature = "std")]
@@ -390,6 +515,13 @@ pub fn is_valid(customization: &impl Customization) -> bool {
         return false;
     }
 
+    // The AAGUID must identify the model if enterprise attestation is enabled.
+    if customization.enterprise_attestation_mode().is_some()
+        && customization.aaguid() == &[0; key_material::AAGUID_LENGTH]
+    {
+        return false;
+    }
+
     // enterprise_rp_id_list() should be non-empty in vendor facilitated mode.
     if matches!(
         customization.enterprise_attestation_mode(),
@@ -416,11 +548,10 @@ pub fn is_valid(customization: &impl Customization) -> bool {
         return false;
     }
 
-    // Max credential count in list should be positive if exists.
-    if let Some(count) = customization.max_credential_count_in_list() {
-        if count < 1 {
-            return false;
-        }
+    // Max credential count in list should exist and be positive.
+    match customization.max_credential_count_in_list() {
+        Some(count) if count >= 1 => (),
+        _ => return false,
     }
 
     // Max large blob array size should not be less than 1024.
@@ -435,6 +566,13 @@ pub fn is_valid(customization: &impl Customization) -> bool {
         return false;
     }
 
+    // The watchdog timeout should be at least 3 seconds if it exists.
+    if let Some(timeout_ms) = customization.watchdog_timeout_ms() {
+        if timeout_ms < 3000 {
+            return false;
+        }
+    }
+
     true
 }
 
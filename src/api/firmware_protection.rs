@@ -12,9 +12,68 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use arrayref::{array_refs, mut_array_refs};
+
+/// Version of the running firmware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FirmwareVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+    /// Git commit the firmware was built from, all zeros if unknown.
+    pub git_hash: [u8; 20],
+}
+
+impl FirmwareVersion {
+    pub const BYTES_LENGTH: usize = 26;
+
+    /// Serializes the version numbers in big-endian, followed by the git hash.
+    pub fn to_bytes(&self) -> [u8; FirmwareVersion::BYTES_LENGTH] {
+        let mut bytes = [0; FirmwareVersion::BYTES_LENGTH];
+        let (major, minor, patch, git_hash) = mut_array_refs![&mut bytes, 2, 2, 2, 20];
+        *major = self.major.to_be_bytes();
+        *minor = self.minor.to_be_bytes();
+        *patch = self.patch.to_be_bytes();
+        *git_hash = self.git_hash;
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; FirmwareVersion::BYTES_LENGTH]) -> FirmwareVersion {
+        let (major, minor, patch, git_hash) = array_refs![bytes, 2, 2, 2, 20];
+        FirmwareVersion {
+            major: u16::from_be_bytes(*major),
+            minor: u16::from_be_bytes(*minor),
+            patch: u16::from_be_bytes(*patch),
+            git_hash: *git_hash,
+        }
+    }
+}
+
 pub trait FirmwareProtection {
     /// Locks the firmware.
     ///
     /// Returns whether the operation was successful.
     fn lock(&mut self) -> bool;
+
+    /// Returns the version of the running firmware.
+    fn firmware_version(&self) -> FirmwareVersion;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_firmware_version_bytes() {
+        let version = FirmwareVersion {
+            major: 1,
+            minor: 0x0203,
+            patch: 4,
+            git_hash: [0xA5; 20],
+        };
+        let bytes = version.to_bytes();
+        assert_eq!(bytes[..6], [0x00, 0x01, 0x02, 0x03, 0x00, 0x04]);
+        assert_eq!(bytes[6..], [0xA5; 20]);
+        assert_eq!(FirmwareVersion::from_bytes(&bytes), version);
+    }
 }
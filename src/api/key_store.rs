@@ -14,6 +14,7 @@
 
 use alloc::vec::Vec;
 use crypto::ecdsa::SecKey;
+use crypto::hkdf::hkdf_sha256;
 use persistent_store::StoreError;
 use rng256::Rng256;
 
@@ -21,7 +22,8 @@ use crate::env::Env;
 
 /// Provides storage for secret keys.
 ///
-/// Implementations may use the environment store: [`STORAGE_KEY`] is reserved for this usage.
+/// Implementations may use the environment store: [`STORAGE_KEY`] and [`PERSISTENT_STORAGE_KEY`]
+/// are reserved for this usage.
 pub trait KeyStore {
     /// Returns the AES key for key handles encryption.
     fn key_handle_encryption(&mut self) -> Result<[u8; 32], Error>;
@@ -29,6 +31,17 @@ pub trait KeyStore {
     /// Returns the key for key handles authentication.
     fn key_handle_authentication(&mut self) -> Result<[u8; 32], Error>;
 
+    /// Returns the keys for the encryption of secrets in the persistent store.
+    ///
+    /// The keys must change on reset. Encryption only protects these secrets against a dump of the
+    /// store if the keys don't depend on its content alone.
+    fn store_encryption(&mut self) -> Result<StoreEncryptionKeys, Error>;
+
+    /// Returns the keys for the encryption of secrets that persist a reset.
+    ///
+    /// The keys must not change on reset, nor be equal to the keys of [`Self::store_encryption`].
+    fn persistent_store_encryption(&mut self) -> Result<StoreEncryptionKeys, Error>;
+
     /// Derives an ECDSA private key from a seed.
     ///
     /// The result is big-endian.
@@ -41,6 +54,16 @@ pub trait KeyStore {
     fn reset(&mut self) -> Result<(), Error>;
 }
 
+/// Keys for the encryption of secrets in the persistent store.
+#[derive(Clone)]
+pub struct StoreEncryptionKeys {
+    /// AES key for encryption.
+    pub encryption: [u8; 32],
+
+    /// HMAC key for authentication.
+    pub authentication: [u8; 32],
+}
+
 /// Key store errors.
 ///
 /// They are deliberately indistinguishable to avoid leaking information.
@@ -50,8 +73,31 @@ pub struct Error;
 /// Key of the environment store reserved for the key store.
 pub const STORAGE_KEY: usize = 2046;
 
+/// Key of the environment store reserved for the key store, that persists a reset.
+pub const PERSISTENT_STORAGE_KEY: usize = 9;
+
 /// Implements a default key store using the environment rng and store.
-pub trait Helper: Env {}
+///
+/// The master keys are stored in plaintext. The store encryption keys are derived from them and
+/// from a secret of the environment, so that a dump of the store alone doesn't reveal them.
+/// Environments with a hardware key should implement [`KeyStore`] themselves.
+pub trait Helper: Env {
+    /// Returns the cache for the store encryption keys.
+    ///
+    /// It must be empty initially. Deriving the keys is costly, and they are needed for each
+    /// access to a secret in the store.
+    fn store_encryption_cache(&mut self) -> &mut StoreEncryptionCache;
+
+    /// Returns a secret outside of the store, from which the store encryption keys are derived.
+    fn store_encryption_secret(&self) -> &[u8; 32];
+}
+
+/// Cache for the store encryption keys of the default key store.
+#[derive(Default)]
+pub struct StoreEncryptionCache {
+    keys: Option<StoreEncryptionKeys>,
+    persistent_keys: Option<StoreEncryptionKeys>,
+}
 
 impl<T: Helper> KeyStore for T {
     fn key_handle_encryption(&mut self) -> Result<[u8; 32], Error> {
@@ -62,6 +108,36 @@ impl<T: Helper> KeyStore for T {
         Ok(get_master_keys(self)?.authentication)
     }
 
+    fn store_encryption(&mut self) -> Result<StoreEncryptionKeys, Error> {
+        if let Some(keys) = &self.store_encryption_cache().keys {
+            return Ok(keys.clone());
+        }
+        let master_keys = get_master_keys(self)?;
+        let keys = derive_store_encryption_keys(
+            self.store_encryption_secret(),
+            &master_keys.encryption,
+            &master_keys.authentication,
+            b"store",
+        )?;
+        self.store_encryption_cache().keys = Some(keys.clone());
+        Ok(keys)
+    }
+
+    fn persistent_store_encryption(&mut self) -> Result<StoreEncryptionKeys, Error> {
+        if let Some(keys) = &self.store_encryption_cache().persistent_keys {
+            return Ok(keys.clone());
+        }
+        let master_key = get_persistent_master_key(self)?;
+        let keys = derive_store_encryption_keys(
+            self.store_encryption_secret(),
+            &master_key,
+            &master_key,
+            b"persistent store",
+        )?;
+        self.store_encryption_cache().persistent_keys = Some(keys.clone());
+        Ok(keys)
+    }
+
     fn derive_ecdsa(&mut self, seed: &[u8; 32]) -> Result<[u8; 32], Error> {
         match SecKey::from_bytes(seed) {
             None => Err(Error),
@@ -76,6 +152,7 @@ impl<T: Helper> KeyStore for T {
     }
 
     fn reset(&mut self) -> Result<(), Error> {
+        self.store_encryption_cache().keys = None;
         Ok(self.store().remove(STORAGE_KEY)?)
     }
 }
@@ -111,6 +188,49 @@ fn get_master_keys(env: &mut impl Env) -> Result<MasterKeys, Error> {
     })
 }
 
+fn get_persistent_master_key(env: &mut impl Env) -> Result<[u8; 32], Error> {
+    let master_key = match env.store().find(PERSISTENT_STORAGE_KEY)? {
+        Some(x) => x,
+        None => {
+            let master_key = env.rng().gen_uniform_u8x32();
+            env.store().insert(PERSISTENT_STORAGE_KEY, &master_key)?;
+            master_key.to_vec()
+        }
+    };
+    if master_key.len() != 32 {
+        return Err(Error);
+    }
+    Ok(*array_ref![master_key, 0, 32])
+}
+
+/// Derives store encryption keys from master keys, with the environment secret as salt.
+///
+/// The label separates keys derived from the same master keys.
+fn derive_store_encryption_keys(
+    secret: &[u8; 32],
+    master_encryption_key: &[u8; 32],
+    master_authentication_key: &[u8; 32],
+    label: &[u8],
+) -> Result<StoreEncryptionKeys, Error> {
+    let mut keys = StoreEncryptionKeys {
+        encryption: [0; 32],
+        authentication: [0; 32],
+    };
+    let mut info = label.to_vec();
+    info.extend_from_slice(b" encryption");
+    hkdf_sha256(secret, master_encryption_key, &info, &mut keys.encryption).map_err(|_| Error)?;
+    let mut info = label.to_vec();
+    info.extend_from_slice(b" authentication");
+    hkdf_sha256(
+        secret,
+        master_authentication_key,
+        &info,
+        &mut keys.authentication,
+    )
+    .map_err(|_| Error)?;
+    Ok(keys)
+}
+
 impl From<StoreError> for Error {
     fn from(_: StoreError) -> Self {
         Error
@@ -135,6 +255,12 @@ mod test {
             Ok(authentication_key)
         );
 
+        // Store encryption keys are well-defined and stable.
+        let store_keys = key_store.store_encryption().unwrap();
+        let cached_keys = key_store.store_encryption().unwrap();
+        assert_eq!(cached_keys.encryption, store_keys.encryption);
+        assert_eq!(cached_keys.authentication, store_keys.authentication);
+
         // ECDSA seeds are well-defined and stable.
         let ecdsa_seed = key_store.generate_ecdsa_seed().unwrap();
         let ecdsa_key = key_store.derive_ecdsa(&ecdsa_seed).unwrap();
@@ -145,5 +271,31 @@ mod test {
         key_store.reset().unwrap();
         assert!(key_store.key_handle_encryption().unwrap() != encryption_key);
         assert!(key_store.key_handle_authentication().unwrap() != authentication_key);
+        let store_keys_after_reset = key_store.store_encryption().unwrap();
+        assert!(store_keys_after_reset.encryption != store_keys.encryption);
+        assert!(store_keys_after_reset.authentication != store_keys.authentication);
+    }
+
+    #[test]
+    fn test_persistent_store_encryption() {
+        let mut env = crate::env::test::TestEnv::new();
+        let key_store = env.key_store();
+        let store_keys = key_store.store_encryption().unwrap();
+        let persistent_keys = key_store.persistent_store_encryption().unwrap();
+        assert!(persistent_keys.encryption != store_keys.encryption);
+        assert!(persistent_keys.authentication != store_keys.authentication);
+
+        // Persistent keys are stable across resets and reboots.
+        key_store.reset().unwrap();
+        env.power_cycle();
+        let persistent_keys_after_reset = env.key_store().persistent_store_encryption().unwrap();
+        assert_eq!(
+            persistent_keys_after_reset.encryption,
+            persistent_keys.encryption
+        );
+        assert_eq!(
+            persistent_keys_after_reset.authentication,
+            persistent_keys.authentication
+        );
     }
 }
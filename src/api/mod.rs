@@ -17,10 +17,19 @@
 //! The [environment](crate::env::Env) is split into components. Each component has an API described
 //! by a trait. This module gathers the API of those components.
 
+pub mod aes;
 pub mod attestation_store;
+pub mod clock;
 pub mod connection;
 pub mod customization;
 pub mod firmware_protection;
 pub mod key_store;
+pub mod power;
+pub mod signature_counter;
+pub mod store_encryption;
 pub mod upgrade_storage;
 pub mod user_presence;
+pub mod user_verification;
+#[cfg(feature = "vendor_hid")]
+pub mod vendor_command;
+pub mod watchdog;
@@ -0,0 +1,78 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Power supply of the device, as far as it can tell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PowerStatus {
+    /// Whether the device is powered externally, for example over USB.
+    pub external_power: bool,
+    /// Approximate battery charge in percent, if the device has a battery and can measure it.
+    pub charge_percent: Option<u8>,
+}
+
+/// Battery charge in percent below which long operations, like firmware upgrades, are refused
+/// without external power.
+pub const LOW_BATTERY_PERCENT: u8 = 20;
+
+impl PowerStatus {
+    /// Returns whether the battery might run out during a long operation.
+    ///
+    /// Devices that can't measure their charge are never considered low.
+    pub fn is_low_battery(&self) -> bool {
+        !self.external_power
+            && self
+                .charge_percent
+                .map_or(false, |charge| charge < LOW_BATTERY_PERCENT)
+    }
+}
+
+pub trait Power {
+    /// Returns the power status of the device.
+    ///
+    /// Devices without power sensing return `None`, which is the default.
+    fn power_status(&mut self) -> Option<PowerStatus> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::test::TestEnv;
+    use crate::env::Env;
+
+    #[test]
+    fn test_power_status() {
+        let mut env = TestEnv::new();
+        assert_eq!(env.power().power_status(), None);
+        let status = PowerStatus {
+            external_power: false,
+            charge_percent: Some(15),
+        };
+        env.power().set(Some(status));
+        assert_eq!(env.power().power_status(), Some(status));
+    }
+
+    #[test]
+    fn test_is_low_battery() {
+        let status = |external_power, charge_percent| PowerStatus {
+            external_power,
+            charge_percent,
+        };
+        assert!(status(false, Some(LOW_BATTERY_PERCENT - 1)).is_low_battery());
+        assert!(!status(false, Some(LOW_BATTERY_PERCENT)).is_low_battery());
+        assert!(!status(true, Some(0)).is_low_battery());
+        assert!(!status(false, None).is_low_battery());
+    }
+}
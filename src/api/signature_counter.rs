@@ -0,0 +1,397 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::api::customization::Customization;
+use crate::ctap::INITIAL_SIGNATURE_COUNTER;
+use crate::env::Env;
+use alloc::vec::Vec;
+use arrayref::array_ref;
+use core::ops::Range;
+use crypto::sha256::Sha256;
+use crypto::util::ct_eq;
+use crypto::Hash256;
+use persistent_store::StoreError;
+use rng256::Rng256;
+
+/// How signature counters are shared between credentials.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureCounterStrategy {
+    /// All credentials share one counter.
+    ///
+    /// The counter is incremented by a random amount, so that relying parties can't tell how many
+    /// signatures happened in between.
+    Global,
+    /// Each resident credential has its own counter, incremented by one.
+    ///
+    /// The number of counters is bounded, see [`CREDENTIAL_STORAGE_KEYS`]. Non-resident
+    /// credentials, and resident credentials without a free counter, share the global counter. A
+    /// new counter starts from the global value, so the counter of a credential never decreases.
+    PerCredential,
+}
+
+/// Provides signature counters for credentials.
+///
+/// Implementations may use the environment store: [`GLOBAL_STORAGE_KEY`] and
+/// [`CREDENTIAL_STORAGE_KEYS`] are reserved for this usage.
+pub trait SignatureCounter {
+    /// Returns the signature counter of a credential.
+    fn get_counter(&mut self, credential_id: &[u8]) -> Result<u32, Error>;
+
+    /// Increments the signature counter of a credential and returns its new value.
+    ///
+    /// Counters never wrap around. Once a counter can't be incremented anymore, this returns
+    /// [`Error::Exhausted`] and signatures must be refused.
+    fn increment_counter(&mut self, credential_id: &[u8]) -> Result<u32, Error>;
+
+    /// Returns the key and value of the environment store entry for a new counter of a credential.
+    ///
+    /// Only resident credentials should get their own counter. Inserting this entry creates the
+    /// counter, which lets callers create it in the same transaction as the credential. Returns
+    /// `None` if the credential already has a counter or if no counter is free.
+    fn new_counter_entry(
+        &mut self,
+        credential_id: &[u8],
+    ) -> Result<Option<(usize, Vec<u8>)>, Error>;
+
+    /// Removes the signature counter of a credential, if it has its own.
+    fn remove_counter(&mut self, credential_id: &[u8]) -> Result<(), Error>;
+
+    /// Returns the key of the environment store holding the counter of a credential, if any.
+    ///
+    /// Removing this key removes the counter like [`Self::remove_counter`]. This lets callers
+    /// remove the counter in the same transaction as other store updates.
+    fn counter_storage_key(&mut self, credential_id: &[u8]) -> Result<Option<usize>, Error>;
+}
+
+/// Signature counter errors.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    Storage,
+    Internal,
+    /// The store has no space left.
+    NoCapacity,
+    /// The counter reached its maximum value.
+    Exhausted,
+}
+
+/// Key of the environment store reserved for the global signature counter.
+pub const GLOBAL_STORAGE_KEY: usize = 2047;
+
+/// Keys of the environment store reserved for the per-credential signature counters.
+pub const CREDENTIAL_STORAGE_KEYS: Range<usize> = 1000..1200;
+
+/// Number of keys where the counter of a credential may be stored.
+///
+/// The first key is derived from the hash of the credential ID, the others follow it.
+const NUM_CANDIDATE_KEYS: usize = 4;
+
+/// Implements the default signature counters using the environment rng, store and customization.
+pub trait Helper: Env {}
+
+impl<T: Helper> SignatureCounter for T {
+    fn get_counter(&mut self, credential_id: &[u8]) -> Result<u32, Error> {
+        match self.customization().signature_counter_strategy() {
+            SignatureCounterStrategy::Global => global_counter(self),
+            SignatureCounterStrategy::PerCredential => {
+                match find_credential_counter(self, credential_id)? {
+                    Some((_, value)) => Ok(value),
+                    None => global_counter(self),
+                }
+            }
+        }
+    }
+
+    fn increment_counter(&mut self, credential_id: &[u8]) -> Result<u32, Error> {
+        match self.customization().signature_counter_strategy() {
+            SignatureCounterStrategy::Global => increment_global_counter(self),
+            SignatureCounterStrategy::PerCredential => {
+                let (key, old_value) = match find_credential_counter(self, credential_id)? {
+                    Some(counter) => counter,
+                    None => return increment_global_counter(self),
+                };
+                let value = old_value.checked_add(1).ok_or(Error::Exhausted)?;
+                let entry = credential_counter_entry(credential_id, value);
+                self.store().insert(key, &entry)?;
+                Ok(value)
+            }
+        }
+    }
+
+    fn new_counter_entry(
+        &mut self,
+        credential_id: &[u8],
+    ) -> Result<Option<(usize, Vec<u8>)>, Error> {
+        if self.customization().signature_counter_strategy()
+            != SignatureCounterStrategy::PerCredential
+            || find_credential_counter(self, credential_id)?.is_some()
+        {
+            return Ok(None);
+        }
+        for key in candidate_keys(credential_id) {
+            if self.store().find_handle(key)?.is_none() {
+                let entry = credential_counter_entry(credential_id, global_counter(self)?);
+                return Ok(Some((key, entry)));
+            }
+        }
+        // Without a free counter, the credential keeps sharing the global one.
+        Ok(None)
+    }
+
+    fn remove_counter(&mut self, credential_id: &[u8]) -> Result<(), Error> {
+        if let Some(key) = self.counter_storage_key(credential_id)? {
+            self.store().remove(key)?;
+        }
+        Ok(())
+    }
+
+    fn counter_storage_key(&mut self, credential_id: &[u8]) -> Result<Option<usize>, Error> {
+        Ok(find_credential_counter(self, credential_id)?.map(|(key, _)| key))
+    }
+}
+
+fn increment_global_counter(env: &mut impl Env) -> Result<u32, Error> {
+    let increment = env.rng().gen_uniform_u32x8()[0] % 8 + 1;
+    let value = global_counter(env)?
+        .checked_add(increment)
+        .ok_or(Error::Exhausted)?;
+    env.store()
+        .insert(GLOBAL_STORAGE_KEY, &value.to_ne_bytes())?;
+    Ok(value)
+}
+
+fn global_counter(env: &mut impl Env) -> Result<u32, Error> {
+    match env.store().find(GLOBAL_STORAGE_KEY)? {
+        None => Ok(INITIAL_SIGNATURE_COUNTER),
+        Some(value) if value.len() == 4 => Ok(u32::from_ne_bytes(*array_ref![value, 0, 4])),
+        Some(_) => Err(Error::Internal),
+    }
+}
+
+/// Returns the keys where the counter of a credential may be stored.
+fn candidate_keys(credential_id: &[u8]) -> impl Iterator<Item = usize> {
+    let num_keys = CREDENTIAL_STORAGE_KEYS.end - CREDENTIAL_STORAGE_KEYS.start;
+    let credential_hash = Sha256::hash(credential_id);
+    let first = u32::from_be_bytes(*array_ref![credential_hash, 0, 4]) as usize % num_keys;
+    (0..NUM_CANDIDATE_KEYS).map(move |i| CREDENTIAL_STORAGE_KEYS.start + (first + i) % num_keys)
+}
+
+/// Encodes a credential counter as the SHA-256 of the credential ID followed by its value.
+fn credential_counter_entry(credential_id: &[u8], value: u32) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(36);
+    entry.extend_from_slice(&Sha256::hash(credential_id));
+    entry.extend_from_slice(&value.to_ne_bytes());
+    entry
+}
+
+/// Returns the key and value of the counter of a credential, if it exists.
+///
+/// Only the candidate keys of the credential are read. The stored hash tells which credential
+/// owns a counter, since candidate keys are shared between credentials.
+fn find_credential_counter(
+    env: &mut impl Env,
+    credential_id: &[u8],
+) -> Result<Option<(usize, u32)>, Error> {
+    let credential_hash = Sha256::hash(credential_id);
+    for key in candidate_keys(credential_id) {
+        let value = match env.store().find(key)? {
+            None => continue,
+            Some(value) => value,
+        };
+        if value.len() != 36 {
+            return Err(Error::Internal);
+        }
+        if ct_eq(&value[..32], &credential_hash) {
+            let counter = u32::from_ne_bytes(*array_ref![value, 32, 4]);
+            return Ok(Some((key, counter)));
+        }
+    }
+    Ok(None)
+}
+
+impl From<StoreError> for Error {
+    fn from(error: StoreError) -> Self {
+        match error {
+            StoreError::NoCapacity | StoreError::NoLifetime => Error::NoCapacity,
+            StoreError::InvalidArgument | StoreError::InvalidStorage => Error::Internal,
+            StoreError::StorageError | StoreError::Busy => Error::Storage,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::test::TestEnv;
+
+    #[test]
+    fn test_global_counter() {
+        let mut env = TestEnv::new();
+        let counter = env.signature_counter();
+        let mut last_value = counter.get_counter(&[0x01]).unwrap();
+        assert_eq!(last_value, INITIAL_SIGNATURE_COUNTER);
+        for _ in 0..100 {
+            let value = counter.increment_counter(&[0x01]).unwrap();
+            assert!(value > last_value);
+            assert_eq!(counter.get_counter(&[0x01]), Ok(value));
+            // All credentials share the same counter.
+            assert_eq!(counter.get_counter(&[0x02]), Ok(value));
+            last_value = value;
+        }
+    }
+
+    fn create_counter(env: &mut TestEnv, credential_id: &[u8]) {
+        let (key, value) = env
+            .signature_counter()
+            .new_counter_entry(credential_id)
+            .unwrap()
+            .unwrap();
+        env.store().insert(key, &value).unwrap();
+    }
+
+    #[test]
+    fn test_per_credential_counters_are_independent() {
+        let mut env = TestEnv::new();
+        env.customization_mut()
+            .set_signature_counter_strategy(SignatureCounterStrategy::PerCredential);
+        create_counter(&mut env, &[0x01]);
+        create_counter(&mut env, &[0x02]);
+        let counter = env.signature_counter();
+        assert_eq!(counter.get_counter(&[0x01]), Ok(INITIAL_SIGNATURE_COUNTER));
+        assert_eq!(counter.get_counter(&[0x02]), Ok(INITIAL_SIGNATURE_COUNTER));
+
+        for i in 1..=3 {
+            assert_eq!(
+                counter.increment_counter(&[0x01]),
+                Ok(INITIAL_SIGNATURE_COUNTER + i)
+            );
+        }
+        assert_eq!(
+            counter.increment_counter(&[0x02]),
+            Ok(INITIAL_SIGNATURE_COUNTER + 1)
+        );
+        assert_eq!(
+            counter.get_counter(&[0x01]),
+            Ok(INITIAL_SIGNATURE_COUNTER + 3)
+        );
+        assert_eq!(
+            counter.get_counter(&[0x02]),
+            Ok(INITIAL_SIGNATURE_COUNTER + 1)
+        );
+
+        assert_eq!(counter.remove_counter(&[0x01]), Ok(()));
+        assert_eq!(counter.get_counter(&[0x01]), Ok(INITIAL_SIGNATURE_COUNTER));
+        assert_eq!(
+            counter.get_counter(&[0x02]),
+            Ok(INITIAL_SIGNATURE_COUNTER + 1)
+        );
+    }
+
+    #[test]
+    fn test_credentials_without_counter_share_global_counter() {
+        let mut env = TestEnv::new();
+        env.customization_mut()
+            .set_signature_counter_strategy(SignatureCounterStrategy::PerCredential);
+        let value = env.signature_counter().increment_counter(&[0x01]).unwrap();
+        assert!(value > INITIAL_SIGNATURE_COUNTER);
+        assert_eq!(env.signature_counter().get_counter(&[0x02]), Ok(value));
+        for key in CREDENTIAL_STORAGE_KEYS {
+            assert_eq!(env.store().find_handle(key), Ok(None));
+        }
+
+        // A new counter starts from the global value.
+        create_counter(&mut env, &[0x01]);
+        assert_eq!(
+            env.signature_counter().increment_counter(&[0x01]),
+            Ok(value + 1)
+        );
+        assert_eq!(env.signature_counter().get_counter(&[0x02]), Ok(value));
+    }
+
+    #[test]
+    fn test_new_counter_entry() {
+        let mut env = TestEnv::new();
+        assert_eq!(env.signature_counter().new_counter_entry(&[0x01]), Ok(None));
+
+        env.customization_mut()
+            .set_signature_counter_strategy(SignatureCounterStrategy::PerCredential);
+        let (key, _) = env
+            .signature_counter()
+            .new_counter_entry(&[0x01])
+            .unwrap()
+            .unwrap();
+        assert_eq!(candidate_keys(&[0x01]).next(), Some(key));
+        create_counter(&mut env, &[0x01]);
+        assert_eq!(
+            env.signature_counter().counter_storage_key(&[0x01]),
+            Ok(Some(key))
+        );
+        // A credential gets at most one counter.
+        assert_eq!(env.signature_counter().new_counter_entry(&[0x01]), Ok(None));
+    }
+
+    #[test]
+    fn test_per_credential_counters_are_bounded() {
+        let mut env = TestEnv::new();
+        env.customization_mut()
+            .set_signature_counter_strategy(SignatureCounterStrategy::PerCredential);
+        // Other credentials use all candidate keys.
+        let mut other_id = 0u32;
+        for key in candidate_keys(&[0x01]) {
+            other_id += 1;
+            let entry =
+                credential_counter_entry(&other_id.to_ne_bytes(), INITIAL_SIGNATURE_COUNTER);
+            env.store().insert(key, &entry).unwrap();
+        }
+        assert_eq!(env.signature_counter().new_counter_entry(&[0x01]), Ok(None));
+
+        // A freed candidate key goes to the next credential.
+        let freed_key = candidate_keys(&[0x01]).last().unwrap();
+        env.store().remove(freed_key).unwrap();
+        assert_eq!(
+            env.signature_counter()
+                .new_counter_entry(&[0x01])
+                .unwrap()
+                .map(|(key, _)| key),
+            Some(freed_key)
+        );
+        create_counter(&mut env, &[0x01]);
+        assert_eq!(
+            env.signature_counter().increment_counter(&[0x01]),
+            Ok(INITIAL_SIGNATURE_COUNTER + 1)
+        );
+    }
+
+    #[test]
+    fn test_counters_do_not_wrap() {
+        let mut env = TestEnv::new();
+        env.store()
+            .insert(GLOBAL_STORAGE_KEY, &u32::MAX.to_ne_bytes())
+            .unwrap();
+        assert_eq!(
+            env.signature_counter().increment_counter(&[0x01]),
+            Err(Error::Exhausted)
+        );
+        assert_eq!(env.signature_counter().get_counter(&[0x01]), Ok(u32::MAX));
+
+        env.customization_mut()
+            .set_signature_counter_strategy(SignatureCounterStrategy::PerCredential);
+        create_counter(&mut env, &[0x01]);
+        assert_eq!(
+            env.signature_counter().increment_counter(&[0x01]),
+            Err(Error::Exhausted)
+        );
+        assert_eq!(env.signature_counter().get_counter(&[0x01]), Ok(u32::MAX));
+    }
+}
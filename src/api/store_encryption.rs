@@ -0,0 +1,344 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encryption at rest of store entries.
+//!
+//! Entry keys stay in plaintext, their values are encrypted and authenticated. The keys of the
+//! encryption come from the [key store](crate::api::key_store::KeyStore).
+
+use crate::api::key_store::{self, KeyStore, StoreEncryptionKeys};
+use crate::env::Env;
+use alloc::vec::Vec;
+use arrayref::array_ref;
+use core::ops::Range;
+use crypto::aes256::EncryptionKey;
+use crypto::ctr::ctr_encrypt;
+use crypto::hmac::{hmac_256, verify_hmac_256};
+use crypto::sha256::Sha256;
+use persistent_store::{fragment, StoreError, StoreResult};
+use rng256::Rng256;
+
+/// Version number of encrypted values.
+const ENCRYPTED_VALUE_VERSION: u8 = 0x01;
+
+const NONCE_LENGTH: usize = 16;
+const TAG_LENGTH: usize = 32;
+
+/// Number of bytes an encrypted value is longer than its plaintext.
+pub const ENCRYPTION_OVERHEAD: usize = 1 + NONCE_LENGTH + TAG_LENGTH;
+
+/// Encrypts and authenticates values before they are written to the store.
+///
+/// An encrypted value consists of:
+/// -  1 byte : version number,
+/// - 16 bytes: initial counter block for AES-256-CTR,
+/// -  n bytes: encrypted plaintext,
+/// - 32 bytes: HMAC-SHA256 over the store key (4 bytes big-endian) and everything else.
+///
+/// Authenticating the store key prevents moving a value to another entry.
+pub struct StoreEncryption {
+    aes_enc_key: EncryptionKey,
+    hmac_key: [u8; 32],
+}
+
+impl StoreEncryption {
+    /// Creates the encryption of entries that are removed on reset.
+    pub fn new(env: &mut impl Env) -> Result<Self, key_store::Error> {
+        Ok(Self::from_keys(&env.key_store().store_encryption()?))
+    }
+
+    /// Creates the encryption of entries that persist a reset.
+    pub fn new_persistent(env: &mut impl Env) -> Result<Self, key_store::Error> {
+        Ok(Self::from_keys(
+            &env.key_store().persistent_store_encryption()?,
+        ))
+    }
+
+    fn from_keys(keys: &StoreEncryptionKeys) -> Self {
+        StoreEncryption {
+            aes_enc_key: EncryptionKey::new(&keys.encryption),
+            hmac_key: keys.authentication,
+        }
+    }
+
+    /// Encrypts the value of an entry with a random nonce.
+    pub fn encrypt(&self, rng: &mut impl Rng256, key: usize, plaintext: &[u8]) -> Vec<u8> {
+        let random_bytes = rng.gen_uniform_u8x32();
+        let nonce = *array_ref!(random_bytes, 0, NONCE_LENGTH);
+        let mut value = Vec::with_capacity(plaintext.len() + ENCRYPTION_OVERHEAD);
+        value.push(ENCRYPTED_VALUE_VERSION);
+        value.extend_from_slice(&nonce);
+        value.extend_from_slice(plaintext);
+        ctr_encrypt(&self.aes_enc_key, nonce, &mut value[1 + NONCE_LENGTH..]);
+        let tag = self.tag(key, &value);
+        value.extend_from_slice(&tag);
+        value
+    }
+
+    /// Decrypts the value of an entry.
+    ///
+    /// Returns `None` if the value is not in the encrypted format or fails authentication, for
+    /// example because it was written for another entry.
+    pub fn decrypt(&self, key: usize, value: &[u8]) -> Option<Vec<u8>> {
+        if value.len() < ENCRYPTION_OVERHEAD || value[0] != ENCRYPTED_VALUE_VERSION {
+            return None;
+        }
+        let tag_start = value.len() - TAG_LENGTH;
+        if !verify_hmac_256::<Sha256>(
+            &self.hmac_key,
+            &authenticated_data(key, &value[..tag_start]),
+            array_ref!(value, tag_start, TAG_LENGTH),
+        ) {
+            return None;
+        }
+        let nonce = *array_ref!(value, 1, NONCE_LENGTH);
+        let mut plaintext = value[1 + NONCE_LENGTH..tag_start].to_vec();
+        ctr_encrypt(&self.aes_enc_key, nonce, &mut plaintext);
+        Some(plaintext)
+    }
+
+    fn tag(&self, key: usize, value: &[u8]) -> [u8; TAG_LENGTH] {
+        hmac_256::<Sha256>(&self.hmac_key, &authenticated_data(key, value))
+    }
+}
+
+/// Prefixes the encrypted value with its store key.
+fn authenticated_data(key: usize, value: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(4 + value.len());
+    data.extend_from_slice(&(key as u32).to_be_bytes());
+    data.extend_from_slice(value);
+    data
+}
+
+/// Store of the environment, encrypting values on write and decrypting them on read.
+///
+/// It is meant to be created once per operation, since creating it looks up the keys. Values that
+/// fail decryption are reported as [`StoreError::InvalidStorage`].
+pub struct EncryptedStore<'a, E: Env> {
+    env: &'a mut E,
+    encryption: StoreEncryption,
+}
+
+impl<'a, E: Env> EncryptedStore<'a, E> {
+    /// Wraps the store for entries that are removed on reset.
+    pub fn new(env: &'a mut E) -> Result<Self, key_store::Error> {
+        let encryption = StoreEncryption::new(env)?;
+        Ok(EncryptedStore { env, encryption })
+    }
+
+    /// Wraps the store for entries that persist a reset.
+    pub fn new_persistent(env: &'a mut E) -> Result<Self, key_store::Error> {
+        let encryption = StoreEncryption::new_persistent(env)?;
+        Ok(EncryptedStore { env, encryption })
+    }
+
+    /// Returns the decrypted value of an entry.
+    pub fn find(&mut self, key: usize) -> StoreResult<Option<Vec<u8>>> {
+        match self.env.store().find(key)? {
+            None => Ok(None),
+            Some(value) => decrypted(self.encryption.decrypt(key, &value)),
+        }
+    }
+
+    /// Encrypts a value and inserts it.
+    pub fn insert(&mut self, key: usize, plaintext: &[u8]) -> StoreResult<()> {
+        let value = self.encrypt(key, plaintext);
+        self.env.store().insert(key, &value)
+    }
+
+    /// Returns the decrypted value of a sequence of keys.
+    ///
+    /// The value is encrypted as a whole before being split, see [`fragment::read`].
+    pub fn read_fragments(&mut self, keys: &Range<usize>) -> StoreResult<Option<Vec<u8>>> {
+        match fragment::read(self.env.store(), keys)? {
+            None => Ok(None),
+            Some(value) => decrypted(self.encryption.decrypt(keys.start, &value)),
+        }
+    }
+
+    /// Encrypts a value and writes it to a sequence of keys.
+    ///
+    /// As with [`fragment::write`], an empty value removes all keys.
+    pub fn write_fragments(&mut self, keys: &Range<usize>, plaintext: &[u8]) -> StoreResult<()> {
+        let value = self.encrypt_fragments(keys, plaintext);
+        fragment::write(self.env.store(), keys, &value)
+    }
+
+    /// Returns the value to store for an entry, for use in transactions.
+    pub fn encrypt(&mut self, key: usize, plaintext: &[u8]) -> Vec<u8> {
+        self.encryption.encrypt(self.env.rng(), key, plaintext)
+    }
+
+    /// Returns the value to split over a sequence of keys, for use in transactions.
+    ///
+    /// An empty value stays empty, so that writing it removes all keys.
+    pub fn encrypt_fragments(&mut self, keys: &Range<usize>, plaintext: &[u8]) -> Vec<u8> {
+        if plaintext.is_empty() {
+            return Vec::new();
+        }
+        self.encrypt(keys.start, plaintext)
+    }
+
+    /// Encrypts the value of an entry if it was stored in plaintext by a previous version.
+    ///
+    /// The value is only considered plaintext if it fails decryption and `is_plaintext` accepts it.
+    pub fn encrypt_plaintext(
+        &mut self,
+        key: usize,
+        is_plaintext: impl Fn(&[u8]) -> bool,
+    ) -> StoreResult<()> {
+        if let Some(value) = self.env.store().find(key)? {
+            if self.encryption.decrypt(key, &value).is_none() && is_plaintext(&value) {
+                self.insert(key, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encrypts the value of a sequence of keys if it was stored in plaintext by a previous version.
+    ///
+    /// See [`Self::encrypt_plaintext`].
+    pub fn encrypt_plaintext_fragments(
+        &mut self,
+        keys: &Range<usize>,
+        is_plaintext: impl Fn(&[u8]) -> bool,
+    ) -> StoreResult<()> {
+        if let Some(value) = fragment::read(self.env.store(), keys)? {
+            if self.encryption.decrypt(keys.start, &value).is_none() && is_plaintext(&value) {
+                self.write_fragments(keys, &value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn decrypted(plaintext: Option<Vec<u8>>) -> StoreResult<Option<Vec<u8>>> {
+    plaintext.map(Some).ok_or(StoreError::InvalidStorage)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::test::TestEnv;
+    use alloc::vec;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let mut env = TestEnv::new();
+        let encryption = StoreEncryption::new(&mut env).unwrap();
+        for plaintext in [&[][..], &[0x5C; 17][..], &[0xA3; 100][..]].iter() {
+            let value = encryption.encrypt(env.rng(), 5, plaintext);
+            assert_eq!(value.len(), plaintext.len() + ENCRYPTION_OVERHEAD);
+            assert_eq!(encryption.decrypt(5, &value).as_deref(), Some(*plaintext));
+        }
+    }
+
+    #[test]
+    fn test_decrypt_rejects_tampering() {
+        let mut env = TestEnv::new();
+        let encryption = StoreEncryption::new(&mut env).unwrap();
+        let value = encryption.encrypt(env.rng(), 5, &[0x5C; 32]);
+        for i in 0..value.len() {
+            let mut modified_value = value.clone();
+            modified_value[i] ^= 0x01;
+            assert_eq!(encryption.decrypt(5, &modified_value), None);
+        }
+        assert_eq!(encryption.decrypt(5, &value[..value.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_decrypt_rejects_other_key() {
+        let mut env = TestEnv::new();
+        let encryption = StoreEncryption::new(&mut env).unwrap();
+        let value = encryption.encrypt(env.rng(), 5, &[0x5C; 32]);
+        assert_eq!(encryption.decrypt(6, &value), None);
+    }
+
+    #[test]
+    fn test_keys_change_on_reset() {
+        let mut env = TestEnv::new();
+        let encryption = StoreEncryption::new(&mut env).unwrap();
+        let persistent_encryption = StoreEncryption::new_persistent(&mut env).unwrap();
+        let value = encryption.encrypt(env.rng(), 5, &[0x5C; 32]);
+        let persistent_value = persistent_encryption.encrypt(env.rng(), 5, &[0x5C; 32]);
+        assert_eq!(persistent_encryption.decrypt(5, &value), None);
+        env.key_store().reset().unwrap();
+        let encryption = StoreEncryption::new(&mut env).unwrap();
+        let persistent_encryption = StoreEncryption::new_persistent(&mut env).unwrap();
+        assert_eq!(encryption.decrypt(5, &value), None);
+        assert_eq!(
+            persistent_encryption
+                .decrypt(5, &persistent_value)
+                .as_deref(),
+            Some(&[0x5C; 32][..])
+        );
+    }
+
+    #[test]
+    fn test_encrypted_store() {
+        let mut env = TestEnv::new();
+        let mut store = EncryptedStore::new(&mut env).unwrap();
+        assert_eq!(store.find(5), Ok(None));
+        store.insert(5, &[0x5C; 32]).unwrap();
+        assert_eq!(store.find(5), Ok(Some(vec![0x5C; 32])));
+        assert_eq!(
+            env.store().find(5).unwrap().unwrap().len(),
+            32 + ENCRYPTION_OVERHEAD
+        );
+
+        // Values moved to another entry are rejected.
+        let value = env.store().find(5).unwrap().unwrap();
+        env.store().insert(6, &value).unwrap();
+        let mut store = EncryptedStore::new(&mut env).unwrap();
+        assert_eq!(store.find(6), Err(StoreError::InvalidStorage));
+    }
+
+    #[test]
+    fn test_encrypted_store_fragments() {
+        let mut env = TestEnv::new();
+        let keys = 10..14;
+        let plaintext = vec![0xA3; 1500];
+        let mut store = EncryptedStore::new(&mut env).unwrap();
+        assert_eq!(store.read_fragments(&keys), Ok(None));
+        store.write_fragments(&keys, &plaintext).unwrap();
+        assert_eq!(store.read_fragments(&keys), Ok(Some(plaintext)));
+        assert!(env.store().find(11).unwrap().is_some());
+
+        // An empty value removes all fragments.
+        let mut store = EncryptedStore::new(&mut env).unwrap();
+        store.write_fragments(&keys, &[]).unwrap();
+        assert_eq!(store.read_fragments(&keys), Ok(None));
+        assert_eq!(env.store().find(10), Ok(None));
+    }
+
+    #[test]
+    fn test_encrypt_plaintext() {
+        let mut env = TestEnv::new();
+        env.store().insert(5, &[0x5C; 32]).unwrap();
+        env.store().insert(6, &[0x5C; 3]).unwrap();
+        let mut store = EncryptedStore::new(&mut env).unwrap();
+        assert_eq!(store.find(5), Err(StoreError::InvalidStorage));
+        let is_plaintext = |value: &[u8]| value.len() == 32;
+        store.encrypt_plaintext(5, is_plaintext).unwrap();
+        store.encrypt_plaintext(6, is_plaintext).unwrap();
+        assert_eq!(store.find(5), Ok(Some(vec![0x5C; 32])));
+        assert_eq!(store.find(6), Err(StoreError::InvalidStorage));
+
+        // Encrypted values are left as is.
+        let value = env.store().find(5).unwrap().unwrap();
+        let mut store = EncryptedStore::new(&mut env).unwrap();
+        store.encrypt_plaintext(5, |_| true).unwrap();
+        assert_eq!(env.store().find(5), Ok(Some(value)));
+    }
+}
@@ -16,9 +16,24 @@
 #![cfg_attr(feature = "std", allow(dead_code))]
 
 use alloc::vec::Vec;
+use arrayref::array_ref;
+use byteorder::{ByteOrder, LittleEndian};
 use core::iter::Iterator;
+use crypto::sha256::Sha256;
+use crypto::{ecdsa, Hash256};
 use persistent_store::{StorageError, StorageResult};
 
+/// Offset of the signed part of the upgrade metadata.
+///
+/// The metadata is a page starting with:
+/// - 32 B upgrade hash (SHA256)
+/// - 64 B signature,
+/// that are not signed over. The second part is included in the signature with
+/// -  8 B version and
+/// -  4 B partition address in little endian encoding
+/// written at this offset.
+pub const METADATA_SIGN_OFFSET: usize = 0x800;
+
 /// Reads a slice from a list of slices.
 ///
 /// The returned slice contains the interval `[start, start+length)`.
@@ -217,9 +232,98 @@ impl Partition {
     }
 }
 
+/// Progress of a bundle written in consecutive chunks.
+///
+/// Only the written length is tracked. The declared hash is checked once against the whole bundle
+/// when the install is finalized, so that no hasher lives across chunks.
+pub struct ChunkedWrite {
+    total_length: usize,
+    hash: [u8; 32],
+    written: usize,
+}
+
+impl ChunkedWrite {
+    pub fn new(total_length: usize, hash: [u8; 32]) -> ChunkedWrite {
+        ChunkedWrite {
+            total_length,
+            hash,
+            written: 0,
+        }
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// Checks that a chunk of the given length continues the write.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StorageError::OutOfBounds`] if the chunk is empty, leaves a gap, overlaps written
+    /// data, or exceeds the declared length.
+    pub fn check_chunk(&self, offset: usize, length: usize) -> StorageResult<()> {
+        if length == 0 || offset != self.written || length > self.total_length - self.written {
+            return Err(StorageError::OutOfBounds);
+        }
+        Ok(())
+    }
+
+    /// Records a chunk that passed [`Self::check_chunk`] and was written.
+    pub fn commit_chunk(&mut self, length: usize) {
+        self.written += length;
+    }
+
+    /// Checks the hash of the whole bundle against the declared hash.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StorageError::CustomError`] if the hashes don't match.
+    pub fn check_hash(&self, computed_hash: &[u8; 32]) -> StorageResult<()> {
+        if computed_hash != &self.hash {
+            return Err(StorageError::CustomError);
+        }
+        Ok(())
+    }
+}
+
+/// Parses the metadata, returns the hash.
+pub fn parse_metadata_hash(data: &[u8]) -> &[u8; 32] {
+    array_ref!(data, 0, 32)
+}
+
+/// Parses the metadata, returns the signature.
+pub fn parse_metadata_signature(data: &[u8]) -> &[u8; 64] {
+    array_ref!(data, 32, 64)
+}
+
+/// Parses the metadata, returns the firmware version.
+pub fn parse_metadata_version(data: &[u8]) -> u64 {
+    LittleEndian::read_u64(&data[METADATA_SIGN_OFFSET..][..8])
+}
+
+/// Verifies the signature over the given hash.
+///
+/// The public key is an uncompressed P-256 point, and the hash is a SHA256.
+pub fn verify_signature(
+    signature_bytes: &[u8; 64],
+    public_key_bytes: &[u8],
+    signed_hash: &[u8; 32],
+) -> StorageResult<()> {
+    let signature =
+        ecdsa::Signature::from_bytes(signature_bytes).ok_or(StorageError::CustomError)?;
+    let public_key = ecdsa::PubKey::from_bytes_uncompressed(public_key_bytes)
+        .ok_or(StorageError::CustomError)?;
+    if !public_key.verify_hash_vartime(signed_hash, &signature) {
+        return Err(StorageError::CustomError);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::env::test::TestEnv;
 
     #[test]
     fn find_slice_ok() {
@@ -381,4 +485,83 @@ mod tests {
         let partial_range = partition.ranges_from(0x30000);
         assert_eq!(partial_range[0], ModRange::new(0x30000, 0x30000));
     }
+
+    #[test]
+    fn test_verify_signature() {
+        let mut env = TestEnv::new();
+        let private_key = ecdsa::SecKey::gensk(env.rng());
+        let message = [0x44; 64];
+        let signed_hash = Sha256::hash(&message);
+        let signature = private_key.sign_rfc6979::<Sha256>(&message);
+
+        let mut signature_bytes = [0; ecdsa::Signature::BYTES_LENGTH];
+        signature.to_bytes(&mut signature_bytes);
+
+        let public_key = private_key.genpk();
+        let mut public_key_bytes = [0; 65];
+        public_key.to_bytes_uncompressed(&mut public_key_bytes);
+
+        assert_eq!(
+            verify_signature(&signature_bytes, &public_key_bytes, &signed_hash),
+            Ok(())
+        );
+        assert_eq!(
+            verify_signature(&signature_bytes, &public_key_bytes, &[0x55; 32]),
+            Err(StorageError::CustomError)
+        );
+        public_key_bytes[0] ^= 0x01;
+        assert_eq!(
+            verify_signature(&signature_bytes, &public_key_bytes, &signed_hash),
+            Err(StorageError::CustomError)
+        );
+        public_key_bytes[0] ^= 0x01;
+        signature_bytes[0] ^= 0x01;
+        assert_eq!(
+            verify_signature(&signature_bytes, &public_key_bytes, &signed_hash),
+            Err(StorageError::CustomError)
+        );
+    }
+
+    #[test]
+    fn test_chunked_write() {
+        let data = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let mut chunked_write = ChunkedWrite::new(data.len(), Sha256::hash(&data));
+        assert_eq!(chunked_write.written(), 0);
+        assert_eq!(chunked_write.check_chunk(0, 2), Ok(()));
+        chunked_write.commit_chunk(2);
+        assert_eq!(chunked_write.written(), 2);
+
+        // Gaps, overlaps and overflows are rejected.
+        assert_eq!(
+            chunked_write.check_chunk(4, 2),
+            Err(StorageError::OutOfBounds)
+        );
+        assert_eq!(
+            chunked_write.check_chunk(0, 2),
+            Err(StorageError::OutOfBounds)
+        );
+        assert_eq!(
+            chunked_write.check_chunk(1, 2),
+            Err(StorageError::OutOfBounds)
+        );
+        assert_eq!(
+            chunked_write.check_chunk(2, 5),
+            Err(StorageError::OutOfBounds)
+        );
+        assert_eq!(
+            chunked_write.check_chunk(2, 0),
+            Err(StorageError::OutOfBounds)
+        );
+
+        assert_eq!(chunked_write.check_chunk(2, 4), Ok(()));
+        chunked_write.commit_chunk(4);
+        assert_eq!(chunked_write.written(), data.len());
+
+        // The whole bundle must match the declared hash.
+        assert_eq!(chunked_write.check_hash(&Sha256::hash(&data)), Ok(()));
+        assert_eq!(
+            chunked_write.check_hash(&Sha256::hash(&data[..5])),
+            Err(StorageError::CustomError)
+        );
+    }
 }
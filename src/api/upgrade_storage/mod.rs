@@ -13,10 +13,30 @@
 // limitations under the License.
 
 use alloc::vec::Vec;
-use persistent_store::StorageResult;
+use helper::ChunkedWrite;
+use persistent_store::{StorageError, StorageResult};
 
 pub(crate) mod helper;
 
+/// Firmware slots of an A/B upgrade scheme.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+/// State of a firmware slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlotMetadata {
+    /// Firmware version, as written in the slot metadata.
+    pub version: u64,
+    /// Whether the slot content was verified.
+    ///
+    /// The running slot is always valid. The other slot is only valid after
+    /// [`UpgradeStorage::finalize_install`] succeeded, and until it is written again.
+    pub valid: bool,
+}
+
 /// Accessors to storage locations used for upgrading from a CTAP command.
 pub trait UpgradeStorage {
     /// Processes the given data as part of an upgrade.
@@ -29,6 +49,53 @@ pub trait UpgradeStorage {
     /// - Returns [`StorageError::CustomError`] if any Metadata or other check fails.
     fn write_bundle(&mut self, offset: usize, data: Vec<u8>) -> StorageResult<()>;
 
+    /// Declares the length and SHA256 of a bundle that is then written with [`Self::write_chunk`].
+    ///
+    /// Any previous chunked write is abandoned.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StorageError::OutOfBounds`] if the length doesn't match the bundle size.
+    fn start_chunked_write(&mut self, total_length: usize, hash: [u8; 32]) -> StorageResult<()>;
+
+    /// Writes the next chunk of the declared bundle.
+    ///
+    /// Chunks must be written in order: the offset of each chunk is [`Self::written_so_far`]. The
+    /// last chunk finalizes the install like [`Self::write_bundle`], which then also checks the
+    /// hash of the whole bundle against the declared hash.
+    ///
+    /// # Errors
+    ///
+    /// - Returns [`StorageError::OutOfBounds`] if the chunk leaves a gap, overlaps written data or
+    ///   exceeds the declared length. Nothing is written, and the host can resume from
+    ///   [`Self::written_so_far`].
+    /// - Returns [`StorageError::CustomError`] if no bundle was declared, the hash doesn't match,
+    ///   or any check of [`Self::write_bundle`] fails.
+    fn write_chunk(&mut self, offset: usize, data: Vec<u8>) -> StorageResult<()> {
+        let length = data.len();
+        self.chunked_write()
+            .ok_or(StorageError::CustomError)?
+            .check_chunk(offset, length)?;
+        self.write_bundle(offset, data)?;
+        if let Some(chunked_write) = self.chunked_write_mut() {
+            chunked_write.commit_chunk(length);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of bytes of the declared bundle that were successfully written.
+    ///
+    /// This is 0 if no bundle was declared.
+    fn written_so_far(&self) -> usize {
+        self.chunked_write().map_or(0, ChunkedWrite::written)
+    }
+
+    /// Returns the progress of the declared bundle, if any.
+    fn chunked_write(&self) -> Option<&ChunkedWrite>;
+
+    /// Returns the progress of the declared bundle mutably, if any.
+    fn chunked_write_mut(&mut self) -> Option<&mut ChunkedWrite>;
+
     /// Returns an identifier for the requested bundle.
     ///
     /// Use this to determine whether you are writing to A or B.
@@ -36,4 +103,28 @@ pub trait UpgradeStorage {
 
     /// Returns the currently running firmware version.
     fn running_firmware_version(&self) -> u64;
+
+    /// Returns the slot of the running firmware. Bundles are written to the other slot.
+    fn active_slot(&self) -> Slot;
+
+    /// Returns the metadata of the given slot.
+    fn slot_metadata(&self, slot: Slot) -> SlotMetadata;
+
+    /// Returns whether a firmware of this version may be installed.
+    ///
+    /// Firmware older than the running one is refused, to prevent downgrade attacks.
+    fn can_install(&self, version: u64) -> bool {
+        version >= self.running_firmware_version()
+    }
+
+    /// Verifies the written bundle and marks its slot valid.
+    ///
+    /// This is called by [`Self::write_bundle`] when the last slice is written. If a bundle was
+    /// declared with [`Self::start_chunked_write`], its hash is checked as well.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StorageError::CustomError`] if the version can't be installed, or if the hash or
+    /// signature of the bundle doesn't match. The slot is then left invalid.
+    fn finalize_install(&mut self) -> StorageResult<()>;
 }
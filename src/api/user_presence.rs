@@ -38,8 +38,9 @@ pub trait UserPresence {
     /// Must be called between calls to [`Self::check_init`] and [`Self::check_complete`].
     fn wait_with_timeout(&mut self, timeout: Milliseconds<ClockInt>) -> UserPresenceResult;
 
-    /// Finalizes a user presence check.
+    /// Finalizes a user presence check with its result.
     ///
-    /// Must be called after [`Self::check_init`].
-    fn check_complete(&mut self);
+    /// Must be called after [`Self::check_init`], on success as well as on failure. Indicators such
+    /// as LEDs should be back to idle when this returns.
+    fn check_complete(&mut self, result: &UserPresenceResult);
 }
@@ -0,0 +1,102 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UserVerificationError {
+    /// The user was not recognized.
+    Invalid,
+    /// There are no attempts left, see [`UserVerification::retries`].
+    Blocked,
+    /// The user did not interact in time.
+    Timeout,
+}
+
+pub type UserVerificationResult = Result<(), UserVerificationError>;
+
+/// Built-in user verification, such as a fingerprint reader.
+///
+/// Devices without a built-in method verify users with their PIN only, which is the default.
+pub trait UserVerification {
+    /// Returns whether the device has a built-in user verification method.
+    fn is_supported(&mut self) -> bool {
+        false
+    }
+
+    /// Returns whether a user is enrolled, so that user verification can succeed.
+    ///
+    /// Always false if built-in user verification is not supported.
+    fn is_configured(&mut self) -> bool {
+        false
+    }
+
+    /// Returns the number of attempts left before built-in user verification is blocked.
+    fn retries(&mut self) -> u8 {
+        0
+    }
+
+    /// Verifies the user, waiting for them to interact with the device.
+    ///
+    /// Each failed attempt uses up a retry, and a successful one restores all retries. Once no
+    /// retries are left, returns [`UserVerificationError::Blocked`] without interacting.
+    fn verify(&mut self) -> UserVerificationResult {
+        Err(UserVerificationError::Blocked)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::test::{TestEnv, MAX_UV_RETRIES};
+    use crate::env::Env;
+
+    #[test]
+    fn test_unsupported_by_default() {
+        let mut env = TestEnv::new();
+        assert!(!env.user_verification().is_supported());
+        assert!(!env.user_verification().is_configured());
+    }
+
+    #[test]
+    fn test_retries() {
+        let mut env = TestEnv::new();
+        env.user_verification().set_enrolled(Some(true));
+        env.user_verification()
+            .set(|| Err(UserVerificationError::Invalid));
+        for retries in (0..MAX_UV_RETRIES).rev() {
+            assert_eq!(
+                env.user_verification().verify(),
+                Err(UserVerificationError::Invalid)
+            );
+            assert_eq!(env.user_verification().retries(), retries);
+        }
+        env.user_verification().set(|| Ok(()));
+        assert_eq!(
+            env.user_verification().verify(),
+            Err(UserVerificationError::Blocked)
+        );
+    }
+
+    #[test]
+    fn test_success_restores_retries() {
+        let mut env = TestEnv::new();
+        env.user_verification().set_enrolled(Some(true));
+        env.user_verification()
+            .set(|| Err(UserVerificationError::Invalid));
+        assert!(env.user_verification().verify().is_err());
+        assert_eq!(env.user_verification().retries(), MAX_UV_RETRIES - 1);
+        env.user_verification().set(|| Ok(()));
+        assert_eq!(env.user_verification().verify(), Ok(()));
+        assert_eq!(env.user_verification().retries(), MAX_UV_RETRIES);
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Vendor specific CTAPHID commands.
+//!
+//! The environment provides a table of handlers, see
+//! [`Env::vendor_commands`](crate::env::Env::vendor_commands). Commands received on the vendor HID
+//! interface are dispatched to the handler registered for their command byte.
+
+use crate::api::firmware_protection::FirmwareProtection;
+use crate::env::Env;
+use alloc::vec::Vec;
+
+/// Handles a vendor command: gets the request payload and returns the response payload.
+pub type Handler<E> = fn(&mut E, &[u8]) -> Result<Vec<u8>, Error>;
+
+/// Registers a handler for a vendor command.
+pub struct Entry<E> {
+    /// The command byte, between 0x40 and 0x7F.
+    pub command: u8,
+    pub handler: Handler<E>,
+}
+
+/// Vendor command errors, reported as CTAPHID errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    InvalidParameter,
+    InvalidLength,
+    Other,
+}
+
+/// Command byte of [`firmware_version`].
+pub const FIRMWARE_VERSION_COMMAND: u8 = 0x40;
+
+/// Returns the version of the running firmware.
+///
+/// The request must be empty. The response is the serialized
+/// [`FirmwareVersion`](crate::api::firmware_protection::FirmwareVersion).
+pub fn firmware_version<E: Env>(env: &mut E, request: &[u8]) -> Result<Vec<u8>, Error> {
+    if !request.is_empty() {
+        return Err(Error::InvalidLength);
+    }
+    Ok(env
+        .firmware_protection()
+        .firmware_version()
+        .to_bytes()
+        .to_vec())
+}
+
+/// Runs the handler registered for a command.
+///
+/// Returns `None` if no handler is registered.
+pub fn dispatch<E: Env>(
+    env: &mut E,
+    command: u8,
+    request: &[u8],
+) -> Option<Result<Vec<u8>, Error>> {
+    let handler = env
+        .vendor_commands()
+        .iter()
+        .find(|entry| entry.command == command)?
+        .handler;
+    Some(handler(env, request))
+}
@@ -0,0 +1,55 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Hardware watchdog resetting the device when it hangs.
+///
+/// Once enabled, the device resets unless the watchdog is kicked at least once per timeout. The
+/// main loop kicks it on each iteration, and so do long operations that don't return to the main
+/// loop, such as user presence checks.
+///
+/// Devices without a watchdog never reset, which is the default.
+pub trait Watchdog {
+    /// Starts the watchdog, or restarts it with a new timeout.
+    ///
+    /// Returns whether the watchdog is running.
+    fn enable(&mut self, _timeout_ms: usize) -> bool {
+        false
+    }
+
+    /// Restarts the countdown of a running watchdog.
+    fn kick(&mut self) {}
+
+    /// Stops the watchdog.
+    fn disable(&mut self) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::test::TestEnv;
+    use crate::env::Env;
+
+    #[test]
+    fn test_enable_kick_disable() {
+        let mut env = TestEnv::new();
+        assert_eq!(env.watchdog().timeout_ms(), None);
+        assert!(env.watchdog().enable(2000));
+        assert_eq!(env.watchdog().timeout_ms(), Some(2000));
+        let kicks = env.watchdog().kicks();
+        env.watchdog().kick();
+        assert_eq!(env.watchdog().kicks(), kicks + 1);
+        env.watchdog().disable();
+        assert_eq!(env.watchdog().timeout_ms(), None);
+    }
+}
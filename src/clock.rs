@@ -61,19 +61,29 @@ impl<const CLOCK_FREQUENCY: u32> embedded_time::Clock for LibtockClock<CLOCK_FRE
     }
 }
 
+/// Frequency of the CTAP timers.
+const CTAP_CLOCK_FREQUENCY_HZ: u32 = 32768;
+
 #[cfg(not(feature = "std"))]
-pub type CtapClock = LibtockClock<32768>;
+pub type CtapClock = LibtockClock<CTAP_CLOCK_FREQUENCY_HZ>;
 #[cfg(feature = "std")]
 pub type CtapClock = TestClock;
 
-pub fn new_clock() -> CtapClock {
-    CtapClock::new()
-}
-
 pub type CtapInstant = embedded_time::Instant<CtapClock>;
 
+/// Converts the time since boot, as returned by the environment clock, to a CTAP instant.
+///
+/// Instants wrap around when the tick count overflows `ClockInt`, which the timers handle.
+pub fn instant_from_ms(ms: u64) -> CtapInstant {
+    let frequency_hz = CTAP_CLOCK_FREQUENCY_HZ as u64;
+    let ticks = (ms / 1000)
+        .wrapping_mul(frequency_hz)
+        .wrapping_add(ms % 1000 * frequency_hz / 1000);
+    CtapInstant::new(ticks as ClockInt)
+}
+
 #[cfg(feature = "std")]
-pub const TEST_CLOCK_FREQUENCY_HZ: u32 = 32768;
+pub const TEST_CLOCK_FREQUENCY_HZ: u32 = CTAP_CLOCK_FREQUENCY_HZ;
 
 #[cfg(feature = "std")]
 #[derive(Default, Clone, Copy, Debug)]
@@ -161,6 +171,17 @@ mod test {
         assert_eq!(early.checked_duration_since(&later), None);
     }
 
+    #[test]
+    fn test_instant_from_ms() {
+        assert_eq!(instant_from_ms(0), CtapInstant::new(0));
+        assert_eq!(
+            instant_from_ms(1500),
+            CtapInstant::new(3 * TEST_CLOCK_FREQUENCY_HZ as ClockInt / 2)
+        );
+        // Large values wrap around instead of overflowing.
+        let _ = instant_from_ms(u64::MAX);
+    }
+
     #[test]
     #[should_panic]
     fn add_panic() {
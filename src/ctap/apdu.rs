@@ -30,6 +30,8 @@ pub enum ApduStatusCode {
     SW_MEMERR = 0x65_01,
     SW_WRONG_DATA = 0x6a_80,
     SW_WRONG_LENGTH = 0x67_00,
+    /// The command interrupts a chain of commands.
+    SW_LAST_COMMAND_EXPECTED = 0x68_83,
     SW_COND_USE_NOT_SATISFIED = 0x69_85,
     SW_COMMAND_NOT_ALLOWED = 0x69_86,
     SW_FILE_NOT_FOUND = 0x6a_82,
@@ -62,6 +64,10 @@ pub enum ApduInstructions {
     Select = 0xA4,
     ReadBinary = 0xB0,
     GetResponse = 0xC0,
+    /// CTAP message over NFC, see CTAP 2.1 from 2021-06-15, section 11.3.
+    NfcCtapMsg = 0x10,
+    /// Fetches the rest of a CTAP response over NFC, like GET RESPONSE.
+    NfcCtapGetResponse = 0x11,
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
@@ -14,14 +14,16 @@
 
 use super::super::clock::CtapInstant;
 use super::command::AuthenticatorClientPinParameters;
+use super::cose::CoseKey;
 use super::data_formats::{
-    ok_or_missing, ClientPinSubCommand, CoseKey, GetAssertionHmacSecretInput, PinUvAuthProtocol,
+    ok_or_missing, ClientPinSubCommand, GetAssertionHmacSecretInput, PinUvAuthProtocol,
 };
 use super::pin_protocol::{verify_pin_uv_auth_token, PinProtocol, SharedSecret};
 use super::response::{AuthenticatorClientPinResponse, ResponseData};
 use super::status_code::Ctap2StatusCode;
 use super::token_state::PinUvAuthTokenState;
 use crate::api::customization::Customization;
+use crate::api::user_verification::UserVerification;
 use crate::ctap::storage;
 use crate::env::Env;
 use alloc::boxed::Box;
@@ -30,11 +32,11 @@ use alloc::string::String;
 use alloc::vec::Vec;
 use crypto::hmac::hmac_256;
 use crypto::sha256::Sha256;
+use crypto::util::{ct_eq, Zeroizing};
 use crypto::Hash256;
 #[cfg(test)]
 use enum_iterator::IntoEnumIterator;
 use rng256::Rng256;
-use subtle::ConstantTimeEq;
 
 /// The prefix length of the PIN hash that is stored and compared.
 ///
@@ -62,27 +64,33 @@ fn decrypt_pin(
     shared_secret: &dyn SharedSecret,
     new_pin_enc: Vec<u8>,
 ) -> Result<Vec<u8>, Ctap2StatusCode> {
-    let decrypted_pin = shared_secret.decrypt(&new_pin_enc)?;
+    let decrypted_pin = Zeroizing::new(shared_secret.decrypt(&new_pin_enc)?);
     if decrypted_pin.len() != PIN_PADDED_LENGTH {
         return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
     }
     // In CTAP 2.1, the specification changed. The new wording might lead to
     // different behavior when there are non-zero bytes after zero bytes.
     // This implementation consistently ignores those degenerate cases.
-    Ok(decrypted_pin.into_iter().take_while(|&c| c != 0).collect())
+    let pin_length = decrypted_pin
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(PIN_PADDED_LENGTH);
+    // Copying with a known length avoids reallocations leaving parts of the PIN behind.
+    Ok(decrypted_pin[..pin_length].to_vec())
 }
 
 /// Stores a hash prefix of the new PIN in the persistent storage, if correct.
 ///
 /// The new PIN is passed encrypted, so it is first decrypted and stripped from
-/// padding. Next, it is checked against the PIN policy. Last, it is hashed and
-/// truncated for persistent storage.
+/// padding. Next, it is checked against the PIN policy, which requires a new
+/// PIN while a change is forced. Last, it is hashed and truncated for
+/// persistent storage.
 fn check_and_store_new_pin(
     env: &mut impl Env,
     shared_secret: &dyn SharedSecret,
     new_pin_enc: Vec<u8>,
 ) -> Result<(), Ctap2StatusCode> {
-    let pin = decrypt_pin(shared_secret, new_pin_enc)?;
+    let pin = Zeroizing::new(decrypt_pin(shared_secret, new_pin_enc)?);
     let min_pin_length = storage::min_pin_length(env)? as usize;
     let pin_length = str::from_utf8(&pin).unwrap_or("").chars().count();
     if pin_length < min_pin_length || pin.len() == PIN_PADDED_LENGTH {
@@ -90,16 +98,40 @@ fn check_and_store_new_pin(
     }
     let mut pin_hash = [0u8; PIN_AUTH_LENGTH];
     pin_hash.copy_from_slice(&Sha256::hash(&pin[..])[..PIN_AUTH_LENGTH]);
+    // A forced PIN change must actually change the PIN.
+    if storage::has_force_pin_change(env)? {
+        if let Some(old_pin_hash) = storage::pin_hash(env)? {
+            if ct_eq(&old_pin_hash, &pin_hash) {
+                return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
+            }
+        }
+    }
     // The PIN length is always < PIN_PADDED_LENGTH < 256.
     storage::set_pin(env, &pin_hash, pin_length as u8)?;
     Ok(())
 }
 
+/// Checks the permissions requested with a pinUvAuthToken.
+fn check_permissions(
+    permissions: u8,
+    permissions_rp_id: &Option<String>,
+) -> Result<(), Ctap2StatusCode> {
+    if permissions == 0 {
+        return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+    }
+    // This check is not mentioned protocol steps, but mentioned in a side note.
+    if permissions & 0x03 != 0 && permissions_rp_id.is_none() {
+        return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+    }
+    Ok(())
+}
+
 #[cfg_attr(test, derive(IntoEnumIterator))]
 pub enum PinPermission {
     // All variants should use integers with a single bit set.
     MakeCredential = 0x01,
     GetAssertion = 0x02,
+    #[cfg_attr(not(feature = "cred_management"), allow(dead_code))]
     CredentialManagement = 0x04,
     _BioEnrollment = 0x08,
     LargeBlobWrite = 0x10,
@@ -155,8 +187,10 @@ impl ClientPin {
     /// Checks the given encrypted PIN hash against the stored PIN hash.
     ///
     /// Decrypts the encrypted pin_hash and compares it to the stored pin_hash.
-    /// Resets or decreases the PIN retries, depending on success or failure.
-    /// Also, in case of failure, the key agreement key is randomly reset.
+    /// The PIN retries are decreased before the comparison, so that cutting
+    /// the power during a wrong guess doesn't save a retry. They are reset on
+    /// success. Also, in case of failure, the key agreement key is randomly
+    /// reset.
     fn verify_pin_hash_enc(
         &mut self,
         env: &mut impl Env,
@@ -166,15 +200,20 @@ impl ClientPin {
     ) -> Result<(), Ctap2StatusCode> {
         match storage::pin_hash(env)? {
             Some(pin_hash) => {
+                if storage::pin_retries(env)? == 0 {
+                    return Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED);
+                }
                 if self.consecutive_pin_mismatches >= 3 {
                     return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED);
                 }
                 storage::decr_pin_retries(env)?;
-                let pin_hash_dec = shared_secret
-                    .decrypt(&pin_hash_enc)
-                    .map_err(|_| Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)?;
+                let pin_hash_dec = Zeroizing::new(
+                    shared_secret
+                        .decrypt(&pin_hash_enc)
+                        .map_err(|_| Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)?,
+                );
 
-                if !bool::from(pin_hash.ct_eq(&pin_hash_dec)) {
+                if !ct_eq(&pin_hash, &pin_hash_dec) {
                     self.get_mut_pin_protocol(pin_uv_auth_protocol)
                         .regenerate(env.rng());
                     if storage::pin_retries(env)? == 0 {
@@ -195,6 +234,17 @@ impl ClientPin {
         Ok(())
     }
 
+    /// Invalidates all pinUvAuthTokens that were handed out before.
+    ///
+    /// Both protocols get a fresh token, so HMACs computed with an old token
+    /// no longer verify. The token state is also stopped, so that a new token
+    /// has to be requested before the next use.
+    fn invalidate_pin_uv_auth_tokens(&mut self, rng: &mut impl Rng256) {
+        self.pin_protocol_v1.reset_pin_uv_auth_token(rng);
+        self.pin_protocol_v2.reset_pin_uv_auth_token(rng);
+        self.pin_uv_auth_token_state.stop_using_pin_uv_auth_token();
+    }
+
     fn process_get_pin_retries(
         &self,
         env: &mut impl Env,
@@ -204,6 +254,7 @@ impl ClientPin {
             pin_uv_auth_token: None,
             retries: Some(storage::pin_retries(env)? as u64),
             power_cycle_state: Some(self.consecutive_pin_mismatches >= 3),
+            uv_retries: None,
         })
     }
 
@@ -220,6 +271,7 @@ impl ClientPin {
             pin_uv_auth_token: None,
             retries: None,
             power_cycle_state: None,
+            uv_retries: None,
         })
     }
 
@@ -247,6 +299,7 @@ impl ClientPin {
 
         check_and_store_new_pin(env, shared_secret.as_ref(), new_pin_enc)?;
         storage::reset_pin_retries(env)?;
+        self.invalidate_pin_uv_auth_tokens(env.rng());
         Ok(())
     }
 
@@ -283,8 +336,7 @@ impl ClientPin {
         )?;
 
         check_and_store_new_pin(env, shared_secret.as_ref(), new_pin_enc)?;
-        self.pin_protocol_v1.reset_pin_uv_auth_token(env.rng());
-        self.pin_protocol_v2.reset_pin_uv_auth_token(env.rng());
+        self.invalidate_pin_uv_auth_tokens(env.rng());
         Ok(())
     }
 
@@ -319,14 +371,29 @@ impl ClientPin {
             pin_hash_enc,
         )?;
         if storage::has_force_pin_change(env)? {
-            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID);
+            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
         }
 
+        let pin_uv_auth_token =
+            self.begin_using_new_pin_uv_auth_token(env, pin_uv_auth_protocol, shared_secret, now)?;
+        self.pin_uv_auth_token_state.set_default_permissions();
+        Ok(pin_uv_auth_token)
+    }
+
+    /// Resets the pinUvAuthToken after a successful user verification.
+    ///
+    /// The response contains the new token, encrypted with the shared secret.
+    fn begin_using_new_pin_uv_auth_token(
+        &mut self,
+        env: &mut impl Env,
+        pin_uv_auth_protocol: PinUvAuthProtocol,
+        shared_secret: Box<dyn SharedSecret>,
+        now: CtapInstant,
+    ) -> Result<AuthenticatorClientPinResponse, Ctap2StatusCode> {
         self.pin_protocol_v1.reset_pin_uv_auth_token(env.rng());
         self.pin_protocol_v2.reset_pin_uv_auth_token(env.rng());
         self.pin_uv_auth_token_state
             .begin_using_pin_uv_auth_token(now);
-        self.pin_uv_auth_token_state.set_default_permissions();
         let pin_uv_auth_token = shared_secret.encrypt(
             env.rng(),
             self.get_pin_protocol(pin_uv_auth_protocol)
@@ -338,22 +405,58 @@ impl ClientPin {
             pin_uv_auth_token: Some(pin_uv_auth_token),
             retries: None,
             power_cycle_state: None,
+            uv_retries: None,
         })
     }
 
     fn process_get_pin_uv_auth_token_using_uv_with_permissions(
-        &self,
-        // If you want to support local user verification, implement this function.
-        // Lacking a fingerprint reader, this subcommand is currently unsupported.
-        _client_pin_params: AuthenticatorClientPinParameters,
+        &mut self,
+        env: &mut impl Env,
+        client_pin_params: AuthenticatorClientPinParameters,
+        now: CtapInstant,
     ) -> Result<AuthenticatorClientPinResponse, Ctap2StatusCode> {
-        // User verification is only supported through PIN currently.
-        Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND)
+        let AuthenticatorClientPinParameters {
+            pin_uv_auth_protocol,
+            key_agreement,
+            permissions,
+            permissions_rp_id,
+            ..
+        } = client_pin_params;
+        let key_agreement = ok_or_missing(key_agreement)?;
+        let permissions = ok_or_missing(permissions)?;
+        check_permissions(permissions, &permissions_rp_id)?;
+
+        if !env.user_verification().is_supported() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND);
+        }
+        if !env.user_verification().is_configured() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED);
+        }
+        let shared_secret = self.get_shared_secret(pin_uv_auth_protocol, key_agreement)?;
+        env.user_verification().verify()?;
+
+        let pin_uv_auth_token =
+            self.begin_using_new_pin_uv_auth_token(env, pin_uv_auth_protocol, shared_secret, now)?;
+        self.pin_uv_auth_token_state.set_permissions(permissions);
+        self.pin_uv_auth_token_state
+            .set_permissions_rp_id(permissions_rp_id);
+        Ok(pin_uv_auth_token)
     }
 
-    fn process_get_uv_retries(&self) -> Result<AuthenticatorClientPinResponse, Ctap2StatusCode> {
-        // User verification is only supported through PIN currently.
-        Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND)
+    fn process_get_uv_retries(
+        &self,
+        env: &mut impl Env,
+    ) -> Result<AuthenticatorClientPinResponse, Ctap2StatusCode> {
+        if !env.user_verification().is_supported() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND);
+        }
+        Ok(AuthenticatorClientPinResponse {
+            key_agreement: None,
+            pin_uv_auth_token: None,
+            retries: None,
+            power_cycle_state: None,
+            uv_retries: Some(env.user_verification().retries() as u64),
+        })
     }
 
     fn process_get_pin_uv_auth_token_using_pin_with_permissions(
@@ -367,14 +470,7 @@ impl ClientPin {
         // getPinToken requires permissions* to be None.
         let permissions = ok_or_missing(client_pin_params.permissions.take())?;
         let permissions_rp_id = client_pin_params.permissions_rp_id.take();
-
-        if permissions == 0 {
-            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
-        }
-        // This check is not mentioned protocol steps, but mentioned in a side note.
-        if permissions & 0x03 != 0 && permissions_rp_id.is_none() {
-            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
-        }
+        check_permissions(permissions, &permissions_rp_id)?;
 
         let response = self.process_get_pin_token(env, client_pin_params, now)?;
         self.pin_uv_auth_token_state.set_permissions(permissions);
@@ -413,9 +509,13 @@ impl ClientPin {
                 Some(self.process_get_pin_token(env, client_pin_params, now)?)
             }
             ClientPinSubCommand::GetPinUvAuthTokenUsingUvWithPermissions => Some(
-                self.process_get_pin_uv_auth_token_using_uv_with_permissions(client_pin_params)?,
+                self.process_get_pin_uv_auth_token_using_uv_with_permissions(
+                    env,
+                    client_pin_params,
+                    now,
+                )?,
             ),
-            ClientPinSubCommand::GetUvRetries => Some(self.process_get_uv_retries()?),
+            ClientPinSubCommand::GetUvRetries => Some(self.process_get_uv_retries(env)?),
             ClientPinSubCommand::GetPinUvAuthTokenUsingPinWithPermissions => Some(
                 self.process_get_pin_uv_auth_token_using_pin_with_permissions(
                     env,
@@ -449,11 +549,9 @@ impl ClientPin {
     /// Resets all held state.
     pub fn reset(&mut self, rng: &mut impl Rng256) {
         self.pin_protocol_v1.regenerate(rng);
-        self.pin_protocol_v1.reset_pin_uv_auth_token(rng);
         self.pin_protocol_v2.regenerate(rng);
-        self.pin_protocol_v2.reset_pin_uv_auth_token(rng);
+        self.invalidate_pin_uv_auth_tokens(rng);
         self.consecutive_pin_mismatches = 0;
-        self.pin_uv_auth_token_state.stop_using_pin_uv_auth_token();
     }
 
     /// Verifies, computes and encrypts the HMAC-secret outputs.
@@ -521,11 +619,13 @@ impl ClientPin {
     }
 
     /// Check if no RP ID is associated with the token permission.
+    #[cfg(feature = "cred_management")]
     pub fn has_no_rp_id_permission(&self) -> Result<(), Ctap2StatusCode> {
         self.pin_uv_auth_token_state.has_no_permissions_rp_id()
     }
 
     /// Check if no or the passed RP ID is associated with the token permission.
+    #[cfg(feature = "cred_management")]
     pub fn has_no_or_rp_id_permission(&mut self, rp_id: &str) -> Result<(), Ctap2StatusCode> {
         self.pin_uv_auth_token_state
             .has_no_permissions_rp_id()
@@ -533,6 +633,7 @@ impl ClientPin {
     }
 
     /// Check if no RP ID is associated with the token permission, or it matches the hash.
+    #[cfg(feature = "cred_management")]
     pub fn has_no_or_rp_id_hash_permission(
         &self,
         rp_id_hash: &[u8],
@@ -588,7 +689,8 @@ impl ClientPin {
 mod test {
     use super::super::pin_protocol::authenticate_pin_uv_auth_token;
     use super::*;
-    use crate::env::test::TestEnv;
+    use crate::api::user_verification::UserVerificationError;
+    use crate::env::test::{TestEnv, MAX_UV_RETRIES};
     use alloc::vec;
     use embedded_time::duration::Milliseconds;
 
@@ -800,6 +902,123 @@ mod test {
         );
     }
 
+    fn test_helper_verify_pin_hash_enc_retries(pin_uv_auth_protocol: PinUvAuthProtocol) {
+        let mut env = TestEnv::new();
+        set_standard_pin(&mut env);
+        let mut pin = [0u8; 64];
+        pin[..4].copy_from_slice(b"1234");
+        let pin_hash = Sha256::hash(&pin)[..16].to_vec();
+        let wrong_pin_hash = vec![0xEE; 16];
+        let max_pin_retries = env.customization().max_pin_retries();
+
+        let verify = |env: &mut TestEnv,
+                      client_pin: &mut ClientPin,
+                      shared_secret: &dyn SharedSecret,
+                      pin_hash: &[u8]| {
+            let pin_hash_enc = shared_secret.encrypt(env.rng(), pin_hash).unwrap();
+            client_pin.verify_pin_hash_enc(env, pin_uv_auth_protocol, shared_secret, pin_hash_enc)
+        };
+
+        // A new ClientPin simulates a power cycle. The retries are in the store.
+        let (mut client_pin, shared_secret) =
+            create_client_pin_and_shared_secret(pin_uv_auth_protocol);
+        for _ in 0..2 {
+            assert_eq!(
+                verify(
+                    &mut env,
+                    &mut client_pin,
+                    shared_secret.as_ref(),
+                    &wrong_pin_hash
+                ),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)
+            );
+        }
+        assert_eq!(
+            verify(
+                &mut env,
+                &mut client_pin,
+                shared_secret.as_ref(),
+                &wrong_pin_hash
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED)
+        );
+        assert_eq!(storage::pin_retries(&mut env), Ok(max_pin_retries - 3));
+        // Until the next power cycle, even the correct PIN is refused without using a retry.
+        assert_eq!(
+            verify(&mut env, &mut client_pin, shared_secret.as_ref(), &pin_hash),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED)
+        );
+        assert_eq!(storage::pin_retries(&mut env), Ok(max_pin_retries - 3));
+
+        // The correct PIN restores the retries.
+        let (mut client_pin, mut shared_secret) =
+            create_client_pin_and_shared_secret(pin_uv_auth_protocol);
+        assert_eq!(
+            verify(&mut env, &mut client_pin, shared_secret.as_ref(), &pin_hash),
+            Ok(())
+        );
+        assert_eq!(storage::pin_retries(&mut env), Ok(max_pin_retries));
+
+        // Wrong PINs over power cycles use up all retries.
+        let mut retries = max_pin_retries;
+        let mut consecutive_mismatches = 0;
+        while retries > 1 {
+            if consecutive_mismatches == 3 {
+                let (new_client_pin, new_shared_secret) =
+                    create_client_pin_and_shared_secret(pin_uv_auth_protocol);
+                client_pin = new_client_pin;
+                shared_secret = new_shared_secret;
+                consecutive_mismatches = 0;
+            }
+            retries -= 1;
+            consecutive_mismatches += 1;
+            let expected = if consecutive_mismatches == 3 {
+                Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED
+            } else {
+                Ctap2StatusCode::CTAP2_ERR_PIN_INVALID
+            };
+            assert_eq!(
+                verify(
+                    &mut env,
+                    &mut client_pin,
+                    shared_secret.as_ref(),
+                    &wrong_pin_hash
+                ),
+                Err(expected)
+            );
+            assert_eq!(storage::pin_retries(&mut env), Ok(retries));
+        }
+        assert_eq!(
+            verify(
+                &mut env,
+                &mut client_pin,
+                shared_secret.as_ref(),
+                &wrong_pin_hash
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED)
+        );
+        assert_eq!(storage::pin_retries(&mut env), Ok(0));
+
+        // Once blocked, the correct PIN doesn't restore the retries, even after a power cycle.
+        let (mut client_pin, shared_secret) =
+            create_client_pin_and_shared_secret(pin_uv_auth_protocol);
+        assert_eq!(
+            verify(&mut env, &mut client_pin, shared_secret.as_ref(), &pin_hash),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED)
+        );
+        assert_eq!(storage::pin_retries(&mut env), Ok(0));
+    }
+
+    #[test]
+    fn test_verify_pin_hash_enc_retries_v1() {
+        test_helper_verify_pin_hash_enc_retries(PinUvAuthProtocol::V1);
+    }
+
+    #[test]
+    fn test_verify_pin_hash_enc_retries_v2() {
+        test_helper_verify_pin_hash_enc_retries(PinUvAuthProtocol::V2);
+    }
+
     #[test]
     fn test_verify_pin_hash_enc_v1() {
         test_helper_verify_pin_hash_enc(PinUvAuthProtocol::V1);
@@ -821,6 +1040,7 @@ mod test {
             pin_uv_auth_token: None,
             retries: Some(storage::pin_retries(&mut env).unwrap() as u64),
             power_cycle_state: Some(false),
+            uv_retries: None,
         });
         assert_eq!(
             client_pin.process_command(&mut env, params.clone(), CtapInstant::new(0)),
@@ -833,6 +1053,7 @@ mod test {
             pin_uv_auth_token: None,
             retries: Some(storage::pin_retries(&mut env).unwrap() as u64),
             power_cycle_state: Some(true),
+            uv_retries: None,
         });
         assert_eq!(
             client_pin.process_command(&mut env, params, CtapInstant::new(0)),
@@ -861,6 +1082,7 @@ mod test {
             pin_uv_auth_token: None,
             retries: None,
             power_cycle_state: None,
+            uv_retries: None,
         });
         assert_eq!(
             client_pin.process_command(&mut env, params, CtapInstant::new(0)),
@@ -950,6 +1172,120 @@ mod test {
         );
     }
 
+    /// Returns parameters to change the stored PIN to `new_pin`.
+    fn create_change_pin_parameters(
+        env: &mut TestEnv,
+        client_pin: &ClientPin,
+        pin_uv_auth_protocol: PinUvAuthProtocol,
+        new_pin: &[u8],
+    ) -> AuthenticatorClientPinParameters {
+        let key_agreement = client_pin
+            .get_pin_protocol(pin_uv_auth_protocol)
+            .get_public_key();
+        let shared_secret = client_pin
+            .get_pin_protocol(pin_uv_auth_protocol)
+            .decapsulate(key_agreement.clone(), pin_uv_auth_protocol)
+            .unwrap();
+        let mut padded_pin = [0u8; 64];
+        padded_pin[..new_pin.len()].copy_from_slice(new_pin);
+        let new_pin_enc = shared_secret.encrypt(env.rng(), &padded_pin).unwrap();
+        let pin_hash = storage::pin_hash(env).unwrap().unwrap();
+        let pin_hash_enc = shared_secret.encrypt(env.rng(), &pin_hash).unwrap();
+        let mut auth_param_data = new_pin_enc.clone();
+        auth_param_data.extend(&pin_hash_enc);
+        AuthenticatorClientPinParameters {
+            pin_uv_auth_protocol,
+            sub_command: ClientPinSubCommand::ChangePin,
+            key_agreement: Some(key_agreement),
+            pin_uv_auth_param: Some(shared_secret.authenticate(&auth_param_data)),
+            new_pin_enc: Some(new_pin_enc),
+            pin_hash_enc: Some(pin_hash_enc),
+            permissions: None,
+            permissions_rp_id: None,
+        }
+    }
+
+    fn test_helper_process_change_pin_policy(pin_uv_auth_protocol: PinUvAuthProtocol) {
+        let mut env = TestEnv::new();
+        let mut client_pin = ClientPin::new(env.rng());
+        set_standard_pin(&mut env);
+        let old_pin_hash = storage::pin_hash(&mut env).unwrap();
+        assert_eq!(storage::set_min_pin_length(&mut env, 6), Ok(()));
+
+        for new_pin in [&b"12345"[..], &[0x31; 64][..]] {
+            let params =
+                create_change_pin_parameters(&mut env, &client_pin, pin_uv_auth_protocol, new_pin);
+            assert_eq!(
+                client_pin.process_command(&mut env, params, CtapInstant::new(0)),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION)
+            );
+            // The old PIN was correct, so the retries are not consumed.
+            assert_eq!(
+                storage::pin_retries(&mut env),
+                Ok(env.customization().max_pin_retries())
+            );
+        }
+        assert_eq!(storage::pin_hash(&mut env).unwrap(), old_pin_hash);
+
+        let params =
+            create_change_pin_parameters(&mut env, &client_pin, pin_uv_auth_protocol, b"123456");
+        assert_eq!(
+            client_pin.process_command(&mut env, params, CtapInstant::new(0)),
+            Ok(ResponseData::AuthenticatorClientPin(None))
+        );
+        assert_eq!(
+            storage::pin_hash(&mut env).unwrap().unwrap()[..],
+            Sha256::hash(b"123456")[..16]
+        );
+        assert_eq!(storage::pin_code_point_length(&mut env), Ok(Some(6)));
+    }
+
+    #[test]
+    fn test_process_change_pin_policy_v1() {
+        test_helper_process_change_pin_policy(PinUvAuthProtocol::V1);
+    }
+
+    #[test]
+    fn test_process_change_pin_policy_v2() {
+        test_helper_process_change_pin_policy(PinUvAuthProtocol::V2);
+    }
+
+    fn test_helper_process_change_pin_force_pin_change(pin_uv_auth_protocol: PinUvAuthProtocol) {
+        let mut env = TestEnv::new();
+        let mut client_pin = ClientPin::new(env.rng());
+        let mut pin_hash = [0u8; 16];
+        pin_hash.copy_from_slice(&Sha256::hash(b"1234")[..16]);
+        storage::set_pin(&mut env, &pin_hash, 4).unwrap();
+        assert_eq!(storage::force_pin_change(&mut env), Ok(()));
+
+        // Keeping the same PIN doesn't satisfy the forced change.
+        let params =
+            create_change_pin_parameters(&mut env, &client_pin, pin_uv_auth_protocol, b"1234");
+        assert_eq!(
+            client_pin.process_command(&mut env, params, CtapInstant::new(0)),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION)
+        );
+        assert_eq!(storage::has_force_pin_change(&mut env), Ok(true));
+
+        let params =
+            create_change_pin_parameters(&mut env, &client_pin, pin_uv_auth_protocol, b"5678");
+        assert_eq!(
+            client_pin.process_command(&mut env, params, CtapInstant::new(0)),
+            Ok(ResponseData::AuthenticatorClientPin(None))
+        );
+        assert_eq!(storage::has_force_pin_change(&mut env), Ok(false));
+    }
+
+    #[test]
+    fn test_process_change_pin_force_pin_change_v1() {
+        test_helper_process_change_pin_force_pin_change(PinUvAuthProtocol::V1);
+    }
+
+    #[test]
+    fn test_process_change_pin_force_pin_change_v2() {
+        test_helper_process_change_pin_force_pin_change(PinUvAuthProtocol::V2);
+    }
+
     #[test]
     fn test_process_change_pin_v1() {
         test_helper_process_change_pin(PinUvAuthProtocol::V1);
@@ -960,6 +1296,60 @@ mod test {
         test_helper_process_change_pin(PinUvAuthProtocol::V2);
     }
 
+    fn test_helper_change_pin_invalidates_token(pin_uv_auth_protocol: PinUvAuthProtocol) {
+        let (mut client_pin, mut params) =
+            create_client_pin_and_parameters(pin_uv_auth_protocol, ClientPinSubCommand::ChangePin);
+        let shared_secret = client_pin
+            .get_pin_protocol(pin_uv_auth_protocol)
+            .decapsulate(
+                params.key_agreement.clone().unwrap(),
+                params.pin_uv_auth_protocol,
+            )
+            .unwrap();
+        let mut env = TestEnv::new();
+        set_standard_pin(&mut env);
+
+        let message = [0xAA; 32];
+        let old_token = *client_pin
+            .get_pin_protocol(pin_uv_auth_protocol)
+            .get_pin_uv_auth_token();
+        let old_param = authenticate_pin_uv_auth_token(&old_token, &message, pin_uv_auth_protocol);
+        assert_eq!(
+            client_pin.verify_pin_uv_auth_token(&message, &old_param, pin_uv_auth_protocol),
+            Ok(())
+        );
+
+        let mut auth_param_data = params.new_pin_enc.clone().unwrap();
+        auth_param_data.extend(params.pin_hash_enc.as_ref().unwrap());
+        params.pin_uv_auth_param = Some(shared_secret.authenticate(&auth_param_data));
+        assert_eq!(
+            client_pin.process_command(&mut env, params, CtapInstant::new(0)),
+            Ok(ResponseData::AuthenticatorClientPin(None))
+        );
+
+        assert_ne!(
+            client_pin
+                .get_pin_protocol(pin_uv_auth_protocol)
+                .get_pin_uv_auth_token(),
+            &old_token
+        );
+        assert!(!client_pin.pin_uv_auth_token_state.is_in_use());
+        assert_eq!(
+            client_pin.verify_pin_uv_auth_token(&message, &old_param, pin_uv_auth_protocol),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+    }
+
+    #[test]
+    fn test_change_pin_invalidates_token_v1() {
+        test_helper_change_pin_invalidates_token(PinUvAuthProtocol::V1);
+    }
+
+    #[test]
+    fn test_change_pin_invalidates_token_v2() {
+        test_helper_change_pin_invalidates_token(PinUvAuthProtocol::V2);
+    }
+
     fn test_helper_process_get_pin_token(pin_uv_auth_protocol: PinUvAuthProtocol) {
         let (mut client_pin, params) = create_client_pin_and_parameters(
             pin_uv_auth_protocol,
@@ -1038,7 +1428,7 @@ mod test {
         assert_eq!(storage::force_pin_change(&mut env), Ok(()));
         assert_eq!(
             client_pin.process_command(&mut env, params, CtapInstant::new(0)),
-            Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION),
         );
     }
 
@@ -1148,7 +1538,7 @@ mod test {
         assert_eq!(storage::force_pin_change(&mut env), Ok(()));
         assert_eq!(
             client_pin.process_command(&mut env, params, CtapInstant::new(0)),
-            Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION)
         );
     }
 
@@ -1166,6 +1556,128 @@ mod test {
         );
     }
 
+    fn test_helper_process_get_pin_uv_auth_token_using_uv_with_permissions(
+        pin_uv_auth_protocol: PinUvAuthProtocol,
+    ) {
+        let (mut client_pin, params) = create_client_pin_and_parameters(
+            pin_uv_auth_protocol,
+            ClientPinSubCommand::GetPinUvAuthTokenUsingUvWithPermissions,
+        );
+        let shared_secret = client_pin
+            .get_pin_protocol(pin_uv_auth_protocol)
+            .decapsulate(
+                params.key_agreement.clone().unwrap(),
+                params.pin_uv_auth_protocol,
+            )
+            .unwrap();
+        let mut env = TestEnv::new();
+        env.user_verification().set_enrolled(Some(true));
+
+        let response = client_pin
+            .process_command(&mut env, params.clone(), CtapInstant::new(0))
+            .unwrap();
+        let encrypted_token = match response {
+            ResponseData::AuthenticatorClientPin(Some(response)) => {
+                response.pin_uv_auth_token.unwrap()
+            }
+            _ => panic!("Invalid response type"),
+        };
+        assert_eq!(
+            &shared_secret.decrypt(&encrypted_token).unwrap(),
+            client_pin
+                .get_pin_protocol(pin_uv_auth_protocol)
+                .get_pin_uv_auth_token()
+        );
+        assert_eq!(
+            client_pin
+                .pin_uv_auth_token_state
+                .has_permission(PinPermission::MakeCredential),
+            Ok(())
+        );
+        assert_eq!(
+            client_pin
+                .pin_uv_auth_token_state
+                .has_permissions_rp_id("example.com"),
+            Ok(())
+        );
+        assert!(client_pin
+            .pin_uv_auth_token_state
+            .get_user_verified_flag_value());
+
+        let mut bad_params = params.clone();
+        bad_params.permissions = Some(0x00);
+        assert_eq!(
+            client_pin.process_command(&mut env, bad_params, CtapInstant::new(0)),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+
+        env.user_verification()
+            .set(|| Err(UserVerificationError::Invalid));
+        assert_eq!(
+            client_pin.process_command(&mut env, params, CtapInstant::new(0)),
+            Err(Ctap2StatusCode::CTAP2_ERR_UV_INVALID)
+        );
+    }
+
+    #[test]
+    fn test_process_get_pin_uv_auth_token_using_uv_with_permissions_v1() {
+        test_helper_process_get_pin_uv_auth_token_using_uv_with_permissions(PinUvAuthProtocol::V1);
+    }
+
+    #[test]
+    fn test_process_get_pin_uv_auth_token_using_uv_with_permissions_v2() {
+        test_helper_process_get_pin_uv_auth_token_using_uv_with_permissions(PinUvAuthProtocol::V2);
+    }
+
+    #[test]
+    fn test_process_get_pin_uv_auth_token_using_uv_without_uv() {
+        let (mut client_pin, params) = create_client_pin_and_parameters(
+            PinUvAuthProtocol::V2,
+            ClientPinSubCommand::GetPinUvAuthTokenUsingUvWithPermissions,
+        );
+        let mut env = TestEnv::new();
+        assert_eq!(
+            client_pin.process_command(&mut env, params.clone(), CtapInstant::new(0)),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND)
+        );
+
+        env.user_verification().set_enrolled(Some(false));
+        assert_eq!(
+            client_pin.process_command(&mut env, params, CtapInstant::new(0)),
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+        assert!(!client_pin.pin_uv_auth_token_state.is_in_use());
+    }
+
+    #[test]
+    fn test_process_get_uv_retries() {
+        let (mut client_pin, params) = create_client_pin_and_parameters(
+            PinUvAuthProtocol::V2,
+            ClientPinSubCommand::GetUvRetries,
+        );
+        let mut env = TestEnv::new();
+        assert_eq!(
+            client_pin.process_command(&mut env, params.clone(), CtapInstant::new(0)),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND)
+        );
+
+        env.user_verification().set_enrolled(Some(true));
+        env.user_verification()
+            .set(|| Err(UserVerificationError::Invalid));
+        assert!(env.user_verification().verify().is_err());
+        let expected_response = Some(AuthenticatorClientPinResponse {
+            key_agreement: None,
+            pin_uv_auth_token: None,
+            retries: None,
+            power_cycle_state: None,
+            uv_retries: Some(MAX_UV_RETRIES as u64 - 1),
+        });
+        assert_eq!(
+            client_pin.process_command(&mut env, params, CtapInstant::new(0)),
+            Ok(ResponseData::AuthenticatorClientPin(expected_response))
+        );
+    }
+
     fn test_helper_decrypt_pin(pin_uv_auth_protocol: PinUvAuthProtocol) {
         let mut env = TestEnv::new();
         let pin_protocol = PinProtocol::new(env.rng());
@@ -1425,6 +1937,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "cred_management")]
     fn test_has_no_rp_id_permission() {
         let mut env = TestEnv::new();
         let mut client_pin = ClientPin::new(env.rng());
@@ -1439,6 +1952,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "cred_management")]
     fn test_has_no_or_rp_id_permission() {
         let mut env = TestEnv::new();
         let mut client_pin = ClientPin::new(env.rng());
@@ -1454,6 +1968,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "cred_management")]
     fn test_has_no_or_rp_id_hash_permission() {
         let mut env = TestEnv::new();
         let mut client_pin = ClientPin::new(env.rng());
@@ -12,16 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use super::cose::CoseKey;
 use super::data_formats::{
     extract_array, extract_bool, extract_byte_string, extract_map, extract_text_string,
     extract_unsigned, ok_or_missing, ClientPinSubCommand, ConfigSubCommand, ConfigSubCommandParams,
-    CoseKey, CredentialManagementSubCommand, CredentialManagementSubCommandParameters,
+    CredentialManagementSubCommand, CredentialManagementSubCommandParameters,
     GetAssertionExtensions, GetAssertionOptions, MakeCredentialExtensions, MakeCredentialOptions,
     PinUvAuthProtocol, PublicKeyCredentialDescriptor, PublicKeyCredentialParameter,
     PublicKeyCredentialRpEntity, PublicKeyCredentialUserEntity, SetMinPinLengthParams,
 };
 use super::status_code::Ctap2StatusCode;
 use super::{cbor_read, key_material};
+use crate::api::customization::Customization;
 use alloc::string::String;
 use alloc::vec::Vec;
 #[cfg(feature = "fuzz")]
@@ -44,6 +46,7 @@ pub enum Command {
     AuthenticatorClientPin(AuthenticatorClientPinParameters),
     AuthenticatorReset,
     AuthenticatorGetNextAssertion,
+    AuthenticatorBioEnrollment(AuthenticatorBioEnrollmentParameters),
     AuthenticatorCredentialManagement(AuthenticatorCredentialManagementParameters),
     AuthenticatorSelection,
     AuthenticatorLargeBlobs(AuthenticatorLargeBlobsParameters),
@@ -52,6 +55,7 @@ pub enum Command {
     AuthenticatorVendorConfigure(AuthenticatorVendorConfigureParameters),
     AuthenticatorVendorUpgrade(AuthenticatorVendorUpgradeParameters),
     AuthenticatorVendorUpgradeInfo,
+    AuthenticatorVendorCredentialDump,
 }
 
 impl Command {
@@ -61,8 +65,7 @@ impl Command {
     const AUTHENTICATOR_CLIENT_PIN: u8 = 0x06;
     const AUTHENTICATOR_RESET: u8 = 0x07;
     const AUTHENTICATOR_GET_NEXT_ASSERTION: u8 = 0x08;
-    // Implement Bio Enrollment when your hardware supports biometrics.
-    const _AUTHENTICATOR_BIO_ENROLLMENT: u8 = 0x09;
+    const AUTHENTICATOR_BIO_ENROLLMENT: u8 = 0x09;
     const AUTHENTICATOR_CREDENTIAL_MANAGEMENT: u8 = 0x0A;
     const AUTHENTICATOR_SELECTION: u8 = 0x0B;
     const AUTHENTICATOR_LARGE_BLOBS: u8 = 0x0C;
@@ -75,27 +78,38 @@ impl Command {
     const AUTHENTICATOR_VENDOR_CREDENTIAL_MANAGEMENT: u8 = 0x41;
     const AUTHENTICATOR_VENDOR_UPGRADE: u8 = 0x42;
     const AUTHENTICATOR_VENDOR_UPGRADE_INFO: u8 = 0x43;
+    const AUTHENTICATOR_VENDOR_CREDENTIAL_DUMP: u8 = 0x44;
     const _AUTHENTICATOR_VENDOR_LAST: u8 = 0xBF;
 
-    pub fn deserialize(bytes: &[u8]) -> Result<Command, Ctap2StatusCode> {
+    /// Parses a command, enforcing the request size limits of the customization.
+    ///
+    /// Messages longer than `max_msg_size` and credential lists longer than
+    /// `max_credential_count_in_list` fail with `CTAP2_ERR_LIMIT_EXCEEDED`.
+    pub fn deserialize(
+        bytes: &[u8],
+        customization: &impl Customization,
+    ) -> Result<Command, Ctap2StatusCode> {
         if bytes.is_empty() {
             // The error to return is not specified, missing parameter seems to fit best.
             return Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER);
         }
+        if bytes.len() > customization.max_msg_size() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_LIMIT_EXCEEDED);
+        }
 
         let command_value = bytes[0];
         match command_value {
             Command::AUTHENTICATOR_MAKE_CREDENTIAL => {
                 let decoded_cbor = cbor_read(&bytes[1..])?;
-                Ok(Command::AuthenticatorMakeCredential(
-                    AuthenticatorMakeCredentialParameters::try_from(decoded_cbor)?,
-                ))
+                let parameters = AuthenticatorMakeCredentialParameters::try_from(decoded_cbor)?;
+                check_credential_count(&parameters.exclude_list, customization)?;
+                Ok(Command::AuthenticatorMakeCredential(parameters))
             }
             Command::AUTHENTICATOR_GET_ASSERTION => {
                 let decoded_cbor = cbor_read(&bytes[1..])?;
-                Ok(Command::AuthenticatorGetAssertion(
-                    AuthenticatorGetAssertionParameters::try_from(decoded_cbor)?,
-                ))
+                let parameters = AuthenticatorGetAssertionParameters::try_from(decoded_cbor)?;
+                check_credential_count(&parameters.allow_list, customization)?;
+                Ok(Command::AuthenticatorGetAssertion(parameters))
             }
             Command::AUTHENTICATOR_GET_INFO => {
                 // Parameters are ignored.
@@ -115,6 +129,12 @@ impl Command {
                 // Parameters are ignored.
                 Ok(Command::AuthenticatorGetNextAssertion)
             }
+            Command::AUTHENTICATOR_BIO_ENROLLMENT => {
+                let decoded_cbor = cbor_read(&bytes[1..])?;
+                Ok(Command::AuthenticatorBioEnrollment(
+                    AuthenticatorBioEnrollmentParameters::try_from(decoded_cbor)?,
+                ))
+            }
             Command::AUTHENTICATOR_CREDENTIAL_MANAGEMENT
             | Command::AUTHENTICATOR_VENDOR_CREDENTIAL_MANAGEMENT => {
                 let decoded_cbor = cbor_read(&bytes[1..])?;
@@ -154,11 +174,29 @@ impl Command {
                 // Parameters are ignored.
                 Ok(Command::AuthenticatorVendorUpgradeInfo)
             }
+            Command::AUTHENTICATOR_VENDOR_CREDENTIAL_DUMP => {
+                // Parameters are ignored.
+                Ok(Command::AuthenticatorVendorCredentialDump)
+            }
             _ => Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND),
         }
     }
 }
 
+/// Rejects credential lists that are longer than the customization allows.
+fn check_credential_count(
+    credential_list: &Option<Vec<PublicKeyCredentialDescriptor>>,
+    customization: &impl Customization,
+) -> Result<(), Ctap2StatusCode> {
+    let list_length = credential_list.as_ref().map_or(0, Vec::len);
+    match customization.max_credential_count_in_list() {
+        Some(max_count) if list_length > max_count => {
+            Err(Ctap2StatusCode::CTAP2_ERR_LIMIT_EXCEEDED)
+        }
+        _ => Ok(()),
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "fuzz", derive(Arbitrary))]
 pub struct AuthenticatorMakeCredentialParameters {
@@ -379,6 +417,36 @@ impl TryFrom<cbor::Value> for AuthenticatorClientPinParameters {
     }
 }
 
+/// Parameters of bio enrollment, as far as needed without biometric hardware.
+///
+/// Enrollment subcommands are not supported, so their parameters are ignored.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AuthenticatorBioEnrollmentParameters {
+    pub sub_command: Option<u64>,
+    pub get_modality: bool,
+}
+
+impl TryFrom<cbor::Value> for AuthenticatorBioEnrollmentParameters {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        destructure_cbor_map! {
+            let {
+                0x02 => sub_command,
+                0x06 => get_modality,
+            } = extract_map(cbor_value)?;
+        }
+
+        let sub_command = sub_command.map(extract_unsigned).transpose()?;
+        let get_modality = get_modality.map(extract_bool).transpose()?.unwrap_or(false);
+
+        Ok(AuthenticatorBioEnrollmentParameters {
+            sub_command,
+            get_modality,
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct AuthenticatorLargeBlobsParameters {
     pub get: Option<usize>,
@@ -499,6 +567,7 @@ impl TryFrom<cbor::Value> for AuthenticatorConfigParameters {
 pub struct AuthenticatorAttestationMaterial {
     pub certificate: Vec<u8>,
     pub private_key: [u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH],
+    pub intermediate_certificates: Vec<Vec<u8>>,
 }
 
 impl TryFrom<cbor::Value> for AuthenticatorAttestationMaterial {
@@ -509,6 +578,7 @@ impl TryFrom<cbor::Value> for AuthenticatorAttestationMaterial {
             let {
                 0x01 => certificate,
                 0x02 => private_key,
+                0x03 => intermediate_certificates,
             } = extract_map(cbor_value)?;
         }
         let certificate = extract_byte_string(ok_or_missing(certificate)?)?;
@@ -517,9 +587,17 @@ impl TryFrom<cbor::Value> for AuthenticatorAttestationMaterial {
             return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
         }
         let private_key = array_ref!(private_key, 0, key_material::ATTESTATION_PRIVATE_KEY_LENGTH);
+        let intermediate_certificates = match intermediate_certificates {
+            None => Vec::new(),
+            Some(entry) => extract_array(entry)?
+                .into_iter()
+                .map(extract_byte_string)
+                .collect::<Result<Vec<_>, Ctap2StatusCode>>()?,
+        };
         Ok(AuthenticatorAttestationMaterial {
             certificate,
             private_key: *private_key,
+            intermediate_certificates,
         })
     }
 }
@@ -595,6 +673,9 @@ pub struct AuthenticatorVendorUpgradeParameters {
     pub offset: usize,
     pub data: Vec<u8>,
     pub hash: [u8; 32],
+    /// Length of the whole bundle, declared together with its hash to start a chunked write.
+    pub bundle_length: Option<usize>,
+    pub bundle_hash: Option<[u8; 32]>,
 }
 
 impl TryFrom<cbor::Value> for AuthenticatorVendorUpgradeParameters {
@@ -606,13 +687,36 @@ impl TryFrom<cbor::Value> for AuthenticatorVendorUpgradeParameters {
                 0x01 => offset,
                 0x02 => data,
                 0x03 => hash,
+                0x04 => bundle_length,
+                0x05 => bundle_hash,
             } = extract_map(cbor_value)?;
         }
         let offset = extract_unsigned(ok_or_missing(offset)?)? as usize;
         let data = extract_byte_string(ok_or_missing(data)?)?;
         let hash = <[u8; 32]>::try_from(extract_byte_string(ok_or_missing(hash)?)?)
             .map_err(|_| Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)?;
-        Ok(AuthenticatorVendorUpgradeParameters { offset, data, hash })
+        if bundle_length.is_some() != bundle_hash.is_some() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER);
+        }
+        let bundle_length = bundle_length
+            .map(extract_unsigned)
+            .transpose()?
+            .map(|length| length as usize);
+        let bundle_hash = bundle_hash
+            .map(extract_byte_string)
+            .transpose()?
+            .map(|bundle_hash| {
+                <[u8; 32]>::try_from(bundle_hash)
+                    .map_err(|_| Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+            })
+            .transpose()?;
+        Ok(AuthenticatorVendorUpgradeParameters {
+            offset,
+            data,
+            hash,
+            bundle_length,
+            bundle_hash,
+        })
     }
 }
 
@@ -624,8 +728,9 @@ mod test {
     };
     use super::super::ES256_CRED_PARAM;
     use super::*;
+    use crate::api::customization::{CustomizationImpl, DEFAULT_CUSTOMIZATION};
     use crate::env::test::TestEnv;
-    use cbor::{cbor_array, cbor_map};
+    use cbor::{cbor_array, cbor_array_vec, cbor_map};
 
     #[test]
     fn test_from_cbor_make_credential_parameters() {
@@ -772,10 +877,94 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_deserialize_non_canonical_cbor() {
+        let cases: Vec<(u8, &[u8])> = vec![
+            // Duplicate key 0x01.
+            (
+                Command::AUTHENTICATOR_GET_ASSERTION,
+                &[0xA2, 0x01, 0x61, 0x61, 0x01, 0x61, 0x62],
+            ),
+            // Keys 0x02 and 0x01 are out of order.
+            (
+                Command::AUTHENTICATOR_GET_ASSERTION,
+                &[0xA2, 0x02, 0x41, 0x00, 0x01, 0x61, 0x61],
+            ),
+            // Key "z" must come before "up", because it is shorter.
+            (
+                Command::AUTHENTICATOR_MAKE_CREDENTIAL,
+                &[0xA1, 0x07, 0xA2, 0x62, b'u', b'p', 0xF5, 0x61, b'z', 0xF5],
+            ),
+            // Key 0x01 is encoded on 1 additional byte.
+            (
+                Command::AUTHENTICATOR_GET_ASSERTION,
+                &[0xA1, 0x18, 0x01, 0x61, 0x61],
+            ),
+            // The byte string length is encoded on 2 bytes.
+            (
+                Command::AUTHENTICATOR_GET_ASSERTION,
+                &[0xA1, 0x02, 0x59, 0x00, 0x01, 0x00],
+            ),
+            // The map has an indefinite length.
+            (
+                Command::AUTHENTICATOR_GET_ASSERTION,
+                &[0xBF, 0x01, 0x61, 0x61, 0xFF],
+            ),
+            // The RP ID is tagged.
+            (
+                Command::AUTHENTICATOR_GET_ASSERTION,
+                &[0xA1, 0x01, 0xC0, 0x61, 0x61],
+            ),
+        ];
+        for (command, cbor) in cases {
+            let mut cbor_bytes = vec![command];
+            cbor_bytes.extend(cbor);
+            assert_eq!(
+                Command::deserialize(&cbor_bytes, &DEFAULT_CUSTOMIZATION),
+                Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)
+            );
+        }
+    }
+
+    #[test]
+    fn test_deserialize_limits() {
+        let customization = CustomizationImpl {
+            max_msg_size: 100,
+            max_credential_count_in_list: Some(2),
+            ..DEFAULT_CUSTOMIZATION
+        };
+        let credential = cbor_map! {
+            "id" => vec![0x2D, 0x2D, 0x2D, 0x2D],
+            "type" => "public-key",
+        };
+        let get_assertion = |credential_count: usize| {
+            let cbor_value = cbor_map! {
+                0x01 => "example.com",
+                0x02 => vec![0xCD],
+                0x03 => cbor_array_vec!(vec![credential.clone(); credential_count]),
+            };
+            let mut cbor_bytes = vec![Command::AUTHENTICATOR_GET_ASSERTION];
+            assert!(cbor::write(cbor_value, &mut cbor_bytes).is_ok());
+            cbor_bytes
+        };
+
+        assert!(Command::deserialize(&get_assertion(2), &customization).is_ok());
+        assert_eq!(
+            Command::deserialize(&get_assertion(3), &customization),
+            Err(Ctap2StatusCode::CTAP2_ERR_LIMIT_EXCEEDED)
+        );
+        let mut long_message = get_assertion(2);
+        long_message.resize(101, 0x00);
+        assert_eq!(
+            Command::deserialize(&long_message, &customization),
+            Err(Ctap2StatusCode::CTAP2_ERR_LIMIT_EXCEEDED)
+        );
+    }
+
     #[test]
     fn test_deserialize_get_info() {
         let cbor_bytes = [Command::AUTHENTICATOR_GET_INFO];
-        let command = Command::deserialize(&cbor_bytes);
+        let command = Command::deserialize(&cbor_bytes, &DEFAULT_CUSTOMIZATION);
         assert_eq!(command, Ok(Command::AuthenticatorGetInfo));
     }
 
@@ -783,14 +972,14 @@ mod test {
     fn test_deserialize_reset() {
         // Adding some random bytes to see if they are ignored.
         let cbor_bytes = [Command::AUTHENTICATOR_RESET, 0xAB, 0xCD, 0xEF];
-        let command = Command::deserialize(&cbor_bytes);
+        let command = Command::deserialize(&cbor_bytes, &DEFAULT_CUSTOMIZATION);
         assert_eq!(command, Ok(Command::AuthenticatorReset));
     }
 
     #[test]
     fn test_deserialize_get_next_assertion() {
         let cbor_bytes = [Command::AUTHENTICATOR_GET_NEXT_ASSERTION];
-        let command = Command::deserialize(&cbor_bytes);
+        let command = Command::deserialize(&cbor_bytes, &DEFAULT_CUSTOMIZATION);
         assert_eq!(command, Ok(Command::AuthenticatorGetNextAssertion));
     }
 
@@ -825,10 +1014,28 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_deserialize_bio_enrollment() {
+        let cbor_value = cbor_map! {
+            0x06 => true,
+        };
+        let mut cbor_bytes = vec![Command::AUTHENTICATOR_BIO_ENROLLMENT];
+        assert!(cbor::write(cbor_value, &mut cbor_bytes).is_ok());
+        let command = Command::deserialize(&cbor_bytes, &DEFAULT_CUSTOMIZATION);
+        let expected_parameters = AuthenticatorBioEnrollmentParameters {
+            sub_command: None,
+            get_modality: true,
+        };
+        assert_eq!(
+            command,
+            Ok(Command::AuthenticatorBioEnrollment(expected_parameters))
+        );
+    }
+
     #[test]
     fn test_deserialize_selection() {
         let cbor_bytes = [Command::AUTHENTICATOR_SELECTION];
-        let command = Command::deserialize(&cbor_bytes);
+        let command = Command::deserialize(&cbor_bytes, &DEFAULT_CUSTOMIZATION);
         assert_eq!(command, Ok(Command::AuthenticatorSelection));
     }
 
@@ -979,11 +1186,11 @@ mod test {
     fn test_vendor_configure() {
         // Incomplete command
         let mut cbor_bytes = vec![Command::AUTHENTICATOR_VENDOR_CONFIGURE];
-        let command = Command::deserialize(&cbor_bytes);
+        let command = Command::deserialize(&cbor_bytes, &DEFAULT_CUSTOMIZATION);
         assert_eq!(command, Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR));
 
         cbor_bytes.extend(&[0xA1, 0x01, 0xF5]);
-        let command = Command::deserialize(&cbor_bytes);
+        let command = Command::deserialize(&cbor_bytes, &DEFAULT_CUSTOMIZATION);
         assert_eq!(
             command,
             Ok(Command::AuthenticatorVendorConfigure(
@@ -1048,17 +1255,51 @@ mod test {
                 lockdown: false,
                 attestation_material: Some(AuthenticatorAttestationMaterial {
                     certificate: dummy_cert.to_vec(),
-                    private_key: dummy_pkey
+                    private_key: dummy_pkey,
+                    intermediate_certificates: vec![],
                 }),
             })
         );
+
+        // Valid, with intermediate certificates
+        let cbor_value = cbor_map! {
+            0x02 => cbor_map! {
+                0x01 => dummy_cert,
+                0x02 => dummy_pkey,
+                0x03 => cbor_array![[0x11u8; 20], [0x22u8; 20]],
+            },
+        };
+        assert_eq!(
+            AuthenticatorVendorConfigureParameters::try_from(cbor_value),
+            Ok(AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: Some(AuthenticatorAttestationMaterial {
+                    certificate: dummy_cert.to_vec(),
+                    private_key: dummy_pkey,
+                    intermediate_certificates: vec![vec![0x11; 20], vec![0x22; 20]],
+                }),
+            })
+        );
+
+        // Intermediate certificates must be byte strings
+        let cbor_value = cbor_map! {
+            0x02 => cbor_map! {
+                0x01 => dummy_cert,
+                0x02 => dummy_pkey,
+                0x03 => cbor_array![1],
+            },
+        };
+        assert_eq!(
+            AuthenticatorVendorConfigureParameters::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE)
+        );
     }
 
     #[test]
     fn test_vendor_upgrade() {
         // Incomplete command
         let cbor_bytes = vec![Command::AUTHENTICATOR_VENDOR_UPGRADE];
-        let command = Command::deserialize(&cbor_bytes);
+        let command = Command::deserialize(&cbor_bytes, &DEFAULT_CUSTOMIZATION);
         assert_eq!(command, Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR));
 
         // Missing offset
@@ -1114,6 +1355,52 @@ mod test {
                 offset: 0x1000,
                 data: vec![0xFF; 0x100],
                 hash: [0x44; 32],
+                bundle_length: None,
+                bundle_hash: None,
+            })
+        );
+
+        // Bundle length without bundle hash
+        let cbor_value = cbor_map! {
+            0x01 => 0,
+            0x02 => [0xFF; 0x100],
+            0x03 => [0x44; 32],
+            0x04 => 0x41000,
+        };
+        assert_eq!(
+            AuthenticatorVendorUpgradeParameters::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+
+        // Invalid bundle hash size
+        let cbor_value = cbor_map! {
+            0x01 => 0,
+            0x02 => [0xFF; 0x100],
+            0x03 => [0x44; 32],
+            0x04 => 0x41000,
+            0x05 => [0x55; 31],
+        };
+        assert_eq!(
+            AuthenticatorVendorUpgradeParameters::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+
+        // Valid with a declared bundle
+        let cbor_value = cbor_map! {
+            0x01 => 0,
+            0x02 => [0xFF; 0x100],
+            0x03 => [0x44; 32],
+            0x04 => 0x41000,
+            0x05 => [0x55; 32],
+        };
+        assert_eq!(
+            AuthenticatorVendorUpgradeParameters::try_from(cbor_value),
+            Ok(AuthenticatorVendorUpgradeParameters {
+                offset: 0,
+                data: vec![0xFF; 0x100],
+                hash: [0x44; 32],
+                bundle_length: Some(0x41000),
+                bundle_hash: Some([0x55; 32]),
             })
         );
     }
@@ -1121,7 +1408,14 @@ mod test {
     #[test]
     fn test_deserialize_vendor_upgrade_info() {
         let cbor_bytes = [Command::AUTHENTICATOR_VENDOR_UPGRADE_INFO];
-        let command = Command::deserialize(&cbor_bytes);
+        let command = Command::deserialize(&cbor_bytes, &DEFAULT_CUSTOMIZATION);
         assert_eq!(command, Ok(Command::AuthenticatorVendorUpgradeInfo));
     }
+
+    #[test]
+    fn test_vendor_credential_dump() {
+        let cbor_bytes = [Command::AUTHENTICATOR_VENDOR_CREDENTIAL_DUMP];
+        let command = Command::deserialize(&cbor_bytes, &DEFAULT_CUSTOMIZATION);
+        assert_eq!(command, Ok(Command::AuthenticatorVendorCredentialDump));
+    }
 }
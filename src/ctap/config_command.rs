@@ -18,11 +18,13 @@ use super::data_formats::{ConfigSubCommand, ConfigSubCommandParams, SetMinPinLen
 use super::response::ResponseData;
 use super::status_code::Ctap2StatusCode;
 use crate::api::customization::Customization;
+use crate::api::user_verification::UserVerification;
 use crate::ctap::storage;
 use crate::env::Env;
 use alloc::vec;
 
 /// Processes the subcommand enableEnterpriseAttestation for AuthenticatorConfig.
+#[cfg(feature = "enterprise_attestation")]
 fn process_enable_enterprise_attestation(
     env: &mut impl Env,
 ) -> Result<ResponseData, Ctap2StatusCode> {
@@ -36,6 +38,13 @@ fn process_enable_enterprise_attestation(
 
 /// Processes the subcommand toggleAlwaysUv for AuthenticatorConfig.
 fn process_toggle_always_uv(env: &mut impl Env) -> Result<ResponseData, Ctap2StatusCode> {
+    // Turning alwaysUv on requires a way to verify the user: a PIN or built-in user verification.
+    if !storage::has_always_uv(env)?
+        && storage::pin_hash(env)?.is_none()
+        && !env.user_verification().is_configured()
+    {
+        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET);
+    }
     storage::toggle_always_uv(env)?;
     Ok(ResponseData::AuthenticatorConfig)
 }
@@ -62,13 +71,12 @@ fn process_set_min_pin_length(
     if let Some(old_length) = storage::pin_code_point_length(env)? {
         force_change_pin |= new_min_pin_length > old_length;
     }
-    if force_change_pin {
-        storage::force_pin_change(env)?;
-    }
-    storage::set_min_pin_length(env, new_min_pin_length)?;
-    if let Some(min_pin_length_rp_ids) = min_pin_length_rp_ids {
-        storage::set_min_pin_length_rp_ids(env, min_pin_length_rp_ids)?;
-    }
+    storage::set_min_pin_length_policy(
+        env,
+        new_min_pin_length,
+        min_pin_length_rp_ids,
+        force_change_pin,
+    )?;
     Ok(ResponseData::AuthenticatorConfig)
 }
 
@@ -87,7 +95,8 @@ pub fn process_config(
 
     let enforce_uv =
         !matches!(sub_command, ConfigSubCommand::ToggleAlwaysUv) && storage::has_always_uv(env)?;
-    if storage::pin_hash(env)?.is_some() || enforce_uv {
+    let is_protected = storage::pin_hash(env)?.is_some() || env.user_verification().is_configured();
+    if is_protected || enforce_uv {
         let pin_uv_auth_param =
             pin_uv_auth_param.ok_or(Ctap2StatusCode::CTAP2_ERR_PUAT_REQUIRED)?;
         let pin_uv_auth_protocol =
@@ -107,6 +116,7 @@ pub fn process_config(
     }
 
     match sub_command {
+        #[cfg(feature = "enterprise_attestation")]
         ConfigSubCommand::EnableEnterpriseAttestation => process_enable_enterprise_attestation(env),
         ConfigSubCommand::ToggleAlwaysUv => process_toggle_always_uv(env),
         ConfigSubCommand::SetMinPinLength => {
@@ -129,6 +139,7 @@ mod test {
     use crate::env::test::TestEnv;
 
     #[test]
+    #[cfg(feature = "enterprise_attestation")]
     fn test_process_enable_enterprise_attestation() {
         let mut env = TestEnv::new();
         let key_agreement_key = crypto::ecdh::SecKey::gensk(env.rng());
@@ -163,16 +174,6 @@ mod test {
         let mut client_pin =
             ClientPin::new_test(key_agreement_key, pin_uv_auth_token, PinUvAuthProtocol::V1);
 
-        let config_params = AuthenticatorConfigParameters {
-            sub_command: ConfigSubCommand::ToggleAlwaysUv,
-            sub_command_params: None,
-            pin_uv_auth_param: None,
-            pin_uv_auth_protocol: None,
-        };
-        let config_response = process_config(&mut env, &mut client_pin, config_params);
-        assert_eq!(config_response, Ok(ResponseData::AuthenticatorConfig));
-        assert!(storage::has_always_uv(&mut env).unwrap());
-
         let config_params = AuthenticatorConfigParameters {
             sub_command: ConfigSubCommand::ToggleAlwaysUv,
             sub_command_params: None,
@@ -186,7 +187,8 @@ mod test {
                 Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED)
             );
         } else {
-            assert_eq!(config_response, Ok(ResponseData::AuthenticatorConfig));
+            // Without a PIN, there is no way to verify the user.
+            assert_eq!(config_response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET));
             assert!(!storage::has_always_uv(&mut env).unwrap());
         }
     }
@@ -241,6 +243,51 @@ mod test {
         test_helper_process_toggle_always_uv_with_pin(PinUvAuthProtocol::V2);
     }
 
+    #[test]
+    fn test_process_toggle_always_uv_with_built_in_uv() {
+        let mut env = TestEnv::new();
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(env.rng());
+        let pin_uv_auth_token = [0x55; 32];
+        let mut client_pin =
+            ClientPin::new_test(key_agreement_key, pin_uv_auth_token, PinUvAuthProtocol::V2);
+        env.user_verification().set_enrolled(Some(true));
+
+        // Built-in user verification protects the command like a PIN.
+        let config_params = AuthenticatorConfigParameters {
+            sub_command: ConfigSubCommand::ToggleAlwaysUv,
+            sub_command_params: None,
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let config_response = process_config(&mut env, &mut client_pin, config_params);
+        assert_eq!(
+            config_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_PUAT_REQUIRED)
+        );
+
+        let mut config_data = vec![0xFF; 32];
+        config_data.extend(&[0x0D, ConfigSubCommand::ToggleAlwaysUv as u8]);
+        let pin_uv_auth_param =
+            authenticate_pin_uv_auth_token(&pin_uv_auth_token, &config_data, PinUvAuthProtocol::V2);
+        let config_params = AuthenticatorConfigParameters {
+            sub_command: ConfigSubCommand::ToggleAlwaysUv,
+            sub_command_params: None,
+            pin_uv_auth_param: Some(pin_uv_auth_param),
+            pin_uv_auth_protocol: Some(PinUvAuthProtocol::V2),
+        };
+        let config_response = process_config(&mut env, &mut client_pin, config_params);
+        if env.customization().enforce_always_uv() {
+            assert_eq!(
+                config_response,
+                Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED)
+            );
+        } else {
+            // No PIN is needed to turn alwaysUv on.
+            assert_eq!(config_response, Ok(ResponseData::AuthenticatorConfig));
+            assert!(storage::has_always_uv(&mut env).unwrap());
+        }
+    }
+
     fn create_min_pin_config_params(
         min_pin_length: u8,
         min_pin_length_rp_ids: Option<Vec<String>>,
@@ -0,0 +1,513 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(feature = "ed25519")]
+use super::data_formats::EDDSA_ALGORITHM;
+use super::data_formats::{
+    extract_byte_string, extract_integer, extract_map, ok_or_missing, ES256_ALGORITHM,
+};
+use super::status_code::Ctap2StatusCode;
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "fuzz")]
+use arbitrary::Arbitrary;
+use arrayref::array_ref;
+use core::convert::TryFrom;
+use crypto::{ecdh, ecdsa, hybrid};
+use sk_cbor::{cbor_bytes, cbor_map_options, destructure_cbor_map};
+use {dilithium, sk_cbor as cbor};
+
+// The COSE key is used for both ECDH and ECDSA public keys for transmission.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzz", derive(Arbitrary))]
+pub struct CoseKey {
+    x_bytes: [u8; ecdh::NBYTES],
+    y_bytes: [u8; ecdh::NBYTES],
+    algorithm: i64,
+    key_type: i64,
+    curve: i64,
+    dilithium_bytes: Option<Vec<u8>>,
+}
+
+impl CoseKey {
+    // This is the algorithm specifier for ECDH.
+    // CTAP requests -25 which represents ECDH-ES + HKDF-256 here:
+    // https://www.iana.org/assignments/cose/cose.xhtml#algorithms
+    const ECDH_ALGORITHM: i64 = -25;
+    // The parameter behind map key 1.
+    const EC2_KEY_TYPE: i64 = 2;
+    #[cfg(feature = "ed25519")]
+    const OKP_KEY_TYPE: i64 = 1;
+    // The key type changes for hybrid. The value is made up.
+    const HYBRID_KEY_TYPE: i64 = -65537;
+    // The parameter behind map key -1.
+    const P_256_CURVE: i64 = 1;
+    #[cfg(feature = "ed25519")]
+    const ED25519_CURVE: i64 = 6;
+}
+
+/// Returns the value of a key parameter, which must be present.
+fn ok_or_invalid<T>(value_option: Option<T>) -> Result<T, Ctap2StatusCode> {
+    value_option.ok_or(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+}
+
+/// Extracts a coordinate of a P-256 point.
+fn extract_coordinate(
+    cbor_value: Option<cbor::Value>,
+) -> Result<[u8; ecdh::NBYTES], Ctap2StatusCode> {
+    let bytes = extract_byte_string(ok_or_invalid(cbor_value)?)?;
+    if bytes.len() != ecdh::NBYTES {
+        return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+    }
+    Ok(*array_ref![bytes.as_slice(), 0, ecdh::NBYTES])
+}
+
+// This conversion accepts both ECDH and ECDSA.
+//
+// All keys received from the platform are checked here, so that malformed keys never reach the
+// crypto layer. The curve and key type are part of the key, so unexpected values are invalid
+// parameters, while an unknown algorithm is unsupported.
+impl TryFrom<cbor::Value> for CoseKey {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        destructure_cbor_map! {
+            let {
+                // This is sorted correctly, negative encoding is bigger.
+                1 => key_type,
+                3 => algorithm,
+                -1 => curve,
+                -2 => x_bytes,
+                -3 => y_bytes,
+                -4 => dilithium_bytes,
+            } = extract_map(cbor_value)?;
+        }
+
+        let algorithm = extract_integer(ok_or_missing(algorithm)?)?;
+        if algorithm != CoseKey::ECDH_ALGORITHM && algorithm != ES256_ALGORITHM {
+            return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM);
+        }
+        let key_type = extract_integer(ok_or_invalid(key_type)?)?;
+        if key_type != CoseKey::EC2_KEY_TYPE && key_type != CoseKey::HYBRID_KEY_TYPE {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+        }
+        let curve = extract_integer(ok_or_invalid(curve)?)?;
+        if curve != CoseKey::P_256_CURVE {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+        }
+        let x_bytes = extract_coordinate(x_bytes)?;
+        let y_bytes = extract_coordinate(y_bytes)?;
+
+        let parsed_dilithium_bytes = if key_type == CoseKey::EC2_KEY_TYPE {
+            if dilithium_bytes.is_some() {
+                return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+            }
+            // The ECDH and ECDSA keys are points on the same curve.
+            if ecdh::PubKey::from_coordinates(&x_bytes, &y_bytes).is_none() {
+                return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+            }
+            None
+        } else {
+            let dilithium_bytes = extract_byte_string(ok_or_invalid(dilithium_bytes)?)?;
+            if dilithium_bytes.len() != dilithium::params::PK_SIZE_PACKED {
+                return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+            }
+            Some(dilithium_bytes)
+        };
+
+        Ok(CoseKey {
+            x_bytes,
+            y_bytes,
+            algorithm,
+            key_type,
+            curve,
+            dilithium_bytes: parsed_dilithium_bytes,
+        })
+    }
+}
+
+impl From<CoseKey> for cbor::Value {
+    fn from(cose_key: CoseKey) -> Self {
+        let CoseKey {
+            x_bytes,
+            y_bytes,
+            algorithm,
+            key_type,
+            curve,
+            dilithium_bytes,
+        } = cose_key;
+        // OKP keys only have an x coordinate.
+        #[cfg(feature = "ed25519")]
+        let y_bytes = Some(y_bytes).filter(|_| key_type != CoseKey::OKP_KEY_TYPE);
+        #[cfg(not(feature = "ed25519"))]
+        let y_bytes = Some(y_bytes);
+
+        cbor_map_options! {
+            1 => Some(key_type),
+            3 => Some(algorithm),
+            -1 => Some(curve),
+            -2 => Some(cbor_bytes!(x_bytes.to_vec())),
+            -3 => y_bytes.map(|y_bytes| cbor_bytes!(y_bytes.to_vec())),
+            -4 => dilithium_bytes.map(|b| cbor_bytes!(b)),
+        }
+    }
+}
+
+impl From<ecdh::PubKey> for CoseKey {
+    fn from(pk: ecdh::PubKey) -> Self {
+        let mut x_bytes = [0; ecdh::NBYTES];
+        let mut y_bytes = [0; ecdh::NBYTES];
+        pk.to_coordinates(&mut x_bytes, &mut y_bytes);
+        CoseKey {
+            x_bytes,
+            y_bytes,
+            algorithm: CoseKey::ECDH_ALGORITHM,
+            key_type: CoseKey::EC2_KEY_TYPE,
+            curve: CoseKey::P_256_CURVE,
+            dilithium_bytes: None,
+        }
+    }
+}
+
+impl From<ecdsa::PubKey> for CoseKey {
+    fn from(pk: ecdsa::PubKey) -> Self {
+        let mut x_bytes = [0; ecdsa::NBYTES];
+        let mut y_bytes = [0; ecdsa::NBYTES];
+        pk.to_coordinates(&mut x_bytes, &mut y_bytes);
+        CoseKey {
+            x_bytes,
+            y_bytes,
+            algorithm: ES256_ALGORITHM,
+            key_type: CoseKey::EC2_KEY_TYPE,
+            curve: CoseKey::P_256_CURVE,
+            dilithium_bytes: None,
+        }
+    }
+}
+
+#[cfg(feature = "ed25519")]
+impl From<ed25519_compact::PublicKey> for CoseKey {
+    fn from(pk: ed25519_compact::PublicKey) -> Self {
+        CoseKey {
+            x_bytes: *pk,
+            y_bytes: [0u8; 32],
+            key_type: CoseKey::OKP_KEY_TYPE,
+            curve: CoseKey::ED25519_CURVE,
+            algorithm: EDDSA_ALGORITHM,
+            dilithium_bytes: None,
+        }
+    }
+}
+
+impl From<hybrid::PubKey> for CoseKey {
+    fn from(pk: hybrid::PubKey) -> Self {
+        let ecdsa_x_bytes = [0; ecdsa::NBYTES];
+        let ecdsa_y_bytes = [0; ecdsa::NBYTES];
+
+        let mut dilithium_bytes = vec![0; hybrid::PubKey::BYTES_LENGTH];
+        dilithium_bytes.extend(pk.spx_pubkey);
+        CoseKey {
+            x_bytes: ecdsa_x_bytes,
+            y_bytes: ecdsa_y_bytes,
+            key_type: CoseKey::EC2_KEY_TYPE,
+            curve: CoseKey::P_256_CURVE,
+            algorithm: ES256_ALGORITHM,
+            dilithium_bytes: Some(dilithium_bytes),
+        }
+    }
+}
+
+impl TryFrom<CoseKey> for ecdh::PubKey {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cose_key: CoseKey) -> Result<Self, Ctap2StatusCode> {
+        let CoseKey {
+            x_bytes,
+            y_bytes,
+            algorithm,
+            key_type,
+            curve,
+            dilithium_bytes,
+        } = cose_key;
+
+        // Since algorithm can be used for different COSE key types, we check
+        // whether the current type is correct for ECDH. For an OpenSSH bugfix,
+        // the algorithm ES256_ALGORITHM is allowed here too.
+        // https://github.com/google/OpenSK/issues/90
+        if algorithm != CoseKey::ECDH_ALGORITHM && algorithm != ES256_ALGORITHM {
+            return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM);
+        }
+        if key_type != CoseKey::EC2_KEY_TYPE || curve != CoseKey::P_256_CURVE {
+            return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM);
+        }
+        if dilithium_bytes.is_some() {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+        }
+        ecdh::PubKey::from_coordinates(&x_bytes, &y_bytes)
+            .ok_or(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+    }
+}
+
+impl TryFrom<CoseKey> for ecdsa::PubKey {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cose_key: CoseKey) -> Result<Self, Ctap2StatusCode> {
+        let CoseKey {
+            x_bytes,
+            y_bytes,
+            algorithm,
+            key_type,
+            curve,
+            dilithium_bytes,
+        } = cose_key;
+
+        if algorithm != ES256_ALGORITHM
+            || key_type != CoseKey::EC2_KEY_TYPE
+            || curve != CoseKey::P_256_CURVE
+        {
+            return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM);
+        }
+        if dilithium_bytes.is_some() {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+        }
+        ecdsa::PubKey::from_coordinates(&x_bytes, &y_bytes)
+            .ok_or(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::test::TestEnv;
+    use sk_cbor::cbor_map;
+
+    /// Returns the coordinates of a valid P-256 point.
+    fn valid_coordinates() -> ([u8; 32], [u8; 32]) {
+        let mut env = TestEnv::new();
+        let pk = crypto::ecdh::SecKey::gensk(env.rng()).genpk();
+        let mut x_bytes = [0; 32];
+        let mut y_bytes = [0; 32];
+        pk.to_coordinates(&mut x_bytes, &mut y_bytes);
+        (x_bytes, y_bytes)
+    }
+
+    #[test]
+    fn test_from_into_cose_key_cbor() {
+        let (x_bytes, y_bytes) = valid_coordinates();
+        for algorithm in &[CoseKey::ECDH_ALGORITHM, ES256_ALGORITHM] {
+            let cbor_value = cbor_map! {
+                1 => CoseKey::EC2_KEY_TYPE,
+                3 => algorithm,
+                -1 => CoseKey::P_256_CURVE,
+                -2 => x_bytes,
+                -3 => y_bytes,
+            };
+            let cose_key = CoseKey::try_from(cbor_value.clone()).unwrap();
+            let created_cbor_value = cbor::Value::from(cose_key);
+            assert_eq!(created_cbor_value, cbor_value);
+        }
+    }
+
+    #[test]
+    fn test_cose_key_unknown_algorithm() {
+        let (x_bytes, y_bytes) = valid_coordinates();
+        let cbor_value = cbor_map! {
+            1 => CoseKey::EC2_KEY_TYPE,
+            // unknown algorithm
+            3 => 0,
+            -1 => CoseKey::P_256_CURVE,
+            -2 => x_bytes,
+            -3 => y_bytes,
+        };
+        assert_eq!(
+            CoseKey::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM)
+        );
+    }
+
+    #[test]
+    fn test_cose_key_unknown_type() {
+        let (x_bytes, y_bytes) = valid_coordinates();
+        let cbor_value = cbor_map! {
+            // unknown type
+            1 => 0,
+            3 => CoseKey::ECDH_ALGORITHM,
+            -1 => CoseKey::P_256_CURVE,
+            -2 => x_bytes,
+            -3 => y_bytes,
+        };
+        assert_eq!(
+            CoseKey::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_cose_key_unknown_curve() {
+        let (x_bytes, y_bytes) = valid_coordinates();
+        let cbor_value = cbor_map! {
+            1 => CoseKey::EC2_KEY_TYPE,
+            3 => CoseKey::ECDH_ALGORITHM,
+            // unknown curve
+            -1 => 0,
+            -2 => x_bytes,
+            -3 => y_bytes,
+        };
+        assert_eq!(
+            CoseKey::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_cose_key_missing_parameter() {
+        let (x_bytes, y_bytes) = valid_coordinates();
+        let cbor_values = vec![
+            cbor_map! {
+                3 => CoseKey::ECDH_ALGORITHM,
+                -1 => CoseKey::P_256_CURVE,
+                -2 => x_bytes,
+                -3 => y_bytes,
+            },
+            cbor_map! {
+                1 => CoseKey::EC2_KEY_TYPE,
+                3 => CoseKey::ECDH_ALGORITHM,
+                -2 => x_bytes,
+                -3 => y_bytes,
+            },
+            cbor_map! {
+                1 => CoseKey::EC2_KEY_TYPE,
+                3 => CoseKey::ECDH_ALGORITHM,
+                -1 => CoseKey::P_256_CURVE,
+                -3 => y_bytes,
+            },
+            cbor_map! {
+                1 => CoseKey::EC2_KEY_TYPE,
+                3 => CoseKey::ECDH_ALGORITHM,
+                -1 => CoseKey::P_256_CURVE,
+                -2 => x_bytes,
+            },
+        ];
+        for cbor_value in cbor_values {
+            assert_eq!(
+                CoseKey::try_from(cbor_value),
+                Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+            );
+        }
+    }
+
+    #[test]
+    fn test_cose_key_wrong_length_x() {
+        let (_, y_bytes) = valid_coordinates();
+        let cbor_value = cbor_map! {
+            1 => CoseKey::EC2_KEY_TYPE,
+            3 => CoseKey::ECDH_ALGORITHM,
+            -1 => CoseKey::P_256_CURVE,
+            // wrong length
+            -2 => [0u8; 31],
+            -3 => y_bytes,
+        };
+        assert_eq!(
+            CoseKey::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_cose_key_wrong_length_y() {
+        let (x_bytes, _) = valid_coordinates();
+        let cbor_value = cbor_map! {
+            1 => CoseKey::EC2_KEY_TYPE,
+            3 => CoseKey::ECDH_ALGORITHM,
+            -1 => CoseKey::P_256_CURVE,
+            -2 => x_bytes,
+            // wrong length
+            -3 => [0u8; 33],
+        };
+        assert_eq!(
+            CoseKey::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_cose_key_off_curve() {
+        let (x_bytes, mut y_bytes) = valid_coordinates();
+        y_bytes[31] ^= 0x01;
+        for (x_bytes, y_bytes) in &[(x_bytes, y_bytes), ([0u8; 32], [0u8; 32])] {
+            let cbor_value = cbor_map! {
+                1 => CoseKey::EC2_KEY_TYPE,
+                3 => CoseKey::ECDH_ALGORITHM,
+                -1 => CoseKey::P_256_CURVE,
+                -2 => *x_bytes,
+                -3 => *y_bytes,
+            };
+            assert_eq!(
+                CoseKey::try_from(cbor_value),
+                Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+            );
+        }
+    }
+
+    #[test]
+    fn test_cose_key_unexpected_dilithium_bytes() {
+        let (x_bytes, y_bytes) = valid_coordinates();
+        let cbor_value = cbor_map! {
+            1 => CoseKey::EC2_KEY_TYPE,
+            3 => CoseKey::ECDH_ALGORITHM,
+            -1 => CoseKey::P_256_CURVE,
+            -2 => x_bytes,
+            -3 => y_bytes,
+            -4 => vec![0x00; dilithium::params::PK_SIZE_PACKED],
+        };
+        assert_eq!(
+            CoseKey::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_from_into_cose_key_ecdh() {
+        let mut env = TestEnv::new();
+        let sk = crypto::ecdh::SecKey::gensk(env.rng());
+        let pk = sk.genpk();
+        let cose_key = CoseKey::from(pk.clone());
+        let created_pk = ecdh::PubKey::try_from(cose_key);
+        assert_eq!(created_pk, Ok(pk));
+    }
+
+    #[test]
+    fn test_into_cose_key_ecdsa() {
+        let mut env = TestEnv::new();
+        let sk = crypto::ecdsa::SecKey::gensk(env.rng());
+        let pk = sk.genpk();
+        let cose_key = CoseKey::from(pk);
+        assert_eq!(cose_key.algorithm, ES256_ALGORITHM);
+    }
+
+    #[test]
+    #[cfg(feature = "ed25519")]
+    fn test_into_cose_key_ed25519() {
+        let seed = ed25519_compact::Seed::from_slice(&[0x55; 32]).unwrap();
+        let pk = ed25519_compact::KeyPair::from_seed(seed).pk;
+        let cose_key = CoseKey::from(pk);
+        let expected_cbor = cbor_map! {
+            1 => 1,
+            3 => -8,
+            -1 => 6,
+            -2 => cbor_bytes!(pk.to_vec()),
+        };
+        assert_eq!(cbor::Value::from(cose_key), expected_cbor);
+    }
+}
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::crypto_wrapper::{aes256_cbc_decrypt, aes256_cbc_encrypt, PrivateKey};
+use super::crypto_wrapper::{aes256_cbc_decrypt, aes256_cbc_encrypt, random_iv, PrivateKey};
 use super::data_formats::{
     CredentialProtectionPolicy, PublicKeyCredentialSource, PublicKeyCredentialType,
 };
@@ -26,6 +26,7 @@ use alloc::vec::Vec;
 use core::convert::{TryFrom, TryInto};
 use crypto::hmac::{hmac_256, verify_hmac_256};
 use crypto::sha256::Sha256;
+use crypto::util::ct_eq;
 use sk_cbor::{cbor_map_options, destructure_cbor_map};
 
 pub const LEGACY_CREDENTIAL_ID_SIZE: usize = 112;
@@ -69,8 +70,8 @@ fn decrypt_legacy_credential_id(
     env: &mut impl Env,
     bytes: &[u8],
 ) -> Result<Option<CredentialSource>, Ctap2StatusCode> {
-    let aes_enc_key = crypto::aes256::EncryptionKey::new(&env.key_store().key_handle_encryption()?);
-    let plaintext = aes256_cbc_decrypt(&aes_enc_key, bytes, true)?;
+    let aes_key = env.key_store().key_handle_encryption()?;
+    let plaintext = aes256_cbc_decrypt(env.aes(), &aes_key, bytes, true)?;
     if plaintext.len() != 64 {
         return Ok(None);
     }
@@ -91,8 +92,8 @@ fn decrypt_cbor_credential_id(
     env: &mut impl Env,
     bytes: &[u8],
 ) -> Result<Option<CredentialSource>, Ctap2StatusCode> {
-    let aes_enc_key = crypto::aes256::EncryptionKey::new(&env.key_store().key_handle_encryption()?);
-    let mut plaintext = aes256_cbc_decrypt(&aes_enc_key, bytes, true)?;
+    let aes_key = env.key_store().key_handle_encryption()?;
+    let mut plaintext = aes256_cbc_decrypt(env.aes(), &aes_key, bytes, true)?;
     remove_padding(&mut plaintext)?;
 
     let cbor_credential_source = cbor_read(plaintext.as_slice())?;
@@ -178,8 +179,9 @@ pub fn encrypt_to_credential_id(
     cbor_write(cbor, &mut payload)?;
     add_padding(&mut payload)?;
 
-    let aes_enc_key = crypto::aes256::EncryptionKey::new(&env.key_store().key_handle_encryption()?);
-    let encrypted_payload = aes256_cbc_encrypt(env.rng(), &aes_enc_key, &payload, true)?;
+    let aes_key = env.key_store().key_handle_encryption()?;
+    let iv = random_iv(env.rng());
+    let encrypted_payload = aes256_cbc_encrypt(env.aes(), &aes_key, Some(iv), &payload)?;
     let mut credential_id = encrypted_payload;
     credential_id.insert(0, CBOR_CREDENTIAL_ID_VERSION);
 
@@ -245,7 +247,7 @@ pub fn decrypt_credential_id(
         return Ok(None);
     };
 
-    if rp_id_hash != credential_source.rp_id_hash {
+    if !ct_eq(rp_id_hash, &credential_source.rp_id_hash) {
         return Ok(None);
     }
 
@@ -339,6 +341,30 @@ mod test {
         }
     }
 
+    fn test_decrypt_credential_other_rp(signature_algorithm: SignatureAlgorithm) {
+        let mut env = TestEnv::new();
+        let private_key = PrivateKey::new(&mut env, signature_algorithm);
+
+        let rp_id_hash = [0x55; 32];
+        let encrypted_id =
+            encrypt_to_credential_id(&mut env, &private_key, &rp_id_hash, None, None).unwrap();
+        assert_eq!(
+            decrypt_credential_id(&mut env, encrypted_id, &[0xAA; 32]),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_ecdsa_decrypt_credential_other_rp() {
+        test_decrypt_credential_other_rp(SignatureAlgorithm::Es256);
+    }
+
+    #[test]
+    #[cfg(feature = "ed25519")]
+    fn test_ed25519_decrypt_credential_other_rp() {
+        test_decrypt_credential_other_rp(SignatureAlgorithm::Eddsa);
+    }
+
     #[test]
     fn test_ecdsa_encrypt_decrypt_bad_hmac() {
         test_encrypt_decrypt_bad_hmac(SignatureAlgorithm::Es256);
@@ -383,13 +409,13 @@ mod test {
         private_key: crypto::ecdsa::SecKey,
         application: &[u8; 32],
     ) -> Result<Vec<u8>, Ctap2StatusCode> {
-        let aes_enc_key =
-            crypto::aes256::EncryptionKey::new(&env.key_store().key_handle_encryption()?);
+        let aes_key = env.key_store().key_handle_encryption()?;
         let mut plaintext = [0; 64];
         private_key.to_bytes(array_mut_ref!(plaintext, 0, 32));
         plaintext[32..64].copy_from_slice(application);
 
-        let mut encrypted_id = aes256_cbc_encrypt(env.rng(), &aes_enc_key, &plaintext, true)?;
+        let iv = random_iv(env.rng());
+        let mut encrypted_id = aes256_cbc_encrypt(env.aes(), &aes_key, Some(iv), &plaintext)?;
         let id_hmac = hmac_256::<Sha256>(
             &env.key_store().key_handle_authentication()?,
             &encrypted_id[..],
@@ -416,6 +442,21 @@ mod test {
         assert!(decrypted_source.cred_protect_policy.is_none());
     }
 
+    #[test]
+    fn test_decrypt_credential_legacy_other_rp() {
+        let mut env = TestEnv::new();
+        let private_key = PrivateKey::new_ecdsa(&mut env);
+        let ecdsa_key = private_key.ecdsa_key(&mut env).unwrap();
+
+        let rp_id_hash = [0x55; 32];
+        let encrypted_id =
+            legacy_encrypt_to_credential_id(&mut env, ecdsa_key, &rp_id_hash).unwrap();
+        assert_eq!(
+            decrypt_credential_id(&mut env, encrypted_id, &[0xAA; 32]),
+            Ok(None)
+        );
+    }
+
     #[test]
     fn test_encrypt_credential_size() {
         let mut env = TestEnv::new();
@@ -30,6 +30,7 @@ use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use crypto::sha256::Sha256;
+use crypto::util::ct_eq;
 use crypto::Hash256;
 
 /// Generates a set with all existing RP IDs.
@@ -188,7 +189,7 @@ fn process_enumerate_credentials_begin(
     let mut rp_credentials: Vec<usize> = iter
         .filter_map(|(key, credential)| {
             let cred_rp_id_hash = Sha256::hash(credential.rp_id.as_bytes());
-            if cred_rp_id_hash == rp_id_hash.as_slice() {
+            if ct_eq(&cred_rp_id_hash, &rp_id_hash) {
                 Some(key)
             } else {
                 None
@@ -814,6 +815,33 @@ mod test {
             cred_management_response,
             Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)
         );
+
+        let management_data = vec![CredentialManagementSubCommand::GetCredsMetadata as u8];
+        let pin_uv_auth_param = authenticate_pin_uv_auth_token(
+            &pin_uv_auth_token,
+            &management_data,
+            PinUvAuthProtocol::V1,
+        );
+        let cred_management_params = AuthenticatorCredentialManagementParameters {
+            sub_command: CredentialManagementSubCommand::GetCredsMetadata,
+            sub_command_params: None,
+            pin_uv_auth_protocol: Some(PinUvAuthProtocol::V1),
+            pin_uv_auth_param: Some(pin_uv_auth_param),
+        };
+        let cred_management_response = process_credential_management(
+            &mut env,
+            &mut ctap_state.stateful_command_permission,
+            &mut ctap_state.client_pin,
+            cred_management_params,
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        match cred_management_response.unwrap() {
+            ResponseData::AuthenticatorCredentialManagement(Some(response)) => {
+                assert_eq!(response.existing_resident_credentials_count, Some(0));
+            }
+            _ => panic!("Invalid response type"),
+        };
     }
 
     #[test]
@@ -12,48 +12,54 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::api::aes::Aes256;
 use crate::api::key_store::KeyStore;
-use crate::ctap::data_formats::{extract_array, extract_byte_string, CoseKey, SignatureAlgorithm};
+use crate::ctap::cose::CoseKey;
+use crate::ctap::data_formats::{extract_array, extract_byte_string, SignatureAlgorithm};
 use crate::ctap::status_code::Ctap2StatusCode;
 use crate::env::Env;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::convert::TryFrom;
-use crypto::cbc::{cbc_decrypt, cbc_encrypt};
 use crypto::sha256::Sha256;
 use crypto::{ecdsa, hybrid};
 use rng256::Rng256;
 use sk_cbor as cbor;
 use sk_cbor::{cbor_array, cbor_bytes, cbor_int};
 
+/// Returns a random IV for [`aes256_cbc_encrypt`].
+pub fn random_iv(rng: &mut dyn Rng256) -> [u8; 16] {
+    let random_bytes = rng.gen_uniform_u8x32();
+    *array_ref!(random_bytes, 0, 16)
+}
+
 /// Wraps the AES256-CBC encryption to match what we need in CTAP.
+///
+/// A given IV is embedded before the ciphertext. Without IV, a zero IV is used.
 pub fn aes256_cbc_encrypt(
-    rng: &mut dyn Rng256,
-    aes_enc_key: &crypto::aes256::EncryptionKey,
+    aes: &mut impl Aes256,
+    aes_key: &[u8; 32],
+    iv: Option<[u8; 16]>,
     plaintext: &[u8],
-    embeds_iv: bool,
 ) -> Result<Vec<u8>, Ctap2StatusCode> {
     if plaintext.len() % 16 != 0 {
         return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
     }
     // The extra 1 capacity is because encrypt_key_handle adds a version number.
-    let mut ciphertext = Vec::with_capacity(plaintext.len() + 16 * embeds_iv as usize + 1);
-    let iv = if embeds_iv {
-        let random_bytes = rng.gen_uniform_u8x32();
-        ciphertext.extend_from_slice(&random_bytes[..16]);
-        *array_ref!(ciphertext, 0, 16)
-    } else {
-        [0u8; 16]
-    };
+    let mut ciphertext = Vec::with_capacity(plaintext.len() + 16 * iv.is_some() as usize + 1);
+    if let Some(iv) = &iv {
+        ciphertext.extend_from_slice(iv);
+    }
     let start = ciphertext.len();
     ciphertext.extend_from_slice(plaintext);
-    cbc_encrypt(aes_enc_key, iv, &mut ciphertext[start..]);
+    aes.cbc_encrypt(aes_key, &iv.unwrap_or([0; 16]), &mut ciphertext[start..]);
     Ok(ciphertext)
 }
 
 /// Wraps the AES256-CBC decryption to match what we need in CTAP.
 pub fn aes256_cbc_decrypt(
-    aes_enc_key: &crypto::aes256::EncryptionKey,
+    aes: &mut impl Aes256,
+    aes_key: &[u8; 32],
     ciphertext: &[u8],
     embeds_iv: bool,
 ) -> Result<Vec<u8>, Ctap2StatusCode> {
@@ -67,8 +73,7 @@ pub fn aes256_cbc_decrypt(
         ([0u8; 16], ciphertext)
     };
     let mut plaintext = ciphertext.to_vec();
-    let aes_dec_key = crypto::aes256::DecryptionKey::new(aes_enc_key);
-    cbc_decrypt(&aes_dec_key, iv, &mut plaintext);
+    aes.cbc_decrypt(aes_key, &iv, &mut plaintext);
     Ok(plaintext)
 }
 
@@ -283,58 +288,62 @@ mod test {
     #[test]
     fn test_encrypt_decrypt_with_iv() {
         let mut env = TestEnv::new();
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&[0xC2; 32]);
+        let aes_key = [0xC2; 32];
         let plaintext = vec![0xAA; 64];
-        let ciphertext = aes256_cbc_encrypt(env.rng(), &aes_enc_key, &plaintext, true).unwrap();
-        let decrypted = aes256_cbc_decrypt(&aes_enc_key, &ciphertext, true).unwrap();
+        let iv = random_iv(env.rng());
+        let ciphertext = aes256_cbc_encrypt(env.aes(), &aes_key, Some(iv), &plaintext).unwrap();
+        let decrypted = aes256_cbc_decrypt(env.aes(), &aes_key, &ciphertext, true).unwrap();
         assert_eq!(decrypted, plaintext);
     }
 
     #[test]
     fn test_encrypt_decrypt_without_iv() {
         let mut env = TestEnv::new();
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&[0xC2; 32]);
+        let aes_key = [0xC2; 32];
         let plaintext = vec![0xAA; 64];
-        let ciphertext = aes256_cbc_encrypt(env.rng(), &aes_enc_key, &plaintext, false).unwrap();
-        let decrypted = aes256_cbc_decrypt(&aes_enc_key, &ciphertext, false).unwrap();
+        let ciphertext = aes256_cbc_encrypt(env.aes(), &aes_key, None, &plaintext).unwrap();
+        let decrypted = aes256_cbc_decrypt(env.aes(), &aes_key, &ciphertext, false).unwrap();
         assert_eq!(decrypted, plaintext);
     }
 
     #[test]
     fn test_correct_iv_usage() {
         let mut env = TestEnv::new();
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&[0xC2; 32]);
+        let aes_key = [0xC2; 32];
         let plaintext = vec![0xAA; 64];
         let mut ciphertext_no_iv =
-            aes256_cbc_encrypt(env.rng(), &aes_enc_key, &plaintext, false).unwrap();
+            aes256_cbc_encrypt(env.aes(), &aes_key, None, &plaintext).unwrap();
         let mut ciphertext_with_iv = vec![0u8; 16];
         ciphertext_with_iv.append(&mut ciphertext_no_iv);
-        let decrypted = aes256_cbc_decrypt(&aes_enc_key, &ciphertext_with_iv, true).unwrap();
+        let decrypted = aes256_cbc_decrypt(env.aes(), &aes_key, &ciphertext_with_iv, true).unwrap();
         assert_eq!(decrypted, plaintext);
     }
 
     #[test]
     fn test_iv_manipulation_property() {
         let mut env = TestEnv::new();
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&[0xC2; 32]);
+        let aes_key = [0xC2; 32];
         let plaintext = vec![0xAA; 64];
-        let mut ciphertext = aes256_cbc_encrypt(env.rng(), &aes_enc_key, &plaintext, true).unwrap();
+        let iv = random_iv(env.rng());
+        let mut ciphertext = aes256_cbc_encrypt(env.aes(), &aes_key, Some(iv), &plaintext).unwrap();
         let mut expected_plaintext = plaintext;
         for i in 0..16 {
             ciphertext[i] ^= 0xBB;
             expected_plaintext[i] ^= 0xBB;
         }
-        let decrypted = aes256_cbc_decrypt(&aes_enc_key, &ciphertext, true).unwrap();
+        let decrypted = aes256_cbc_decrypt(env.aes(), &aes_key, &ciphertext, true).unwrap();
         assert_eq!(decrypted, expected_plaintext);
     }
 
     #[test]
     fn test_chaining() {
         let mut env = TestEnv::new();
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&[0xC2; 32]);
+        let aes_key = [0xC2; 32];
         let plaintext = vec![0xAA; 64];
-        let ciphertext1 = aes256_cbc_encrypt(env.rng(), &aes_enc_key, &plaintext, true).unwrap();
-        let ciphertext2 = aes256_cbc_encrypt(env.rng(), &aes_enc_key, &plaintext, true).unwrap();
+        let iv1 = random_iv(env.rng());
+        let ciphertext1 = aes256_cbc_encrypt(env.aes(), &aes_key, Some(iv1), &plaintext).unwrap();
+        let iv2 = random_iv(env.rng());
+        let ciphertext2 = aes256_cbc_encrypt(env.aes(), &aes_key, Some(iv2), &plaintext).unwrap();
         assert_eq!(ciphertext1.len(), 80);
         assert_eq!(ciphertext2.len(), 80);
         // The ciphertext should mutate in all blocks with a different IV.
@@ -368,6 +377,67 @@ mod test {
         );
     }
 
+    #[test]
+    #[cfg(feature = "ed25519")]
+    fn test_ed25519_rfc8032_test_vectors() {
+        // Tests 1 and 2 from RFC 8032, section 7.1.
+        let test_vectors: [(&[u8], &[u8], &[u8], &[u8]); 2] = [
+            (
+                &[
+                    0x9D, 0x61, 0xB1, 0x9D, 0xEF, 0xFD, 0x5A, 0x60, 0xBA, 0x84, 0x4A, 0xF4, 0x92,
+                    0xEC, 0x2C, 0xC4, 0x44, 0x49, 0xC5, 0x69, 0x7B, 0x32, 0x69, 0x19, 0x70, 0x3B,
+                    0xAC, 0x03, 0x1C, 0xAE, 0x7F, 0x60,
+                ],
+                &[
+                    0xD7, 0x5A, 0x98, 0x01, 0x82, 0xB1, 0x0A, 0xB7, 0xD5, 0x4B, 0xFE, 0xD3, 0xC9,
+                    0x64, 0x07, 0x3A, 0x0E, 0xE1, 0x72, 0xF3, 0xDA, 0xA6, 0x23, 0x25, 0xAF, 0x02,
+                    0x1A, 0x68, 0xF7, 0x07, 0x51, 0x1A,
+                ],
+                &[],
+                &[
+                    0xE5, 0x56, 0x43, 0x00, 0xC3, 0x60, 0xAC, 0x72, 0x90, 0x86, 0xE2, 0xCC, 0x80,
+                    0x6E, 0x82, 0x8A, 0x84, 0x87, 0x7F, 0x1E, 0xB8, 0xE5, 0xD9, 0x74, 0xD8, 0x73,
+                    0xE0, 0x65, 0x22, 0x49, 0x01, 0x55, 0x5F, 0xB8, 0x82, 0x15, 0x90, 0xA3, 0x3B,
+                    0xAC, 0xC6, 0x1E, 0x39, 0x70, 0x1C, 0xF9, 0xB4, 0x6B, 0xD2, 0x5B, 0xF5, 0xF0,
+                    0x59, 0x5B, 0xBE, 0x24, 0x65, 0x51, 0x41, 0x43, 0x8E, 0x7A, 0x10, 0x0B,
+                ],
+            ),
+            (
+                &[
+                    0x4C, 0xCD, 0x08, 0x9B, 0x28, 0xFF, 0x96, 0xDA, 0x9D, 0xB6, 0xC3, 0x46, 0xEC,
+                    0x11, 0x4E, 0x0F, 0x5B, 0x8A, 0x31, 0x9F, 0x35, 0xAB, 0xA6, 0x24, 0xDA, 0x8C,
+                    0xF6, 0xED, 0x4F, 0xB8, 0xA6, 0xFB,
+                ],
+                &[
+                    0x3D, 0x40, 0x17, 0xC3, 0xE8, 0x43, 0x89, 0x5A, 0x92, 0xB7, 0x0A, 0xA7, 0x4D,
+                    0x1B, 0x7E, 0xBC, 0x9C, 0x98, 0x2C, 0xCF, 0x2E, 0xC4, 0x96, 0x8C, 0xC0, 0xCD,
+                    0x55, 0xF1, 0x2A, 0xF4, 0x66, 0x0C,
+                ],
+                &[0x72],
+                &[
+                    0x92, 0xA0, 0x09, 0xA9, 0xF0, 0xD4, 0xCA, 0xB8, 0x72, 0x0E, 0x82, 0x0B, 0x5F,
+                    0x64, 0x25, 0x40, 0xA2, 0xB2, 0x7B, 0x54, 0x16, 0x50, 0x3F, 0x8F, 0xB3, 0x76,
+                    0x22, 0x23, 0xEB, 0xDB, 0x69, 0xDA, 0x08, 0x5A, 0xC1, 0xE4, 0x3E, 0x15, 0x99,
+                    0x6E, 0x45, 0x8F, 0x36, 0x13, 0xD0, 0xF1, 0x1D, 0x8C, 0x38, 0x7B, 0x2E, 0xAE,
+                    0xB4, 0x30, 0x2A, 0xEE, 0xB0, 0x0D, 0x29, 0x16, 0x12, 0xBB, 0x0C, 0x00,
+                ],
+            ),
+        ];
+        let mut env = TestEnv::new();
+        for (secret_key, public_key, message, signature) in test_vectors.iter() {
+            let private_key = PrivateKey::new_ed25519_from_bytes(secret_key).unwrap();
+            let public_key = ed25519_compact::PublicKey::from_slice(public_key).unwrap();
+            assert_eq!(
+                private_key.get_pub_key(&mut env),
+                Ok(CoseKey::from(public_key))
+            );
+            assert_eq!(
+                private_key.sign_and_encode(&mut env, message),
+                Ok(signature.to_vec())
+            );
+        }
+    }
+
     #[test]
     fn test_new_ecdsa_from_bytes_wrong_length() {
         assert_eq!(PrivateKey::new_ecdsa_from_bytes(&[0x55; 16]), None);
@@ -410,6 +480,28 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_private_key_sign_is_deterministic() {
+        let mut env = TestEnv::new();
+        let message = [0x5A; 32];
+        let private_key = PrivateKey::new_ecdsa(&mut env);
+        let ecdsa_key = private_key.ecdsa_key(&mut env).unwrap();
+        let signature = ecdsa_key.sign_rfc6979::<Sha256>(&message);
+        assert!(ecdsa_key
+            .genpk()
+            .verify_vartime::<Sha256>(&message, &signature));
+        // The nonce only depends on the key and message, not on the RNG state.
+        env.rng().gen_uniform_u8x32();
+        assert_eq!(
+            private_key.sign_and_encode(&mut env, &message),
+            private_key.sign_and_encode(&mut env, &message)
+        );
+        assert_ne!(
+            private_key.sign_and_encode(&mut env, &message),
+            private_key.sign_and_encode(&mut env, &[0xA5; 32])
+        );
+    }
+
     fn test_private_key_signature_algorithm(signature_algorithm: SignatureAlgorithm) {
         let mut env = TestEnv::new();
         let private_key = PrivateKey::new(&mut env, signature_algorithm);
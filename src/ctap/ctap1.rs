@@ -259,6 +259,7 @@ impl Ctap1Command {
         let Attestation {
             private_key,
             certificate,
+            ..
         } = env
             .attestation_store()
             .get(&attestation_store::Id::Batch)?
@@ -320,13 +321,14 @@ impl Ctap1Command {
                 return Err(Ctap1StatusCode::SW_COND_USE_NOT_SATISFIED);
             }
             ctap_state
-                .increment_global_signature_counter(env)
+                .increment_signature_counter(env, &credential_source.credential_id)
                 .map_err(|_| Ctap1StatusCode::SW_WRONG_DATA)?;
             let mut signature_data = ctap_state
                 .generate_auth_data(
                     env,
                     &application,
                     Ctap1Command::USER_PRESENCE_INDICATOR_BYTE,
+                    &credential_source.credential_id,
                 )
                 .map_err(|_| Ctap1StatusCode::SW_WRONG_DATA)?;
             signature_data.extend(&challenge);
@@ -347,8 +349,9 @@ mod test {
     use super::super::data_formats::SignatureAlgorithm;
     use super::*;
     use crate::api::customization::Customization;
+    use crate::api::signature_counter::{SignatureCounter, SignatureCounterStrategy};
     use crate::clock::TEST_CLOCK_FREQUENCY_HZ;
-    use crate::ctap::storage;
+    use crate::ctap::{storage, INITIAL_SIGNATURE_COUNTER};
     use crate::env::test::TestEnv;
     use crypto::Hash256;
 
@@ -406,6 +409,23 @@ mod test {
         assert_eq!(response, Err(Ctap1StatusCode::SW_COMMAND_NOT_ALLOWED));
     }
 
+    #[test]
+    fn test_process_disallowed_by_customization() {
+        let mut env = TestEnv::new();
+        env.user_presence()
+            .set(|| panic!("Unexpected user presence check in CTAP1"));
+        env.customization_mut().set_allows_ctap1(false);
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let application = [0x0A; 32];
+        let message = create_register_message(&application);
+        ctap_state.u2f_up_state.consume_up(CtapInstant::new(0));
+        ctap_state.u2f_up_state.grant_up(CtapInstant::new(0));
+        let response =
+            Ctap1Command::process_command(&mut env, &message, &mut ctap_state, CtapInstant::new(0));
+        assert_eq!(response, Err(Ctap1StatusCode::SW_COMMAND_NOT_ALLOWED));
+    }
+
     #[test]
     fn test_process_register() {
         let mut env = TestEnv::new();
@@ -425,6 +445,7 @@ mod test {
         let attestation = Attestation {
             private_key: [0x41; 32],
             certificate: vec![0x99; 100],
+            intermediate_certificates: vec![],
         };
         env.attestation_store()
             .set(&attestation_store::Id::Batch, Some(&attestation))
@@ -651,12 +672,110 @@ mod test {
             Ctap1Command::process_command(&mut env, &message, &mut ctap_state, CtapInstant::new(0))
                 .unwrap();
         assert_eq!(response[0], 0x01);
-        let global_signature_counter = storage::global_signature_counter(&mut env).unwrap();
-        check_signature_counter(
-            &mut env,
-            array_ref!(response, 1, 4),
-            global_signature_counter,
+        let signature_counter = env.signature_counter().get_counter(&key_handle).unwrap();
+        check_signature_counter(&mut env, array_ref!(response, 1, 4), signature_counter);
+    }
+
+    #[test]
+    fn test_process_authenticate_per_credential_counter_is_global() {
+        let mut env = TestEnv::new();
+        env.user_presence()
+            .set(|| panic!("Unexpected user presence check in CTAP1"));
+        env.customization_mut()
+            .set_signature_counter_strategy(SignatureCounterStrategy::PerCredential);
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let application = crypto::sha256::Sha256::hash(b"example.com");
+        let sk1 = PrivateKey::new(&mut env, SignatureAlgorithm::Es256);
+        let key_handle1 =
+            encrypt_to_credential_id(&mut env, &sk1, &application, None, None).unwrap();
+        let sk2 = PrivateKey::new(&mut env, SignatureAlgorithm::Es256);
+        let key_handle2 =
+            encrypt_to_credential_id(&mut env, &sk2, &application, None, None).unwrap();
+
+        let mut authenticate = |key_handle: &[u8]| {
+            let message = create_authenticate_message(
+                &application,
+                Ctap1Flags::DontEnforceUpAndSign,
+                key_handle,
+            );
+            let response = Ctap1Command::process_command(
+                &mut env,
+                &message,
+                &mut ctap_state,
+                CtapInstant::new(0),
+            )
+            .unwrap();
+            u32::from_be_bytes(*array_ref!(response, 1, 4))
+        };
+        // Key handles are not resident, so they share the global counter.
+        let value1 = authenticate(&key_handle1);
+        let value2 = authenticate(&key_handle2);
+        assert!(value1 > INITIAL_SIGNATURE_COUNTER);
+        assert!(value2 > value1);
+        assert_eq!(
+            env.signature_counter().get_counter(&key_handle1),
+            Ok(value2)
+        );
+    }
+
+    #[test]
+    fn test_process_register_then_authenticate() {
+        let mut env = TestEnv::new();
+        env.user_presence()
+            .set(|| panic!("Unexpected user presence check in CTAP1"));
+        let attestation = Attestation {
+            private_key: [0x41; 32],
+            certificate: vec![0x99; 100],
+            intermediate_certificates: vec![],
+        };
+        env.attestation_store()
+            .set(&attestation_store::Id::Batch, Some(&attestation))
+            .unwrap();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let rp_id = "example.com";
+        let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
+        let message = create_register_message(&application);
+        ctap_state.u2f_up_state.consume_up(CtapInstant::new(0));
+        ctap_state.u2f_up_state.grant_up(CtapInstant::new(0));
+        let response =
+            Ctap1Command::process_command(&mut env, &message, &mut ctap_state, CtapInstant::new(0))
+                .unwrap();
+        let key_handle = response[67..67 + CBOR_CREDENTIAL_ID_SIZE].to_vec();
+
+        // A credential made through CTAP1 is also a valid CTAP2 credential.
+        let credential = decrypt_credential_id(&mut env, key_handle.clone(), &application)
+            .unwrap()
+            .unwrap();
+        let pk = credential.private_key.ecdsa_key(&mut env).unwrap().genpk();
+        let mut pk_bytes = [0; 65];
+        pk.to_bytes_uncompressed(&mut pk_bytes);
+        assert_eq!(&response[1..66], &pk_bytes[..]);
+
+        let message =
+            create_authenticate_message(&application, Ctap1Flags::EnforceUpAndSign, &key_handle);
+        ctap_state.u2f_up_state.consume_up(CtapInstant::new(0));
+        ctap_state.u2f_up_state.grant_up(CtapInstant::new(0));
+        let response =
+            Ctap1Command::process_command(&mut env, &message, &mut ctap_state, CtapInstant::new(0))
+                .unwrap();
+        assert_eq!(response[0], Ctap1Command::USER_PRESENCE_INDICATOR_BYTE);
+        let signature_counter = env.signature_counter().get_counter(&key_handle).unwrap();
+        check_signature_counter(&mut env, array_ref!(response, 1, 4), signature_counter);
+
+        // The key handle is bound to the application.
+        let other_application = crypto::sha256::Sha256::hash(b"other.com");
+        let message = create_authenticate_message(
+            &other_application,
+            Ctap1Flags::EnforceUpAndSign,
+            &key_handle,
         );
+        ctap_state.u2f_up_state.consume_up(CtapInstant::new(0));
+        ctap_state.u2f_up_state.grant_up(CtapInstant::new(0));
+        let response =
+            Ctap1Command::process_command(&mut env, &message, &mut ctap_state, CtapInstant::new(0));
+        assert_eq!(response, Err(Ctap1StatusCode::SW_WRONG_DATA));
     }
 
     #[test]
@@ -684,12 +803,8 @@ mod test {
         )
         .unwrap();
         assert_eq!(response[0], 0x01);
-        let global_signature_counter = storage::global_signature_counter(&mut env).unwrap();
-        check_signature_counter(
-            &mut env,
-            array_ref!(response, 1, 4),
-            global_signature_counter,
-        );
+        let signature_counter = env.signature_counter().get_counter(&key_handle).unwrap();
+        check_signature_counter(&mut env, array_ref!(response, 1, 4), signature_counter);
     }
 
     #[test]
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use super::cose::CoseKey;
 use super::crypto_wrapper::PrivateKey;
 use super::status_code::Ctap2StatusCode;
 use alloc::string::String;
@@ -19,13 +20,11 @@ use alloc::vec;
 use alloc::vec::Vec;
 #[cfg(feature = "fuzz")]
 use arbitrary::Arbitrary;
-use arrayref::array_ref;
 use core::convert::TryFrom;
-use crypto::{ecdh, ecdsa, hybrid};
 #[cfg(test)]
 use enum_iterator::IntoEnumIterator;
+use sk_cbor as cbor;
 use sk_cbor::{cbor_array_vec, cbor_bytes, cbor_map_options, destructure_cbor_map};
-use {dilithium, sk_cbor as cbor};
 
 // Used as the identifier for ECDSA in assertion signatures and COSE.
 pub const ES256_ALGORITHM: i64 = -7;
@@ -285,6 +284,20 @@ impl From<PublicKeyCredentialDescriptor> for cbor::Value {
     }
 }
 
+/// Parses the input of a known extension, if present.
+///
+/// Unknown extensions are ignored, since only known keys are destructured. A known extension with
+/// a malformed input fails the whole request with `CTAP1_ERR_INVALID_PARAMETER`.
+fn parse_extension_input<T>(
+    input: Option<cbor::Value>,
+    parse: impl FnOnce(cbor::Value) -> Result<T, Ctap2StatusCode>,
+) -> Result<Option<T>, Ctap2StatusCode> {
+    input
+        .map(parse)
+        .transpose()
+        .map_err(|_| Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+}
+
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 #[cfg_attr(feature = "fuzz", derive(Arbitrary))]
 pub struct MakeCredentialExtensions {
@@ -309,13 +322,12 @@ impl TryFrom<cbor::Value> for MakeCredentialExtensions {
             } = extract_map(cbor_value)?;
         }
 
-        let hmac_secret = hmac_secret.map_or(Ok(false), extract_bool)?;
-        let cred_protect = cred_protect
-            .map(CredentialProtectionPolicy::try_from)
-            .transpose()?;
-        let min_pin_length = min_pin_length.map_or(Ok(false), extract_bool)?;
-        let cred_blob = cred_blob.map(extract_byte_string).transpose()?;
-        let large_blob_key = large_blob_key.map(extract_bool).transpose()?;
+        let hmac_secret = parse_extension_input(hmac_secret, extract_bool)?.unwrap_or(false);
+        let cred_protect =
+            parse_extension_input(cred_protect, CredentialProtectionPolicy::try_from)?;
+        let min_pin_length = parse_extension_input(min_pin_length, extract_bool)?.unwrap_or(false);
+        let cred_blob = parse_extension_input(cred_blob, extract_byte_string)?;
+        let large_blob_key = parse_extension_input(large_blob_key, extract_bool)?;
         if let Some(large_blob_key) = large_blob_key {
             if !large_blob_key {
                 return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
@@ -351,11 +363,10 @@ impl TryFrom<cbor::Value> for GetAssertionExtensions {
             } = extract_map(cbor_value)?;
         }
 
-        let hmac_secret = hmac_secret
-            .map(GetAssertionHmacSecretInput::try_from)
-            .transpose()?;
-        let cred_blob = cred_blob.map_or(Ok(false), extract_bool)?;
-        let large_blob_key = large_blob_key.map(extract_bool).transpose()?;
+        let hmac_secret =
+            parse_extension_input(hmac_secret, GetAssertionHmacSecretInput::try_from)?;
+        let cred_blob = parse_extension_input(cred_blob, extract_bool)?.unwrap_or(false);
+        let large_blob_key = parse_extension_input(large_blob_key, extract_bool)?;
         if let Some(large_blob_key) = large_blob_key {
             if !large_blob_key {
                 return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
@@ -731,243 +742,6 @@ impl PublicKeyCredentialSource {
     }
 }
 
-// The COSE key is used for both ECDH and ECDSA public keys for transmission.
-#[derive(Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "fuzz", derive(Arbitrary))]
-pub struct CoseKey {
-    x_bytes: [u8; ecdh::NBYTES],
-    y_bytes: [u8; ecdh::NBYTES],
-    algorithm: i64,
-    key_type: i64,
-    curve: i64,
-    dilithium_bytes: Option<Vec<u8>>,
-}
-
-impl CoseKey {
-    // This is the algorithm specifier for ECDH.
-    // CTAP requests -25 which represents ECDH-ES + HKDF-256 here:
-    // https://www.iana.org/assignments/cose/cose.xhtml#algorithms
-    const ECDH_ALGORITHM: i64 = -25;
-    // The parameter behind map key 1.
-    const EC2_KEY_TYPE: i64 = 2;
-    #[cfg(feature = "ed25519")]
-    const OKP_KEY_TYPE: i64 = 1;
-    // The key type changes for hybrid. The value is made up.
-    const HYBRID_KEY_TYPE: i64 = -65537;
-    // The parameter behind map key -1.
-    const P_256_CURVE: i64 = 1;
-    #[cfg(feature = "ed25519")]
-    const ED25519_CURVE: i64 = 6;
-}
-
-// This conversion accepts both ECDH and ECDSA.
-impl TryFrom<cbor::Value> for CoseKey {
-    type Error = Ctap2StatusCode;
-
-    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
-        destructure_cbor_map! {
-            let {
-                // This is sorted correctly, negative encoding is bigger.
-                1 => key_type,
-                3 => algorithm,
-                -1 => curve,
-                -2 => x_bytes,
-                -3 => y_bytes,
-                -4 => dilithium_bytes,
-            } = extract_map(cbor_value)?;
-        }
-
-        let algorithm = extract_integer(ok_or_missing(algorithm)?)?;
-        let nbytes = match algorithm {
-            CoseKey::ECDH_ALGORITHM => ecdh::NBYTES,
-            ES256_ALGORITHM => ecdsa::NBYTES,
-            _ => return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM),
-        };
-        let x_bytes = extract_byte_string(ok_or_missing(x_bytes)?)?;
-        if x_bytes.len() != nbytes {
-            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
-        }
-        let y_bytes = extract_byte_string(ok_or_missing(y_bytes)?)?;
-        if y_bytes.len() != nbytes {
-            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
-        }
-        let curve = extract_integer(ok_or_missing(curve)?)?;
-        if curve != CoseKey::P_256_CURVE {
-            return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM);
-        }
-        let key_type = extract_integer(ok_or_missing(key_type)?)?;
-        if key_type != CoseKey::EC2_KEY_TYPE && key_type != CoseKey::HYBRID_KEY_TYPE {
-            return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM);
-        }
-
-        let parsed_dilithium_bytes = if key_type == CoseKey::EC2_KEY_TYPE {
-            if dilithium_bytes.is_some() {
-                return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
-            }
-            None
-        } else {
-            let dilithium_bytes = extract_byte_string(ok_or_missing(dilithium_bytes)?)?;
-            if dilithium_bytes.len() != dilithium::params::PK_SIZE_PACKED {
-                return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
-            }
-            Some(dilithium_bytes)
-        };
-
-        Ok(CoseKey {
-            x_bytes: *array_ref![x_bytes.as_slice(), 0, ecdh::NBYTES],
-            y_bytes: *array_ref![y_bytes.as_slice(), 0, ecdh::NBYTES],
-            algorithm,
-            key_type,
-            curve,
-            dilithium_bytes: parsed_dilithium_bytes,
-        })
-    }
-}
-
-impl From<CoseKey> for cbor::Value {
-    fn from(cose_key: CoseKey) -> Self {
-        let CoseKey {
-            x_bytes,
-            y_bytes,
-            algorithm,
-            key_type,
-            curve,
-            dilithium_bytes,
-        } = cose_key;
-
-        cbor_map_options! {
-            1 => Some(key_type),
-            3 => Some(algorithm),
-            -1 => Some(curve),
-            -2 => Some(cbor_bytes!(x_bytes.to_vec())),
-            -3 => Some(cbor_bytes!(y_bytes.to_vec())),
-            -4 => dilithium_bytes.map(|b| cbor_bytes!(b)),
-        }
-    }
-}
-
-impl From<ecdh::PubKey> for CoseKey {
-    fn from(pk: ecdh::PubKey) -> Self {
-        let mut x_bytes = [0; ecdh::NBYTES];
-        let mut y_bytes = [0; ecdh::NBYTES];
-        pk.to_coordinates(&mut x_bytes, &mut y_bytes);
-        CoseKey {
-            x_bytes,
-            y_bytes,
-            algorithm: CoseKey::ECDH_ALGORITHM,
-            key_type: CoseKey::EC2_KEY_TYPE,
-            curve: CoseKey::P_256_CURVE,
-            dilithium_bytes: None,
-        }
-    }
-}
-
-impl From<ecdsa::PubKey> for CoseKey {
-    fn from(pk: ecdsa::PubKey) -> Self {
-        let mut x_bytes = [0; ecdsa::NBYTES];
-        let mut y_bytes = [0; ecdsa::NBYTES];
-        pk.to_coordinates(&mut x_bytes, &mut y_bytes);
-        CoseKey {
-            x_bytes,
-            y_bytes,
-            algorithm: ES256_ALGORITHM,
-            key_type: CoseKey::EC2_KEY_TYPE,
-            curve: CoseKey::P_256_CURVE,
-            dilithium_bytes: None,
-        }
-    }
-}
-
-#[cfg(feature = "ed25519")]
-impl From<ed25519_compact::PublicKey> for CoseKey {
-    fn from(pk: ed25519_compact::PublicKey) -> Self {
-        CoseKey {
-            x_bytes: *pk,
-            y_bytes: [0u8; 32],
-            key_type: CoseKey::OKP_KEY_TYPE,
-            curve: CoseKey::ED25519_CURVE,
-            algorithm: EDDSA_ALGORITHM,
-            dilithium_bytes: None,
-        }
-    }
-}
-
-impl From<hybrid::PubKey> for CoseKey {
-    fn from(pk: hybrid::PubKey) -> Self {
-        let ecdsa_x_bytes = [0; ecdsa::NBYTES];
-        let ecdsa_y_bytes = [0; ecdsa::NBYTES];
-       
-        let mut dilithium_bytes = vec![0; hybrid::PubKey::BYTES_LENGTH];
-        dilithium_bytes.extend(pk.spx_pubkey);
-        CoseKey {
-            x_bytes: ecdsa_x_bytes,
-            y_bytes: ecdsa_y_bytes,
-            key_type: CoseKey::EC2_KEY_TYPE,
-            curve: CoseKey::P_256_CURVE,
-            algorithm: ES256_ALGORITHM,
-            dilithium_bytes: Some(dilithium_bytes),
-        }
-    }
-}
-
-impl TryFrom<CoseKey> for ecdh::PubKey {
-    type Error = Ctap2StatusCode;
-
-    fn try_from(cose_key: CoseKey) -> Result<Self, Ctap2StatusCode> {
-        let CoseKey {
-            x_bytes,
-            y_bytes,
-            algorithm,
-            key_type,
-            curve,
-            dilithium_bytes,
-        } = cose_key;
-
-        // Since algorithm can be used for different COSE key types, we check
-        // whether the current type is correct for ECDH. For an OpenSSH bugfix,
-        // the algorithm ES256_ALGORITHM is allowed here too.
-        // https://github.com/google/OpenSK/issues/90
-        if algorithm != CoseKey::ECDH_ALGORITHM && algorithm != ES256_ALGORITHM {
-            return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM);
-        }
-        if key_type != CoseKey::EC2_KEY_TYPE || curve != CoseKey::P_256_CURVE {
-            return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM);
-        }
-        if dilithium_bytes.is_some() {
-            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
-        }
-        ecdh::PubKey::from_coordinates(&x_bytes, &y_bytes)
-            .ok_or(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
-    }
-}
-
-impl TryFrom<CoseKey> for ecdsa::PubKey {
-    type Error = Ctap2StatusCode;
-
-    fn try_from(cose_key: CoseKey) -> Result<Self, Ctap2StatusCode> {
-        let CoseKey {
-            x_bytes,
-            y_bytes,
-            algorithm,
-            key_type,
-            curve,
-            dilithium_bytes,
-        } = cose_key;
-
-        if algorithm != ES256_ALGORITHM
-            || key_type != CoseKey::EC2_KEY_TYPE
-            || curve != CoseKey::P_256_CURVE
-        {
-            return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM);
-        }
-        if dilithium_bytes.is_some() {
-            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
-        }
-        ecdsa::PubKey::from_coordinates(&x_bytes, &y_bytes)
-            .ok_or(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
-    }
-}
-
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "fuzz", derive(Arbitrary))]
 pub enum PinUvAuthProtocol {
@@ -1787,6 +1561,64 @@ mod test {
         assert_eq!(extensions, Ok(expected_extensions));
     }
 
+    #[test]
+    fn test_from_make_credential_extensions_unknown_ignored() {
+        let cbor_extensions = cbor_map! {
+            "credProtect" => CredentialProtectionPolicy::UserVerificationRequired,
+            "unknownExtension" => cbor_map! { 1 => "anything" },
+        };
+        let extensions = MakeCredentialExtensions::try_from(cbor_extensions);
+        let expected_extensions = MakeCredentialExtensions {
+            cred_protect: Some(CredentialProtectionPolicy::UserVerificationRequired),
+            ..Default::default()
+        };
+        assert_eq!(extensions, Ok(expected_extensions));
+    }
+
+    #[test]
+    fn test_from_make_credential_extensions_malformed() {
+        let malformed_extensions = vec![
+            cbor_map! { "credProtect" => 0x04 },
+            cbor_map! { "credProtect" => "userVerificationRequired" },
+            cbor_map! { "hmac-secret" => 1 },
+            cbor_map! { "minPinLength" => vec![0x01] },
+            cbor_map! { "credBlob" => "blob" },
+            cbor_map! { "largeBlobKey" => 1 },
+        ];
+        for cbor_extensions in malformed_extensions {
+            assert_eq!(
+                MakeCredentialExtensions::try_from(cbor_extensions),
+                Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+            );
+        }
+        // A well-formed but unsupported value is an invalid option.
+        assert_eq!(
+            MakeCredentialExtensions::try_from(cbor_map! { "largeBlobKey" => false }),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION)
+        );
+    }
+
+    #[test]
+    fn test_from_get_assertion_extensions_malformed() {
+        let malformed_extensions = vec![
+            cbor_map! { "credBlob" => 1 },
+            cbor_map! { "hmac-secret" => true },
+            cbor_map! { "hmac-secret" => cbor_map! { 2 => vec![0x02; 32] } },
+            cbor_map! { "largeBlobKey" => "true" },
+        ];
+        for cbor_extensions in malformed_extensions {
+            assert_eq!(
+                GetAssertionExtensions::try_from(cbor_extensions),
+                Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+            );
+        }
+        let cbor_extensions = cbor_map! { "unknownExtension" => true };
+        assert_eq!(
+            GetAssertionExtensions::try_from(cbor_extensions),
+            Ok(GetAssertionExtensions::default())
+        );
+    }
+
     #[test]
     fn test_from_get_assertion_extensions_default_protocol() {
         let mut env = TestEnv::new();
@@ -1896,121 +1728,6 @@ mod test {
         assert_eq!(created_cbor, cbor_packed_attestation_statement);
     }
 
-    #[test]
-    fn test_from_into_cose_key_cbor() {
-        for algorithm in &[CoseKey::ECDH_ALGORITHM, ES256_ALGORITHM] {
-            let cbor_value = cbor_map! {
-                1 => CoseKey::EC2_KEY_TYPE,
-                3 => algorithm,
-                -1 => CoseKey::P_256_CURVE,
-                -2 => [0u8; 32],
-                -3 => [0u8; 32],
-            };
-            let cose_key = CoseKey::try_from(cbor_value.clone()).unwrap();
-            let created_cbor_value = cbor::Value::from(cose_key);
-            assert_eq!(created_cbor_value, cbor_value);
-        }
-    }
-
-    #[test]
-    fn test_cose_key_unknown_algorithm() {
-        let cbor_value = cbor_map! {
-            1 => CoseKey::EC2_KEY_TYPE,
-            // unknown algorithm
-            3 => 0,
-            -1 => CoseKey::P_256_CURVE,
-            -2 => [0u8; 32],
-            -3 => [0u8; 32],
-        };
-        assert_eq!(
-            CoseKey::try_from(cbor_value),
-            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM)
-        );
-    }
-
-    #[test]
-    fn test_cose_key_unknown_type() {
-        let cbor_value = cbor_map! {
-            // unknown type
-            1 => 0,
-            3 => CoseKey::ECDH_ALGORITHM,
-            -1 => CoseKey::P_256_CURVE,
-            -2 => [0u8; 32],
-            -3 => [0u8; 32],
-        };
-        assert_eq!(
-            CoseKey::try_from(cbor_value),
-            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM)
-        );
-    }
-
-    #[test]
-    fn test_cose_key_unknown_curve() {
-        let cbor_value = cbor_map! {
-            1 => CoseKey::EC2_KEY_TYPE,
-            3 => CoseKey::ECDH_ALGORITHM,
-            // unknown curve
-            -1 => 0,
-            -2 => [0u8; 32],
-            -3 => [0u8; 32],
-        };
-        assert_eq!(
-            CoseKey::try_from(cbor_value),
-            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM)
-        );
-    }
-
-    #[test]
-    fn test_cose_key_wrong_length_x() {
-        let cbor_value = cbor_map! {
-            1 => CoseKey::EC2_KEY_TYPE,
-            3 => CoseKey::ECDH_ALGORITHM,
-            -1 => CoseKey::P_256_CURVE,
-            // wrong length
-            -2 => [0u8; 31],
-            -3 => [0u8; 32],
-        };
-        assert_eq!(
-            CoseKey::try_from(cbor_value),
-            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
-        );
-    }
-
-    #[test]
-    fn test_cose_key_wrong_length_y() {
-        let cbor_value = cbor_map! {
-            1 => CoseKey::EC2_KEY_TYPE,
-            3 => CoseKey::ECDH_ALGORITHM,
-            -1 => CoseKey::P_256_CURVE,
-            -2 => [0u8; 32],
-            // wrong length
-            -3 => [0u8; 33],
-        };
-        assert_eq!(
-            CoseKey::try_from(cbor_value),
-            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
-        );
-    }
-
-    #[test]
-    fn test_from_into_cose_key_ecdh() {
-        let mut env = TestEnv::new();
-        let sk = crypto::ecdh::SecKey::gensk(env.rng());
-        let pk = sk.genpk();
-        let cose_key = CoseKey::from(pk.clone());
-        let created_pk = ecdh::PubKey::try_from(cose_key);
-        assert_eq!(created_pk, Ok(pk));
-    }
-
-    #[test]
-    fn test_into_cose_key_ecdsa() {
-        let mut env = TestEnv::new();
-        let sk = crypto::ecdsa::SecKey::gensk(env.rng());
-        let pk = sk.genpk();
-        let cose_key = CoseKey::from(pk);
-        assert_eq!(cose_key.algorithm, ES256_ALGORITHM);
-    }
-
     #[test]
     fn test_from_pin_uv_auth_protocol() {
         let cbor_protocol: cbor::Value = cbor_int!(0x01);
@@ -27,9 +27,8 @@ use crate::env::Env;
 use alloc::vec;
 use alloc::vec::Vec;
 use arrayref::{array_ref, array_refs};
+use core::ops::RangeInclusive;
 use embedded_time::duration::Milliseconds;
-#[cfg(test)]
-use enum_iterator::IntoEnumIterator;
 
 pub type HidPacket = [u8; 64];
 pub type ChannelID = [u8; 4];
@@ -38,32 +37,38 @@ pub type ChannelID = [u8; 4];
 ///
 /// See section 11.2.9. of FIDO 2.1 (2021-06-15).
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-#[cfg_attr(test, derive(IntoEnumIterator))]
 pub enum CtapHidCommand {
-    Ping = 0x01,
-    Msg = 0x03,
+    Ping,
+    Msg,
     // Lock is optional and may be used in the future.
-    Lock = 0x04,
-    Init = 0x06,
-    Wink = 0x08,
-    Cbor = 0x10,
-    Cancel = 0x11,
-    Keepalive = 0x3B,
-    Error = 0x3F,
-    // The vendor range starts here, going from 0x40 to 0x7F.
+    Lock,
+    Init,
+    Wink,
+    Cbor,
+    Cancel,
+    Keepalive,
+    Error,
+    /// Vendor specific command, in [`CtapHidCommand::VENDOR_RANGE`].
+    Vendor(u8),
+}
+
+impl CtapHidCommand {
+    /// Range of the command bytes reserved for vendors.
+    pub const VENDOR_RANGE: RangeInclusive<u8> = 0x40..=0x7F;
 }
 
 impl From<u8> for CtapHidCommand {
     fn from(cmd: u8) -> Self {
         match cmd {
-            x if x == CtapHidCommand::Ping as u8 => CtapHidCommand::Ping,
-            x if x == CtapHidCommand::Msg as u8 => CtapHidCommand::Msg,
-            x if x == CtapHidCommand::Lock as u8 => CtapHidCommand::Lock,
-            x if x == CtapHidCommand::Init as u8 => CtapHidCommand::Init,
-            x if x == CtapHidCommand::Wink as u8 => CtapHidCommand::Wink,
-            x if x == CtapHidCommand::Cbor as u8 => CtapHidCommand::Cbor,
-            x if x == CtapHidCommand::Cancel as u8 => CtapHidCommand::Cancel,
-            x if x == CtapHidCommand::Keepalive as u8 => CtapHidCommand::Keepalive,
+            0x01 => CtapHidCommand::Ping,
+            0x03 => CtapHidCommand::Msg,
+            0x04 => CtapHidCommand::Lock,
+            0x06 => CtapHidCommand::Init,
+            0x08 => CtapHidCommand::Wink,
+            0x10 => CtapHidCommand::Cbor,
+            0x11 => CtapHidCommand::Cancel,
+            0x3B => CtapHidCommand::Keepalive,
+            x if CtapHidCommand::VENDOR_RANGE.contains(&x) => CtapHidCommand::Vendor(x),
             // This includes the actual error code 0x3F. Error is not used for incoming packets in
             // the specification, so we can safely reuse it for unknown bytes.
             _ => CtapHidCommand::Error,
@@ -71,6 +76,23 @@ impl From<u8> for CtapHidCommand {
     }
 }
 
+impl From<CtapHidCommand> for u8 {
+    fn from(cmd: CtapHidCommand) -> Self {
+        match cmd {
+            CtapHidCommand::Ping => 0x01,
+            CtapHidCommand::Msg => 0x03,
+            CtapHidCommand::Lock => 0x04,
+            CtapHidCommand::Init => 0x06,
+            CtapHidCommand::Wink => 0x08,
+            CtapHidCommand::Cbor => 0x10,
+            CtapHidCommand::Cancel => 0x11,
+            CtapHidCommand::Keepalive => 0x3B,
+            CtapHidCommand::Error => 0x3F,
+            CtapHidCommand::Vendor(x) => x,
+        }
+    }
+}
+
 /// CTAPHID errors
 ///
 /// See section 11.2.9.1.6. of FIDO 2.1 (2021-06-15).
@@ -79,7 +101,7 @@ pub enum CtapHidError {
     /// The command in the request is invalid.
     InvalidCmd = 0x01,
     /// A parameter in the request is invalid.
-    _InvalidPar = 0x02,
+    InvalidPar = 0x02,
     /// The length of a message is too big.
     InvalidLen = 0x03,
     /// Expected a continuation packet with a specific sequence number, got another sequence number.
@@ -96,7 +118,7 @@ pub enum CtapHidError {
     /// The requested channel ID is invalid.
     InvalidChannel = 0x0B,
     /// Unspecified error.
-    _Other = 0x7F,
+    Other = 0x7F,
     /// This error is silently ignored.
     UnexpectedContinuation,
 }
@@ -128,6 +150,16 @@ pub struct Message {
     pub payload: Vec<u8>,
 }
 
+impl Message {
+    /// Encodes the message as its channel ID, command byte and payload.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.cid.to_vec();
+        bytes.push(u8::from(self.cmd));
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
 /// A keepalive packet reports the reason why a command does not finish.
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -315,6 +347,8 @@ impl CtapHid {
                 None
             }
             CtapHidCommand::Wink => Some(message),
+            // Vendor commands are dispatched by the transport.
+            CtapHidCommand::Vendor(_) => Some(message),
             _ => {
                 // Unknown or unsupported command.
                 Some(CtapHid::error_message(cid, CtapHidError::InvalidCmd))
@@ -481,6 +515,37 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_command_init_allocates_fresh_channels() {
+        let mut ctap_hid = CtapHid::new(0x0D);
+        for (nonce, expected_cid) in [[0x11; 8], [0x22; 8]].iter().zip(1u32..) {
+            let init_message = Message {
+                cid: CtapHid::CHANNEL_BROADCAST,
+                cmd: CtapHidCommand::Init,
+                payload: nonce.to_vec(),
+            };
+            let reply = ctap_hid.preprocess_message(init_message).unwrap();
+            assert_eq!(reply.cid, CtapHid::CHANNEL_BROADCAST);
+            assert_eq!(&reply.payload[..8], nonce);
+            assert_eq!(reply.payload[8..12], expected_cid.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn test_unallocated_channel() {
+        let mut env = TestEnv::new();
+        let (mut ctap_hid, _) = CtapHid::new_initialized();
+        let cid = [0x00, 0x00, 0x00, 0x05];
+
+        let mut packet = [0x00; 64];
+        packet[..4].copy_from_slice(&cid);
+        packet[4..9].copy_from_slice(&[0x81, 0x00, 0x02, 0x99, 0x99]);
+        assert_eq!(
+            ctap_hid.parse_packet(&mut env, &packet, CtapInstant::new(0)),
+            Some(CtapHid::error_message(cid, CtapHidError::InvalidChannel))
+        );
+    }
+
     #[test]
     fn test_command_init_for_sync() {
         let mut env = TestEnv::new();
@@ -607,7 +672,7 @@ mod test {
         let (processed_cid, processed_packet) = CtapHid::process_single_packet(&packet);
         assert_eq!(processed_cid, &cid);
         let expected_packet = ProcessedPacket::InitPacket {
-            cmd: CtapHidCommand::Ping as u8,
+            cmd: u8::from(CtapHidCommand::Ping),
             len: 2,
             data: array_ref!(packet, 7, 57),
         };
@@ -618,9 +683,15 @@ mod test {
     fn test_from_ctap_hid_command() {
         // 0x3E is unassigned.
         assert_eq!(CtapHidCommand::from(0x3E), CtapHidCommand::Error);
-        for command in CtapHidCommand::into_enum_iter() {
-            assert_eq!(CtapHidCommand::from(command as u8), command);
+        assert_eq!(CtapHidCommand::from(0x80), CtapHidCommand::Error);
+        for cmd in 0..=0x7F {
+            let command = CtapHidCommand::from(cmd);
+            if command != CtapHidCommand::Error {
+                assert_eq!(u8::from(command), cmd);
+            }
         }
+        assert_eq!(CtapHidCommand::from(0x40), CtapHidCommand::Vendor(0x40));
+        assert_eq!(CtapHidCommand::from(0x7F), CtapHidCommand::Vendor(0x7F));
     }
 
     #[test]
@@ -119,7 +119,7 @@ impl MessageAssembler {
                 // Unexpected initialization packet.
                 ProcessedPacket::InitPacket { cmd, len, data } => {
                     self.reset();
-                    if cmd == CtapHidCommand::Init as u8 {
+                    if cmd == u8::from(CtapHidCommand::Init) {
                         self.parse_init_packet(env, *cid, cmd, len, data, timestamp)
                     } else {
                         Err((*cid, CtapHidError::InvalidSeq))
@@ -377,7 +377,7 @@ mod test {
                 assembler.parse_packet(
                     &mut env,
                     &byte_extend(
-                        &[0x12, 0x34, 0x56, 0x78, 0x80 | cmd as u8, 0x00, 0x80],
+                        &[0x12, 0x34, 0x56, 0x78, 0x80 | u8::from(cmd), 0x00, 0x80],
                         byte
                     ),
                     CtapInstant::new(0)
@@ -421,7 +421,10 @@ mod test {
             assert_eq!(
                 assembler.parse_packet(
                     &mut env,
-                    &byte_extend(&[0x12, 0x34, 0x56, cid, 0x80 | cmd as u8, 0x00, 0x80], byte),
+                    &byte_extend(
+                        &[0x12, 0x34, 0x56, cid, 0x80 | u8::from(cmd), 0x00, 0x80],
+                        byte
+                    ),
                     CtapInstant::new(0)
                 ),
                 Ok(None)
@@ -469,7 +472,7 @@ mod test {
                 assert_eq!(
                     assembler.parse_packet(
                         &mut env,
-                        &byte_extend(&[0x12, 0x34, 0x56, 0x9A, cmd as u8, 0x00], byte),
+                        &byte_extend(&[0x12, 0x34, 0x56, 0x9A, u8::from(cmd), 0x00], byte),
                         CtapInstant::new(0)
                     ),
                     Err(([0x12, 0x34, 0x56, 0x9A], CtapHidError::ChannelBusy))
@@ -552,6 +555,33 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_too_long_payload() {
+        let mut env = TestEnv::new();
+        let mut assembler = MessageAssembler::new();
+        // 0x1DBA is one byte more than the maximum message size.
+        assert_eq!(
+            assembler.parse_packet(
+                &mut env,
+                &zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x81, 0x1D, 0xBA]),
+                CtapInstant::new(0)
+            ),
+            Err(([0x12, 0x34, 0x56, 0x78], CtapHidError::InvalidLen))
+        );
+        // The assembler stays idle, so continuation packets are ignored.
+        assert_eq!(
+            assembler.parse_packet(
+                &mut env,
+                &zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x00]),
+                CtapInstant::new(0)
+            ),
+            Err((
+                [0x12, 0x34, 0x56, 0x78],
+                CtapHidError::UnexpectedContinuation
+            ))
+        );
+    }
+
     #[test]
     fn test_unexpected_seq() {
         let mut env = TestEnv::new();
@@ -65,7 +65,8 @@ impl MessageSplitter {
     ///
     /// This fails if the message is too long to fit into a sequence of HID packets.
     pub fn new(message: Message) -> Option<MessageSplitter> {
-        if message.payload.len() > 11857 {
+        // An init packet and 128 continuation packets, with sequence numbers 0 to 0x7F.
+        if message.payload.len() > (64 - 7) + 128 * (64 - 5) {
             None
         } else {
             // Cache the CID, as it is constant for all packets in this message.
@@ -121,7 +122,7 @@ impl Iterator for MessageSplitter {
         match self.seq {
             None => {
                 // First, send an initialization packet.
-                self.packet[4] = self.message.cmd as u8 | CtapHid::TYPE_INIT_BIT;
+                self.packet[4] = u8::from(self.message.cmd) | CtapHid::TYPE_INIT_BIT;
                 self.packet[5] = (payload_len >> 8) as u8;
                 self.packet[6] = payload_len as u8;
 
@@ -14,6 +14,10 @@
 
 pub const ATTESTATION_PRIVATE_KEY_LENGTH: usize = 32;
 pub const AAGUID_LENGTH: usize = 16;
+pub const UPGRADE_PUBLIC_KEY_LENGTH: usize = 65;
 
 pub const AAGUID: &[u8; AAGUID_LENGTH] =
     include_bytes!(concat!(env!("OUT_DIR"), "/opensk_aaguid.bin"));
+
+pub const UPGRADE_PUBLIC_KEY: &[u8; UPGRADE_PUBLIC_KEY_LENGTH] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/opensk_upgrade_pubkey.bin"));
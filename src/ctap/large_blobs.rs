@@ -327,6 +327,78 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_process_command_commit_incomplete_length() {
+        let mut env = TestEnv::new();
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(env.rng());
+        let pin_uv_auth_token = [0x55; 32];
+        let mut client_pin =
+            ClientPin::new_test(key_agreement_key, pin_uv_auth_token, PinUvAuthProtocol::V1);
+        let mut large_blobs = LargeBlobs::new();
+
+        const BLOB_LEN: usize = 200;
+        const DATA_LEN: usize = BLOB_LEN - TRUNCATED_HASH_LEN;
+        let mut large_blob = vec![0x1B; DATA_LEN];
+        large_blob.extend_from_slice(&Sha256::hash(&large_blob[..])[..TRUNCATED_HASH_LEN]);
+
+        let large_blobs_params = AuthenticatorLargeBlobsParameters {
+            get: None,
+            set: Some(large_blob[..BLOB_LEN / 2].to_vec()),
+            offset: 0,
+            // The length is 1 too big.
+            length: Some(BLOB_LEN + 1),
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let large_blobs_response =
+            large_blobs.process_command(&mut env, &mut client_pin, large_blobs_params);
+        assert_eq!(
+            large_blobs_response,
+            Ok(ResponseData::AuthenticatorLargeBlobs(None))
+        );
+
+        let large_blobs_params = AuthenticatorLargeBlobsParameters {
+            get: None,
+            set: Some(large_blob[BLOB_LEN / 2..].to_vec()),
+            offset: BLOB_LEN / 2,
+            length: None,
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let large_blobs_response =
+            large_blobs.process_command(&mut env, &mut client_pin, large_blobs_params);
+        assert_eq!(
+            large_blobs_response,
+            Ok(ResponseData::AuthenticatorLargeBlobs(None))
+        );
+
+        // Nothing is committed before the declared length is reached.
+        let empty_large_blob = vec![
+            0x80, 0x76, 0xBE, 0x8B, 0x52, 0x8D, 0x00, 0x75, 0xF7, 0xAA, 0xE9, 0x8D, 0x6F, 0xA5,
+            0x7A, 0x6D, 0x3C,
+        ];
+        assert_eq!(
+            storage::get_large_blob_array(&mut env, 0, BLOB_LEN + 1),
+            Ok(empty_large_blob)
+        );
+
+        // Exceeding the declared length is rejected.
+        let large_blobs_params = AuthenticatorLargeBlobsParameters {
+            get: None,
+            set: Some(vec![0x1B; 2]),
+            offset: BLOB_LEN,
+            length: None,
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let large_blobs_response =
+            large_blobs.process_command(&mut env, &mut client_pin, large_blobs_params);
+        assert_eq!(
+            large_blobs_response,
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER),
+        );
+    }
+
     #[test]
     fn test_process_command_commit_end_offset_overflow() {
         let mut env = TestEnv::new();
@@ -115,6 +115,8 @@ impl MainHid {
                     CtapHid::error_message(cid, CtapHidError::InvalidLen)
                 }
             }
+            // Vendor commands are only accepted on the vendor HID interface.
+            CtapHidCommand::Vendor(_) => CtapHid::error_message(cid, CtapHidError::InvalidCmd),
             // All other commands have already been processed, keep them as is.
             _ => message,
         }
@@ -232,4 +234,28 @@ mod test {
         assert!(main_hid.should_wink(CtapInstant::new(0)));
         assert!(!main_hid.should_wink(CtapInstant::new(1) + MainHid::WINK_TIMEOUT_DURATION));
     }
+
+    #[test]
+    fn test_wink_invalid_length() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let (mut main_hid, cid) = new_initialized();
+
+        let mut wink_packet = [0x00; 64];
+        wink_packet[..4].copy_from_slice(&cid);
+        wink_packet[4..8].copy_from_slice(&[0x88, 0x00, 0x01, 0x00]);
+
+        let mut response = main_hid.process_hid_packet(
+            &mut env,
+            &wink_packet,
+            CtapInstant::new(0),
+            &mut ctap_state,
+        );
+        let mut error_packet = [0x00; 64];
+        error_packet[..4].copy_from_slice(&cid);
+        error_packet[4..8].copy_from_slice(&[0xBF, 0x00, 0x01, 0x03]);
+        assert_eq!(response.next(), Some(error_packet));
+        assert_eq!(response.next(), None);
+        assert!(!main_hid.should_wink(CtapInstant::new(0)));
+    }
 }
@@ -16,7 +16,9 @@ pub mod apdu;
 mod client_pin;
 pub mod command;
 mod config_command;
+pub mod cose;
 mod credential_id;
+#[cfg(feature = "cred_management")]
 mod credential_management;
 mod crypto_wrapper;
 #[cfg(feature = "with_ctap1")]
@@ -24,8 +26,11 @@ mod ctap1;
 pub mod data_formats;
 pub mod hid;
 pub mod key_material;
+#[cfg(feature = "large_blobs")]
 mod large_blobs;
 pub mod main_hid;
+#[cfg(feature = "with_nfc")]
+pub mod nfc;
 mod pin_protocol;
 pub mod response;
 pub mod status_code;
@@ -37,27 +42,33 @@ pub mod vendor_hid;
 
 use self::client_pin::{ClientPin, PinPermission};
 use self::command::{
-    AuthenticatorGetAssertionParameters, AuthenticatorMakeCredentialParameters,
-    AuthenticatorVendorConfigureParameters, AuthenticatorVendorUpgradeParameters, Command,
+    AuthenticatorBioEnrollmentParameters, AuthenticatorGetAssertionParameters,
+    AuthenticatorMakeCredentialParameters, AuthenticatorVendorConfigureParameters,
+    AuthenticatorVendorUpgradeParameters, Command,
 };
 use self::config_command::process_config;
 use self::credential_id::{
     decrypt_credential_id, encrypt_to_credential_id, MAX_CREDENTIAL_ID_SIZE,
 };
+#[cfg(feature = "cred_management")]
 use self::credential_management::process_credential_management;
 use self::crypto_wrapper::PrivateKey;
+#[cfg(feature = "enterprise_attestation")]
+use self::data_formats::EnterpriseAttestationMode;
 use self::data_formats::{
-    AuthenticatorTransport, CredentialProtectionPolicy, EnterpriseAttestationMode,
+    AuthenticatorTransport, ClientPinSubCommand, CredentialProtectionPolicy,
     GetAssertionExtensions, PackedAttestationStatement, PinUvAuthProtocol,
     PublicKeyCredentialDescriptor, PublicKeyCredentialParameter, PublicKeyCredentialSource,
     PublicKeyCredentialType, PublicKeyCredentialUserEntity, SignatureAlgorithm,
 };
 use self::hid::{ChannelID, CtapHid, CtapHidCommand, KeepaliveStatus, ProcessedPacket};
+#[cfg(feature = "large_blobs")]
 use self::large_blobs::LargeBlobs;
 use self::response::{
     AuthenticatorGetAssertionResponse, AuthenticatorGetInfoResponse,
     AuthenticatorMakeCredentialResponse, AuthenticatorVendorConfigureResponse,
-    AuthenticatorVendorUpgradeInfoResponse, ResponseData,
+    AuthenticatorVendorCredentialDumpResponse, AuthenticatorVendorUpgradeInfoResponse,
+    CredentialMetadata, ResponseData,
 };
 use self::status_code::Ctap2StatusCode;
 use self::timed_permission::TimedPermission;
@@ -67,8 +78,12 @@ use crate::api::attestation_store::{self, Attestation, AttestationStore};
 use crate::api::connection::{HidConnection, SendOrRecvStatus};
 use crate::api::customization::Customization;
 use crate::api::firmware_protection::FirmwareProtection;
+use crate::api::power::Power;
+use crate::api::signature_counter::SignatureCounter;
 use crate::api::upgrade_storage::UpgradeStorage;
 use crate::api::user_presence::{UserPresence, UserPresenceError};
+use crate::api::user_verification::UserVerification;
+use crate::api::watchdog::Watchdog;
 use crate::clock::{ClockInt, CtapInstant, KEEPALIVE_DELAY, KEEPALIVE_DELAY_MS};
 use crate::env::Env;
 use alloc::boxed::Box;
@@ -76,6 +91,7 @@ use alloc::string::{String, ToString};
 use alloc::vec;
 use alloc::vec::Vec;
 use byteorder::{BigEndian, ByteOrder};
+#[cfg(feature = "enterprise_attestation")]
 use core::convert::TryFrom;
 use crypto::hmac::hmac_256;
 use crypto::sha256::Sha256;
@@ -137,6 +153,12 @@ const SUPPORTED_CRED_PARAMS: &[PublicKeyCredentialParameter] = &[
     HYBRID_CRED_PARAM,
 ];
 
+/// CTAP transports advertised in getInfo.
+///
+/// Only USB HID carries CTAP commands. The `with_nfc` feature processes APDUs, but Tock boards
+/// don't bind the NFC driver to them yet, so NFC is not advertised. There is no BLE driver.
+const SUPPORTED_TRANSPORTS: &[AuthenticatorTransport] = &[AuthenticatorTransport::Usb];
+
 fn get_preferred_cred_param(
     params: &[PublicKeyCredentialParameter],
 ) -> Option<&PublicKeyCredentialParameter> {
@@ -185,12 +207,34 @@ pub enum Channel {
     /// No equivalent in CTAP, used for communication outside the specification.
     #[cfg(feature = "vendor_hid")]
     VendorHid(ChannelID),
+    /// Corresponds to CTAP's NFC transport.
+    #[cfg(feature = "with_nfc")]
+    Nfc,
 }
 
 // Helpers to perform CBOR read/write while respecting CTAP2 nesting limits.
+//
+// The reader only accepts the CTAP2 canonical CBOR encoding form: integers and lengths are
+// minimally encoded, map keys are sorted without duplicates, and there are no indefinite length
+// items or floats. Tags are rejected below, since the reader supports them.
 pub fn cbor_read(encoded_cbor: &[u8]) -> Result<cbor::Value, Ctap2StatusCode> {
-    cbor::reader::read_nested(encoded_cbor, Some(MAX_CBOR_NESTING_DEPTH))
-        .map_err(|_e| Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)
+    let value = cbor::reader::read_nested(encoded_cbor, Some(MAX_CBOR_NESTING_DEPTH))
+        .map_err(|_e| Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)?;
+    if contains_tag(&value) {
+        return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR);
+    }
+    Ok(value)
+}
+
+fn contains_tag(value: &cbor::Value) -> bool {
+    match value {
+        cbor::Value::Tag(_, _) => true,
+        cbor::Value::Array(array) => array.iter().any(contains_tag),
+        cbor::Value::Map(map) => map
+            .iter()
+            .any(|(key, value)| contains_tag(key) || contains_tag(value)),
+        _ => false,
+    }
 }
 
 fn cbor_write(value: cbor::Value, encoded_cbor: &mut Vec<u8>) -> Result<(), Ctap2StatusCode> {
@@ -213,16 +257,19 @@ fn truncate_to_char_boundary(s: &str, mut max: usize) -> &str {
 }
 
 // Sends keepalive packet during user presence checking. If user agent replies with CANCEL response,
-// returns Err(UserPresenceError::Canceled).
+// returns Err(CTAP2_ERR_KEEPALIVE_CANCEL). Transport failures are returned as their status.
 fn send_keepalive_up_needed(
     env: &mut impl Env,
     channel: Channel,
     timeout: Milliseconds<ClockInt>,
-) -> Result<(), UserPresenceError> {
+) -> Result<(), Ctap2StatusCode> {
     let (cid, transport) = match channel {
         Channel::MainHid(cid) => (cid, Transport::MainHid),
         #[cfg(feature = "vendor_hid")]
         Channel::VendorHid(cid) => (cid, Transport::VendorHid),
+        // NFC has no keepalive, the reader waits for the response.
+        #[cfg(feature = "with_nfc")]
+        Channel::Nfc => return Ok(()),
     };
     let keepalive_msg = CtapHid::keepalive(cid, KeepaliveStatus::UpNeeded);
     for mut pkt in keepalive_msg {
@@ -232,7 +279,7 @@ fn send_keepalive_up_needed(
                 debug_ctap!(env, "Sending a KEEPALIVE packet timed out");
                 // TODO: abort user presence test?
             }
-            Err(_) => panic!("Error sending KEEPALIVE packet"),
+            Err(error) => return Err(error.into()),
             Ok(SendOrRecvStatus::Sent) => {
                 debug_ctap!(env, "Sent KEEPALIVE packet");
             }
@@ -267,10 +314,10 @@ fn send_keepalive_up_needed(
                     ProcessedPacket::InitPacket { cmd, .. } => {
                         // Clippy doesn't understand the macro.
                         #[allow(clippy::branches_sharing_code)]
-                        if cmd == CtapHidCommand::Cancel as u8 {
+                        if cmd == u8::from(CtapHidCommand::Cancel) {
                             // We ignore the payload, we can't answer with an error code anyway.
                             debug_ctap!(env, "User presence check cancelled");
-                            return Err(UserPresenceError::Canceled);
+                            return Err(UserPresenceError::Canceled.into());
                         } else {
                             debug_ctap!(
                                 env,
@@ -305,7 +352,10 @@ fn check_user_presence(env: &mut impl Env, channel: Channel) -> Result<(), Ctap2
     // check_complete(...) cleanup function.
 
     let mut result = Err(UserPresenceError::Timeout);
+    let mut keepalive_error = None;
     for i in 0..=TIMEOUT_ITERATIONS {
+        // The check may take much longer than the watchdog timeout.
+        env.watchdog().kick();
         // First presence check is made without timeout. That way Env implementation may return
         // user presence check result immediately to client, without sending any keepalive packets.
         result = env.user_presence().wait_with_timeout(if i == 0 {
@@ -321,26 +371,50 @@ fn check_user_presence(env: &mut impl Env, channel: Channel) -> Result<(), Ctap2
         // equal time intervals. That way token indicators, such as LEDs, will blink
         // with a consistent pattern.
         let keepalive_result = send_keepalive_up_needed(env, channel, KEEPALIVE_DELAY);
-        if keepalive_result.is_err() {
-            debug_ctap!(
-                env,
-                "Sending keepalive failed with error {:?}",
-                keepalive_result.as_ref().unwrap_err()
-            );
-            result = keepalive_result;
+        if let Err(error) = keepalive_result {
+            debug_ctap!(env, "Sending keepalive failed with error {:?}", error);
+            result = Err(UserPresenceError::Canceled);
+            keepalive_error = Some(error);
             break;
         }
     }
 
-    env.user_presence().check_complete();
-    result.map_err(|e| e.into())
+    env.user_presence().check_complete(&result);
+    match keepalive_error {
+        Some(error) => Err(error),
+        None => result.map_err(|e| e.into()),
+    }
+}
+
+/// Processes the authenticatorBioEnrollment command.
+///
+/// There is no biometric hardware, so there is no modality to report and nothing to enroll.
+fn process_bio_enrollment(
+    params: AuthenticatorBioEnrollmentParameters,
+) -> Result<ResponseData, Ctap2StatusCode> {
+    if params.get_modality {
+        return Ok(ResponseData::AuthenticatorBioEnrollment);
+    }
+    if params.sub_command.is_none() {
+        return Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER);
+    }
+    Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND)
+}
+
+/// Verifies the user with the built-in method, as requested by the uv option.
+fn check_built_in_user_verification(env: &mut impl Env) -> Result<(), Ctap2StatusCode> {
+    if !env.user_verification().is_configured() {
+        return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
+    }
+    Ok(env.user_verification().verify()?)
 }
 
 /// Holds data necessary to sign an assertion for a credential.
 #[derive(Clone)]
 pub struct AssertionInput {
     client_data_hash: Vec<u8>,
-    auth_data: Vec<u8>,
+    rp_id_hash: [u8; 32],
+    flags: u8,
     extensions: GetAssertionExtensions,
     has_uv: bool,
 }
@@ -452,15 +526,18 @@ impl StatefulPermission {
     /// Returns the state for the next assertion and advances it.
     ///
     /// The state includes all information from GetAssertion and the storage key
-    /// to the next credential that needs to be processed.
+    /// to the next credential that needs to be processed. The timeout restarts,
+    /// since it applies to the time between two calls.
     pub fn next_assertion_credential(
         &mut self,
+        now: CtapInstant,
     ) -> Result<(AssertionInput, usize), Ctap2StatusCode> {
         if let Some(StatefulCommand::GetAssertion(assertion_state)) = &mut self.command_type {
             let credential_key = assertion_state
                 .next_credential_keys
                 .pop()
                 .ok_or(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)?;
+            self.permission = TimedPermission::granted(now, STATEFUL_COMMAND_TIMEOUT_DURATION);
             Ok((assertion_state.assertion_input.clone(), credential_key))
         } else {
             Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
@@ -499,7 +576,36 @@ pub struct CtapState {
     pub(crate) u2f_up_state: U2fUserPresenceState,
     // The state initializes to Reset and its timeout, and never goes back to Reset.
     stateful_command_permission: StatefulPermission,
+    #[cfg(feature = "large_blobs")]
     large_blobs: LargeBlobs,
+    // Parts of the GetInfo response that only change with PIN, config and reset commands.
+    get_info_cache: Option<GetInfoCache>,
+}
+
+/// GetInfo map keys that are encoded for each request, as they change without invalidating the
+/// cache: options (built-in user verification can be enrolled at any time), forcePINChange and
+/// remainingDiscoverableCredentials.
+const DYNAMIC_GET_INFO_KEYS: [u64; 3] = [0x04, 0x0C, 0x14];
+
+/// Parts of the GetInfo response that only change with PIN, config and reset commands.
+struct GetInfoCache {
+    stored: StoredGetInfo,
+    // Encoded key and value of each entry not in DYNAMIC_GET_INFO_KEYS, filled when the first
+    // response is encoded.
+    encoded_entries: Option<Vec<(u64, Vec<u8>)>>,
+}
+
+/// GetInfo values read from the store, which only change with PIN, config and reset commands.
+#[derive(Clone, Copy)]
+struct StoredGetInfo {
+    #[cfg(feature = "with_ctap1")]
+    allows_ctap1: bool,
+    #[cfg(feature = "enterprise_attestation")]
+    enterprise_attestation: Option<bool>,
+    has_always_uv: bool,
+    has_pin: bool,
+    min_pin_length: u8,
+    firmware_version: Option<u64>,
 }
 
 impl CtapState {
@@ -511,7 +617,9 @@ impl CtapState {
             #[cfg(feature = "with_ctap1")]
             u2f_up_state: U2fUserPresenceState::new(U2F_UP_PROMPT_TIMEOUT, TOUCH_TIMEOUT),
             stateful_command_permission: StatefulPermission::new_reset(now),
+            #[cfg(feature = "large_blobs")]
             large_blobs: LargeBlobs::new(),
+            get_info_cache: None,
         }
     }
 
@@ -520,23 +628,23 @@ impl CtapState {
         self.client_pin.update_timeouts(now);
     }
 
-    pub fn increment_global_signature_counter(
+    pub fn increment_signature_counter(
         &mut self,
         env: &mut impl Env,
+        credential_id: &[u8],
     ) -> Result<(), Ctap2StatusCode> {
         if env.customization().use_signature_counter() {
-            let increment = env.rng().gen_uniform_u32x8()[0] % 8 + 1;
-            storage::incr_global_signature_counter(env, increment)?;
+            env.signature_counter().increment_counter(credential_id)?;
         }
         Ok(())
     }
 
     // Returns whether CTAP1 commands are currently supported.
     // If alwaysUv is enabled and the authenticator does not support internal UV,
-    // CTAP1 needs to be disabled.
+    // CTAP1 needs to be disabled. The customization can also disable CTAP1 entirely.
     #[cfg(feature = "with_ctap1")]
     pub fn allows_ctap1(&self, env: &mut impl Env) -> Result<bool, Ctap2StatusCode> {
-        Ok(!storage::has_always_uv(env)?)
+        Ok(env.customization().allows_ctap1() && !storage::has_always_uv(env)?)
     }
 
     pub fn process_command(
@@ -546,7 +654,7 @@ impl CtapState {
         channel: Channel,
         now: CtapInstant,
     ) -> Vec<u8> {
-        let cmd = Command::deserialize(command_cbor);
+        let cmd = Command::deserialize(command_cbor, env.customization());
         debug_ctap!(env, "Received command: {:#?}", cmd);
         let response =
             cmd.and_then(|command| self.process_parsed_command(env, command, channel, now));
@@ -554,10 +662,17 @@ impl CtapState {
         match response {
             Ok(response_data) => {
                 let mut response_vec = vec![Ctap2StatusCode::CTAP2_OK as u8];
-                if let Some(value) = response_data.into() {
-                    if cbor_write(value, &mut response_vec).is_err() {
-                        response_vec = vec![Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR as u8];
+                let encoding_result = match response_data {
+                    ResponseData::AuthenticatorGetInfo(response) => {
+                        self.encode_get_info(response, &mut response_vec)
                     }
+                    response_data => match response_data.into() {
+                        Some(value) => cbor_write(value, &mut response_vec),
+                        None => Ok(()),
+                    },
+                };
+                if encoding_result.is_err() {
+                    response_vec = vec![Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR as u8];
                 }
                 response_vec
             }
@@ -583,6 +698,8 @@ impl CtapState {
             // (like #[cfg]) are not supported on expressions.
             self.u2f_up_state = U2fUserPresenceState::new(U2F_UP_PROMPT_TIMEOUT, TOUCH_TIMEOUT);
         }
+        // Expire the pinUvAuthToken before any command gets to use it.
+        self.client_pin.update_timeouts(now);
         self.stateful_command_permission
             .clear_old_channels(&channel);
         self.stateful_command_permission.clear_timer(now);
@@ -610,6 +727,8 @@ impl CtapState {
             Channel::MainHid(_) => self.process_fido_command(env, command, channel, now),
             #[cfg(feature = "vendor_hid")]
             Channel::VendorHid(_) => self.process_vendor_command(env, command, channel),
+            #[cfg(feature = "with_nfc")]
+            Channel::Nfc => self.process_fido_command(env, command, channel, now),
         }
     }
 
@@ -627,12 +746,22 @@ impl CtapState {
             Command::AuthenticatorGetAssertion(params) => {
                 self.process_get_assertion(env, params, channel, now)
             }
-            Command::AuthenticatorGetNextAssertion => self.process_get_next_assertion(env),
+            Command::AuthenticatorGetNextAssertion => self.process_get_next_assertion(env, now),
             Command::AuthenticatorGetInfo => self.process_get_info(env),
             Command::AuthenticatorClientPin(params) => {
-                self.client_pin.process_command(env, params, now)
+                let sets_pin = matches!(
+                    params.sub_command,
+                    ClientPinSubCommand::SetPin | ClientPinSubCommand::ChangePin
+                );
+                let response = self.client_pin.process_command(env, params, now);
+                if sets_pin && response.is_ok() {
+                    self.get_info_cache = None;
+                }
+                response
             }
             Command::AuthenticatorReset => self.process_reset(env, channel),
+            Command::AuthenticatorBioEnrollment(params) => process_bio_enrollment(params),
+            #[cfg(feature = "cred_management")]
             Command::AuthenticatorCredentialManagement(params) => process_credential_management(
                 env,
                 &mut self.stateful_command_permission,
@@ -642,12 +771,17 @@ impl CtapState {
                 now,
             ),
             Command::AuthenticatorSelection => self.process_selection(env, channel),
+            #[cfg(feature = "large_blobs")]
             Command::AuthenticatorLargeBlobs(params) => {
                 self.large_blobs
                     .process_command(env, &mut self.client_pin, params)
             }
             Command::AuthenticatorConfig(params) => {
-                process_config(env, &mut self.client_pin, params)
+                let response = process_config(env, &mut self.client_pin, params);
+                if response.is_ok() {
+                    self.get_info_cache = None;
+                }
+                response
             }
             #[cfg(feature = "vendor_hid")]
             _ => Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND),
@@ -668,6 +802,9 @@ impl CtapState {
             }
             Command::AuthenticatorVendorUpgrade(params) => self.process_vendor_upgrade(env, params),
             Command::AuthenticatorVendorUpgradeInfo => self.process_vendor_upgrade_info(env),
+            Command::AuthenticatorVendorCredentialDump => {
+                self.process_vendor_credential_dump(env, channel)
+            }
             Command::AuthenticatorGetInfo => self.process_get_info(env),
             _ => Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND),
         }
@@ -732,12 +869,13 @@ impl CtapState {
 
         self.pin_uv_auth_precheck(env, &pin_uv_auth_param, pin_uv_auth_protocol, channel)?;
 
-        // When more algorithms are supported, iterate and pick the first match.
+        // The list is ordered by the RP's preference, we pick the first supported algorithm.
         let cred_param = get_preferred_cred_param(&pub_key_cred_params)
             .ok_or(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM)?;
         let algorithm = cred_param.alg;
 
         let rp_id = rp.rp_id;
+        #[cfg(feature = "enterprise_attestation")]
         let ep_att = if let Some(enterprise_attestation) = enterprise_attestation {
             let authenticator_mode = env
                 .customization()
@@ -759,11 +897,18 @@ impl CtapState {
         } else {
             false
         };
+        // Without enterprise attestation support, the option is rejected like any other
+        // unsupported option, whatever the customization says.
+        #[cfg(not(feature = "enterprise_attestation"))]
+        let ep_att = if enterprise_attestation.is_some() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
+        } else {
+            false
+        };
 
         // MakeCredential always requires user presence.
-        // User verification depends on the PIN auth inputs, which are checked here.
-        // The ED flag is added later, if applicable.
-        let has_uv = pin_uv_auth_param.is_some();
+        // User verification depends on the PIN auth inputs or the uv option, which are checked
+        // here. The ED flag is added later, if applicable.
         let mut flags = match pin_uv_auth_param {
             Some(pin_uv_auth_param) => {
                 // This case is not mentioned in CTAP2.1, so we keep 2.0 logic.
@@ -784,10 +929,11 @@ impl CtapState {
                 self.client_pin.ensure_rp_id_permission(&rp_id)?;
                 UV_FLAG
             }
+            None if options.uv => {
+                check_built_in_user_verification(env)?;
+                UV_FLAG
+            }
             None => {
-                if options.uv {
-                    return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
-                }
                 if storage::has_always_uv(env)? {
                     return Err(Ctap2StatusCode::CTAP2_ERR_PUAT_REQUIRED);
                 }
@@ -798,6 +944,7 @@ impl CtapState {
                 0x00
             }
         };
+        let has_uv = flags & UV_FLAG != 0;
         flags |= UP_FLAG | AT_FLAG;
 
         let rp_id_hash = Sha256::hash(rp_id.as_bytes());
@@ -847,10 +994,14 @@ impl CtapState {
         if has_extension_output {
             flags |= ED_FLAG
         };
+        #[cfg(feature = "large_blobs")]
         let large_blob_key = match (options.rk, extensions.large_blob_key) {
             (true, Some(true)) => Some(env.rng().gen_uniform_u8x32().to_vec()),
             _ => None,
         };
+        // The largeBlobKey extension is only useful together with largeBlobs.
+        #[cfg(not(feature = "large_blobs"))]
+        let large_blob_key = None;
 
         // We decide on the algorithm early, but delay key creation since it takes time.
         // We rather do that later so all intermediate checks may return faster.
@@ -891,8 +1042,33 @@ impl CtapState {
             )?
         };
 
-        let mut auth_data = self.generate_auth_data(env, &rp_id_hash, flags)?;
-        auth_data.extend(&storage::aaguid(env)?);
+        let attestation_id = if ep_att {
+            Some(attestation_store::Id::Enterprise)
+        } else if env.customization().use_batch_attestation() {
+            Some(attestation_store::Id::Batch)
+        } else {
+            None
+        };
+        let attestation = match attestation_id {
+            Some(attestation_store::Id::Enterprise) => Some(
+                env.attestation_store()
+                    .get(&attestation_store::Id::Enterprise)?
+                    .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?,
+            ),
+            // Unprovisioned devices fall back to self attestation.
+            Some(attestation_store::Id::Batch) => {
+                env.attestation_store().get(&attestation_store::Id::Batch)?
+            }
+            None => None,
+        };
+        // Self attestation on unprovisioned devices doesn't identify the authenticator model.
+        let aaguid = if attestation_id.is_some() && attestation.is_none() {
+            &[0; key_material::AAGUID_LENGTH]
+        } else {
+            env.customization().aaguid()
+        };
+        let mut auth_data = self.generate_auth_data(env, &rp_id_hash, flags, &credential_id)?;
+        auth_data.extend(aaguid);
         auth_data.extend(vec![
             (credential_id.len() >> 8) as u8,
             credential_id.len() as u8,
@@ -923,28 +1099,21 @@ impl CtapState {
         let mut signature_data = auth_data.clone();
         signature_data.extend(client_data_hash);
 
-        let attestation_id = if ep_att {
-            Some(attestation_store::Id::Enterprise)
-        } else if env.customization().use_batch_attestation() {
-            Some(attestation_store::Id::Batch)
-        } else {
-            None
-        };
-        let (signature, x5c) = match attestation_id {
-            Some(id) => {
-                let Attestation {
-                    private_key,
-                    certificate,
-                } = env
-                    .attestation_store()
-                    .get(&id)?
-                    .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
+        let (signature, x5c) = match attestation {
+            Some(Attestation {
+                private_key,
+                certificate,
+                intermediate_certificates,
+            }) => {
                 let attestation_key = ecdsa::SecKey::from_bytes(&private_key).unwrap();
+                // The leaf certificate comes first, each following one certifying the previous.
+                let mut x5c = vec![certificate];
+                x5c.extend(intermediate_certificates);
                 (
                     attestation_key
                         .sign_rfc6979::<Sha256>(&signature_data)
                         .to_asn1_der(),
-                    Some(vec![certificate]),
+                    Some(x5c),
                 )
             }
             None => {
@@ -1005,11 +1174,16 @@ impl CtapState {
     ) -> Result<ResponseData, Ctap2StatusCode> {
         let AssertionInput {
             client_data_hash,
-            mut auth_data,
+            rp_id_hash,
+            flags,
             extensions,
             has_uv,
         } = assertion_input;
 
+        self.increment_signature_counter(env, &credential.credential_id)?;
+        let mut auth_data =
+            self.generate_auth_data(env, &rp_id_hash, flags, &credential.credential_id)?;
+
         // Process extensions.
         if extensions.hmac_secret.is_some() || extensions.cred_blob {
             let encrypted_output = if let Some(hmac_secret_input) = extensions.hmac_secret {
@@ -1084,6 +1258,10 @@ impl CtapState {
     }
 
     // Returns the first applicable credential from the allow list.
+    //
+    // The store is scanned once for the credentials of the RP, then each entry is matched against
+    // them. All entries are also decrypted as credential IDs, even after a match or an error, so
+    // that the time spent doesn't depend on which entries match.
     fn get_any_credential_from_allow_list(
         &mut self,
         env: &mut impl Env,
@@ -1092,17 +1270,49 @@ impl CtapState {
         rp_id_hash: &[u8],
         has_uv: bool,
     ) -> Result<Option<PublicKeyCredentialSource>, Ctap2StatusCode> {
+        let mut iter_result = Ok(());
+        let iter = storage::iter_credentials(env, &mut iter_result)?;
+        let stored_credentials: Vec<(usize, Vec<u8>)> = iter
+            .filter_map(|(key, credential)| {
+                if credential.rp_id == rp_id {
+                    Some((key, credential.credential_id))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        iter_result?;
+
+        let mut applicable_credential = Ok(None);
         for allowed_credential in allow_list {
-            let credential = storage::find_credential(env, rp_id, &allowed_credential.key_id)?;
-            if self.check_cred_protect_for_listed_credential(&credential, has_uv) {
-                return Ok(credential);
-            }
-            let credential = decrypt_credential_id(env, allowed_credential.key_id, rp_id_hash)?;
-            if self.check_cred_protect_for_listed_credential(&credential, has_uv) {
-                return Ok(credential);
+            let stored_key = stored_credentials
+                .iter()
+                .find(|(_key, credential_id)| *credential_id == allowed_credential.key_id)
+                .map(|&(key, _)| key);
+            let decrypted_credential =
+                decrypt_credential_id(env, allowed_credential.key_id, rp_id_hash);
+            if !matches!(applicable_credential, Ok(None)) {
+                continue;
             }
+            let stored_credential = stored_key
+                .map(|key| storage::get_credential(env, key))
+                .transpose();
+            applicable_credential = match (stored_credential, decrypted_credential) {
+                (Err(e), _) | (_, Err(e)) => Err(e),
+                (Ok(stored_credential), Ok(decrypted_credential)) => {
+                    if self.check_cred_protect_for_listed_credential(&stored_credential, has_uv) {
+                        Ok(stored_credential)
+                    } else if self
+                        .check_cred_protect_for_listed_credential(&decrypted_credential, has_uv)
+                    {
+                        Ok(decrypted_credential)
+                    } else {
+                        Ok(None)
+                    }
+                }
+            };
         }
-        Ok(None)
+        applicable_credential
     }
 
     fn process_get_assertion(
@@ -1117,21 +1327,25 @@ impl CtapState {
             client_data_hash,
             allow_list,
             extensions,
-            options,
+            mut options,
             pin_uv_auth_param,
             pin_uv_auth_protocol,
         } = get_assertion_params;
 
         self.pin_uv_auth_precheck(env, &pin_uv_auth_param, pin_uv_auth_protocol, channel)?;
 
+        // The customization overrides requests for silent assertions.
+        if env.customization().always_require_up() {
+            options.up = true;
+        }
+
         if extensions.hmac_secret.is_some() && !options.up {
             // The extension is actually supported, but we need user presence.
             return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_OPTION);
         }
 
-        // The user verification bit depends on the existance of PIN auth, since we do
-        // not support internal UV. User presence is requested as an option.
-        let has_uv = pin_uv_auth_param.is_some();
+        // The user verification bit depends on the existance of PIN auth, or on the uv option
+        // with built-in user verification. User presence is requested as an option.
         let mut flags = match pin_uv_auth_param {
             Some(pin_uv_auth_param) => {
                 // This case is not mentioned in CTAP2.1, so we keep 2.0 logic.
@@ -1152,16 +1366,18 @@ impl CtapState {
                 self.client_pin.ensure_rp_id_permission(&rp_id)?;
                 UV_FLAG
             }
+            None if options.uv => {
+                check_built_in_user_verification(env)?;
+                UV_FLAG
+            }
             None => {
-                if options.uv {
-                    return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
-                }
                 if options.up && storage::has_always_uv(env)? {
                     return Err(Ctap2StatusCode::CTAP2_ERR_PUAT_REQUIRED);
                 }
                 0x00
             }
         };
+        let has_uv = flags & UV_FLAG != 0;
         if options.up {
             flags |= UP_FLAG;
         }
@@ -1170,6 +1386,8 @@ impl CtapState {
         }
 
         let rp_id_hash = Sha256::hash(rp_id.as_bytes());
+        // An empty allow list is handled like a missing one.
+        let allow_list = allow_list.filter(|allow_list| !allow_list.is_empty());
         let (credential, next_credential_keys) = if let Some(allow_list) = allow_list {
             (
                 self.get_any_credential_from_allow_list(
@@ -1182,6 +1400,8 @@ impl CtapState {
                 vec![],
             )
         } else {
+            // Only resident credentials are stored. Non-resident credentials are encrypted in
+            // their credential ID, so they can't be discovered and need an allow list.
             let mut iter_result = Ok(());
             let iter = storage::iter_credentials(env, &mut iter_result)?;
             let mut stored_credentials: Vec<(usize, u64)> = iter
@@ -1214,11 +1434,10 @@ impl CtapState {
             self.client_pin.clear_token_flags();
         }
 
-        self.increment_global_signature_counter(env)?;
-
         let assertion_input = AssertionInput {
             client_data_hash,
-            auth_data: self.generate_auth_data(env, &rp_id_hash, flags)?,
+            rp_id_hash,
+            flags,
             extensions,
             has_uv,
         };
@@ -1246,41 +1465,60 @@ impl CtapState {
     fn process_get_next_assertion(
         &mut self,
         env: &mut impl Env,
+        now: CtapInstant,
     ) -> Result<ResponseData, Ctap2StatusCode> {
         let (assertion_input, credential_key) = self
             .stateful_command_permission
-            .next_assertion_credential()?;
+            .next_assertion_credential(now)?;
         let credential = storage::get_credential(env, credential_key)?;
         self.assertion_response(env, credential, assertion_input, None, true)
     }
 
-    fn process_get_info(&self, env: &mut impl Env) -> Result<ResponseData, Ctap2StatusCode> {
-        let has_always_uv = storage::has_always_uv(env)?;
+    fn process_get_info(&mut self, env: &mut impl Env) -> Result<ResponseData, Ctap2StatusCode> {
+        let stored = match &self.get_info_cache {
+            Some(cache) => cache.stored,
+            None => {
+                let stored = self.read_stored_get_info(env)?;
+                self.get_info_cache = Some(GetInfoCache {
+                    stored,
+                    encoded_entries: None,
+                });
+                stored
+            }
+        };
         #[cfg_attr(not(feature = "with_ctap1"), allow(unused_mut))]
         let mut versions = vec![
             String::from(FIDO2_VERSION_STRING),
             String::from(FIDO2_1_VERSION_STRING),
         ];
         #[cfg(feature = "with_ctap1")]
-        if !has_always_uv {
+        if stored.allows_ctap1 {
             versions.insert(0, String::from(U2F_VERSION_STRING))
         }
         let mut options = vec![];
-        if env.customization().enterprise_attestation_mode().is_some() {
-            options.push((String::from("ep"), storage::enterprise_attestation(env)?));
+        #[cfg(feature = "enterprise_attestation")]
+        if let Some(enterprise_attestation) = stored.enterprise_attestation {
+            options.push((String::from("ep"), enterprise_attestation));
         }
         options.append(&mut vec![
             (String::from("rk"), true),
             (String::from("up"), true),
-            (String::from("alwaysUv"), has_always_uv),
+            (String::from("alwaysUv"), stored.has_always_uv),
+            #[cfg(feature = "cred_management")]
             (String::from("credMgmt"), true),
             (String::from("authnrCfg"), true),
-            (String::from("clientPin"), storage::pin_hash(env)?.is_some()),
+            (String::from("clientPin"), stored.has_pin),
+            #[cfg(feature = "large_blobs")]
             (String::from("largeBlobs"), true),
             (String::from("pinUvAuthToken"), true),
             (String::from("setMinPINLength"), true),
-            (String::from("makeCredUvNotRqd"), !has_always_uv),
+            (String::from("makeCredUvNotRqd"), !stored.has_always_uv),
         ]);
+        if env.user_verification().is_supported() {
+            let is_configured = env.user_verification().is_configured();
+            options.push((String::from("uv"), is_configured));
+            options.push((String::from("uvToken"), true));
+        }
         let mut pin_protocols = vec![PinUvAuthProtocol::V2 as u64];
         if env.customization().allows_pin_protocol_v1() {
             pin_protocols.push(PinUvAuthProtocol::V1 as u64);
@@ -1294,9 +1532,10 @@ impl CtapState {
                     String::from("credProtect"),
                     String::from("minPinLength"),
                     String::from("credBlob"),
+                    #[cfg(feature = "large_blobs")]
                     String::from("largeBlobKey"),
                 ]),
-                aaguid: storage::aaguid(env)?,
+                aaguid: *env.customization().aaguid(),
                 options: Some(options),
                 max_msg_size: Some(env.customization().max_msg_size() as u64),
                 // The order implies preference. We favor the new V2.
@@ -1306,14 +1545,17 @@ impl CtapState {
                     .max_credential_count_in_list()
                     .map(|c| c as u64),
                 max_credential_id_length: Some(MAX_CREDENTIAL_ID_SIZE as u64),
-                transports: Some(vec![AuthenticatorTransport::Usb]),
+                transports: Some(SUPPORTED_TRANSPORTS.to_vec()),
                 algorithms: Some(SUPPORTED_CRED_PARAMS.to_vec()),
+                #[cfg(feature = "large_blobs")]
                 max_serialized_large_blob_array: Some(
                     env.customization().max_large_blob_array_size() as u64,
                 ),
+                #[cfg(not(feature = "large_blobs"))]
+                max_serialized_large_blob_array: None,
                 force_pin_change: Some(storage::has_force_pin_change(env)?),
-                min_pin_length: storage::min_pin_length(env)?,
-                firmware_version: env.upgrade_storage().map(|u| u.running_firmware_version()),
+                min_pin_length: stored.min_pin_length,
+                firmware_version: stored.firmware_version,
                 max_cred_blob_length: Some(env.customization().max_cred_blob_length() as u64),
                 max_rp_ids_for_set_min_pin_length: Some(
                     env.customization().max_rp_ids_length() as u64
@@ -1326,6 +1568,80 @@ impl CtapState {
         ))
     }
 
+    /// Encodes a GetInfo response, reusing the cached encoding of its static entries.
+    ///
+    /// Only the entries in `DYNAMIC_GET_INFO_KEYS` are encoded for each request. The response
+    /// must come from `process_get_info`, so that its static entries match the cache.
+    fn encode_get_info(
+        &mut self,
+        response: AuthenticatorGetInfoResponse,
+        encoded_cbor: &mut Vec<u8>,
+    ) -> Result<(), Ctap2StatusCode> {
+        let cache = self
+            .get_info_cache
+            .as_mut()
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
+        let entries = match cbor::Value::from(response) {
+            cbor::Value::Map(entries) => entries,
+            _ => return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR),
+        };
+        let mut static_entries = Vec::new();
+        let mut dynamic_entries = Vec::new();
+        for (key, value) in entries {
+            let key = match key {
+                cbor::Value::Unsigned(key) => key,
+                _ => return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR),
+            };
+            let is_dynamic = DYNAMIC_GET_INFO_KEYS.contains(&key);
+            if !is_dynamic && cache.encoded_entries.is_some() {
+                continue;
+            }
+            let mut encoded_entry = Vec::new();
+            cbor_write(cbor::Value::Unsigned(key), &mut encoded_entry)?;
+            cbor_write(value, &mut encoded_entry)?;
+            if is_dynamic {
+                dynamic_entries.push((key, encoded_entry));
+            } else {
+                static_entries.push((key, encoded_entry));
+            }
+        }
+        let static_entries = cache.encoded_entries.get_or_insert(static_entries);
+
+        let mut entries: Vec<&(u64, Vec<u8>)> = static_entries
+            .iter()
+            .chain(dynamic_entries.iter())
+            .collect();
+        // The map header fits the initial byte for up to 23 entries, like in canonical CBOR.
+        if entries.len() > 23 {
+            return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
+        }
+        encoded_cbor.push(0xA0 | entries.len() as u8);
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        for (_, encoded_entry) in entries {
+            encoded_cbor.extend_from_slice(encoded_entry);
+        }
+        Ok(())
+    }
+
+    /// Reads the GetInfo values that only change with PIN, config and reset commands.
+    fn read_stored_get_info(&self, env: &mut impl Env) -> Result<StoredGetInfo, Ctap2StatusCode> {
+        #[cfg(feature = "enterprise_attestation")]
+        let enterprise_attestation = match env.customization().enterprise_attestation_mode() {
+            Some(_) => Some(storage::enterprise_attestation(env)?),
+            None => None,
+        };
+        Ok(StoredGetInfo {
+            #[cfg(feature = "with_ctap1")]
+            allows_ctap1: self.allows_ctap1(env)?,
+            #[cfg(feature = "enterprise_attestation")]
+            enterprise_attestation,
+            has_always_uv: storage::has_always_uv(env)?,
+            has_pin: storage::pin_hash(env)?.is_some(),
+            min_pin_length: storage::min_pin_length(env)?,
+            firmware_version: env.upgrade_storage().map(|u| u.running_firmware_version()),
+        })
+    }
+
     fn process_reset(
         &mut self,
         env: &mut impl Env,
@@ -1337,6 +1653,7 @@ impl CtapState {
         }
         check_user_presence(env, channel)?;
 
+        self.get_info_cache = None;
         storage::reset(env)?;
         self.client_pin.reset(env.rng());
         #[cfg(feature = "with_ctap1")]
@@ -1376,6 +1693,9 @@ impl CtapState {
                 cert_programmed: current_attestation.is_some(),
                 pkey_programmed: current_attestation.is_some(),
             },
+            Some(_) if storage::attestation_locked(env)? => {
+                return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED);
+            }
             Some(data) => {
                 // We don't overwrite the attestation if it's already set. We don't return any error
                 // to not leak information.
@@ -1383,6 +1703,7 @@ impl CtapState {
                     let attestation = Attestation {
                         private_key: data.private_key,
                         certificate: data.certificate,
+                        intermediate_certificates: data.intermediate_certificates,
                     };
                     env.attestation_store()
                         .set(&attestation_id, Some(&attestation))?;
@@ -1407,6 +1728,7 @@ impl CtapState {
             {
                 return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
             }
+            storage::lock_attestation(env)?;
         }
         Ok(ResponseData::AuthenticatorVendorConfigure(response))
     }
@@ -1416,15 +1738,37 @@ impl CtapState {
         env: &mut impl Env,
         params: AuthenticatorVendorUpgradeParameters,
     ) -> Result<ResponseData, Ctap2StatusCode> {
-        let AuthenticatorVendorUpgradeParameters { offset, data, hash } = params;
+        let AuthenticatorVendorUpgradeParameters {
+            offset,
+            data,
+            hash,
+            bundle_length,
+            bundle_hash,
+        } = params;
         let calculated_hash = Sha256::hash(&data);
         if hash != calculated_hash {
             return Err(Ctap2StatusCode::CTAP2_ERR_INTEGRITY_FAILURE);
         }
-        env.upgrade_storage()
-            .ok_or(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND)?
-            .write_bundle(offset, data)
-            .map_err(|_| Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)?;
+        // Running out of power in the middle of an upgrade leaves a partial bundle behind.
+        let power_status = env.power().power_status();
+        if power_status.map_or(false, |status| status.is_low_battery()) {
+            return Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED);
+        }
+        let upgrade_storage = env
+            .upgrade_storage()
+            .ok_or(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND)?;
+        if let (Some(bundle_length), Some(bundle_hash)) = (bundle_length, bundle_hash) {
+            upgrade_storage
+                .start_chunked_write(bundle_length, bundle_hash)
+                .map_err(|_| Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)?;
+        }
+        // Once a bundle is declared, its chunks must be sent in order.
+        if upgrade_storage.chunked_write().is_some() {
+            upgrade_storage.write_chunk(offset, data)
+        } else {
+            upgrade_storage.write_bundle(offset, data)
+        }
+        .map_err(|_| Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)?;
         Ok(ResponseData::AuthenticatorVendorUpgrade)
     }
 
@@ -1438,25 +1782,62 @@ impl CtapState {
         Ok(ResponseData::AuthenticatorVendorUpgradeInfo(
             AuthenticatorVendorUpgradeInfoResponse {
                 info: upgrade_locations.bundle_identifier(),
+                written_so_far: upgrade_locations.written_so_far(),
             },
         ))
     }
 
+    fn process_vendor_credential_dump(
+        &self,
+        env: &mut impl Env,
+        channel: Channel,
+    ) -> Result<ResponseData, Ctap2StatusCode> {
+        if !env.customization().allows_credential_dump() {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND);
+        }
+        check_user_presence(env, channel)?;
+
+        let mut iter_result = Ok(());
+        let mut credentials = storage::iter_credentials(env, &mut iter_result)?
+            .map(|(_, credential)| {
+                (
+                    credential.creation_order,
+                    credential.rp_id,
+                    credential.credential_id,
+                )
+            })
+            .collect::<Vec<_>>();
+        iter_result?;
+        credentials.sort_by_key(|(creation_order, _, _)| *creation_order);
+        let mut dump = Vec::with_capacity(credentials.len());
+        for (creation_order, rp_id, credential_id) in credentials {
+            dump.push(CredentialMetadata {
+                rp_id,
+                creation_order,
+                signature_counter: env.signature_counter().get_counter(&credential_id)?,
+            });
+        }
+        Ok(ResponseData::AuthenticatorVendorCredentialDump(
+            AuthenticatorVendorCredentialDumpResponse { credentials: dump },
+        ))
+    }
+
     pub fn generate_auth_data(
         &self,
         env: &mut impl Env,
         rp_id_hash: &[u8],
         flag_byte: u8,
+        credential_id: &[u8],
     ) -> Result<Vec<u8>, Ctap2StatusCode> {
         let mut auth_data = vec![];
         auth_data.extend(rp_id_hash);
         auth_data.push(flag_byte);
-        // The global counter is only increased if use_signature_counter() is true.
+        // The counter is only increased if use_signature_counter() is true.
         // It uses a big-endian representation.
         let mut signature_counter = [0u8; 4];
         BigEndian::write_u32(
             &mut signature_counter,
-            storage::global_signature_counter(env)?,
+            env.signature_counter().get_counter(credential_id)?,
         );
         auth_data.extend(&signature_counter);
         Ok(auth_data)
@@ -1476,23 +1857,35 @@ impl CtapState {
 #[cfg(test)]
 mod test {
     use super::client_pin::PIN_TOKEN_LENGTH;
+    #[cfg(feature = "cred_management")]
+    use super::command::AuthenticatorCredentialManagementParameters;
+    #[cfg(not(feature = "large_blobs"))]
+    use super::command::AuthenticatorLargeBlobsParameters;
     use super::command::{
         AuthenticatorAttestationMaterial, AuthenticatorClientPinParameters,
-        AuthenticatorCredentialManagementParameters,
+        AuthenticatorConfigParameters,
     };
+    use super::cose::CoseKey;
     use super::credential_id::CBOR_CREDENTIAL_ID_SIZE;
+    #[cfg(feature = "cred_management")]
+    use super::data_formats::CredentialManagementSubCommand;
+    #[cfg(not(feature = "enterprise_attestation"))]
+    use super::data_formats::EnterpriseAttestationMode;
     use super::data_formats::{
-        ClientPinSubCommand, CoseKey, CredentialManagementSubCommand, GetAssertionHmacSecretInput,
+        ClientPinSubCommand, ConfigSubCommand, ConfigSubCommandParams, GetAssertionHmacSecretInput,
         GetAssertionOptions, MakeCredentialExtensions, MakeCredentialOptions, PinUvAuthProtocol,
-        PublicKeyCredentialRpEntity, PublicKeyCredentialUserEntity,
+        PublicKeyCredentialRpEntity, PublicKeyCredentialUserEntity, SetMinPinLengthParams,
     };
     use super::pin_protocol::{authenticate_pin_uv_auth_token, PinProtocol};
     use super::*;
+    use crate::api::connection::SendOrRecvError;
     use crate::api::customization;
+    use crate::api::power::{PowerStatus, LOW_BATTERY_PERCENT};
     use crate::api::user_presence::UserPresenceResult;
-    use crate::env::test::TestEnv;
+    use crate::api::user_verification::UserVerificationError;
+    use crate::env::test::{TestEnv, TestRng256};
     use crate::test_helpers;
-    use cbor::{cbor_array, cbor_array_vec, cbor_map};
+    use cbor::{cbor_array, cbor_array_vec, cbor_bytes, cbor_int, cbor_map, cbor_text};
 
     // The keep-alive logic in the processing of some commands needs a channel ID to send
     // keep-alive packets to.
@@ -1544,6 +1937,27 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_cbor_read_nesting_depth() {
+        // Encodes an integer inside the given number of nested arrays.
+        let nested_arrays = |depth: usize| {
+            let mut encoded_cbor = vec![0x81; depth];
+            encoded_cbor.push(0x00);
+            encoded_cbor
+        };
+        let max_depth = MAX_CBOR_NESTING_DEPTH as usize;
+        assert!(cbor_read(&nested_arrays(max_depth)).is_ok());
+        assert_eq!(
+            cbor_read(&nested_arrays(max_depth + 1)),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)
+        );
+        // Decoding stops at the limit, so deep inputs don't exhaust the stack.
+        assert_eq!(
+            cbor_read(&nested_arrays(100_000)),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)
+        );
+    }
+
     #[test]
     fn test_get_info() {
         let mut env = TestEnv::new();
@@ -1558,23 +1972,28 @@ mod test {
                     String::from(FIDO2_VERSION_STRING),
                     String::from(FIDO2_1_VERSION_STRING),
                 ]],
-            0x02 => cbor_array![
+            0x02 => cbor_array_vec![vec![
                     String::from("hmac-secret"),
                     String::from("credProtect"),
                     String::from("minPinLength"),
                     String::from("credBlob"),
+                    #[cfg(feature = "large_blobs")]
                     String::from("largeBlobKey"),
-                ],
-            0x03 => storage::aaguid(&mut env).unwrap(),
+                ]],
+            0x03 => *env.customization().aaguid(),
             0x04 => cbor_map_options! {
-                "ep" => env.customization().enterprise_attestation_mode().map(|_| false),
+                "ep" => env
+                    .customization()
+                    .enterprise_attestation_mode()
+                    .filter(|_| cfg!(feature = "enterprise_attestation"))
+                    .map(|_| false),
                 "rk" => true,
                 "up" => true,
                 "alwaysUv" => false,
-                "credMgmt" => true,
+                "credMgmt" => cfg!(feature = "cred_management").then(|| true),
                 "authnrCfg" => true,
                 "clientPin" => false,
-                "largeBlobs" => true,
+                "largeBlobs" => cfg!(feature = "large_blobs").then(|| true),
                 "pinUvAuthToken" => true,
                 "setMinPINLength" => true,
                 "makeCredUvNotRqd" => true,
@@ -1585,7 +2004,8 @@ mod test {
             0x08 => MAX_CREDENTIAL_ID_SIZE as u64,
             0x09 => cbor_array!["usb"],
             0x0A => cbor_array_vec!(SUPPORTED_CRED_PARAMS.to_vec()),
-            0x0B => env.customization().max_large_blob_array_size() as u64,
+            0x0B => cfg!(feature = "large_blobs")
+                .then(|| env.customization().max_large_blob_array_size() as u64),
             0x0C => false,
             0x0D => storage::min_pin_length(&mut env).unwrap() as u64,
             0x0E => 0,
@@ -1599,11 +2019,32 @@ mod test {
         assert_eq!(info_reponse, response_cbor);
     }
 
+    #[test]
+    fn test_get_info_transports_and_algorithms() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let info_response = ctap_state.process_get_info(&mut env).unwrap();
+        match info_response {
+            ResponseData::AuthenticatorGetInfo(response) => {
+                assert_eq!(response.transports, Some(vec![AuthenticatorTransport::Usb]));
+                let algorithms: Vec<i64> = response
+                    .algorithms
+                    .unwrap()
+                    .into_iter()
+                    .map(|param| param.alg as i64)
+                    .collect();
+                assert!(algorithms.contains(&-7));
+                assert_eq!(algorithms.contains(&-8), cfg!(feature = "ed25519"));
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
     #[test]
     fn test_get_info_no_pin_protocol_v1() {
         let mut env = TestEnv::new();
         env.customization_mut().set_allows_pin_protocol_v1(false);
-        let ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
         let info_response = ctap_state.process_get_info(&mut env).unwrap();
         match info_response {
             ResponseData::AuthenticatorGetInfo(response) => {
@@ -1616,117 +2057,545 @@ mod test {
         }
     }
 
-    fn create_minimal_make_credential_parameters() -> AuthenticatorMakeCredentialParameters {
-        let client_data_hash = vec![0xCD];
-        let rp = PublicKeyCredentialRpEntity {
-            rp_id: String::from("example.com"),
-            rp_name: None,
-            rp_icon: None,
-        };
-        let user = PublicKeyCredentialUserEntity {
-            user_id: vec![0x1D],
-            user_name: None,
-            user_display_name: None,
-            user_icon: None,
-        };
-        let pub_key_cred_params = vec![ES256_CRED_PARAM];
-        let options = MakeCredentialOptions {
-            rk: true,
-            uv: false,
-        };
-        AuthenticatorMakeCredentialParameters {
-            client_data_hash,
-            rp,
-            user,
-            pub_key_cred_params,
-            exclude_list: None,
-            extensions: MakeCredentialExtensions::default(),
-            options,
-            pin_uv_auth_param: None,
-            pin_uv_auth_protocol: None,
-            enterprise_attestation: None,
+    #[test]
+    #[cfg(feature = "with_ctap1")]
+    fn test_get_info_no_ctap1() {
+        let mut env = TestEnv::new();
+        env.customization_mut().set_allows_ctap1(false);
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let info_response = ctap_state.process_get_info(&mut env).unwrap();
+        match info_response {
+            ResponseData::AuthenticatorGetInfo(response) => {
+                assert!(!response
+                    .versions
+                    .contains(&String::from(U2F_VERSION_STRING)));
+            }
+            _ => panic!("Invalid response type"),
         }
     }
 
-    fn create_make_credential_parameters_with_exclude_list(
-        excluded_credential_id: &[u8],
-    ) -> AuthenticatorMakeCredentialParameters {
-        let excluded_credential_descriptor = PublicKeyCredentialDescriptor {
-            key_type: PublicKeyCredentialType::PublicKey,
-            key_id: excluded_credential_id.to_vec(),
-            transports: None,
-        };
-        let exclude_list = Some(vec![excluded_credential_descriptor]);
-        let mut make_credential_params = create_minimal_make_credential_parameters();
-        make_credential_params.exclude_list = exclude_list;
-        make_credential_params
-    }
-
-    fn create_make_credential_parameters_with_cred_protect_policy(
-        policy: CredentialProtectionPolicy,
-    ) -> AuthenticatorMakeCredentialParameters {
-        let extensions = MakeCredentialExtensions {
-            cred_protect: Some(policy),
-            ..Default::default()
-        };
-        let mut make_credential_params = create_minimal_make_credential_parameters();
-        make_credential_params.extensions = extensions;
-        make_credential_params
+    #[test]
+    fn test_get_info_pin_policy() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        storage::set_pin(&mut env, &[0x88; 16], 4).unwrap();
+        storage::set_min_pin_length(&mut env, 6).unwrap();
+        storage::force_pin_change(&mut env).unwrap();
+        let info_response = ctap_state.process_get_info(&mut env).unwrap();
+        match info_response {
+            ResponseData::AuthenticatorGetInfo(response) => {
+                assert_eq!(response.min_pin_length, 6);
+                assert_eq!(response.force_pin_change, Some(true));
+            }
+            _ => panic!("Invalid response type"),
+        }
     }
 
-    fn parse_credential_id_from_non_resident_make_credential_response(
-        env: &mut impl Env,
-        make_credential_response: ResponseData,
-    ) -> Vec<u8> {
-        match make_credential_response {
-            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
-                let auth_data = make_credential_response.auth_data;
-                let offset = 37 + storage::aaguid(env).unwrap().len();
-                assert_eq!(auth_data[offset], 0x00);
-                assert_eq!(auth_data[offset + 1] as usize, CBOR_CREDENTIAL_ID_SIZE);
-                auth_data[offset + 2..offset + 2 + CBOR_CREDENTIAL_ID_SIZE].to_vec()
+    #[test]
+    fn test_get_info_built_in_uv() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let mut uv_options = |env: &mut TestEnv| match ctap_state.process_get_info(env).unwrap() {
+            ResponseData::AuthenticatorGetInfo(response) => {
+                let options = response.options.unwrap();
+                let option = |id: &str| {
+                    options
+                        .iter()
+                        .find(|(name, _)| name == id)
+                        .map(|(_, value)| *value)
+                };
+                (option("uv"), option("uvToken"))
             }
             _ => panic!("Invalid response type"),
-        }
+        };
+        // The options are absent without built-in user verification.
+        assert_eq!(uv_options(&mut env), (None, None));
+        env.user_verification().set_enrolled(Some(false));
+        assert_eq!(uv_options(&mut env), (Some(false), Some(true)));
+        env.user_verification().set_enrolled(Some(true));
+        assert_eq!(uv_options(&mut env), (Some(true), Some(true)));
     }
 
     #[test]
-    fn test_resident_process_make_credential() {
+    fn test_get_info_cache() {
         let mut env = TestEnv::new();
         let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let mut get_info = |env: &mut TestEnv| match ctap_state.process_get_info(env).unwrap() {
+            ResponseData::AuthenticatorGetInfo(response) => response,
+            _ => panic!("Invalid response type"),
+        };
+        let always_uv_option = |response: &AuthenticatorGetInfoResponse| {
+            response
+                .options
+                .as_ref()
+                .unwrap()
+                .iter()
+                .find(|(name, _)| name == "alwaysUv")
+                .map(|(_, value)| *value)
+        };
 
-        let make_credential_params = create_minimal_make_credential_parameters();
-        let make_credential_response =
-            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        let info_response = get_info(&mut env);
+        assert_eq!(always_uv_option(&info_response), Some(false));
+        let remaining_credentials = info_response.remaining_discoverable_credentials.unwrap();
 
-        check_make_response(
-            &make_credential_response,
-            0x41,
-            &storage::aaguid(&mut env).unwrap(),
-            0x20,
-            &[],
+        // Cached fields only change with commands, dynamic fields are always fresh.
+        storage::toggle_always_uv(&mut env).unwrap();
+        storage::force_pin_change(&mut env).unwrap();
+        let private_key = PrivateKey::new_ecdsa(&mut env);
+        storage::store_credential(
+            &mut env,
+            PublicKeyCredentialSource {
+                key_type: PublicKeyCredentialType::PublicKey,
+                credential_id: vec![0x1D; 16],
+                private_key,
+                rp_id: String::from("example.com"),
+                user_handle: vec![0x1D],
+                user_display_name: None,
+                cred_protect_policy: None,
+                creation_order: 0,
+                user_name: None,
+                user_icon: None,
+                cred_blob: None,
+                large_blob_key: None,
+            },
+        )
+        .unwrap();
+        let info_response = get_info(&mut env);
+        assert_eq!(always_uv_option(&info_response), Some(false));
+        assert_eq!(info_response.force_pin_change, Some(true));
+        assert_eq!(
+            info_response.remaining_discoverable_credentials,
+            Some(remaining_credentials - 1)
         );
     }
 
     #[test]
-    fn test_non_resident_process_make_credential() {
+    fn test_get_info_cache_invalidated_by_config() {
         let mut env = TestEnv::new();
         let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
-
-        let mut make_credential_params = create_minimal_make_credential_parameters();
-        make_credential_params.options.rk = false;
+        let min_pin_length = |ctap_state: &mut CtapState, env: &mut TestEnv| match ctap_state
+            .process_parsed_command(
+                env,
+                Command::AuthenticatorGetInfo,
+                DUMMY_CHANNEL,
+                CtapInstant::new(0),
+            )
+            .unwrap()
+        {
+            ResponseData::AuthenticatorGetInfo(response) => response.min_pin_length,
+            _ => panic!("Invalid response type"),
+        };
+        assert_eq!(min_pin_length(&mut ctap_state, &mut env), 4);
+
+        // Without a PIN, the config command needs no pinUvAuthParam.
+        let config_params = AuthenticatorConfigParameters {
+            sub_command: ConfigSubCommand::SetMinPinLength,
+            sub_command_params: Some(ConfigSubCommandParams::SetMinPinLength(
+                SetMinPinLengthParams {
+                    new_min_pin_length: Some(6),
+                    min_pin_length_rp_ids: None,
+                    force_change_pin: None,
+                },
+            )),
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let config_response = ctap_state.process_parsed_command(
+            &mut env,
+            Command::AuthenticatorConfig(config_params),
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        assert_eq!(config_response, Ok(ResponseData::AuthenticatorConfig));
+        assert_eq!(min_pin_length(&mut ctap_state, &mut env), 6);
+    }
+
+    #[test]
+    fn test_get_info_cache_kept_on_failed_config() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let expected_response = |ctap_state: &mut CtapState, env: &mut TestEnv| {
+            let response = ctap_state.process_get_info(env).unwrap();
+            let mut response_vec = vec![0x00];
+            cbor_write(
+                Option::<cbor::Value>::from(response).unwrap(),
+                &mut response_vec,
+            )
+            .unwrap();
+            response_vec
+        };
+        let get_info = |ctap_state: &mut CtapState, env: &mut TestEnv| {
+            ctap_state.process_command(env, &[0x04], DUMMY_CHANNEL, CtapInstant::new(0))
+        };
+        let cached_response = get_info(&mut ctap_state, &mut env);
+        assert_eq!(
+            cached_response,
+            expected_response(&mut ctap_state, &mut env)
+        );
+
+        // Lowering the minimum PIN length fails, so the stale alwaysUv value stays cached.
+        storage::toggle_always_uv(&mut env).unwrap();
+        let config_params = AuthenticatorConfigParameters {
+            sub_command: ConfigSubCommand::SetMinPinLength,
+            sub_command_params: Some(ConfigSubCommandParams::SetMinPinLength(
+                SetMinPinLengthParams {
+                    new_min_pin_length: Some(3),
+                    min_pin_length_rp_ids: None,
+                    force_change_pin: None,
+                },
+            )),
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let config_response = ctap_state.process_parsed_command(
+            &mut env,
+            Command::AuthenticatorConfig(config_params),
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        assert_eq!(
+            config_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION)
+        );
+        assert_eq!(get_info(&mut ctap_state, &mut env), cached_response);
+
+        ctap_state.get_info_cache = None;
+        let fresh_response = get_info(&mut ctap_state, &mut env);
+        assert_ne!(fresh_response, cached_response);
+        assert_eq!(fresh_response, expected_response(&mut ctap_state, &mut env));
+    }
+
+    #[test]
+    fn test_get_info_cache_invalidated_by_set_pin() {
+        let mut env = TestEnv::new();
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(env.rng());
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let mut process = |env: &mut TestEnv, command: Command| {
+            ctap_state
+                .process_parsed_command(env, command, DUMMY_CHANNEL, CtapInstant::new(0))
+                .unwrap()
+        };
+        let option = |response: &ResponseData, name: &str| match response {
+            ResponseData::AuthenticatorGetInfo(response) => response
+                .options
+                .as_ref()
+                .unwrap()
+                .iter()
+                .find(|(option_name, _)| option_name == name)
+                .map(|(_, value)| *value),
+            _ => panic!("Invalid response type"),
+        };
+        let client_pin_params = |sub_command| AuthenticatorClientPinParameters {
+            pin_uv_auth_protocol: PinUvAuthProtocol::V2,
+            sub_command,
+            key_agreement: None,
+            pin_uv_auth_param: None,
+            new_pin_enc: None,
+            pin_hash_enc: None,
+            permissions: None,
+            permissions_rp_id: None,
+        };
+
+        let info_response = process(&mut env, Command::AuthenticatorGetInfo);
+        assert_eq!(option(&info_response, "clientPin"), Some(false));
+        assert_eq!(option(&info_response, "alwaysUv"), Some(false));
+
+        // Read-only subcommands keep the cache.
+        storage::toggle_always_uv(&mut env).unwrap();
+        process(
+            &mut env,
+            Command::AuthenticatorClientPin(client_pin_params(ClientPinSubCommand::GetPinRetries)),
+        );
+        let key_agreement_response = process(
+            &mut env,
+            Command::AuthenticatorClientPin(client_pin_params(
+                ClientPinSubCommand::GetKeyAgreement,
+            )),
+        );
+        let info_response = process(&mut env, Command::AuthenticatorGetInfo);
+        assert_eq!(option(&info_response, "alwaysUv"), Some(false));
+
+        let public_key = match key_agreement_response {
+            ResponseData::AuthenticatorClientPin(Some(client_pin_response)) => {
+                client_pin_response.key_agreement.unwrap()
+            }
+            _ => panic!("Invalid response type"),
+        };
+        let platform_public_key = CoseKey::from(key_agreement_key.genpk());
+        let pin_protocol = PinProtocol::new_test(key_agreement_key, [0x91; 32]);
+        let shared_secret = pin_protocol
+            .decapsulate(public_key, PinUvAuthProtocol::V2)
+            .unwrap();
+        let mut padded_pin = [0u8; 64];
+        padded_pin[..4].copy_from_slice(b"1234");
+        let new_pin_enc = shared_secret.encrypt(env.rng(), &padded_pin).unwrap();
+        let mut set_pin_params = client_pin_params(ClientPinSubCommand::SetPin);
+        set_pin_params.key_agreement = Some(platform_public_key);
+        set_pin_params.pin_uv_auth_param = Some(shared_secret.authenticate(&new_pin_enc));
+        set_pin_params.new_pin_enc = Some(new_pin_enc);
+        process(&mut env, Command::AuthenticatorClientPin(set_pin_params));
+        let info_response = process(&mut env, Command::AuthenticatorGetInfo);
+        assert_eq!(option(&info_response, "clientPin"), Some(true));
+        assert_eq!(option(&info_response, "alwaysUv"), Some(true));
+    }
+
+    #[test]
+    fn test_process_bio_enrollment() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let get_modality = AuthenticatorBioEnrollmentParameters {
+            sub_command: None,
+            get_modality: true,
+        };
+        assert_eq!(
+            ctap_state.process_parsed_command(
+                &mut env,
+                Command::AuthenticatorBioEnrollment(get_modality),
+                DUMMY_CHANNEL,
+                CtapInstant::new(0),
+            ),
+            Ok(ResponseData::AuthenticatorBioEnrollment)
+        );
+
+        let enroll_begin = AuthenticatorBioEnrollmentParameters {
+            sub_command: Some(0x01),
+            get_modality: false,
+        };
+        assert_eq!(
+            ctap_state.process_parsed_command(
+                &mut env,
+                Command::AuthenticatorBioEnrollment(enroll_begin),
+                DUMMY_CHANNEL,
+                CtapInstant::new(0),
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND)
+        );
+    }
+
+    /// Parses the ASN1 DER representation of an ECDSA signature.
+    fn signature_from_asn1_der(der: &[u8]) -> ecdsa::Signature {
+        assert_eq!(der[0], 0x30);
+        assert_eq!(der[1] as usize, der.len() - 2);
+        let mut bytes = [0; ecdsa::Signature::BYTES_LENGTH];
+        let mut offset = 2;
+        for exponent in bytes.chunks_mut(ecdsa::Signature::BYTES_LENGTH / 2) {
+            let exponent_length = exponent.len();
+            assert_eq!(der[offset], 0x02);
+            let length = der[offset + 1] as usize;
+            let value = &der[offset + 2..offset + 2 + length];
+            // Strip the sign byte of the minimal encoding.
+            let value = &value[value.len().saturating_sub(exponent_length)..];
+            exponent[exponent_length - value.len()..].copy_from_slice(value);
+            offset += 2 + length;
+        }
+        assert_eq!(offset, der.len());
+        ecdsa::Signature::from_bytes(&bytes).unwrap()
+    }
+
+    /// Returns the signature counter shared by all credentials with the global strategy.
+    fn global_signature_counter(env: &mut TestEnv) -> u32 {
+        env.signature_counter().get_counter(&[]).unwrap()
+    }
+
+    fn create_minimal_make_credential_parameters() -> AuthenticatorMakeCredentialParameters {
+        let client_data_hash = vec![0xCD];
+        let rp = PublicKeyCredentialRpEntity {
+            rp_id: String::from("example.com"),
+            rp_name: None,
+            rp_icon: None,
+        };
+        let user = PublicKeyCredentialUserEntity {
+            user_id: vec![0x1D],
+            user_name: None,
+            user_display_name: None,
+            user_icon: None,
+        };
+        let pub_key_cred_params = vec![ES256_CRED_PARAM];
+        let options = MakeCredentialOptions {
+            rk: true,
+            uv: false,
+        };
+        AuthenticatorMakeCredentialParameters {
+            client_data_hash,
+            rp,
+            user,
+            pub_key_cred_params,
+            exclude_list: None,
+            extensions: MakeCredentialExtensions::default(),
+            options,
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+            enterprise_attestation: None,
+        }
+    }
+
+    fn create_make_credential_parameters_with_exclude_list(
+        excluded_credential_id: &[u8],
+    ) -> AuthenticatorMakeCredentialParameters {
+        let excluded_credential_descriptor = PublicKeyCredentialDescriptor {
+            key_type: PublicKeyCredentialType::PublicKey,
+            key_id: excluded_credential_id.to_vec(),
+            transports: None,
+        };
+        let exclude_list = Some(vec![excluded_credential_descriptor]);
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.exclude_list = exclude_list;
+        make_credential_params
+    }
+
+    fn create_make_credential_parameters_with_cred_protect_policy(
+        policy: CredentialProtectionPolicy,
+    ) -> AuthenticatorMakeCredentialParameters {
+        let extensions = MakeCredentialExtensions {
+            cred_protect: Some(policy),
+            ..Default::default()
+        };
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.extensions = extensions;
+        make_credential_params
+    }
+
+    fn parse_credential_id_from_non_resident_make_credential_response(
+        env: &mut impl Env,
+        make_credential_response: ResponseData,
+    ) -> Vec<u8> {
+        match make_credential_response {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                let auth_data = make_credential_response.auth_data;
+                let offset = 37 + env.customization().aaguid().len();
+                assert_eq!(auth_data[offset], 0x00);
+                assert_eq!(auth_data[offset + 1] as usize, CBOR_CREDENTIAL_ID_SIZE);
+                auth_data[offset + 2..offset + 2 + CBOR_CREDENTIAL_ID_SIZE].to_vec()
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    fn test_resident_process_make_credential_replaces_same_user() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let mut credential_ids = vec![];
+        for _ in 0..2 {
+            let make_credential_params = create_minimal_make_credential_parameters();
+            let auth_data = match ctap_state
+                .process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL)
+                .unwrap()
+            {
+                ResponseData::AuthenticatorMakeCredential(response) => response.auth_data,
+                _ => panic!("Invalid response type"),
+            };
+            let offset = 37 + env.customization().aaguid().len();
+            let length = BigEndian::read_u16(&auth_data[offset..offset + 2]) as usize;
+            credential_ids.push(auth_data[offset + 2..offset + 2 + length].to_vec());
+        }
+        assert_ne!(credential_ids[0], credential_ids[1]);
+        assert_eq!(storage::count_credentials(&mut env), Ok(1));
+
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: GetAssertionExtensions::default(),
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let get_assertion_response = ctap_state.process_get_assertion(
+            &mut env,
+            get_assertion_params,
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        match get_assertion_response.unwrap() {
+            ResponseData::AuthenticatorGetAssertion(response) => {
+                assert_eq!(response.credential.unwrap().key_id, credential_ids[1]);
+                assert_eq!(response.number_of_credentials, None);
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    fn test_resident_process_make_credential() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let make_credential_params = create_minimal_make_credential_parameters();
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+
+        check_make_response(
+            &make_credential_response,
+            0x41,
+            env.customization().aaguid(),
+            0x20,
+            &[],
+        );
+    }
+
+    #[test]
+    fn test_non_resident_process_make_credential() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.options.rk = false;
         let make_credential_response =
             ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
 
         check_make_response(
             &make_credential_response,
             0x41,
-            &storage::aaguid(&mut env).unwrap(),
+            env.customization().aaguid(),
             CBOR_CREDENTIAL_ID_SIZE as u8,
             &[],
         );
     }
 
+    #[test]
+    fn test_process_make_credential_deterministic_rng() {
+        let make_credential = |seed: [u8; 32]| {
+            let mut env = TestEnv::new();
+            *env.rng() = TestRng256::from_seed(seed);
+            let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+            let mut make_credential_params = create_minimal_make_credential_parameters();
+            make_credential_params.options.rk = false;
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL)
+        };
+        let response = make_credential([0x55; 32]);
+        assert!(response.is_ok());
+        assert_eq!(make_credential([0x55; 32]), response);
+        assert_ne!(make_credential([0xAA; 32]), response);
+    }
+
+    #[test]
+    fn test_process_make_credential_customized_aaguid() {
+        const AAGUID: &[u8; 16] = &[
+            0x5C, 0x6D, 0xE2, 0x01, 0x93, 0x4A, 0x4E, 0x1B, 0x8F, 0x27, 0x10, 0xB3, 0x64, 0xD9,
+            0xAE, 0x52,
+        ];
+        let mut env = TestEnv::new();
+        env.customization_mut().set_aaguid(AAGUID);
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let make_credential_params = create_minimal_make_credential_parameters();
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        check_make_response(&make_credential_response, 0x41, AAGUID, 0x20, &[]);
+
+        match ctap_state.process_get_info(&mut env).unwrap() {
+            ResponseData::AuthenticatorGetInfo(get_info_response) => {
+                assert_eq!(&get_info_response.aaguid, AAGUID);
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
     #[test]
     fn test_process_make_credential_unsupported_algorithm() {
         let mut env = TestEnv::new();
@@ -1743,6 +2612,68 @@ mod test {
         );
     }
 
+    fn create_rs256_cred_param() -> PublicKeyCredentialParameter {
+        PublicKeyCredentialParameter::try_from(cbor_map! {
+            "alg" => -257,
+            "type" => "public-key",
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_get_preferred_cred_param() {
+        let rs256_cred_param = create_rs256_cred_param();
+        assert_eq!(get_preferred_cred_param(&[rs256_cred_param.clone()]), None);
+        assert_eq!(
+            get_preferred_cred_param(&[rs256_cred_param.clone(), ES256_CRED_PARAM]),
+            Some(&ES256_CRED_PARAM)
+        );
+        // The order of the list decides between supported algorithms.
+        assert_eq!(
+            get_preferred_cred_param(&[ES256_CRED_PARAM, HYBRID_CRED_PARAM]),
+            Some(&ES256_CRED_PARAM)
+        );
+        assert_eq!(
+            get_preferred_cred_param(&[rs256_cred_param, HYBRID_CRED_PARAM, ES256_CRED_PARAM]),
+            Some(&HYBRID_CRED_PARAM)
+        );
+        #[cfg(feature = "ed25519")]
+        assert_eq!(
+            get_preferred_cred_param(&[EDDSA_CRED_PARAM, ES256_CRED_PARAM]),
+            Some(&EDDSA_CRED_PARAM)
+        );
+    }
+
+    #[test]
+    fn test_process_make_credential_first_supported_algorithm() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.pub_key_cred_params = vec![create_rs256_cred_param()];
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM)
+        );
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.pub_key_cred_params =
+            vec![create_rs256_cred_param(), ES256_CRED_PARAM];
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        assert!(make_credential_response.is_ok());
+        let mut iter_result = Ok(());
+        let iter = storage::iter_credentials(&mut env, &mut iter_result).unwrap();
+        let (_, stored_credential) = iter.last().unwrap();
+        iter_result.unwrap();
+        assert_eq!(
+            stored_credential.private_key.signature_algorithm(),
+            SignatureAlgorithm::Es256
+        );
+    }
+
     #[test]
     fn test_process_make_credential_credential_excluded() {
         let mut env = TestEnv::new();
@@ -1776,6 +2707,79 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_process_make_credential_credential_excluded_without_user_presence() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.options.rk = false;
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        let credential_id = parse_credential_id_from_non_resident_make_credential_response(
+            &mut env,
+            make_credential_response.unwrap(),
+        );
+
+        // User presence is requested, but its outcome doesn't reveal the credential.
+        env.user_presence().set(|| Err(UserPresenceError::Declined));
+        let make_credential_params =
+            create_make_credential_parameters_with_exclude_list(&credential_id);
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_CREDENTIAL_EXCLUDED)
+        );
+    }
+
+    #[test]
+    fn test_process_make_credential_exclude_list_not_matching() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.options.rk = false;
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        let non_resident_credential_id =
+            parse_credential_id_from_non_resident_make_credential_response(
+                &mut env,
+                make_credential_response.unwrap(),
+            );
+        let make_credential_params = create_minimal_make_credential_parameters();
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        assert!(make_credential_response.is_ok());
+        let mut iter_result = Ok(());
+        let iter = storage::iter_credentials(&mut env, &mut iter_result).unwrap();
+        let (_, stored_credential) = iter.last().unwrap();
+        iter_result.unwrap();
+        let resident_credential_id = stored_credential.credential_id;
+
+        // Unknown or invalid credential IDs are ignored.
+        for credential_id in &[
+            vec![0x01, 0x23, 0x45, 0x67],
+            vec![0x55; non_resident_credential_id.len()],
+        ] {
+            let make_credential_params =
+                create_make_credential_parameters_with_exclude_list(credential_id);
+            let make_credential_response =
+                ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+            assert!(make_credential_response.is_ok());
+        }
+
+        // Credentials of another RP don't exclude.
+        for credential_id in &[non_resident_credential_id, resident_credential_id] {
+            let mut make_credential_params =
+                create_make_credential_parameters_with_exclude_list(credential_id);
+            make_credential_params.rp.rp_id = String::from("another.example.com");
+            let make_credential_response =
+                ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+            assert!(make_credential_response.is_ok());
+        }
+    }
+
     #[test]
     fn test_process_make_credential_credential_with_cred_protect() {
         let mut env = TestEnv::new();
@@ -1891,7 +2895,7 @@ mod test {
         check_make_response(
             &make_credential_response,
             0xC1,
-            &storage::aaguid(&mut env).unwrap(),
+            env.customization().aaguid(),
             CBOR_CREDENTIAL_ID_SIZE as u8,
             &expected_extension_cbor,
         );
@@ -1917,7 +2921,7 @@ mod test {
         check_make_response(
             &make_credential_response,
             0xC1,
-            &storage::aaguid(&mut env).unwrap(),
+            env.customization().aaguid(),
             0x20,
             &expected_extension_cbor,
         );
@@ -1940,7 +2944,7 @@ mod test {
         check_make_response(
             &make_credential_response,
             0x41,
-            &storage::aaguid(&mut env).unwrap(),
+            env.customization().aaguid(),
             0x20,
             &[],
         );
@@ -1966,7 +2970,29 @@ mod test {
         check_make_response(
             &make_credential_response,
             0xC1,
-            &storage::aaguid(&mut env).unwrap(),
+            env.customization().aaguid(),
+            0x20,
+            &expected_extension_cbor,
+        );
+
+        // Third part: The extension reports the updated minimum PIN length.
+        assert_eq!(storage::set_min_pin_length(&mut env, 8), Ok(()));
+        let extensions = MakeCredentialExtensions {
+            min_pin_length: true,
+            ..Default::default()
+        };
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.extensions = extensions;
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        let expected_extension_cbor = [
+            0xA1, 0x6C, 0x6D, 0x69, 0x6E, 0x50, 0x69, 0x6E, 0x4C, 0x65, 0x6E, 0x67, 0x74, 0x68,
+            0x08,
+        ];
+        check_make_response(
+            &make_credential_response,
+            0xC1,
+            env.customization().aaguid(),
             0x20,
             &expected_extension_cbor,
         );
@@ -1991,7 +3017,7 @@ mod test {
         check_make_response(
             &make_credential_response,
             0xC1,
-            &storage::aaguid(&mut env).unwrap(),
+            env.customization().aaguid(),
             0x20,
             &expected_extension_cbor,
         );
@@ -2004,6 +3030,42 @@ mod test {
         assert_eq!(stored_credential.cred_blob, Some(vec![0xCB]));
     }
 
+    #[test]
+    fn test_process_make_credential_cred_blob_max_length() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let max_cred_blob_length = env.customization().max_cred_blob_length();
+        let extensions = MakeCredentialExtensions {
+            cred_blob: Some(vec![0xCB; max_cred_blob_length]),
+            ..Default::default()
+        };
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.extensions = extensions;
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        let expected_extension_cbor = [
+            0xA1, 0x68, 0x63, 0x72, 0x65, 0x64, 0x42, 0x6C, 0x6F, 0x62, 0xF5,
+        ];
+        check_make_response(
+            &make_credential_response,
+            0xC1,
+            env.customization().aaguid(),
+            0x20,
+            &expected_extension_cbor,
+        );
+
+        let mut iter_result = Ok(());
+        let iter = storage::iter_credentials(&mut env, &mut iter_result).unwrap();
+        // There is only 1 credential, so last is good enough.
+        let (_, stored_credential) = iter.last().unwrap();
+        iter_result.unwrap();
+        assert_eq!(
+            stored_credential.cred_blob,
+            Some(vec![0xCB; max_cred_blob_length])
+        );
+    }
+
     #[test]
     fn test_process_make_credential_cred_blob_too_big() {
         let mut env = TestEnv::new();
@@ -2023,7 +3085,7 @@ mod test {
         check_make_response(
             &make_credential_response,
             0xC1,
-            &storage::aaguid(&mut env).unwrap(),
+            env.customization().aaguid(),
             0x20,
             &expected_extension_cbor,
         );
@@ -2037,6 +3099,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "large_blobs")]
     fn test_process_make_credential_large_blob_key() {
         let mut env = TestEnv::new();
         let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
@@ -2097,7 +3160,7 @@ mod test {
         check_make_response(
             &make_credential_response,
             0x45,
-            &storage::aaguid(&mut env).unwrap(),
+            env.customization().aaguid(),
             0x20,
             &[],
         );
@@ -2120,6 +3183,39 @@ mod test {
         test_helper_process_make_credential_with_pin_and_uv(PinUvAuthProtocol::V2);
     }
 
+    #[test]
+    fn test_process_make_credential_with_expired_pin_uv_auth_token() {
+        let mut env = TestEnv::new();
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(env.rng());
+        let pin_uv_auth_token = [0x91; PIN_TOKEN_LENGTH];
+        let client_pin =
+            ClientPin::new_test(key_agreement_key, pin_uv_auth_token, PinUvAuthProtocol::V2);
+
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        ctap_state.client_pin = client_pin;
+        storage::set_pin(&mut env, &[0x88; 16], 4).unwrap();
+
+        let client_data_hash = [0xCD];
+        let pin_uv_auth_param = authenticate_pin_uv_auth_token(
+            &pin_uv_auth_token,
+            &client_data_hash,
+            PinUvAuthProtocol::V2,
+        );
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.pin_uv_auth_param = Some(pin_uv_auth_param);
+        make_credential_params.pin_uv_auth_protocol = Some(PinUvAuthProtocol::V2);
+        let make_credential_response = ctap_state.process_parsed_command(
+            &mut env,
+            Command::AuthenticatorMakeCredential(make_credential_params),
+            DUMMY_CHANNEL,
+            CtapInstant::new(0) + Milliseconds(30001 as ClockInt),
+        );
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+    }
+
     #[test]
     fn test_non_resident_process_make_credential_with_pin() {
         let mut env = TestEnv::new();
@@ -2131,67 +3227,224 @@ mod test {
         let make_credential_response =
             ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
 
-        check_make_response(
-            &make_credential_response,
-            0x41,
-            &storage::aaguid(&mut env).unwrap(),
-            CBOR_CREDENTIAL_ID_SIZE as u8,
-            &[],
-        );
+        check_make_response(
+            &make_credential_response,
+            0x41,
+            env.customization().aaguid(),
+            CBOR_CREDENTIAL_ID_SIZE as u8,
+            &[],
+        );
+    }
+
+    #[test]
+    fn test_resident_process_make_credential_with_pin() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        storage::set_pin(&mut env, &[0x88; 16], 4).unwrap();
+
+        let make_credential_params = create_minimal_make_credential_parameters();
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_PUAT_REQUIRED)
+        );
+    }
+
+    #[test]
+    fn test_process_make_credential_with_pin_always_uv() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        storage::toggle_always_uv(&mut env).unwrap();
+        let make_credential_params = create_minimal_make_credential_parameters();
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_PUAT_REQUIRED)
+        );
+
+        storage::set_pin(&mut env, &[0x88; 16], 4).unwrap();
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.pin_uv_auth_param = Some(vec![0xA4; 16]);
+        make_credential_params.pin_uv_auth_protocol = Some(PinUvAuthProtocol::V1);
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+    }
+
+    #[test]
+    fn test_process_make_credential_always_uv_cred_protect() {
+        let mut env = TestEnv::new();
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(env.rng());
+        let pin_uv_auth_token = [0x91; PIN_TOKEN_LENGTH];
+        let client_pin =
+            ClientPin::new_test(key_agreement_key, pin_uv_auth_token, PinUvAuthProtocol::V2);
+
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        ctap_state.client_pin = client_pin;
+        storage::set_pin(&mut env, &[0x88; 16], 4).unwrap();
+        storage::toggle_always_uv(&mut env).unwrap();
+
+        // The lowest protection level does not lift the UV requirement.
+        let make_credential_params = create_make_credential_parameters_with_cred_protect_policy(
+            CredentialProtectionPolicy::UserVerificationOptional,
+        );
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_PUAT_REQUIRED)
+        );
+
+        let test_policy = CredentialProtectionPolicy::UserVerificationRequired;
+        let mut make_credential_params =
+            create_make_credential_parameters_with_cred_protect_policy(test_policy);
+        let pin_uv_auth_param = authenticate_pin_uv_auth_token(
+            &pin_uv_auth_token,
+            &make_credential_params.client_data_hash,
+            PinUvAuthProtocol::V2,
+        );
+        make_credential_params.pin_uv_auth_param = Some(pin_uv_auth_param);
+        make_credential_params.pin_uv_auth_protocol = Some(PinUvAuthProtocol::V2);
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        assert!(make_credential_response.is_ok());
+
+        let mut iter_result = Ok(());
+        let iter = storage::iter_credentials(&mut env, &mut iter_result).unwrap();
+        // There is only 1 credential, so last is good enough.
+        let (_, stored_credential) = iter.last().unwrap();
+        iter_result.unwrap();
+        assert_eq!(stored_credential.cred_protect_policy, Some(test_policy));
+    }
+
+    #[cfg(feature = "enterprise_attestation")]
+    fn check_ep(make_credential_response: Result<ResponseData, Ctap2StatusCode>, has_ep: bool) {
+        let ep_att = if has_ep { Some(true) } else { None };
+        match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                assert_eq!(make_credential_response.ep_att, ep_att);
+            }
+            _ => panic!("Invalid response type"),
+        }
     }
 
     #[test]
-    fn test_resident_process_make_credential_with_pin() {
+    fn test_process_make_credential_batch_attestation_fallback() {
         let mut env = TestEnv::new();
+        env.customization_mut().set_use_batch_attestation(true);
         let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
-        storage::set_pin(&mut env, &[0x88; 16], 4).unwrap();
 
+        // Without provisioned attestation, self attestation is used.
         let make_credential_params = create_minimal_make_credential_parameters();
+        let client_data_hash = make_credential_params.client_data_hash.clone();
         let make_credential_response =
             ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        let make_credential_response = match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                make_credential_response
+            }
+            _ => panic!("Invalid response type"),
+        };
+        assert_eq!(make_credential_response.fmt, "packed");
+        assert_eq!(make_credential_response.att_stmt.x5c, None);
+        let auth_data = make_credential_response.auth_data;
+        assert_eq!(&auth_data[37..53], &[0; key_material::AAGUID_LENGTH]);
+        let length = BigEndian::read_u16(&auth_data[53..55]) as usize;
+        let credential_id = &auth_data[55..55 + length];
+        let (_, credential) = storage::find_credential_item(&mut env, credential_id).unwrap();
+        let public_key = credential.private_key.ecdsa_key(&mut env).unwrap().genpk();
         assert_eq!(
-            make_credential_response,
-            Err(Ctap2StatusCode::CTAP2_ERR_PUAT_REQUIRED)
+            cbor_read(&auth_data[55 + length..]),
+            Ok(cbor::Value::from(CoseKey::from(public_key.clone())))
         );
+        let mut signed_data = auth_data.clone();
+        signed_data.extend(client_data_hash);
+        let signature = signature_from_asn1_der(&make_credential_response.att_stmt.sig);
+        assert!(public_key.verify_vartime::<Sha256>(&signed_data, &signature));
+
+        // Once provisioned, batch attestation is used.
+        let attestation = Attestation {
+            private_key: [0x41; key_material::ATTESTATION_PRIVATE_KEY_LENGTH],
+            certificate: vec![0xdd; 20],
+            intermediate_certificates: vec![],
+        };
+        env.attestation_store()
+            .set(&attestation_store::Id::Batch, Some(&attestation))
+            .unwrap();
+        let make_credential_params = create_minimal_make_credential_parameters();
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                assert_eq!(
+                    &make_credential_response.auth_data[37..53],
+                    env.customization().aaguid()
+                );
+                assert_eq!(
+                    make_credential_response.att_stmt.x5c,
+                    Some(vec![attestation.certificate])
+                );
+            }
+            _ => panic!("Invalid response type"),
+        }
     }
 
     #[test]
-    fn test_process_make_credential_with_pin_always_uv() {
+    fn test_process_make_credential_with_certificate_chain() {
         let mut env = TestEnv::new();
+        env.customization_mut().set_use_batch_attestation(true);
         let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
 
-        storage::toggle_always_uv(&mut env).unwrap();
+        let leaf_certificate = vec![0x30, 0x01, 0x11];
+        let intermediate_certificate = vec![0x30, 0x02, 0x22, 0x22];
+        let attestation = Attestation {
+            private_key: [0x41; key_material::ATTESTATION_PRIVATE_KEY_LENGTH],
+            certificate: leaf_certificate.clone(),
+            intermediate_certificates: vec![intermediate_certificate.clone()],
+        };
+        env.attestation_store()
+            .set(&attestation_store::Id::Batch, Some(&attestation))
+            .unwrap();
         let make_credential_params = create_minimal_make_credential_parameters();
-        let make_credential_response =
-            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
-        assert_eq!(
-            make_credential_response,
-            Err(Ctap2StatusCode::CTAP2_ERR_PUAT_REQUIRED)
-        );
-
-        storage::set_pin(&mut env, &[0x88; 16], 4).unwrap();
-        let mut make_credential_params = create_minimal_make_credential_parameters();
-        make_credential_params.pin_uv_auth_param = Some(vec![0xA4; 16]);
-        make_credential_params.pin_uv_auth_protocol = Some(PinUvAuthProtocol::V1);
-        let make_credential_response =
-            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
-        assert_eq!(
-            make_credential_response,
-            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
-        );
-    }
-
-    fn check_ep(make_credential_response: Result<ResponseData, Ctap2StatusCode>, has_ep: bool) {
-        let ep_att = if has_ep { Some(true) } else { None };
-        match make_credential_response.unwrap() {
-            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
-                assert_eq!(make_credential_response.ep_att, ep_att);
+        let make_credential_response = ctap_state
+            .process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL)
+            .unwrap();
+        let response_cbor: Option<cbor::Value> = make_credential_response.into();
+        let att_stmt = match response_cbor {
+            Some(cbor::Value::Map(map)) => {
+                map.into_iter()
+                    .find(|(key, _)| *key == cbor_int!(0x03))
+                    .unwrap()
+                    .1
             }
             _ => panic!("Invalid response type"),
-        }
+        };
+        let x5c = match att_stmt {
+            cbor::Value::Map(map) => {
+                map.into_iter()
+                    .find(|(key, _)| *key == cbor_text!("x5c"))
+                    .unwrap()
+                    .1
+            }
+            _ => panic!("Invalid attestation statement"),
+        };
+        assert_eq!(
+            x5c,
+            cbor_array![
+                cbor_bytes!(leaf_certificate),
+                cbor_bytes!(intermediate_certificate),
+            ]
+        );
     }
 
     #[test]
+    #[cfg(feature = "enterprise_attestation")]
     fn test_process_make_credential_with_enterprise_attestation_vendor_facilitated() {
         let mut env = TestEnv::new();
         env.customization_mut().setup_enterprise_attestation(
@@ -2238,6 +3491,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "enterprise_attestation")]
     fn test_process_make_credential_with_enterprise_attestation_platform_managed() {
         let mut env = TestEnv::new();
         env.customization_mut().setup_enterprise_attestation(
@@ -2274,6 +3528,46 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "enterprise_attestation")]
+    fn test_process_make_credential_enterprise_attestation_certificate() {
+        let mut env = TestEnv::new();
+        env.customization_mut()
+            .setup_enterprise_attestation(Some(EnterpriseAttestationMode::PlatformManaged), None);
+
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let attestation_material =
+            test_helpers::enable_enterprise_attestation(&mut ctap_state, &mut env).unwrap();
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.enterprise_attestation = Some(2);
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                assert_eq!(make_credential_response.ep_att, Some(true));
+                assert_eq!(
+                    make_credential_response.att_stmt.x5c,
+                    Some(vec![attestation_material.certificate])
+                );
+            }
+            _ => panic!("Invalid response type"),
+        }
+
+        // Without the parameter, no enterprise attestation is returned.
+        let make_credential_params = create_minimal_make_credential_parameters();
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                assert_eq!(make_credential_response.ep_att, None);
+                assert_eq!(make_credential_response.att_stmt.x5c, None);
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "enterprise_attestation")]
     fn test_process_make_credential_with_enterprise_attestation_invalid() {
         let mut env = TestEnv::new();
         env.customization_mut()
@@ -2376,31 +3670,261 @@ mod test {
         );
     }
 
-    fn check_assertion_response(
-        response: Result<ResponseData, Ctap2StatusCode>,
-        expected_user_id: Vec<u8>,
-        signature_counter: u32,
-        expected_number_of_credentials: Option<u64>,
-    ) {
-        let expected_user = PublicKeyCredentialUserEntity {
-            user_id: expected_user_id,
-            user_name: None,
-            user_display_name: None,
-            user_icon: None,
-        };
-        check_assertion_response_with_user(
-            response,
-            Some(expected_user),
-            0x00,
-            signature_counter,
-            expected_number_of_credentials,
+    fn check_assertion_response(
+        response: Result<ResponseData, Ctap2StatusCode>,
+        expected_user_id: Vec<u8>,
+        signature_counter: u32,
+        expected_number_of_credentials: Option<u64>,
+    ) {
+        let expected_user = PublicKeyCredentialUserEntity {
+            user_id: expected_user_id,
+            user_name: None,
+            user_display_name: None,
+            user_icon: None,
+        };
+        check_assertion_response_with_user(
+            response,
+            Some(expected_user),
+            0x00,
+            signature_counter,
+            expected_number_of_credentials,
+            &[],
+        );
+    }
+
+    #[test]
+    fn test_resident_process_get_assertion() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let make_credential_params = create_minimal_make_credential_parameters();
+        assert!(ctap_state
+            .process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL)
+            .is_ok());
+
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: GetAssertionExtensions::default(),
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let get_assertion_response = ctap_state.process_get_assertion(
+            &mut env,
+            get_assertion_params,
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        let signature_counter = global_signature_counter(&mut env);
+        check_assertion_response(get_assertion_response, vec![0x1D], signature_counter, None);
+    }
+
+    fn create_silent_get_assertion_parameters() -> AuthenticatorGetAssertionParameters {
+        AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: GetAssertionExtensions::default(),
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        }
+    }
+
+    #[test]
+    fn test_process_get_assertion_silent() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let make_credential_params = create_minimal_make_credential_parameters();
+        assert!(ctap_state
+            .process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL)
+            .is_ok());
+
+        // Without the customization, no touch is requested and the UP flag stays unset.
+        env.user_presence()
+            .set(|| panic!("Unexpected user presence check"));
+        let get_assertion_response = ctap_state.process_get_assertion(
+            &mut env,
+            create_silent_get_assertion_parameters(),
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        let signature_counter = global_signature_counter(&mut env);
+        check_assertion_response_with_user(
+            get_assertion_response,
+            Some(PublicKeyCredentialUserEntity {
+                user_id: vec![0x1D],
+                user_name: None,
+                user_display_name: None,
+                user_icon: None,
+            }),
+            0x00,
+            signature_counter,
+            None,
+            &[],
+        );
+    }
+
+    #[test]
+    fn test_process_get_assertion_always_require_up() {
+        let mut env = TestEnv::new();
+        env.customization_mut().set_always_require_up(true);
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let make_credential_params = create_minimal_make_credential_parameters();
+        assert!(ctap_state
+            .process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL)
+            .is_ok());
+
+        // The touch is required even though the request asks for silence.
+        env.user_presence().set(|| Err(UserPresenceError::Declined));
+        let get_assertion_response = ctap_state.process_get_assertion(
+            &mut env,
+            create_silent_get_assertion_parameters(),
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED)
+        );
+
+        // With a touch, the UP flag is set in the authenticator data.
+        env.user_presence().set(|| Ok(()));
+        let get_assertion_response = ctap_state.process_get_assertion(
+            &mut env,
+            create_silent_get_assertion_parameters(),
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        let signature_counter = global_signature_counter(&mut env);
+        check_assertion_response_with_user(
+            get_assertion_response,
+            Some(PublicKeyCredentialUserEntity {
+                user_id: vec![0x1D],
+                user_name: None,
+                user_display_name: None,
+                user_icon: None,
+            }),
+            UP_FLAG,
+            signature_counter,
+            None,
+            &[],
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "ed25519")]
+    fn test_process_make_credential_and_get_assertion_eddsa() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.pub_key_cred_params = vec![EDDSA_CRED_PARAM];
+        let auth_data = match ctap_state
+            .process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL)
+            .unwrap()
+        {
+            ResponseData::AuthenticatorMakeCredential(response) => response.auth_data,
+            _ => panic!("Invalid response type"),
+        };
+        let offset = 37 + env.customization().aaguid().len();
+        let length = BigEndian::read_u16(&auth_data[offset..offset + 2]) as usize;
+        let credential_id = &auth_data[offset + 2..offset + 2 + length];
+        let (_, credential) = storage::find_credential_item(&mut env, credential_id).unwrap();
+        let public_key = match &credential.private_key {
+            PrivateKey::Ed25519(secret_key) => secret_key.public_key(),
+            _ => panic!("Invalid private key type"),
+        };
+        assert_eq!(
+            cbor_read(&auth_data[offset + 2 + length..]),
+            Ok(cbor::Value::from(CoseKey::from(public_key)))
+        );
+
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: GetAssertionExtensions::default(),
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let response = match ctap_state
+            .process_get_assertion(
+                &mut env,
+                get_assertion_params,
+                DUMMY_CHANNEL,
+                CtapInstant::new(0),
+            )
+            .unwrap()
+        {
+            ResponseData::AuthenticatorGetAssertion(response) => response,
+            _ => panic!("Invalid response type"),
+        };
+        let mut signed_data = response.auth_data;
+        signed_data.push(0xCD);
+        let signature = ed25519_compact::Signature::from_slice(&response.signature).unwrap();
+        assert!(public_key.verify(&signed_data, &signature).is_ok());
+    }
+
+    #[test]
+    fn test_process_make_credential_built_in_uv() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.options.uv = true;
+
+        env.user_verification().set_enrolled(Some(false));
+        assert_eq!(
+            ctap_state.process_make_credential(
+                &mut env,
+                make_credential_params.clone(),
+                DUMMY_CHANNEL
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION)
+        );
+
+        env.user_verification().set_enrolled(Some(true));
+        env.user_verification()
+            .set(|| Err(UserVerificationError::Invalid));
+        assert_eq!(
+            ctap_state.process_make_credential(
+                &mut env,
+                make_credential_params.clone(),
+                DUMMY_CHANNEL
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_UV_INVALID)
+        );
+
+        env.user_verification().set(|| Ok(()));
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        check_make_response(
+            &make_credential_response,
+            0x45,
+            env.customization().aaguid(),
+            0x20,
             &[],
         );
     }
 
     #[test]
-    fn test_resident_process_get_assertion() {
+    fn test_process_get_assertion_built_in_uv() {
         let mut env = TestEnv::new();
+        env.user_verification().set_enrolled(Some(true));
         let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
 
         let make_credential_params = create_minimal_make_credential_parameters();
@@ -2415,7 +3939,7 @@ mod test {
             extensions: GetAssertionExtensions::default(),
             options: GetAssertionOptions {
                 up: false,
-                uv: false,
+                uv: true,
             },
             pin_uv_auth_param: None,
             pin_uv_auth_protocol: None,
@@ -2426,8 +3950,21 @@ mod test {
             DUMMY_CHANNEL,
             CtapInstant::new(0),
         );
-        let signature_counter = storage::global_signature_counter(&mut env).unwrap();
-        check_assertion_response(get_assertion_response, vec![0x1D], signature_counter, None);
+        let signature_counter = global_signature_counter(&mut env);
+        let expected_user = PublicKeyCredentialUserEntity {
+            user_id: vec![0x1D],
+            user_name: None,
+            user_display_name: None,
+            user_icon: None,
+        };
+        check_assertion_response_with_user(
+            get_assertion_response,
+            Some(expected_user),
+            0x04,
+            signature_counter,
+            None,
+            &[],
+        );
     }
 
     fn get_assertion_hmac_secret_params(
@@ -2483,6 +4020,25 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_generate_cred_random() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let private_key = PrivateKey::new_ecdsa(&mut env);
+
+        let cred_random_uv = ctap_state
+            .generate_cred_random(&mut env, &private_key, true)
+            .unwrap();
+        let cred_random_no_uv = ctap_state
+            .generate_cred_random(&mut env, &private_key, false)
+            .unwrap();
+        assert_ne!(cred_random_uv, cred_random_no_uv);
+        assert_eq!(
+            ctap_state.generate_cred_random(&mut env, &private_key, true),
+            Ok(cred_random_uv)
+        );
+    }
+
     fn test_helper_process_get_assertion_hmac_secret(pin_uv_auth_protocol: PinUvAuthProtocol) {
         let mut env = TestEnv::new();
         let key_agreement_key = crypto::ecdh::SecKey::gensk(env.rng());
@@ -2525,77 +4081,359 @@ mod test {
         );
         let get_assertion_response = ctap_state.process_get_assertion(
             &mut env,
-            get_assertion_params,
-            DUMMY_CHANNEL,
-            CtapInstant::new(0),
+            get_assertion_params,
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        assert!(get_assertion_response.is_ok());
+    }
+
+    #[test]
+    fn test_process_get_assertion_hmac_secret_v1() {
+        test_helper_process_get_assertion_hmac_secret(PinUvAuthProtocol::V1);
+    }
+
+    #[test]
+    fn test_process_get_assertion_hmac_secret_v2() {
+        test_helper_process_get_assertion_hmac_secret(PinUvAuthProtocol::V2);
+    }
+
+    fn test_helper_resident_process_get_assertion_hmac_secret(
+        pin_uv_auth_protocol: PinUvAuthProtocol,
+    ) {
+        let mut env = TestEnv::new();
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(env.rng());
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let make_extensions = MakeCredentialExtensions {
+            hmac_secret: true,
+            ..Default::default()
+        };
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.extensions = make_extensions;
+        assert!(ctap_state
+            .process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL)
+            .is_ok());
+
+        let client_pin_params = AuthenticatorClientPinParameters {
+            pin_uv_auth_protocol,
+            sub_command: ClientPinSubCommand::GetKeyAgreement,
+            key_agreement: None,
+            pin_uv_auth_param: None,
+            new_pin_enc: None,
+            pin_hash_enc: None,
+            permissions: None,
+            permissions_rp_id: None,
+        };
+        let key_agreement_response =
+            ctap_state
+                .client_pin
+                .process_command(&mut env, client_pin_params, CtapInstant::new(0));
+        let get_assertion_params = get_assertion_hmac_secret_params(
+            key_agreement_key,
+            key_agreement_response.unwrap(),
+            None,
+            pin_uv_auth_protocol,
+        );
+        let get_assertion_response = ctap_state.process_get_assertion(
+            &mut env,
+            get_assertion_params,
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        assert!(get_assertion_response.is_ok());
+    }
+
+    #[test]
+    fn test_process_resident_get_assertion_hmac_secret_v1() {
+        test_helper_resident_process_get_assertion_hmac_secret(PinUvAuthProtocol::V1);
+    }
+
+    #[test]
+    fn test_resident_process_get_assertion_hmac_secret_v2() {
+        test_helper_resident_process_get_assertion_hmac_secret(PinUvAuthProtocol::V2);
+    }
+
+    #[test]
+    fn test_process_get_assertion_allow_list_filtering() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let mut credential_ids = vec![];
+        for _ in 0..2 {
+            let mut make_credential_params = create_minimal_make_credential_parameters();
+            make_credential_params.options.rk = false;
+            let make_credential_response =
+                ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+            credential_ids.push(
+                parse_credential_id_from_non_resident_make_credential_response(
+                    &mut env,
+                    make_credential_response.unwrap(),
+                ),
+            );
+        }
+        let mut bogus_credential_id = credential_ids[0].clone();
+        bogus_credential_id[0] ^= 0x01;
+        let to_allow_list = |key_ids: &[&Vec<u8>]| {
+            key_ids
+                .iter()
+                .map(|&key_id| PublicKeyCredentialDescriptor {
+                    key_type: PublicKeyCredentialType::PublicKey,
+                    key_id: key_id.clone(),
+                    transports: None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let get_assertion_params = |allow_list| AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: Some(allow_list),
+            extensions: GetAssertionExtensions::default(),
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+
+        // One valid and one bogus entry return exactly one assertion.
+        let allow_list = to_allow_list(&[&bogus_credential_id, &credential_ids[1]]);
+        let get_assertion_response = ctap_state.process_get_assertion(
+            &mut env,
+            get_assertion_params(allow_list),
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        match get_assertion_response.unwrap() {
+            ResponseData::AuthenticatorGetAssertion(response) => {
+                assert_eq!(response.credential.unwrap().key_id, credential_ids[1]);
+                assert_eq!(response.number_of_credentials, None);
+            }
+            _ => panic!("Invalid response type"),
+        }
+
+        // Multiple valid entries also return one assertion, using the first.
+        let allow_list = to_allow_list(&[&credential_ids[0], &credential_ids[1]]);
+        let get_assertion_response = ctap_state.process_get_assertion(
+            &mut env,
+            get_assertion_params(allow_list),
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        match get_assertion_response.unwrap() {
+            ResponseData::AuthenticatorGetAssertion(response) => {
+                assert_eq!(response.credential.unwrap().key_id, credential_ids[0]);
+                assert_eq!(response.number_of_credentials, None);
+            }
+            _ => panic!("Invalid response type"),
+        }
+        assert_eq!(
+            ctap_state.process_get_next_assertion(&mut env, CtapInstant::new(0)),
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+
+        // Only bogus entries don't match any credential.
+        let short_credential_id = vec![0x01; 16];
+        let allow_list = to_allow_list(&[&bogus_credential_id, &short_credential_id]);
+        let get_assertion_response = ctap_state.process_get_assertion(
+            &mut env,
+            get_assertion_params(allow_list),
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)
+        );
+    }
+
+    #[test]
+    fn test_process_get_assertion_empty_allow_list() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        for user_id in 0..2 {
+            let mut make_credential_params = create_minimal_make_credential_parameters();
+            make_credential_params.user.user_id = vec![user_id];
+            assert!(ctap_state
+                .process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL)
+                .is_ok());
+        }
+
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: Some(vec![]),
+            extensions: GetAssertionExtensions::default(),
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let get_assertion_response = ctap_state.process_get_assertion(
+            &mut env,
+            get_assertion_params,
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        let signature_counter = global_signature_counter(&mut env);
+        check_assertion_response(
+            get_assertion_response,
+            vec![0x01],
+            signature_counter,
+            Some(2),
+        );
+    }
+
+    /// Returns the credential ID of the assertion for the given allow list.
+    fn get_assertion_key_id(
+        env: &mut TestEnv,
+        ctap_state: &mut CtapState,
+        allow_list: Option<Vec<PublicKeyCredentialDescriptor>>,
+    ) -> Result<Vec<u8>, Ctap2StatusCode> {
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            allow_list,
+            ..create_silent_get_assertion_parameters()
+        };
+        match ctap_state.process_get_assertion(
+            env,
+            get_assertion_params,
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        )? {
+            ResponseData::AuthenticatorGetAssertion(response) => {
+                Ok(response.credential.unwrap().key_id)
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    fn test_non_resident_process_get_assertion_discovery() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.options.rk = false;
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        let credential_id = parse_credential_id_from_non_resident_make_credential_response(
+            &mut env,
+            make_credential_response.unwrap(),
         );
-        assert!(get_assertion_response.is_ok());
-    }
+        assert_eq!(storage::count_credentials(&mut env), Ok(0));
 
-    #[test]
-    fn test_process_get_assertion_hmac_secret_v1() {
-        test_helper_process_get_assertion_hmac_secret(PinUvAuthProtocol::V1);
-    }
+        // Without an allow list, the credential is not discovered.
+        for allow_list in [None, Some(vec![])] {
+            assert_eq!(
+                get_assertion_key_id(&mut env, &mut ctap_state, allow_list),
+                Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)
+            );
+        }
 
-    #[test]
-    fn test_process_get_assertion_hmac_secret_v2() {
-        test_helper_process_get_assertion_hmac_secret(PinUvAuthProtocol::V2);
+        let allow_list = vec![PublicKeyCredentialDescriptor {
+            key_type: PublicKeyCredentialType::PublicKey,
+            key_id: credential_id.clone(),
+            transports: None,
+        }];
+        assert_eq!(
+            get_assertion_key_id(&mut env, &mut ctap_state, Some(allow_list)),
+            Ok(credential_id)
+        );
     }
 
-    fn test_helper_resident_process_get_assertion_hmac_secret(
-        pin_uv_auth_protocol: PinUvAuthProtocol,
-    ) {
+    #[test]
+    fn test_resident_process_get_assertion_discovery() {
         let mut env = TestEnv::new();
-        let key_agreement_key = crypto::ecdh::SecKey::gensk(env.rng());
         let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
 
-        let make_extensions = MakeCredentialExtensions {
-            hmac_secret: true,
-            ..Default::default()
-        };
-        let mut make_credential_params = create_minimal_make_credential_parameters();
-        make_credential_params.extensions = make_extensions;
-        assert!(ctap_state
+        let make_credential_params = create_minimal_make_credential_parameters();
+        let auth_data = match ctap_state
             .process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL)
-            .is_ok());
-
-        let client_pin_params = AuthenticatorClientPinParameters {
-            pin_uv_auth_protocol,
-            sub_command: ClientPinSubCommand::GetKeyAgreement,
-            key_agreement: None,
-            pin_uv_auth_param: None,
-            new_pin_enc: None,
-            pin_hash_enc: None,
-            permissions: None,
-            permissions_rp_id: None,
+            .unwrap()
+        {
+            ResponseData::AuthenticatorMakeCredential(response) => response.auth_data,
+            _ => panic!("Invalid response type"),
         };
-        let key_agreement_response =
-            ctap_state
-                .client_pin
-                .process_command(&mut env, client_pin_params, CtapInstant::new(0));
-        let get_assertion_params = get_assertion_hmac_secret_params(
-            key_agreement_key,
-            key_agreement_response.unwrap(),
-            None,
-            pin_uv_auth_protocol,
-        );
-        let get_assertion_response = ctap_state.process_get_assertion(
-            &mut env,
-            get_assertion_params,
-            DUMMY_CHANNEL,
-            CtapInstant::new(0),
+        let offset = 37 + env.customization().aaguid().len();
+        let length = BigEndian::read_u16(&auth_data[offset..offset + 2]) as usize;
+        let credential_id = auth_data[offset + 2..offset + 2 + length].to_vec();
+        assert_eq!(storage::count_credentials(&mut env), Ok(1));
+
+        for allow_list in [None, Some(vec![])] {
+            assert_eq!(
+                get_assertion_key_id(&mut env, &mut ctap_state, allow_list),
+                Ok(credential_id.clone())
+            );
+        }
+
+        let allow_list = vec![PublicKeyCredentialDescriptor {
+            key_type: PublicKeyCredentialType::PublicKey,
+            key_id: credential_id.clone(),
+            transports: None,
+        }];
+        assert_eq!(
+            get_assertion_key_id(&mut env, &mut ctap_state, Some(allow_list)),
+            Ok(credential_id)
         );
-        assert!(get_assertion_response.is_ok());
     }
 
     #[test]
-    fn test_process_resident_get_assertion_hmac_secret_v1() {
-        test_helper_resident_process_get_assertion_hmac_secret(PinUvAuthProtocol::V1);
-    }
+    fn test_process_get_assertion_allow_list_limit() {
+        let mut env = TestEnv::new();
+        env.customization_mut()
+            .set_max_credential_count_in_list(Some(2));
+        let private_key = PrivateKey::new_ecdsa(&mut env);
+        let credential_id = env.rng().gen_uniform_u8x32().to_vec();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
 
-    #[test]
-    fn test_resident_process_get_assertion_hmac_secret_v2() {
-        test_helper_resident_process_get_assertion_hmac_secret(PinUvAuthProtocol::V2);
+        let credential = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id: credential_id.clone(),
+            private_key,
+            rp_id: String::from("example.com"),
+            user_handle: vec![0x1D],
+            user_display_name: None,
+            cred_protect_policy: None,
+            creation_order: 0,
+            user_name: None,
+            user_icon: None,
+            cred_blob: None,
+            large_blob_key: None,
+        };
+        assert!(storage::store_credential(&mut env, credential).is_ok());
+        let signature_counter = global_signature_counter(&mut env);
+
+        let mut command_cbor = vec![0x02];
+        let cbor_value = cbor_map! {
+            1 => "example.com",
+            2 => vec![0xCD],
+            3 => cbor_array![
+                cbor_map! { "id" => credential_id, "type" => "public-key" },
+                cbor_map! { "id" => vec![0x01], "type" => "public-key" },
+                cbor_map! { "id" => vec![0x02], "type" => "public-key" },
+            ],
+            5 => cbor_map! { "up" => false },
+        };
+        assert!(cbor_write(cbor_value, &mut command_cbor).is_ok());
+        let response =
+            ctap_state.process_command(&mut env, &command_cbor, DUMMY_CHANNEL, CtapInstant::new(0));
+        assert_eq!(
+            response,
+            vec![Ctap2StatusCode::CTAP2_ERR_LIMIT_EXCEEDED as u8]
+        );
+        // No credential was used.
+        assert_eq!(global_signature_counter(&mut env), signature_counter);
+
+        env.customization_mut()
+            .set_max_credential_count_in_list(Some(3));
+        let response =
+            ctap_state.process_command(&mut env, &command_cbor, DUMMY_CHANNEL, CtapInstant::new(0));
+        assert_eq!(response[0], Ctap2StatusCode::CTAP2_OK as u8);
+        assert!(global_signature_counter(&mut env) > signature_counter);
     }
 
     #[test]
@@ -2669,7 +4507,7 @@ mod test {
             DUMMY_CHANNEL,
             CtapInstant::new(0),
         );
-        let signature_counter = storage::global_signature_counter(&mut env).unwrap();
+        let signature_counter = global_signature_counter(&mut env);
         check_assertion_response(get_assertion_response, vec![0x1D], signature_counter, None);
 
         let credential = PublicKeyCredentialSource {
@@ -2712,6 +4550,53 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_resident_process_get_assertion_uv_required_not_discoverable() {
+        let mut env = TestEnv::new();
+        let private_key = PrivateKey::new_ecdsa(&mut env);
+        let credential_id = env.rng().gen_uniform_u8x32().to_vec();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let credential = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id,
+            private_key,
+            rp_id: String::from("example.com"),
+            user_handle: vec![0x1D],
+            user_display_name: None,
+            cred_protect_policy: Some(CredentialProtectionPolicy::UserVerificationRequired),
+            creation_order: 0,
+            user_name: None,
+            user_icon: None,
+            cred_blob: None,
+            large_blob_key: None,
+        };
+        assert!(storage::store_credential(&mut env, credential).is_ok());
+
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: GetAssertionExtensions::default(),
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let get_assertion_response = ctap_state.process_get_assertion(
+            &mut env,
+            get_assertion_params,
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+        );
+    }
+
     #[test]
     fn test_non_resident_process_get_assertion_with_cred_protect() {
         let mut env = TestEnv::new();
@@ -2838,7 +4723,7 @@ mod test {
             DUMMY_CHANNEL,
             CtapInstant::new(0),
         );
-        let signature_counter = storage::global_signature_counter(&mut env).unwrap();
+        let signature_counter = global_signature_counter(&mut env);
         let expected_extension_cbor = [
             0xA1, 0x68, 0x63, 0x72, 0x65, 0x64, 0x42, 0x6C, 0x6F, 0x62, 0x41, 0xCB,
         ];
@@ -2871,7 +4756,7 @@ mod test {
         check_make_response(
             &make_credential_response,
             0xC1,
-            &storage::aaguid(&mut env).unwrap(),
+            env.customization().aaguid(),
             CBOR_CREDENTIAL_ID_SIZE as u8,
             &expected_extension_cbor,
         );
@@ -2907,7 +4792,7 @@ mod test {
             DUMMY_CHANNEL,
             CtapInstant::new(0),
         );
-        let signature_counter = storage::global_signature_counter(&mut env).unwrap();
+        let signature_counter = global_signature_counter(&mut env);
         let expected_extension_cbor = [
             0xA1, 0x68, 0x63, 0x72, 0x65, 0x64, 0x42, 0x6C, 0x6F, 0x62, 0x41, 0xCB,
         ];
@@ -3036,7 +4921,7 @@ mod test {
             DUMMY_CHANNEL,
             CtapInstant::new(0),
         );
-        let signature_counter = storage::global_signature_counter(&mut env).unwrap();
+        let signature_counter = global_signature_counter(&mut env);
         check_assertion_response_with_user(
             get_assertion_response,
             Some(user2),
@@ -3046,7 +4931,9 @@ mod test {
             &[],
         );
 
-        let get_assertion_response = ctap_state.process_get_next_assertion(&mut env);
+        let get_assertion_response =
+            ctap_state.process_get_next_assertion(&mut env, CtapInstant::new(0));
+        let signature_counter = global_signature_counter(&mut env);
         check_assertion_response_with_user(
             get_assertion_response,
             Some(user1),
@@ -3056,7 +4943,8 @@ mod test {
             &[],
         );
 
-        let get_assertion_response = ctap_state.process_get_next_assertion(&mut env);
+        let get_assertion_response =
+            ctap_state.process_get_next_assertion(&mut env, CtapInstant::new(0));
         assert_eq!(
             get_assertion_response,
             Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
@@ -3121,7 +5009,7 @@ mod test {
             DUMMY_CHANNEL,
             CtapInstant::new(0),
         );
-        let signature_counter = storage::global_signature_counter(&mut env).unwrap();
+        let signature_counter = global_signature_counter(&mut env);
         check_assertion_response(
             get_assertion_response,
             vec![0x03],
@@ -3129,13 +5017,18 @@ mod test {
             Some(3),
         );
 
-        let get_assertion_response = ctap_state.process_get_next_assertion(&mut env);
+        let get_assertion_response =
+            ctap_state.process_get_next_assertion(&mut env, CtapInstant::new(0));
+        let signature_counter = global_signature_counter(&mut env);
         check_assertion_response(get_assertion_response, vec![0x02], signature_counter, None);
 
-        let get_assertion_response = ctap_state.process_get_next_assertion(&mut env);
+        let get_assertion_response =
+            ctap_state.process_get_next_assertion(&mut env, CtapInstant::new(0));
+        let signature_counter = global_signature_counter(&mut env);
         check_assertion_response(get_assertion_response, vec![0x01], signature_counter, None);
 
-        let get_assertion_response = ctap_state.process_get_next_assertion(&mut env);
+        let get_assertion_response =
+            ctap_state.process_get_next_assertion(&mut env, CtapInstant::new(0));
         assert_eq!(
             get_assertion_response,
             Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
@@ -3147,7 +5040,8 @@ mod test {
         let mut env = TestEnv::new();
         let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
 
-        let get_assertion_response = ctap_state.process_get_next_assertion(&mut env);
+        let get_assertion_response =
+            ctap_state.process_get_next_assertion(&mut env, CtapInstant::new(0));
         assert_eq!(
             get_assertion_response,
             Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
@@ -3199,13 +5093,59 @@ mod test {
         assert!(cbor_write(cbor_value, &mut command_cbor).is_ok());
         ctap_state.process_command(&mut env, &command_cbor, DUMMY_CHANNEL, CtapInstant::new(0));
 
-        let get_assertion_response = ctap_state.process_get_next_assertion(&mut env);
+        let get_assertion_response =
+            ctap_state.process_get_next_assertion(&mut env, CtapInstant::new(0));
         assert_eq!(
             get_assertion_response,
             Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
         );
     }
 
+    #[test]
+    #[cfg(all(feature = "log", feature = "debug_ctap"))]
+    fn test_process_command_logs_make_credential() {
+        use std::sync::Mutex;
+
+        struct CaptureLogger(Mutex<Vec<String>>);
+
+        impl log::Log for CaptureLogger {
+            fn enabled(&self, metadata: &log::Metadata) -> bool {
+                metadata.level() <= log::Level::Debug
+            }
+
+            fn log(&self, record: &log::Record) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+
+            fn flush(&self) {}
+        }
+
+        let logger: &'static CaptureLogger = Box::leak(Box::new(CaptureLogger(Mutex::new(vec![]))));
+        assert!(log::set_logger(logger).is_ok());
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let mut command_cbor = vec![0x01];
+        let cbor_value = cbor_map! {
+            1 => vec![0xCD; 16],
+            2 => cbor_map! {
+                "id" => "example.com",
+            },
+            3 => cbor_map! {
+                "id" => vec![0x1D, 0x1D, 0x1D, 0x1D],
+            },
+            4 => cbor_array![ES256_CRED_PARAM],
+        };
+        assert!(cbor_write(cbor_value, &mut command_cbor).is_ok());
+        ctap_state.process_command(&mut env, &command_cbor, DUMMY_CHANNEL, CtapInstant::new(0));
+
+        let lines = logger.0.lock().unwrap();
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("Received command") && line.contains("MakeCredential")));
+    }
+
     #[test]
     fn test_process_reset() {
         let mut env = TestEnv::new();
@@ -3237,6 +5177,28 @@ mod test {
         assert!(storage::count_credentials(&mut env).unwrap() == 0);
     }
 
+    #[test]
+    fn test_process_reset_clears_pin() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        storage::set_pin(&mut env, &[0x88; 16], 4).unwrap();
+
+        let reset_reponse = ctap_state.process_reset(&mut env, DUMMY_CHANNEL);
+        assert_eq!(reset_reponse, Ok(ResponseData::AuthenticatorReset));
+        assert_eq!(storage::pin_hash(&mut env), Ok(None));
+
+        let info_response = ctap_state.process_get_info(&mut env).unwrap();
+        match info_response {
+            ResponseData::AuthenticatorGetInfo(response) => {
+                assert!(response
+                    .options
+                    .unwrap()
+                    .contains(&(String::from("clientPin"), false)));
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
     #[test]
     fn test_process_reset_cancelled() {
         let mut env = TestEnv::new();
@@ -3264,6 +5226,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "cred_management")]
     fn test_process_credential_management_unknown_subcommand() {
         let mut env = TestEnv::new();
         let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
@@ -3275,20 +5238,117 @@ mod test {
             DUMMY_CHANNEL,
             CtapInstant::new(0),
         );
-        let expected_response = vec![Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND as u8];
-        assert_eq!(reponse, expected_response);
+        let expected_response = vec![Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND as u8];
+        assert_eq!(reponse, expected_response);
+    }
+
+    #[test]
+    fn test_process_unknown_command() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        // This command does not exist.
+        let reponse =
+            ctap_state.process_command(&mut env, &[0xDF], DUMMY_CHANNEL, CtapInstant::new(0));
+        let expected_response = vec![Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND as u8];
+        assert_eq!(reponse, expected_response);
+    }
+
+    #[test]
+    #[cfg(not(feature = "cred_management"))]
+    fn test_process_credential_management_disabled() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        // This is a getCredsMetadata subcommand.
+        let reponse = ctap_state.process_command(
+            &mut env,
+            &[0x0A, 0xA1, 0x01, 0x01],
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        let expected_response = vec![Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND as u8];
+        assert_eq!(reponse, expected_response);
+        match ctap_state.process_get_info(&mut env).unwrap() {
+            ResponseData::AuthenticatorGetInfo(response) => {
+                let options = response.options.unwrap();
+                assert!(!options.iter().any(|(name, _)| name == "credMgmt"));
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "large_blobs"))]
+    fn test_process_large_blobs_disabled() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let large_blobs_params = AuthenticatorLargeBlobsParameters {
+            get: Some(1),
+            set: None,
+            offset: 0,
+            length: None,
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let response = ctap_state.process_parsed_command(
+            &mut env,
+            Command::AuthenticatorLargeBlobs(large_blobs_params),
+            DUMMY_CHANNEL,
+            CtapInstant::new(0),
+        );
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND));
+        match ctap_state.process_get_info(&mut env).unwrap() {
+            ResponseData::AuthenticatorGetInfo(response) => {
+                let options = response.options.unwrap();
+                assert!(!options.iter().any(|(name, _)| name == "largeBlobs"));
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "enterprise_attestation"))]
+    fn test_process_make_credential_enterprise_attestation_disabled_without_mode() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.enterprise_attestation = Some(2);
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION)
+        );
     }
 
     #[test]
-    fn test_process_unknown_command() {
+    #[cfg(not(feature = "enterprise_attestation"))]
+    fn test_process_make_credential_enterprise_attestation_disabled() {
         let mut env = TestEnv::new();
+        env.customization_mut()
+            .setup_enterprise_attestation(Some(EnterpriseAttestationMode::PlatformManaged), None);
         let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
 
-        // This command does not exist.
-        let reponse =
-            ctap_state.process_command(&mut env, &[0xDF], DUMMY_CHANNEL, CtapInstant::new(0));
-        let expected_response = vec![Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND as u8];
-        assert_eq!(reponse, expected_response);
+        for enterprise_attestation in 1..=2 {
+            let mut make_credential_params = create_minimal_make_credential_parameters();
+            make_credential_params.enterprise_attestation = Some(enterprise_attestation);
+            let make_credential_response =
+                ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+            assert_eq!(
+                make_credential_response,
+                Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION)
+            );
+        }
+        match ctap_state.process_get_info(&mut env).unwrap() {
+            ResponseData::AuthenticatorGetInfo(response) => {
+                let options = response.options.unwrap();
+                assert!(!options.iter().any(|(name, _)| name == "ep"));
+            }
+            _ => panic!("Invalid response type"),
+        }
     }
 
     #[test]
@@ -3296,18 +5356,72 @@ mod test {
         let mut env = TestEnv::new();
         let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
 
-        let mut last_counter = storage::global_signature_counter(&mut env).unwrap();
+        let mut last_counter = global_signature_counter(&mut env);
         assert!(last_counter > 0);
         for _ in 0..100 {
             assert!(ctap_state
-                .increment_global_signature_counter(&mut env)
+                .increment_signature_counter(&mut env, &[0x01])
                 .is_ok());
-            let next_counter = storage::global_signature_counter(&mut env).unwrap();
+            let next_counter = global_signature_counter(&mut env);
             assert!(next_counter > last_counter);
             last_counter = next_counter;
         }
     }
 
+    #[test]
+    fn test_get_assertion_with_per_credential_counters() {
+        use crate::api::signature_counter::SignatureCounterStrategy;
+
+        let mut env = TestEnv::new();
+        env.customization_mut()
+            .set_signature_counter_strategy(SignatureCounterStrategy::PerCredential);
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.options.rk = false;
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        let non_resident_id = parse_credential_id_from_non_resident_make_credential_response(
+            &mut env,
+            make_credential_response.unwrap(),
+        );
+        assert_eq!(
+            env.signature_counter()
+                .counter_storage_key(&non_resident_id),
+            Ok(None)
+        );
+
+        let make_credential_params = create_minimal_make_credential_parameters();
+        assert!(ctap_state
+            .process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL)
+            .is_ok());
+        let resident_id = get_assertion_key_id(&mut env, &mut ctap_state, None).unwrap();
+        assert_eq!(
+            env.signature_counter().get_counter(&resident_id),
+            Ok(INITIAL_SIGNATURE_COUNTER + 1)
+        );
+
+        // The non-resident credential falls back to the global counter.
+        let allow_list = vec![PublicKeyCredentialDescriptor {
+            key_type: PublicKeyCredentialType::PublicKey,
+            key_id: non_resident_id.clone(),
+            transports: None,
+        }];
+        assert_eq!(
+            get_assertion_key_id(&mut env, &mut ctap_state, Some(allow_list)),
+            Ok(non_resident_id.clone())
+        );
+        assert!(global_signature_counter(&mut env) > INITIAL_SIGNATURE_COUNTER);
+        assert_eq!(
+            env.signature_counter().get_counter(&non_resident_id),
+            Ok(global_signature_counter(&mut env))
+        );
+        assert_eq!(
+            env.signature_counter().get_counter(&resident_id),
+            Ok(INITIAL_SIGNATURE_COUNTER + 1)
+        );
+    }
+
     #[test]
     fn test_vendor_configure() {
         let mut env = TestEnv::new();
@@ -3342,6 +5456,7 @@ mod test {
                 attestation_material: Some(AuthenticatorAttestationMaterial {
                     certificate: dummy_cert.to_vec(),
                     private_key: dummy_key,
+                    intermediate_certificates: vec![],
                 }),
             },
             DUMMY_CHANNEL,
@@ -3360,6 +5475,7 @@ mod test {
             Ok(Some(Attestation {
                 private_key: dummy_key,
                 certificate: dummy_cert.to_vec(),
+                intermediate_certificates: vec![],
             }))
         );
 
@@ -3372,6 +5488,7 @@ mod test {
                 attestation_material: Some(AuthenticatorAttestationMaterial {
                     certificate: dummy_cert.to_vec(),
                     private_key: other_dummy_key,
+                    intermediate_certificates: vec![],
                 }),
             },
             DUMMY_CHANNEL,
@@ -3390,6 +5507,7 @@ mod test {
             Ok(Some(Attestation {
                 private_key: dummy_key,
                 certificate: dummy_cert.to_vec(),
+                intermediate_certificates: vec![],
             }))
         );
 
@@ -3413,6 +5531,140 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_vendor_configure_with_certificate_chain() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let attestation_material = AuthenticatorAttestationMaterial {
+            certificate: vec![0xdd; 20],
+            private_key: [0x41; key_material::ATTESTATION_PRIVATE_KEY_LENGTH],
+            intermediate_certificates: vec![vec![0x11; 20], vec![0x22; 20]],
+        };
+        let response = ctap_state.process_vendor_configure(
+            &mut env,
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: Some(attestation_material.clone()),
+            },
+            DUMMY_CHANNEL,
+        );
+        assert!(response.is_ok());
+        assert_eq!(
+            env.attestation_store().get(&attestation_store::Id::Batch),
+            Ok(Some(Attestation {
+                private_key: attestation_material.private_key,
+                certificate: attestation_material.certificate,
+                intermediate_certificates: attestation_material.intermediate_certificates,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_vendor_configure_locked_attestation() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let attestation_material = AuthenticatorAttestationMaterial {
+            certificate: vec![0xdd; 20],
+            private_key: [0x41; key_material::ATTESTATION_PRIVATE_KEY_LENGTH],
+            intermediate_certificates: vec![],
+        };
+
+        // Provisioning needs user presence.
+        env.user_presence().set(|| Err(UserPresenceError::Declined));
+        let response = ctap_state.process_vendor_configure(
+            &mut env,
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: Some(attestation_material.clone()),
+            },
+            DUMMY_CHANNEL,
+        );
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED));
+        assert_eq!(
+            env.attestation_store().get(&attestation_store::Id::Batch),
+            Ok(None)
+        );
+        env.user_presence().set(|| Ok(()));
+
+        // Provision and lock in a single command.
+        let response = ctap_state.process_vendor_configure(
+            &mut env,
+            AuthenticatorVendorConfigureParameters {
+                lockdown: true,
+                attestation_material: Some(attestation_material.clone()),
+            },
+            DUMMY_CHANNEL,
+        );
+        assert!(response.is_ok());
+        assert_eq!(storage::attestation_locked(&mut env), Ok(true));
+
+        // The lock survives a reboot and a reset.
+        env.power_cycle();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        assert_eq!(storage::reset(&mut env), Ok(()));
+        assert_eq!(storage::attestation_locked(&mut env), Ok(true));
+
+        // Overwriting the attestation fails, even with user presence.
+        let response = ctap_state.process_vendor_configure(
+            &mut env,
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: Some(AuthenticatorAttestationMaterial {
+                    certificate: vec![0xee; 20],
+                    private_key: [0x44; key_material::ATTESTATION_PRIVATE_KEY_LENGTH],
+                    intermediate_certificates: vec![],
+                }),
+            },
+            DUMMY_CHANNEL,
+        );
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED));
+
+        // Querying the state is still possible.
+        let response = ctap_state.process_vendor_configure(
+            &mut env,
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: None,
+            },
+            DUMMY_CHANNEL,
+        );
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorVendorConfigure(
+                AuthenticatorVendorConfigureResponse {
+                    cert_programmed: true,
+                    pkey_programmed: true,
+                }
+            ))
+        );
+
+        // The provisioned attestation is still used.
+        env.customization_mut().set_use_batch_attestation(true);
+        let make_credential_params = create_minimal_make_credential_parameters();
+        let client_data_hash = make_credential_params.client_data_hash.clone();
+        let make_credential_response =
+            ctap_state.process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL);
+        match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                let mut signature_data = make_credential_response.auth_data;
+                signature_data.extend(client_data_hash);
+                let attestation_key =
+                    ecdsa::SecKey::from_bytes(&attestation_material.private_key).unwrap();
+                assert_eq!(
+                    make_credential_response.att_stmt.sig,
+                    attestation_key
+                        .sign_rfc6979::<Sha256>(&signature_data)
+                        .to_asn1_der()
+                );
+                assert_eq!(
+                    make_credential_response.att_stmt.x5c,
+                    Some(vec![attestation_material.certificate])
+                );
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
     #[test]
     fn test_vendor_upgrade() {
         // The test partition storage has size 0x40000.
@@ -3434,6 +5686,8 @@ mod test {
                 offset: 0x20000,
                 data: data.clone(),
                 hash,
+                bundle_length: None,
+                bundle_hash: None,
             },
         );
         assert_eq!(response, Ok(ResponseData::AuthenticatorVendorUpgrade));
@@ -3445,6 +5699,8 @@ mod test {
                 offset: 0,
                 data: metadata.clone(),
                 hash: metadata_hash,
+                bundle_length: None,
+                bundle_hash: None,
             },
         );
         assert_eq!(response, Ok(ResponseData::AuthenticatorVendorUpgrade));
@@ -3456,6 +5712,8 @@ mod test {
                 offset: METADATA_LEN,
                 data: data.clone(),
                 hash,
+                bundle_length: None,
+                bundle_hash: None,
             },
         );
         assert_eq!(response, Ok(ResponseData::AuthenticatorVendorUpgrade));
@@ -3467,6 +5725,8 @@ mod test {
                 offset: 0,
                 data: metadata[..METADATA_LEN - 1].to_vec(),
                 hash: metadata_hash,
+                bundle_length: None,
+                bundle_hash: None,
             },
         );
         assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_INTEGRITY_FAILURE));
@@ -3478,6 +5738,8 @@ mod test {
                 offset: 0x41000,
                 data: data.clone(),
                 hash,
+                bundle_length: None,
+                bundle_hash: None,
             },
         );
         assert_eq!(response, Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER));
@@ -3489,11 +5751,102 @@ mod test {
                 offset: 0x20000,
                 data,
                 hash: [0xEE; 32],
+                bundle_length: None,
+                bundle_hash: None,
             },
         );
         assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_INTEGRITY_FAILURE));
     }
 
+    #[test]
+    fn test_vendor_upgrade_low_battery() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let bundle = vec![0xFF; 0x41000];
+        let params = || AuthenticatorVendorUpgradeParameters {
+            offset: 0,
+            data: bundle[..0x1000].to_vec(),
+            hash: Sha256::hash(&bundle[..0x1000]),
+            bundle_length: Some(bundle.len()),
+            bundle_hash: Some(Sha256::hash(&bundle)),
+        };
+
+        env.power().set(Some(PowerStatus {
+            external_power: false,
+            charge_percent: Some(LOW_BATTERY_PERCENT - 1),
+        }));
+        let response = ctap_state.process_vendor_upgrade(&mut env, params());
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED));
+        assert!(env.upgrade_storage().unwrap().chunked_write().is_none());
+
+        // Plugging the device in allows the upgrade again.
+        env.power().set(Some(PowerStatus {
+            external_power: true,
+            charge_percent: Some(LOW_BATTERY_PERCENT - 1),
+        }));
+        let response = ctap_state.process_vendor_upgrade(&mut env, params());
+        assert_eq!(response, Ok(ResponseData::AuthenticatorVendorUpgrade));
+        assert_eq!(env.upgrade_storage().unwrap().written_so_far(), 0x1000);
+    }
+
+    #[test]
+    fn test_vendor_upgrade_chunked() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        const METADATA_LEN: usize = 0x1000;
+        const BUNDLE_LEN: usize = 0x41000;
+        let bundle = vec![0xFF; BUNDLE_LEN];
+        let chunk_params = |offset: usize, length: usize| AuthenticatorVendorUpgradeParameters {
+            offset,
+            data: bundle[offset..][..length].to_vec(),
+            hash: Sha256::hash(&bundle[offset..][..length]),
+            bundle_length: None,
+            bundle_hash: None,
+        };
+
+        // The first chunk declares the bundle.
+        let response = ctap_state.process_vendor_upgrade(
+            &mut env,
+            AuthenticatorVendorUpgradeParameters {
+                bundle_length: Some(BUNDLE_LEN),
+                bundle_hash: Some(Sha256::hash(&bundle)),
+                ..chunk_params(0, METADATA_LEN)
+            },
+        );
+        assert_eq!(response, Ok(ResponseData::AuthenticatorVendorUpgrade));
+
+        // Chunks that leave a gap are refused.
+        let response = ctap_state.process_vendor_upgrade(&mut env, chunk_params(0x20000, 0x1000));
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER));
+        let response =
+            ctap_state.process_vendor_upgrade(&mut env, chunk_params(METADATA_LEN, 0x1000));
+        assert_eq!(response, Ok(ResponseData::AuthenticatorVendorUpgrade));
+
+        // The host can resume from the written length.
+        let bundle_identifier = env.upgrade_storage().unwrap().bundle_identifier();
+        assert_eq!(
+            ctap_state.process_vendor_upgrade_info(&mut env),
+            Ok(ResponseData::AuthenticatorVendorUpgradeInfo(
+                AuthenticatorVendorUpgradeInfoResponse {
+                    info: bundle_identifier,
+                    written_so_far: METADATA_LEN + 0x1000,
+                }
+            ))
+        );
+
+        // A wrong declared length is refused.
+        let response = ctap_state.process_vendor_upgrade(
+            &mut env,
+            AuthenticatorVendorUpgradeParameters {
+                bundle_length: Some(BUNDLE_LEN - 1),
+                bundle_hash: Some(Sha256::hash(&bundle)),
+                ..chunk_params(0, METADATA_LEN)
+            },
+        );
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER));
+    }
+
     #[test]
     fn test_vendor_upgrade_no_second_partition() {
         let mut env = TestEnv::new();
@@ -3508,6 +5861,8 @@ mod test {
                 offset: 0,
                 data,
                 hash,
+                bundle_length: None,
+                bundle_hash: None,
             },
         );
         assert_eq!(response, Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND));
@@ -3525,18 +5880,110 @@ mod test {
             Ok(ResponseData::AuthenticatorVendorUpgradeInfo(
                 AuthenticatorVendorUpgradeInfoResponse {
                     info: bundle_identifier,
+                    written_so_far: 0,
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_vendor_credential_dump_disabled() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let make_credential_params = create_minimal_make_credential_parameters();
+        assert!(ctap_state
+            .process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL)
+            .is_ok());
+
+        let response = ctap_state.process_vendor_credential_dump(&mut env, DUMMY_CHANNEL);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND));
+    }
+
+    #[test]
+    fn test_vendor_credential_dump() {
+        let mut env = TestEnv::new();
+        env.customization_mut().set_allows_credential_dump(true);
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let response = ctap_state.process_vendor_credential_dump(&mut env, DUMMY_CHANNEL);
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorVendorCredentialDump(
+                AuthenticatorVendorCredentialDumpResponse {
+                    credentials: vec![],
                 }
             ))
         );
+
+        for rp_id in &["first.com", "second.com", "third.com"] {
+            let mut make_credential_params = create_minimal_make_credential_parameters();
+            make_credential_params.rp.rp_id = String::from(*rp_id);
+            assert!(ctap_state
+                .process_make_credential(&mut env, make_credential_params, DUMMY_CHANNEL)
+                .is_ok());
+        }
+        let signature_counter = env.signature_counter().get_counter(&[]).unwrap();
+
+        let response = ctap_state
+            .process_vendor_credential_dump(&mut env, DUMMY_CHANNEL)
+            .unwrap();
+        let credentials = match &response {
+            ResponseData::AuthenticatorVendorCredentialDump(response) => &response.credentials,
+            _ => panic!("Invalid response type"),
+        };
+        let rp_ids = credentials
+            .iter()
+            .map(|credential| credential.rp_id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(rp_ids, vec!["first.com", "second.com", "third.com"]);
+        assert!(credentials
+            .windows(2)
+            .all(|pair| pair[0].creation_order < pair[1].creation_order));
+        assert!(credentials
+            .iter()
+            .all(|credential| credential.signature_counter == signature_counter));
+
+        // Only metadata is serialized, without keys or user data.
+        let expected_cbor = cbor_map! {
+            0x01 => 3,
+            0x02 => cbor_array_vec!(credentials_cbor(credentials)),
+        };
+        let response_cbor: Option<cbor::Value> = response.into();
+        assert_eq!(response_cbor, Some(expected_cbor));
+    }
+
+    fn credentials_cbor(credentials: &[CredentialMetadata]) -> Vec<cbor::Value> {
+        credentials
+            .iter()
+            .map(|credential| {
+                cbor_map! {
+                    0x01 => credential.rp_id.as_str(),
+                    0x02 => credential.creation_order,
+                    0x03 => credential.signature_counter as u64,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_vendor_credential_dump_declined() {
+        let mut env = TestEnv::new();
+        env.customization_mut().set_allows_credential_dump(true);
+        env.user_presence().set(|| Err(UserPresenceError::Declined));
+        let ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+
+        let response = ctap_state.process_vendor_credential_dump(&mut env, DUMMY_CHANNEL);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED));
     }
 
     #[test]
+    #[cfg(feature = "cred_management")]
     fn test_permission_timeout() {
         let mut env = TestEnv::new();
         let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
 
-        // Write 2 credentials for later assertions.
-        for i in 0..3 {
+        // Write 4 credentials for later assertions.
+        for i in 0..4 {
             let mut make_credential_params = create_minimal_make_credential_parameters();
             make_credential_params.user.user_id = vec![i as u8];
             assert!(ctap_state
@@ -3570,11 +6017,23 @@ mod test {
             CtapInstant::new(0) + STATEFUL_COMMAND_TIMEOUT_DURATION - Milliseconds(1 as ClockInt),
         );
         assert!(get_next_assertion_response.is_ok());
+        // The timeout restarts with each call.
+        let second_call = CtapInstant::new(0) + STATEFUL_COMMAND_TIMEOUT_DURATION
+            - Milliseconds(1 as ClockInt)
+            + STATEFUL_COMMAND_TIMEOUT_DURATION
+            - Milliseconds(1 as ClockInt);
         let get_next_assertion_response = ctap_state.process_parsed_command(
             &mut env,
             Command::AuthenticatorGetNextAssertion,
             DUMMY_CHANNEL,
-            CtapInstant::new(0) + STATEFUL_COMMAND_TIMEOUT_DURATION + Milliseconds(1 as ClockInt),
+            second_call,
+        );
+        assert!(get_next_assertion_response.is_ok());
+        let get_next_assertion_response = ctap_state.process_parsed_command(
+            &mut env,
+            Command::AuthenticatorGetNextAssertion,
+            DUMMY_CHANNEL,
+            second_call + STATEFUL_COMMAND_TIMEOUT_DURATION + Milliseconds(1 as ClockInt),
         );
         assert_eq!(
             get_next_assertion_response,
@@ -3706,6 +6165,37 @@ mod test {
             response,
             Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT)
         ));
+        // The watchdog is kicked with every keepalive.
+        let iterations = TOUCH_TIMEOUT_MS as usize / KEEPALIVE_DELAY_MS as usize;
+        assert_eq!(env.watchdog().kicks(), iterations + 1);
+    }
+
+    #[test]
+    fn test_check_user_presence_canceled() {
+        let mut env = TestEnv::new();
+        env.user_presence().set(|| Err(UserPresenceError::Canceled));
+        let response = check_user_presence(&mut env, DUMMY_CHANNEL);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL));
+    }
+
+    #[test]
+    fn test_check_user_presence_keepalive_error() {
+        let mut env = TestEnv::new();
+        env.user_presence().set(|| Err(UserPresenceError::Timeout));
+        env.set_hid_error(Some(SendOrRecvError::NoMemory));
+        let response = check_user_presence(&mut env, DUMMY_CHANNEL);
+        assert_eq!(
+            response,
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)
+        );
+    }
+
+    #[test]
+    fn test_check_user_presence_declined() {
+        let mut env = TestEnv::new();
+        env.user_presence().set(|| Err(UserPresenceError::Declined));
+        let response = check_user_presence(&mut env, DUMMY_CHANNEL);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED));
     }
 
     #[test]
@@ -0,0 +1,414 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::apdu::{Apdu, ApduInstructions, ApduStatusCode, ApduType};
+use super::{Channel, CtapState};
+use crate::api::customization::Customization;
+use crate::clock::CtapInstant;
+use crate::env::Env;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// Application identifier of FIDO, selected by the reader before sending CTAP messages.
+const FIDO_AID: [u8; 8] = [0xA0, 0x00, 0x00, 0x06, 0x47, 0x2F, 0x00, 0x01];
+
+/// Answer to the selection of the FIDO application, for authenticators supporting CTAP2.
+const FIDO_2_0_VERSION: &[u8] = b"FIDO_2_0";
+
+/// Bit of the class byte announcing that the command continues in the next APDU.
+const CLA_CHAINING: u8 = 0x10;
+
+/// Class of the ISO 7816-4 interindustry commands.
+const CLA_INTERINDUSTRY: u8 = 0x00;
+
+/// Class of the CTAP commands.
+const CLA_PROPRIETARY: u8 = 0x80;
+
+/// Command APDUs received so far, while the reader chains them.
+struct ChainedCommand {
+    cla: u8,
+    ins: u8,
+    p1: u8,
+    p2: u8,
+    data: Vec<u8>,
+}
+
+/// Implements CTAP over NFC, where messages are ISO 7816-4 APDUs.
+///
+/// See CTAP 2.1 from 2021-06-15, section 11.3. Chained command APDUs are reassembled into a single
+/// message. Responses that exceed the expected length are split, and the reader fetches the rest
+/// with GET RESPONSE.
+pub struct Nfc {
+    selected: bool,
+    chained_command: Option<ChainedCommand>,
+    pending_response: Vec<u8>,
+}
+
+impl Nfc {
+    /// Instantiates an NFC handler, waiting for the selection of the FIDO application.
+    pub fn new() -> Self {
+        Nfc {
+            selected: false,
+            chained_command: None,
+            pending_response: Vec::new(),
+        }
+    }
+
+    /// Processes a command APDU, and returns the response APDU.
+    pub fn process_apdu(
+        &mut self,
+        env: &mut impl Env,
+        frame: &[u8],
+        now: CtapInstant,
+        ctap_state: &mut CtapState,
+    ) -> Vec<u8> {
+        let (mut response, status) = match self.process_frame(env, frame, now, ctap_state) {
+            Ok((data, max_len)) => self.split_response(data, max_len),
+            Err(status) => {
+                self.chained_command = None;
+                self.pending_response.clear();
+                (Vec::new(), u16::from(status))
+            }
+        };
+        debug_ctap!(env, "Sending NFC response with status {:04x}", status);
+        response.extend_from_slice(&status.to_be_bytes());
+        response
+    }
+
+    /// Returns the response data and its maximum length in this APDU.
+    fn process_frame(
+        &mut self,
+        env: &mut impl Env,
+        frame: &[u8],
+        now: CtapInstant,
+        ctap_state: &mut CtapState,
+    ) -> Result<(Vec<u8>, usize), ApduStatusCode> {
+        let apdu = Apdu::try_from(frame)?;
+        let max_len = match (apdu.case_type, apdu.le) {
+            (ApduType::Extended(_), 0) => 0x10000,
+            (_, 0) => 0x100,
+            (_, le) => le as usize,
+        };
+        let header = apdu.header;
+        let cla = header.cla & !CLA_CHAINING;
+        let is_get_response = match cla {
+            CLA_INTERINDUSTRY => header.ins == ApduInstructions::GetResponse as u8,
+            CLA_PROPRIETARY => header.ins == ApduInstructions::NfcCtapGetResponse as u8,
+            _ => false,
+        };
+        if is_get_response && self.chained_command.is_none() {
+            // The remainder is served in order, and split again if it still exceeds the maximum.
+            let response = core::mem::take(&mut self.pending_response);
+            if response.is_empty() {
+                return Err(ApduStatusCode::SW_COND_USE_NOT_SATISFIED);
+            }
+            return Ok((response, max_len));
+        }
+        self.pending_response.clear();
+
+        let mut data = match self.chained_command.take() {
+            None => Vec::new(),
+            Some(chained) => {
+                if (chained.cla, chained.ins, chained.p1, chained.p2)
+                    != (cla, header.ins, header.p1, header.p2)
+                {
+                    return Err(ApduStatusCode::SW_LAST_COMMAND_EXPECTED);
+                }
+                chained.data
+            }
+        };
+        data.extend_from_slice(&apdu.data);
+        if data.len() > env.customization().max_msg_size() {
+            return Err(ApduStatusCode::SW_WRONG_LENGTH);
+        }
+        if header.cla & CLA_CHAINING != 0 {
+            self.chained_command = Some(ChainedCommand {
+                cla,
+                ins: header.ins,
+                p1: header.p1,
+                p2: header.p2,
+                data,
+            });
+            return Ok((Vec::new(), max_len));
+        }
+
+        match cla {
+            CLA_INTERINDUSTRY if header.ins == ApduInstructions::Select as u8 => {
+                self.selected = header.p1 == 0x04 && data == FIDO_AID;
+                if !self.selected {
+                    return Err(ApduStatusCode::SW_FILE_NOT_FOUND);
+                }
+                Ok((FIDO_2_0_VERSION.to_vec(), max_len))
+            }
+            CLA_INTERINDUSTRY => Err(ApduStatusCode::SW_INS_INVALID),
+            CLA_PROPRIETARY if !self.selected => Err(ApduStatusCode::SW_COND_USE_NOT_SATISFIED),
+            CLA_PROPRIETARY if header.ins == ApduInstructions::NfcCtapMsg as u8 => {
+                let response = ctap_state.process_command(env, &data, Channel::Nfc, now);
+                Ok((response, max_len))
+            }
+            CLA_PROPRIETARY => Err(ApduStatusCode::SW_INS_INVALID),
+            _ => Err(ApduStatusCode::SW_CLA_INVALID),
+        }
+    }
+
+    /// Keeps what exceeds the maximum length for GET RESPONSE, and returns the status.
+    ///
+    /// The status 61XX announces the number of bytes left, which the reader fetches with GET
+    /// RESPONSE or NFCCTAP_GETRESPONSE.
+    fn split_response(&mut self, mut data: Vec<u8>, max_len: usize) -> (Vec<u8>, u16) {
+        if data.len() <= max_len {
+            return (data, ApduStatusCode::SW_SUCCESS.into());
+        }
+        self.pending_response = data.split_off(max_len);
+        // The length of the next response is a single byte, where 0 means 256 or more.
+        let next_len = core::cmp::min(self.pending_response.len(), 0x100) as u8;
+        let status = u16::from(ApduStatusCode::SW_GET_RESPONSE) | next_len as u16;
+        (data, status)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "large_blobs")]
+    use crate::ctap::storage;
+    use crate::env::test::TestEnv;
+
+    const SELECT: [u8; 13] = [
+        0x00, 0xA4, 0x04, 0x00, 0x08, 0xA0, 0x00, 0x00, 0x06, 0x47, 0x2F, 0x00, 0x01,
+    ];
+    const GET_INFO: [u8; 7] = [0x80, 0x10, 0x00, 0x00, 0x01, 0x04, 0x00];
+
+    struct Reader {
+        nfc: Nfc,
+        env: TestEnv,
+        ctap_state: CtapState,
+    }
+
+    impl Reader {
+        fn new() -> Self {
+            let mut env = TestEnv::new();
+            let ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+            Reader {
+                nfc: Nfc::new(),
+                env,
+                ctap_state,
+            }
+        }
+
+        /// Returns the response to a command APDU, split into its data and status.
+        fn send(&mut self, frame: &[u8]) -> (Vec<u8>, u16) {
+            let mut response = self.nfc.process_apdu(
+                &mut self.env,
+                frame,
+                CtapInstant::new(0),
+                &mut self.ctap_state,
+            );
+            let status = response.split_off(response.len() - 2);
+            (response, u16::from_be_bytes([status[0], status[1]]))
+        }
+
+        /// Returns the response of the CTAP command, without NFC.
+        fn process_command(&mut self, message: &[u8]) -> Vec<u8> {
+            self.ctap_state.process_command(
+                &mut self.env,
+                message,
+                Channel::Nfc,
+                CtapInstant::new(0),
+            )
+        }
+    }
+
+    /// Builds a short NFCCTAP_MSG with the given class.
+    fn ctap_msg(cla: u8, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![cla, 0x10, 0x00, 0x00, data.len() as u8];
+        frame.extend_from_slice(data);
+        frame.push(0x00);
+        frame
+    }
+
+    #[test]
+    fn test_select() {
+        let mut reader = Reader::new();
+        assert_eq!(reader.send(&SELECT), (b"FIDO_2_0".to_vec(), 0x9000));
+    }
+
+    #[test]
+    fn test_select_other_application() {
+        let mut reader = Reader::new();
+        let mut frame = SELECT;
+        frame[12] = 0x02;
+        assert_eq!(reader.send(&frame), (vec![], 0x6A82));
+        // CTAP messages are rejected until FIDO is selected.
+        assert_eq!(reader.send(&GET_INFO), (vec![], 0x6985));
+    }
+
+    #[test]
+    fn test_unknown_instructions() {
+        let mut reader = Reader::new();
+        reader.send(&SELECT);
+        assert_eq!(reader.send(&[0x80, 0x12, 0x00, 0x00]), (vec![], 0x6D00));
+        assert_eq!(reader.send(&[0x40, 0x10, 0x00, 0x00]), (vec![], 0x6E00));
+        assert_eq!(reader.send(&[0x00, 0x12]), (vec![], 0x6A80));
+    }
+
+    #[test]
+    fn test_get_info() {
+        let mut reader = Reader::new();
+        reader.send(&SELECT);
+        // Extended APDU, so that the whole response fits.
+        let frame = [0x80, 0x10, 0x00, 0x00, 0x00, 0x00, 0x01, 0x04, 0x00, 0x00];
+        let expected = reader.process_command(&[0x04]);
+        assert_eq!(expected[0], 0x00);
+        assert_eq!(reader.send(&frame), (expected, 0x9000));
+    }
+
+    #[test]
+    fn test_chained_command() {
+        let mut reader = Reader::new();
+        reader.send(&SELECT);
+        // ClientPin getPinRetries with PIN protocol 1.
+        let message = [0x06, 0xA2, 0x01, 0x01, 0x02, 0x01];
+        let expected = reader.process_command(&message);
+        assert_eq!(expected[0], 0x00);
+
+        assert_eq!(
+            reader.send(&ctap_msg(0x90, &message[..1])),
+            (vec![], 0x9000)
+        );
+        assert_eq!(
+            reader.send(&ctap_msg(0x90, &message[1..4])),
+            (vec![], 0x9000)
+        );
+        assert_eq!(
+            reader.send(&ctap_msg(0x80, &message[4..])),
+            (expected, 0x9000)
+        );
+    }
+
+    #[test]
+    fn test_chained_command_interrupted() {
+        let mut reader = Reader::new();
+        reader.send(&SELECT);
+        assert_eq!(reader.send(&ctap_msg(0x90, &[0x04])), (vec![], 0x9000));
+        // A different instruction breaks the chain, which is then dropped.
+        assert_eq!(reader.send(&[0x80, 0x12, 0x00, 0x00]), (vec![], 0x6883));
+        let (data, status) = reader.send(&GET_INFO);
+        assert_eq!(data[0], 0x00);
+        assert_ne!(status, 0x6883);
+    }
+
+    #[test]
+    fn test_chained_command_too_long() {
+        let mut reader = Reader::new();
+        reader.send(&SELECT);
+        let max_msg_size = reader.env.customization().max_msg_size();
+        let chunk = [0x00; 0xFF];
+        let mut sent = 0;
+        let mut status = 0x9000;
+        while status == 0x9000 {
+            status = reader.send(&ctap_msg(0x90, &chunk)).1;
+            sent += chunk.len();
+        }
+        assert_eq!(status, 0x6700);
+        assert!(sent > max_msg_size);
+        assert!(sent - chunk.len() <= max_msg_size);
+    }
+
+    #[test]
+    fn test_get_response() {
+        let mut reader = Reader::new();
+        reader.send(&SELECT);
+        let expected = reader.process_command(&[0x04]);
+        assert!(expected.len() > 0x20);
+
+        // Expect 16 bytes at a time.
+        let mut frame = GET_INFO;
+        frame[6] = 0x10;
+        let get_response = [0x00, 0xC0, 0x00, 0x00, 0x10];
+        let mut response = Vec::new();
+        let (mut data, mut status) = reader.send(&frame);
+        while status != 0x9000 {
+            assert_eq!(data.len(), 0x10);
+            response.extend_from_slice(&data);
+            let remaining = expected.len() - response.len();
+            assert_eq!(
+                status,
+                0x6100 | core::cmp::min(remaining, 0x100) as u8 as u16
+            );
+            let next = reader.send(&get_response);
+            data = next.0;
+            status = next.1;
+        }
+        response.extend_from_slice(&data);
+        assert_eq!(response, expected);
+
+        // There is nothing left to get.
+        assert_eq!(reader.send(&get_response), (vec![], 0x6985));
+    }
+
+    #[test]
+    #[cfg(feature = "large_blobs")]
+    fn test_long_response_served_in_parts() {
+        let mut reader = Reader::new();
+        reader.send(&SELECT);
+        let large_blob_array: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        storage::commit_large_blob_array(&mut reader.env, &large_blob_array).unwrap();
+        // LargeBlobs reading 600 bytes at offset 0.
+        let message = [0x0C, 0xA2, 0x01, 0x19, 0x02, 0x58, 0x03, 0x00];
+        let expected = reader.process_command(&message);
+        assert_eq!(expected.len(), 606);
+        assert_eq!(expected[0], 0x00);
+
+        // A short APDU gets 256 bytes, and 350 bytes are left, announced as 256 or more.
+        let (mut response, status) = reader.send(&ctap_msg(0x80, &message));
+        assert_eq!(response.len(), 0x100);
+        assert_eq!(status, 0x6100);
+
+        let get_response = [0x00, 0xC0, 0x00, 0x00, 0x80];
+        let (data, status) = reader.send(&get_response);
+        assert_eq!(data.len(), 0x80);
+        assert_eq!(status, 0x61DE);
+        response.extend_from_slice(&data);
+
+        let nfcctap_get_response = [0x80, 0x11, 0x00, 0x00, 0x80];
+        let (data, status) = reader.send(&nfcctap_get_response);
+        assert_eq!(data.len(), 0x80);
+        assert_eq!(status, 0x615E);
+        response.extend_from_slice(&data);
+
+        // The reader may ask for more than what is left.
+        let (data, status) = reader.send(&[0x00, 0xC0, 0x00, 0x00, 0x00]);
+        assert_eq!(data.len(), 0x5E);
+        assert_eq!(status, 0x9000);
+        response.extend_from_slice(&data);
+        assert_eq!(response, expected);
+        assert_eq!(reader.send(&nfcctap_get_response), (vec![], 0x6985));
+    }
+
+    #[test]
+    fn test_other_command_drops_pending_response() {
+        let mut reader = Reader::new();
+        reader.send(&SELECT);
+        let mut frame = GET_INFO;
+        frame[6] = 0x10;
+        let (_, status) = reader.send(&frame);
+        assert_eq!(status & 0xFF00, 0x6100);
+        // ClientPin getPinRetries with PIN protocol 1.
+        let message = [0x06, 0xA2, 0x01, 0x01, 0x02, 0x01];
+        let expected = reader.process_command(&message);
+        assert_eq!(reader.send(&ctap_msg(0x80, &message)), (expected, 0x9000));
+        assert_eq!(reader.send(&[0x00, 0xC0, 0x00, 0x00]), (vec![], 0x6985));
+    }
+}
@@ -12,9 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::api::aes::SoftwareAes256;
 use crate::ctap::client_pin::PIN_TOKEN_LENGTH;
-use crate::ctap::crypto_wrapper::{aes256_cbc_decrypt, aes256_cbc_encrypt};
-use crate::ctap::data_formats::{CoseKey, PinUvAuthProtocol};
+use crate::ctap::cose::CoseKey;
+use crate::ctap::crypto_wrapper::{aes256_cbc_decrypt, aes256_cbc_encrypt, random_iv};
+use crate::ctap::data_formats::PinUvAuthProtocol;
 use crate::ctap::status_code::Ctap2StatusCode;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
@@ -24,6 +26,7 @@ use crypto::hkdf::hkdf_empty_salt_256;
 use crypto::hmac::hmac_256;
 use crypto::hmac::{verify_hmac_256, verify_hmac_256_first_128bits};
 use crypto::sha256::Sha256;
+use crypto::util::{Zeroize, Zeroizing};
 use crypto::Hash256;
 use rng256::Rng256;
 
@@ -68,10 +71,13 @@ impl PinProtocol {
         pin_uv_auth_protocol: PinUvAuthProtocol,
     ) -> Result<Box<dyn SharedSecret>, Ctap2StatusCode> {
         let pk: crypto::ecdh::PubKey = CoseKey::try_into(peer_cose_key)?;
-        let handshake = self.key_agreement_key.exchange_x(&pk);
+        let handshake = Zeroizing::new(
+            crypto::ecdh::shared_secret(&self.key_agreement_key, &pk)
+                .map_err(|_| Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)?,
+        );
         match pin_uv_auth_protocol {
-            PinUvAuthProtocol::V1 => Ok(Box::new(SharedSecretV1::new(handshake))),
-            PinUvAuthProtocol::V2 => Ok(Box::new(SharedSecretV2::new(handshake))),
+            PinUvAuthProtocol::V1 => Ok(Box::new(SharedSecretV1::new(&handshake))),
+            PinUvAuthProtocol::V2 => Ok(Box::new(SharedSecretV2::new(&handshake))),
         }
     }
 
@@ -119,6 +125,9 @@ pub fn verify_pin_uv_auth_token(
     }
 }
 
+/// Secret shared with the platform for the duration of a PIN protocol exchange.
+///
+/// Encryption uses the software AES, since shared secrets have no access to the environment.
 pub trait SharedSecret {
     /// Returns the encrypted plaintext.
     fn encrypt(&self, rng: &mut dyn Rng256, plaintext: &[u8]) -> Result<Vec<u8>, Ctap2StatusCode>;
@@ -158,28 +167,30 @@ fn verify_v2(key: &[u8; 32], message: &[u8], signature: &[u8]) -> Result<(), Cta
 
 pub struct SharedSecretV1 {
     common_secret: [u8; 32],
-    aes_enc_key: crypto::aes256::EncryptionKey,
 }
 
 impl SharedSecretV1 {
     /// Creates a new shared secret from the handshake result.
-    fn new(handshake: [u8; 32]) -> SharedSecretV1 {
-        let common_secret = Sha256::hash(&handshake);
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&common_secret);
+    fn new(handshake: &[u8; 32]) -> SharedSecretV1 {
         SharedSecretV1 {
-            common_secret,
-            aes_enc_key,
+            common_secret: Sha256::hash(handshake),
         }
     }
 }
 
+impl Drop for SharedSecretV1 {
+    fn drop(&mut self) {
+        self.common_secret.zeroize();
+    }
+}
+
 impl SharedSecret for SharedSecretV1 {
-    fn encrypt(&self, rng: &mut dyn Rng256, plaintext: &[u8]) -> Result<Vec<u8>, Ctap2StatusCode> {
-        aes256_cbc_encrypt(rng, &self.aes_enc_key, plaintext, false)
+    fn encrypt(&self, _rng: &mut dyn Rng256, plaintext: &[u8]) -> Result<Vec<u8>, Ctap2StatusCode> {
+        aes256_cbc_encrypt(&mut SoftwareAes256, &self.common_secret, None, plaintext)
     }
 
     fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Ctap2StatusCode> {
-        aes256_cbc_decrypt(&self.aes_enc_key, ciphertext, false)
+        aes256_cbc_decrypt(&mut SoftwareAes256, &self.common_secret, ciphertext, false)
     }
 
     fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), Ctap2StatusCode> {
@@ -193,28 +204,39 @@ impl SharedSecret for SharedSecretV1 {
 }
 
 pub struct SharedSecretV2 {
-    aes_enc_key: crypto::aes256::EncryptionKey,
+    aes_key: [u8; 32],
     hmac_key: [u8; 32],
 }
 
 impl SharedSecretV2 {
     /// Creates a new shared secret from the handshake result.
-    fn new(handshake: [u8; 32]) -> SharedSecretV2 {
-        let aes_key = hkdf_empty_salt_256::<Sha256>(&handshake, b"CTAP2 AES key");
+    fn new(handshake: &[u8; 32]) -> SharedSecretV2 {
         SharedSecretV2 {
-            aes_enc_key: crypto::aes256::EncryptionKey::new(&aes_key),
-            hmac_key: hkdf_empty_salt_256::<Sha256>(&handshake, b"CTAP2 HMAC key"),
+            aes_key: hkdf_empty_salt_256::<Sha256>(handshake, b"CTAP2 AES key"),
+            hmac_key: hkdf_empty_salt_256::<Sha256>(handshake, b"CTAP2 HMAC key"),
         }
     }
 }
 
+impl Drop for SharedSecretV2 {
+    fn drop(&mut self) {
+        self.aes_key.zeroize();
+        self.hmac_key.zeroize();
+    }
+}
+
 impl SharedSecret for SharedSecretV2 {
     fn encrypt(&self, rng: &mut dyn Rng256, plaintext: &[u8]) -> Result<Vec<u8>, Ctap2StatusCode> {
-        aes256_cbc_encrypt(rng, &self.aes_enc_key, plaintext, true)
+        aes256_cbc_encrypt(
+            &mut SoftwareAes256,
+            &self.aes_key,
+            Some(random_iv(rng)),
+            plaintext,
+        )
     }
 
     fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Ctap2StatusCode> {
-        aes256_cbc_decrypt(&self.aes_enc_key, ciphertext, true)
+        aes256_cbc_decrypt(&mut SoftwareAes256, &self.aes_key, ciphertext, true)
     }
 
     fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), Ctap2StatusCode> {
@@ -231,6 +253,8 @@ impl SharedSecret for SharedSecretV2 {
 mod test {
     use super::*;
     use crate::env::test::TestEnv;
+    use core::mem::ManuallyDrop;
+    use core::ptr;
 
     #[test]
     fn test_pin_protocol_public_key() {
@@ -255,7 +279,7 @@ mod test {
     #[test]
     fn test_shared_secret_v1_encrypt_decrypt() {
         let mut env = TestEnv::new();
-        let shared_secret = SharedSecretV1::new([0x55; 32]);
+        let shared_secret = SharedSecretV1::new(&[0x55; 32]);
         let plaintext = vec![0xAA; 64];
         let ciphertext = shared_secret.encrypt(env.rng(), &plaintext).unwrap();
         assert_eq!(shared_secret.decrypt(&ciphertext), Ok(plaintext));
@@ -263,7 +287,7 @@ mod test {
 
     #[test]
     fn test_shared_secret_v1_authenticate_verify() {
-        let shared_secret = SharedSecretV1::new([0x55; 32]);
+        let shared_secret = SharedSecretV1::new(&[0x55; 32]);
         let message = [0xAA; 32];
         let signature = shared_secret.authenticate(&message);
         assert_eq!(shared_secret.verify(&message, &signature), Ok(()));
@@ -271,7 +295,7 @@ mod test {
 
     #[test]
     fn test_shared_secret_v1_verify() {
-        let shared_secret = SharedSecretV1::new([0x55; 32]);
+        let shared_secret = SharedSecretV1::new(&[0x55; 32]);
         let message = [0xAA];
         let signature = [
             0x8B, 0x60, 0x15, 0x7D, 0xF3, 0x44, 0x82, 0x2E, 0x54, 0x34, 0x7A, 0x01, 0xFB, 0x02,
@@ -291,15 +315,35 @@ mod test {
     #[test]
     fn test_shared_secret_v2_encrypt_decrypt() {
         let mut env = TestEnv::new();
-        let shared_secret = SharedSecretV2::new([0x55; 32]);
+        let shared_secret = SharedSecretV2::new(&[0x55; 32]);
         let plaintext = vec![0xAA; 64];
         let ciphertext = shared_secret.encrypt(env.rng(), &plaintext).unwrap();
         assert_eq!(shared_secret.decrypt(&ciphertext), Ok(plaintext));
     }
 
+    #[test]
+    fn test_shared_secret_v2_invalid_lengths() {
+        let mut env = TestEnv::new();
+        let shared_secret = SharedSecretV2::new(&[0x55; 32]);
+        assert_eq!(
+            shared_secret.encrypt(env.rng(), &[0xAA; 15]),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        // The ciphertext is prefixed with a 16 byte IV.
+        for &length in &[0, 15, 17, 40] {
+            assert_eq!(
+                shared_secret.decrypt(&vec![0xAA; length]),
+                Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+            );
+        }
+        assert_eq!(shared_secret.decrypt(&[0xAA; 16]), Ok(Vec::new()));
+        let ciphertext = shared_secret.encrypt(env.rng(), &[0xAA; 32]).unwrap();
+        assert_eq!(ciphertext.len(), 48);
+    }
+
     #[test]
     fn test_shared_secret_v2_authenticate_verify() {
-        let shared_secret = SharedSecretV2::new([0x55; 32]);
+        let shared_secret = SharedSecretV2::new(&[0x55; 32]);
         let message = [0xAA; 32];
         let signature = shared_secret.authenticate(&message);
         assert_eq!(shared_secret.verify(&message, &signature), Ok(()));
@@ -307,7 +351,7 @@ mod test {
 
     #[test]
     fn test_shared_secret_v2_verify() {
-        let shared_secret = SharedSecretV2::new([0x55; 32]);
+        let shared_secret = SharedSecretV2::new(&[0x55; 32]);
         let message = [0xAA];
         let signature = [
             0xC0, 0x3F, 0x2A, 0x22, 0x5C, 0xC3, 0x4E, 0x05, 0xC1, 0x0E, 0x72, 0x9C, 0x8D, 0xD5,
@@ -325,6 +369,25 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_shared_secret_zeroized_on_drop() {
+        let mut shared_secret = ManuallyDrop::new(SharedSecretV1::new(&[0x55; 32]));
+        let shared_secret_ptr: *mut SharedSecretV1 = &mut *shared_secret;
+        // The memory outlives the drop, so its content can still be inspected.
+        unsafe {
+            ptr::drop_in_place(shared_secret_ptr);
+            assert_eq!((*shared_secret_ptr).common_secret, [0x00; 32]);
+        }
+
+        let mut shared_secret = ManuallyDrop::new(SharedSecretV2::new(&[0x55; 32]));
+        let shared_secret_ptr: *mut SharedSecretV2 = &mut *shared_secret;
+        unsafe {
+            ptr::drop_in_place(shared_secret_ptr);
+            assert_eq!((*shared_secret_ptr).aes_key, [0x00; 32]);
+            assert_eq!((*shared_secret_ptr).hmac_key, [0x00; 32]);
+        }
+    }
+
     #[test]
     fn test_decapsulate_symmetric() {
         let mut env = TestEnv::new();
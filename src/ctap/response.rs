@@ -12,8 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use super::cose::CoseKey;
 use super::data_formats::{
-    AuthenticatorTransport, CoseKey, CredentialProtectionPolicy, PackedAttestationStatement,
+    AuthenticatorTransport, CredentialProtectionPolicy, PackedAttestationStatement,
     PublicKeyCredentialDescriptor, PublicKeyCredentialParameter, PublicKeyCredentialRpEntity,
     PublicKeyCredentialUserEntity,
 };
@@ -33,6 +34,7 @@ pub enum ResponseData {
     AuthenticatorGetInfo(AuthenticatorGetInfoResponse),
     AuthenticatorClientPin(Option<AuthenticatorClientPinResponse>),
     AuthenticatorReset,
+    AuthenticatorBioEnrollment,
     AuthenticatorCredentialManagement(Option<AuthenticatorCredentialManagementResponse>),
     AuthenticatorSelection,
     AuthenticatorLargeBlobs(Option<AuthenticatorLargeBlobsResponse>),
@@ -40,6 +42,7 @@ pub enum ResponseData {
     AuthenticatorVendorConfigure(AuthenticatorVendorConfigureResponse),
     AuthenticatorVendorUpgrade,
     AuthenticatorVendorUpgradeInfo(AuthenticatorVendorUpgradeInfoResponse),
+    AuthenticatorVendorCredentialDump(AuthenticatorVendorCredentialDumpResponse),
 }
 
 impl From<ResponseData> for Option<cbor::Value> {
@@ -51,6 +54,7 @@ impl From<ResponseData> for Option<cbor::Value> {
             ResponseData::AuthenticatorGetInfo(data) => Some(data.into()),
             ResponseData::AuthenticatorClientPin(data) => data.map(|d| d.into()),
             ResponseData::AuthenticatorReset => None,
+            ResponseData::AuthenticatorBioEnrollment => None,
             ResponseData::AuthenticatorCredentialManagement(data) => data.map(|d| d.into()),
             ResponseData::AuthenticatorSelection => None,
             ResponseData::AuthenticatorLargeBlobs(data) => data.map(|d| d.into()),
@@ -58,6 +62,7 @@ impl From<ResponseData> for Option<cbor::Value> {
             ResponseData::AuthenticatorVendorConfigure(data) => Some(data.into()),
             ResponseData::AuthenticatorVendorUpgrade => None,
             ResponseData::AuthenticatorVendorUpgradeInfo(data) => Some(data.into()),
+            ResponseData::AuthenticatorVendorCredentialDump(data) => Some(data.into()),
         }
     }
 }
@@ -124,7 +129,7 @@ impl From<AuthenticatorGetAssertionResponse> for cbor::Value {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct AuthenticatorGetInfoResponse {
     pub versions: Vec<String>,
     pub extensions: Option<Vec<String>>,
@@ -220,7 +225,7 @@ pub struct AuthenticatorClientPinResponse {
     pub pin_uv_auth_token: Option<Vec<u8>>,
     pub retries: Option<u64>,
     pub power_cycle_state: Option<bool>,
-    // - 0x05: uvRetries missing as we don't support internal UV.
+    pub uv_retries: Option<u64>,
 }
 
 impl From<AuthenticatorClientPinResponse> for cbor::Value {
@@ -230,6 +235,7 @@ impl From<AuthenticatorClientPinResponse> for cbor::Value {
             pin_uv_auth_token,
             retries,
             power_cycle_state,
+            uv_retries,
         } = client_pin_response;
 
         cbor_map_options! {
@@ -237,6 +243,7 @@ impl From<AuthenticatorClientPinResponse> for cbor::Value {
             0x02 => pin_uv_auth_token,
             0x03 => retries,
             0x04 => power_cycle_state,
+            0x05 => uv_retries,
         }
     }
 }
@@ -326,14 +333,62 @@ impl From<AuthenticatorVendorConfigureResponse> for cbor::Value {
 #[derive(Debug, PartialEq, Eq)]
 pub struct AuthenticatorVendorUpgradeInfoResponse {
     pub info: u32,
+    /// Bytes of the declared bundle written so far, where a chunked write resumes.
+    pub written_so_far: usize,
 }
 
 impl From<AuthenticatorVendorUpgradeInfoResponse> for cbor::Value {
     fn from(vendor_upgrade_info_response: AuthenticatorVendorUpgradeInfoResponse) -> Self {
-        let AuthenticatorVendorUpgradeInfoResponse { info } = vendor_upgrade_info_response;
+        let AuthenticatorVendorUpgradeInfoResponse {
+            info,
+            written_so_far,
+        } = vendor_upgrade_info_response;
 
         cbor_map_options! {
             0x01 => info as u64,
+            0x02 => written_so_far as u64,
+        }
+    }
+}
+
+/// Metadata of a stored credential, without keys or user information.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CredentialMetadata {
+    pub rp_id: String,
+    pub creation_order: u64,
+    pub signature_counter: u32,
+}
+
+impl From<CredentialMetadata> for cbor::Value {
+    fn from(credential_metadata: CredentialMetadata) -> Self {
+        let CredentialMetadata {
+            rp_id,
+            creation_order,
+            signature_counter,
+        } = credential_metadata;
+
+        cbor_map_options! {
+            0x01 => rp_id,
+            0x02 => creation_order,
+            0x03 => signature_counter as u64,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AuthenticatorVendorCredentialDumpResponse {
+    /// Credentials sorted by creation order.
+    pub credentials: Vec<CredentialMetadata>,
+}
+
+impl From<AuthenticatorVendorCredentialDumpResponse> for cbor::Value {
+    fn from(vendor_credential_dump_response: AuthenticatorVendorCredentialDumpResponse) -> Self {
+        let AuthenticatorVendorCredentialDumpResponse { credentials } =
+            vendor_credential_dump_response;
+
+        cbor_map_options! {
+            0x01 => credentials.len() as u64,
+            0x02 => cbor_array_vec!(credentials),
         }
     }
 }
@@ -515,6 +570,7 @@ mod test {
             pin_uv_auth_token: Some(vec![70]),
             retries: Some(8),
             power_cycle_state: Some(false),
+            uv_retries: Some(5),
         };
         let response_cbor: Option<cbor::Value> =
             ResponseData::AuthenticatorClientPin(Some(client_pin_response)).into();
@@ -523,6 +579,7 @@ mod test {
             0x02 => vec![70],
             0x03 => 8,
             0x04 => false,
+            0x05 => 5,
         };
         assert_eq!(response_cbor, Some(expected_cbor));
     }
@@ -676,12 +733,37 @@ mod test {
 
     #[test]
     fn test_vendor_upgrade_info_into_cbor() {
-        let vendor_upgrade_info_response =
-            AuthenticatorVendorUpgradeInfoResponse { info: 0x00060000 };
+        let vendor_upgrade_info_response = AuthenticatorVendorUpgradeInfoResponse {
+            info: 0x00060000,
+            written_so_far: 0x1000,
+        };
         let response_cbor: Option<cbor::Value> =
             ResponseData::AuthenticatorVendorUpgradeInfo(vendor_upgrade_info_response).into();
         let expected_cbor = cbor_map! {
             0x01 => 0x00060000,
+            0x02 => 0x1000,
+        };
+        assert_eq!(response_cbor, Some(expected_cbor));
+    }
+
+    #[test]
+    fn test_vendor_credential_dump_into_cbor() {
+        let vendor_credential_dump_response = AuthenticatorVendorCredentialDumpResponse {
+            credentials: vec![CredentialMetadata {
+                rp_id: String::from("example.com"),
+                creation_order: 3,
+                signature_counter: 7,
+            }],
+        };
+        let response_cbor: Option<cbor::Value> =
+            ResponseData::AuthenticatorVendorCredentialDump(vendor_credential_dump_response).into();
+        let expected_cbor = cbor_map! {
+            0x01 => 1,
+            0x02 => cbor_array![cbor_map! {
+                0x01 => "example.com",
+                0x02 => 3,
+                0x03 => 7,
+            }],
         };
         assert_eq!(response_cbor, Some(expected_cbor));
     }
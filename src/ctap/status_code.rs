@@ -12,8 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::api::connection::SendOrRecvError;
 use crate::api::user_presence::UserPresenceError;
-use crate::api::{attestation_store, key_store};
+use crate::api::user_verification::UserVerificationError;
+use crate::api::{attestation_store, key_store, signature_counter};
 
 // CTAP specification (version 20190130) section 6.3
 // For now, only the CTAP2 codes are here, the CTAP1 are not included.
@@ -83,9 +85,29 @@ pub enum Ctap2StatusCode {
     /// It may be possible that some of those errors are actually internal errors.
     CTAP2_ERR_VENDOR_HARDWARE_FAILURE = 0xF3,
     CTAP2_ERR_VENDOR_INTERNAL_ERROR_PAYLOAD_TOO_LONG = 0xF4,
+
+    /// A stored value has a format version unknown to this firmware.
+    ///
+    /// The value was probably written by a more recent version and is not parsed.
+    CTAP2_ERR_VENDOR_UNSUPPORTED_FORMAT = 0xF5,
     _CTAP2_ERR_VENDOR_LAST = 0xFF,
 }
 
+impl From<SendOrRecvError> for Ctap2StatusCode {
+    fn from(error: SendOrRecvError) -> Self {
+        match error {
+            SendOrRecvError::Busy => Self::CTAP1_ERR_CHANNEL_BUSY,
+            SendOrRecvError::InvalidParameter => Self::CTAP1_ERR_INVALID_PARAMETER,
+            SendOrRecvError::InvalidLength => Self::CTAP1_ERR_INVALID_LENGTH,
+            SendOrRecvError::Canceled => Self::CTAP2_ERR_KEEPALIVE_CANCEL,
+            SendOrRecvError::NoAck => Self::CTAP1_ERR_TIMEOUT,
+            SendOrRecvError::NoMemory => Self::CTAP2_ERR_VENDOR_INTERNAL_ERROR,
+            SendOrRecvError::Unavailable => Self::CTAP2_ERR_VENDOR_HARDWARE_FAILURE,
+            SendOrRecvError::Other => Self::CTAP1_ERR_OTHER,
+        }
+    }
+}
+
 impl From<UserPresenceError> for Ctap2StatusCode {
     fn from(user_presence_error: UserPresenceError) -> Self {
         match user_presence_error {
@@ -96,6 +118,16 @@ impl From<UserPresenceError> for Ctap2StatusCode {
     }
 }
 
+impl From<UserVerificationError> for Ctap2StatusCode {
+    fn from(user_verification_error: UserVerificationError) -> Self {
+        match user_verification_error {
+            UserVerificationError::Invalid => Self::CTAP2_ERR_UV_INVALID,
+            UserVerificationError::Blocked => Self::CTAP2_ERR_UV_BLOCKED,
+            UserVerificationError::Timeout => Self::CTAP2_ERR_USER_ACTION_TIMEOUT,
+        }
+    }
+}
+
 impl From<key_store::Error> for Ctap2StatusCode {
     fn from(_: key_store::Error) -> Self {
         Self::CTAP2_ERR_VENDOR_INTERNAL_ERROR
@@ -112,3 +144,55 @@ impl From<attestation_store::Error> for Ctap2StatusCode {
         }
     }
 }
+
+impl From<signature_counter::Error> for Ctap2StatusCode {
+    fn from(error: signature_counter::Error) -> Self {
+        use signature_counter::Error;
+        match error {
+            Error::Storage => Self::CTAP2_ERR_VENDOR_HARDWARE_FAILURE,
+            Error::Internal => Self::CTAP2_ERR_VENDOR_INTERNAL_ERROR,
+            Error::NoCapacity => Self::CTAP2_ERR_KEY_STORE_FULL,
+            Error::Exhausted => Self::CTAP2_ERR_LIMIT_EXCEEDED,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_send_or_recv_error() {
+        let expected = [
+            (
+                SendOrRecvError::Busy,
+                Ctap2StatusCode::CTAP1_ERR_CHANNEL_BUSY,
+            ),
+            (
+                SendOrRecvError::InvalidParameter,
+                Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER,
+            ),
+            (
+                SendOrRecvError::InvalidLength,
+                Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH,
+            ),
+            (
+                SendOrRecvError::Canceled,
+                Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL,
+            ),
+            (SendOrRecvError::NoAck, Ctap2StatusCode::CTAP1_ERR_TIMEOUT),
+            (
+                SendOrRecvError::NoMemory,
+                Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR,
+            ),
+            (
+                SendOrRecvError::Unavailable,
+                Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE,
+            ),
+            (SendOrRecvError::Other, Ctap2StatusCode::CTAP1_ERR_OTHER),
+        ];
+        for (error, status) in expected.iter() {
+            assert_eq!(Ctap2StatusCode::from(*error), *status);
+        }
+    }
+}
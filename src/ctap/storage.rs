@@ -14,26 +14,47 @@
 
 mod key;
 
+#[cfg(feature = "enterprise_attestation")]
 use crate::api::attestation_store::{self, AttestationStore};
 use crate::api::customization::Customization;
 use crate::api::key_store::KeyStore;
+use crate::api::signature_counter::SignatureCounter;
+use crate::api::store_encryption::{EncryptedStore, StoreEncryption};
 use crate::ctap::client_pin::PIN_AUTH_LENGTH;
-use crate::ctap::data_formats::{
-    extract_array, extract_text_string, PublicKeyCredentialSource, PublicKeyCredentialUserEntity,
-};
+#[cfg(feature = "cred_management")]
+use crate::ctap::data_formats::PublicKeyCredentialUserEntity;
+use crate::ctap::data_formats::{extract_array, extract_text_string, PublicKeyCredentialSource};
 use crate::ctap::status_code::Ctap2StatusCode;
-use crate::ctap::{key_material, INITIAL_SIGNATURE_COUNTER};
 use crate::env::Env;
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use arrayref::array_ref;
+#[cfg(feature = "large_blobs")]
 use core::cmp;
 use core::convert::TryInto;
-use persistent_store::{fragment, StoreUpdate};
+#[cfg(feature = "large_blobs")]
+use crypto::sha256::Sha256;
+#[cfg(feature = "large_blobs")]
+use crypto::Hash256;
+use persistent_store::{StoreError, StoreUpdate};
 use rng256::Rng256;
 use sk_cbor::cbor_array_vec;
 
+/// Version of the format of serialized credentials.
+///
+/// A serialized credential is this version byte followed by the CBOR map of the credential. CBOR
+/// fixes the byte order of all integers, so the format doesn't depend on the target or the struct
+/// layout. Credentials written before the version byte was introduced start with a CBOR map header,
+/// so the bytes 0xA0 to 0xBF (major type 5) are reserved and must never be used as a version.
+const CREDENTIAL_FORMAT_VERSION: u8 = 0x01;
+
+/// Length of the serialized PIN properties: the code point length followed by the PIN hash.
+const PIN_PROPERTIES_LENGTH: usize = 1 + PIN_AUTH_LENGTH;
+
+/// Length of the CredRandom secrets: first without UV, then with UV.
+const CRED_RANDOM_SECRET_LENGTH: usize = 64;
+
 /// Wrapper for PIN properties.
 struct PinProperties {
     /// 16 byte prefix of SHA256 of the currently set PIN.
@@ -45,27 +66,87 @@ struct PinProperties {
 
 /// Initializes the store by creating missing objects.
 pub fn init(env: &mut impl Env) -> Result<(), Ctap2StatusCode> {
-    // Generate and store the CredRandom secrets if they are missing.
-    if env.store().find_handle(key::CRED_RANDOM_SECRET)?.is_none() {
+    // The aaguid isn't stored anymore.
+    env.store().remove(key::_AAGUID)?;
+
+    // Generate the CredRandom secrets if they are missing.
+    let cred_random = if env.store().find_handle(key::CRED_RANDOM_SECRET)?.is_none() {
         let cred_random_with_uv = env.rng().gen_uniform_u8x32();
         let cred_random_without_uv = env.rng().gen_uniform_u8x32();
-        let mut cred_random = Vec::with_capacity(64);
+        let mut cred_random = Vec::with_capacity(CRED_RANDOM_SECRET_LENGTH);
         cred_random.extend_from_slice(&cred_random_without_uv);
         cred_random.extend_from_slice(&cred_random_with_uv);
-        env.store().insert(key::CRED_RANDOM_SECRET, &cred_random)?;
+        Some(cred_random)
+    } else {
+        None
+    };
+
+    let mut credential_keys = Vec::new();
+    for handle in env.store().iter_range(key::CREDENTIALS)? {
+        credential_keys.push(handle?.get_key());
+    }
+    let mut store = EncryptedStore::new(env)?;
+    if let Some(cred_random) = cred_random {
+        store.insert(key::CRED_RANDOM_SECRET, &cred_random)?;
     }
+    encrypt_plaintext_entries(&mut store, &credential_keys)?;
+    Ok(())
+}
 
-    if env.store().find_handle(key::AAGUID)?.is_none() {
-        set_aaguid(env, key_material::AAGUID)?;
+/// Encrypts the entries that were stored in plaintext by previous versions.
+///
+/// The credentials, the secrets, the RP IDs allowed to read the minimum PIN length and the large
+/// blob array are encrypted. Other entries hold public values or counters, and stay in plaintext.
+/// A value that fails decryption is only encrypted if it is a valid plaintext for its entry, so
+/// that tampered values are still rejected.
+fn encrypt_plaintext_entries<E: Env>(
+    store: &mut EncryptedStore<E>,
+    credential_keys: &[usize],
+) -> Result<(), Ctap2StatusCode> {
+    for &key in credential_keys {
+        store.encrypt_plaintext(key, |value| {
+            deserialize_plaintext_credential(value).is_some()
+        })?;
     }
+    store.encrypt_plaintext(key::CRED_RANDOM_SECRET, |value| {
+        value.len() == CRED_RANDOM_SECRET_LENGTH
+    })?;
+    store.encrypt_plaintext(key::PIN_PROPERTIES, |value| {
+        value.len() == PIN_PROPERTIES_LENGTH
+    })?;
+    store.encrypt_plaintext(key::MIN_PIN_LENGTH_RP_IDS, |value| {
+        deserialize_min_pin_length_rp_ids(value).is_some()
+    })?;
+    #[cfg(feature = "large_blobs")]
+    store.encrypt_plaintext_fragments(&key::LARGE_BLOB_SHARDS, is_large_blob_array)?;
     Ok(())
 }
 
+/// Reads and decrypts the value of a secret entry.
+///
+/// # Errors
+///
+/// Returns `CTAP2_ERR_VENDOR_HARDWARE_FAILURE` if the value fails authentication, and
+/// `CTAP2_ERR_VENDOR_INTERNAL_ERROR` if it doesn't have the expected length.
+fn find_secret(
+    env: &mut impl Env,
+    key: usize,
+    length: usize,
+) -> Result<Option<Vec<u8>>, Ctap2StatusCode> {
+    match EncryptedStore::new(env)?.find(key)? {
+        Some(plaintext) if plaintext.len() != length => {
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)
+        }
+        plaintext => Ok(plaintext),
+    }
+}
+
 /// Returns the credential at the given key.
 ///
 /// # Errors
 ///
-/// Returns `CTAP2_ERR_VENDOR_INTERNAL_ERROR` if the key does not hold a valid credential.
+/// Returns `CTAP2_ERR_VENDOR_INTERNAL_ERROR` if the key does not hold a valid credential, and
+/// `CTAP2_ERR_VENDOR_UNSUPPORTED_FORMAT` if the credential format version is unknown.
 pub fn get_credential(
     env: &mut impl Env,
     key: usize,
@@ -74,12 +155,10 @@ pub fn get_credential(
     if key < min_key || key >= min_key + env.customization().max_supported_resident_keys() {
         return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
     }
-    let credential_entry = env
-        .store()
+    let credential_entry = EncryptedStore::new(env)?
         .find(key)?
         .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
     deserialize_credential(&credential_entry)
-        .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)
 }
 
 /// Finds the key and value for a given credential ID.
@@ -93,10 +172,19 @@ pub fn find_credential_item(
 ) -> Result<(usize, PublicKeyCredentialSource), Ctap2StatusCode> {
     let mut iter_result = Ok(());
     let iter = iter_credentials(env, &mut iter_result)?;
-    let mut credentials: Vec<(usize, PublicKeyCredentialSource)> = iter
+    let credential = select_credential(iter, credential_id);
+    iter_result?;
+    credential
+}
+
+/// Returns the only credential of the iterator with the given ID.
+fn select_credential(
+    credentials: impl Iterator<Item = (usize, PublicKeyCredentialSource)>,
+    credential_id: &[u8],
+) -> Result<(usize, PublicKeyCredentialSource), Ctap2StatusCode> {
+    let mut credentials: Vec<(usize, PublicKeyCredentialSource)> = credentials
         .filter(|(_, credential)| credential.credential_id == credential_id)
         .collect();
-    iter_result?;
     if credentials.len() > 1 {
         return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
     }
@@ -127,38 +215,49 @@ pub fn find_credential(
 
 /// Stores or updates a credential.
 ///
-/// If a credential with the same RP id and user handle already exists, it is replaced.
+/// If a credential with the same RP id and user handle already exists, it is replaced. The new
+/// credential is written over the old one in a single store update, so exactly one of them exists
+/// at any time. The signature counter of the replaced credential is removed, and the new
+/// credential gets its own counter if one is free.
 pub fn store_credential(
     env: &mut impl Env,
     new_credential: PublicKeyCredentialSource,
 ) -> Result<(), Ctap2StatusCode> {
     let max_supported_resident_keys = env.customization().max_supported_resident_keys();
-    // Holds the key of the existing credential if this is an update.
-    let mut old_key = None;
+    // Holds the key and ID of the existing credential if this is an update.
+    let mut old_credential = None;
     let min_key = key::CREDENTIALS.start;
     // Holds whether a key is used (indices are shifted by min_key).
     let mut keys = vec![false; max_supported_resident_keys];
-    let mut iter_result = Ok(());
-    let iter = iter_credentials(env, &mut iter_result)?;
-    for (key, credential) in iter {
+    // Keys are marked without deserializing, so that credentials of unknown format version are
+    // never overwritten.
+    for handle in env.store().iter_range(key::CREDENTIALS)? {
+        let key = handle?.get_key();
         if key < min_key || key - min_key >= max_supported_resident_keys || keys[key - min_key] {
             return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
         }
         keys[key - min_key] = true;
+    }
+    let mut iter_result = Ok(());
+    let mut iter = iter_credentials(env, &mut iter_result)?;
+    for (key, credential) in &mut iter {
         if credential.rp_id == new_credential.rp_id
             && credential.user_handle == new_credential.user_handle
         {
-            if old_key.is_some() {
+            if old_credential.is_some() {
                 return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
             }
-            old_key = Some(key);
+            old_credential = Some((key, credential.credential_id));
         }
     }
+    let encryption = iter.into_encryption();
     iter_result?;
-    if old_key.is_none() && keys.iter().filter(|&&x| x).count() >= max_supported_resident_keys {
+    if old_credential.is_none()
+        && keys.iter().filter(|&&x| x).count() >= max_supported_resident_keys
+    {
         return Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL);
     }
-    let key = match old_key {
+    let key = match &old_credential {
         // This is a new credential being added, we need to allocate a free key. We choose the
         // first available key.
         None => key::CREDENTIALS
@@ -166,11 +265,34 @@ pub fn store_credential(
             .find(|key| !keys[key - min_key])
             .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?,
         // This is an existing credential being updated, we reuse its key.
-        Some(x) => x,
+        Some((x, _)) => *x,
     };
-    let value = serialize_credential(new_credential)?;
-    env.store().insert(key, &value)?;
-    Ok(())
+    // The replaced credential can't sign anymore, so its counter would be stale.
+    let stale_counter_key = match old_credential {
+        Some((_, old_credential_id)) if old_credential_id != new_credential.credential_id => env
+            .signature_counter()
+            .counter_storage_key(&old_credential_id)?,
+        _ => None,
+    };
+    // Only resident credentials get their own counter, since they are bounded in number.
+    let new_counter = env
+        .signature_counter()
+        .new_counter_entry(&new_credential.credential_id)?;
+    let value = serialize_credential(&encryption, env.rng(), key, new_credential)?;
+    let mut updates = vec![StoreUpdate::Insert {
+        key,
+        value: &value[..],
+    }];
+    if let Some(key) = stale_counter_key {
+        updates.push(StoreUpdate::Remove { key });
+    }
+    if let Some((key, value)) = &new_counter {
+        updates.push(StoreUpdate::Insert {
+            key: *key,
+            value: &value[..],
+        });
+    }
+    Ok(env.store().transaction(&updates)?)
 }
 
 /// Deletes a credential.
@@ -178,9 +300,14 @@ pub fn store_credential(
 /// # Errors
 ///
 /// Returns `CTAP2_ERR_NO_CREDENTIALS` if the credential is not found.
+#[cfg(feature = "cred_management")]
 pub fn delete_credential(env: &mut impl Env, credential_id: &[u8]) -> Result<(), Ctap2StatusCode> {
     let (key, _) = find_credential_item(env, credential_id)?;
-    Ok(env.store().remove(key)?)
+    let mut updates: Vec<StoreUpdate<&[u8]>> = vec![StoreUpdate::Remove { key }];
+    if let Some(key) = env.signature_counter().counter_storage_key(credential_id)? {
+        updates.push(StoreUpdate::Remove { key });
+    }
+    Ok(env.store().transaction(&updates)?)
 }
 
 /// Updates a credential's user information.
@@ -188,24 +315,31 @@ pub fn delete_credential(env: &mut impl Env, credential_id: &[u8]) -> Result<(),
 /// # Errors
 ///
 /// Returns `CTAP2_ERR_NO_CREDENTIALS` if the credential is not found.
+#[cfg(feature = "cred_management")]
 pub fn update_credential(
     env: &mut impl Env,
     credential_id: &[u8],
     user: PublicKeyCredentialUserEntity,
 ) -> Result<(), Ctap2StatusCode> {
-    let (key, mut credential) = find_credential_item(env, credential_id)?;
+    let mut iter_result = Ok(());
+    let mut iter = iter_credentials(env, &mut iter_result)?;
+    let credential = select_credential(&mut iter, credential_id);
+    let encryption = iter.into_encryption();
+    iter_result?;
+    let (key, mut credential) = credential?;
     credential.user_name = user.user_name;
     credential.user_display_name = user.user_display_name;
     credential.user_icon = user.user_icon;
-    let value = serialize_credential(credential)?;
+    let value = serialize_credential(&encryption, env.rng(), key, credential)?;
     Ok(env.store().insert(key, &value)?)
 }
 
 /// Returns the number of credentials.
 pub fn count_credentials(env: &mut impl Env) -> Result<usize, Ctap2StatusCode> {
     let mut count = 0;
-    for handle in env.store().iter()? {
-        count += key::CREDENTIALS.contains(&handle?.get_key()) as usize;
+    for handle in env.store().iter_range(key::CREDENTIALS)? {
+        handle?;
+        count += 1;
     }
     Ok(count)
 }
@@ -220,12 +354,22 @@ pub fn remaining_credentials(env: &mut impl Env) -> Result<usize, Ctap2StatusCod
 
 /// Iterates through the credentials.
 ///
-/// If an error is encountered during iteration, it is written to `result`.
+/// If an error is encountered during iteration, it is written to `result`. Credentials with an
+/// unknown format version are skipped.
 pub fn iter_credentials<'a, E: Env>(
     env: &'a mut E,
     result: &'a mut Result<(), Ctap2StatusCode>,
 ) -> Result<IterCredentials<'a, E>, Ctap2StatusCode> {
-    IterCredentials::new(env.store(), result)
+    let encryption = StoreEncryption::new(env)?;
+    #[cfg(feature = "debug_ctap")]
+    let write = env.write();
+    IterCredentials::new(
+        env.store(),
+        encryption,
+        #[cfg(feature = "debug_ctap")]
+        write,
+        result,
+    )
 }
 
 /// Returns the next creation order.
@@ -237,55 +381,24 @@ pub fn new_creation_order(env: &mut impl Env) -> Result<u64, Ctap2StatusCode> {
     Ok(max.unwrap_or(0).wrapping_add(1))
 }
 
-/// Returns the global signature counter.
-pub fn global_signature_counter(env: &mut impl Env) -> Result<u32, Ctap2StatusCode> {
-    match env.store().find(key::GLOBAL_SIGNATURE_COUNTER)? {
-        None => Ok(INITIAL_SIGNATURE_COUNTER),
-        Some(value) if value.len() == 4 => Ok(u32::from_ne_bytes(*array_ref!(&value, 0, 4))),
-        Some(_) => Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR),
-    }
-}
-
-/// Increments the global signature counter.
-pub fn incr_global_signature_counter(
-    env: &mut impl Env,
-    increment: u32,
-) -> Result<(), Ctap2StatusCode> {
-    let old_value = global_signature_counter(env)?;
-    // In hopes that servers handle the wrapping gracefully.
-    let new_value = old_value.wrapping_add(increment);
-    env.store()
-        .insert(key::GLOBAL_SIGNATURE_COUNTER, &new_value.to_ne_bytes())?;
-    Ok(())
-}
-
 /// Returns the CredRandom secret.
 pub fn cred_random_secret(env: &mut impl Env, has_uv: bool) -> Result<[u8; 32], Ctap2StatusCode> {
-    let cred_random_secret = env
-        .store()
-        .find(key::CRED_RANDOM_SECRET)?
+    let cred_random_secret = find_secret(env, key::CRED_RANDOM_SECRET, CRED_RANDOM_SECRET_LENGTH)?
         .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
-    if cred_random_secret.len() != 64 {
-        return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
-    }
     let offset = if has_uv { 32 } else { 0 };
     Ok(*array_ref![cred_random_secret, offset, 32])
 }
 
 /// Reads the PIN properties and wraps them into PinProperties.
 fn pin_properties(env: &mut impl Env) -> Result<Option<PinProperties>, Ctap2StatusCode> {
-    let pin_properties = match env.store().find(key::PIN_PROPERTIES)? {
+    let pin_properties = match find_secret(env, key::PIN_PROPERTIES, PIN_PROPERTIES_LENGTH)? {
         None => return Ok(None),
         Some(pin_properties) => pin_properties,
     };
-    const PROPERTIES_LENGTH: usize = PIN_AUTH_LENGTH + 1;
-    match pin_properties.len() {
-        PROPERTIES_LENGTH => Ok(Some(PinProperties {
-            hash: *array_ref![pin_properties, 1, PIN_AUTH_LENGTH],
-            code_point_length: pin_properties[0],
-        })),
-        _ => Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR),
-    }
+    Ok(Some(PinProperties {
+        hash: *array_ref![pin_properties, 1, PIN_AUTH_LENGTH],
+        code_point_length: pin_properties[0],
+    }))
 }
 
 /// Returns the PIN hash if defined.
@@ -306,13 +419,14 @@ pub fn set_pin(
     pin_hash: &[u8; PIN_AUTH_LENGTH],
     pin_code_point_length: u8,
 ) -> Result<(), Ctap2StatusCode> {
-    let mut pin_properties = [0; 1 + PIN_AUTH_LENGTH];
+    let mut pin_properties = [0; PIN_PROPERTIES_LENGTH];
     pin_properties[0] = pin_code_point_length;
     pin_properties[1..].clone_from_slice(pin_hash);
+    let value = EncryptedStore::new(env)?.encrypt(key::PIN_PROPERTIES, &pin_properties);
     Ok(env.store().transaction(&[
         StoreUpdate::Insert {
             key: key::PIN_PROPERTIES,
-            value: &pin_properties[..],
+            value: &value[..],
         },
         StoreUpdate::Remove {
             key: key::FORCE_PIN_CHANGE,
@@ -361,7 +475,8 @@ pub fn set_min_pin_length(env: &mut impl Env, min_pin_length: u8) -> Result<(),
 /// Returns the list of RP IDs that are used to check if reading the minimum PIN length is
 /// allowed.
 pub fn min_pin_length_rp_ids(env: &mut impl Env) -> Result<Vec<String>, Ctap2StatusCode> {
-    let rp_ids = env.store().find(key::MIN_PIN_LENGTH_RP_IDS)?.map_or_else(
+    let value = EncryptedStore::new(env)?.find(key::MIN_PIN_LENGTH_RP_IDS)?;
+    let rp_ids = value.map_or_else(
         || Some(env.customization().default_min_pin_length_rp_ids()),
         |value| deserialize_min_pin_length_rp_ids(&value),
     );
@@ -374,7 +489,46 @@ pub fn set_min_pin_length_rp_ids(
     env: &mut impl Env,
     min_pin_length_rp_ids: Vec<String>,
 ) -> Result<(), Ctap2StatusCode> {
-    let mut min_pin_length_rp_ids = min_pin_length_rp_ids;
+    let value = build_min_pin_length_rp_ids(env, min_pin_length_rp_ids)?;
+    Ok(EncryptedStore::new(env)?.insert(key::MIN_PIN_LENGTH_RP_IDS, &value)?)
+}
+
+/// Atomically updates the minimum PIN length policy.
+///
+/// The minimum PIN length, the optional list of RP IDs and the optional forced PIN change are
+/// written in a single store transaction, so that an interrupted update leaves either the old or
+/// the new policy, never a mix of both.
+pub fn set_min_pin_length_policy(
+    env: &mut impl Env,
+    min_pin_length: u8,
+    min_pin_length_rp_ids: Option<Vec<String>>,
+    force_pin_change: bool,
+) -> Result<(), Ctap2StatusCode> {
+    let mut updates = vec![StoreUpdate::Insert {
+        key: key::MIN_PIN_LENGTH,
+        value: vec![min_pin_length],
+    }];
+    if let Some(min_pin_length_rp_ids) = min_pin_length_rp_ids {
+        let plaintext = build_min_pin_length_rp_ids(env, min_pin_length_rp_ids)?;
+        updates.push(StoreUpdate::Insert {
+            key: key::MIN_PIN_LENGTH_RP_IDS,
+            value: EncryptedStore::new(env)?.encrypt(key::MIN_PIN_LENGTH_RP_IDS, &plaintext),
+        });
+    }
+    if force_pin_change {
+        updates.push(StoreUpdate::Insert {
+            key: key::FORCE_PIN_CHANGE,
+            value: vec![],
+        });
+    }
+    Ok(env.store().transaction(&updates)?)
+}
+
+/// Adds the default RP IDs and serializes the list, checking its length.
+fn build_min_pin_length_rp_ids(
+    env: &mut impl Env,
+    mut min_pin_length_rp_ids: Vec<String>,
+) -> Result<Vec<u8>, Ctap2StatusCode> {
     for rp_id in env.customization().default_min_pin_length_rp_ids() {
         if !min_pin_length_rp_ids.contains(&rp_id) {
             min_pin_length_rp_ids.push(rp_id);
@@ -383,42 +537,40 @@ pub fn set_min_pin_length_rp_ids(
     if min_pin_length_rp_ids.len() > env.customization().max_rp_ids_length() {
         return Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL);
     }
-    Ok(env.store().insert(
-        key::MIN_PIN_LENGTH_RP_IDS,
-        &serialize_min_pin_length_rp_ids(min_pin_length_rp_ids)?,
-    )?)
+    serialize_min_pin_length_rp_ids(min_pin_length_rp_ids)
 }
 
 /// Reads the byte vector stored as the serialized large blobs array.
 ///
 /// If too few bytes exist at that offset, return the maximum number
 /// available. This includes cases of offset being beyond the stored array.
+/// The array is encrypted as a whole, so it is read entirely.
 ///
 /// If no large blob is committed to the store, get responds as if an empty
 /// CBOR array (0x80) was written, together with the 16 byte prefix of its
 /// SHA256, to a total length of 17 byte (which is the shortest legitimate
 /// large blob entry possible).
+#[cfg(feature = "large_blobs")]
 pub fn get_large_blob_array(
     env: &mut impl Env,
     offset: usize,
     byte_count: usize,
 ) -> Result<Vec<u8>, Ctap2StatusCode> {
-    let byte_range = offset..offset + byte_count;
-    let output = fragment::read_range(env.store(), &key::LARGE_BLOB_SHARDS, byte_range)?;
-    Ok(output.unwrap_or_else(|| {
-        const EMPTY_LARGE_BLOB: [u8; 17] = [
-            0x80, 0x76, 0xBE, 0x8B, 0x52, 0x8D, 0x00, 0x75, 0xF7, 0xAA, 0xE9, 0x8D, 0x6F, 0xA5,
-            0x7A, 0x6D, 0x3C,
-        ];
-        let last_index = cmp::min(EMPTY_LARGE_BLOB.len(), offset + byte_count);
-        EMPTY_LARGE_BLOB
-            .get(offset..last_index)
-            .unwrap_or_default()
-            .to_vec()
-    }))
+    const EMPTY_LARGE_BLOB: [u8; 17] = [
+        0x80, 0x76, 0xBE, 0x8B, 0x52, 0x8D, 0x00, 0x75, 0xF7, 0xAA, 0xE9, 0x8D, 0x6F, 0xA5, 0x7A,
+        0x6D, 0x3C,
+    ];
+    let stored_array = EncryptedStore::new(env)?.read_fragments(&key::LARGE_BLOB_SHARDS)?;
+    let large_blob_array = stored_array.as_deref().unwrap_or(&EMPTY_LARGE_BLOB);
+    let end = cmp::min(offset.saturating_add(byte_count), large_blob_array.len());
+    Ok(large_blob_array
+        .get(offset..end)
+        .unwrap_or_default()
+        .to_vec())
 }
 
 /// Sets a byte vector as the serialized large blobs array.
+#[cfg(feature = "large_blobs")]
 pub fn commit_large_blob_array(
     env: &mut impl Env,
     large_blob_array: &[u8],
@@ -427,33 +579,19 @@ pub fn commit_large_blob_array(
     if large_blob_array.len() > env.customization().max_large_blob_array_size() {
         return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
     }
-    Ok(fragment::write(
-        env.store(),
-        &key::LARGE_BLOB_SHARDS,
-        large_blob_array,
-    )?)
+    Ok(EncryptedStore::new(env)?.write_fragments(&key::LARGE_BLOB_SHARDS, large_blob_array)?)
 }
 
-/// Returns the AAGUID.
-pub fn aaguid(env: &mut impl Env) -> Result<[u8; key_material::AAGUID_LENGTH], Ctap2StatusCode> {
-    let aaguid = env
-        .store()
-        .find(key::AAGUID)?
-        .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
-    if aaguid.len() != key_material::AAGUID_LENGTH {
-        return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
+/// Returns whether a value is a large blob array followed by its truncated hash.
+#[cfg(feature = "large_blobs")]
+fn is_large_blob_array(value: &[u8]) -> bool {
+    const TRUNCATED_HASH_LEN: usize = 16;
+    match value.len().checked_sub(TRUNCATED_HASH_LEN) {
+        Some(hash_index) if hash_index > 0 => {
+            Sha256::hash(&value[..hash_index])[..TRUNCATED_HASH_LEN] == value[hash_index..]
+        }
+        _ => false,
     }
-    Ok(*array_ref![aaguid, 0, key_material::AAGUID_LENGTH])
-}
-
-/// Sets the AAGUID.
-///
-/// If it is already defined, it is overwritten.
-pub fn set_aaguid(
-    env: &mut impl Env,
-    aaguid: &[u8; key_material::AAGUID_LENGTH],
-) -> Result<(), Ctap2StatusCode> {
-    Ok(env.store().insert(key::AAGUID, aaguid)?)
 }
 
 /// Resets the store as for a CTAP reset.
@@ -481,6 +619,7 @@ pub fn force_pin_change(env: &mut impl Env) -> Result<(), Ctap2StatusCode> {
 }
 
 /// Returns whether enterprise attestation is enabled.
+#[cfg(feature = "enterprise_attestation")]
 pub fn enterprise_attestation(env: &mut impl Env) -> Result<bool, Ctap2StatusCode> {
     match env.store().find(key::ENTERPRISE_ATTESTATION)? {
         None => Ok(false),
@@ -490,6 +629,7 @@ pub fn enterprise_attestation(env: &mut impl Env) -> Result<bool, Ctap2StatusCod
 }
 
 /// Marks enterprise attestation as enabled.
+#[cfg(feature = "enterprise_attestation")]
 pub fn enable_enterprise_attestation(env: &mut impl Env) -> Result<(), Ctap2StatusCode> {
     if env
         .attestation_store()
@@ -504,6 +644,23 @@ pub fn enable_enterprise_attestation(env: &mut impl Env) -> Result<(), Ctap2Stat
     Ok(())
 }
 
+/// Returns whether the batch attestation is locked.
+pub fn attestation_locked(env: &mut impl Env) -> Result<bool, Ctap2StatusCode> {
+    match env.store().find(key::ATTESTATION_LOCKED)? {
+        None => Ok(false),
+        Some(value) if value.is_empty() => Ok(true),
+        _ => Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR),
+    }
+}
+
+/// Prevents provisioning the batch attestation from now on.
+pub fn lock_attestation(env: &mut impl Env) -> Result<(), Ctap2StatusCode> {
+    if !attestation_locked(env)? {
+        env.store().insert(key::ATTESTATION_LOCKED, &[])?;
+    }
+    Ok(())
+}
+
 /// Returns whether alwaysUv is enabled.
 pub fn has_always_uv(env: &mut impl Env) -> Result<bool, Ctap2StatusCode> {
     if env.customization().enforce_always_uv() {
@@ -544,6 +701,8 @@ impl From<persistent_store::StoreError> for Ctap2StatusCode {
             StoreError::InvalidStorage => Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE,
             // This error is not expected. The kernel is failing our syscalls.
             StoreError::StorageError => Ctap2StatusCode::CTAP1_ERR_OTHER,
+            // This error is not expected. The storage was accessed during another operation.
+            StoreError::Busy => Ctap2StatusCode::CTAP1_ERR_OTHER,
         }
     }
 }
@@ -556,6 +715,13 @@ pub struct IterCredentials<'a, E: Env> {
     /// The store iterator.
     iter: persistent_store::StoreIter<'a>,
 
+    /// Decrypts the credentials.
+    encryption: StoreEncryption,
+
+    /// Logs the skipped credentials.
+    #[cfg(feature = "debug_ctap")]
+    write: E::Write,
+
     /// The iteration result.
     ///
     /// It starts as success and gets written at most once with an error if something fails. The
@@ -567,16 +733,32 @@ impl<'a, E: Env> IterCredentials<'a, E> {
     /// Creates a credential iterator.
     fn new(
         store: &'a persistent_store::Store<E::Storage>,
+        encryption: StoreEncryption,
+        #[cfg(feature = "debug_ctap")] write: E::Write,
         result: &'a mut Result<(), Ctap2StatusCode>,
     ) -> Result<Self, Ctap2StatusCode> {
-        let iter = store.iter()?;
+        let iter = store.iter_range(key::CREDENTIALS)?;
         Ok(IterCredentials {
             store,
             iter,
+            encryption,
+            #[cfg(feature = "debug_ctap")]
+            write,
             result,
         })
     }
 
+    /// Returns the encryption, to write credentials after iterating.
+    fn into_encryption(self) -> StoreEncryption {
+        self.encryption
+    }
+
+    /// Returns the debug writer, for use with `debug_ctap`.
+    #[cfg(feature = "debug_ctap")]
+    fn write(&mut self) -> &mut E::Write {
+        &mut self.write
+    }
+
     /// Marks the iteration as failed if the content is absent.
     ///
     /// For convenience, the function takes and returns ownership instead of taking a shared
@@ -597,31 +779,68 @@ impl<'a, E: Env> Iterator for IterCredentials<'a, E> {
         if self.result.is_err() {
             return None;
         }
-        while let Some(next) = self.iter.next() {
-            let handle = self.unwrap(next.ok())?;
+        loop {
+            let handle = self.unwrap(self.iter.next()?.ok())?;
             let key = handle.get_key();
-            if !key::CREDENTIALS.contains(&key) {
-                continue;
-            }
             let value = self.unwrap(handle.get_value(self.store).ok())?;
-            let credential = self.unwrap(deserialize_credential(&value))?;
-            return Some((key, credential));
+            let credential = match self.encryption.decrypt(key, &value) {
+                None => Err(Ctap2StatusCode::from(StoreError::InvalidStorage)),
+                Some(plaintext) => deserialize_credential(&plaintext),
+            };
+            match credential {
+                Ok(credential) => return Some((key, credential)),
+                // Credentials written by a newer firmware don't prevent using the others.
+                Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_UNSUPPORTED_FORMAT) => {
+                    debug_ctap!(
+                        self,
+                        "Skipping credential {} of unknown format version.",
+                        key
+                    );
+                }
+                Err(error) => {
+                    *self.result = Err(error);
+                    return None;
+                }
+            }
         }
-        None
     }
 }
 
-/// Deserializes a credential from storage representation.
-fn deserialize_credential(data: &[u8]) -> Option<PublicKeyCredentialSource> {
+/// Deserializes a credential from its decrypted storage representation.
+///
+/// # Errors
+///
+/// Returns `CTAP2_ERR_VENDOR_UNSUPPORTED_FORMAT` if the credential has an unknown format version,
+/// and `CTAP2_ERR_VENDOR_INTERNAL_ERROR` if it is otherwise invalid.
+fn deserialize_credential(plaintext: &[u8]) -> Result<PublicKeyCredentialSource, Ctap2StatusCode> {
+    let credential = match plaintext.split_first() {
+        Some((&CREDENTIAL_FORMAT_VERSION, cbor)) => deserialize_plaintext_credential(cbor),
+        // Credentials without version are a bare CBOR map, of major type 5.
+        Some((&header, _)) if header >> 5 == 5 => deserialize_plaintext_credential(plaintext),
+        Some(_) => return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_UNSUPPORTED_FORMAT),
+        None => None,
+    };
+    credential.ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)
+}
+
+/// Deserializes a credential from its CBOR representation.
+fn deserialize_plaintext_credential(data: &[u8]) -> Option<PublicKeyCredentialSource> {
     let cbor = super::cbor_read(data).ok()?;
     cbor.try_into().ok()
 }
 
-/// Serializes a credential to storage representation.
-fn serialize_credential(credential: PublicKeyCredentialSource) -> Result<Vec<u8>, Ctap2StatusCode> {
-    let mut data = Vec::new();
+/// Serializes a credential to storage representation for the given key.
+///
+/// Credentials are encrypted because they contain private keys and user information.
+fn serialize_credential(
+    encryption: &StoreEncryption,
+    rng: &mut impl Rng256,
+    key: usize,
+    credential: PublicKeyCredentialSource,
+) -> Result<Vec<u8>, Ctap2StatusCode> {
+    let mut data = vec![CREDENTIAL_FORMAT_VERSION];
     super::cbor_write(credential.into(), &mut data)?;
-    Ok(data)
+    Ok(encryption.encrypt(rng, key, &data))
 }
 
 /// Deserializes a list of RP IDs from storage representation.
@@ -646,11 +865,16 @@ fn serialize_min_pin_length_rp_ids(rp_ids: Vec<String>) -> Result<Vec<u8>, Ctap2
 mod test {
     use super::*;
     use crate::api::attestation_store::{self, Attestation, AttestationStore};
+    use crate::api::signature_counter::{SignatureCounterStrategy, CREDENTIAL_STORAGE_KEYS};
+    use crate::api::store_encryption::ENCRYPTION_OVERHEAD;
     use crate::ctap::crypto_wrapper::PrivateKey;
     use crate::ctap::data_formats::{
         CredentialProtectionPolicy, PublicKeyCredentialSource, PublicKeyCredentialType,
     };
+    use crate::ctap::{key_material, INITIAL_SIGNATURE_COUNTER};
     use crate::env::test::TestEnv;
+    #[cfg(feature = "large_blobs")]
+    use persistent_store::fragment;
     use rng256::Rng256;
 
     fn create_credential_source(
@@ -685,6 +909,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "cred_management")]
     fn test_delete_credential() {
         let mut env = TestEnv::new();
         assert_eq!(count_credentials(&mut env).unwrap(), 0);
@@ -706,6 +931,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "cred_management")]
     fn test_update_credential() {
         let mut env = TestEnv::new();
         let user = PublicKeyCredentialUserEntity {
@@ -824,6 +1050,109 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_overwrite_removes_signature_counter() {
+        let mut env = TestEnv::new();
+        env.customization_mut()
+            .set_signature_counter_strategy(SignatureCounterStrategy::PerCredential);
+        let credential_source0 = create_credential_source(&mut env, "example.com", vec![0x00]);
+        let credential_id0 = credential_source0.credential_id.clone();
+        assert_eq!(store_credential(&mut env, credential_source0), Ok(()));
+        assert_eq!(
+            env.signature_counter().increment_counter(&credential_id0),
+            Ok(INITIAL_SIGNATURE_COUNTER + 1)
+        );
+
+        // Updating the same credential keeps its counter.
+        let credential_source0 = find_credential(&mut env, "example.com", &credential_id0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(store_credential(&mut env, credential_source0), Ok(()));
+        assert_eq!(
+            env.signature_counter().get_counter(&credential_id0),
+            Ok(INITIAL_SIGNATURE_COUNTER + 1)
+        );
+
+        let credential_source1 = create_credential_source(&mut env, "example.com", vec![0x00]);
+        let credential_id1 = credential_source1.credential_id.clone();
+        assert_eq!(store_credential(&mut env, credential_source1), Ok(()));
+        assert_eq!(
+            env.signature_counter().counter_storage_key(&credential_id0),
+            Ok(None)
+        );
+        assert!(env
+            .signature_counter()
+            .counter_storage_key(&credential_id1)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_reset_removes_signature_counters() {
+        let mut env = TestEnv::new();
+        env.customization_mut()
+            .set_signature_counter_strategy(SignatureCounterStrategy::PerCredential);
+        let credential_source = create_credential_source(&mut env, "example.com", vec![0x00]);
+        let credential_id = credential_source.credential_id.clone();
+        assert_eq!(store_credential(&mut env, credential_source), Ok(()));
+        assert_eq!(
+            env.signature_counter().increment_counter(&credential_id),
+            Ok(INITIAL_SIGNATURE_COUNTER + 1)
+        );
+
+        reset(&mut env).unwrap();
+        for key in CREDENTIAL_STORAGE_KEYS {
+            assert_eq!(env.store().find_handle(key), Ok(None));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "cred_management")]
+    fn test_delete_credential_removes_signature_counter() {
+        let mut env = TestEnv::new();
+        env.customization_mut()
+            .set_signature_counter_strategy(SignatureCounterStrategy::PerCredential);
+        let credential_source = create_credential_source(&mut env, "example.com", vec![0x00]);
+        let credential_id = credential_source.credential_id.clone();
+        assert_eq!(store_credential(&mut env, credential_source), Ok(()));
+        assert!(env
+            .signature_counter()
+            .counter_storage_key(&credential_id)
+            .unwrap()
+            .is_some());
+
+        assert_eq!(delete_credential(&mut env, &credential_id), Ok(()));
+        for key in CREDENTIAL_STORAGE_KEYS {
+            assert_eq!(env.store().find_handle(key), Ok(None));
+        }
+    }
+
+    #[test]
+    fn test_overwrite_full_store() {
+        let mut env = TestEnv::new();
+        let max_supported_resident_keys = env.customization().max_supported_resident_keys();
+        for i in 0..max_supported_resident_keys {
+            let user_handle = (i as u32).to_ne_bytes().to_vec();
+            let credential_source = create_credential_source(&mut env, "example.com", user_handle);
+            assert!(store_credential(&mut env, credential_source).is_ok());
+        }
+        assert_eq!(remaining_credentials(&mut env), Ok(0));
+
+        // Replacing the credential of an existing user doesn't need a new slot.
+        let user_handle = 0u32.to_ne_bytes().to_vec();
+        let credential_source = create_credential_source(&mut env, "example.com", user_handle);
+        let credential_id = credential_source.credential_id.clone();
+        assert_eq!(store_credential(&mut env, credential_source), Ok(()));
+        assert!(find_credential(&mut env, "example.com", &credential_id)
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            count_credentials(&mut env).unwrap(),
+            max_supported_resident_keys
+        );
+        assert_eq!(remaining_credentials(&mut env), Ok(0));
+    }
+
     #[test]
     fn test_get_credential() {
         let mut env = TestEnv::new();
@@ -965,11 +1294,11 @@ mod test {
         let dummy_attestation = Attestation {
             private_key: [0x41; key_material::ATTESTATION_PRIVATE_KEY_LENGTH],
             certificate: vec![0xdd; 20],
+            intermediate_certificates: vec![],
         };
         env.attestation_store()
             .set(&attestation_store::Id::Batch, Some(&dummy_attestation))
             .unwrap();
-        assert_eq!(&aaguid(&mut env).unwrap(), key_material::AAGUID);
 
         // The persistent keys stay initialized and preserve their value after a reset.
         reset(&mut env).unwrap();
@@ -977,7 +1306,6 @@ mod test {
             env.attestation_store().get(&attestation_store::Id::Batch),
             Ok(Some(dummy_attestation))
         );
-        assert_eq!(&aaguid(&mut env).unwrap(), key_material::AAGUID);
     }
 
     #[test]
@@ -1017,18 +1345,54 @@ mod test {
         assert_eq!(min_pin_length_rp_ids(&mut env).unwrap(), rp_ids);
     }
 
+    #[test]
+    fn test_set_min_pin_length_policy() {
+        let mut env = TestEnv::new();
+
+        // Only the minimum PIN length is changed if nothing else is given.
+        assert_eq!(set_min_pin_length_policy(&mut env, 6, None, false), Ok(()));
+        assert_eq!(min_pin_length(&mut env), Ok(6));
+        assert_eq!(
+            min_pin_length_rp_ids(&mut env).unwrap(),
+            env.customization().default_min_pin_length_rp_ids()
+        );
+        assert_eq!(has_force_pin_change(&mut env), Ok(false));
+
+        // All values are changed together.
+        let rp_ids = vec![String::from("example.com")];
+        assert_eq!(
+            set_min_pin_length_policy(&mut env, 8, Some(rp_ids.clone()), true),
+            Ok(())
+        );
+        assert_eq!(min_pin_length(&mut env), Ok(8));
+        assert!(min_pin_length_rp_ids(&mut env)
+            .unwrap()
+            .contains(&rp_ids[0]));
+        assert_eq!(has_force_pin_change(&mut env), Ok(true));
+
+        // Nothing is changed if one of the values is invalid.
+        let too_many_rp_ids =
+            vec![String::from("example.com"); env.customization().max_rp_ids_length() + 1];
+        assert_eq!(
+            set_min_pin_length_policy(&mut env, 10, Some(too_many_rp_ids), true),
+            Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
+        );
+        assert_eq!(min_pin_length(&mut env), Ok(8));
+    }
+
     #[test]
     fn test_max_large_blob_array_size() {
         let mut env = TestEnv::new();
 
         assert!(
-            env.customization().max_large_blob_array_size()
+            env.customization().max_large_blob_array_size() + ENCRYPTION_OVERHEAD
                 <= env.store().max_value_length()
                     * (key::LARGE_BLOB_SHARDS.end - key::LARGE_BLOB_SHARDS.start)
         );
     }
 
     #[test]
+    #[cfg(feature = "large_blobs")]
     fn test_commit_get_large_blob_array() {
         let mut env = TestEnv::new();
 
@@ -1049,6 +1413,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "large_blobs")]
     fn test_commit_get_large_blob_array_overwrite() {
         let mut env = TestEnv::new();
 
@@ -1068,6 +1433,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "large_blobs")]
     fn test_commit_get_large_blob_array_no_commit() {
         let mut env = TestEnv::new();
 
@@ -1083,19 +1449,6 @@ mod test {
         assert_eq!(vec![0x3C], restored_large_blob_array);
     }
 
-    #[test]
-    fn test_global_signature_counter() {
-        let mut env = TestEnv::new();
-
-        let mut counter_value = 1;
-        assert_eq!(global_signature_counter(&mut env).unwrap(), counter_value);
-        for increment in 1..10 {
-            assert!(incr_global_signature_counter(&mut env, increment).is_ok());
-            counter_value += increment;
-            assert_eq!(global_signature_counter(&mut env).unwrap(), counter_value);
-        }
-    }
-
     #[test]
     fn test_force_pin_change() {
         let mut env = TestEnv::new();
@@ -1108,12 +1461,14 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "enterprise_attestation")]
     fn test_enterprise_attestation() {
         let mut env = TestEnv::new();
 
         let dummy_attestation = Attestation {
             private_key: [0x41; key_material::ATTESTATION_PRIVATE_KEY_LENGTH],
             certificate: vec![0xdd; 20],
+            intermediate_certificates: vec![],
         };
         env.attestation_store()
             .set(&attestation_store::Id::Enterprise, Some(&dummy_attestation))
@@ -1163,11 +1518,256 @@ mod test {
             cred_blob: Some(vec![0xCB]),
             large_blob_key: Some(vec![0x1B]),
         };
-        let serialized = serialize_credential(credential.clone()).unwrap();
-        let reconstructed = deserialize_credential(&serialized).unwrap();
+        let key = key::CREDENTIALS.start;
+        let encryption = StoreEncryption::new(&mut env).unwrap();
+        let serialized =
+            serialize_credential(&encryption, env.rng(), key, credential.clone()).unwrap();
+        let plaintext = encryption.decrypt(key, &serialized).unwrap();
+        assert_eq!(plaintext[0], CREDENTIAL_FORMAT_VERSION);
+        let reconstructed = deserialize_credential(&plaintext).unwrap();
         assert_eq!(credential, reconstructed);
     }
 
+    #[test]
+    fn test_unsupported_credential_format_version() {
+        let mut env = TestEnv::new();
+        let supported = create_credential_source(&mut env, "example.com", vec![0x1E]);
+        let supported_id = supported.credential_id.clone();
+        assert!(store_credential(&mut env, supported).is_ok());
+        let credential_source = create_credential_source(&mut env, "example.com", vec![0x1D]);
+        let mut plaintext = vec![CREDENTIAL_FORMAT_VERSION + 1];
+        super::super::cbor_write(credential_source.into(), &mut plaintext).unwrap();
+        let unsupported_key = key::CREDENTIALS.start + 1;
+        let mut store = EncryptedStore::new(&mut env).unwrap();
+        store.insert(unsupported_key, &plaintext).unwrap();
+
+        assert_eq!(
+            get_credential(&mut env, unsupported_key),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_UNSUPPORTED_FORMAT)
+        );
+        // The unsupported credential is skipped, the others are still found.
+        let mut iter_result = Ok(());
+        let iter = iter_credentials(&mut env, &mut iter_result).unwrap();
+        let ids: Vec<_> = iter
+            .map(|(_, credential)| credential.credential_id)
+            .collect();
+        assert_eq!(ids, vec![supported_id]);
+        assert_eq!(iter_result, Ok(()));
+    }
+
+    #[test]
+    fn test_store_credential_keeps_unsupported_format_version() {
+        let mut env = TestEnv::new();
+        let credential_source = create_credential_source(&mut env, "example.com", vec![0x1D]);
+        let mut plaintext = vec![CREDENTIAL_FORMAT_VERSION + 1];
+        super::super::cbor_write(credential_source.into(), &mut plaintext).unwrap();
+        let unsupported_key = key::CREDENTIALS.start;
+        let mut store = EncryptedStore::new(&mut env).unwrap();
+        store.insert(unsupported_key, &plaintext).unwrap();
+
+        let credential_source = create_credential_source(&mut env, "example.com", vec![0x1E]);
+        let credential_id = credential_source.credential_id.clone();
+        assert!(store_credential(&mut env, credential_source).is_ok());
+        let (key, _) = find_credential_item(&mut env, &credential_id).unwrap();
+        assert_ne!(key, unsupported_key);
+        assert_eq!(
+            get_credential(&mut env, unsupported_key),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_UNSUPPORTED_FORMAT)
+        );
+        assert_eq!(count_credentials(&mut env).unwrap(), 2);
+        let max_supported_resident_keys = env.customization().max_supported_resident_keys();
+        assert_eq!(
+            remaining_credentials(&mut env).unwrap(),
+            max_supported_resident_keys - 2
+        );
+    }
+
+    #[test]
+    fn test_credential_encrypted_at_rest() {
+        let mut env = TestEnv::new();
+        let credential_source = create_credential_source(&mut env, "example.com", vec![0x1D]);
+        let credential_id = credential_source.credential_id.clone();
+        assert!(store_credential(&mut env, credential_source).is_ok());
+
+        let value = env.store().find(key::CREDENTIALS.start).unwrap().unwrap();
+        assert!(!value
+            .windows(credential_id.len())
+            .any(|window| window == &credential_id[..]));
+
+        // A value moved to another key is not accepted.
+        let other_key = key::CREDENTIALS.start + 1;
+        env.store().insert(other_key, &value).unwrap();
+        assert_eq!(
+            get_credential(&mut env, other_key),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE)
+        );
+
+        // A modified value is not accepted.
+        let mut modified_value = value;
+        modified_value[1] ^= 0x01;
+        env.store()
+            .insert(key::CREDENTIALS.start, &modified_value)
+            .unwrap();
+        assert_eq!(
+            get_credential(&mut env, key::CREDENTIALS.start),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE)
+        );
+    }
+
+    #[test]
+    fn test_encrypt_plaintext_credentials() {
+        let mut env = TestEnv::new();
+        let credential_source = create_credential_source(&mut env, "example.com", vec![0x1D]);
+        let mut plaintext = Vec::new();
+        super::super::cbor_write(credential_source.clone().into(), &mut plaintext).unwrap();
+        env.store()
+            .insert(key::CREDENTIALS.start, &plaintext)
+            .unwrap();
+        assert!(get_credential(&mut env, key::CREDENTIALS.start).is_err());
+
+        init(&mut env).unwrap();
+        let value = env.store().find(key::CREDENTIALS.start).unwrap().unwrap();
+        assert_ne!(value, plaintext);
+        assert_eq!(
+            get_credential(&mut env, key::CREDENTIALS.start),
+            Ok(credential_source)
+        );
+    }
+
+    #[test]
+    fn test_secrets_encrypted_at_rest() {
+        let mut env = TestEnv::new();
+        init(&mut env).unwrap();
+        let hash = [0x55; PIN_AUTH_LENGTH];
+        set_pin(&mut env, &hash, 4).unwrap();
+
+        let value = env.store().find(key::PIN_PROPERTIES).unwrap().unwrap();
+        assert!(!value
+            .windows(PIN_AUTH_LENGTH)
+            .any(|window| window == &hash[..]));
+        let cred_random = cred_random_secret(&mut env, false).unwrap();
+        let value = env.store().find(key::CRED_RANDOM_SECRET).unwrap().unwrap();
+        assert!(!value
+            .windows(cred_random.len())
+            .any(|window| window == &cred_random[..]));
+
+        // A modified value is not accepted.
+        for &key in [key::PIN_PROPERTIES, key::CRED_RANDOM_SECRET].iter() {
+            let mut value = env.store().find(key).unwrap().unwrap();
+            value[1] ^= 0x01;
+            env.store().insert(key, &value).unwrap();
+        }
+        assert_eq!(
+            pin_hash(&mut env),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE)
+        );
+        assert_eq!(
+            cred_random_secret(&mut env, false),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE)
+        );
+    }
+
+    #[test]
+    fn test_secrets_bound_to_their_key() {
+        let mut env = TestEnv::new();
+        init(&mut env).unwrap();
+        set_pin(&mut env, &[0x55; PIN_AUTH_LENGTH], 4).unwrap();
+
+        // The encrypted PIN properties are not accepted as CredRandom secrets, even after
+        // initializing the store again.
+        let value = env.store().find(key::PIN_PROPERTIES).unwrap().unwrap();
+        env.store().insert(key::CRED_RANDOM_SECRET, &value).unwrap();
+        init(&mut env).unwrap();
+        assert_eq!(
+            env.store().find(key::CRED_RANDOM_SECRET).unwrap(),
+            Some(value)
+        );
+        assert_eq!(
+            cred_random_secret(&mut env, false),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE)
+        );
+    }
+
+    #[test]
+    fn test_encrypt_plaintext_secrets() {
+        let mut env = TestEnv::new();
+        let mut pin_properties = [0x55; PIN_PROPERTIES_LENGTH];
+        pin_properties[0] = 4;
+        let cred_random = [0xC3; CRED_RANDOM_SECRET_LENGTH];
+        env.store()
+            .insert(key::PIN_PROPERTIES, &pin_properties)
+            .unwrap();
+        env.store()
+            .insert(key::CRED_RANDOM_SECRET, &cred_random)
+            .unwrap();
+        // Plaintext values are only accepted once, when initializing the store.
+        assert!(pin_hash(&mut env).is_err());
+
+        init(&mut env).unwrap();
+        assert_ne!(
+            env.store().find(key::PIN_PROPERTIES).unwrap().unwrap(),
+            pin_properties
+        );
+        assert_ne!(
+            env.store().find(key::CRED_RANDOM_SECRET).unwrap().unwrap(),
+            cred_random
+        );
+        assert_eq!(pin_hash(&mut env), Ok(Some([0x55; PIN_AUTH_LENGTH])));
+        assert_eq!(pin_code_point_length(&mut env), Ok(Some(4)));
+        assert_eq!(cred_random_secret(&mut env, false), Ok([0xC3; 32]));
+        assert_eq!(cred_random_secret(&mut env, true), Ok([0xC3; 32]));
+    }
+
+    #[test]
+    fn test_encrypt_plaintext_min_pin_length_rp_ids() {
+        let mut env = TestEnv::new();
+        let rp_ids = vec![String::from("example.com")];
+        let plaintext = serialize_min_pin_length_rp_ids(rp_ids.clone()).unwrap();
+        env.store()
+            .insert(key::MIN_PIN_LENGTH_RP_IDS, &plaintext)
+            .unwrap();
+
+        init(&mut env).unwrap();
+        let value = env
+            .store()
+            .find(key::MIN_PIN_LENGTH_RP_IDS)
+            .unwrap()
+            .unwrap();
+        assert!(!value
+            .windows(rp_ids[0].len())
+            .any(|window| window == rp_ids[0].as_bytes()));
+        assert_eq!(min_pin_length_rp_ids(&mut env), Ok(rp_ids));
+    }
+
+    #[test]
+    #[cfg(feature = "large_blobs")]
+    fn test_encrypt_plaintext_large_blob_array() {
+        let mut env = TestEnv::new();
+        let mut large_blob_array = vec![0x5A; 40];
+        large_blob_array.extend_from_slice(&Sha256::hash(&large_blob_array)[..16]);
+        fragment::write(env.store(), &key::LARGE_BLOB_SHARDS, &large_blob_array).unwrap();
+        assert_eq!(
+            get_large_blob_array(&mut env, 0, 56),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE)
+        );
+
+        init(&mut env).unwrap();
+        let value = fragment::read(env.store(), &key::LARGE_BLOB_SHARDS)
+            .unwrap()
+            .unwrap();
+        assert_eq!(value.len(), large_blob_array.len() + ENCRYPTION_OVERHEAD);
+        assert_eq!(get_large_blob_array(&mut env, 0, 56), Ok(large_blob_array));
+    }
+
+    #[test]
+    fn test_init_removes_stale_aaguid() {
+        let mut env = TestEnv::new();
+        env.store().insert(key::_AAGUID, &[0xAA; 16]).unwrap();
+
+        init(&mut env).unwrap();
+        assert!(env.store().find(key::_AAGUID).unwrap().is_none());
+    }
+
     #[test]
     fn test_serialize_deserialize_min_pin_length_rp_ids() {
         let rp_ids = vec![String::from("example.com")];
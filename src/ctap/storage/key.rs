@@ -64,8 +64,19 @@ make_partition! {
     /// Reserved for the attestation store implementation of the environment.
     _RESERVED_ATTESTATION_STORE = 1..3;
 
-    /// The aaguid.
-    AAGUID = 3;
+    /// Previously stored the aaguid, which now comes from `Customization::aaguid()`.
+    ///
+    /// Initializing the store removes the stale entry.
+    _AAGUID = 3;
+
+    /// Reserved for the intermediate certificates of the attestation store.
+    _RESERVED_ATTESTATION_CHAIN = 4..8;
+
+    /// If this entry exists and is empty, the batch attestation can't be provisioned anymore.
+    ATTESTATION_LOCKED = 8;
+
+    /// Reserved for the key store implementation of the environment, for keys that persist reset.
+    _RESERVED_PERSISTENT_KEY_STORE = 9;
 
     // This is the persistent key limit:
     // - When adding a (persistent) key above this message, make sure its value is smaller than
@@ -73,11 +84,14 @@ make_partition! {
     // - When adding a (non-persistent) key below this message, make sure its value is bigger or
     //   equal than NUM_PERSISTENT_KEYS.
 
+    /// Reserved for the per-credential signature counters of the environment.
+    _RESERVED_CREDENTIAL_SIGNATURE_COUNTERS = 1000..1200;
+
     /// Reserved for future credential-related objects.
     ///
     /// In particular, additional credentials could be added there by reducing the lower bound of
     /// the credential range below as well as the upper bound of this range in a similar manner.
-    _RESERVED_CREDENTIALS = 1000..1700;
+    _RESERVED_CREDENTIALS = 1200..1700;
 
     /// The credentials.
     ///
@@ -89,12 +103,14 @@ make_partition! {
     /// Storage for the serialized large blob array.
     ///
     /// The stored large blob can be too big for one key, so it has to be sharded.
+    #[cfg_attr(not(feature = "large_blobs"), allow(dead_code))]
     LARGE_BLOB_SHARDS = 2000..2004;
 
     /// If this entry exists and is empty, alwaysUv is enabled.
     ALWAYS_UV = 2038;
 
     /// If this entry exists and is empty, enterprise attestation is enabled.
+    #[cfg_attr(not(feature = "enterprise_attestation"), allow(dead_code))]
     ENTERPRISE_ATTESTATION = 2039;
 
     /// If this entry exists and is empty, the PIN needs to be changed.
@@ -125,10 +141,8 @@ make_partition! {
     /// Reserved for the key store implementation of the environment.
     _RESERVED_KEY_STORE = 2046;
 
-    /// The global signature counter.
-    ///
-    /// If the entry is absent, the counter is 0.
-    GLOBAL_SIGNATURE_COUNTER = 2047;
+    /// Reserved for the global signature counter of the environment.
+    _RESERVED_GLOBAL_SIGNATURE_COUNTER = 2047;
 }
 
 #[cfg(test)]
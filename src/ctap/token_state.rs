@@ -17,6 +17,7 @@ use crate::ctap::status_code::Ctap2StatusCode;
 use crate::ctap::timed_permission::TimedPermission;
 use alloc::string::String;
 use crypto::sha256::Sha256;
+use crypto::util::ct_eq;
 use crypto::Hash256;
 use embedded_time::duration::Milliseconds;
 
@@ -87,9 +88,10 @@ impl PinUvAuthTokenState {
     }
 
     /// Checks if the permissions RPID's association matches the hash.
+    #[cfg_attr(not(feature = "cred_management"), allow(dead_code))]
     pub fn has_permissions_rp_id_hash(&self, rp_id_hash: &[u8]) -> Result<(), Ctap2StatusCode> {
         match &self.permissions_rp_id {
-            Some(p) if rp_id_hash == Sha256::hash(p.as_bytes()) => Ok(()),
+            Some(p) if ct_eq(rp_id_hash, &Sha256::hash(p.as_bytes())) => Ok(()),
             _ => Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID),
         }
     }
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::api::vendor_command;
 use crate::clock::CtapInstant;
 use crate::ctap::hid::{
     CtapHid, CtapHidCommand, CtapHidError, HidPacket, HidPacketIterator, Message,
@@ -21,7 +22,8 @@ use crate::env::Env;
 
 /// Implements the non-standard command processing for HID.
 ///
-/// Outside of the pure HID commands like INIT, only PING and CBOR commands are allowed.
+/// Outside of the pure HID commands like INIT, only PING, CBOR and the vendor commands of the
+/// environment are allowed.
 pub struct VendorHid {
     hid: CtapHid,
 }
@@ -78,17 +80,38 @@ impl VendorHid {
             }
             // Call Wink over the main HID.
             CtapHidCommand::Wink => CtapHid::error_message(cid, CtapHidError::InvalidCmd),
+            CtapHidCommand::Vendor(command) => {
+                match vendor_command::dispatch(env, command, &message.payload) {
+                    Some(Ok(payload)) => Message {
+                        cid,
+                        cmd: CtapHidCommand::Vendor(command),
+                        payload,
+                    },
+                    Some(Err(error)) => CtapHid::error_message(cid, error.into()),
+                    None => CtapHid::error_message(cid, CtapHidError::InvalidCmd),
+                }
+            }
             // All other commands have already been processed, keep them as is.
             _ => message,
         }
     }
 }
 
+impl From<vendor_command::Error> for CtapHidError {
+    fn from(error: vendor_command::Error) -> Self {
+        match error {
+            vendor_command::Error::InvalidParameter => CtapHidError::InvalidPar,
+            vendor_command::Error::InvalidLength => CtapHidError::InvalidLen,
+            vendor_command::Error::Other => CtapHidError::Other,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::ctap::hid::ChannelID;
-    use crate::env::test::TestEnv;
+    use crate::env::test::{TestEnv, TEST_FIRMWARE_VERSION};
 
     fn new_initialized() -> (VendorHid, ChannelID) {
         let (hid, cid) = CtapHid::new_initialized();
@@ -171,4 +194,95 @@ mod test {
         assert_eq!(response.next(), Some(error_packet));
         assert_eq!(response.next(), None);
     }
+
+    #[test]
+    fn test_vendor_command() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let (mut vendor_hid, cid) = new_initialized();
+
+        let mut version_packet = [0x00; 64];
+        version_packet[..4].copy_from_slice(&cid);
+        version_packet[4..7].copy_from_slice(&[0xC0, 0x00, 0x00]);
+
+        let mut expected_packet = [0x00; 64];
+        expected_packet[..4].copy_from_slice(&cid);
+        expected_packet[4..7].copy_from_slice(&[0xC0, 0x00, 0x1A]);
+        expected_packet[7..33].copy_from_slice(&TEST_FIRMWARE_VERSION.to_bytes());
+
+        let mut response = vendor_hid.process_hid_packet(
+            &mut env,
+            &version_packet,
+            CtapInstant::new(0),
+            &mut ctap_state,
+        );
+        assert_eq!(response.next(), Some(expected_packet));
+        assert_eq!(response.next(), None);
+
+        // The firmware version command takes no parameters.
+        version_packet[4..8].copy_from_slice(&[0xC0, 0x00, 0x01, 0x00]);
+        let mut error_packet = [0x00; 64];
+        error_packet[..4].copy_from_slice(&cid);
+        error_packet[4..8].copy_from_slice(&[0xBF, 0x00, 0x01, 0x03]);
+        let mut response = vendor_hid.process_hid_packet(
+            &mut env,
+            &version_packet,
+            CtapInstant::new(0),
+            &mut ctap_state,
+        );
+        assert_eq!(response.next(), Some(error_packet));
+        assert_eq!(response.next(), None);
+    }
+
+    #[test]
+    fn test_registered_vendor_command() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let (mut vendor_hid, cid) = new_initialized();
+        env.vendor_commands_mut().push(vendor_command::Entry {
+            command: 0x7F,
+            handler: |_, request| Ok(request.iter().rev().cloned().collect()),
+        });
+
+        let mut request_packet = [0x00; 64];
+        request_packet[..4].copy_from_slice(&cid);
+        request_packet[4..9].copy_from_slice(&[0xFF, 0x00, 0x02, 0x01, 0x02]);
+
+        let mut expected_packet = [0x00; 64];
+        expected_packet[..4].copy_from_slice(&cid);
+        expected_packet[4..9].copy_from_slice(&[0xFF, 0x00, 0x02, 0x02, 0x01]);
+
+        let mut response = vendor_hid.process_hid_packet(
+            &mut env,
+            &request_packet,
+            CtapInstant::new(0),
+            &mut ctap_state,
+        );
+        assert_eq!(response.next(), Some(expected_packet));
+        assert_eq!(response.next(), None);
+    }
+
+    #[test]
+    fn test_unregistered_vendor_command() {
+        let mut env = TestEnv::new();
+        let mut ctap_state = CtapState::new(&mut env, CtapInstant::new(0));
+        let (mut vendor_hid, cid) = new_initialized();
+
+        let mut vendor_packet = [0x00; 64];
+        vendor_packet[..4].copy_from_slice(&cid);
+        vendor_packet[4..7].copy_from_slice(&[0xC1, 0x00, 0x00]);
+
+        let mut error_packet = [0x00; 64];
+        error_packet[..4].copy_from_slice(&cid);
+        error_packet[4..8].copy_from_slice(&[0xBF, 0x00, 0x01, 0x01]);
+
+        let mut response = vendor_hid.process_hid_packet(
+            &mut env,
+            &vendor_packet,
+            CtapInstant::new(0),
+            &mut ctap_state,
+        );
+        assert_eq!(response.next(), Some(error_packet));
+        assert_eq!(response.next(), None);
+    }
 }
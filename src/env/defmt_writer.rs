@@ -0,0 +1,47 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::string::String;
+
+/// Debug writer that forwards its output to defmt.
+///
+/// Writes are buffered and sent as a single defmt frame when the writer is dropped, so that one
+/// `writeln!` on a fresh writer produces one log line. The transport is chosen by the global
+/// logger linked into the binary, for example RTT through the `defmt-rtt` feature.
+#[derive(Default)]
+pub struct DefmtWriter {
+    buffer: String,
+}
+
+impl DefmtWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl core::fmt::Write for DefmtWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.buffer.push_str(s);
+        Ok(())
+    }
+}
+
+impl Drop for DefmtWriter {
+    fn drop(&mut self) {
+        let message = self.buffer.trim_end_matches('\n');
+        if !message.is_empty() {
+            ::defmt::println!("{=str}", message);
+        }
+    }
+}
@@ -12,16 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::api::aes::Aes256;
 use crate::api::attestation_store::AttestationStore;
+use crate::api::clock::Clock;
 use crate::api::connection::HidConnection;
+#[cfg(feature = "with_nfc")]
+use crate::api::connection::NfcTransport;
 use crate::api::customization::Customization;
 use crate::api::firmware_protection::FirmwareProtection;
 use crate::api::key_store::KeyStore;
+use crate::api::power::Power;
+use crate::api::signature_counter::SignatureCounter;
 use crate::api::upgrade_storage::UpgradeStorage;
 use crate::api::user_presence::UserPresence;
+use crate::api::user_verification::UserVerification;
+#[cfg(feature = "vendor_hid")]
+use crate::api::vendor_command;
+use crate::api::watchdog::Watchdog;
 use persistent_store::{Storage, Store};
 use rng256::Rng256;
 
+#[cfg(feature = "defmt")]
+pub mod defmt_writer;
 #[cfg(feature = "std")]
 pub mod test;
 pub mod tock;
@@ -30,6 +42,7 @@ pub mod tock;
 pub trait Env {
     type Rng: Rng256;
     type UserPresence: UserPresence;
+    type UserVerification: UserVerification;
     type Storage: Storage;
     type KeyStore: KeyStore;
     type UpgradeStorage: UpgradeStorage;
@@ -38,12 +51,33 @@ pub trait Env {
     type Customization: Customization;
     type HidConnection: HidConnection;
     type AttestationStore: AttestationStore;
+    type SignatureCounter: SignatureCounter;
+    type Power: Power;
+    type Aes: Aes256;
+    type Watchdog: Watchdog;
+    type Clock: Clock;
+    #[cfg(feature = "with_nfc")]
+    type NfcTransport: NfcTransport;
 
     fn rng(&mut self) -> &mut Self::Rng;
     fn user_presence(&mut self) -> &mut Self::UserPresence;
+    fn user_verification(&mut self) -> &mut Self::UserVerification;
     fn store(&mut self) -> &mut Store<Self::Storage>;
     fn key_store(&mut self) -> &mut Self::KeyStore;
     fn attestation_store(&mut self) -> &mut Self::AttestationStore;
+    fn signature_counter(&mut self) -> &mut Self::SignatureCounter;
+    fn power(&mut self) -> &mut Self::Power;
+
+    /// Returns the AES engine.
+    ///
+    /// It may differ from the software implementation of the crypto library, but must give the
+    /// same results.
+    fn aes(&mut self) -> &mut Self::Aes;
+
+    fn watchdog(&mut self) -> &mut Self::Watchdog;
+
+    /// Returns the monotonic clock measuring CTAP timeouts.
+    fn clock(&mut self) -> &mut Self::Clock;
 
     /// Returns the upgrade storage instance.
     ///
@@ -56,9 +90,9 @@ pub trait Env {
     /// Creates a write instance for debugging.
     ///
     /// This API doesn't return a reference such that drop may flush. This matches the Tock
-    /// environment. Non-Tock embedded environments should use the defmt feature (to be implemented
-    /// using the defmt crate) and ignore this API. Non-embedded environments may either use this
-    /// API or use the log feature (to be implemented using the log crate).
+    /// environment. With the defmt feature, embedded environments should return a `DefmtWriter`
+    /// instead. With the log feature, the test environment forwards each message to the log crate
+    /// at debug level when the writer is dropped.
     fn write(&mut self) -> Self::Write;
 
     fn customization(&self) -> &Self::Customization;
@@ -69,4 +103,14 @@ pub trait Env {
     /// I/O connection for sending packets implementing vendor extensions to CTAP HID protocol.
     #[cfg(feature = "vendor_hid")]
     fn vendor_hid_connection(&mut self) -> &mut Self::HidConnection;
+
+    /// Connection to NFC readers, carrying CTAP messages in APDUs.
+    #[cfg(feature = "with_nfc")]
+    fn nfc_transport(&mut self) -> &mut Self::NfcTransport;
+
+    /// Handlers of the CTAPHID vendor commands received on the vendor HID connection.
+    #[cfg(feature = "vendor_hid")]
+    fn vendor_commands(&self) -> &[vendor_command::Entry<Self>]
+    where
+        Self: Sized;
 }
@@ -13,34 +13,82 @@
 // limitations under the License.
 
 use crate::api::customization::{Customization, CustomizationImpl};
+use crate::api::signature_counter::SignatureCounterStrategy;
 use crate::ctap::data_formats::{CredentialProtectionPolicy, EnterpriseAttestationMode};
+use crate::ctap::key_material;
 use alloc::string::String;
 use alloc::vec::Vec;
 
 pub struct TestCustomization {
+    aaguid: &'static [u8; key_material::AAGUID_LENGTH],
+    upgrade_public_key: &'static [u8; key_material::UPGRADE_PUBLIC_KEY_LENGTH],
     allows_pin_protocol_v1: bool,
+    allows_ctap1: bool,
+    allows_credential_dump: bool,
     default_cred_protect: Option<CredentialProtectionPolicy>,
     default_min_pin_length: u8,
     default_min_pin_length_rp_ids: Vec<String>,
     enforce_always_uv: bool,
+    always_require_up: bool,
     enterprise_attestation_mode: Option<EnterpriseAttestationMode>,
     enterprise_rp_id_list: Vec<String>,
     max_msg_size: usize,
     max_pin_retries: u8,
     use_batch_attestation: bool,
     use_signature_counter: bool,
+    signature_counter_strategy: SignatureCounterStrategy,
     max_cred_blob_length: usize,
     max_credential_count_in_list: Option<usize>,
     max_large_blob_array_size: usize,
     max_rp_ids_length: usize,
     max_supported_resident_keys: usize,
+    watchdog_timeout_ms: Option<usize>,
 }
 
 impl TestCustomization {
+    pub fn set_aaguid(&mut self, aaguid: &'static [u8; key_material::AAGUID_LENGTH]) {
+        self.aaguid = aaguid;
+    }
+
+    pub fn set_upgrade_public_key(
+        &mut self,
+        upgrade_public_key: &'static [u8; key_material::UPGRADE_PUBLIC_KEY_LENGTH],
+    ) {
+        self.upgrade_public_key = upgrade_public_key;
+    }
+
     pub fn set_allows_pin_protocol_v1(&mut self, is_allowed: bool) {
         self.allows_pin_protocol_v1 = is_allowed;
     }
 
+    pub fn set_allows_ctap1(&mut self, is_allowed: bool) {
+        self.allows_ctap1 = is_allowed;
+    }
+
+    pub fn set_allows_credential_dump(&mut self, is_allowed: bool) {
+        self.allows_credential_dump = is_allowed;
+    }
+
+    pub fn set_always_require_up(&mut self, always_require_up: bool) {
+        self.always_require_up = always_require_up;
+    }
+
+    pub fn set_use_batch_attestation(&mut self, use_batch_attestation: bool) {
+        self.use_batch_attestation = use_batch_attestation;
+    }
+
+    pub fn set_max_credential_count_in_list(&mut self, max_count: Option<usize>) {
+        self.max_credential_count_in_list = max_count;
+    }
+
+    pub fn set_watchdog_timeout_ms(&mut self, timeout_ms: Option<usize>) {
+        self.watchdog_timeout_ms = timeout_ms;
+    }
+
+    pub fn set_signature_counter_strategy(&mut self, strategy: SignatureCounterStrategy) {
+        self.signature_counter_strategy = strategy;
+    }
+
     pub fn setup_enterprise_attestation(
         &mut self,
         mode: Option<EnterpriseAttestationMode>,
@@ -54,10 +102,26 @@ impl TestCustomization {
 }
 
 impl Customization for TestCustomization {
+    fn aaguid(&self) -> &'static [u8; key_material::AAGUID_LENGTH] {
+        self.aaguid
+    }
+
+    fn upgrade_public_key(&self) -> &'static [u8; key_material::UPGRADE_PUBLIC_KEY_LENGTH] {
+        self.upgrade_public_key
+    }
+
     fn allows_pin_protocol_v1(&self) -> bool {
         self.allows_pin_protocol_v1
     }
 
+    fn allows_ctap1(&self) -> bool {
+        self.allows_ctap1
+    }
+
+    fn allows_credential_dump(&self) -> bool {
+        self.allows_credential_dump
+    }
+
     fn default_cred_protect(&self) -> Option<CredentialProtectionPolicy> {
         self.default_cred_protect
     }
@@ -74,6 +138,10 @@ impl Customization for TestCustomization {
         self.enforce_always_uv
     }
 
+    fn always_require_up(&self) -> bool {
+        self.always_require_up
+    }
+
     fn enterprise_attestation_mode(&self) -> Option<EnterpriseAttestationMode> {
         self.enterprise_attestation_mode
     }
@@ -102,6 +170,10 @@ impl Customization for TestCustomization {
         self.use_signature_counter
     }
 
+    fn signature_counter_strategy(&self) -> SignatureCounterStrategy {
+        self.signature_counter_strategy
+    }
+
     fn max_cred_blob_length(&self) -> usize {
         self.max_cred_blob_length
     }
@@ -121,27 +193,38 @@ impl Customization for TestCustomization {
     fn max_supported_resident_keys(&self) -> usize {
         self.max_supported_resident_keys
     }
+
+    fn watchdog_timeout_ms(&self) -> Option<usize> {
+        self.watchdog_timeout_ms
+    }
 }
 
 impl From<CustomizationImpl> for TestCustomization {
     fn from(c: CustomizationImpl) -> Self {
         let CustomizationImpl {
+            aaguid,
+            upgrade_public_key,
             allows_pin_protocol_v1,
+            allows_ctap1,
+            allows_credential_dump,
             default_cred_protect,
             default_min_pin_length,
             default_min_pin_length_rp_ids,
             enforce_always_uv,
+            always_require_up,
             enterprise_attestation_mode,
             enterprise_rp_id_list,
             max_msg_size,
             max_pin_retries,
             use_batch_attestation,
             use_signature_counter,
+            signature_counter_strategy,
             max_cred_blob_length,
             max_credential_count_in_list,
             max_large_blob_array_size,
             max_rp_ids_length,
             max_supported_resident_keys,
+            watchdog_timeout_ms,
         } = c;
 
         let default_min_pin_length_rp_ids = default_min_pin_length_rp_ids
@@ -155,22 +238,29 @@ impl From<CustomizationImpl> for TestCustomization {
             .collect::<Vec<_>>();
 
         Self {
+            aaguid,
+            upgrade_public_key,
             allows_pin_protocol_v1,
+            allows_ctap1,
+            allows_credential_dump,
             default_cred_protect,
             default_min_pin_length,
             default_min_pin_length_rp_ids,
             enforce_always_uv,
+            always_require_up,
             enterprise_attestation_mode,
             enterprise_rp_id_list,
             max_msg_size,
             max_pin_retries,
             use_batch_attestation,
             use_signature_counter,
+            signature_counter_strategy,
             max_cred_blob_length,
             max_credential_count_in_list,
             max_large_blob_array_size,
             max_rp_ids_length,
             max_supported_resident_keys,
+            watchdog_timeout_ms,
         }
     }
 }
@@ -185,4 +275,36 @@ mod test {
         let customization = TestCustomization::from(DEFAULT_CUSTOMIZATION.clone());
         assert!(is_valid(&customization));
     }
+
+    #[test]
+    fn test_zero_aaguid_with_enterprise_attestation() {
+        let mut customization = TestCustomization::from(DEFAULT_CUSTOMIZATION.clone());
+        customization.set_aaguid(&[0; key_material::AAGUID_LENGTH]);
+        assert!(is_valid(&customization));
+        customization
+            .setup_enterprise_attestation(Some(EnterpriseAttestationMode::PlatformManaged), None);
+        assert!(!is_valid(&customization));
+    }
+
+    #[test]
+    fn test_watchdog_timeout() {
+        let mut customization = TestCustomization::from(DEFAULT_CUSTOMIZATION.clone());
+        customization.set_watchdog_timeout_ms(None);
+        assert!(is_valid(&customization));
+        customization.set_watchdog_timeout_ms(Some(3000));
+        assert!(is_valid(&customization));
+        customization.set_watchdog_timeout_ms(Some(2999));
+        assert!(!is_valid(&customization));
+    }
+
+    #[test]
+    fn test_max_credential_count_in_list() {
+        let mut customization = TestCustomization::from(DEFAULT_CUSTOMIZATION.clone());
+        customization.set_max_credential_count_in_list(Some(1));
+        assert!(is_valid(&customization));
+        customization.set_max_credential_count_in_list(Some(0));
+        assert!(!is_valid(&customization));
+        customization.set_max_credential_count_in_list(None);
+        assert!(!is_valid(&customization));
+    }
 }
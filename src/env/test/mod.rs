@@ -12,15 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use self::storage::FaultyStorage;
+pub use self::storage::StorageFaults;
 use self::upgrade_storage::BufferUpgradeStorage;
+use crate::api::aes::SoftwareAes256;
 use crate::api::attestation_store::AttestationStore;
-use crate::api::connection::{HidConnection, SendOrRecvResult, SendOrRecvStatus};
+use crate::api::clock::Clock;
+#[cfg(feature = "with_nfc")]
+use crate::api::connection::NfcTransport;
+use crate::api::connection::{HidConnection, SendOrRecvError, SendOrRecvResult, SendOrRecvStatus};
 use crate::api::customization::DEFAULT_CUSTOMIZATION;
-use crate::api::firmware_protection::FirmwareProtection;
+use crate::api::firmware_protection::{FirmwareProtection, FirmwareVersion};
+use crate::api::power::{Power, PowerStatus};
 use crate::api::user_presence::{UserPresence, UserPresenceResult};
-use crate::api::{attestation_store, key_store};
+use crate::api::user_verification::{
+    UserVerification, UserVerificationError, UserVerificationResult,
+};
+#[cfg(feature = "vendor_hid")]
+use crate::api::vendor_command;
+use crate::api::watchdog::Watchdog;
+use crate::api::{attestation_store, key_store, signature_counter};
 use crate::clock::ClockInt;
 use crate::env::Env;
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
 use customization::TestCustomization;
 use embedded_time::duration::Milliseconds;
 use persistent_store::{BufferOptions, BufferStorage, Store};
@@ -29,34 +44,89 @@ use rand::{Rng, SeedableRng};
 use rng256::Rng256;
 
 pub mod customization;
+mod storage;
 mod upgrade_storage;
 
 pub struct TestEnv {
     rng: TestRng256,
     user_presence: TestUserPresence,
-    store: Store<BufferStorage>,
+    user_verification: TestUserVerification,
+    power: TestPower,
+    aes: SoftwareAes256,
+    watchdog: TestWatchdog,
+    clock: TestClock,
+    store: Store<FaultyStorage>,
+    storage_faults: Rc<StorageFaults>,
     upgrade_storage: Option<BufferUpgradeStorage>,
     customization: TestCustomization,
+    /// Error returned by the HID connections instead of sending packets.
+    hid_error: Option<SendOrRecvError>,
+    store_encryption_cache: key_store::StoreEncryptionCache,
+    #[cfg(feature = "vendor_hid")]
+    vendor_commands: Vec<vendor_command::Entry<TestEnv>>,
 }
 
+/// Deterministic random number generator, which can record and replay its output.
 pub struct TestRng256 {
     rng: StdRng,
+    /// Bytes generated since recording started.
+    recording: Option<Vec<u8>>,
+    /// Bytes to output instead of generating them.
+    replay: Option<VecDeque<u8>>,
 }
 
 impl TestRng256 {
+    /// Creates a generator producing the same stream for the same seed.
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        TestRng256 {
+            rng: StdRng::from_seed(seed),
+            recording: None,
+            replay: None,
+        }
+    }
+
     pub fn seed_from_u64(&mut self, state: u64) {
         self.rng = StdRng::seed_from_u64(state);
     }
+
+    /// Starts recording the generated bytes, discarding a previous recording.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Vec::new());
+    }
+
+    /// Stops recording, and returns the bytes generated since recording started.
+    pub fn take_recording(&mut self) -> Vec<u8> {
+        self.recording.take().unwrap_or_default()
+    }
+
+    /// Outputs the given bytes, for example a recording, instead of generating them.
+    ///
+    /// Once all bytes are consumed, using the generator panics, because the replayed test took a
+    /// different path than the recorded one.
+    pub fn replay(&mut self, bytes: Vec<u8>) {
+        self.replay = Some(bytes.into());
+    }
 }
 
 impl Rng256 for TestRng256 {
     fn fill_bytes(&mut self, buf: &mut [u8]) {
-        self.rng.fill(buf)
+        match &mut self.replay {
+            Some(replay) => {
+                assert!(buf.len() <= replay.len());
+                for (byte, replayed) in buf.iter_mut().zip(replay.drain(..buf.len())) {
+                    *byte = replayed;
+                }
+            }
+            None => self.rng.fill(buf),
+        }
+        if let Some(recording) = &mut self.recording {
+            recording.extend_from_slice(buf);
+        }
     }
 
     fn gen_uniform_u8x32(&mut self) -> [u8; 32] {
         let mut result = [Default::default(); 32];
-        self.rng.fill(&mut result);
+        self.fill_bytes(&mut result);
         result
     }
 }
@@ -65,15 +135,70 @@ pub struct TestUserPresence {
     check: Box<dyn Fn() -> UserPresenceResult>,
 }
 
-pub struct TestWrite;
+/// Number of attempts of the test built-in user verification.
+pub const MAX_UV_RETRIES: u8 = 5;
+
+/// Built-in user verification of the test environment, unsupported by default.
+pub struct TestUserVerification {
+    /// Whether a user is enrolled, or `None` if unsupported.
+    enrolled: Option<bool>,
+    retries: u8,
+    check: Box<dyn Fn() -> UserVerificationResult>,
+}
+
+/// Power of the test environment, without power sensing by default.
+#[derive(Default)]
+pub struct TestPower {
+    status: Option<PowerStatus>,
+}
+
+/// Watchdog of the test environment, recording how it is used.
+#[derive(Default)]
+pub struct TestWatchdog {
+    timeout_ms: Option<usize>,
+    kicks: usize,
+}
+
+/// Clock of the test environment, which only moves when tests advance it.
+#[derive(Default)]
+pub struct TestClock {
+    now_ms: u64,
+}
+
+/// Debug writer of the test environment.
+///
+/// With the log feature, each writer collects one message and forwards it to the log crate at
+/// debug level when dropped. Otherwise, the output is discarded.
+#[derive(Default)]
+pub struct TestWrite {
+    #[cfg(feature = "log")]
+    buffer: String,
+}
 
 impl core::fmt::Write for TestWrite {
+    #[cfg(not(feature = "log"))]
     fn write_str(&mut self, _: &str) -> core::fmt::Result {
         Ok(())
     }
+
+    #[cfg(feature = "log")]
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.buffer.push_str(s);
+        Ok(())
+    }
 }
 
-fn new_storage() -> BufferStorage {
+#[cfg(feature = "log")]
+impl Drop for TestWrite {
+    fn drop(&mut self) {
+        let message = self.buffer.trim_end_matches('\n');
+        if !message.is_empty() {
+            log::debug!("{}", message);
+        }
+    }
+}
+
+fn new_storage(faults: Rc<StorageFaults>) -> FaultyStorage {
     // Use the Nordic configuration.
     const PAGE_SIZE: usize = 0x1000;
     const NUM_PAGES: usize = 20;
@@ -85,7 +210,7 @@ fn new_storage() -> BufferStorage {
         max_page_erases: 10000,
         strict_mode: true,
     };
-    BufferStorage::new(store, options)
+    FaultyStorage::new(BufferStorage::new(store, options), faults)
 }
 
 impl HidConnection for TestEnv {
@@ -94,32 +219,83 @@ impl HidConnection for TestEnv {
         _buf: &mut [u8; 64],
         _timeout: Milliseconds<ClockInt>,
     ) -> SendOrRecvResult {
+        if let Some(error) = self.hid_error {
+            return Err(error);
+        }
         // TODO: Implement I/O from canned requests/responses for integration testing.
         Ok(SendOrRecvStatus::Sent)
     }
 }
 
+#[cfg(feature = "with_nfc")]
+impl NfcTransport for TestEnv {}
+
 impl TestEnv {
     pub fn new() -> Self {
         let rng = TestRng256 {
             rng: StdRng::seed_from_u64(0),
+            recording: None,
+            replay: None,
         };
         let user_presence = TestUserPresence {
             check: Box::new(|| Ok(())),
         };
-        let storage = new_storage();
+        let user_verification = TestUserVerification {
+            enrolled: None,
+            retries: MAX_UV_RETRIES,
+            check: Box::new(|| Ok(())),
+        };
+        let power = TestPower::default();
+        let storage_faults = Rc::new(StorageFaults::default());
+        let storage = new_storage(storage_faults.clone());
         let store = Store::new(storage).ok().unwrap();
-        let upgrade_storage = Some(BufferUpgradeStorage::new().unwrap());
-        let customization = DEFAULT_CUSTOMIZATION.into();
+        let customization: TestCustomization = DEFAULT_CUSTOMIZATION.into();
+        let upgrade_storage =
+            Some(BufferUpgradeStorage::new(*customization.upgrade_public_key()).unwrap());
         TestEnv {
             rng,
             user_presence,
+            user_verification,
+            power,
+            aes: SoftwareAes256,
+            watchdog: TestWatchdog::default(),
+            clock: TestClock::default(),
             store,
+            storage_faults,
             upgrade_storage,
             customization,
+            hid_error: None,
+            store_encryption_cache: key_store::StoreEncryptionCache::default(),
+            #[cfg(feature = "vendor_hid")]
+            vendor_commands: vec![vendor_command::Entry {
+                command: vendor_command::FIRMWARE_VERSION_COMMAND,
+                handler: vendor_command::firmware_version,
+            }],
         }
     }
 
+    /// Returns the faults to inject in the storage.
+    pub fn storage_faults(&self) -> &StorageFaults {
+        &self.storage_faults
+    }
+
+    /// Makes the HID connections fail with the given error, or succeed again if `None`.
+    pub fn set_hid_error(&mut self, error: Option<SendOrRecvError>) {
+        self.hid_error = error;
+    }
+
+    /// Reboots the device, which disarms storage faults and recovers the store.
+    pub fn power_cycle(&mut self) {
+        // The store is replaced while its storage is extracted.
+        let store = Store::new(new_storage(Rc::new(StorageFaults::default())))
+            .ok()
+            .unwrap();
+        let storage = core::mem::replace(&mut self.store, store).extract_storage();
+        self.storage_faults.reset();
+        self.store = Store::new(storage).ok().unwrap();
+        self.store_encryption_cache = key_store::StoreEncryptionCache::default();
+    }
+
     pub fn disable_upgrade_storage(&mut self) {
         self.upgrade_storage = None;
     }
@@ -128,6 +304,11 @@ impl TestEnv {
         &mut self.customization
     }
 
+    #[cfg(feature = "vendor_hid")]
+    pub fn vendor_commands_mut(&mut self) -> &mut Vec<vendor_command::Entry<TestEnv>> {
+        &mut self.vendor_commands
+    }
+
     pub fn rng(&mut self) -> &mut TestRng256 {
         &mut self.rng
     }
@@ -144,16 +325,140 @@ impl UserPresence for TestUserPresence {
     fn wait_with_timeout(&mut self, _timeout: Milliseconds<ClockInt>) -> UserPresenceResult {
         (self.check)()
     }
-    fn check_complete(&mut self) {}
+    fn check_complete(&mut self, _result: &UserPresenceResult) {}
+}
+
+impl TestUserVerification {
+    pub fn set_enrolled(&mut self, enrolled: Option<bool>) {
+        self.enrolled = enrolled;
+    }
+
+    pub fn set(&mut self, check: impl Fn() -> UserVerificationResult + 'static) {
+        self.check = Box::new(check);
+    }
+}
+
+impl UserVerification for TestUserVerification {
+    fn is_supported(&mut self) -> bool {
+        self.enrolled.is_some()
+    }
+
+    fn is_configured(&mut self) -> bool {
+        self.enrolled == Some(true)
+    }
+
+    fn retries(&mut self) -> u8 {
+        self.retries
+    }
+
+    fn verify(&mut self) -> UserVerificationResult {
+        if self.retries == 0 {
+            return Err(UserVerificationError::Blocked);
+        }
+        let result = (self.check)();
+        match result {
+            Ok(()) => self.retries = MAX_UV_RETRIES,
+            Err(UserVerificationError::Invalid) => self.retries -= 1,
+            Err(_) => (),
+        }
+        result
+    }
+}
+
+impl TestPower {
+    pub fn set(&mut self, status: Option<PowerStatus>) {
+        self.status = status;
+    }
+}
+
+impl Power for TestPower {
+    fn power_status(&mut self) -> Option<PowerStatus> {
+        self.status
+    }
+}
+
+impl TestWatchdog {
+    /// Returns the timeout of the running watchdog, or `None` if it is disabled.
+    pub fn timeout_ms(&self) -> Option<usize> {
+        self.timeout_ms
+    }
+
+    /// Returns how many times the watchdog was kicked.
+    pub fn kicks(&self) -> usize {
+        self.kicks
+    }
 }
 
+impl Watchdog for TestWatchdog {
+    fn enable(&mut self, timeout_ms: usize) -> bool {
+        self.timeout_ms = Some(timeout_ms);
+        true
+    }
+
+    fn kick(&mut self) {
+        self.kicks += 1;
+    }
+
+    fn disable(&mut self) {
+        self.timeout_ms = None;
+    }
+}
+
+impl TestClock {
+    /// Sets the time since boot.
+    ///
+    /// # Panics
+    ///
+    /// - If the clock would go back in time.
+    pub fn set_ms(&mut self, now_ms: u64) {
+        assert!(now_ms >= self.now_ms);
+        self.now_ms = now_ms;
+    }
+
+    /// Moves the clock forward.
+    pub fn advance_ms(&mut self, duration_ms: u64) {
+        self.now_ms += duration_ms;
+    }
+}
+
+impl Clock for TestClock {
+    fn now_ms(&mut self) -> u64 {
+        self.now_ms
+    }
+}
+
+/// Firmware version reported by the test environment.
+pub const TEST_FIRMWARE_VERSION: FirmwareVersion = FirmwareVersion {
+    major: 1,
+    minor: 2,
+    patch: 3,
+    git_hash: [0x5A; 20],
+};
+
 impl FirmwareProtection for TestEnv {
     fn lock(&mut self) -> bool {
         true
     }
+
+    fn firmware_version(&self) -> FirmwareVersion {
+        TEST_FIRMWARE_VERSION
+    }
 }
 
-impl key_store::Helper for TestEnv {}
+/// Secret of the store encryption keys of the test environment.
+const TEST_STORE_ENCRYPTION_SECRET: [u8; 32] = [0x5E; 32];
+
+impl key_store::Helper for TestEnv {
+    fn store_encryption_cache(&mut self) -> &mut key_store::StoreEncryptionCache {
+        &mut self.store_encryption_cache
+    }
+
+    fn store_encryption_secret(&self) -> &[u8; 32] {
+        &TEST_STORE_ENCRYPTION_SECRET
+    }
+}
+
+impl signature_counter::Helper for TestEnv {}
 
 impl AttestationStore for TestEnv {
     fn get(
@@ -175,14 +480,22 @@ impl AttestationStore for TestEnv {
 impl Env for TestEnv {
     type Rng = TestRng256;
     type UserPresence = TestUserPresence;
-    type Storage = BufferStorage;
+    type UserVerification = TestUserVerification;
+    type Storage = FaultyStorage;
     type KeyStore = Self;
     type AttestationStore = Self;
+    type SignatureCounter = Self;
+    type Power = TestPower;
+    type Aes = SoftwareAes256;
+    type Watchdog = TestWatchdog;
+    type Clock = TestClock;
     type UpgradeStorage = BufferUpgradeStorage;
     type FirmwareProtection = Self;
     type Write = TestWrite;
     type Customization = TestCustomization;
     type HidConnection = Self;
+    #[cfg(feature = "with_nfc")]
+    type NfcTransport = Self;
 
     fn rng(&mut self) -> &mut Self::Rng {
         &mut self.rng
@@ -192,6 +505,10 @@ impl Env for TestEnv {
         &mut self.user_presence
     }
 
+    fn user_verification(&mut self) -> &mut Self::UserVerification {
+        &mut self.user_verification
+    }
+
     fn store(&mut self) -> &mut Store<Self::Storage> {
         &mut self.store
     }
@@ -204,6 +521,26 @@ impl Env for TestEnv {
         self
     }
 
+    fn signature_counter(&mut self) -> &mut Self {
+        self
+    }
+
+    fn power(&mut self) -> &mut Self::Power {
+        &mut self.power
+    }
+
+    fn aes(&mut self) -> &mut Self::Aes {
+        &mut self.aes
+    }
+
+    fn watchdog(&mut self) -> &mut Self::Watchdog {
+        &mut self.watchdog
+    }
+
+    fn clock(&mut self) -> &mut Self::Clock {
+        &mut self.clock
+    }
+
     fn upgrade_storage(&mut self) -> Option<&mut Self::UpgradeStorage> {
         self.upgrade_storage.as_mut()
     }
@@ -213,7 +550,7 @@ impl Env for TestEnv {
     }
 
     fn write(&mut self) -> Self::Write {
-        TestWrite
+        TestWrite::default()
     }
 
     fn customization(&self) -> &Self::Customization {
@@ -228,4 +565,56 @@ impl Env for TestEnv {
     fn vendor_hid_connection(&mut self) -> &mut Self::HidConnection {
         self
     }
+
+    #[cfg(feature = "with_nfc")]
+    fn nfc_transport(&mut self) -> &mut Self::NfcTransport {
+        self
+    }
+
+    #[cfg(feature = "vendor_hid")]
+    fn vendor_commands(&self) -> &[vendor_command::Entry<Self>] {
+        &self.vendor_commands
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rng_from_seed() {
+        let mut rng = TestRng256::from_seed([0x55; 32]);
+        let mut same_rng = TestRng256::from_seed([0x55; 32]);
+        let mut other_rng = TestRng256::from_seed([0xAA; 32]);
+        let output = rng.gen_uniform_u8x32();
+        assert_eq!(same_rng.gen_uniform_u8x32(), output);
+        assert_ne!(other_rng.gen_uniform_u8x32(), output);
+    }
+
+    #[test]
+    fn test_rng_record_and_replay() {
+        let mut rng = TestRng256::from_seed([0x55; 32]);
+        rng.start_recording();
+        let mut output = [0; 40];
+        rng.fill_bytes(&mut output[..8]);
+        output[8..].copy_from_slice(&rng.gen_uniform_u8x32());
+        let recording = rng.take_recording();
+        assert_eq!(recording, output.to_vec());
+        assert!(rng.take_recording().is_empty());
+
+        let mut replay_rng = TestRng256::from_seed([0xAA; 32]);
+        replay_rng.replay(recording);
+        let mut replayed = [0; 40];
+        replay_rng.fill_bytes(&mut replayed[..32]);
+        replay_rng.fill_bytes(&mut replayed[32..]);
+        assert_eq!(replayed, output);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rng_replay_exhausted() {
+        let mut rng = TestRng256::from_seed([0x55; 32]);
+        rng.replay(vec![0x00; 16]);
+        rng.gen_uniform_u8x32();
+    }
 }
@@ -0,0 +1,292 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use core::cell::Cell;
+use persistent_store::{BufferStorage, Storage, StorageError, StorageIndex, StorageResult};
+
+/// Faults to inject in the storage of the test environment.
+///
+/// Faults are armed from the environment while the storage is owned by the store. Each fault
+/// triggers once. Failed operations return [`StorageError::CustomError`], like a failing syscall.
+#[derive(Default)]
+pub struct StorageFaults {
+    /// Number of writes to let through before failing one.
+    write_failure: Cell<Option<usize>>,
+
+    /// Whether the next read fails.
+    read_failure: Cell<bool>,
+
+    /// Number of writes and erases to let through before losing power during one.
+    power_loss: Cell<Option<usize>>,
+
+    /// Whether power was lost, until the next power cycle.
+    powered_off: Cell<bool>,
+}
+
+impl StorageFaults {
+    /// Fails the next write, leaving the storage unchanged.
+    pub fn fail_next_write(&self) {
+        self.fail_write_after(0);
+    }
+
+    /// Fails the write following `count` successful ones.
+    pub fn fail_write_after(&self, count: usize) {
+        self.write_failure.set(Some(count));
+    }
+
+    /// Fails the next read.
+    pub fn fail_next_read(&self) {
+        self.read_failure.set(true);
+    }
+
+    /// Loses power during the write or erase following `count` successful ones.
+    ///
+    /// Only the first half of the words of the interrupted operation reach the storage. All
+    /// operations fail from then on, until the device is power cycled.
+    pub fn simulate_power_loss_after(&self, count: usize) {
+        self.power_loss.set(Some(count));
+    }
+
+    /// Returns whether power was lost since the last power cycle.
+    pub fn is_powered_off(&self) -> bool {
+        self.powered_off.get()
+    }
+
+    /// Disarms all faults and restores power.
+    pub fn reset(&self) {
+        self.write_failure.set(None);
+        self.read_failure.set(false);
+        self.power_loss.set(None);
+        self.powered_off.set(false);
+    }
+
+    /// Returns whether an armed counter triggers, and decrements it otherwise.
+    fn tick(counter: &Cell<Option<usize>>) -> bool {
+        match counter.get() {
+            None => false,
+            Some(0) => {
+                counter.set(None);
+                true
+            }
+            Some(count) => {
+                counter.set(Some(count - 1));
+                false
+            }
+        }
+    }
+}
+
+/// Buffer storage failing on demand.
+pub struct FaultyStorage {
+    storage: BufferStorage,
+    faults: Rc<StorageFaults>,
+}
+
+impl FaultyStorage {
+    pub fn new(storage: BufferStorage, faults: Rc<StorageFaults>) -> Self {
+        FaultyStorage { storage, faults }
+    }
+
+    /// Performs a mutable operation, unless it is interrupted by an injected fault.
+    fn mutate(
+        &mut self,
+        is_write: bool,
+        operation: impl FnOnce(&mut BufferStorage) -> StorageResult<()>,
+    ) -> StorageResult<()> {
+        if self.faults.is_powered_off() {
+            return Err(StorageError::CustomError);
+        }
+        if StorageFaults::tick(&self.faults.power_loss) {
+            self.faults.powered_off.set(true);
+            let word_size = self.storage.word_size();
+            self.storage.arm_interruption(0);
+            match operation(&mut self.storage) {
+                Err(StorageError::CustomError) => self.storage.corrupt_operation(Box::new(
+                    move |before: &mut [u8], after: &[u8]| {
+                        let length = before.len() / word_size / 2 * word_size;
+                        before[..length].copy_from_slice(&after[..length]);
+                    },
+                )),
+                // Invalid operations are rejected before reaching the storage.
+                _ => self.storage.reset_interruption(),
+            }
+            return Err(StorageError::CustomError);
+        }
+        if is_write && StorageFaults::tick(&self.faults.write_failure) {
+            return Err(StorageError::CustomError);
+        }
+        operation(&mut self.storage)
+    }
+}
+
+impl Storage for FaultyStorage {
+    fn word_size(&self) -> usize {
+        self.storage.word_size()
+    }
+
+    fn page_size(&self) -> usize {
+        self.storage.page_size()
+    }
+
+    fn num_pages(&self) -> usize {
+        self.storage.num_pages()
+    }
+
+    fn max_word_writes(&self) -> usize {
+        self.storage.max_word_writes()
+    }
+
+    fn max_page_erases(&self) -> usize {
+        self.storage.max_page_erases()
+    }
+
+    fn read_slice(&self, index: StorageIndex, length: usize) -> StorageResult<Cow<[u8]>> {
+        if self.faults.is_powered_off() || self.faults.read_failure.replace(false) {
+            return Err(StorageError::CustomError);
+        }
+        self.storage.read_slice(index, length)
+    }
+
+    fn write_slice(&mut self, index: StorageIndex, value: &[u8]) -> StorageResult<()> {
+        self.mutate(true, |storage| storage.write_slice(index, value))
+    }
+
+    fn erase_page(&mut self, page: usize) -> StorageResult<()> {
+        self.mutate(false, |storage| storage.erase_page(page))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ctap::storage;
+    use crate::env::test::TestEnv;
+    use crate::env::Env;
+    use persistent_store::StoreError;
+
+    const KEY: usize = 1;
+
+    #[test]
+    fn test_fail_next_write() {
+        let mut env = TestEnv::new();
+        env.store().insert(KEY, &[0x01; 8]).unwrap();
+        env.storage_faults().fail_next_write();
+        assert_eq!(
+            env.store().insert(KEY, &[0x02; 8]),
+            Err(StoreError::StorageError)
+        );
+        env.store().recover().unwrap();
+        assert_eq!(env.store().find(KEY), Ok(Some(vec![0x01; 8])));
+
+        // The fault triggered once.
+        env.store().insert(KEY, &[0x02; 8]).unwrap();
+        assert_eq!(env.store().find(KEY), Ok(Some(vec![0x02; 8])));
+    }
+
+    #[test]
+    fn test_fail_write_after() {
+        let mut env = TestEnv::new();
+        let old_value = vec![0x01; 32];
+        let new_value = vec![0x02; 32];
+        env.store().insert(KEY, &old_value).unwrap();
+        let mut count = 0;
+        loop {
+            env.storage_faults().fail_write_after(count);
+            let result = env.store().insert(KEY, &new_value);
+            if result.is_ok() {
+                break;
+            }
+            assert_eq!(result, Err(StoreError::StorageError));
+            env.store().recover().unwrap();
+            // The value is either updated or not, and the store stays usable.
+            let value = env.store().find(KEY).unwrap().unwrap();
+            assert!(value == old_value || value == new_value);
+            env.store().insert(KEY, &old_value).unwrap();
+            count += 1;
+        }
+        assert!(count > 0);
+        env.storage_faults().reset();
+        assert_eq!(env.store().find(KEY), Ok(Some(new_value)));
+    }
+
+    #[test]
+    fn test_fail_next_read() {
+        let mut env = TestEnv::new();
+        env.store().insert(KEY, &[0x01; 8]).unwrap();
+        env.storage_faults().fail_next_read();
+        assert_eq!(env.store().find(KEY), Err(StoreError::StorageError));
+        assert_eq!(env.store().find(KEY), Ok(Some(vec![0x01; 8])));
+    }
+
+    #[test]
+    fn test_power_loss() {
+        let mut env = TestEnv::new();
+        let old_value = vec![0x01; 100];
+        let new_value = vec![0x02; 100];
+        env.store().insert(KEY, &old_value).unwrap();
+        let mut count = 0;
+        loop {
+            env.storage_faults().simulate_power_loss_after(count);
+            let result = env.store().insert(KEY, &new_value);
+            if !env.storage_faults().is_powered_off() {
+                result.unwrap();
+                break;
+            }
+            assert_eq!(result, Err(StoreError::StorageError));
+            // Nothing works until the device reboots.
+            assert_eq!(env.store().find(KEY), Err(StoreError::StorageError));
+            env.power_cycle();
+            let value = env.store().find(KEY).unwrap().unwrap();
+            assert!(value == old_value || value == new_value);
+            env.store().insert(KEY, &old_value).unwrap();
+            count += 1;
+        }
+        assert!(count > 0);
+        env.storage_faults().reset();
+        assert_eq!(env.store().find(KEY), Ok(Some(new_value)));
+    }
+
+    #[test]
+    fn test_power_loss_while_setting_pin() {
+        let mut env = TestEnv::new();
+        let old_pin_hash = [0x55; 16];
+        let new_pin_hash = [0xAA; 16];
+        storage::set_pin(&mut env, &old_pin_hash, 4).unwrap();
+        let mut count = 0;
+        loop {
+            env.storage_faults().simulate_power_loss_after(count);
+            let result = storage::set_pin(&mut env, &new_pin_hash, 6);
+            if !env.storage_faults().is_powered_off() {
+                result.unwrap();
+                break;
+            }
+            assert!(result.is_err());
+            env.power_cycle();
+            // The hash and its length are updated together.
+            let state = (
+                storage::pin_hash(&mut env).unwrap(),
+                storage::pin_code_point_length(&mut env).unwrap(),
+            );
+            assert!(
+                state == (Some(old_pin_hash), Some(4)) || state == (Some(new_pin_hash), Some(6))
+            );
+            storage::set_pin(&mut env, &old_pin_hash, 4).unwrap();
+            count += 1;
+        }
+        assert!(count > 0);
+        assert_eq!(storage::pin_hash(&mut env), Ok(Some(new_pin_hash)));
+    }
+}
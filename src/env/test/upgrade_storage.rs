@@ -12,26 +12,70 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::api::upgrade_storage::helper::ModRange;
-use crate::api::upgrade_storage::UpgradeStorage;
+use crate::api::upgrade_storage::helper::{
+    parse_metadata_hash, parse_metadata_signature, parse_metadata_version, verify_signature,
+    ChunkedWrite, ModRange, METADATA_SIGN_OFFSET,
+};
+use crate::api::upgrade_storage::{Slot, SlotMetadata, UpgradeStorage};
+use crate::ctap::key_material::UPGRADE_PUBLIC_KEY_LENGTH;
 use alloc::boxed::Box;
+use crypto::sha256::Sha256;
+use crypto::Hash256;
 use persistent_store::{StorageError, StorageResult};
 
 const PARTITION_LENGTH: usize = 0x41000;
 const METADATA_LENGTH: usize = 0x1000;
 
+/// Upgrade storage running from slot A, with the partition of slot B in memory.
+///
+/// The metadata layout is the same as on Tock. The partition address is not checked.
 pub struct BufferUpgradeStorage {
     /// Content of the partition storage.
     partition: Box<[u8]>,
+    public_key: [u8; UPGRADE_PUBLIC_KEY_LENGTH],
+    chunked_write: Option<ChunkedWrite>,
+    running_version: u64,
+    valid: bool,
 }
 
 impl BufferUpgradeStorage {
-    pub fn new() -> StorageResult<BufferUpgradeStorage> {
+    pub fn new(public_key: [u8; UPGRADE_PUBLIC_KEY_LENGTH]) -> StorageResult<BufferUpgradeStorage> {
         Ok(BufferUpgradeStorage {
             partition: vec![0xff; PARTITION_LENGTH].into_boxed_slice(),
+            public_key,
+            chunked_write: None,
+            running_version: 0,
+            valid: false,
         })
     }
 
+    pub fn set_running_firmware_version(&mut self, version: u64) {
+        self.running_version = version;
+    }
+
+    fn metadata_version(&self) -> u64 {
+        parse_metadata_version(&self.partition)
+    }
+
+    /// Checks the version, hashes and signature of the staged bundle.
+    fn check_bundle(&self) -> StorageResult<()> {
+        if !self.can_install(self.metadata_version()) {
+            return Err(StorageError::CustomError);
+        }
+        if let Some(chunked_write) = &self.chunked_write {
+            chunked_write.check_hash(&Sha256::hash(&self.partition))?;
+        }
+        let computed_hash = Sha256::hash(&self.partition[METADATA_SIGN_OFFSET..]);
+        if &computed_hash != parse_metadata_hash(&self.partition) {
+            return Err(StorageError::CustomError);
+        }
+        verify_signature(
+            parse_metadata_signature(&self.partition),
+            &self.public_key,
+            &computed_hash,
+        )
+    }
+
     #[cfg(test)]
     fn read_partition(&self, offset: usize, length: usize) -> StorageResult<&[u8]> {
         if length == 0 {
@@ -48,19 +92,45 @@ impl BufferUpgradeStorage {
 
 impl UpgradeStorage for BufferUpgradeStorage {
     fn write_bundle(&mut self, offset: usize, data: Vec<u8>) -> StorageResult<()> {
-        if offset == 0 && data.len() != METADATA_LENGTH {
-            return Err(StorageError::OutOfBounds);
-        }
         if data.is_empty() {
             return Err(StorageError::OutOfBounds);
         }
+        if offset == 0 {
+            // The version is parsed from the metadata, which must be complete.
+            if data.len() != METADATA_LENGTH {
+                return Err(StorageError::OutOfBounds);
+            }
+            if !self.can_install(parse_metadata_version(&data)) {
+                return Err(StorageError::CustomError);
+            }
+        }
         let partition_range = ModRange::new(0, self.partition.len());
-        if partition_range.contains_range(&ModRange::new(offset, data.len())) {
-            self.partition[offset..][..data.len()].copy_from_slice(&data);
-            Ok(())
-        } else {
-            Err(StorageError::OutOfBounds)
+        if !partition_range.contains_range(&ModRange::new(offset, data.len())) {
+            return Err(StorageError::OutOfBounds);
+        }
+        self.valid = false;
+        self.partition[offset..][..data.len()].copy_from_slice(&data);
+        // Case: Last slice is written.
+        if offset + data.len() == self.partition.len() {
+            self.finalize_install()?;
+        }
+        Ok(())
+    }
+
+    fn start_chunked_write(&mut self, total_length: usize, hash: [u8; 32]) -> StorageResult<()> {
+        if total_length != self.partition.len() {
+            return Err(StorageError::OutOfBounds);
         }
+        self.chunked_write = Some(ChunkedWrite::new(total_length, hash));
+        Ok(())
+    }
+
+    fn chunked_write(&self) -> Option<&ChunkedWrite> {
+        self.chunked_write.as_ref()
+    }
+
+    fn chunked_write_mut(&mut self) -> Option<&mut ChunkedWrite> {
+        self.chunked_write.as_mut()
     }
 
     fn bundle_identifier(&self) -> u32 {
@@ -68,17 +138,61 @@ impl UpgradeStorage for BufferUpgradeStorage {
     }
 
     fn running_firmware_version(&self) -> u64 {
-        0
+        self.running_version
+    }
+
+    fn active_slot(&self) -> Slot {
+        Slot::A
+    }
+
+    fn slot_metadata(&self, slot: Slot) -> SlotMetadata {
+        match slot {
+            Slot::A => SlotMetadata {
+                version: self.running_version,
+                valid: true,
+            },
+            Slot::B => SlotMetadata {
+                version: self.metadata_version(),
+                valid: self.valid,
+            },
+        }
+    }
+
+    fn finalize_install(&mut self) -> StorageResult<()> {
+        self.valid = false;
+        let result = self.check_bundle();
+        if result.is_err() {
+            // Like an erased metadata page on flash.
+            self.partition[..METADATA_LENGTH].fill(0xFF);
+        }
+        result?;
+        self.valid = true;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ctap::key_material::UPGRADE_PUBLIC_KEY;
+    use crate::env::test::TestEnv;
+    use crate::env::Env;
+    use byteorder::{ByteOrder, LittleEndian};
+    use crypto::ecdsa;
+    use rng256::Rng256;
+
+    fn signing_key(rng: &mut impl Rng256) -> (ecdsa::SecKey, [u8; UPGRADE_PUBLIC_KEY_LENGTH]) {
+        let private_key = ecdsa::SecKey::gensk(rng);
+        let mut public_key_bytes = [0; UPGRADE_PUBLIC_KEY_LENGTH];
+        private_key
+            .genpk()
+            .to_bytes_uncompressed(&mut public_key_bytes);
+        (private_key, public_key_bytes)
+    }
 
     #[test]
     fn read_write_bundle() {
-        let mut storage = BufferUpgradeStorage::new().unwrap();
+        let mut storage = BufferUpgradeStorage::new(*UPGRADE_PUBLIC_KEY).unwrap();
         assert_eq!(storage.read_partition(0, 2).unwrap(), &[0xFF, 0xFF]);
         assert!(storage.write_bundle(1, vec![0x88, 0x88]).is_ok());
         assert_eq!(storage.read_partition(0, 2).unwrap(), &[0xFF, 0x88]);
@@ -109,9 +223,221 @@ mod tests {
         );
     }
 
+    #[test]
+    fn write_bundle_short_metadata() {
+        let mut storage = BufferUpgradeStorage::new(*UPGRADE_PUBLIC_KEY).unwrap();
+        assert_eq!(
+            storage.write_bundle(0, vec![0x88; METADATA_SIGN_OFFSET]),
+            Err(StorageError::OutOfBounds)
+        );
+        assert_eq!(
+            storage.write_bundle(0, vec![0x88; METADATA_LENGTH - 1]),
+            Err(StorageError::OutOfBounds)
+        );
+        assert_eq!(storage.read_partition(0, 2).unwrap(), &[0xFF, 0xFF]);
+    }
+
+    /// Returns the metadata and firmware of a bundle with the given version.
+    fn bundle(private_key: &ecdsa::SecKey, version: u64) -> (Vec<u8>, Vec<u8>) {
+        let mut metadata = vec![0xFF; METADATA_LENGTH];
+        LittleEndian::write_u64(&mut metadata[METADATA_SIGN_OFFSET..][..8], version);
+        let firmware = vec![0x42; PARTITION_LENGTH - METADATA_LENGTH];
+        let mut signed_over_data = metadata[METADATA_SIGN_OFFSET..].to_vec();
+        signed_over_data.extend(&firmware);
+        metadata[..32].copy_from_slice(&Sha256::hash(&signed_over_data));
+        let signature = private_key.sign_rfc6979::<Sha256>(&signed_over_data);
+        signature.to_bytes(array_mut_ref!(metadata, 32, ecdsa::Signature::BYTES_LENGTH));
+        (metadata, firmware)
+    }
+
+    #[test]
+    fn upgrade_a_to_b() {
+        let mut env = TestEnv::new();
+        let (private_key, public_key) = signing_key(env.rng());
+        let mut storage = BufferUpgradeStorage::new(public_key).unwrap();
+        storage.set_running_firmware_version(1);
+        assert_eq!(storage.active_slot(), Slot::A);
+        assert!(!storage.slot_metadata(Slot::B).valid);
+
+        let (metadata, firmware) = bundle(&private_key, 2);
+        assert!(storage.can_install(2));
+        assert_eq!(storage.write_bundle(0, metadata), Ok(()));
+        assert!(!storage.slot_metadata(Slot::B).valid);
+        assert_eq!(storage.write_bundle(METADATA_LENGTH, firmware), Ok(()));
+        assert_eq!(
+            storage.slot_metadata(Slot::B),
+            SlotMetadata {
+                version: 2,
+                valid: true
+            }
+        );
+        assert_eq!(
+            storage.slot_metadata(Slot::A),
+            SlotMetadata {
+                version: 1,
+                valid: true
+            }
+        );
+
+        // Writing again invalidates the slot until it is verified.
+        assert_eq!(storage.write_bundle(METADATA_LENGTH, vec![0x00]), Ok(()));
+        assert!(!storage.slot_metadata(Slot::B).valid);
+        assert_eq!(storage.finalize_install(), Err(StorageError::CustomError));
+        assert!(!storage.slot_metadata(Slot::B).valid);
+    }
+
+    #[test]
+    fn refuse_downgrade() {
+        let mut env = TestEnv::new();
+        let (private_key, public_key) = signing_key(env.rng());
+        let mut storage = BufferUpgradeStorage::new(public_key).unwrap();
+        storage.set_running_firmware_version(3);
+        assert!(!storage.can_install(2));
+        assert!(storage.can_install(3));
+
+        let (metadata, firmware) = bundle(&private_key, 2);
+        assert_eq!(
+            storage.write_bundle(0, metadata.clone()),
+            Err(StorageError::CustomError)
+        );
+
+        // Even if the metadata was written while an older version was running.
+        storage.set_running_firmware_version(1);
+        assert_eq!(storage.write_bundle(0, metadata), Ok(()));
+        storage.set_running_firmware_version(3);
+        assert_eq!(
+            storage.write_bundle(METADATA_LENGTH, firmware),
+            Err(StorageError::CustomError)
+        );
+        assert!(!storage.slot_metadata(Slot::B).valid);
+    }
+
+    #[test]
+    fn accept_signed_bundle() {
+        let mut env = TestEnv::new();
+        let (private_key, public_key) = signing_key(env.rng());
+        let mut storage = BufferUpgradeStorage::new(public_key).unwrap();
+        let (metadata, firmware) = bundle(&private_key, 1);
+        assert_eq!(storage.write_bundle(0, metadata.clone()), Ok(()));
+        assert_eq!(storage.write_bundle(METADATA_LENGTH, firmware), Ok(()));
+        assert!(storage.slot_metadata(Slot::B).valid);
+        assert_eq!(
+            storage.read_partition(0, METADATA_LENGTH).unwrap(),
+            &metadata[..]
+        );
+    }
+
+    #[test]
+    fn reject_tampered_bundle() {
+        let mut env = TestEnv::new();
+        let (private_key, public_key) = signing_key(env.rng());
+        let mut storage = BufferUpgradeStorage::new(public_key).unwrap();
+
+        // The hash matches the firmware, but the signature is over a different one.
+        let (mut metadata, mut firmware) = bundle(&private_key, 1);
+        firmware[0] ^= 0x01;
+        let mut signed_over_data = metadata[METADATA_SIGN_OFFSET..].to_vec();
+        signed_over_data.extend(&firmware);
+        metadata[..32].copy_from_slice(&Sha256::hash(&signed_over_data));
+        assert_eq!(storage.write_bundle(0, metadata), Ok(()));
+        assert_eq!(
+            storage.write_bundle(METADATA_LENGTH, firmware),
+            Err(StorageError::CustomError)
+        );
+        assert!(!storage.slot_metadata(Slot::B).valid);
+        assert_eq!(
+            storage.read_partition(0, METADATA_LENGTH).unwrap(),
+            &[0xFF; METADATA_LENGTH][..]
+        );
+
+        // A bundle signed with another key is rejected as well.
+        let (other_private_key, _) = signing_key(env.rng());
+        let (metadata, firmware) = bundle(&other_private_key, 1);
+        assert_eq!(storage.write_bundle(0, metadata), Ok(()));
+        assert_eq!(
+            storage.write_bundle(METADATA_LENGTH, firmware),
+            Err(StorageError::CustomError)
+        );
+        assert!(!storage.slot_metadata(Slot::B).valid);
+    }
+
+    #[test]
+    fn write_chunks() {
+        let mut env = TestEnv::new();
+        let (private_key, public_key) = signing_key(env.rng());
+        let mut storage = BufferUpgradeStorage::new(public_key).unwrap();
+        let (mut bundle, firmware) = bundle(&private_key, 1);
+        bundle.extend(firmware);
+        let hash = Sha256::hash(&bundle);
+        assert_eq!(storage.written_so_far(), 0);
+        assert_eq!(
+            storage.write_chunk(0, bundle[..METADATA_LENGTH].to_vec()),
+            Err(StorageError::CustomError)
+        );
+        assert_eq!(
+            storage.start_chunked_write(PARTITION_LENGTH - 1, hash),
+            Err(StorageError::OutOfBounds)
+        );
+        assert_eq!(storage.start_chunked_write(PARTITION_LENGTH, hash), Ok(()));
+
+        let chunks: Vec<(usize, &[u8])> = [0, METADATA_LENGTH, 0x20000, 0x30000]
+            .iter()
+            .zip([METADATA_LENGTH, 0x20000, 0x30000, PARTITION_LENGTH].iter())
+            .map(|(&start, &end)| (start, &bundle[start..end]))
+            .collect();
+        assert_eq!(storage.write_chunk(0, chunks[0].1.to_vec()), Ok(()));
+        assert_eq!(storage.written_so_far(), METADATA_LENGTH);
+
+        // Out of order chunks leave a gap.
+        assert_eq!(
+            storage.write_chunk(chunks[2].0, chunks[2].1.to_vec()),
+            Err(StorageError::OutOfBounds)
+        );
+        assert_eq!(
+            storage.write_chunk(chunks[1].0, chunks[1].1.to_vec()),
+            Ok(())
+        );
+        // Duplicate chunks overlap.
+        assert_eq!(
+            storage.write_chunk(chunks[1].0, chunks[1].1.to_vec()),
+            Err(StorageError::OutOfBounds)
+        );
+        assert_eq!(storage.written_so_far(), 0x20000);
+
+        // The host resumes from the written length.
+        let offset = storage.written_so_far();
+        assert_eq!(storage.write_chunk(offset, chunks[2].1.to_vec()), Ok(()));
+        assert!(!storage.slot_metadata(Slot::B).valid);
+        assert_eq!(
+            storage.write_chunk(chunks[3].0, chunks[3].1.to_vec()),
+            Ok(())
+        );
+        assert_eq!(storage.written_so_far(), PARTITION_LENGTH);
+        assert!(storage.slot_metadata(Slot::B).valid);
+    }
+
+    #[test]
+    fn write_chunks_with_wrong_hash() {
+        let mut env = TestEnv::new();
+        let (private_key, public_key) = signing_key(env.rng());
+        let mut storage = BufferUpgradeStorage::new(public_key).unwrap();
+        let (metadata, firmware) = bundle(&private_key, 1);
+        assert_eq!(
+            storage.start_chunked_write(PARTITION_LENGTH, [0x55; 32]),
+            Ok(())
+        );
+        assert_eq!(storage.write_chunk(0, metadata), Ok(()));
+        assert_eq!(
+            storage.write_chunk(METADATA_LENGTH, firmware),
+            Err(StorageError::CustomError)
+        );
+        assert_eq!(storage.written_so_far(), METADATA_LENGTH);
+        assert!(!storage.slot_metadata(Slot::B).valid);
+    }
+
     #[test]
     fn partition_slice() {
-        let storage = BufferUpgradeStorage::new().unwrap();
+        let storage = BufferUpgradeStorage::new(*UPGRADE_PUBLIC_KEY).unwrap();
         assert_eq!(storage.bundle_identifier(), 0x60000);
     }
 }
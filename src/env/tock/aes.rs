@@ -0,0 +1,213 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::api::aes::{Aes256, BLOCK_SIZE};
+use crypto::aes256::{DecryptionKey, EncryptionKey};
+use crypto::util::xor_block_16;
+use libtock_drivers::aes;
+
+/// Block engine that is shared with other users.
+pub trait EcbEngine {
+    /// Encrypts blocks in place, each on its own.
+    ///
+    /// Returns whether the blocks were encrypted. If the engine is busy or missing, returns false
+    /// and leaves the blocks unchanged. At most [`aes::MAX_LENGTH`] bytes are given at once.
+    fn encrypt(&mut self, key: &[u8; 32], blocks: &mut [u8]) -> bool;
+
+    /// Decrypts blocks in place, each on its own.
+    ///
+    /// See [`Self::encrypt`] for the contract.
+    fn decrypt(&mut self, key: &[u8; 32], blocks: &mut [u8]) -> bool;
+}
+
+/// The AES hardware engine, through the Tock driver.
+pub struct HardwareEngine {
+    available: bool,
+}
+
+impl HardwareEngine {
+    pub fn new() -> Self {
+        HardwareEngine {
+            available: aes::is_available().is_ok(),
+        }
+    }
+}
+
+impl EcbEngine for HardwareEngine {
+    fn encrypt(&mut self, key: &[u8; 32], blocks: &mut [u8]) -> bool {
+        self.available && aes::encrypt_ecb(key, blocks).is_ok()
+    }
+
+    fn decrypt(&mut self, key: &[u8; 32], blocks: &mut [u8]) -> bool {
+        self.available && aes::decrypt_ecb(key, blocks).is_ok()
+    }
+}
+
+/// AES-256 on a shared engine, falling back to software instead of waiting while it is busy.
+///
+/// The choice is made for each call to the engine, so a single message may be processed partly
+/// in hardware and partly in software.
+pub struct FallbackAes256<E: EcbEngine> {
+    engine: E,
+}
+
+/// AES-256 using the hardware engine when it is free.
+pub type TockAes256 = FallbackAes256<HardwareEngine>;
+
+impl<E: EcbEngine> FallbackAes256<E> {
+    pub fn new(engine: E) -> Self {
+        FallbackAes256 { engine }
+    }
+}
+
+impl<E: EcbEngine> Aes256 for FallbackAes256<E> {
+    fn cbc_encrypt(&mut self, key: &[u8; 32], iv: &[u8; BLOCK_SIZE], blocks: &mut [u8]) {
+        assert_eq!(blocks.len() % BLOCK_SIZE, 0);
+        let mut software_key = None;
+        let mut chain = *iv;
+        // Each block depends on the previous ciphertext, so blocks go one by one.
+        for block in blocks.chunks_exact_mut(BLOCK_SIZE) {
+            let block = array_mut_ref!(block, 0, BLOCK_SIZE);
+            xor_block_16(block, &chain);
+            if !self.engine.encrypt(key, block) {
+                software_key
+                    .get_or_insert_with(|| EncryptionKey::new(key))
+                    .encrypt_block(block);
+            }
+            chain = *block;
+        }
+    }
+
+    fn cbc_decrypt(&mut self, key: &[u8; 32], iv: &[u8; BLOCK_SIZE], blocks: &mut [u8]) {
+        assert_eq!(blocks.len() % BLOCK_SIZE, 0);
+        let mut software_key = None;
+        let mut chain = *iv;
+        for chunk in blocks.chunks_mut(aes::MAX_LENGTH) {
+            let mut ciphertext = [0; aes::MAX_LENGTH];
+            ciphertext[..chunk.len()].copy_from_slice(chunk);
+            if !self.engine.decrypt(key, chunk) {
+                let software_key = software_key
+                    .get_or_insert_with(|| DecryptionKey::new(&EncryptionKey::new(key)));
+                for block in chunk.chunks_exact_mut(BLOCK_SIZE) {
+                    software_key.decrypt_block(array_mut_ref!(block, 0, BLOCK_SIZE));
+                }
+            }
+            for (block, ciphertext) in chunk
+                .chunks_exact_mut(BLOCK_SIZE)
+                .zip(ciphertext.chunks_exact(BLOCK_SIZE))
+            {
+                xor_block_16(array_mut_ref!(block, 0, BLOCK_SIZE), &chain);
+                chain = *array_ref!(ciphertext, 0, BLOCK_SIZE);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::aes::SoftwareAes256;
+
+    /// Engine computing in software, busy every `period` calls.
+    struct FakeEngine {
+        period: usize,
+        calls: usize,
+        busy_calls: usize,
+    }
+
+    impl FakeEngine {
+        fn new(period: usize) -> Self {
+            FakeEngine {
+                period,
+                calls: 0,
+                busy_calls: 0,
+            }
+        }
+
+        fn is_busy(&mut self) -> bool {
+            self.calls += 1;
+            let busy = self.calls % self.period == 0;
+            self.busy_calls += busy as usize;
+            busy
+        }
+    }
+
+    impl EcbEngine for FakeEngine {
+        fn encrypt(&mut self, key: &[u8; 32], blocks: &mut [u8]) -> bool {
+            assert!(blocks.len() <= aes::MAX_LENGTH);
+            if self.is_busy() {
+                return false;
+            }
+            let key = EncryptionKey::new(key);
+            for block in blocks.chunks_exact_mut(BLOCK_SIZE) {
+                key.encrypt_block(array_mut_ref!(block, 0, BLOCK_SIZE));
+            }
+            true
+        }
+
+        fn decrypt(&mut self, key: &[u8; 32], blocks: &mut [u8]) -> bool {
+            assert!(blocks.len() <= aes::MAX_LENGTH);
+            if self.is_busy() {
+                return false;
+            }
+            let key = DecryptionKey::new(&EncryptionKey::new(key));
+            for block in blocks.chunks_exact_mut(BLOCK_SIZE) {
+                key.decrypt_block(array_mut_ref!(block, 0, BLOCK_SIZE));
+            }
+            true
+        }
+    }
+
+    fn message(num_blocks: usize) -> Vec<u8> {
+        (0..num_blocks * BLOCK_SIZE).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_cross_check_with_software() {
+        let key = [0xC2; 32];
+        let iv = [0x5A; BLOCK_SIZE];
+        // Period 1 is always busy, and a large period is never busy for short messages.
+        for &period in &[1, 2, 3, 1000] {
+            for &num_blocks in &[0, 1, 7, 8, 9, 20] {
+                let plaintext = message(num_blocks);
+                let mut aes = FallbackAes256::new(FakeEngine::new(period));
+
+                let mut expected = plaintext.clone();
+                SoftwareAes256.cbc_encrypt(&key, &iv, &mut expected);
+                let mut ciphertext = plaintext.clone();
+                aes.cbc_encrypt(&key, &iv, &mut ciphertext);
+                assert_eq!(ciphertext, expected);
+
+                // Decrypting with the engine matches the software.
+                aes.cbc_decrypt(&key, &iv, &mut ciphertext);
+                assert_eq!(ciphertext, plaintext);
+                SoftwareAes256.cbc_decrypt(&key, &iv, &mut expected);
+                assert_eq!(expected, plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn test_falls_back_while_busy() {
+        let key = [0xC2; 32];
+        let iv = [0x00; BLOCK_SIZE];
+        let mut aes = FallbackAes256::new(FakeEngine::new(2));
+        let mut blocks = message(4);
+        aes.cbc_encrypt(&key, &iv, &mut blocks);
+        assert_eq!(aes.engine.calls, 4);
+        assert_eq!(aes.engine.busy_calls, 2);
+        aes.cbc_decrypt(&key, &iv, &mut blocks);
+        assert_eq!(blocks, message(4));
+    }
+}
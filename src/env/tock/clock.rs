@@ -0,0 +1,113 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Monotonic clock on top of the Tock timer driver.
+
+use crate::api::clock::Clock;
+use alloc::boxed::Box;
+use libtock_drivers::result::FlexUnwrap;
+use libtock_drivers::timer;
+
+/// Period of the 24-bit counters of Nordic boards.
+const PERIOD_24_BITS: u64 = 1 << 24;
+
+/// Period of the other counters, which the timer driver truncates to 32 bits.
+const PERIOD_32_BITS: u64 = 1 << 32;
+
+/// Extends the wrapping counter of the timer driver to the ticks elapsed since boot.
+///
+/// A reading lower than the previous one means that the counter wrapped around. Counters only
+/// reach 2^24 if they have 32 bits, so lower previous readings wrap at 2^24. The counter must be
+/// read at least once per period, which is 512 s for 24 bits at 32768 Hz.
+#[derive(Default)]
+struct TickCounter {
+    last_ticks: u64,
+    elapsed_ticks: u64,
+}
+
+impl TickCounter {
+    /// Records a reading of the driver counter, and returns the ticks elapsed since boot.
+    fn update(&mut self, ticks: u32) -> u64 {
+        let ticks = ticks as u64;
+        let period = if self.last_ticks < PERIOD_24_BITS {
+            PERIOD_24_BITS
+        } else {
+            PERIOD_32_BITS
+        };
+        let delta = if ticks >= self.last_ticks {
+            ticks - self.last_ticks
+        } else {
+            period - self.last_ticks + ticks
+        };
+        self.last_ticks = ticks;
+        self.elapsed_ticks += delta;
+        self.elapsed_ticks
+    }
+}
+
+/// Clock reading the Tock timer driver.
+pub struct TockClock {
+    timer: timer::Timer<'static>,
+    counter: TickCounter,
+}
+
+impl TockClock {
+    pub fn new() -> Self {
+        let callback = Box::new(timer::with_callback(|_, _| {}));
+        let timer = Box::leak(callback).init().flex_unwrap();
+        TockClock {
+            timer,
+            counter: TickCounter::default(),
+        }
+    }
+}
+
+impl Clock for TockClock {
+    fn now_ms(&mut self) -> u64 {
+        let ticks = self.timer.get_current_clock().flex_unwrap().num_ticks();
+        let elapsed_ticks = self.counter.update(ticks as u32);
+        let frequency_hz = self.timer.clock_frequency().hz() as u64;
+        elapsed_ticks / frequency_hz * 1000 + elapsed_ticks % frequency_hz * 1000 / frequency_hz
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_counter_without_wrap() {
+        let mut counter = TickCounter::default();
+        assert_eq!(counter.update(100), 100);
+        assert_eq!(counter.update(100), 100);
+        assert_eq!(counter.update(250), 250);
+    }
+
+    #[test]
+    fn test_counter_wraps_at_24_bits() {
+        let mut counter = TickCounter::default();
+        assert_eq!(counter.update(0xFF_FFF0), 0xFF_FFF0);
+        assert_eq!(counter.update(0x10), 0x100_0010);
+        assert_eq!(counter.update(0xFF_FFF0), 0x1FF_FFF0);
+        assert_eq!(counter.update(0x10), 0x200_0010);
+    }
+
+    #[test]
+    fn test_counter_wraps_at_32_bits() {
+        let mut counter = TickCounter::default();
+        assert_eq!(counter.update(0x0100_0000), 0x0100_0000);
+        assert_eq!(counter.update(0xFFFF_FFF0), 0xFFFF_FFF0);
+        assert_eq!(counter.update(0x10), 0x1_0000_0010);
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Visual feedback for user presence checks.
+
+use libtock_drivers::led;
+use libtock_drivers::result::FlexUnwrap;
+
+/// How long the outcome of a user presence check is shown, in milliseconds.
+pub const OUTCOME_DURATION_MS: isize = 300;
+
+/// What the LEDs currently show.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeedbackState {
+    /// No user presence check is running.
+    Idle,
+    /// Waiting for the user to touch the device.
+    AwaitingTouch,
+    /// The user confirmed presence.
+    Success,
+    /// The check was declined, canceled or timed out.
+    Error,
+}
+
+/// LEDs lit in each state.
+///
+/// Each pattern is a bitmask where bit `i` is LED `i` as numbered by the board. Bits of LEDs the
+/// board doesn't have are ignored. Boards whose LEDs are wired differently can provide their own
+/// patterns with [`super::TockEnv::set_led_patterns`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LedPatterns {
+    pub idle: u32,
+    /// Alternating frames, one per keepalive delay.
+    pub awaiting_touch: [u32; 2],
+    pub success: u32,
+    pub error: u32,
+}
+
+pub const DEFAULT_LED_PATTERNS: LedPatterns = LedPatterns {
+    idle: 0x00,
+    awaiting_touch: [0x55, 0xAA],
+    success: 0xFF,
+    error: 0x11,
+};
+
+/// State machine choosing the LEDs to light during user presence checks.
+pub struct LedFeedback {
+    patterns: LedPatterns,
+    state: FeedbackState,
+    frame: usize,
+}
+
+impl LedFeedback {
+    pub fn new(patterns: LedPatterns) -> Self {
+        LedFeedback {
+            patterns,
+            state: FeedbackState::Idle,
+            frame: 0,
+        }
+    }
+
+    pub fn state(&self) -> FeedbackState {
+        self.state
+    }
+
+    pub fn set_patterns(&mut self, patterns: LedPatterns) {
+        self.patterns = patterns;
+    }
+
+    /// Returns whether frames of the awaiting touch state were shown since entering it.
+    pub fn is_blinking(&self) -> bool {
+        self.state == FeedbackState::AwaitingTouch && self.frame > 0
+    }
+
+    /// Switches to a new state, starting from its first frame.
+    pub fn set_state(&mut self, state: FeedbackState) {
+        self.state = state;
+        self.frame = 0;
+    }
+
+    /// Returns the LEDs to light for the current frame and advances to the next frame.
+    pub fn next_frame(&mut self) -> u32 {
+        let pattern = match self.state {
+            FeedbackState::Idle => self.patterns.idle,
+            FeedbackState::AwaitingTouch => {
+                let frames = &self.patterns.awaiting_touch;
+                frames[self.frame % frames.len()]
+            }
+            FeedbackState::Success => self.patterns.success,
+            FeedbackState::Error => self.patterns.error,
+        };
+        self.frame = self.frame.wrapping_add(1);
+        pattern
+    }
+
+    /// Advances to the next frame and shows it on the LEDs.
+    pub fn show_next_frame(&mut self) {
+        show_pattern(self.next_frame());
+    }
+}
+
+/// Lights exactly the LEDs of the pattern.
+pub fn show_pattern(pattern: u32) {
+    for l in 0..led::count().flex_unwrap() {
+        let lit = l < 32 && pattern & (1 << l) != 0;
+        led::get(l).flex_unwrap().set_state(lit).flex_unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_idle_by_default() {
+        let mut feedback = LedFeedback::new(DEFAULT_LED_PATTERNS);
+        assert_eq!(feedback.state(), FeedbackState::Idle);
+        assert_eq!(feedback.next_frame(), DEFAULT_LED_PATTERNS.idle);
+        assert_eq!(feedback.next_frame(), DEFAULT_LED_PATTERNS.idle);
+    }
+
+    #[test]
+    fn test_awaiting_touch_alternates() {
+        let mut feedback = LedFeedback::new(DEFAULT_LED_PATTERNS);
+        feedback.set_state(FeedbackState::AwaitingTouch);
+        let [first, second] = DEFAULT_LED_PATTERNS.awaiting_touch;
+        assert_eq!(feedback.next_frame(), first);
+        assert_eq!(feedback.next_frame(), second);
+        assert_eq!(feedback.next_frame(), first);
+
+        // Entering the state again starts from the first frame.
+        feedback.set_state(FeedbackState::AwaitingTouch);
+        assert_eq!(feedback.next_frame(), first);
+    }
+
+    #[test]
+    fn test_is_blinking() {
+        let mut feedback = LedFeedback::new(DEFAULT_LED_PATTERNS);
+        feedback.next_frame();
+        assert!(!feedback.is_blinking());
+        feedback.set_state(FeedbackState::AwaitingTouch);
+        assert!(!feedback.is_blinking());
+        feedback.next_frame();
+        assert!(feedback.is_blinking());
+        feedback.set_state(FeedbackState::Success);
+        assert!(!feedback.is_blinking());
+    }
+
+    #[test]
+    fn test_outcome_and_back_to_idle() {
+        let mut feedback = LedFeedback::new(DEFAULT_LED_PATTERNS);
+        feedback.set_state(FeedbackState::Success);
+        assert_eq!(feedback.next_frame(), DEFAULT_LED_PATTERNS.success);
+        feedback.set_state(FeedbackState::Error);
+        assert_eq!(feedback.next_frame(), DEFAULT_LED_PATTERNS.error);
+        feedback.set_state(FeedbackState::Idle);
+        assert_eq!(feedback.next_frame(), DEFAULT_LED_PATTERNS.idle);
+    }
+
+    #[test]
+    fn test_patterns_are_distinct() {
+        let p = DEFAULT_LED_PATTERNS;
+        let patterns = [
+            p.idle,
+            p.awaiting_touch[0],
+            p.awaiting_touch[1],
+            p.success,
+            p.error,
+        ];
+        for (i, a) in patterns.iter().enumerate() {
+            for b in &patterns[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_patterns() {
+        let patterns = LedPatterns {
+            idle: 0x01,
+            awaiting_touch: [0x02, 0x04],
+            success: 0x08,
+            error: 0x10,
+        };
+        let mut feedback = LedFeedback::new(DEFAULT_LED_PATTERNS);
+        feedback.set_patterns(patterns);
+        assert_eq!(feedback.next_frame(), 0x01);
+        feedback.set_state(FeedbackState::AwaitingTouch);
+        assert_eq!(feedback.next_frame(), 0x02);
+        assert_eq!(feedback.next_frame(), 0x04);
+    }
+}
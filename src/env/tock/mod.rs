@@ -12,31 +12,74 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub use self::aes::{EcbEngine, FallbackAes256, HardwareEngine, TockAes256};
+pub use self::clock::TockClock;
+pub use self::feedback::{FeedbackState, LedFeedback, LedPatterns, DEFAULT_LED_PATTERNS};
 pub use self::storage::{TockStorage, TockUpgradeStorage};
 use crate::api::attestation_store::AttestationStore;
+#[cfg(feature = "with_nfc")]
+use crate::api::connection::NfcTransport;
 use crate::api::connection::{HidConnection, SendOrRecvError, SendOrRecvResult, SendOrRecvStatus};
-use crate::api::customization::{CustomizationImpl, DEFAULT_CUSTOMIZATION};
-use crate::api::firmware_protection::FirmwareProtection;
+use crate::api::customization::{Customization, CustomizationImpl, DEFAULT_CUSTOMIZATION};
+use crate::api::firmware_protection::{FirmwareProtection, FirmwareVersion};
+use crate::api::power::Power;
 use crate::api::user_presence::{UserPresence, UserPresenceError, UserPresenceResult};
-use crate::api::{attestation_store, key_store};
+use crate::api::user_verification::UserVerification;
+#[cfg(feature = "vendor_hid")]
+use crate::api::vendor_command;
+use crate::api::watchdog::Watchdog;
+use crate::api::{attestation_store, key_store, signature_counter};
 use crate::clock::{ClockInt, KEEPALIVE_DELAY_MS};
+#[cfg(feature = "defmt")]
+use crate::env::defmt_writer::DefmtWriter;
 use crate::env::Env;
 use core::cell::Cell;
 use core::sync::atomic::{AtomicBool, Ordering};
 use embedded_time::duration::Milliseconds;
 use embedded_time::fixed_point::FixedPoint;
-use libtock_core::result::{CommandError, EALREADY};
+use libtock_core::result::{
+    AllowError, CommandError, SubscribeError, EALREADY, EBUSY, ECANCEL, EINVAL, ENOACK, ENODEVICE,
+    ENOMEM, ENOSUPPORT, EOFF, ERESERVE, ESIZE, EUNINSTALLED, SUCCESS,
+};
 use libtock_drivers::buttons::{self, ButtonState};
+#[cfg(not(feature = "defmt"))]
 use libtock_drivers::console::Console;
 use libtock_drivers::result::{FlexUnwrap, TockError};
 use libtock_drivers::timer::Duration;
 use libtock_drivers::usb_ctap_hid::{self, UsbEndpoint};
-use libtock_drivers::{crp, led, timer};
+use libtock_drivers::{crp, led, timer, watchdog};
 use persistent_store::{StorageResult, Store};
 use rng256::TockRng256;
 
+mod aes;
+mod clock;
+mod feedback;
 mod storage;
 
+/// Firmware version of this build, see `build.rs`.
+const FIRMWARE_VERSION: &[u8; FirmwareVersion::BYTES_LENGTH] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/opensk_firmware_version.bin"));
+
+/// Secret of the store encryption keys, see `build.rs`.
+///
+/// It is part of the firmware image, so that a dump of the store alone doesn't reveal the keys.
+const STORE_ENCRYPTION_SECRET: &[u8; 32] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/opensk_store_secret.bin"));
+
+#[cfg(feature = "vendor_hid")]
+const VENDOR_COMMANDS: &[vendor_command::Entry<TockEnv>] = &[vendor_command::Entry {
+    command: vendor_command::FIRMWARE_VERSION_COMMAND,
+    handler: vendor_command::firmware_version,
+}];
+
+/// Random number generator backed by the Tock rng driver.
+///
+/// With `rng_health_check`, the driver output is health tested and conditioned.
+#[cfg(not(feature = "rng_health_check"))]
+pub type TockRng = TockRng256;
+#[cfg(feature = "rng_health_check")]
+pub type TockRng = crypto::entropy::HealthCheckedRng256<TockRng256>;
+
 pub struct TockHidConnection {
     endpoint: UsbEndpoint,
 }
@@ -57,19 +100,49 @@ impl HidConnection for TockHidConnection {
             Ok(usb_ctap_hid::SendOrRecvStatus::Received(recv_endpoint)) => {
                 Ok(SendOrRecvStatus::Received(recv_endpoint))
             }
-            _ => Err(SendOrRecvError),
+            Err(error) => Err(send_or_recv_error(&error)),
         }
     }
 }
 
+/// Converts the error of a failed transport syscall.
+pub fn send_or_recv_error(error: &TockError) -> SendOrRecvError {
+    let return_code = match error {
+        TockError::Subscribe(SubscribeError { return_code, .. })
+        | TockError::Command(CommandError { return_code, .. })
+        | TockError::Allow(AllowError { return_code, .. }) => *return_code,
+        TockError::Format | TockError::Other(_) => return SendOrRecvError::Other,
+    };
+    match return_code {
+        EBUSY | EALREADY => SendOrRecvError::Busy,
+        EINVAL => SendOrRecvError::InvalidParameter,
+        ESIZE => SendOrRecvError::InvalidLength,
+        ECANCEL => SendOrRecvError::Canceled,
+        ENOACK => SendOrRecvError::NoAck,
+        ENOMEM => SendOrRecvError::NoMemory,
+        EOFF | ERESERVE | ENOSUPPORT | ENODEVICE | EUNINSTALLED => SendOrRecvError::Unavailable,
+        // Failed syscalls don't return SUCCESS. If the kernel still does, the call failed anyway.
+        SUCCESS => SendOrRecvError::Other,
+        // FAIL and unknown codes.
+        _ => SendOrRecvError::Other,
+    }
+}
+
+/// The NFC driver is not bound to CTAP yet, so no command APDU is ever received.
+#[cfg(feature = "with_nfc")]
+impl NfcTransport for TockEnv {}
+
 pub struct TockEnv {
-    rng: TockRng256,
+    rng: TockRng,
     store: Store<TockStorage>,
     upgrade_storage: Option<TockUpgradeStorage>,
     main_connection: TockHidConnection,
     #[cfg(feature = "vendor_hid")]
     vendor_connection: TockHidConnection,
-    blink_pattern: usize,
+    led_feedback: LedFeedback,
+    aes: TockAes256,
+    clock: TockClock,
+    store_encryption_cache: key_store::StoreEncryptionCache,
 }
 
 impl TockEnv {
@@ -82,9 +155,13 @@ impl TockEnv {
         // We rely on `take_storage` to ensure that this function is called only once.
         let storage = take_storage().unwrap();
         let store = Store::new(storage).ok().unwrap();
-        let upgrade_storage = TockUpgradeStorage::new().ok();
+        let upgrade_storage =
+            TockUpgradeStorage::new(DEFAULT_CUSTOMIZATION.upgrade_public_key()).ok();
         TockEnv {
+            #[cfg(not(feature = "rng_health_check"))]
             rng: TockRng256 {},
+            #[cfg(feature = "rng_health_check")]
+            rng: crypto::entropy::HealthCheckedRng256::new(TockRng256 {}),
             store,
             upgrade_storage,
             main_connection: TockHidConnection {
@@ -94,9 +171,17 @@ impl TockEnv {
             vendor_connection: TockHidConnection {
                 endpoint: UsbEndpoint::VendorHid,
             },
-            blink_pattern: 0,
+            led_feedback: LedFeedback::new(DEFAULT_LED_PATTERNS),
+            aes: TockAes256::new(aes::HardwareEngine::new()),
+            clock: TockClock::new(),
+            store_encryption_cache: key_store::StoreEncryptionCache::default(),
         }
     }
+
+    /// Changes which LEDs are lit during user presence checks.
+    pub fn set_led_patterns(&mut self, patterns: LedPatterns) {
+        self.led_feedback.set_patterns(patterns);
+    }
 }
 
 /// Returns the unique storage instance.
@@ -113,14 +198,11 @@ pub fn take_storage() -> StorageResult<TockStorage> {
 
 impl UserPresence for TockEnv {
     fn check_init(&mut self) {
-        self.blink_pattern = 0;
+        self.led_feedback.set_state(FeedbackState::AwaitingTouch);
     }
     fn wait_with_timeout(&mut self, timeout: Milliseconds<ClockInt>) -> UserPresenceResult {
-        if timeout.integer() == 0 {
-            return Ok(());
-        }
-        blink_leds(self.blink_pattern);
-        self.blink_pattern += 1;
+        check_wait_duration(timeout)?;
+        self.led_feedback.show_next_frame();
 
         let button_touched = Cell::new(false);
         let mut buttons_callback = buttons::with_callback(|_button_num, state| {
@@ -175,8 +257,20 @@ impl UserPresence for TockEnv {
         }
     }
 
-    fn check_complete(&mut self) {
-        switch_off_leds();
+    fn check_complete(&mut self, result: &UserPresenceResult) {
+        // The outcome delays the response, so it is only shown if the user saw the LEDs blink.
+        if self.led_feedback.is_blinking() {
+            let outcome = match result {
+                Ok(()) => FeedbackState::Success,
+                Err(_) => FeedbackState::Error,
+            };
+            self.led_feedback.set_state(outcome);
+            self.led_feedback.show_next_frame();
+            // Even if sleeping fails, we still want to go back to idle.
+            let _ = timer::sleep(Duration::from_ms(feedback::OUTCOME_DURATION_MS));
+        }
+        self.led_feedback.set_state(FeedbackState::Idle);
+        self.led_feedback.show_next_frame();
     }
 }
 
@@ -191,9 +285,45 @@ impl FirmwareProtection for TockEnv {
                 }))
         )
     }
+
+    fn firmware_version(&self) -> FirmwareVersion {
+        FirmwareVersion::from_bytes(FIRMWARE_VERSION)
+    }
 }
 
-impl key_store::Helper for TockEnv {}
+impl key_store::Helper for TockEnv {
+    fn store_encryption_cache(&mut self) -> &mut key_store::StoreEncryptionCache {
+        &mut self.store_encryption_cache
+    }
+
+    fn store_encryption_secret(&self) -> &[u8; 32] {
+        STORE_ENCRYPTION_SECRET
+    }
+}
+
+impl signature_counter::Helper for TockEnv {}
+
+/// There is no power sensing driver yet, so the power status is unknown.
+impl Power for TockEnv {}
+
+/// There is no biometric sensor driver, so users are verified with their PIN only.
+impl UserVerification for TockEnv {}
+
+/// Boards without a watchdog driver ignore all calls.
+impl Watchdog for TockEnv {
+    fn enable(&mut self, timeout_ms: usize) -> bool {
+        watchdog::enable(timeout_ms).is_ok()
+    }
+
+    fn kick(&mut self) {
+        // Errors mean that the watchdog is not running.
+        let _ = watchdog::kick();
+    }
+
+    fn disable(&mut self) {
+        let _ = watchdog::disable();
+    }
+}
 
 impl AttestationStore for TockEnv {
     fn get(
@@ -219,16 +349,27 @@ impl AttestationStore for TockEnv {
 }
 
 impl Env for TockEnv {
-    type Rng = TockRng256;
+    type Rng = TockRng;
     type UserPresence = Self;
+    type UserVerification = Self;
     type Storage = TockStorage;
     type KeyStore = Self;
     type AttestationStore = Self;
+    type SignatureCounter = Self;
+    type Power = Self;
+    type Aes = TockAes256;
+    type Watchdog = Self;
+    type Clock = TockClock;
     type UpgradeStorage = TockUpgradeStorage;
     type FirmwareProtection = Self;
+    #[cfg(not(feature = "defmt"))]
     type Write = Console;
+    #[cfg(feature = "defmt")]
+    type Write = DefmtWriter;
     type Customization = CustomizationImpl;
     type HidConnection = TockHidConnection;
+    #[cfg(feature = "with_nfc")]
+    type NfcTransport = Self;
 
     fn rng(&mut self) -> &mut Self::Rng {
         &mut self.rng
@@ -238,6 +379,10 @@ impl Env for TockEnv {
         self
     }
 
+    fn user_verification(&mut self) -> &mut Self {
+        self
+    }
+
     fn store(&mut self) -> &mut Store<Self::Storage> {
         &mut self.store
     }
@@ -250,6 +395,26 @@ impl Env for TockEnv {
         self
     }
 
+    fn signature_counter(&mut self) -> &mut Self {
+        self
+    }
+
+    fn power(&mut self) -> &mut Self {
+        self
+    }
+
+    fn aes(&mut self) -> &mut Self::Aes {
+        &mut self.aes
+    }
+
+    fn watchdog(&mut self) -> &mut Self {
+        self
+    }
+
+    fn clock(&mut self) -> &mut Self::Clock {
+        &mut self.clock
+    }
+
     fn upgrade_storage(&mut self) -> Option<&mut Self::UpgradeStorage> {
         self.upgrade_storage.as_mut()
     }
@@ -258,10 +423,16 @@ impl Env for TockEnv {
         self
     }
 
+    #[cfg(not(feature = "defmt"))]
     fn write(&mut self) -> Self::Write {
         Console::new()
     }
 
+    #[cfg(feature = "defmt")]
+    fn write(&mut self) -> Self::Write {
+        DefmtWriter::new()
+    }
+
     fn customization(&self) -> &Self::Customization {
         &DEFAULT_CUSTOMIZATION
     }
@@ -274,6 +445,16 @@ impl Env for TockEnv {
     fn vendor_hid_connection(&mut self) -> &mut Self::HidConnection {
         &mut self.vendor_connection
     }
+
+    #[cfg(feature = "with_nfc")]
+    fn nfc_transport(&mut self) -> &mut Self::NfcTransport {
+        self
+    }
+
+    #[cfg(feature = "vendor_hid")]
+    fn vendor_commands(&self) -> &[vendor_command::Entry<Self>] {
+        VENDOR_COMMANDS
+    }
 }
 
 pub fn blink_leds(pattern_seed: usize) {
@@ -319,6 +500,17 @@ pub fn wink_leds(pattern_seed: usize) {
     }
 }
 
+/// Fails with a timeout if a wait has no time left to register a touch.
+///
+/// Touches are only registered while waiting, so an expired wait must never report one.
+fn check_wait_duration(timeout: Milliseconds<ClockInt>) -> UserPresenceResult {
+    if timeout.integer() == 0 {
+        Err(UserPresenceError::Timeout)
+    } else {
+        Ok(())
+    }
+}
+
 pub fn switch_off_leds() {
     for l in 0..led::count().flex_unwrap() {
         led::get(l).flex_unwrap().off().flex_unwrap();
@@ -326,3 +518,53 @@ pub fn switch_off_leds() {
 }
 
 pub const KEEPALIVE_DELAY_TOCK: Duration<isize> = Duration::from_ms(KEEPALIVE_DELAY_MS as isize);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expired_wait_is_not_a_touch() {
+        assert!(matches!(
+            check_wait_duration(Milliseconds(0)),
+            Err(UserPresenceError::Timeout)
+        ));
+        assert!(check_wait_duration(Milliseconds(1)).is_ok());
+    }
+
+    #[test]
+    fn test_send_or_recv_error() {
+        let command_error = |return_code| {
+            send_or_recv_error(&TockError::Command(CommandError {
+                driver_number: 0,
+                command_number: 0,
+                arg1: 0,
+                arg2: 0,
+                return_code,
+            }))
+        };
+        let expected = [
+            (SUCCESS, SendOrRecvError::Other),
+            (libtock_core::result::FAIL, SendOrRecvError::Other),
+            (EBUSY, SendOrRecvError::Busy),
+            (EALREADY, SendOrRecvError::Busy),
+            (EOFF, SendOrRecvError::Unavailable),
+            (ERESERVE, SendOrRecvError::Unavailable),
+            (EINVAL, SendOrRecvError::InvalidParameter),
+            (ESIZE, SendOrRecvError::InvalidLength),
+            (ECANCEL, SendOrRecvError::Canceled),
+            (ENOMEM, SendOrRecvError::NoMemory),
+            (ENOSUPPORT, SendOrRecvError::Unavailable),
+            (ENODEVICE, SendOrRecvError::Unavailable),
+            (EUNINSTALLED, SendOrRecvError::Unavailable),
+            (ENOACK, SendOrRecvError::NoAck),
+        ];
+        for (return_code, error) in expected.iter() {
+            assert_eq!(command_error(*return_code), *error);
+        }
+        assert_eq!(
+            send_or_recv_error(&TockError::Format),
+            SendOrRecvError::Other
+        );
+    }
+}
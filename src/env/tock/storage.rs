@@ -12,23 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::api::upgrade_storage::helper::{find_slice, is_aligned, ModRange, Partition};
-use crate::api::upgrade_storage::UpgradeStorage;
+use crate::api::upgrade_storage::helper::{
+    find_slice, is_aligned, parse_metadata_hash, parse_metadata_signature, parse_metadata_version,
+    verify_signature, ChunkedWrite, ModRange, Partition, METADATA_SIGN_OFFSET,
+};
+use crate::api::upgrade_storage::{Slot, SlotMetadata, UpgradeStorage};
 use alloc::borrow::Cow;
 use alloc::vec::Vec;
-use arrayref::array_ref;
 use byteorder::{ByteOrder, LittleEndian};
 use core::cell::Cell;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
 use crypto::sha256::Sha256;
-use crypto::{ecdsa, Hash256};
+use crypto::Hash256;
 use libtock_core::{callback, syscalls};
 use persistent_store::{Storage, StorageError, StorageIndex, StorageResult};
 
 const DRIVER_NUMBER: usize = 0x50003;
-const METADATA_SIGN_OFFSET: usize = 0x800;
-
-const UPGRADE_PUBLIC_KEY: &[u8; 65] =
-    include_bytes!(concat!(env!("OUT_DIR"), "/opensk_upgrade_pubkey.bin"));
 
 mod subscribe_nr {
     pub const DONE: usize = 0;
@@ -76,6 +76,27 @@ fn memop(nr: u32, arg: usize) -> StorageResult<usize> {
     }
 }
 
+/// Whether a storage command is waiting for its completion.
+///
+/// Callbacks run while we yield for a command to complete. If one of them accessed the storage, it
+/// would replace the allowed slice and the subscription of the pending command.
+static COMMAND_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Runs a storage command, unless another one is still in progress.
+///
+/// # Errors
+///
+/// Returns `Busy` without running the command if called while another command is in progress, for
+/// example from a callback.
+fn exclusive<T>(command: impl FnOnce() -> StorageResult<T>) -> StorageResult<T> {
+    if COMMAND_IN_PROGRESS.swap(true, Ordering::SeqCst) {
+        return Err(StorageError::Busy);
+    }
+    let result = command();
+    COMMAND_IN_PROGRESS.store(false, Ordering::SeqCst);
+    result
+}
+
 fn block_command(driver: usize, cmd: usize, arg1: usize, arg2: usize) -> StorageResult<()> {
     let done = Cell::new(None);
     let mut alarm = |status| done.set(Some(status));
@@ -106,25 +127,27 @@ unsafe fn read_slice(address: usize, length: usize) -> &'static [u8] {
 }
 
 fn write_slice(ptr: usize, value: &[u8]) -> StorageResult<()> {
-    let code = unsafe {
-        syscalls::raw::allow(
-            DRIVER_NUMBER,
-            allow_nr::WRITE_SLICE,
-            // We rely on the driver not writing to the slice. This should use read-only allow
-            // when available. See https://github.com/tock/tock/issues/1274.
-            value.as_ptr() as *mut u8,
-            value.len(),
-        )
-    };
-    if code < 0 {
-        return Err(StorageError::CustomError);
-    }
+    exclusive(|| {
+        let code = unsafe {
+            syscalls::raw::allow(
+                DRIVER_NUMBER,
+                allow_nr::WRITE_SLICE,
+                // We rely on the driver not writing to the slice. This should use read-only allow
+                // when available. See https://github.com/tock/tock/issues/1274.
+                value.as_ptr() as *mut u8,
+                value.len(),
+            )
+        };
+        if code < 0 {
+            return Err(StorageError::CustomError);
+        }
 
-    block_command(DRIVER_NUMBER, command_nr::WRITE_SLICE, ptr, value.len())
+        block_command(DRIVER_NUMBER, command_nr::WRITE_SLICE, ptr, value.len())
+    })
 }
 
 fn erase_page(ptr: usize, page_length: usize) -> StorageResult<()> {
-    block_command(DRIVER_NUMBER, command_nr::ERASE_PAGE, ptr, page_length)
+    exclusive(|| block_command(DRIVER_NUMBER, command_nr::ERASE_PAGE, ptr, page_length))
 }
 
 pub struct TockStorage {
@@ -229,12 +252,37 @@ impl Storage for TockStorage {
     }
 }
 
+/// Part of the metadata page recording whether the staged bundle was verified.
+///
+/// It lies in the padding between the signature and [`METADATA_SIGN_OFFSET`], which is neither
+/// hashed nor signed. Each change of the flag programs the next erased word, so the bundle is
+/// valid if an odd number of words is programmed. Erasing the metadata page clears the flag.
+const VALID_FLAG_RANGE: Range<usize> = 96..METADATA_SIGN_OFFSET;
+
+/// Size of the words programmed in [`VALID_FLAG_RANGE`].
+const VALID_FLAG_WORD_SIZE: usize = 4;
+
+/// Returns how many words of [`VALID_FLAG_RANGE`] are programmed in the metadata.
+fn count_valid_flag_words(metadata: &[u8]) -> usize {
+    metadata[VALID_FLAG_RANGE]
+        .chunks_exact(VALID_FLAG_WORD_SIZE)
+        .take_while(|word| word.iter().any(|&byte| byte != 0xFF))
+        .count()
+}
+
 pub struct TockUpgradeStorage {
     page_size: usize,
     partition: Partition,
     metadata: ModRange,
     running_metadata: ModRange,
     identifier: u32,
+    /// Whether the bundle in the upgrade slot was verified since it was last written.
+    ///
+    /// Caches the flag persisted in the metadata page, see [`VALID_FLAG_RANGE`].
+    valid: bool,
+    /// Key that upgrade bundles must be signed with, as an uncompressed P-256 point.
+    public_key: &'static [u8; 65],
+    chunked_write: Option<ChunkedWrite>,
 }
 
 impl TockUpgradeStorage {
@@ -258,13 +306,16 @@ impl TockUpgradeStorage {
     /// Returns a `NotAligned` error if partitions or metadata ranges are
     /// - not exclusive or,
     /// - not consecutive.
-    pub fn new() -> StorageResult<TockUpgradeStorage> {
+    pub fn new(public_key: &'static [u8; 65]) -> StorageResult<TockUpgradeStorage> {
         let mut locations = TockUpgradeStorage {
             page_size: get_info(command_nr::get_info_nr::PAGE_SIZE, 0)?,
             partition: Partition::new(),
             metadata: ModRange::new_empty(),
             running_metadata: ModRange::new_empty(),
             identifier: Self::PARTITION_ADDRESS_A as u32,
+            valid: false,
+            public_key,
+            chunked_write: None,
         };
         if !locations.page_size.is_power_of_two() {
             return Err(StorageError::CustomError);
@@ -311,6 +362,7 @@ impl TockUpgradeStorage {
         if !locations.partition.append(firmware_range) {
             return Err(StorageError::NotAligned);
         }
+        locations.valid = count_valid_flag_words(locations.read_metadata()) % 2 == 1;
         Ok(locations)
     }
 
@@ -318,6 +370,34 @@ impl TockUpgradeStorage {
         is_aligned(self.page_size, x)
     }
 
+    /// Returns the metadata page of the upgrade slot.
+    fn read_metadata(&self) -> &'static [u8] {
+        unsafe { read_slice(self.metadata.start(), self.metadata.length()) }
+    }
+
+    /// Persists whether the staged bundle was verified.
+    fn set_valid(&mut self, valid: bool) -> StorageResult<()> {
+        if self.valid == valid {
+            return Ok(());
+        }
+        let num_words = count_valid_flag_words(self.read_metadata());
+        if (num_words + 1) * VALID_FLAG_WORD_SIZE > VALID_FLAG_RANGE.len() {
+            if valid {
+                return Err(StorageError::CustomError);
+            }
+            // Erasing the metadata clears the flag, and makes sure the bundle is never booted.
+            erase_page(self.metadata.start(), self.page_size)?;
+        } else {
+            let address = self.metadata.start() + VALID_FLAG_RANGE.start;
+            write_slice(
+                address + num_words * VALID_FLAG_WORD_SIZE,
+                &[0x00; VALID_FLAG_WORD_SIZE],
+            )?;
+        }
+        self.valid = valid;
+        Ok(())
+    }
+
     /// Returns whether the metadata is contained in this range or not.
     ///
     /// Assumes that metadata is written in one call per range. If the metadata is only partially
@@ -336,18 +416,22 @@ impl TockUpgradeStorage {
 
     /// Checks if the metadata's hash matches the partition's content.
     fn check_partition_hash(&self, metadata: &[u8]) -> StorageResult<()> {
-        let start_address = self.metadata.start() + METADATA_SIGN_OFFSET;
+        let computed_hash = self.partition_hash(self.metadata.start() + METADATA_SIGN_OFFSET);
+        if &computed_hash != parse_metadata_hash(metadata) {
+            return Err(StorageError::CustomError);
+        }
+        Ok(())
+    }
+
+    /// Computes the hash of the partition's content, starting at the given address.
+    fn partition_hash(&self, start_address: usize) -> [u8; 32] {
         let mut hasher = Sha256::new();
         for range in self.partition.ranges_from(start_address) {
             let partition_slice = unsafe { read_slice(range.start(), range.length()) };
             // The hash implementation handles this in chunks, so no memory issues.
             hasher.update(partition_slice);
         }
-        let computed_hash = hasher.finalize();
-        if &computed_hash != parse_metadata_hash(metadata) {
-            return Err(StorageError::CustomError);
-        }
-        Ok(())
+        hasher.finalize()
     }
 }
 
@@ -363,8 +447,13 @@ impl UpgradeStorage for TockUpgradeStorage {
         let write_range = ModRange::new(address, data.len());
         if self.contains_metadata(&write_range)? {
             let new_metadata = &data[self.metadata.start() - address..][..self.metadata.length()];
-            check_metadata(self, UPGRADE_PUBLIC_KEY, new_metadata)?;
+            check_metadata(self, self.public_key, new_metadata)?;
+            // Only verification may set the flag.
+            if count_valid_flag_words(new_metadata) != 0 {
+                return Err(StorageError::CustomError);
+            }
         }
+        self.set_valid(false)?;
 
         // Erases all pages that have their first byte in the write range.
         // Since we expect calls in order, we don't want to erase half-written pages.
@@ -378,12 +467,27 @@ impl UpgradeStorage for TockUpgradeStorage {
         }
         // Case: Last slice is written.
         if data.len() == self.partition.length() - offset {
-            let metadata = unsafe { read_slice(self.metadata.start(), self.metadata.length()) };
-            self.check_partition_hash(metadata)?;
+            self.finalize_install()?;
+        }
+        Ok(())
+    }
+
+    fn start_chunked_write(&mut self, total_length: usize, hash: [u8; 32]) -> StorageResult<()> {
+        if total_length != self.partition.length() {
+            return Err(StorageError::OutOfBounds);
         }
+        self.chunked_write = Some(ChunkedWrite::new(total_length, hash));
         Ok(())
     }
 
+    fn chunked_write(&self) -> Option<&ChunkedWrite> {
+        self.chunked_write.as_ref()
+    }
+
+    fn chunked_write_mut(&mut self) -> Option<&mut ChunkedWrite> {
+        self.chunked_write.as_mut()
+    }
+
     fn bundle_identifier(&self) -> u32 {
         self.identifier
     }
@@ -397,17 +501,53 @@ impl UpgradeStorage for TockUpgradeStorage {
         };
         parse_metadata_version(running_metadata)
     }
+
+    fn active_slot(&self) -> Slot {
+        // The identifier is the address of the upgrade slot.
+        if self.identifier == Self::PARTITION_ADDRESS_A as u32 {
+            Slot::B
+        } else {
+            Slot::A
+        }
+    }
+
+    fn slot_metadata(&self, slot: Slot) -> SlotMetadata {
+        if slot == self.active_slot() {
+            return SlotMetadata {
+                version: self.running_firmware_version(),
+                valid: true,
+            };
+        }
+        let metadata = self.read_metadata();
+        SlotMetadata {
+            version: parse_metadata_version(metadata),
+            valid: self.valid,
+        }
+    }
+
+    fn finalize_install(&mut self) -> StorageResult<()> {
+        self.set_valid(false)?;
+        let metadata = self.read_metadata();
+        let result = check_metadata(self, self.public_key, metadata)
+            .and_then(|()| self.check_partition_hash(metadata))
+            .and_then(|()| match &self.chunked_write {
+                Some(chunked_write) => {
+                    chunked_write.check_hash(&self.partition_hash(self.metadata.start()))
+                }
+                None => Ok(()),
+            });
+        if result.is_err() {
+            // Erasing the metadata makes sure the staged bundle is never booted.
+            erase_page(self.metadata.start(), self.page_size)?;
+        }
+        result?;
+        self.set_valid(true)
+    }
 }
 
 /// Parses the metadata of an upgrade, and checks its correctness.
 ///
-/// The metadata is a page starting with:
-/// - 32 B upgrade hash (SHA256)
-/// - 64 B signature,
-/// that are not signed over. The second part is included in the signature with
-/// -  8 B version and
-/// -  4 B partition address in little endian encoding
-/// written at METADATA_SIGN_OFFSET.
+/// See [`METADATA_SIGN_OFFSET`] for the metadata layout.
 ///
 /// Checks signature correctness against the hash, and whether the partition offset matches.
 /// Whether the hash matches the partition content is not tested here!
@@ -422,7 +562,7 @@ fn check_metadata(
     }
 
     let version = parse_metadata_version(metadata);
-    if version < upgrade_locations.running_firmware_version() {
+    if !upgrade_locations.can_install(version) {
         return Err(StorageError::CustomError);
     }
 
@@ -432,41 +572,13 @@ fn check_metadata(
     }
 
     verify_signature(
-        array_ref!(metadata, 32, 64),
+        parse_metadata_signature(metadata),
         public_key_bytes,
         parse_metadata_hash(metadata),
     )?;
     Ok(())
 }
 
-/// Parses the metadata, returns the hash.
-fn parse_metadata_hash(data: &[u8]) -> &[u8; 32] {
-    array_ref!(data, 0, 32)
-}
-
-/// Parses the metadata, returns the firmware version.
-fn parse_metadata_version(data: &[u8]) -> u64 {
-    LittleEndian::read_u64(&data[METADATA_SIGN_OFFSET..][..8])
-}
-
-/// Verifies the signature over the given hash.
-///
-/// The public key is COSE encoded, and the hash is a SHA256.
-fn verify_signature(
-    signature_bytes: &[u8; 64],
-    public_key_bytes: &[u8],
-    signed_hash: &[u8; 32],
-) -> StorageResult<()> {
-    let signature =
-        ecdsa::Signature::from_bytes(signature_bytes).ok_or(StorageError::CustomError)?;
-    let public_key = ecdsa::PubKey::from_bytes_uncompressed(public_key_bytes)
-        .ok_or(StorageError::CustomError)?;
-    if !public_key.verify_hash_vartime(signed_hash, &signature) {
-        return Err(StorageError::CustomError);
-    }
-    Ok(())
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -480,7 +592,6 @@ mod test {
         let upgrade_locations = env.upgrade_storage().unwrap();
 
         const METADATA_LEN: usize = 0x1000;
-        const METADATA_SIGN_OFFSET: usize = 0x800;
         let mut metadata = vec![0xFF; METADATA_LEN];
         LittleEndian::write_u32(&mut metadata[METADATA_SIGN_OFFSET + 8..][..4], 0x60000);
 
@@ -490,7 +601,7 @@ mod test {
 
         metadata[..32].copy_from_slice(&signed_hash);
         let signature = private_key.sign_rfc6979::<Sha256>(&signed_over_data);
-        let mut signature_bytes = [0; ecdsa::Signature::BYTES_LENGTH];
+        let mut signature_bytes = [0; crypto::ecdsa::Signature::BYTES_LENGTH];
         signature.to_bytes(&mut signature_bytes);
         metadata[32..96].copy_from_slice(&signature_bytes);
 
@@ -535,38 +646,49 @@ mod test {
     }
 
     #[test]
-    fn test_verify_signature() {
-        let mut env = TestEnv::new();
-        let private_key = crypto::ecdsa::SecKey::gensk(env.rng());
-        let message = [0x44; 64];
-        let signed_hash = Sha256::hash(&message);
-        let signature = private_key.sign_rfc6979::<Sha256>(&message);
-
-        let mut signature_bytes = [0; ecdsa::Signature::BYTES_LENGTH];
-        signature.to_bytes(&mut signature_bytes);
-
-        let public_key = private_key.genpk();
-        let mut public_key_bytes = [0; 65];
-        public_key.to_bytes_uncompressed(&mut public_key_bytes);
+    fn test_count_valid_flag_words() {
+        let mut metadata = vec![0xFF; 0x1000];
+        assert_eq!(count_valid_flag_words(&metadata), 0);
+        metadata[VALID_FLAG_RANGE.start..][..VALID_FLAG_WORD_SIZE].fill(0x00);
+        assert_eq!(count_valid_flag_words(&metadata), 1);
+        // A partially programmed word counts as programmed.
+        metadata[VALID_FLAG_RANGE.start + VALID_FLAG_WORD_SIZE + 1] = 0x7F;
+        assert_eq!(count_valid_flag_words(&metadata), 2);
+        // Words after the first erased word are ignored.
+        metadata[VALID_FLAG_RANGE.start + 3 * VALID_FLAG_WORD_SIZE] = 0x00;
+        assert_eq!(count_valid_flag_words(&metadata), 2);
+        // The signed part of the metadata is outside the flag.
+        metadata[METADATA_SIGN_OFFSET..].fill(0x00);
+        metadata[..VALID_FLAG_RANGE.start].fill(0x00);
+        assert_eq!(count_valid_flag_words(&metadata), 2);
+    }
 
-        assert_eq!(
-            verify_signature(&signature_bytes, &public_key_bytes, &signed_hash),
+    #[test]
+    fn test_exclusive_rejects_reentrant_command() {
+        assert!(!COMMAND_IN_PROGRESS.load(Ordering::SeqCst));
+        let mut reentrant_command_ran = false;
+        let result = exclusive(|| {
+            assert!(COMMAND_IN_PROGRESS.load(Ordering::SeqCst));
+            // Simulates a callback accessing the storage while a command is in progress.
+            let reentrant_result = exclusive(|| {
+                reentrant_command_ran = true;
+                Ok(())
+            });
+            assert_eq!(reentrant_result, Err(StorageError::Busy));
+            // The rejected command doesn't release the lock of the running one.
+            assert!(COMMAND_IN_PROGRESS.load(Ordering::SeqCst));
             Ok(())
-        );
-        assert_eq!(
-            verify_signature(&signature_bytes, &public_key_bytes, &[0x55; 32]),
-            Err(StorageError::CustomError)
-        );
-        public_key_bytes[0] ^= 0x01;
-        assert_eq!(
-            verify_signature(&signature_bytes, &public_key_bytes, &signed_hash),
-            Err(StorageError::CustomError)
-        );
-        public_key_bytes[0] ^= 0x01;
-        signature_bytes[0] ^= 0x01;
-        assert_eq!(
-            verify_signature(&signature_bytes, &public_key_bytes, &signed_hash),
-            Err(StorageError::CustomError)
-        );
+        });
+        assert_eq!(result, Ok(()));
+        assert!(!reentrant_command_ran);
+        assert!(!COMMAND_IN_PROGRESS.load(Ordering::SeqCst));
+
+        // A failing command also releases the lock.
+        let result: StorageResult<()> = exclusive(|| Err(StorageError::CustomError));
+        assert_eq!(result, Err(StorageError::CustomError));
+        assert!(!COMMAND_IN_PROGRESS.load(Ordering::SeqCst));
+
+        // The next command is accepted again.
+        assert_eq!(exclusive(|| Ok(())), Ok(()));
     }
 }
@@ -18,14 +18,23 @@ extern crate alloc;
 #[macro_use]
 extern crate arrayref;
 
-use crate::ctap::hid::{HidPacket, HidPacketIterator};
+use crate::api::clock::Clock;
+#[cfg(feature = "with_nfc")]
+use crate::api::connection::NfcTransport;
+use crate::api::customization::Customization;
+use crate::api::watchdog::Watchdog;
+use crate::ctap::hid::{
+    CtapHid, CtapHidCommand, CtapHidError, HidPacket, HidPacketIterator, Message, MessageAssembler,
+};
 use crate::ctap::main_hid::MainHid;
+#[cfg(feature = "with_nfc")]
+use crate::ctap::nfc::Nfc;
 #[cfg(feature = "vendor_hid")]
 use crate::ctap::vendor_hid::VendorHid;
 use crate::ctap::CtapState;
 pub use crate::ctap::Transport;
 use crate::env::Env;
-use clock::CtapInstant;
+use alloc::vec::Vec;
 
 // Those macros should eventually be split into trace, debug, info, warn, and error macros when
 // adding either the defmt or log feature and crate dependency.
@@ -41,6 +50,7 @@ macro_rules! debug_ctap {
     ($env: expr, $($rest:tt)*) => {
         // To avoid unused variable warnings.
         let _ = $env;
+        let _ = format_args!($($rest)*);
     };
 }
 
@@ -59,13 +69,22 @@ pub struct Ctap<E: Env> {
     hid: MainHid,
     #[cfg(feature = "vendor_hid")]
     vendor_hid: VendorHid,
+    #[cfg(feature = "with_nfc")]
+    nfc: Nfc,
 }
 
 impl<E: Env> Ctap<E> {
     /// Instantiates a CTAP implementation given its environment.
-    // This should only take the environment, but it temporarily takes the boot time until the
-    // clock is part of the environment.
-    pub fn new(mut env: E, now: CtapInstant) -> Self {
+    ///
+    /// Also starts the watchdog if customized. From then on, the caller must kick it on each
+    /// iteration of its main loop, see [`Watchdog`](api::watchdog::Watchdog).
+    ///
+    /// Timeouts are measured with the [clock](api::clock::Clock) of the environment, starting now.
+    pub fn new(mut env: E) -> Self {
+        if let Some(timeout_ms) = env.customization().watchdog_timeout_ms() {
+            env.watchdog().enable(timeout_ms);
+        }
+        let now = env.clock().now();
         let state = CtapState::new(&mut env, now);
         let hid = MainHid::new();
         #[cfg(feature = "vendor_hid")]
@@ -76,6 +95,8 @@ impl<E: Env> Ctap<E> {
             hid,
             #[cfg(feature = "vendor_hid")]
             vendor_hid,
+            #[cfg(feature = "with_nfc")]
+            nfc: Nfc::new(),
         }
     }
 
@@ -95,8 +116,8 @@ impl<E: Env> Ctap<E> {
         &mut self,
         packet: &HidPacket,
         transport: Transport,
-        now: CtapInstant,
     ) -> HidPacketIterator {
+        let now = self.env.clock().now();
         match transport {
             Transport::MainHid => {
                 self.hid
@@ -110,8 +131,223 @@ impl<E: Env> Ctap<E> {
         }
     }
 
-    pub fn update_timeouts(&mut self, now: CtapInstant) {
+    /// Processes a complete CTAPHID message, and returns the response message.
+    ///
+    /// Both messages are encoded as the channel ID, the command byte and the payload. The message
+    /// goes through the same packet processing as on USB, so this is a single entry point to the
+    /// CTAPHID command dispatch, for example for fuzzing. Malformed messages are answered with a
+    /// CTAPHID error. The response is empty if the command has none, like CANCEL.
+    pub fn process_hid_message(&mut self, message: &[u8], transport: Transport) -> Vec<u8> {
+        let mut cid = [0; 4];
+        let header_length = core::cmp::min(message.len(), 4);
+        cid[..header_length].copy_from_slice(&message[..header_length]);
+        let packets = match message.get(4) {
+            None => None,
+            Some(&cmd) => HidPacketIterator::new(Message {
+                cid,
+                cmd: CtapHidCommand::from(cmd),
+                payload: message[5..].to_vec(),
+            }),
+        };
+        let packets = match packets {
+            Some(packets) => packets,
+            None => return CtapHid::error_message(cid, CtapHidError::InvalidLen).to_bytes(),
+        };
+        let mut assembler = MessageAssembler::new();
+        for packet in packets {
+            for response_packet in self.process_hid_packet(&packet, transport) {
+                let now = self.env.clock().now();
+                if let Ok(Some(response)) =
+                    assembler.parse_packet(&mut self.env, &response_packet, now)
+                {
+                    return response.to_bytes();
+                }
+            }
+        }
+        Vec::new()
+    }
+
+    /// Processes a command APDU received over NFC, and returns the response APDU.
+    #[cfg(feature = "with_nfc")]
+    pub fn process_nfc_apdu(&mut self, apdu: &[u8]) -> Vec<u8> {
+        let now = self.env.clock().now();
+        self.nfc
+            .process_apdu(&mut self.env, apdu, now, &mut self.state)
+    }
+
+    /// Answers the next command APDU of the NFC transport, if there is one.
+    #[cfg(feature = "with_nfc")]
+    pub fn poll_nfc(&mut self) {
+        if let Some(apdu) = self.env.nfc_transport().receive_apdu() {
+            let response = self.process_nfc_apdu(&apdu);
+            if let Err(error) = self.env.nfc_transport().send_apdu(&response) {
+                debug_ctap!(
+                    &mut self.env,
+                    "Sending the NFC response failed with error {:?}",
+                    ctap::status_code::Ctap2StatusCode::from(error)
+                );
+            }
+        }
+    }
+
+    pub fn update_timeouts(&mut self) {
+        let now = self.env.clock().now();
         self.state.update_timeouts(now);
         self.hid.update_wink_timeout(now);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::test::TestEnv;
+
+    #[test]
+    fn test_new_enables_watchdog() {
+        let env = TestEnv::new();
+        let timeout_ms = env.customization().watchdog_timeout_ms();
+        assert!(timeout_ms.is_some());
+        let mut ctap = Ctap::new(env);
+        assert_eq!(ctap.env().watchdog().timeout_ms(), timeout_ms);
+    }
+
+    #[test]
+    fn test_new_without_watchdog() {
+        let mut env = TestEnv::new();
+        env.customization_mut().set_watchdog_timeout_ms(None);
+        let mut ctap = Ctap::new(env);
+        assert_eq!(ctap.env().watchdog().timeout_ms(), None);
+    }
+
+    #[test]
+    fn test_reset_window_uses_env_clock() {
+        use crate::ctap::status_code::Ctap2StatusCode;
+
+        let reset_after_ms = |elapsed_ms: u64| {
+            let mut env = TestEnv::new();
+            env.clock().set_ms(5000);
+            let mut ctap = Ctap::new(env);
+            let cid = init_channel(&mut ctap);
+            ctap.env().clock().advance_ms(elapsed_ms);
+            ctap.update_timeouts();
+            let message = [&cid[..], &[0x10, 0x07]].concat();
+            ctap.process_hid_message(&message, Transport::MainHid)[5]
+        };
+        assert_eq!(reset_after_ms(9000), Ctap2StatusCode::CTAP2_OK as u8);
+        assert_eq!(
+            reset_after_ms(10_001),
+            Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED as u8
+        );
+    }
+
+    /// Allocates a channel, and returns its ID.
+    fn init_channel(ctap: &mut Ctap<TestEnv>) -> Vec<u8> {
+        let mut message = vec![0xFF, 0xFF, 0xFF, 0xFF, 0x06];
+        message.extend_from_slice(&[0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0]);
+        let response = ctap.process_hid_message(&message, Transport::MainHid);
+        assert_eq!(response[..5], message[..5]);
+        response[13..17].to_vec()
+    }
+
+    #[test]
+    fn test_process_hid_message() {
+        let mut ctap = Ctap::new(TestEnv::new());
+        let cid = init_channel(&mut ctap);
+        let mut message = cid.clone();
+        message.extend_from_slice(&[0x10, 0x04]);
+        let response = ctap.process_hid_message(&message, Transport::MainHid);
+        assert_eq!(response[..4], cid[..]);
+        // The response is a successful CBOR message.
+        assert_eq!(response[4..6], [0x10, 0x00]);
+
+        // Cancel has no response.
+        let mut message = cid.clone();
+        message.push(0x11);
+        let response = ctap.process_hid_message(&message, Transport::MainHid);
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn test_process_hid_message_malformed() {
+        let mut ctap = Ctap::new(TestEnv::new());
+        let cid = init_channel(&mut ctap);
+        let mut process = |message: &[u8]| ctap.process_hid_message(message, Transport::MainHid);
+
+        // Messages without a command byte.
+        assert_eq!(process(&[]), vec![0x00, 0x00, 0x00, 0x00, 0x3F, 0x03]);
+        assert_eq!(
+            process(&cid[..3]),
+            [&cid[..3], &[0x00, 0x3F, 0x03]].concat()
+        );
+        assert_eq!(process(&cid), [&cid[..], &[0x3F, 0x03]].concat());
+
+        // Payloads that don't fit in the sequence numbers of HID packets.
+        for &length in &[7610, 11857, 0x10000] {
+            let message = [&cid[..], &[0x10], &vec![0x00; length]].concat();
+            assert_eq!(process(&message), [&cid[..], &[0x3F, 0x03]].concat());
+        }
+
+        // Unknown commands, and commands on channels that were not allocated.
+        assert_eq!(
+            process(&[&cid[..], &[0x3A]].concat()),
+            [&cid[..], &[0x3F, 0x01]].concat()
+        );
+        let other_cid = [0x12, 0x34, 0x56, 0x78];
+        assert_eq!(
+            process(&[&other_cid[..], &[0x10, 0x04]].concat()),
+            [&other_cid[..], &[0x3F, 0x0B]].concat()
+        );
+
+        // Empty and truncated CBOR commands are answered with a CTAP error.
+        for payload in &[&[][..], &[0x01][..], &[0x01, 0xA1][..], &[0x06, 0xFF][..]] {
+            let response = process(&[&cid[..], &[0x10], payload].concat());
+            assert_eq!(response[..5], [&cid[..], &[0x10]].concat()[..]);
+            assert_eq!(response.len(), 6);
+            assert_ne!(response[5], 0x00);
+        }
+    }
+
+    #[test]
+    fn test_process_hid_message_length_guards() {
+        use crate::ctap::status_code::Ctap2StatusCode;
+
+        let mut ctap = Ctap::new(TestEnv::new());
+        let cid = init_channel(&mut ctap);
+        let mut process_cbor = |command: &[u8]| {
+            let message = [&cid[..], &[0x10], command].concat();
+            let response = ctap.process_hid_message(&message, Transport::MainHid);
+            assert_eq!(response[..5], message[..5]);
+            assert_eq!(response.len(), 6);
+            response[5]
+        };
+
+        // Arrays and maps announcing 2^64 - 1 elements are rejected before allocating.
+        let huge_array = [0x01, 0x9B, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        let huge_map = [0x02, 0xBB, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        for &command in &[&huge_array[..], &huge_map[..]] {
+            assert_eq!(
+                process_cbor(command),
+                Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR as u8
+            );
+        }
+
+        #[cfg(feature = "large_blobs")]
+        {
+            // Reading a large blob at offset 2^64 - 1 is rejected without overflowing.
+            let get_at_max_offset = [
+                0x0C, 0xA2, 0x01, 0x01, 0x03, 0x1B, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            ];
+            assert_eq!(
+                process_cbor(&get_at_max_offset),
+                Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH as u8
+            );
+
+            // Writing a large blob shorter than its hash is rejected without underflowing.
+            let set_short_blob = [0x0C, 0xA3, 0x02, 0x41, 0x00, 0x03, 0x00, 0x04, 0x01];
+            assert_eq!(
+                process_cbor(&set_short_blob),
+                Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER as u8
+            );
+        }
+    }
+}
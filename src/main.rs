@@ -19,31 +19,35 @@ extern crate arrayref;
 extern crate byteorder;
 #[cfg(feature = "std")]
 extern crate core;
+#[cfg(feature = "defmt-rtt")]
+extern crate defmt_rtt;
 extern crate lang_items;
 
 #[cfg(feature = "with_ctap1")]
 use core::cell::Cell;
-#[cfg(feature = "debug_ctap")]
-use core::convert::TryFrom;
 use core::convert::TryInto;
 #[cfg(feature = "debug_ctap")]
 use core::fmt::Write;
+use ctap2::api::clock::Clock;
 use ctap2::api::connection::{HidConnection, SendOrRecvStatus};
-#[cfg(feature = "debug_ctap")]
-use ctap2::clock::CtapClock;
-use ctap2::clock::{new_clock, Clock, ClockInt, KEEPALIVE_DELAY, KEEPALIVE_DELAY_MS};
+use ctap2::api::watchdog::Watchdog;
+use ctap2::clock::{ClockInt, KEEPALIVE_DELAY, KEEPALIVE_DELAY_MS};
 use ctap2::ctap::hid::HidPacketIterator;
+#[cfg(feature = "debug_ctap")]
+use ctap2::ctap::status_code::Ctap2StatusCode;
 #[cfg(feature = "with_ctap1")]
 use ctap2::env::tock::blink_leds;
+#[cfg(feature = "debug_ctap")]
+use ctap2::env::tock::send_or_recv_error;
 use ctap2::env::tock::{switch_off_leds, wink_leds, TockEnv};
+use ctap2::env::Env;
 use ctap2::Transport;
-#[cfg(feature = "debug_ctap")]
-use embedded_time::duration::Microseconds;
 use embedded_time::duration::Milliseconds;
 #[cfg(feature = "with_ctap1")]
 use libtock_drivers::buttons::{self, ButtonState};
 #[cfg(feature = "debug_ctap")]
 use libtock_drivers::console::Console;
+#[cfg(feature = "with_ctap1")]
 use libtock_drivers::result::FlexUnwrap;
 use libtock_drivers::timer::Duration;
 use libtock_drivers::usb_ctap_hid;
@@ -112,21 +116,27 @@ impl EndpointReplies {
         }
         None
     }
+
+    /// Drops the rest of the reply on a transport.
+    pub fn cancel(&mut self, transport: Transport) {
+        for ep in self.replies.iter_mut() {
+            if ep.transport == transport {
+                ep.reply = HidPacketIterator::none();
+            }
+        }
+    }
 }
 fn main() {
-    let clock = new_clock();
-
     // Setup USB driver.
     if !usb_ctap_hid::setup() {
         panic!("Cannot setup USB driver");
     }
 
-    let boot_time = clock.try_now().unwrap();
     let env = TockEnv::new();
-    let mut ctap = ctap2::Ctap::new(env, boot_time);
+    let mut ctap = ctap2::Ctap::new(env);
 
     let mut led_counter = 0;
-    let mut last_led_increment = boot_time;
+    let mut last_led_increment = ctap.env().clock().now();
 
     let mut replies = EndpointReplies::new();
 
@@ -134,6 +144,10 @@ fn main() {
     // The way TockOS and apps currently interact, callbacks need a yield syscall to execute,
     // making consistent blinking patterns and sending keepalives harder.
     loop {
+        // Each iteration takes at most the send timeout plus the processing of one packet, which
+        // the watchdog timeout accounts for.
+        ctap.env().watchdog().kick();
+
         // Create the button callback, used for CTAP1.
         #[cfg(feature = "with_ctap1")]
         let button_touched = Cell::new(false);
@@ -162,45 +176,57 @@ fn main() {
             match hid_connection.send_and_maybe_recv(&mut packet.packet, SEND_TIMEOUT) {
                 Ok(SendOrRecvStatus::Timeout) => {
                     #[cfg(feature = "debug_ctap")]
-                    print_packet_notice("Sending packet timed out", &clock);
+                    print_packet_notice("Sending packet timed out", ctap.env());
                     // TODO: reset the ctap_hid state.
                     // Since sending the packet timed out, we cancel this reply.
                     break;
                 }
                 Ok(SendOrRecvStatus::Sent) => {
                     #[cfg(feature = "debug_ctap")]
-                    print_packet_notice("Sent packet", &clock);
+                    print_packet_notice("Sent packet", ctap.env());
                 }
                 Ok(SendOrRecvStatus::Received(ep)) => {
                     #[cfg(feature = "debug_ctap")]
-                    print_packet_notice("Received another packet", &clock);
+                    print_packet_notice("Received another packet", ctap.env());
                     usb_endpoint = Some(ep);
 
                     // Copy to incoming packet to local buffer to be consistent
                     // with the receive flow.
                     pkt_request = packet.packet;
                 }
-                Err(_) => panic!("Error sending packet"),
+                Err(_error) => {
+                    #[cfg(feature = "debug_ctap")]
+                    print_error_notice("Sending packet", _error.into(), ctap.env());
+                    // The host can't receive the rest of this reply anymore.
+                    replies.cancel(packet.transport);
+                }
             }
         } else {
             // receive
             usb_endpoint =
-                match usb_ctap_hid::recv_with_timeout(&mut pkt_request, KEEPALIVE_DELAY_TOCK)
-                    .flex_unwrap()
-                {
-                    usb_ctap_hid::SendOrRecvStatus::Received(endpoint) => {
+                match usb_ctap_hid::recv_with_timeout(&mut pkt_request, KEEPALIVE_DELAY_TOCK) {
+                    Ok(usb_ctap_hid::SendOrRecvStatus::Received(endpoint)) => {
                         #[cfg(feature = "debug_ctap")]
-                        print_packet_notice("Received packet", &clock);
+                        print_packet_notice("Received packet", ctap.env());
                         Some(endpoint)
                     }
-                    usb_ctap_hid::SendOrRecvStatus::Sent => {
+                    Ok(usb_ctap_hid::SendOrRecvStatus::Sent) => {
                         panic!("Returned transmit status on receive")
                     }
-                    usb_ctap_hid::SendOrRecvStatus::Timeout => None,
+                    Ok(usb_ctap_hid::SendOrRecvStatus::Timeout) => None,
+                    Err(_error) => {
+                        #[cfg(feature = "debug_ctap")]
+                        print_error_notice(
+                            "Receiving packet",
+                            send_or_recv_error(&_error).into(),
+                            ctap.env(),
+                        );
+                        None
+                    }
                 };
         }
 
-        let now = clock.try_now().unwrap();
+        let now = ctap.env().clock().now();
         #[cfg(feature = "with_ctap1")]
         {
             if button_touched.get() {
@@ -218,7 +244,10 @@ fn main() {
 
         // These calls are making sure that even for long inactivity, wrapping clock values
         // don't cause problems with timers.
-        ctap.update_timeouts(now);
+        ctap.update_timeouts();
+
+        #[cfg(feature = "with_nfc")]
+        ctap.poll_nfc();
 
         if let Some(endpoint) = usb_endpoint {
             let transport = match endpoint {
@@ -226,7 +255,7 @@ fn main() {
                 #[cfg(feature = "vendor_hid")]
                 UsbEndpoint::VendorHid => Transport::VendorHid,
             };
-            let reply = ctap.process_hid_packet(&pkt_request, transport, now);
+            let reply = ctap.process_hid_packet(&pkt_request, transport);
             if reply.has_data() {
                 // Update endpoint with the reply.
                 for ep in replies.replies.iter_mut() {
@@ -247,7 +276,7 @@ fn main() {
             }
         }
 
-        let now = clock.try_now().unwrap();
+        let now = ctap.env().clock().now();
         if let Some(wait_duration) = now.checked_duration_since(&last_led_increment) {
             let wait_duration: Milliseconds<ClockInt> = wait_duration.try_into().unwrap();
             if wait_duration > KEEPALIVE_DELAY {
@@ -280,17 +309,20 @@ fn main() {
 }
 
 #[cfg(feature = "debug_ctap")]
-fn print_packet_notice(notice_text: &str, clock: &CtapClock) {
-    let now = clock.try_now().unwrap();
-    let now_us = Microseconds::<u64>::try_from(now.duration_since_epoch())
-        .unwrap()
-        .0;
+fn print_error_notice(action: &str, status: Ctap2StatusCode, env: &mut TockEnv) {
+    let notice_text = alloc::format!("{} failed with error {:?}", action, status);
+    print_packet_notice(&notice_text, env);
+}
+
+#[cfg(feature = "debug_ctap")]
+fn print_packet_notice(notice_text: &str, env: &mut TockEnv) {
+    let now_ms = env.clock().now_ms();
     writeln!(
         Console::new(),
-        "{} at {}.{:06} s",
+        "{} at {}.{:03} s",
         notice_text,
-        now_us / 1_000_000,
-        now_us % 1_000_000
+        now_ms / 1000,
+        now_ms % 1000
     )
     .unwrap();
 }
@@ -37,6 +37,7 @@ pub fn enable_enterprise_attestation(
     let attestation_material = AuthenticatorAttestationMaterial {
         certificate: dummy_cert,
         private_key: dummy_key,
+        intermediate_certificates: vec![],
     };
     let configure_params = AuthenticatorVendorConfigureParameters {
         lockdown: false,
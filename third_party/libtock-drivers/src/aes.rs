@@ -0,0 +1,74 @@
+use crate::result::{OtherError, TockResult};
+use crate::util;
+use core::cell::Cell;
+use libtock_core::{callback, syscalls};
+
+const DRIVER_NUMBER: usize = 0x40005;
+
+/// Size of an AES block in bytes.
+pub const BLOCK_SIZE: usize = 16;
+
+/// Maximum number of bytes processed by a single call.
+pub const MAX_LENGTH: usize = 128;
+
+mod command_nr {
+    pub const AVAILABLE: usize = 0;
+    pub const ENCRYPT: usize = 1;
+    pub const DECRYPT: usize = 2;
+}
+
+mod subscribe_nr {
+    pub const DONE: usize = 0;
+}
+
+mod allow_nr {
+    pub const KEY: usize = 0;
+    pub const DATA: usize = 1;
+}
+
+pub fn is_available() -> TockResult<()> {
+    syscalls::command(DRIVER_NUMBER, command_nr::AVAILABLE, 0, 0)?;
+    Ok(())
+}
+
+/// Encrypts blocks in place with AES-256 in ECB mode.
+///
+/// At most [`MAX_LENGTH`] bytes can be processed at once. If the hardware is already in use, this
+/// fails with `EBUSY`. On error, the blocks are left unchanged.
+pub fn encrypt_ecb(key: &[u8; 32], blocks: &mut [u8]) -> TockResult<()> {
+    crypt_ecb(command_nr::ENCRYPT, key, blocks)
+}
+
+/// Decrypts blocks in place with AES-256 in ECB mode.
+///
+/// See [`encrypt_ecb`] for the constraints.
+pub fn decrypt_ecb(key: &[u8; 32], blocks: &mut [u8]) -> TockResult<()> {
+    crypt_ecb(command_nr::DECRYPT, key, blocks)
+}
+
+fn crypt_ecb(command: usize, key: &[u8; 32], blocks: &mut [u8]) -> TockResult<()> {
+    if blocks.len() % BLOCK_SIZE != 0 || blocks.len() > MAX_LENGTH {
+        return Err(OtherError::OutOfRange.into());
+    }
+    let mut key_buffer = *key;
+    let result = crypt_ecb_shared(command, &mut key_buffer, blocks);
+    key_buffer.iter_mut().for_each(|x| *x = 0);
+    result
+}
+
+fn crypt_ecb_shared(command: usize, key: &mut [u8; 32], blocks: &mut [u8]) -> TockResult<()> {
+    let _key_share = syscalls::allow(DRIVER_NUMBER, allow_nr::KEY, key)?;
+    let _data_share = syscalls::allow(DRIVER_NUMBER, allow_nr::DATA, blocks)?;
+
+    let done = Cell::new(false);
+    let mut done_callback = || done.set(true);
+    let _subscription = syscalls::subscribe::<callback::Identity0Consumer, _>(
+        DRIVER_NUMBER,
+        subscribe_nr::DONE,
+        &mut done_callback,
+    )?;
+
+    syscalls::command(DRIVER_NUMBER, command, 0, 0)?;
+    util::yieldk_for(|| done.get());
+    Ok(())
+}
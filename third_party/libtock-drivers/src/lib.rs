@@ -1,5 +1,6 @@
 #![no_std]
 
+pub mod aes;
 pub mod buttons;
 pub mod console;
 pub mod crp;
@@ -11,3 +12,4 @@ pub mod rng;
 pub mod timer;
 pub mod usb_ctap_hid;
 pub mod util;
+pub mod watchdog;
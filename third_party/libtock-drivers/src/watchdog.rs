@@ -0,0 +1,35 @@
+use crate::result::TockResult;
+use libtock_core::syscalls;
+
+const DRIVER_NUMBER: usize = 0x00009;
+
+mod command_nr {
+    pub const AVAILABLE: usize = 0;
+    pub const ENABLE: usize = 1;
+    pub const KICK: usize = 2;
+    pub const DISABLE: usize = 3;
+}
+
+pub fn is_available() -> TockResult<()> {
+    syscalls::command(DRIVER_NUMBER, command_nr::AVAILABLE, 0, 0)?;
+    Ok(())
+}
+
+/// Starts the watchdog, or restarts it with a new timeout.
+///
+/// The device resets if the watchdog isn't kicked for `timeout_ms` milliseconds. It keeps running
+/// if the app faults. Only one app may use the watchdog, others get `EBUSY`.
+pub fn enable(timeout_ms: usize) -> TockResult<()> {
+    syscalls::command(DRIVER_NUMBER, command_nr::ENABLE, timeout_ms, 0)?;
+    Ok(())
+}
+
+pub fn kick() -> TockResult<()> {
+    syscalls::command(DRIVER_NUMBER, command_nr::KICK, 0, 0)?;
+    Ok(())
+}
+
+pub fn disable() -> TockResult<()> {
+    syscalls::command(DRIVER_NUMBER, command_nr::DISABLE, 0, 0)?;
+    Ok(())
+}
@@ -0,0 +1,93 @@
+//! Component for an entropy source sampling a floating ADC input.
+//!
+//! This provides one Component, `AdcEntropyComponent`, for boards without a
+//! TRNG. Its output can be given to `RngComponent`. The ADC is used exclusively
+//! by this component, it can't be shared with the ADC syscall driver.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let entropy = components::adc_entropy::AdcEntropyComponent::supplementing(
+//!     &nrf52840::adc::ADC,
+//!     &adc_channel,
+//!     primary_entropy,
+//! )
+//! .finalize(components::adc_entropy_component_helper!(nrf52840::adc::Adc));
+//! let rng = components::rng::RngComponent::new(board_kernel, entropy).finalize(());
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::adc_entropy::AdcEntropy32;
+use kernel::component::Component;
+use kernel::hil::adc;
+use kernel::hil::entropy::Entropy32;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! adc_entropy_component_helper {
+    ($A:ty $(,)?) => {{
+        use capsules::adc_entropy::AdcEntropy32;
+        use core::mem::MaybeUninit;
+        static mut BUF: MaybeUninit<AdcEntropy32<'static, $A>> = MaybeUninit::uninit();
+        &mut BUF
+    };};
+}
+
+pub struct AdcEntropyComponent<A: 'static + adc::Adc> {
+    adc: &'static A,
+    channel: &'static A::Channel,
+    primary: Option<&'static dyn Entropy32<'static>>,
+}
+
+impl<A: 'static + adc::Adc> AdcEntropyComponent<A> {
+    /// Mixes the ADC noise into a primary entropy source.
+    pub fn supplementing(
+        adc: &'static A,
+        channel: &'static A::Channel,
+        primary: &'static dyn Entropy32<'static>,
+    ) -> Self {
+        AdcEntropyComponent {
+            adc,
+            channel,
+            primary: Some(primary),
+        }
+    }
+
+    /// Uses the ADC noise as the sole entropy source.
+    pub fn standalone(adc: &'static A, channel: &'static A::Channel) -> Self {
+        AdcEntropyComponent {
+            adc,
+            channel,
+            primary: None,
+        }
+    }
+}
+
+impl<A: 'static + adc::Adc> Component for AdcEntropyComponent<A> {
+    type StaticInput = &'static mut MaybeUninit<AdcEntropy32<'static, A>>;
+    type Output = &'static AdcEntropy32<'static, A>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let entropy = match self.primary {
+            Some(primary) => static_init_half!(
+                static_buffer,
+                AdcEntropy32<'static, A>,
+                AdcEntropy32::supplementing(self.adc, self.channel, primary)
+            ),
+            None => static_init_half!(
+                static_buffer,
+                AdcEntropy32<'static, A>,
+                AdcEntropy32::standalone(self.adc, self.channel)
+            ),
+        };
+
+        self.adc.set_client(entropy);
+        if let Some(primary) = self.primary {
+            primary.set_client(entropy);
+        }
+
+        entropy
+    }
+}
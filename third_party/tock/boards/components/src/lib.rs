@@ -1,6 +1,7 @@
 #![no_std]
 
 pub mod adc;
+pub mod adc_entropy;
 pub mod adc_microphone;
 pub mod alarm;
 pub mod analog_comparator;
@@ -27,6 +27,7 @@ pub struct UsbCtapComponent<C: 'static + hil::usb::UsbController<'static>> {
     board_kernel: &'static kernel::Kernel,
     controller: &'static C,
     max_ctrl_packet_size: u8,
+    interrupt_interval: u8,
     vendor_id: u16,
     product_id: u16,
     strings: &'static [&'static str],
@@ -37,6 +38,7 @@ impl<C: 'static + hil::usb::UsbController<'static>> UsbCtapComponent<C> {
         board_kernel: &'static kernel::Kernel,
         controller: &'static C,
         max_ctrl_packet_size: u8,
+        interrupt_interval: u8,
         vendor_id: u16,
         product_id: u16,
         strings: &'static [&'static str],
@@ -45,6 +47,7 @@ impl<C: 'static + hil::usb::UsbController<'static>> UsbCtapComponent<C> {
             board_kernel,
             controller,
             max_ctrl_packet_size,
+            interrupt_interval,
             vendor_id,
             product_id,
             strings,
@@ -68,6 +71,7 @@ impl<C: 'static + hil::usb::UsbController<'static>> Component for UsbCtapCompone
             ClientCtapHID::new(
                 self.controller,
                 self.max_ctrl_packet_size,
+                self.interrupt_interval,
                 self.vendor_id,
                 self.product_id,
                 self.strings,
@@ -73,11 +73,13 @@ struct EarlGreyNexysVideo {
         VirtualMuxHmac<'static, lowrisc::hmac::Hmac<'static>, [u8; 32]>,
         [u8; 32],
     >,
+    aes: &'static capsules::aes_ecb::AesEcbDriver<'static, earlgrey::aes::Aes<'static>>,
     lldb: &'static capsules::low_level_debug::LowLevelDebug<
         'static,
         capsules::virtual_uart::UartDevice<'static>,
     >,
     i2c_master: &'static capsules::i2c_master::I2CMasterDriver<lowrisc::i2c::I2c<'static>>,
+    watchdog: &'static capsules::watchdog::WatchdogDriver<'static, lowrisc::aon_timer::AonTimer>,
 }
 
 /// Mapping of integer syscalls to objects that implement syscalls.
@@ -89,11 +91,13 @@ impl Platform for EarlGreyNexysVideo {
         match driver_num {
             capsules::led::DRIVER_NUM => f(Some(self.led)),
             capsules::hmac::DRIVER_NUM => f(Some(self.hmac)),
+            capsules::aes_ecb::DRIVER_NUM => f(Some(self.aes)),
             capsules::gpio::DRIVER_NUM => f(Some(self.gpio)),
             capsules::console::DRIVER_NUM => f(Some(self.console)),
             capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
             capsules::low_level_debug::DRIVER_NUM => f(Some(self.lldb)),
             capsules::i2c_master::DRIVER_NUM => f(Some(self.i2c_master)),
+            capsules::watchdog::DRIVER_NUM => f(Some(self.watchdog)),
             _ => f(None),
         }
     }
@@ -138,6 +142,16 @@ pub unsafe fn reset_handler() {
         None,
     );
 
+    if earlgrey::uart::UART0_FLOW_CONTROL {
+        let rts = &peripherals.gpio_port[earlgrey::uart::UART0_RTS_PIN];
+        let cts = &peripherals.gpio_port[earlgrey::uart::UART0_CTS_PIN];
+        hil::gpio::Interrupt::set_client(cts, &peripherals.uart0);
+        peripherals.uart0.set_flow_control(rts, cts);
+    }
+    peripherals
+        .uart0
+        .set_rx_timeout(earlgrey::uart::UART0_RX_TIMEOUT);
+
     // Create a shared UART channel for the console and for kernel debug.
     let uart_mux = components::console::UartMuxComponent::new(
         &peripherals.uart0,
@@ -220,6 +234,10 @@ pub unsafe fn reset_handler() {
 
     // Need to enable all interrupts for Tock Kernel
     chip.enable_plic_interrupts();
+    // Wake up from idle light sleep on USB, button and timer activity
+    chip.set_wakeup_sources(earlgrey::pwrmgr::DEFAULT_WAKEUP_SOURCES);
+    // Reset when the watchdog bites
+    chip.set_reset_requests(earlgrey::pwrmgr::RESET_AON_TIMER);
     // enable interrupts globally
     csr::CSR
         .mie
@@ -251,6 +269,17 @@ pub unsafe fn reset_handler() {
         [u8; 32]
     ));
 
+    let aes_buffer = static_init!([u8; 128], [0; 128]);
+    let aes = static_init!(
+        capsules::aes_ecb::AesEcbDriver<'static, earlgrey::aes::Aes<'static>>,
+        capsules::aes_ecb::AesEcbDriver::new(
+            &peripherals.aes,
+            aes_buffer,
+            board_kernel.create_grant(&memory_allocation_cap)
+        )
+    );
+    hil::symmetric_encryption::AES128::set_client(&peripherals.aes, aes);
+
     let i2c_master = static_init!(
         capsules::i2c_master::I2CMasterDriver<lowrisc::i2c::I2c<'static>>,
         capsules::i2c_master::I2CMasterDriver::new(
@@ -262,6 +291,15 @@ pub unsafe fn reset_handler() {
 
     peripherals.i2c.set_master_client(i2c_master);
 
+    // The watchdog stays off until an app enables it.
+    let watchdog = static_init!(
+        capsules::watchdog::WatchdogDriver<'static, lowrisc::aon_timer::AonTimer>,
+        capsules::watchdog::WatchdogDriver::new(&peripherals.aon_timer)
+    );
+    peripherals
+        .i2c
+        .set_stretch_timeout(earlgrey::i2c::I2C_STRETCH_TIMEOUT);
+
     // USB support is currently broken in the OpenTitan hardware
     // See https://github.com/lowRISC/opentitan/issues/2598 for more details
     // let usb = usb::UsbComponent::new(board_kernel).finalize(());
@@ -337,8 +375,10 @@ pub unsafe fn reset_handler() {
         console: console,
         alarm: alarm,
         hmac,
+        aes,
         lldb: lldb,
         i2c_master,
+        watchdog,
     };
 
     // This is PMP support for kernel regions
@@ -0,0 +1,497 @@
+//! Entropy source sampling the noise of a floating ADC input.
+//!
+//! This is meant for boards without a hardware TRNG. The least significant bit
+//! of each ADC sample is debiased with a von Neumann extractor: pairs of equal
+//! bits are discarded, `01` yields a `0` and `10` yields a `1`. This removes
+//! the bias of independent samples, but not their correlation, so the ADC noise
+//! should not be trusted on its own.
+//!
+//! By default, each word of the ADC is XORed with a word of a primary entropy
+//! source. The output is then at least as good as the better of both sources.
+//! Using the ADC as the sole source must be explicitly chosen with
+//! `AdcEntropy32::standalone`.
+//!
+//! If too many pairs in a row are discarded, the input is considered stuck and
+//! the request fails. Userspace still applies its own health tests and
+//! conditioning to the output.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let adc_entropy = static_init!(
+//!     capsules::adc_entropy::AdcEntropy32<'static, nrf52840::adc::Adc>,
+//!     capsules::adc_entropy::AdcEntropy32::supplementing(
+//!         &nrf52840::adc::ADC,
+//!         &adc_channel,
+//!         &primary_entropy,
+//!     )
+//! );
+//! nrf52840::adc::ADC.set_client(adc_entropy);
+//! primary_entropy.set_client(adc_entropy);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::adc;
+use kernel::hil::entropy::{Client32, Continue, Entropy32};
+use kernel::ReturnCode;
+
+/// Number of discarded pairs in a row after which the input is stuck.
+///
+/// Even with a bias of 90%, the probability of reaching this limit by chance
+/// is below 2^-40.
+const MAX_DISCARDED_PAIRS: usize = 256;
+
+pub struct AdcEntropy32<'a, A: adc::Adc> {
+    adc: &'a A,
+    channel: &'a A::Channel,
+    primary: Option<&'a dyn Entropy32<'a>>,
+    client: OptionalCell<&'a dyn Client32>,
+    active: Cell<bool>,
+    sampling: Cell<bool>,
+    first_bit: Cell<Option<u32>>,
+    discarded_pairs: Cell<usize>,
+    bits: Cell<u32>,
+    bit_count: Cell<usize>,
+    adc_word: Cell<Option<u32>>,
+    primary_word: Cell<Option<u32>>,
+    primary_pending: Cell<bool>,
+}
+
+impl<'a, A: adc::Adc> AdcEntropy32<'a, A> {
+    /// Creates a source mixing the ADC noise into the primary source.
+    pub fn supplementing(
+        adc: &'a A,
+        channel: &'a A::Channel,
+        primary: &'a dyn Entropy32<'a>,
+    ) -> AdcEntropy32<'a, A> {
+        AdcEntropy32::new(adc, channel, Some(primary))
+    }
+
+    /// Creates a source only relying on the ADC noise.
+    pub fn standalone(adc: &'a A, channel: &'a A::Channel) -> AdcEntropy32<'a, A> {
+        AdcEntropy32::new(adc, channel, None)
+    }
+
+    fn new(
+        adc: &'a A,
+        channel: &'a A::Channel,
+        primary: Option<&'a dyn Entropy32<'a>>,
+    ) -> AdcEntropy32<'a, A> {
+        AdcEntropy32 {
+            adc,
+            channel,
+            primary,
+            client: OptionalCell::empty(),
+            active: Cell::new(false),
+            sampling: Cell::new(false),
+            first_bit: Cell::new(None),
+            discarded_pairs: Cell::new(0),
+            bits: Cell::new(0),
+            bit_count: Cell::new(0),
+            adc_word: Cell::new(None),
+            primary_word: Cell::new(None),
+            primary_pending: Cell::new(false),
+        }
+    }
+
+    /// Requests the missing words from the ADC and the primary source.
+    fn request_words(&self) -> ReturnCode {
+        if self.adc_word.get().is_none() && !self.sampling.get() {
+            let result = self.adc.sample(self.channel);
+            if result != ReturnCode::SUCCESS {
+                return result;
+            }
+            self.sampling.set(true);
+        }
+        if self.primary_word.get().is_none() && !self.primary_pending.get() {
+            if let Some(primary) = self.primary {
+                let result = primary.get();
+                if result != ReturnCode::SUCCESS {
+                    return result;
+                }
+                self.primary_pending.set(true);
+            }
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Adds an ADC bit to the extractor, and returns whether it was kept.
+    fn add_bit(&self, bit: u32) -> bool {
+        let first_bit = match self.first_bit.take() {
+            None => {
+                self.first_bit.set(Some(bit));
+                return true;
+            }
+            Some(first_bit) => first_bit,
+        };
+        if first_bit == bit {
+            self.discarded_pairs.set(self.discarded_pairs.get() + 1);
+            return false;
+        }
+        self.discarded_pairs.set(0);
+        self.bits.set(self.bits.get() << 1 | first_bit);
+        self.bit_count.set(self.bit_count.get() + 1);
+        if self.bit_count.get() == 32 {
+            self.adc_word.set(Some(self.bits.get()));
+            self.bits.set(0);
+            self.bit_count.set(0);
+        }
+        true
+    }
+
+    /// Gives the next word to the client once both sources provided theirs.
+    fn deliver(&self) {
+        let adc_word = match self.adc_word.get() {
+            None => return,
+            Some(adc_word) => adc_word,
+        };
+        let word = match (self.primary, self.primary_word.get()) {
+            (None, _) => adc_word,
+            (Some(_), None) => return,
+            (Some(_), Some(primary_word)) => adc_word ^ primary_word,
+        };
+        self.adc_word.set(None);
+        self.primary_word.set(None);
+        let result = self.client.map_or(Continue::Done, |client| {
+            client.entropy_available(&mut core::iter::once(word), ReturnCode::SUCCESS)
+        });
+        if result == Continue::Done {
+            self.active.set(false);
+        } else if self.request_words() != ReturnCode::SUCCESS {
+            self.fail();
+        }
+    }
+
+    /// Stops the request and reports the failure to the client.
+    fn fail(&self) {
+        self.active.set(false);
+        self.client.map(|client| {
+            client.entropy_available(&mut core::iter::empty(), ReturnCode::FAIL);
+        });
+    }
+}
+
+impl<'a, A: adc::Adc> Entropy32<'a> for AdcEntropy32<'a, A> {
+    fn get(&self) -> ReturnCode {
+        if self.active.get() {
+            return ReturnCode::SUCCESS;
+        }
+        self.discarded_pairs.set(0);
+        let result = self.request_words();
+        if result == ReturnCode::SUCCESS {
+            self.active.set(true);
+        }
+        result
+    }
+
+    fn cancel(&self) -> ReturnCode {
+        self.active.set(false);
+        match self.primary {
+            Some(primary) if self.primary_pending.get() => {
+                let result = primary.cancel();
+                if result == ReturnCode::SUCCESS {
+                    self.primary_pending.set(false);
+                }
+                result
+            }
+            _ => ReturnCode::SUCCESS,
+        }
+    }
+
+    fn set_client(&'a self, client: &'a dyn Client32) {
+        self.client.set(client);
+    }
+}
+
+impl<A: adc::Adc> adc::Client for AdcEntropy32<'_, A> {
+    fn sample_ready(&self, sample: u16) {
+        self.sampling.set(false);
+        if !self.active.get() {
+            return;
+        }
+        // Samples are left-justified.
+        let shift = 16 - self.adc.get_resolution_bits();
+        let bit = (sample >> shift) as u32 & 1;
+        if !self.add_bit(bit) && self.discarded_pairs.get() >= MAX_DISCARDED_PAIRS {
+            self.fail();
+            return;
+        }
+        if self.adc_word.get().is_some() {
+            self.deliver();
+        } else if self.request_words() != ReturnCode::SUCCESS {
+            self.fail();
+        }
+    }
+}
+
+impl<A: adc::Adc> Client32 for AdcEntropy32<'_, A> {
+    fn entropy_available(
+        &self,
+        entropy: &mut dyn Iterator<Item = u32>,
+        error: ReturnCode,
+    ) -> Continue {
+        if !self.active.get() {
+            self.primary_pending.set(false);
+            return Continue::Done;
+        }
+        if error != ReturnCode::SUCCESS {
+            self.primary_pending.set(false);
+            self.fail();
+            return Continue::Done;
+        }
+        // The request stays pending while delivering, such that the next word is
+        // requested by returning `More` instead of calling `get` from this callback.
+        if let Some(word) = entropy.next() {
+            self.primary_word.set(Some(word));
+            self.deliver();
+        }
+        if self.active.get() && self.primary_word.get().is_none() {
+            Continue::More
+        } else {
+            self.primary_pending.set(false);
+            Continue::Done
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    /// ADC whose samples are provided by the test.
+    struct MockAdc {
+        client: OptionalCell<&'static dyn adc::Client>,
+        pending: Cell<bool>,
+    }
+
+    impl MockAdc {
+        fn new() -> &'static MockAdc {
+            Box::leak(Box::new(MockAdc {
+                client: OptionalCell::empty(),
+                pending: Cell::new(false),
+            }))
+        }
+
+        /// Completes the pending sample, and returns whether there was one.
+        fn complete(&self, sample: u16) -> bool {
+            if !self.pending.replace(false) {
+                return false;
+            }
+            self.client.map(|client| client.sample_ready(sample));
+            true
+        }
+    }
+
+    impl adc::Adc for MockAdc {
+        type Channel = ();
+
+        fn sample(&self, _channel: &()) -> ReturnCode {
+            assert!(!self.pending.replace(true));
+            ReturnCode::SUCCESS
+        }
+
+        fn sample_continuous(&self, _channel: &(), _frequency: u32) -> ReturnCode {
+            ReturnCode::ENOSUPPORT
+        }
+
+        fn stop_sampling(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+
+        fn get_resolution_bits(&self) -> usize {
+            12
+        }
+
+        fn get_voltage_reference_mv(&self) -> Option<usize> {
+            None
+        }
+
+        fn set_client(&self, client: &'static dyn adc::Client) {
+            self.client.set(client);
+        }
+    }
+
+    /// Entropy source returning a constant word when the test allows it.
+    struct MockEntropy {
+        client: OptionalCell<&'static dyn Client32>,
+        pending: Cell<bool>,
+    }
+
+    impl MockEntropy {
+        fn new() -> &'static MockEntropy {
+            Box::leak(Box::new(MockEntropy {
+                client: OptionalCell::empty(),
+                pending: Cell::new(false),
+            }))
+        }
+
+        fn complete(&self, word: u32) {
+            if self.pending.replace(false) {
+                let result = self.client.map(|client| {
+                    client.entropy_available(&mut core::iter::once(word), ReturnCode::SUCCESS)
+                });
+                self.pending.set(result == Some(Continue::More));
+            }
+        }
+    }
+
+    impl Entropy32<'static> for MockEntropy {
+        fn get(&self) -> ReturnCode {
+            self.pending.set(true);
+            ReturnCode::SUCCESS
+        }
+
+        fn cancel(&self) -> ReturnCode {
+            self.pending.set(false);
+            ReturnCode::SUCCESS
+        }
+
+        fn set_client(&'static self, client: &'static dyn Client32) {
+            self.client.set(client);
+        }
+    }
+
+    /// Client collecting a fixed number of words.
+    struct Collector {
+        words: core::cell::RefCell<Vec<u32>>,
+        wanted: usize,
+        error: Cell<ReturnCode>,
+    }
+
+    impl Collector {
+        fn new(wanted: usize) -> &'static Collector {
+            Box::leak(Box::new(Collector {
+                words: core::cell::RefCell::new(Vec::new()),
+                wanted,
+                error: Cell::new(ReturnCode::SUCCESS),
+            }))
+        }
+    }
+
+    impl Client32 for Collector {
+        fn entropy_available(
+            &self,
+            entropy: &mut dyn Iterator<Item = u32>,
+            error: ReturnCode,
+        ) -> Continue {
+            self.error.set(error);
+            let mut words = self.words.borrow_mut();
+            words.extend(entropy);
+            if words.len() < self.wanted && error == ReturnCode::SUCCESS {
+                Continue::More
+            } else {
+                Continue::Done
+            }
+        }
+    }
+
+    /// Generates 12-bit left-justified samples whose LSB is 1 with probability 3/4.
+    struct BiasedNoise(u32);
+
+    impl BiasedNoise {
+        fn next_sample(&mut self) -> u16 {
+            self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let bit = (self.0 >> 30 != 0) as u16;
+            (0x5A0 | bit) << 4
+        }
+    }
+
+    fn setup(
+        adc: &'static MockAdc,
+        primary: Option<&'static MockEntropy>,
+        wanted: usize,
+    ) -> (&'static AdcEntropy32<'static, MockAdc>, &'static Collector) {
+        let entropy = Box::leak(Box::new(match primary {
+            Some(primary) => AdcEntropy32::supplementing(adc, &(), primary),
+            None => AdcEntropy32::standalone(adc, &()),
+        }));
+        adc::Adc::set_client(adc, entropy);
+        if let Some(primary) = primary {
+            primary.set_client(entropy);
+        }
+        let collector = Collector::new(wanted);
+        entropy.set_client(collector);
+        (entropy, collector)
+    }
+
+    #[test]
+    fn test_biased_samples_are_debiased() {
+        const NUM_WORDS: usize = 64;
+        let adc = MockAdc::new();
+        let (entropy, collector) = setup(adc, None, NUM_WORDS);
+        let mut noise = BiasedNoise(0);
+        assert_eq!(entropy.get(), ReturnCode::SUCCESS);
+        while adc.complete(noise.next_sample()) {}
+
+        let words = collector.words.borrow();
+        assert_eq!(words.len(), NUM_WORDS);
+        assert_eq!(collector.error.get(), ReturnCode::SUCCESS);
+        let ones: u32 = words.iter().map(|word| word.count_ones()).sum();
+        let total = 32 * NUM_WORDS as u32;
+        // The input has 75% of ones, the output must be close to 50%.
+        assert!(ones * 100 > total * 45, "{} ones out of {}", ones, total);
+        assert!(ones * 100 < total * 55, "{} ones out of {}", ones, total);
+    }
+
+    #[test]
+    fn test_primary_source_is_mixed_in() {
+        let standalone_adc = MockAdc::new();
+        let (standalone, standalone_words) = setup(standalone_adc, None, 1);
+        let mut noise = BiasedNoise(0);
+        assert_eq!(standalone.get(), ReturnCode::SUCCESS);
+        while standalone_adc.complete(noise.next_sample()) {}
+
+        let adc = MockAdc::new();
+        let primary = MockEntropy::new();
+        let (entropy, collector) = setup(adc, Some(primary), 1);
+        let mut noise = BiasedNoise(0);
+        assert_eq!(entropy.get(), ReturnCode::SUCCESS);
+        while adc.complete(noise.next_sample()) {}
+        // The ADC word is held back until the primary source provides its word.
+        assert!(collector.words.borrow().is_empty());
+        primary.complete(0xDEAD_BEEF);
+
+        assert_eq!(
+            *collector.words.borrow(),
+            [standalone_words.words.borrow()[0] ^ 0xDEAD_BEEF]
+        );
+    }
+
+    #[test]
+    fn test_stuck_input_fails() {
+        let adc = MockAdc::new();
+        let (entropy, collector) = setup(adc, None, 1);
+        assert_eq!(entropy.get(), ReturnCode::SUCCESS);
+        let mut num_samples = 0;
+        while adc.complete(0x0FF0) {
+            num_samples += 1;
+        }
+        assert_eq!(num_samples, 2 * MAX_DISCARDED_PAIRS);
+        assert!(collector.words.borrow().is_empty());
+        assert_eq!(collector.error.get(), ReturnCode::FAIL);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let adc = MockAdc::new();
+        let primary = MockEntropy::new();
+        let (entropy, collector) = setup(adc, Some(primary), 1);
+        let mut noise = BiasedNoise(0);
+        assert_eq!(entropy.get(), ReturnCode::SUCCESS);
+        assert!(adc.complete(noise.next_sample()));
+        assert_eq!(entropy.cancel(), ReturnCode::SUCCESS);
+        // The pending sample completes, but no other sample is requested.
+        assert!(adc.complete(noise.next_sample()));
+        assert!(!adc.complete(noise.next_sample()));
+        primary.complete(0xDEAD_BEEF);
+        assert!(collector.words.borrow().is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+//! AES-ECB encryption and decryption for userspace.
+//!
+//! Apps share a key of 16 or 32 bytes and a buffer of whole blocks, which is
+//! encrypted or decrypted in place. The engine serves one request at a time:
+//! while a request is running, other requests fail with `EBUSY` instead of
+//! being queued, so that apps can fall back to a software implementation.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let aes_buffer = static_init!([u8; 128], [0; 128]);
+//! let aes = static_init!(
+//!     capsules::aes_ecb::AesEcbDriver<'static, earlgrey::aes::Aes<'static>>,
+//!     capsules::aes_ecb::AesEcbDriver::new(
+//!         &peripherals.aes,
+//!         aes_buffer,
+//!         board_kernel.create_grant(&memory_allocation_cap),
+//!     )
+//! );
+//! hil::symmetric_encryption::AES128::set_client(&peripherals.aes, aes);
+//! ```
+
+use crate::driver;
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::Aes as usize;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::symmetric_encryption;
+use kernel::hil::symmetric_encryption::{AES128, AES128ECB, AES128_BLOCK_SIZE};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Largest supported key, in bytes.
+const MAX_KEY_SIZE: usize = 32;
+
+pub struct AesEcbDriver<'a, A: AES128<'a> + AES128ECB> {
+    aes: &'a A,
+    apps: Grant<App>,
+    /// The app whose request is running, if any.
+    appid: OptionalCell<AppId>,
+    buffer: TakeCell<'a, [u8]>,
+}
+
+impl<'a, A: AES128<'a> + AES128ECB> AesEcbDriver<'a, A> {
+    pub fn new(aes: &'a A, buffer: &'a mut [u8], grant: Grant<App>) -> AesEcbDriver<'a, A> {
+        AesEcbDriver {
+            aes,
+            apps: grant,
+            appid: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    /// Copies the key and data of the app, and runs the hardware on them.
+    fn run(&self, appid: AppId, encrypting: bool) -> ReturnCode {
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return ReturnCode::EBUSY,
+        };
+        let mut key = [0; MAX_KEY_SIZE];
+        let copied = self
+            .apps
+            .enter(appid, |app, _| {
+                let key_size = match app.key.as_ref() {
+                    Some(app_key) if app_key.len() == 16 || app_key.len() == MAX_KEY_SIZE => {
+                        key[..app_key.len()].copy_from_slice(app_key.as_ref());
+                        app_key.len()
+                    }
+                    Some(_) => return Err(ReturnCode::EINVAL),
+                    None => return Err(ReturnCode::ERESERVE),
+                };
+                let length = match app.data.as_ref() {
+                    Some(data) => {
+                        if data.len() % AES128_BLOCK_SIZE != 0 || data.len() > buffer.len() {
+                            return Err(ReturnCode::EINVAL);
+                        }
+                        buffer[..data.len()].copy_from_slice(data.as_ref());
+                        data.len()
+                    }
+                    None => return Err(ReturnCode::ERESERVE),
+                };
+                Ok((key_size, length))
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+        let (key_size, length) = match copied {
+            Ok(sizes) => sizes,
+            Err(e) => {
+                self.buffer.replace(buffer);
+                return e;
+            }
+        };
+
+        self.aes.enable();
+        self.aes.set_mode_aes128ecb(encrypting);
+        let ret = self.aes.set_key(&key[..key_size]);
+        // The key is now in the hardware, which clears it when disabled.
+        key.iter_mut().for_each(|x| *x = 0);
+        if ret != ReturnCode::SUCCESS {
+            self.aes.disable();
+            self.buffer.replace(buffer);
+            return ret;
+        }
+
+        self.appid.set(appid);
+        match self.aes.crypt(None, buffer, 0, length) {
+            None => ReturnCode::SUCCESS,
+            Some((ret, _, buffer)) => {
+                self.aes.disable();
+                self.appid.clear();
+                clear(buffer);
+                self.buffer.replace(buffer);
+                ret
+            }
+        }
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128ECB> symmetric_encryption::Client<'a> for AesEcbDriver<'a, A> {
+    fn crypt_done(&'a self, _source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
+        self.aes.disable();
+        self.appid.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                if let Some(data) = app.data.as_mut() {
+                    let length = data.len();
+                    data.as_mut().copy_from_slice(&dest[..length]);
+                }
+                app.callback.map(|cb| cb.schedule(0, 0, 0));
+            });
+        });
+        clear(dest);
+        self.buffer.replace(dest);
+    }
+}
+
+/// Specify memory regions to be used.
+///
+/// ### `allow_num`
+///
+/// - `0`: Allow a buffer for the key, of 16 or 32 bytes.
+/// - `1`: Allow a buffer for the data, of at most 128 bytes.
+///        Its length must be a multiple of the block size. The kernel
+///        replaces its content with the result before calling the callback.
+impl<'a, A: AES128<'a> + AES128ECB> Driver for AesEcbDriver<'a, A> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.key = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or(ReturnCode::FAIL),
+
+            1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.data = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or(ReturnCode::FAIL),
+
+            // default
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Subscribe to AES events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Subscribe to the completion of a request.
+    ///        The callback signature is `fn(result: u32)`
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback.insert(callback);
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or(ReturnCode::FAIL),
+
+            // default
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Run the AES hardware on the data buffer.
+    ///
+    /// Returns `EBUSY` if the hardware is already running a request.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Check if the driver is present
+    /// - `1`: Encrypt the data buffer in place
+    /// - `2`: Decrypt the data buffer in place
+    fn command(
+        &self,
+        command_num: usize,
+        _data1: usize,
+        _data2: usize,
+        appid: AppId,
+    ) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.run(appid, true),
+            2 => self.run(appid, false),
+
+            // default
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+fn clear(buffer: &mut [u8]) {
+    buffer.iter_mut().for_each(|x| *x = 0);
+}
+
+pub struct App {
+    callback: OptionalCell<Callback>,
+    key: Option<AppSlice<Shared, u8>>,
+    data: Option<AppSlice<Shared, u8>>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback: OptionalCell::empty(),
+            key: None,
+            data: None,
+        }
+    }
+}
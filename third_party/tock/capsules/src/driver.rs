@@ -17,6 +17,7 @@ pub enum NUM {
     Dac                   = 0x00006,
     AnalogComparator      = 0x00007,
     FirmwareProtection    = 0x00008,
+    Watchdog              = 0x00009,
 
     // Kernel
     Ipc                   = 0x10000,
@@ -39,6 +40,7 @@ pub enum NUM {
     Crc                   = 0x40002,
     Hmac                  = 0x40003,
     CtapHid               = 0x40004,
+    Aes                   = 0x40005,
 
     // Storage
     AppFlash              = 0x50000,
@@ -8,7 +8,9 @@ pub mod test;
 pub mod net;
 
 pub mod adc;
+pub mod adc_entropy;
 pub mod adc_microphone;
+pub mod aes_ecb;
 pub mod alarm;
 pub mod ambient_light;
 pub mod analog_comparator;
@@ -91,3 +93,4 @@ pub mod virtual_rng;
 pub mod virtual_spi;
 pub mod virtual_timer;
 pub mod virtual_uart;
+pub mod watchdog;
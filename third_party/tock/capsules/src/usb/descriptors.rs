@@ -3,10 +3,11 @@
 //! Mostly data types for USB descriptors.
 
 use core::cell::Cell;
-use core::cmp::min;
+use core::cmp::{max, min};
 use core::convert::From;
 use core::fmt;
 use kernel::common::cells::VolatileCell;
+use kernel::hil::usb::DeviceSpeed;
 use kernel::hil::usb::TransferType;
 
 // On Nordic, USB buffers must be 32-bit aligned, with a power-of-2 size. For
@@ -351,6 +352,24 @@ impl Default for DeviceDescriptor {
     }
 }
 
+impl DeviceDescriptor {
+    /// Largest valid `max_packet_size_ep0` at the given speed that fits in
+    /// the control packets of the hardware.
+    ///
+    /// Low speed devices must use 8 bytes, full speed devices may use 8, 16,
+    /// 32 or 64 bytes.
+    pub fn max_packet_size_ep0_for(speed: DeviceSpeed, hardware_max: u8) -> u8 {
+        match speed {
+            DeviceSpeed::Low => 8,
+            DeviceSpeed::Full => [64, 32, 16]
+                .iter()
+                .copied()
+                .find(|&size| size <= hardware_max)
+                .unwrap_or(8),
+        }
+    }
+}
+
 impl Descriptor for DeviceDescriptor {
     fn size(&self) -> usize {
         18
@@ -682,6 +701,19 @@ pub struct EndpointDescriptor {
     pub interval: u8,
 }
 
+impl EndpointDescriptor {
+    /// Closest valid `interval` of an interrupt endpoint at the given speed.
+    ///
+    /// Full speed devices may be polled every 1 to 255 ms, low speed devices
+    /// every 10 to 255 ms.
+    pub fn interrupt_interval_for(speed: DeviceSpeed, interval: u8) -> u8 {
+        match speed {
+            DeviceSpeed::Low => max(interval, 10),
+            DeviceSpeed::Full => max(interval, 1),
+        }
+    }
+}
+
 impl Descriptor for EndpointDescriptor {
     fn size(&self) -> usize {
         7
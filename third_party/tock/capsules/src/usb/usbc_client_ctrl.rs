@@ -25,6 +25,7 @@ use super::descriptors::HIDDescriptor;
 use super::descriptors::LanguagesDescriptor;
 use super::descriptors::Recipient;
 use super::descriptors::ReportDescriptor;
+use super::descriptors::RequestType;
 use super::descriptors::SetupData;
 use super::descriptors::StandardRequest;
 use super::descriptors::StringDescriptor;
@@ -38,6 +39,12 @@ const DESCRIPTOR_BUFLEN: usize = 128;
 
 const N_ENDPOINTS: usize = 3;
 
+/// Speed at which the device enumerates. Must be Full for Bulk transfers.
+pub const DEVICE_SPEED: hil::usb::DeviceSpeed = hil::usb::DeviceSpeed::Full;
+
+/// Offset of `max_packet_size_ep0` in the device descriptor.
+const MAX_PACKET_SIZE_EP0_OFFSET: usize = 7;
+
 #[cfg(feature = "vendor_hid")]
 const N_HID_INTERFACES: usize = 2;
 
@@ -97,6 +104,10 @@ enum State {
     CtrlOut,
 
     SetAddress,
+
+    /// The last request was rejected, so the endpoint is stalled until the
+    /// next SETUP.
+    Stalled,
 }
 
 impl Default for State {
@@ -171,6 +182,12 @@ impl<'a, 'b, U: hil::usb::UsbController<'a>> ClientCtrl<'a, 'b, U> {
         self.controller
     }
 
+    /// Size of the Control In packets, as advertised in the device descriptor.
+    fn max_packet_size(&self) -> usize {
+        let advertised = self.device_descriptor_buffer.buf[MAX_PACKET_SIZE_EP0_OFFSET].get();
+        min(advertised as usize, self.ctrl_buffer.buf.len())
+    }
+
     #[inline]
     fn descriptor_buf(&'a self) -> &'a [Cell<u8>] {
         &self.descriptor_storage
@@ -180,8 +197,7 @@ impl<'a, 'b, U: hil::usb::UsbController<'a>> ClientCtrl<'a, 'b, U> {
         // Set up the default control endpoint
         self.controller
             .endpoint_set_ctrl_buffer(&self.ctrl_buffer.buf);
-        self.controller
-            .enable_as_device(hil::usb::DeviceSpeed::Full); // must be Full for Bulk transfers
+        self.controller.enable_as_device(DEVICE_SPEED);
         self.controller
             .endpoint_out_enable(TransferType::Control, 0);
     }
@@ -196,32 +212,34 @@ impl<'a, 'b, U: hil::usb::UsbController<'a>> ClientCtrl<'a, 'b, U> {
             // For now we only support the default Control endpoint
             return hil::usb::CtrlSetupResult::ErrInvalidDeviceIndex;
         }
-        SetupData::get(&self.ctrl_buffer.buf).map_or(
+        // A new SETUP aborts any transfer in progress on this endpoint, and
+        // clears the stall of a previously rejected request.
+        self.state[endpoint].set(State::Init);
+        let result = SetupData::get(&self.ctrl_buffer.buf).map_or(
             hil::usb::CtrlSetupResult::ErrNoParse,
             |setup_data| {
                 let transfer_direction = setup_data.request_type.transfer_direction();
                 let recipient = setup_data.request_type.recipient();
                 setup_data.get_standard_request().map_or_else(
                     || {
-                        // XX: CtrlSetupResult::ErrNonstandardRequest
-
-                        // For now, promiscuously accept vendor data and even supply
-                        // a few debugging bytes when host does a read
-
-                        match transfer_direction {
-                            TransferDirection::HostToDevice => {
+                        // Class requests sent to the interface (such as
+                        // HID SET_IDLE) are accepted and their data ignored.
+                        // We have nothing to answer to any other request,
+                        // so the host gets a STALL.
+                        match (
+                            setup_data.request_type.request_type(),
+                            &recipient,
+                            transfer_direction,
+                        ) {
+                            (
+                                RequestType::Class,
+                                Recipient::Interface,
+                                TransferDirection::HostToDevice,
+                            ) => {
                                 self.state[endpoint].set(State::CtrlOut);
                                 hil::usb::CtrlSetupResult::Ok
                             }
-                            TransferDirection::DeviceToHost => {
-                                // Arrange to send some crap back
-                                let buf = self.descriptor_buf();
-                                buf[0].set(0xa);
-                                buf[1].set(0xb);
-                                buf[2].set(0xc);
-                                self.state[endpoint].set(State::CtrlIn(0, 3));
-                                hil::usb::CtrlSetupResult::Ok
-                            }
+                            _ => hil::usb::CtrlSetupResult::ErrNonstandardRequest,
                         }
                     },
                     |request| match recipient {
@@ -233,7 +251,12 @@ impl<'a, 'b, U: hil::usb::UsbController<'a>> ClientCtrl<'a, 'b, U> {
                     },
                 )
             },
-        )
+        );
+        match result {
+            hil::usb::CtrlSetupResult::Ok | hil::usb::CtrlSetupResult::OkSetAddress => (),
+            _ => self.state[endpoint].set(State::Stalled),
+        }
+        result
     }
 
     fn handle_standard_device_request(
@@ -386,7 +409,7 @@ impl<'a, 'b, U: hil::usb::UsbController<'a>> ClientCtrl<'a, 'b, U> {
             State::CtrlIn(start, end) => {
                 let len = end.saturating_sub(start);
                 if len > 0 {
-                    let packet_bytes = min(self.ctrl_buffer.buf.len(), len);
+                    let packet_bytes = min(self.max_packet_size(), len);
                     let packet = &self.descriptor_storage[start..start + packet_bytes];
                     let buf = &self.ctrl_buffer.buf;
 
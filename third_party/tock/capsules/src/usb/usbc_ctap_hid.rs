@@ -13,6 +13,7 @@ use super::descriptors::ReportDescriptor;
 use super::descriptors::TransferDirection;
 use super::app::App;
 use super::usb_ctap::CtapUsbClient;
+use super::usbc_client_ctrl;
 use super::usbc_client_ctrl::ClientCtrl;
 use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
@@ -34,6 +35,10 @@ const ENDPOINT_NUM: usize = 1;
 #[cfg(feature = "vendor_hid")]
 const VENDOR_ENDPOINT_NUM: usize = ENDPOINT_NUM + 1;
 
+/// Polling interval of the interrupt endpoints, in milliseconds, used by the
+/// OpenSK boards.
+pub const DEFAULT_INTERRUPT_INTERVAL: u8 = 5;
+
 static ENDPOINTS: &'static [usize] = &[
   ENDPOINT_NUM,
   #[cfg(feature = "vendor_hid")]
@@ -120,6 +125,8 @@ struct EndpointState {
 
     tx_packet: OptionalCell<[u8; 64]>,
     pending_in: Cell<bool>,
+    // Is the client waiting for an OUT packet on this endpoint?
+    pending_out: Cell<bool>,
     // Is there a delayed packet?
     delayed_out: Cell<bool>,
 }
@@ -132,6 +139,7 @@ impl EndpointState {
             out_buffer: Buffer64::default(),
             tx_packet: OptionalCell::empty(),
             pending_in: Cell::new(false),
+            pending_out: Cell::new(false),
             delayed_out: Cell::new(false),
         }
     }
@@ -140,8 +148,6 @@ impl EndpointState {
 pub struct ClientCtapHID<'a, 'b, C: 'a> {
     client_ctrl: ClientCtrl<'a, 'static, C>,
 
-    // Is there a pending OUT transaction happening?
-    pending_out: Cell<bool>,
     next_endpoint_index: Cell<usize>,
 
     endpoints: [EndpointState; NUM_ENDPOINTS],
@@ -151,9 +157,21 @@ pub struct ClientCtapHID<'a, 'b, C: 'a> {
 }
 
 impl<'a, 'b, C: hil::usb::UsbController<'a>> ClientCtapHID<'a, 'b, C> {
+    /// The `strings` are the manufacturer, the product and the serial number,
+    /// in that order. Boards may build them at runtime, for example to use a
+    /// unique identifier of the chip as serial number.
+    ///
+    /// The `max_ctrl_packet_size` is the largest control packet of the
+    /// hardware. The device advertises the largest valid size for its speed
+    /// that does not exceed it.
+    ///
+    /// The `interrupt_interval` is how often the host polls the CTAP HID
+    /// endpoints, in milliseconds. Lower values reduce latency but use more of
+    /// the bus. Values too low for the device speed are raised to the minimum.
     pub fn new(
         controller: &'a C,
         max_ctrl_packet_size: u8,
+        interrupt_interval: u8,
         vendor_id: u16,
         product_id: u16,
         strings: &'static [&'static str],
@@ -180,6 +198,10 @@ impl<'a, 'b, C: hil::usb::UsbController<'a>> ClientCtapHID<'a, 'b, C> {
             },
         ];
 
+        let interval = EndpointDescriptor::interrupt_interval_for(
+            usbc_client_ctrl::DEVICE_SPEED,
+            interrupt_interval,
+        );
         let endpoints: &[&[EndpointDescriptor]] = &[&[
             // 2 Endpoints for FIDO
             EndpointDescriptor {
@@ -189,7 +211,7 @@ impl<'a, 'b, C: hil::usb::UsbController<'a>> ClientCtapHID<'a, 'b, C> {
                 ),
                 transfer_type: TransferType::Interrupt,
                 max_packet_size: 64,
-                interval: 5,
+                interval,
             },
             EndpointDescriptor {
                 endpoint_address: EndpointAddress::new_const(
@@ -198,7 +220,7 @@ impl<'a, 'b, C: hil::usb::UsbController<'a>> ClientCtapHID<'a, 'b, C> {
                 ),
                 transfer_type: TransferType::Interrupt,
                 max_packet_size: 64,
-                interval: 5,
+                interval,
             },],
             // 2 Endpoints for FIDO
             #[cfg(feature = "vendor_hid")]
@@ -210,7 +232,7 @@ impl<'a, 'b, C: hil::usb::UsbController<'a>> ClientCtapHID<'a, 'b, C> {
                     ),
                     transfer_type: TransferType::Interrupt,
                     max_packet_size: 64,
-                    interval: 5,
+                    interval,
                 },
                 EndpointDescriptor {
                     endpoint_address: EndpointAddress::new_const(
@@ -219,7 +241,7 @@ impl<'a, 'b, C: hil::usb::UsbController<'a>> ClientCtapHID<'a, 'b, C> {
                     ),
                     transfer_type: TransferType::Interrupt,
                     max_packet_size: 64,
-                    interval: 5,
+                    interval,
                 },
             ],
         ];
@@ -232,7 +254,10 @@ impl<'a, 'b, C: hil::usb::UsbController<'a>> ClientCtapHID<'a, 'b, C> {
                     manufacturer_string: 1,
                     product_string: 2,
                     serial_number_string: 3,
-                    max_packet_size_ep0: max_ctrl_packet_size,
+                    max_packet_size_ep0: descriptors::DeviceDescriptor::max_packet_size_ep0_for(
+                        usbc_client_ctrl::DEVICE_SPEED,
+                        max_ctrl_packet_size,
+                    ),
                     ..descriptors::DeviceDescriptor::default()
                 },
                 descriptors::ConfigurationDescriptor {
@@ -266,7 +291,6 @@ impl<'a, 'b, C: hil::usb::UsbController<'a>> ClientCtapHID<'a, 'b, C> {
                 LANGUAGES,
                 strings,
             ),
-            pending_out: Cell::new(false),
             next_endpoint_index: Cell::new(0),
             endpoints: [
                 EndpointState::new(ENDPOINT_NUM),
@@ -310,23 +334,27 @@ impl<'a, 'b, C: hil::usb::UsbController<'a>> ClientCtapHID<'a, 'b, C> {
     }
 
     pub fn receive_packet(&'a self, app: &mut App) {
-        if self.pending_out.get() {
-            // The previous packet has not yet been received, reject the new one.
-        } else {
-            self.pending_out.set(true);
-            // Process the next endpoint that has a delayed packet.
-            for i in self.next_endpoint_index.get()..self.next_endpoint_index.get() + NUM_ENDPOINTS {
-                let s = &self.endpoints[i % NUM_ENDPOINTS];
-                // In case we reported Delay before, send the pending packet back to the client.
-                // Otherwise, there's nothing to do, the controller will send us a packet_out when a
-                // packet arrives.
-                if s.delayed_out.take() {
-                    if self.send_packet_to_client(s.endpoint, Some(app)) {
-                        // If that succeeds, alert the controller that we can now
-                        // receive data on the Interrupt OUT endpoint.
-                        self.controller().endpoint_resume_out(s.endpoint);
-                    }
-                }
+        // The client accepts the next packet of any endpoint. Endpoints still waiting from a
+        // previous request, whose other endpoints were cancelled, join the new one.
+        for s in self.endpoints.iter() {
+            s.pending_out.set(true);
+        }
+        // Process the next endpoint that has a delayed packet. Idle endpoints, for example
+        // an interface the host never opened, are skipped without delaying the others.
+        for i in self.next_endpoint_index.get()..self.next_endpoint_index.get() + NUM_ENDPOINTS {
+            let s = &self.endpoints[i % NUM_ENDPOINTS];
+            // In case we reported Delay before, send the pending packet back to the client.
+            // Otherwise, there's nothing to do, the controller will send us a packet_out when a
+            // packet arrives.
+            if !s.delayed_out.take() {
+                continue;
+            }
+            if self.send_packet_to_client(s.endpoint, Some(app)) {
+                // If that succeeds, alert the controller that we can now
+                // receive data on the Interrupt OUT endpoint.
+                self.controller().endpoint_resume_out(s.endpoint);
+                // The client asked for a single packet, the other endpoints keep theirs.
+                break;
             }
         }
     }
@@ -342,19 +370,32 @@ impl<'a, 'b, C: hil::usb::UsbController<'a>> ClientCtapHID<'a, 'b, C> {
                 buf[i] = x.get();
             }
 
-            assert!(!s.delayed_out.get());
+            if s.delayed_out.get() {
+                // The controller should wait for endpoint_resume_out before
+                // giving us another packet. Keep waiting for the client.
+                debug!("Unexpected OUT packet on delayed ep {}", endpoint);
+                return false;
+            }
 
             // Notify the client
             if self
                 .client
                 .map_or(false, |client| client.can_receive_packet(&app))
             {
-                assert!(self.pending_out.take());
+                if !s.pending_out.get() {
+                    // The client did not ask for this packet, so we delay it
+                    // until it does.
+                    debug!("Unexpected OUT packet on ep {}", endpoint);
+                    s.delayed_out.set(true);
+                    return false;
+                }
 
                 // Clear any pending packet on the transmitting side.
                 // It's up to the client to handle the received packet and decide if this packet
                 // should be re-transmitted or not.
                 self.cancel_in_transaction(endpoint);
+                // The client asked for a single packet, so it is not waiting anymore.
+                self.cancel_out_transactions();
 
                 self.client.map(|client| client.packet_received(&buf, endpoint, app));
                 // Update next packet to send.
@@ -399,14 +440,23 @@ impl<'a, 'b, C: hil::usb::UsbController<'a>> ClientCtapHID<'a, 'b, C> {
     }
 
     fn cancel_out_transaction(&'a self, endpoint: usize) -> bool {
-        if let Some(_) = self.get_endpoint(endpoint) {
-            self.pending_out.take()
+        if let Some(s) = self.get_endpoint(endpoint) {
+            s.pending_out.take()
         } else {
             // Unsupported endpoint
           false
         }
     }
 
+    // Stops waiting for an OUT packet on all endpoints.
+    fn cancel_out_transactions(&'a self) -> bool {
+        let mut r = false;
+        for s in self.endpoints.iter() {
+            r |= s.pending_out.take();
+        }
+        r
+    }
+
     #[inline]
     fn controller(&'a self) -> &'a C {
         self.client_ctrl.controller()
@@ -485,7 +535,7 @@ impl<'a, 'b, C: hil::usb::UsbController<'a>> hil::usb::Client<'a> for ClientCtap
                     return hil::usb::InResult::Error
                 }
             }
-            TransferType::Control | TransferType::Isochronous => unreachable!(),
+            TransferType::Control | TransferType::Isochronous => hil::usb::InResult::Error,
         }
     }
 
@@ -514,26 +564,473 @@ impl<'a, 'b, C: hil::usb::UsbController<'a>> hil::usb::Client<'a> for ClientCtap
                     }
                 }
             }
-            TransferType::Control | TransferType::Isochronous => unreachable!(),
+            TransferType::Control | TransferType::Isochronous => hil::usb::OutResult::Error,
         }
     }
 
     fn packet_transmitted(&'a self, endpoint: usize) {
         if let Some(s) = self.get_endpoint(endpoint) {
             if s.tx_packet.is_some() {
-                panic!("Unexpected tx_packet while a packet was being transmitted.");
+                // The controller should only signal the packet we gave it.
+                // Keep the queued packet and recover as if it completed.
+                debug!("Unexpected tx_packet while a packet was being transmitted.");
             }
             s.pending_in.set(false);
 
-            // Clear any pending packet on the receiving side.
+            // Clear any pending packet on the receiving side, on all endpoints since the client
+            // waits for a single event.
             // It's up to the client to handle the transmitted packet and decide if they want to
             // receive another packet.
-            self.cancel_out_transaction(endpoint);
+            self.cancel_out_transactions();
 
             // Notify the client
             self.client.map(|client| client.packet_transmitted());
         } else {
-            panic!("Unexpected transmission on ep {}", endpoint);
+            debug!("Unexpected transmission on ep {}", endpoint);
         }
     }
   }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use hil::usb::{
+        Client, CtrlInResult, CtrlOutResult, CtrlSetupResult, DeviceSpeed, InResult, OutResult,
+        UsbController,
+    };
+    use kernel::common::cells::VolatileCell;
+    use test_debug_writer::lock_debug_writer;
+
+    /// Controller doing nothing, since descriptors are read from the client.
+    struct FakeController;
+
+    impl<'a> UsbController<'a> for FakeController {
+        fn set_client(&self, _client: &'a dyn Client<'a>) {}
+        fn endpoint_set_ctrl_buffer(&self, _buf: &'a [VolatileCell<u8>]) {}
+        fn endpoint_set_in_buffer(&self, _endpoint: usize, _buf: &'a [VolatileCell<u8>]) {}
+        fn endpoint_set_out_buffer(&self, _endpoint: usize, _buf: &'a [VolatileCell<u8>]) {}
+        fn enable_as_device(&self, _speed: DeviceSpeed) {}
+        fn attach(&self) {}
+        fn detach(&self) {}
+        fn set_address(&self, _addr: u16) {}
+        fn enable_address(&self) {}
+        fn endpoint_in_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+        fn endpoint_out_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+        fn endpoint_in_out_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+        fn endpoint_resume_in(&self, _endpoint: usize) {}
+        fn endpoint_resume_out(&self, _endpoint: usize) {}
+    }
+
+    /// Client ready to receive unless busy, counting the packets it is
+    /// notified of.
+    #[derive(Default)]
+    struct FakeClient {
+        busy: Cell<bool>,
+        received: Cell<usize>,
+        last_endpoint: Cell<usize>,
+        transmitted: Cell<usize>,
+    }
+
+    impl CtapUsbClient for FakeClient {
+        fn can_receive_packet(&self, _app: &Option<&mut App>) -> bool {
+            !self.busy.get()
+        }
+
+        fn packet_received(&self, _packet: &[u8; 64], endpoint: usize, _app: Option<&mut App>) {
+            self.received.set(self.received.get() + 1);
+            self.last_endpoint.set(endpoint);
+        }
+
+        fn packet_transmitted(&self) {
+            self.transmitted.set(self.transmitted.get() + 1);
+        }
+    }
+
+    static STRINGS: &'static [&'static str] = &["Maker", "OpenSK", "0123456789ABCDEF"];
+
+    /// Writes a SETUP packet from the host to the control endpoint.
+    fn setup<'a>(
+        hid: &'a ClientCtapHID<'a, 'a, FakeController>,
+        packet: [u8; 8],
+    ) -> CtrlSetupResult {
+        for (cell, byte) in hid.client_ctrl.ctrl_buffer.buf.iter().zip(packet.iter()) {
+            cell.set(*byte);
+        }
+        hid.ctrl_setup(0)
+    }
+
+    /// Requests a string descriptor like a host and checks that it encodes
+    /// `expected`.
+    fn check_string_descriptor<'a>(
+        hid: &'a ClientCtapHID<'a, 'a, FakeController>,
+        index: u8,
+        expected: &str,
+    ) {
+        // GET_DESCRIPTOR of type string in English (United States).
+        let packet = [0x80, 0x06, index, 0x03, 0x09, 0x04, 0xFF, 0x00];
+        assert!(matches!(setup(hid, packet), CtrlSetupResult::Ok));
+        let length = match hid.ctrl_in(0) {
+            CtrlInResult::Packet(length, true) => length,
+            _ => panic!("Expected a single packet"),
+        };
+        let packet = &hid.client_ctrl.ctrl_buffer.buf[..length];
+        assert_eq!(packet[0].get() as usize, length);
+        assert_eq!(packet[1].get(), DescriptorType::String as u8);
+        let mut units = packet[2..]
+            .chunks(2)
+            .map(|unit| unit[0].get() as u16 | (unit[1].get() as u16) << 8);
+        for expected_unit in expected.encode_utf16() {
+            assert_eq!(units.next(), Some(expected_unit));
+        }
+        assert_eq!(units.next(), None);
+    }
+
+    #[test]
+    fn configured_strings_in_descriptors() {
+        let _debug = lock_debug_writer();
+        let controller = FakeController;
+        let hid = ClientCtapHID::new(&controller, 64, 5, 0x1915, 0x521f, STRINGS);
+        check_string_descriptor(&hid, 1, "Maker");
+        check_string_descriptor(&hid, 2, "OpenSK");
+        check_string_descriptor(&hid, 3, "0123456789ABCDEF");
+    }
+
+    #[test]
+    fn unsupported_requests_stall_until_next_setup() {
+        let _debug = lock_debug_writer();
+        let controller = FakeController;
+        let hid = ClientCtapHID::new(&controller, 64, 5, 0x1915, 0x521f, STRINGS);
+        // Vendor request reading from the device.
+        let vendor_get = [0xC0, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00];
+        assert!(matches!(
+            setup(&hid, vendor_get),
+            CtrlSetupResult::ErrNonstandardRequest
+        ));
+        assert!(matches!(hid.ctrl_in(0), CtrlInResult::Error));
+        assert!(matches!(hid.ctrl_out(0, 8), CtrlOutResult::Halted));
+        // HID GET_REPORT on the interface.
+        let get_report = [0xA1, 0x01, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00];
+        assert!(matches!(
+            setup(&hid, get_report),
+            CtrlSetupResult::ErrNonstandardRequest
+        ));
+        assert!(matches!(hid.ctrl_in(0), CtrlInResult::Error));
+        // The next supported request clears the stall.
+        check_string_descriptor(&hid, 2, "OpenSK");
+    }
+
+    #[test]
+    fn new_setup_aborts_transfer_in_progress() {
+        let _debug = lock_debug_writer();
+        let controller = FakeController;
+        let hid = ClientCtapHID::new(&controller, 64, 5, 0x1915, 0x521f, STRINGS);
+        // GET_DESCRIPTOR of the configuration, never read by the host.
+        let get_configuration = [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xFF, 0x00];
+        assert!(matches!(
+            setup(&hid, get_configuration),
+            CtrlSetupResult::Ok
+        ));
+        // SET_IDLE is accepted without a data stage.
+        let set_idle = [0x21, 0x0A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        assert!(matches!(setup(&hid, set_idle), CtrlSetupResult::Ok));
+        assert!(matches!(hid.ctrl_in(0), CtrlInResult::Error));
+        hid.ctrl_status_complete(0);
+        check_string_descriptor(&hid, 1, "Maker");
+    }
+
+    #[test]
+    fn unexpected_events_are_dropped() {
+        let _debug = lock_debug_writer();
+        let controller = FakeController;
+        let hid = ClientCtapHID::new(&controller, 64, 5, 0x1915, 0x521f, STRINGS);
+        let client = FakeClient::default();
+        hid.set_client(&client);
+
+        // Transmissions that were never requested.
+        hid.packet_transmitted(ENDPOINT_NUM + 5);
+        assert_eq!(client.transmitted.get(), 0);
+        assert!(matches!(
+            hid.packet_in(TransferType::Control, ENDPOINT_NUM),
+            InResult::Error
+        ));
+        assert!(matches!(
+            hid.packet_out(TransferType::Isochronous, ENDPOINT_NUM, 64),
+            OutResult::Error
+        ));
+        // A packet the client did not ask for waits, even if sent twice.
+        for _ in 0..2 {
+            assert!(matches!(
+                hid.packet_out(TransferType::Interrupt, ENDPOINT_NUM, 64),
+                OutResult::Delay
+            ));
+        }
+        assert_eq!(client.received.get(), 0);
+
+        // The device keeps working in both directions.
+        hid.receive_packet(&mut App::default());
+        assert_eq!(client.received.get(), 1);
+        hid.receive_packet(&mut App::default());
+        assert!(matches!(
+            hid.packet_out(TransferType::Interrupt, ENDPOINT_NUM, 64),
+            OutResult::Ok
+        ));
+        assert_eq!(client.received.get(), 2);
+        assert_eq!(
+            hid.transmit_packet(&[0x55; 64], ENDPOINT_NUM),
+            ReturnCode::SUCCESS
+        );
+        assert!(matches!(
+            hid.packet_in(TransferType::Interrupt, ENDPOINT_NUM),
+            InResult::Packet(64)
+        ));
+        hid.packet_transmitted(ENDPOINT_NUM);
+        assert_eq!(client.transmitted.get(), 1);
+        check_string_descriptor(&hid, 2, "OpenSK");
+    }
+
+    #[test]
+    #[cfg(feature = "vendor_hid")]
+    fn idle_vendor_endpoint_does_not_delay_fido_packets() {
+        let _debug = lock_debug_writer();
+        let controller = FakeController;
+        let hid = ClientCtapHID::new(&controller, 64, 5, 0x1915, 0x521f, STRINGS);
+        let client = FakeClient::default();
+        hid.set_client(&client);
+
+        // The host never sends anything to the vendor endpoint.
+        for count in 1..=4 {
+            if count % 2 == 0 {
+                // The packet arrives after the client asked for it.
+                hid.receive_packet(&mut App::default());
+                assert!(matches!(
+                    hid.packet_out(TransferType::Interrupt, ENDPOINT_NUM, 64),
+                    OutResult::Ok
+                ));
+            } else {
+                // The packet arrives first and is delivered on the next request.
+                assert!(matches!(
+                    hid.packet_out(TransferType::Interrupt, ENDPOINT_NUM, 64),
+                    OutResult::Delay
+                ));
+                hid.receive_packet(&mut App::default());
+            }
+            assert_eq!(client.received.get(), count);
+            assert_eq!(client.last_endpoint.get(), ENDPOINT_NUM);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "vendor_hid")]
+    fn delayed_packets_are_delivered_one_at_a_time() {
+        let _debug = lock_debug_writer();
+        let controller = FakeController;
+        let hid = ClientCtapHID::new(&controller, 64, 5, 0x1915, 0x521f, STRINGS);
+        let client = FakeClient::default();
+        hid.set_client(&client);
+
+        for &endpoint in &[VENDOR_ENDPOINT_NUM, ENDPOINT_NUM] {
+            assert!(matches!(
+                hid.packet_out(TransferType::Interrupt, endpoint, 64),
+                OutResult::Delay
+            ));
+        }
+        hid.receive_packet(&mut App::default());
+        assert_eq!(client.received.get(), 1);
+        assert_eq!(client.last_endpoint.get(), ENDPOINT_NUM);
+        // The vendor packet is still waiting, and is next.
+        assert!(hid.endpoints[1].delayed_out.get());
+        hid.receive_packet(&mut App::default());
+        assert_eq!(client.received.get(), 2);
+        assert_eq!(client.last_endpoint.get(), VENDOR_ENDPOINT_NUM);
+    }
+
+    #[test]
+    #[cfg(feature = "vendor_hid")]
+    fn fido_receive_survives_vendor_cancel() {
+        let _debug = lock_debug_writer();
+        let controller = FakeController;
+        let hid = ClientCtapHID::new(&controller, 64, 5, 0x1915, 0x521f, STRINGS);
+        let client = FakeClient::default();
+        hid.set_client(&client);
+
+        hid.receive_packet(&mut App::default());
+        assert!(hid.cancel_transaction(VENDOR_ENDPOINT_NUM));
+        // The vendor endpoint does not deliver anymore, the FIDO endpoint still does.
+        assert!(matches!(
+            hid.packet_out(TransferType::Interrupt, VENDOR_ENDPOINT_NUM, 64),
+            OutResult::Delay
+        ));
+        assert_eq!(client.received.get(), 0);
+        assert!(matches!(
+            hid.packet_out(TransferType::Interrupt, ENDPOINT_NUM, 64),
+            OutResult::Ok
+        ));
+        assert_eq!(client.received.get(), 1);
+        assert_eq!(client.last_endpoint.get(), ENDPOINT_NUM);
+
+        // The delayed vendor packet is delivered on the next request.
+        hid.receive_packet(&mut App::default());
+        assert_eq!(client.received.get(), 2);
+        assert_eq!(client.last_endpoint.get(), VENDOR_ENDPOINT_NUM);
+
+        // Cancelling all endpoints stops the receive on both.
+        hid.receive_packet(&mut App::default());
+        assert!(hid.cancel_transaction(0));
+        for &endpoint in &[ENDPOINT_NUM, VENDOR_ENDPOINT_NUM] {
+            assert!(matches!(
+                hid.packet_out(TransferType::Interrupt, endpoint, 64),
+                OutResult::Delay
+            ));
+        }
+        assert_eq!(client.received.get(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "vendor_hid")]
+    fn receive_after_partial_cancel_delivers_delayed_packet() {
+        let _debug = lock_debug_writer();
+        let controller = FakeController;
+        let hid = ClientCtapHID::new(&controller, 64, 5, 0x1915, 0x521f, STRINGS);
+        let client = FakeClient::default();
+        hid.set_client(&client);
+
+        // The client gives up on a receive after cancelling only the vendor endpoint.
+        hid.receive_packet(&mut App::default());
+        hid.cancel_transaction(VENDOR_ENDPOINT_NUM);
+        client.busy.set(true);
+        assert!(matches!(
+            hid.packet_out(TransferType::Interrupt, ENDPOINT_NUM, 64),
+            OutResult::Delay
+        ));
+        // Its next receive gets the packet.
+        client.busy.set(false);
+        hid.receive_packet(&mut App::default());
+        assert_eq!(client.received.get(), 1);
+        assert_eq!(client.last_endpoint.get(), ENDPOINT_NUM);
+    }
+
+    /// Reads the device descriptor with `requested_length`, and returns its
+    /// bytes and the size of each packet.
+    fn read_device_descriptor<'a>(
+        hid: &'a ClientCtapHID<'a, 'a, FakeController>,
+        requested_length: u8,
+    ) -> (std::vec::Vec<u8>, std::vec::Vec<usize>) {
+        read_descriptor(hid, DescriptorType::Device, requested_length)
+    }
+
+    /// Reads a descriptor with `requested_length`, and returns its bytes and
+    /// the size of each packet.
+    fn read_descriptor<'a>(
+        hid: &'a ClientCtapHID<'a, 'a, FakeController>,
+        descriptor_type: DescriptorType,
+        requested_length: u8,
+    ) -> (std::vec::Vec<u8>, std::vec::Vec<usize>) {
+        let packet = [
+            0x80,
+            0x06,
+            0x00,
+            descriptor_type as u8,
+            0x00,
+            0x00,
+            requested_length,
+            0x00,
+        ];
+        assert!(matches!(setup(hid, packet), CtrlSetupResult::Ok));
+        let mut descriptor = std::vec::Vec::new();
+        let mut packet_sizes = std::vec::Vec::new();
+        loop {
+            match hid.ctrl_in(0) {
+                CtrlInResult::Packet(length, complete) => {
+                    let buf = &hid.client_ctrl.ctrl_buffer.buf[..length];
+                    descriptor.extend(buf.iter().map(|cell| cell.get()));
+                    packet_sizes.push(length);
+                    if complete {
+                        break;
+                    }
+                }
+                _ => panic!("Expected a packet"),
+            }
+        }
+        hid.ctrl_status_complete(0);
+        (descriptor, packet_sizes)
+    }
+
+    #[test]
+    fn ctrl_packet_size_is_advertised() {
+        for &(hardware_max, advertised) in &[(64, 64), (63, 32), (32, 32), (20, 16), (8, 8)] {
+            let _debug = lock_debug_writer();
+            let controller = FakeController;
+            let hid = ClientCtapHID::new(&controller, hardware_max, 5, 0x1915, 0x521f, STRINGS);
+            let (descriptor, _) = read_device_descriptor(&hid, 0xFF);
+            assert_eq!(descriptor.len(), 18);
+            assert_eq!(descriptor[7], advertised);
+        }
+    }
+
+    #[test]
+    fn device_descriptor_with_small_ctrl_packets() {
+        let _debug = lock_debug_writer();
+        let controller = FakeController;
+        let hid = ClientCtapHID::new(&controller, 8, 5, 0x1915, 0x521f, STRINGS);
+        // Hosts first read 8 bytes to learn the packet size.
+        let (prefix, packet_sizes) = read_device_descriptor(&hid, 8);
+        assert_eq!(packet_sizes, [8]);
+        assert_eq!(prefix[0], 18);
+        assert_eq!(prefix[1], DescriptorType::Device as u8);
+        assert_eq!(prefix[7], 8);
+        // Then they read the whole descriptor, in packets of that size.
+        let (descriptor, packet_sizes) = read_device_descriptor(&hid, 0xFF);
+        assert_eq!(packet_sizes, [8, 8, 2]);
+        assert_eq!(descriptor[..8], prefix[..]);
+        assert_eq!(descriptor[8..10], [0x15, 0x19]);
+        assert_eq!(descriptor[10..12], [0x1f, 0x52]);
+    }
+
+    /// Returns the `interval` of each endpoint in the configuration
+    /// descriptor, with the endpoint address.
+    fn endpoint_intervals<'a>(
+        hid: &'a ClientCtapHID<'a, 'a, FakeController>,
+    ) -> std::vec::Vec<(u8, u8)> {
+        let (descriptor, _) = read_descriptor(hid, DescriptorType::Configuration, 0xFF);
+        let mut intervals = std::vec::Vec::new();
+        let mut rest = &descriptor[..];
+        while !rest.is_empty() {
+            let length = rest[0] as usize;
+            if rest[1] == DescriptorType::Endpoint as u8 {
+                intervals.push((rest[2], rest[6]));
+            }
+            rest = &rest[length..];
+        }
+        intervals
+    }
+
+    #[test]
+    fn interrupt_interval_in_endpoint_descriptors() {
+        for &(configured, advertised) in &[(5, 5), (1, 1), (255, 255), (0, 1)] {
+            let _debug = lock_debug_writer();
+            let controller = FakeController;
+            let hid = ClientCtapHID::new(&controller, 64, configured, 0x1915, 0x521f, STRINGS);
+            let intervals = endpoint_intervals(&hid);
+            assert_eq!(intervals.len(), 2 * NUM_ENDPOINTS);
+            // Both the OUT and IN endpoints are polled at the same rate.
+            assert!(intervals.contains(&(ENDPOINT_NUM as u8, advertised)));
+            assert!(intervals.contains(&(0x80 | ENDPOINT_NUM as u8, advertised)));
+            for &(_, interval) in &intervals {
+                assert_eq!(interval, advertised);
+            }
+        }
+    }
+
+    #[test]
+    fn interrupt_interval_for_speed() {
+        use descriptors::EndpointDescriptor;
+        assert_eq!(EndpointDescriptor::interrupt_interval_for(DeviceSpeed::Full, 0), 1);
+        assert_eq!(EndpointDescriptor::interrupt_interval_for(DeviceSpeed::Full, 1), 1);
+        assert_eq!(EndpointDescriptor::interrupt_interval_for(DeviceSpeed::Full, 255), 255);
+        assert_eq!(EndpointDescriptor::interrupt_interval_for(DeviceSpeed::Low, 1), 10);
+        assert_eq!(EndpointDescriptor::interrupt_interval_for(DeviceSpeed::Low, 10), 10);
+        assert_eq!(EndpointDescriptor::interrupt_interval_for(DeviceSpeed::Low, 32), 32);
+    }
+}
@@ -1,5 +1,22 @@
 //! Virtualize the Alarm interface to enable multiple users of an underlying
 //! alarm hardware peripheral.
+//!
+//! Fairness
+//! --------
+//!
+//! When the underlying alarm fires, every virtual alarm that expired by then
+//! fires, in list order. Alarms that are set again from within a callback are
+//! only considered the next time the underlying alarm fires, so a client that
+//! keeps re-arming itself can't delay the other clients.
+//!
+//! Coalescing
+//! ----------
+//!
+//! With a coalescing window set through `MuxAlarm::set_coalescing_window`,
+//! the underlying alarm is set to the window after the soonest expiration.
+//! All alarms that expired by then fire together. No alarm fires before its
+//! expiration, and none fires later than its expiration plus the window, on
+//! top of the interrupt latency.
 
 use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
@@ -20,6 +37,8 @@ pub struct VirtualMuxAlarm<'a, A: Alarm<'a>> {
     /// Whether this alarm is currently armed, i.e. whether it should fire when the time has
     /// elapsed.
     armed: Cell<bool>,
+    /// Whether this alarm expired when the underlying alarm last fired, and its callback is due.
+    pending: Cell<bool>,
     /// Next alarm in the list.
     next: ListLink<'a, VirtualMuxAlarm<'a, A>>,
     /// Alarm client for this node in the list.
@@ -40,6 +59,7 @@ impl<'a, A: Alarm<'a>> VirtualMuxAlarm<'a, A> {
             reference: Cell::new(zero),
             dt: Cell::new(zero),
             armed: Cell::new(false),
+            pending: Cell::new(false),
             next: ListLink::empty(),
             client: OptionalCell::empty(),
         }
@@ -73,6 +93,7 @@ impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualMuxAlarm<'a, A> {
         }
 
         self.armed.set(false);
+        self.pending.set(false);
 
         let enabled = self.mux.enabled.get() - 1;
         self.mux.enabled.set(enabled);
@@ -93,6 +114,7 @@ impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualMuxAlarm<'a, A> {
         let enabled = self.mux.enabled.get();
         self.reference.set(reference);
         self.dt.set(dt);
+        self.pending.set(false);
 
         if !self.armed.get() {
             self.mux.enabled.set(enabled + 1);
@@ -102,7 +124,7 @@ impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualMuxAlarm<'a, A> {
         // First alarm, so set it
         if enabled == 0 {
             //debug!("virtual_alarm: first alarm: set it.");
-            self.mux.set_alarm(self.reference.get(), self.dt.get());
+            self.mux.set_alarm(reference, dt);
         } else if self.mux.firing.get() == false {
             // If firing is true, the mux will scan all the alarms after
             // firing and pick the soonest one so do not need to modify the
@@ -117,13 +139,20 @@ impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualMuxAlarm<'a, A> {
             //    window of the current earliest alarm. This means the
             //    current earliest alarm hasn't fired yet (it is in the future).
             // -pal
+            // The comparisons account for the coalescing window, as the
+            // mux would set the underlying alarm that much later.
             let cur_alarm = self.mux.alarm.get_alarm();
             let now = self.mux.alarm.now();
-            let expiration = reference.wrapping_add(dt);
+            let expiration = reference
+                .wrapping_add(dt)
+                .wrapping_add(self.mux.coalescing_window());
             if !cur_alarm.within_range(reference, expiration) {
                 let next = self.mux.next_tick_vals.get();
                 if next.map_or(true, |(next_reference, next_dt)| {
-                    now.within_range(next_reference, next_reference.wrapping_add(next_dt))
+                    let next_expiration = next_reference
+                        .wrapping_add(next_dt)
+                        .wrapping_add(self.mux.coalescing_window());
+                    now.within_range(next_reference, next_expiration)
                 }) {
                     self.mux.set_alarm(reference, dt);
                 }
@@ -160,6 +189,8 @@ pub struct MuxAlarm<'a, A: Alarm<'a>> {
     firing: Cell<bool>,
     /// Reference to next alarm
     next_tick_vals: Cell<Option<(A::Ticks, A::Ticks)>>,
+    /// How long after the soonest expiration the underlying alarm fires, in ticks.
+    coalescing_window: Cell<u32>,
 }
 
 impl<'a, A: Alarm<'a>> MuxAlarm<'a, A> {
@@ -170,12 +201,26 @@ impl<'a, A: Alarm<'a>> MuxAlarm<'a, A> {
             alarm: alarm,
             firing: Cell::new(false),
             next_tick_vals: Cell::new(None),
+            coalescing_window: Cell::new(0),
         }
     }
 
+    /// Lets alarms expiring up to `window` ticks apart fire together.
+    ///
+    /// This saves wakeups, at the cost of delaying alarms by up to `window`
+    /// ticks. It applies from the next time the underlying alarm is set.
+    pub fn set_coalescing_window(&self, window: u32) {
+        self.coalescing_window.set(window);
+    }
+
+    fn coalescing_window(&self) -> A::Ticks {
+        A::Ticks::from(self.coalescing_window.get())
+    }
+
     pub fn set_alarm(&self, reference: A::Ticks, dt: A::Ticks) {
         self.next_tick_vals.set(Some((reference, dt)));
-        self.alarm.set_alarm(reference, dt);
+        self.alarm
+            .set_alarm(reference, dt.wrapping_add(self.coalescing_window()));
     }
 
     pub fn disarm(&self) {
@@ -191,6 +236,8 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for MuxAlarm<'a, A> {
         let now = self.alarm.now();
         // Check whether to fire each alarm. At this level, alarms are one-shot,
         // so a repeating client will set it again in the alarm() callback.
+        // Expired alarms are collected before any callback runs, so that
+        // alarms set again by the callbacks wait for the next round.
         self.firing.set(true);
         self.virtual_alarms
             .iter()
@@ -201,7 +248,12 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for MuxAlarm<'a, A> {
                         cur.reference.get().wrapping_add(cur.dt.get()),
                     )
             })
+            .for_each(|cur| cur.pending.set(true));
+        self.virtual_alarms
+            .iter()
+            .filter(|cur| cur.pending.get())
             .for_each(|cur| {
+                cur.pending.set(false);
                 cur.armed.set(false);
                 self.enabled.set(self.enabled.get() - 1);
                 //debug!("  Virtualizer: {:?} outside {:?}-{:?}, fire!", now, cur.reference.get(), cur.reference.get().wrapping_add(cur.dt.get()));
@@ -230,3 +282,165 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for MuxAlarm<'a, A> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use kernel::hil::time::{Freq1KHz, Ticks32};
+    use std::boxed::Box;
+
+    /// Alarm whose time only advances when the test says so.
+    struct MockAlarm {
+        now: Cell<u32>,
+        reference: Cell<u32>,
+        dt: Cell<u32>,
+        armed: Cell<bool>,
+    }
+
+    impl MockAlarm {
+        fn expired(&self) -> bool {
+            let reference = Ticks32::from(self.reference.get());
+            let expiration = reference.wrapping_add(Ticks32::from(self.dt.get()));
+            self.armed.get() && !self.now().within_range(reference, expiration)
+        }
+    }
+
+    impl Time for MockAlarm {
+        type Frequency = Freq1KHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            Ticks32::from(self.now.get())
+        }
+    }
+
+    impl<'a> Alarm<'a> for MockAlarm {
+        fn set_alarm_client(&'a self, _client: &'a dyn time::AlarmClient) {}
+
+        fn set_alarm(&self, reference: Ticks32, dt: Ticks32) {
+            self.reference.set(reference.into_u32());
+            self.dt.set(dt.into_u32());
+            self.armed.set(true);
+        }
+
+        fn get_alarm(&self) -> Ticks32 {
+            Ticks32::from(self.reference.get().wrapping_add(self.dt.get()))
+        }
+
+        fn disarm(&self) -> ReturnCode {
+            self.armed.set(false);
+            ReturnCode::SUCCESS
+        }
+
+        fn is_armed(&self) -> bool {
+            self.armed.get()
+        }
+
+        fn minimum_dt(&self) -> Ticks32 {
+            Ticks32::from(1)
+        }
+    }
+
+    /// Client re-arming itself with the delays of `delay`, like the clients
+    /// of the multi alarm test.
+    struct TestClient {
+        alarm: &'static VirtualMuxAlarm<'static, MockAlarm>,
+        delay: fn(u32) -> u32,
+        counter: Cell<u32>,
+        expected: Cell<u32>,
+        fired: Cell<u32>,
+        max_delay: Cell<u32>,
+    }
+
+    impl TestClient {
+        fn new(
+            mux: &'static MuxAlarm<'static, MockAlarm>,
+            delay: fn(u32) -> u32,
+        ) -> &'static TestClient {
+            let alarm = Box::leak(Box::new(VirtualMuxAlarm::new(mux)));
+            let client = Box::leak(Box::new(TestClient {
+                alarm,
+                delay,
+                counter: Cell::new(0),
+                expected: Cell::new(0),
+                fired: Cell::new(0),
+                max_delay: Cell::new(0),
+            }));
+            alarm.set_alarm_client(client);
+            client
+        }
+
+        fn set_next_alarm(&self) {
+            let counter = self.counter.get();
+            let delay = (self.delay)(counter);
+            let now = self.alarm.now();
+            self.alarm.set_alarm(now, Ticks32::from(delay));
+            self.counter.set(counter + 1);
+            self.expected.set(now.into_u32() + delay);
+        }
+    }
+
+    impl time::AlarmClient for TestClient {
+        fn alarm(&self) {
+            let now = self.alarm.now().into_u32();
+            assert!(now >= self.expected.get(), "alarm fired early");
+            let delay = now - self.expected.get();
+            self.max_delay.set(self.max_delay.get().max(delay));
+            self.fired.set(self.fired.get() + 1);
+            self.set_next_alarm();
+        }
+    }
+
+    fn run_clients(window: u32) -> [&'static TestClient; 3] {
+        let alarm = Box::leak(Box::new(MockAlarm {
+            now: Cell::new(0),
+            reference: Cell::new(0),
+            dt: Cell::new(0),
+            armed: Cell::new(false),
+        }));
+        let mux = Box::leak(Box::new(MuxAlarm::new(alarm)));
+        mux.set_coalescing_window(window);
+        // A blinking LED, a keepalive and the random delays of the multi
+        // alarm test, including delays of 0.
+        let clients = [
+            TestClient::new(mux, |_| 3),
+            TestClient::new(mux, |_| 50),
+            TestClient::new(mux, |counter| {
+                let delay = (counter * 668410) % 97;
+                if delay % 11 == 0 {
+                    0
+                } else {
+                    delay
+                }
+            }),
+        ];
+        for client in clients.iter() {
+            client.set_next_alarm();
+        }
+        for now in 0..10_000 {
+            alarm.now.set(now);
+            if alarm.expired() {
+                alarm.armed.set(false);
+                time::AlarmClient::alarm(mux);
+            }
+        }
+        clients
+    }
+
+    #[test]
+    fn alarms_fire_within_window() {
+        for &window in [0, 5].iter() {
+            let clients = run_clients(window);
+            // The underlying alarm is checked once per tick, which adds one
+            // tick of latency to alarms set from a callback with a delay of 0.
+            for client in clients.iter() {
+                assert!(client.max_delay.get() <= window + 1);
+            }
+            // The keepalive is not starved by the blinking LED.
+            assert!(clients[1].fired.get() >= 10_000 / (50 + window + 1) - 1);
+            assert!(clients[0].fired.get() >= 10_000 / (3 + window + 1) - 1);
+        }
+    }
+}
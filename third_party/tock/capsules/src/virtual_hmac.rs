@@ -1,5 +1,9 @@
 //! Virtualize the HMAC interface to enable multiple users of an underlying
 //! HMAC hardware peripheral.
+//!
+//! Only one virtual device owns the hardware at a time. The owner keeps it
+//! until it calls `clear_data()`; all other devices get `EBUSY` meanwhile,
+//! since the underlying engine cannot save and restore a partial digest.
 
 use core::cell::Cell;
 use core::marker::PhantomData;
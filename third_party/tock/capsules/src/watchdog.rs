@@ -0,0 +1,109 @@
+//! Provides userspace control of a hardware watchdog.
+//!
+//! An application enables the watchdog with a timeout and then kicks it
+//! regularly, typically once per iteration of its main loop. If the
+//! application hangs, or faults and stops kicking, the watchdog resets the
+//! chip. The first application to enable the watchdog owns it: commands of
+//! other applications fail with `EBUSY`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let watchdog = static_init!(
+//!     capsules::watchdog::WatchdogDriver<'static, lowrisc::aon_timer::AonTimer>,
+//!     capsules::watchdog::WatchdogDriver::new(&peripherals.aon_timer)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Stability: 0 - Draft
+//!
+//! ### Command
+//!
+//! #### `command_num`
+//!
+//! - `0`: Driver check.
+//! - `1`: Enable the watchdog, with the timeout in milliseconds as argument.
+//! - `2`: Kick the watchdog.
+//! - `3`: Disable the watchdog.
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil;
+use kernel::{AppId, Driver, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Watchdog as usize;
+
+pub struct WatchdogDriver<'a, W: hil::watchdog::Watchdog> {
+    watchdog: &'a W,
+    /// The app that enabled the watchdog, if any.
+    owner: OptionalCell<AppId>,
+}
+
+impl<'a, W: hil::watchdog::Watchdog> WatchdogDriver<'a, W> {
+    pub fn new(watchdog: &'a W) -> Self {
+        Self {
+            watchdog,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    fn is_owned_by_other(&self, appid: AppId) -> bool {
+        self.owner.map_or(false, |owner| *owner != appid)
+    }
+}
+
+impl<'a, W: hil::watchdog::Watchdog> Driver for WatchdogDriver<'a, W> {
+    ///
+    /// ### Command numbers
+    ///
+    ///   * `0`: Returns non-zero to indicate the driver is present.
+    ///   * `1`: Enables the watchdog with a timeout of `data` milliseconds.
+    ///   * `2`: Kicks the watchdog.
+    ///   * `3`: Disables the watchdog.
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            // return if driver is available
+            0 => ReturnCode::SUCCESS,
+
+            1 => {
+                if self.is_owned_by_other(appid) {
+                    return ReturnCode::EBUSY;
+                }
+                let result = self.watchdog.enable(data);
+                if result == ReturnCode::SUCCESS {
+                    self.owner.set(appid);
+                }
+                result
+            }
+
+            2 => self.owner.map_or(ReturnCode::EOFF, |owner| {
+                if *owner != appid {
+                    return ReturnCode::EBUSY;
+                }
+                self.watchdog.kick();
+                ReturnCode::SUCCESS
+            }),
+
+            3 => {
+                if self.is_owned_by_other(appid) {
+                    return ReturnCode::EBUSY;
+                }
+                let result = self.watchdog.disable();
+                if result == ReturnCode::SUCCESS {
+                    self.owner.clear();
+                }
+                result
+            }
+
+            // default
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -1,7 +1,19 @@
 //! Support for the AES hardware block on OpenTitan
 //!
 //! <https://docs.opentitan.org/hw/ip/aes/doc/>
+//!
+//! The block version this driver targets only implements ECB: its `CTRL`
+//! register has no mode field and it has no IV registers, see `AesRegisters`
+//! below and the register table of the documentation above. CTR mode is thus
+//! built on top of ECB: the
+//! driver keeps the counter block, feeds it through the hardware to produce
+//! the keystream, and increments it as a 128-bit big-endian integer after each
+//! block.
+//!
+//! Besides the 128-bit keys of the `AES128` HIL, `set_key` also accepts 256-bit
+//! keys, which the hardware supports natively.
 
+use core::cell::Cell;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::common::registers::{
     register_bitfields, register_structs, ReadOnly, ReadWrite, WriteOnly,
@@ -15,6 +27,9 @@ use kernel::ReturnCode;
 
 const MAX_LENGTH: usize = 128;
 
+/// Size of the 256-bit keys accepted by `set_key`.
+pub const AES256_KEY_SIZE: usize = 32;
+
 register_structs! {
     pub AesRegisters {
         (0x00 => key0: WriteOnly<u32>),
@@ -71,12 +86,25 @@ register_bitfields![u32,
 const AES_BASE: StaticRef<AesRegisters> =
     unsafe { StaticRef::new(0x40110000 as *const AesRegisters) };
 
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    ECB,
+    CTR,
+}
+
 pub struct Aes<'a> {
     registers: StaticRef<AesRegisters>,
 
     client: OptionalCell<&'a dyn hil::symmetric_encryption::Client<'a>>,
     source: TakeCell<'a, [u8]>,
     dest: TakeCell<'a, [u8]>,
+    mode: Cell<Mode>,
+    encrypting: Cell<bool>,
+    key_size: Cell<usize>,
+    // Initial counter, as set by `set_iv`.
+    iv: Cell<[u8; AES128_BLOCK_SIZE]>,
+    // Counter for the next block in CTR mode.
+    counter: Cell<[u8; AES128_BLOCK_SIZE]>,
 }
 
 impl<'a> Aes<'a> {
@@ -86,6 +114,11 @@ impl<'a> Aes<'a> {
             client: OptionalCell::empty(),
             source: TakeCell::empty(),
             dest: TakeCell::empty(),
+            mode: Cell::new(Mode::ECB),
+            encrypting: Cell::new(true),
+            key_size: Cell::new(AES128_KEY_SIZE),
+            iv: Cell::new([0; AES128_BLOCK_SIZE]),
+            counter: Cell::new([0; AES128_BLOCK_SIZE]),
         }
     }
 
@@ -98,16 +131,21 @@ impl<'a> Aes<'a> {
 
     fn configure(&self, encrypting: bool) {
         let regs = self.registers;
+        self.encrypting.set(encrypting);
         let e = if encrypting {
             CTRL::OPERATION::Encrypting
         } else {
             CTRL::OPERATION::Decrypting
         };
+        let key_len = if self.key_size.get() == AES256_KEY_SIZE {
+            CTRL::KEY_LEN::Key256
+        } else {
+            CTRL::KEY_LEN::Key128
+        };
         // Set this in manual mode for the moment since automatic block mode
         // does not appear to be working
 
-        regs.ctrl
-            .write(e + CTRL::KEY_LEN::Key128 + CTRL::MANUAL_OPERATION::SET);
+        regs.ctrl.write(e + key_len + CTRL::MANUAL_OPERATION::SET);
     }
 
     fn idle(&self) -> bool {
@@ -230,11 +268,14 @@ impl<'a> Aes<'a> {
             }
         }
 
-        if key.len() != AES128_KEY_SIZE {
+        if key.len() != AES128_KEY_SIZE && key.len() != AES256_KEY_SIZE {
             return ReturnCode::EINVAL;
         }
+        // The key length is part of the control register.
+        self.key_size.set(key.len());
+        self.configure(self.encrypting.get());
 
-        for i in 0..4 {
+        for i in 0..key.len() / 4 {
             let mut k = key[i * 4 + 0] as usize;
             k |= (key[i * 4 + 1] as usize) << 8;
             k |= (key[i * 4 + 2] as usize) << 16;
@@ -244,18 +285,88 @@ impl<'a> Aes<'a> {
                 1 => regs.key1.set(k as u32),
                 2 => regs.key2.set(k as u32),
                 3 => regs.key3.set(k as u32),
+                4 => regs.key4.set(k as u32),
+                5 => regs.key5.set(k as u32),
+                6 => regs.key6.set(k as u32),
+                7 => regs.key7.set(k as u32),
                 _ => {}
             }
         }
 
-        // We must write the rest of the registers as well
-        regs.key4.set(0);
-        regs.key5.set(0);
-        regs.key6.set(0);
-        regs.key7.set(0);
+        if key.len() == AES128_KEY_SIZE {
+            // We must write the rest of the registers as well
+            regs.key4.set(0);
+            regs.key5.set(0);
+            regs.key6.set(0);
+            regs.key7.set(0);
+        }
         ReturnCode::SUCCESS
     }
 
+    /// Encrypts a single block with the hardware.
+    fn encrypt_block(&self, block: &[u8; AES128_BLOCK_SIZE]) -> [u8; AES128_BLOCK_SIZE] {
+        let regs = self.registers;
+
+        loop {
+            if self.input_ready() {
+                break;
+            }
+        }
+
+        for i in 0..4 {
+            let mut v = block[i * 4 + 0] as u32;
+            v |= (block[i * 4 + 1] as u32) << 8;
+            v |= (block[i * 4 + 2] as u32) << 16;
+            v |= (block[i * 4 + 3] as u32) << 24;
+            match i {
+                0 => regs.data_in0.set(v),
+                1 => regs.data_in1.set(v),
+                2 => regs.data_in2.set(v),
+                3 => regs.data_in3.set(v),
+                _ => {}
+            }
+        }
+        self.trigger();
+
+        loop {
+            if self.output_valid() {
+                break;
+            }
+        }
+
+        let mut output = [0; AES128_BLOCK_SIZE];
+        for i in 0..4 {
+            let v = match i {
+                0 => regs.data_out0.get(),
+                1 => regs.data_out1.get(),
+                2 => regs.data_out2.get(),
+                _ => regs.data_out3.get(),
+            };
+            output[i * 4 + 0] = (v >> 0) as u8;
+            output[i * 4 + 1] = (v >> 8) as u8;
+            output[i * 4 + 2] = (v >> 16) as u8;
+            output[i * 4 + 3] = (v >> 24) as u8;
+        }
+        output
+    }
+
+    fn do_crypt_ctr(&self, start_index: usize, stop_index: usize) {
+        // With a separate source buffer, the input starts at its index 0.
+        self.dest.map(|dest| {
+            let dest = &mut dest[start_index..stop_index];
+            let mut counter = self.counter.get();
+            let mut encrypt_block = |block: &[u8; AES128_BLOCK_SIZE]| self.encrypt_block(block);
+            let has_source = self
+                .source
+                .map(|source| ctr_crypt(&mut counter, Some(source), dest, &mut encrypt_block))
+                .is_some();
+            if !has_source {
+                ctr_crypt(&mut counter, None, dest, &mut encrypt_block);
+            }
+            self.counter.set(counter);
+        });
+    }
+
     fn do_crypt(&self, start_index: usize, stop_index: usize, wr_start_index: usize) {
         // convert our indicies into the array into block numbers
         // start and end are pointer for reading
@@ -288,12 +399,21 @@ impl<'a> hil::symmetric_encryption::AES128<'a> for Aes<'a> {
         self.client.set(client);
     }
 
-    fn set_iv(&self, _iv: &[u8]) -> ReturnCode {
-        // nothing because this is ECB
+    fn set_iv(&self, iv: &[u8]) -> ReturnCode {
+        // The IV is only used as the initial counter in CTR mode.
+        if iv.len() != AES128_BLOCK_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut counter = [0; AES128_BLOCK_SIZE];
+        counter.copy_from_slice(iv);
+        self.iv.set(counter);
+        self.counter.set(counter);
         ReturnCode::SUCCESS
     }
 
-    fn start_message(&self) {}
+    fn start_message(&self) {
+        self.counter.set(self.iv.get());
+    }
 
     fn set_key(&self, key: &[u8]) -> ReturnCode {
         self.set_key(key)
@@ -312,12 +432,26 @@ impl<'a> hil::symmetric_encryption::AES128<'a> for Aes<'a> {
                 if s > MAX_LENGTH {
                     return Some((ReturnCode::EINVAL, source, dest));
                 }
-                if s % AES128_BLOCK_SIZE != 0 {
+                // CTR mode is a stream cipher, so the last block can be partial.
+                if s % AES128_BLOCK_SIZE != 0 && self.mode.get() != Mode::CTR {
+                    return Some((ReturnCode::EINVAL, source, dest));
+                }
+                if stop_index > dest.len() || source.as_ref().map_or(false, |src| src.len() < s) {
                     return Some((ReturnCode::EINVAL, source, dest));
                 }
             }
         }
         self.dest.replace(dest);
+        if self.mode.get() == Mode::CTR {
+            if let Some(src) = source {
+                self.source.replace(src);
+            }
+            self.do_crypt_ctr(start_index, stop_index);
+            self.client.map(|client| {
+                client.crypt_done(self.source.take(), self.dest.take().unwrap());
+            });
+            return None;
+        }
         // The crypt API has two cases: separate source and destination
         // buffers and a single source buffer.
         // If we don't have a separate source buffer, we overwrite the
@@ -343,6 +477,156 @@ impl<'a> hil::symmetric_encryption::AES128<'a> for Aes<'a> {
 
 impl kernel::hil::symmetric_encryption::AES128ECB for Aes<'_> {
     fn set_mode_aes128ecb(&self, encrypting: bool) {
+        self.mode.set(Mode::ECB);
         self.configure(encrypting);
     }
 }
+
+impl kernel::hil::symmetric_encryption::AES128Ctr for Aes<'_> {
+    fn set_mode_aes128ctr(&self, _encrypting: bool) {
+        // CTR decryption is the same as encryption, the hardware only ever
+        // encrypts counter blocks.
+        self.mode.set(Mode::CTR);
+        self.configure(true);
+    }
+}
+
+/// Increments a counter block as a 128-bit big-endian integer, wrapping
+/// around on overflow.
+fn increment_counter(mut counter: [u8; AES128_BLOCK_SIZE]) -> [u8; AES128_BLOCK_SIZE] {
+    for byte in counter.iter_mut().rev() {
+        *byte = byte.wrapping_add(1);
+        if *byte != 0 {
+            break;
+        }
+    }
+    counter
+}
+
+/// Encrypts or decrypts `dest` in CTR mode, with `encrypt_block` as the block
+/// cipher.
+///
+/// The input is `source` if given, otherwise `dest` is transformed in place.
+/// The last block may be partial, in which case only the first bytes of its
+/// keystream are used. The counter is incremented after each block.
+fn ctr_crypt(
+    counter: &mut [u8; AES128_BLOCK_SIZE],
+    source: Option<&[u8]>,
+    dest: &mut [u8],
+    mut encrypt_block: impl FnMut(&[u8; AES128_BLOCK_SIZE]) -> [u8; AES128_BLOCK_SIZE],
+) {
+    for (block, dest) in dest.chunks_mut(AES128_BLOCK_SIZE).enumerate() {
+        let keystream = encrypt_block(counter);
+        *counter = increment_counter(*counter);
+        for (i, (d, k)) in dest.iter_mut().zip(keystream.iter()).enumerate() {
+            let input = match source {
+                Some(source) => source[block * AES128_BLOCK_SIZE + i],
+                None => *d,
+            };
+            *d = input ^ k;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    // NIST SP 800-38A, F.5.1 and F.5.2: CTR-AES128. The key is only known to
+    // the hardware, so the vectors give the output block for each counter.
+    const INITIAL_COUNTER: &str = "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff";
+    const BLOCKS: [(&str, &str); 4] = [
+        (
+            "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff",
+            "ec8cdf7398607cb0f2d21675ea9ea1e4",
+        ),
+        (
+            "f0f1f2f3f4f5f6f7f8f9fafbfcfdff00",
+            "362b7c3c6773516318a077d7fc5073ae",
+        ),
+        (
+            "f0f1f2f3f4f5f6f7f8f9fafbfcfdff01",
+            "6a2cc3787889374fbeb4c81b17ba6c44",
+        ),
+        (
+            "f0f1f2f3f4f5f6f7f8f9fafbfcfdff02",
+            "e89c399ff0f198c6d40a31db156cabfe",
+        ),
+    ];
+    const PLAINTEXT: &str = "6bc1bee22e409f96e93d7e117393172a\
+                             ae2d8a571e03ac9c9eb76fac45af8e51\
+                             30c81c46a35ce411e5fbc1191a0a52ef\
+                             f69f2445df4f9b17ad2b417be66c3710";
+    const CIPHERTEXT: &str = "874d6191b620e3261bef6864990db6ce\
+                              9806f66b7970fdff8617187bb9fffdff\
+                              5ae4df3edbd5d35e5b4f09020db03eab\
+                              1e031dda2fbe03d1792170a0f3009cee";
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn block(s: &str) -> [u8; AES128_BLOCK_SIZE] {
+        let mut block = [0; AES128_BLOCK_SIZE];
+        block.copy_from_slice(&hex(s));
+        block
+    }
+
+    /// Plays the hardware for the key of the vectors.
+    fn nist_aes128(input: &[u8; AES128_BLOCK_SIZE]) -> [u8; AES128_BLOCK_SIZE] {
+        let (_, output) = BLOCKS
+            .iter()
+            .find(|(counter, _)| block(counter) == *input)
+            .expect("counter block not in the vectors");
+        block(output)
+    }
+
+    #[test]
+    fn ctr_encrypt_in_place() {
+        // The second block carries from the last byte of the counter into the
+        // one before.
+        let mut counter = block(INITIAL_COUNTER);
+        let mut data = hex(PLAINTEXT);
+        ctr_crypt(&mut counter, None, &mut data, nist_aes128);
+        assert_eq!(data, hex(CIPHERTEXT));
+        assert_eq!(counter, block("f0f1f2f3f4f5f6f7f8f9fafbfcfdff03"));
+    }
+
+    #[test]
+    fn ctr_decrypt_with_source() {
+        let mut counter = block(INITIAL_COUNTER);
+        let source = hex(CIPHERTEXT);
+        let mut dest = [0; 64];
+        ctr_crypt(&mut counter, Some(&source), &mut dest, nist_aes128);
+        assert_eq!(&dest[..], &hex(PLAINTEXT)[..]);
+    }
+
+    #[test]
+    fn ctr_partial_last_block() {
+        let mut counter = block(INITIAL_COUNTER);
+        let mut data = hex(PLAINTEXT);
+        data.truncate(40);
+        ctr_crypt(&mut counter, None, &mut data, nist_aes128);
+        assert_eq!(&data[..], &hex(CIPHERTEXT)[..40]);
+        assert_eq!(counter, block(BLOCKS[3].0));
+    }
+
+    #[test]
+    fn ctr_counter_wraps_around() {
+        // With the identity as block cipher, the keystream is the counter.
+        let mut counter = [0xff; AES128_BLOCK_SIZE];
+        let mut data = [0; 2 * AES128_BLOCK_SIZE];
+        ctr_crypt(&mut counter, None, &mut data, |block| *block);
+        assert_eq!(data[..AES128_BLOCK_SIZE], [0xff; AES128_BLOCK_SIZE]);
+        assert_eq!(data[AES128_BLOCK_SIZE..], [0; AES128_BLOCK_SIZE]);
+        let mut expected = [0; AES128_BLOCK_SIZE];
+        expected[AES128_BLOCK_SIZE - 1] = 1;
+        assert_eq!(counter, expected);
+    }
+}
@@ -0,0 +1,5 @@
+use kernel::common::StaticRef;
+use lowrisc::aon_timer::AonTimerRegisters;
+
+pub(crate) const AON_TIMER_BASE: StaticRef<AonTimerRegisters> =
+    unsafe { StaticRef::new(0x4047_0000 as *const AonTimerRegisters) };
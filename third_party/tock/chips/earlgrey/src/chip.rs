@@ -26,6 +26,7 @@ pub struct EarlGrey<'a, A: 'static + Alarm<'static>, I: InterruptService<()> + '
 
 pub struct EarlGreyDefaultPeripherals<'a> {
     pub aes: crate::aes::Aes<'a>,
+    pub aon_timer: lowrisc::aon_timer::AonTimer,
     pub hmac: lowrisc::hmac::Hmac<'a>,
     pub usb: lowrisc::usbdev::Usb<'a>,
     pub uart0: lowrisc::uart::Uart<'a>,
@@ -38,6 +39,10 @@ impl<'a> EarlGreyDefaultPeripherals<'a> {
     pub fn new() -> Self {
         Self {
             aes: crate::aes::Aes::new(),
+            aon_timer: lowrisc::aon_timer::AonTimer::new(
+                crate::aon_timer::AON_TIMER_BASE,
+                CONFIG.aon_freq,
+            ),
             hmac: lowrisc::hmac::Hmac::new(crate::hmac::HMAC0_BASE),
             usb: lowrisc::usbdev::Usb::new(crate::usbdev::USB0_BASE),
             uart0: lowrisc::uart::Uart::new(crate::uart::UART0_BASE, CONFIG.peripheral_freq),
@@ -97,6 +102,28 @@ impl<'a, A: 'static + Alarm<'static>, I: InterruptService<()> + 'a> EarlGrey<'a,
         }
     }
 
+    /// Selects the wakeup sources of the light sleep entered when idle.
+    ///
+    /// See `crate::pwrmgr::DEFAULT_WAKEUP_SOURCES` for the recommended sources.
+    pub fn set_wakeup_sources(&self, sources: u32) -> bool {
+        if !self.pwrmgr.set_wakeup_sources(sources) {
+            return false;
+        }
+        self.check_until_true_or_interrupt(|| self.pwrmgr.check_clock_propagation(), None);
+        true
+    }
+
+    /// Selects the peripherals allowed to reset the chip.
+    ///
+    /// See `crate::pwrmgr::RESET_AON_TIMER` for the watchdog.
+    pub fn set_reset_requests(&self, requests: u32) -> bool {
+        if !self.pwrmgr.set_reset_requests(requests) {
+            return false;
+        }
+        self.check_until_true_or_interrupt(|| self.pwrmgr.check_clock_propagation(), None);
+        true
+    }
+
     pub unsafe fn enable_plic_interrupts(&self) {
         self.plic.disable_all();
         self.plic.enable_all();
@@ -205,11 +232,19 @@ impl<'a, A: 'static + Alarm<'static>, I: InterruptService<()> + 'a> kernel::Chip
     }
 
     fn sleep(&self) {
-        unsafe {
-            self.pwrmgr.enable_low_power();
-            self.check_until_true_or_interrupt(|| self.pwrmgr.check_clock_propagation(), None);
-            rv32i::support::wfi();
+        self.pwrmgr.enable_light_sleep();
+        // Don't sleep if an interrupt, for example from USB, arrived in the
+        // meantime. Interrupts raised after this check abort the low power
+        // entry in hardware.
+        if self.check_until_true_or_interrupt(|| self.pwrmgr.check_clock_propagation(), None) {
+            unsafe {
+                rv32i::support::wfi();
+            }
         }
+        // Without a wakeup interrupt, for example when the entry was aborted,
+        // the low power configuration is still in place.
+        self.pwrmgr.disable_low_power();
+        self.check_until_true_or_interrupt(|| self.pwrmgr.check_clock_propagation(), None);
     }
 
     unsafe fn atomic<F, R>(&self, f: F) -> R
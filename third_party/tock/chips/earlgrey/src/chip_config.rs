@@ -22,6 +22,21 @@ pub struct Config<'a> {
     /// The baud rate for UART. This allows for a version of the chip that can
     /// support a faster baud rate to use it to help with debugging.
     pub uart_baudrate: u32,
+    /// Whether UART0 uses RTS/CTS flow control. This requires the RTS and CTS
+    /// signals to be connected to the GPIOs given by `UART0_RTS_PIN` and
+    /// `UART0_CTS_PIN`.
+    pub uart_flow_control: bool,
+    /// The UART RX idle timeout in bit-times, or 0 to disable it. When
+    /// enabled, receives complete with the bytes received so far once the
+    /// line goes idle instead of as soon as any byte arrives.
+    pub uart_rx_timeout: u32,
+    /// How long an I2C slave may stretch the clock, in peripheral clock
+    /// cycles, or 0 to disable the timeout. Transfers that time out fail with
+    /// `Error::BusTimeout` after trying to recover the bus.
+    pub i2c_stretch_timeout: u32,
+    /// The clock speed of the always-on domain in Hz, which clocks the
+    /// watchdog.
+    pub aon_freq: u32,
 }
 
 /// Config for running EarlGrey on an FPGA. Also the default configuration.
@@ -34,6 +49,11 @@ pub const CONFIG: Config = Config {
     cpu_freq: 10_000_000,
     peripheral_freq: 2_500_000,
     uart_baudrate: 115200,
+    uart_flow_control: false,
+    uart_rx_timeout: 0,
+    // 10 ms at the peripheral frequency.
+    i2c_stretch_timeout: 25_000,
+    aon_freq: 200_000,
 };
 
 /// Config for running EarlGrey in a verilog simulator.
@@ -43,4 +63,9 @@ pub const CONFIG: Config = Config {
     cpu_freq: 500_000,
     peripheral_freq: 125_000,
     uart_baudrate: 9600,
+    uart_flow_control: false,
+    uart_rx_timeout: 0,
+    // 10 ms at the peripheral frequency.
+    i2c_stretch_timeout: 1_250,
+    aon_freq: 200_000,
 };
@@ -1,6 +1,10 @@
 use kernel::common::StaticRef;
 use lowrisc::i2c::I2cRegisters;
 
+use crate::chip_config::CONFIG;
+
+pub const I2C_STRETCH_TIMEOUT: u32 = CONFIG.i2c_stretch_timeout;
+
 // This is a placeholder address as the I2C MMIO interface isn't avaliable yet
 pub const I2C_BASE: StaticRef<I2cRegisters> =
     unsafe { StaticRef::new(0x4008_0000 as *const I2cRegisters) };
@@ -3,3 +3,21 @@ use lowrisc::pwrmgr::PwrMgrRegisters;
 
 pub(crate) const PWRMGR_BASE: StaticRef<PwrMgrRegisters> =
     unsafe { StaticRef::new(0x4040_0000 as *const PwrMgrRegisters) };
+
+/// Wakeup request of the pinmux, raised by activity on a wakeup pin such as
+/// the user presence button.
+pub const WAKEUP_PIN: u32 = 1 << 2;
+/// Wakeup request of the pinmux USB wakeup detector, raised by bus activity.
+pub const WAKEUP_USB: u32 = 1 << 3;
+/// Wakeup request of the always-on timer.
+pub const WAKEUP_AON_TIMER: u32 = 1 << 4;
+
+/// Wakeup sources enabled by default.
+///
+/// USB activity must wake the chip so that CTAP requests are served without
+/// delay, the button so that user presence checks complete, and the timer so
+/// that alarms, including the scheduler timer, still fire.
+pub const DEFAULT_WAKEUP_SOURCES: u32 = WAKEUP_PIN | WAKEUP_USB | WAKEUP_AON_TIMER;
+
+/// Reset request of the always-on timer, raised by the watchdog bite.
+pub const RESET_AON_TIMER: u32 = 1 << 1;
@@ -1,6 +1,7 @@
 //! Timer driver.
 
 use crate::chip_config::CONFIG;
+use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{register_bitfields, register_structs, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
@@ -53,18 +54,53 @@ register_bitfields![u32,
     ]
 ];
 
+/// Timestamps external events with the timer.
+///
+/// Events are captured by a GPIO pin, see `lowrisc::gpio::GpioPin::set_capture`.
+pub trait InputCapture<'a>: time::Time {
+    fn set_capture_client(&self, client: &'a dyn CaptureClient);
+
+    /// Returns the captured timestamp and clears it.
+    ///
+    /// If another event was captured before the timestamp was read, both are
+    /// discarded and `CaptureError::Overrun` is returned.
+    fn take_capture(&self) -> Result<Self::Ticks, CaptureError>;
+}
+
+pub trait CaptureClient {
+    /// Called when an event is captured, the timestamp is read with
+    /// `InputCapture::take_capture`.
+    fn captured(&self);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureError {
+    /// No event was captured since the last read.
+    Empty,
+    /// Events were captured faster than they were read.
+    Overrun,
+}
+
 pub struct RvTimer<'a> {
     registers: StaticRef<TimerRegisters>,
     alarm_client: OptionalCell<&'a dyn time::AlarmClient>,
     overflow_client: OptionalCell<&'a dyn time::OverflowClient>,
+    capture_client: OptionalCell<&'a dyn CaptureClient>,
+    capture: Cell<Result<Ticks64, CaptureError>>,
 }
 
 impl<'a> RvTimer<'a> {
     pub const fn new() -> RvTimer<'a> {
+        RvTimer::with_registers(TIMER_BASE)
+    }
+
+    const fn with_registers(registers: StaticRef<TimerRegisters>) -> RvTimer<'a> {
         RvTimer {
-            registers: TIMER_BASE,
+            registers,
             alarm_client: OptionalCell::empty(),
             overflow_client: OptionalCell::empty(),
+            capture_client: OptionalCell::empty(),
+            capture: Cell::new(Err(CaptureError::Empty)),
         }
     }
 
@@ -89,6 +125,33 @@ impl<'a> RvTimer<'a> {
     }
 }
 
+impl lowrisc::gpio::DebounceClock for RvTimer<'_> {
+    fn now_ms(&self) -> u64 {
+        self.now().into_u64() * 1000 / <Freq10KHz as time::Frequency>::frequency() as u64
+    }
+}
+
+impl lowrisc::gpio::EdgeCapture for RvTimer<'_> {
+    fn capture(&self) {
+        let capture = match self.capture.get() {
+            Err(CaptureError::Empty) => Ok(self.now()),
+            _ => Err(CaptureError::Overrun),
+        };
+        self.capture.set(capture);
+        self.capture_client.map(|client| client.captured());
+    }
+}
+
+impl<'a> InputCapture<'a> for RvTimer<'a> {
+    fn set_capture_client(&self, client: &'a dyn CaptureClient) {
+        self.capture_client.set(client);
+    }
+
+    fn take_capture(&self) -> Result<Ticks64, CaptureError> {
+        self.capture.replace(Err(CaptureError::Empty))
+    }
+}
+
 impl time::Time for RvTimer<'_> {
     type Frequency = Freq10KHz;
     type Ticks = Ticks64;
@@ -194,3 +257,70 @@ impl<'a> time::Alarm<'a> for RvTimer<'a> {
 
 const TIMER_BASE: StaticRef<TimerRegisters> =
     unsafe { StaticRef::new(0x4010_0000 as *const TimerRegisters) };
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use lowrisc::fake_registers::{fake_registers, set_word};
+    use lowrisc::gpio::EdgeCapture;
+    use std::boxed::Box;
+
+    /// Backs the registers with plain memory, so the counter only changes when
+    /// the test sets it.
+    fn fake_timer() -> (*mut u32, RvTimer<'static>) {
+        let (memory, registers) = fake_registers(Box::leak(Box::new([0u32; 0x120 / 4])));
+        (memory, RvTimer::with_registers(registers))
+    }
+
+    fn set_counter(memory: *mut u32, value: u64) {
+        set_word(memory, 0x104, value as u32);
+        set_word(memory, 0x108, (value >> 32) as u32);
+    }
+
+    struct Client {
+        count: Cell<usize>,
+    }
+
+    impl CaptureClient for Client {
+        fn captured(&self) {
+            self.count.set(self.count.get() + 1);
+        }
+    }
+
+    #[test]
+    fn capture_latches_counter() {
+        let (memory, timer) = fake_timer();
+        let client = Box::leak(Box::new(Client {
+            count: Cell::new(0),
+        }));
+        timer.set_capture_client(client);
+        assert_eq!(timer.take_capture(), Err(CaptureError::Empty));
+
+        set_counter(memory, 0x1_0000_0042);
+        timer.capture();
+        set_counter(memory, 0x1_0000_0100);
+        assert_eq!(client.count.get(), 1);
+        assert_eq!(timer.take_capture(), Ok(Ticks64::from(0x1_0000_0042u64)));
+        assert_eq!(timer.take_capture(), Err(CaptureError::Empty));
+
+        timer.capture();
+        assert_eq!(timer.take_capture(), Ok(Ticks64::from(0x1_0000_0100u64)));
+    }
+
+    #[test]
+    fn capture_reports_overrun() {
+        let (memory, timer) = fake_timer();
+        set_counter(memory, 10);
+        timer.capture();
+        set_counter(memory, 20);
+        timer.capture();
+        timer.capture();
+        assert_eq!(timer.take_capture(), Err(CaptureError::Overrun));
+
+        // Capturing works again once the overrun is read
+        timer.capture();
+        assert_eq!(timer.take_capture(), Ok(Ticks64::from(20u64)));
+    }
+}
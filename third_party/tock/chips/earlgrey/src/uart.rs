@@ -5,6 +5,12 @@ use lowrisc::uart::UartRegisters;
 use crate::chip_config::CONFIG;
 
 pub const UART0_BAUDRATE: u32 = CONFIG.uart_baudrate;
+pub const UART0_FLOW_CONTROL: bool = CONFIG.uart_flow_control;
+pub const UART0_RX_TIMEOUT: u32 = CONFIG.uart_rx_timeout;
+
+/// GPIOs used for UART0 flow control, if enabled.
+pub const UART0_RTS_PIN: usize = 16;
+pub const UART0_CTS_PIN: usize = 17;
 
 pub const UART0_BASE: StaticRef<UartRegisters> =
     unsafe { StaticRef::new(0x4000_0000 as *const UartRegisters) };
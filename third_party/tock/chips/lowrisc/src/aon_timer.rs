@@ -0,0 +1,147 @@
+//! Watchdog of the LowRISC always-on timer.
+//!
+//! The watchdog counts in the always-on clock domain. When the count reaches
+//! the bite threshold, it requests a reset from the power manager, which must
+//! have this reset request enabled. The count is not paused in sleep, so that
+//! software waiting forever for an interrupt is caught as well.
+
+use kernel::common::registers::{register_bitfields, register_structs, ReadWrite};
+use kernel::common::StaticRef;
+use kernel::hil;
+use kernel::ReturnCode;
+
+register_structs! {
+    pub AonTimerRegisters {
+        (0x00 => alert_test: ReadWrite<u32>),
+        (0x04 => wkup_ctrl: ReadWrite<u32>),
+        (0x08 => wkup_thold: ReadWrite<u32>),
+        (0x0C => wkup_count: ReadWrite<u32>),
+        (0x10 => wdog_regwen: ReadWrite<u32, WDOG_REGWEN::Register>),
+        (0x14 => wdog_ctrl: ReadWrite<u32, WDOG_CTRL::Register>),
+        (0x18 => wdog_bark_thold: ReadWrite<u32>),
+        (0x1C => wdog_bite_thold: ReadWrite<u32>),
+        (0x20 => wdog_count: ReadWrite<u32>),
+        (0x24 => intr_state: ReadWrite<u32>),
+        (0x28 => intr_test: ReadWrite<u32>),
+        (0x2C => wkup_cause: ReadWrite<u32>),
+        (0x30 => @END),
+    }
+}
+
+register_bitfields![u32,
+    WDOG_REGWEN [
+        EN OFFSET(0) NUMBITS(1) []
+    ],
+    WDOG_CTRL [
+        ENABLE OFFSET(0) NUMBITS(1) [],
+        PAUSE_IN_SLEEP OFFSET(1) NUMBITS(1) []
+    ]
+];
+
+pub struct AonTimer {
+    registers: StaticRef<AonTimerRegisters>,
+    /// Frequency of the always-on clock in Hz.
+    aon_freq: u32,
+}
+
+impl AonTimer {
+    pub const fn new(base: StaticRef<AonTimerRegisters>, aon_freq: u32) -> AonTimer {
+        AonTimer {
+            registers: base,
+            aon_freq,
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.registers.wdog_regwen.read(WDOG_REGWEN::EN) == 0
+    }
+}
+
+impl hil::watchdog::Watchdog for AonTimer {
+    fn enable(&self, timeout_ms: usize) -> ReturnCode {
+        let ticks = (timeout_ms as u64)
+            .checked_mul(self.aon_freq as u64)
+            .map_or(0, |product| product / 1000);
+        if ticks == 0 || ticks > u32::MAX as u64 {
+            return ReturnCode::EINVAL;
+        }
+        if self.is_locked() {
+            return ReturnCode::FAIL;
+        }
+        let regs = self.registers;
+
+        regs.wdog_ctrl.write(WDOG_CTRL::ENABLE::CLEAR);
+        regs.wdog_count.set(0);
+        // Only the bite is used. The bark interrupt is not handled, so it
+        // never fires.
+        regs.wdog_bark_thold.set(u32::MAX);
+        regs.wdog_bite_thold.set(ticks as u32);
+        regs.wdog_ctrl
+            .write(WDOG_CTRL::ENABLE::SET + WDOG_CTRL::PAUSE_IN_SLEEP::CLEAR);
+        ReturnCode::SUCCESS
+    }
+
+    fn kick(&self) {
+        self.registers.wdog_count.set(0);
+    }
+
+    fn disable(&self) -> ReturnCode {
+        if self.is_locked() {
+            return ReturnCode::FAIL;
+        }
+        self.registers.wdog_ctrl.write(WDOG_CTRL::ENABLE::CLEAR);
+        ReturnCode::SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::fake_registers::{fake_registers, set_word, word};
+    use kernel::hil::watchdog::Watchdog;
+    use std::boxed::Box;
+
+    #[test]
+    fn enable_kick_disable() {
+        let (memory, registers) = fake_registers(Box::leak(Box::new([0u32; 0x30 / 4])));
+        let watchdog = AonTimer::new(registers, 200_000);
+        set_word(memory, 0x10, 1);
+        set_word(memory, 0x20, 1234);
+
+        assert_eq!(watchdog.enable(10_000), ReturnCode::SUCCESS);
+        assert_eq!(word(memory, 0x14), 0b01);
+        assert_eq!(word(memory, 0x18), u32::MAX);
+        assert_eq!(word(memory, 0x1C), 2_000_000);
+        assert_eq!(word(memory, 0x20), 0);
+
+        set_word(memory, 0x20, 1234);
+        watchdog.kick();
+        assert_eq!(word(memory, 0x20), 0);
+
+        assert_eq!(watchdog.disable(), ReturnCode::SUCCESS);
+        assert_eq!(word(memory, 0x14), 0);
+    }
+
+    #[test]
+    fn invalid_timeout() {
+        let (memory, registers) = fake_registers(Box::leak(Box::new([0u32; 0x30 / 4])));
+        let watchdog = AonTimer::new(registers, 200_000);
+        set_word(memory, 0x10, 1);
+
+        assert_eq!(watchdog.enable(0), ReturnCode::EINVAL);
+        assert_eq!(watchdog.enable(usize::MAX), ReturnCode::EINVAL);
+        assert_eq!(word(memory, 0x14), 0);
+    }
+
+    #[test]
+    fn locked() {
+        let (memory, registers) = fake_registers(Box::leak(Box::new([0u32; 0x30 / 4])));
+        let watchdog = AonTimer::new(registers, 200_000);
+
+        assert_eq!(watchdog.enable(10_000), ReturnCode::FAIL);
+        assert_eq!(watchdog.disable(), ReturnCode::FAIL);
+        assert_eq!(word(memory, 0x14), 0);
+    }
+}
@@ -0,0 +1,24 @@
+//! Register blocks backed by plain memory, for driver tests.
+//!
+//! The registers only change when the test writes them, so a test can play the
+//! role of the hardware.
+
+use kernel::common::StaticRef;
+
+/// Backs registers of type `T` with `memory`.
+pub fn fake_registers<T>(memory: &'static mut [u32]) -> (*mut u32, StaticRef<T>) {
+    assert!(core::mem::size_of_val(memory) >= core::mem::size_of::<T>());
+    let memory = memory.as_mut_ptr();
+    let registers = unsafe { StaticRef::new(memory as *const T) };
+    (memory, registers)
+}
+
+/// Reads the register at byte `offset`.
+pub fn word(memory: *mut u32, offset: usize) -> u32 {
+    unsafe { core::ptr::read_volatile(memory.add(offset / 4)) }
+}
+
+/// Writes the register at byte `offset`.
+pub fn set_word(memory: *mut u32, offset: usize, value: u32) {
+    unsafe { core::ptr::write_volatile(memory.add(offset / 4), value) }
+}
@@ -284,7 +284,14 @@ impl<'a> FlashCtrl<'a> {
 
         self.disable_interrupts();
 
-        if irqs.is_set(INTR::OP_ERROR) {
+        // The controller can flag a failed operation either through the
+        // error interrupt or through the error bit of the operation status,
+        // e.g. when programming a write-protected page. Either way the
+        // operation must not be reported as complete.
+        if irqs.is_set(INTR::OP_ERROR) || self.registers.op_status.is_set(OP_STATUS::ERR) {
+            // Clear the status so the next operation starts from a clean state
+            self.registers.op_status.set(0);
+
             let read_buf = self.read_buf.take();
             if let Some(buf) = read_buf {
                 // We were doing a read
@@ -300,6 +307,19 @@ impl<'a> FlashCtrl<'a> {
                     client.write_complete(buf, hil::flash::Error::FlashError);
                 });
             }
+
+            if self.registers.control.matches_all(CONTROL::OP::ERASE) {
+                // Disable erase
+                self.registers
+                    .mp_bank_cfg
+                    .modify(MP_BANK_CFG::ERASE_EN_0::CLEAR + MP_BANK_CFG::ERASE_EN_1::CLEAR);
+
+                self.flash_client.map(move |client| {
+                    client.erase_complete(hil::flash::Error::FlashError);
+                });
+            }
+
+            return;
         }
 
         if irqs.is_set(INTR::RD_LVL) {
@@ -1,6 +1,7 @@
 //! General Purpose Input/Output driver.
 
 use crate::padctrl;
+use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{
     register_bitfields, register_structs, Field, ReadOnly, ReadWrite, WriteOnly,
@@ -70,11 +71,29 @@ register_bitfields![u32,
     ]
 ];
 
+/// Millisecond time source used to debounce pin interrupts.
+///
+/// This is separate from `hil::time::Time`, which can't be used as a trait
+/// object.
+pub trait DebounceClock {
+    /// Returns the current time in milliseconds.
+    fn now_ms(&self) -> u64;
+}
+
+/// Timestamps the edges of a pin, see `GpioPin::set_capture`.
+pub trait EdgeCapture {
+    /// Latches the current time. Called when an edge is detected.
+    fn capture(&self);
+}
+
 pub struct GpioPin<'a> {
     gpio_registers: StaticRef<GpioRegisters>,
     padctrl_registers: StaticRef<padctrl::PadCtrlRegisters>,
     pin: Field<u32, pins::Register>,
     client: OptionalCell<&'a dyn gpio::Client>,
+    debounce: OptionalCell<(&'a dyn DebounceClock, u32)>,
+    last_fired_ms: Cell<Option<u64>>,
+    capture: OptionalCell<&'a dyn EdgeCapture>,
 }
 
 impl<'a> GpioPin<'a> {
@@ -88,9 +107,54 @@ impl<'a> GpioPin<'a> {
             padctrl_registers: padctrl_base,
             pin: pin,
             client: OptionalCell::empty(),
+            debounce: OptionalCell::empty(),
+            last_fired_ms: Cell::new(None),
+            capture: OptionalCell::empty(),
         }
     }
 
+    /// Debounces the interrupts of this pin.
+    ///
+    /// After an interrupt is delivered to the client, further edges are
+    /// suppressed until `window_ms` milliseconds have passed.
+    pub fn set_debounce(&self, clock: &'a dyn DebounceClock, window_ms: u32) {
+        self.debounce.set((clock, window_ms));
+        self.last_fired_ms.set(None);
+    }
+
+    /// Disables debouncing, interrupts are delivered immediately again.
+    pub fn clear_debounce(&self) {
+        self.debounce.clear();
+        self.last_fired_ms.set(None);
+    }
+
+    /// Captures the time of the edges of this pin.
+    ///
+    /// The capture happens before the client is called, for edges that are
+    /// not suppressed by debouncing. Interrupts must be enabled on the pin.
+    pub fn set_capture(&self, capture: &'a dyn EdgeCapture) {
+        self.capture.set(capture);
+    }
+
+    pub fn clear_capture(&self) {
+        self.capture.clear();
+    }
+
+    /// Returns whether an interrupt at this time falls in the debounce window
+    /// of the previous one. Otherwise, the time is recorded.
+    fn is_bounce(&self) -> bool {
+        self.debounce.map_or(false, |&mut (clock, window_ms)| {
+            let now_ms = clock.now_ms();
+            if let Some(last_ms) = self.last_fired_ms.get() {
+                if now_ms.wrapping_sub(last_ms) < window_ms as u64 {
+                    return true;
+                }
+            }
+            self.last_fired_ms.set(Some(now_ms));
+            false
+        })
+    }
+
     #[inline(always)]
     fn half_set(
         val: bool,
@@ -115,6 +179,10 @@ impl<'a> GpioPin<'a> {
 
         if self.gpio_registers.intr_state.is_set(pin) {
             self.gpio_registers.intr_state.modify(pin.val(1));
+            if self.is_bounce() {
+                return;
+            }
+            self.capture.map(|capture| capture.capture());
             self.client.map(|client| {
                 client.fired();
             });
@@ -64,6 +64,13 @@ register_bitfields![u32,
     ]
 ];
 
+/// Driver for the lowRISC HMAC engine.
+///
+/// The engine cannot be shared between in-flight digests: this revision of
+/// the IP exposes the intermediate `digest` and `msg_length` registers as
+/// read-only, so a partially hashed message cannot be saved and restored.
+/// Users must finish (or clear) one digest before starting the next, which
+/// is what `VirtualMuxHmac` enforces by returning `EBUSY` to other clients.
 pub struct Hmac<'a> {
     registers: StaticRef<HmacRegisters>,
 
@@ -98,6 +105,8 @@ impl Hmac<'_> {
         if idx < len {
             let data_len = len - idx;
 
+            // Fill the FIFO in a burst until it is full. The CPU is then
+            // released until the FIFO empty interrupt resumes the transfer.
             for i in 0..(data_len / 4) {
                 if regs.status.is_set(STATUS::FIFO_FULL) {
                     self.data.set(Some(LeasableBuffer::new(slice)));
@@ -106,13 +115,6 @@ impl Hmac<'_> {
                     return;
                 }
 
-                if !regs.status.is_set(STATUS::FIFO_EMPTY) {
-                    self.data.set(Some(LeasableBuffer::new(slice)));
-                    // Enable interrupts
-                    regs.intr_enable.modify(INTR_ENABLE::FIFO_EMPTY::SET);
-                    return;
-                }
-
                 let data_idx = idx + i * 4;
 
                 let mut d = (slice[data_idx + 0] as u32) << 0;
@@ -127,6 +129,13 @@ impl Hmac<'_> {
             let idx = self.data_index.get();
 
             for i in 0..(data_len % 4) {
+                if regs.status.is_set(STATUS::FIFO_FULL) {
+                    self.data.set(Some(LeasableBuffer::new(slice)));
+                    // Enable interrupts
+                    regs.intr_enable.modify(INTR_ENABLE::FIFO_EMPTY::SET);
+                    return;
+                }
+
                 let data_idx = idx + i;
                 let d = (slice[data_idx]) as u32;
 
@@ -124,6 +124,12 @@ register_bitfields![u32,
     ]
 ];
 
+/// Number of clock pulses needed to finish any byte a slave may be stuck in.
+const RECOVERY_PULSES: usize = 9;
+
+/// Half the clock period of a standard mode (100 kHz) bus.
+const RECOVERY_HALF_PERIOD_NANOS: u32 = 5000;
+
 pub struct I2c<'a> {
     registers: StaticRef<I2cRegisters>,
     clock_period_nanos: u32,
@@ -175,6 +181,18 @@ impl<'a> I2c<'_> {
                 + INTR::SDA_UNSTABLE::SET,
         );
 
+        if irqs.is_set(INTR::STRETCH_TIMEOUT) {
+            // A slave held the clock for too long
+            self.abort(hil::i2c::Error::BusTimeout);
+            return;
+        }
+
+        if irqs.is_set(INTR::SDA_INTERFERENCE) {
+            // A slave held the data line low while the host released it
+            self.abort(hil::i2c::Error::BusTimeout);
+            return;
+        }
+
         if irqs.is_set(INTR::FMT_WATERMARK) {
             // FMT Watermark
             if self.slave_read_address.get() != 0 {
@@ -190,6 +208,105 @@ impl<'a> I2c<'_> {
         }
     }
 
+    /// Sets how long a slave may stretch the clock, in clock cycles.
+    ///
+    /// When a slave holds the clock low for longer, the transfer is aborted, the bus is recovered
+    /// and the client is called with `Error::BusTimeout`. A value of 0 disables the timeout.
+    pub fn set_stretch_timeout(&self, cycles: u32) {
+        let regs = self.registers;
+
+        if cycles == 0 {
+            regs.timeout_ctrl.write(TIMEOUT_CTRL::EN::CLEAR);
+        } else {
+            regs.timeout_ctrl
+                .write(TIMEOUT_CTRL::VAL.val(cycles) + TIMEOUT_CTRL::EN::SET);
+        }
+    }
+
+    /// Tries to free a bus whose data line is held low by a slave.
+    ///
+    /// The lines are driven through the override register: up to 9 clock pulses are sent, so a
+    /// slave stuck in the middle of a byte can finish it, followed by a STOP condition. Returns
+    /// whether the data line was released.
+    pub fn recover_bus(&self) -> bool {
+        let regs = self.registers;
+
+        regs.ovrd
+            .write(OVRD::TXOVRDEN::SET + OVRD::SCLVAL::SET + OVRD::SDAVAL::SET);
+        self.recovery_delay();
+
+        let mut pulses = 0;
+        while !regs.val.is_set(VAL::SDA_RX) && pulses < RECOVERY_PULSES {
+            regs.ovrd.modify(OVRD::SCLVAL::CLEAR);
+            self.recovery_delay();
+            regs.ovrd.modify(OVRD::SCLVAL::SET);
+            self.recovery_delay();
+            pulses += 1;
+        }
+        let released = regs.val.is_set(VAL::SDA_RX);
+
+        // STOP condition: SDA rises while SCL is high
+        regs.ovrd.modify(OVRD::SCLVAL::CLEAR);
+        self.recovery_delay();
+        regs.ovrd.modify(OVRD::SDAVAL::CLEAR);
+        self.recovery_delay();
+        regs.ovrd.modify(OVRD::SCLVAL::SET);
+        self.recovery_delay();
+        regs.ovrd.modify(OVRD::SDAVAL::SET);
+        self.recovery_delay();
+
+        regs.ovrd.write(OVRD::TXOVRDEN::CLEAR);
+        released
+    }
+
+    /// Returns whether a slave still holds the data line low after trying to recover the bus.
+    ///
+    /// The host can't send a START on such a bus and would wait forever, so the transfer must not
+    /// be started. Recovery takes at most 9 clock pulses, which bounds the wait.
+    fn bus_stuck(&self) -> bool {
+        let regs = self.registers;
+
+        if regs.val.is_set(VAL::SDA_RX) {
+            return false;
+        }
+        regs.ctrl.modify(CTRL::ENABLEHOST::CLEAR);
+        let released = self.recover_bus();
+        regs.ctrl.modify(CTRL::ENABLEHOST::SET);
+        !released
+    }
+
+    /// Returns the buffer of a transfer that couldn't start to the client.
+    fn reject(&self, buffer: &'static mut [u8]) {
+        self.master_client.map(move |client| {
+            client.command_complete(buffer, hil::i2c::Error::BusTimeout);
+        });
+    }
+
+    fn recovery_delay(&self) {
+        let regs = self.registers;
+
+        // Reading the lines doesn't have side effects, but can't be optimized away
+        for _ in 0..RECOVERY_HALF_PERIOD_NANOS / self.clock_period_nanos.max(1) {
+            regs.val.get();
+        }
+    }
+
+    /// Aborts the current transfer and reports the error to the client.
+    fn abort(&self, error: hil::i2c::Error) {
+        let regs = self.registers;
+
+        regs.ctrl.modify(CTRL::ENABLEHOST::CLEAR);
+        self.fifo_reset();
+        self.recover_bus();
+        regs.ctrl.modify(CTRL::ENABLEHOST::SET);
+
+        self.buffer.take().map(|buffer| {
+            self.master_client.map(move |client| {
+                client.command_complete(buffer, error);
+            });
+        });
+    }
+
     fn timing_parameter_init(&self, clock_period_nanos: u32) {
         let regs = self.registers;
 
@@ -397,6 +514,11 @@ impl<'a> hil::i2c::I2CMaster for I2c<'a> {
     fn write_read(&self, addr: u8, data: &'static mut [u8], write_len: u8, read_len: u8) {
         let regs = self.registers;
 
+        if self.bus_stuck() {
+            self.reject(data);
+            return;
+        }
+
         // Set the FIFO depth and reset the FIFO
         if write_len > 8 {
             regs.fifo_ctrl.modify(FIFO_CTRL::FMTILVL::FMTLVL8);
@@ -437,6 +559,11 @@ impl<'a> hil::i2c::I2CMaster for I2c<'a> {
     fn write(&self, addr: u8, data: &'static mut [u8], len: u8) {
         let regs = self.registers;
 
+        if self.bus_stuck() {
+            self.reject(data);
+            return;
+        }
+
         // Set the FIFO depth and reset the FIFO
         if len > 8 {
             regs.fifo_ctrl.modify(FIFO_CTRL::FMTILVL::FMTLVL8);
@@ -467,6 +594,11 @@ impl<'a> hil::i2c::I2CMaster for I2c<'a> {
     fn read(&self, addr: u8, buffer: &'static mut [u8], len: u8) {
         let regs = self.registers;
 
+        if self.bus_stuck() {
+            self.reject(buffer);
+            return;
+        }
+
         // Set the FIFO depth and reset the FIFO
         if len > 8 {
             regs.fifo_ctrl.modify(FIFO_CTRL::RXILVL::RXLVL8);
@@ -494,3 +626,110 @@ impl<'a> hil::i2c::I2CMaster for I2c<'a> {
         self.read_data();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::fake_registers::{fake_registers, set_word, word};
+    use kernel::hil::i2c::I2CMaster;
+    use std::boxed::Box;
+
+    struct Client {
+        error: Cell<Option<hil::i2c::Error>>,
+    }
+
+    impl hil::i2c::I2CHwMasterClient for Client {
+        fn command_complete(&self, _buffer: &'static mut [u8], error: hil::i2c::Error) {
+            self.error.set(Some(error));
+        }
+    }
+
+    #[test]
+    fn stretch_timeout_recovers_stuck_bus() {
+        let (memory, registers) = fake_registers(Box::leak(Box::new([0u32; 0x44 / 4])));
+        let i2c = I2c::new(registers, 10);
+        let client = Box::leak(Box::new(Client {
+            error: Cell::new(None),
+        }));
+        i2c.set_master_client(client);
+        i2c.enable();
+        i2c.set_stretch_timeout(1000);
+        assert_eq!(word(memory, 0x40), (1 << 31) | 1000);
+
+        // The bus is free when the transfer starts
+        set_word(memory, 0x28, 1 << 1);
+        set_word(memory, 0x10, 1 << 5);
+        i2c.read(0x50, Box::leak(Box::new([0; 4])), 4);
+        assert_eq!(client.error.get(), None);
+
+        // The slave never answers and holds SDA low
+        set_word(memory, 0x28, 0);
+
+        set_word(memory, 0x00, 1 << 7);
+        i2c.handle_interrupt();
+        assert_eq!(client.error.get(), Some(hil::i2c::Error::BusTimeout));
+
+        // The FIFOs were reset, the override released and the host enabled again
+        assert_eq!(word(memory, 0x1C) & 0b11, 0b11);
+        assert_eq!(word(memory, 0x24), 0);
+        assert_eq!(word(memory, 0x0C), 1);
+        assert!(!i2c.recover_bus());
+    }
+
+    #[test]
+    fn sda_held_low_during_transfer_aborts() {
+        let (memory, registers) = fake_registers(Box::leak(Box::new([0u32; 0x44 / 4])));
+        let i2c = I2c::new(registers, 10);
+        let client = Box::leak(Box::new(Client {
+            error: Cell::new(None),
+        }));
+        i2c.set_master_client(client);
+        i2c.enable();
+
+        set_word(memory, 0x28, 1 << 1);
+        set_word(memory, 0x10, 1 << 5);
+        i2c.read(0x50, Box::leak(Box::new([0; 4])), 4);
+        assert_eq!(client.error.get(), None);
+
+        // The host releases SDA for the next bit, but the slave keeps it low
+        set_word(memory, 0x28, 0);
+        set_word(memory, 0x00, 1 << 6);
+        i2c.handle_interrupt();
+        assert_eq!(client.error.get(), Some(hil::i2c::Error::BusTimeout));
+        assert_eq!(word(memory, 0x24), 0);
+        assert_eq!(word(memory, 0x0C), 1);
+    }
+
+    #[test]
+    fn sda_held_low_before_transfer_is_rejected() {
+        let (memory, registers) = fake_registers(Box::leak(Box::new([0u32; 0x44 / 4])));
+        let i2c = I2c::new(registers, 10);
+        let client = Box::leak(Box::new(Client {
+            error: Cell::new(None),
+        }));
+        i2c.set_master_client(client);
+        i2c.enable();
+
+        // The slave holds SDA low and recovery doesn't release it
+        set_word(memory, 0x28, 0);
+        i2c.write(0x50, Box::leak(Box::new([0; 4])), 4);
+        assert_eq!(client.error.get(), Some(hil::i2c::Error::BusTimeout));
+
+        // No START was queued and the host is enabled again
+        assert_eq!(word(memory, 0x18), 0);
+        assert_eq!(word(memory, 0x24), 0);
+        assert_eq!(word(memory, 0x0C), 1);
+    }
+
+    #[test]
+    fn recover_free_bus() {
+        let (memory, registers) = fake_registers(Box::leak(Box::new([0u32; 0x44 / 4])));
+        let i2c = I2c::new(registers, 10);
+
+        set_word(memory, 0x28, 1 << 1);
+        assert!(i2c.recover_bus());
+        assert_eq!(word(memory, 0x24), 0);
+    }
+}
@@ -7,6 +7,7 @@
 #![crate_name = "lowrisc"]
 #![crate_type = "rlib"]
 
+pub mod aon_timer;
 pub mod flash_ctrl;
 pub mod gpio;
 pub mod hmac;
@@ -15,3 +16,6 @@ pub mod padctrl;
 pub mod pwrmgr;
 pub mod uart;
 pub mod usbdev;
+
+#[cfg(any(test, feature = "fake_registers"))]
+pub mod fake_registers;
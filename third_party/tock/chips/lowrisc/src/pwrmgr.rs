@@ -1,5 +1,6 @@
 //! Power Mangement for LowRISC
 
+use core::cell::Cell;
 use kernel::common::registers::{register_bitfields, register_structs, ReadOnly, ReadWrite};
 use kernel::common::StaticRef;
 
@@ -63,11 +64,15 @@ register_bitfields![u32,
 
 pub struct PwrMgr {
     registers: StaticRef<PwrMgrRegisters>,
+    wakeup_sources: Cell<u32>,
 }
 
 impl PwrMgr {
     pub const fn new(base: StaticRef<PwrMgrRegisters>) -> PwrMgr {
-        PwrMgr { registers: base }
+        PwrMgr {
+            registers: base,
+            wakeup_sources: Cell::new(0),
+        }
     }
 
     pub fn check_clock_propagation(&self) -> bool {
@@ -80,31 +85,158 @@ impl PwrMgr {
         false
     }
 
-    pub fn handle_interrupt(&self) {
+    /// Selects which wakeup requests bring the chip out of low power.
+    ///
+    /// `sources` is a bitmask of the chip specific wakeup request indices.
+    /// Returns false if the wakeup configuration is locked.
+    pub fn set_wakeup_sources(&self, sources: u32) -> bool {
+        let regs = self.registers;
+
+        if regs.wakeup_en_regwen.read(WAKEUP_EN_REGWEN::EN) == 0 {
+            return false;
+        }
+        regs.wakeup_en.write(WAKEUP_EN::START.val(sources));
+        self.wakeup_sources.set(sources);
+
+        // Propagate changes to slow clock domain
+        regs.cfg_cdc_sync.write(CFG_CDC_SYNC::SYNC::SET);
+        true
+    }
+
+    pub fn wakeup_sources(&self) -> u32 {
+        self.wakeup_sources.get()
+    }
+
+    /// Selects which reset requests of peripherals reset the chip.
+    ///
+    /// `requests` is a bitmask of the chip specific reset request indices.
+    /// Returns false if the reset configuration is locked.
+    pub fn set_reset_requests(&self, requests: u32) -> bool {
         let regs = self.registers;
 
-        // Disable power saving
-        regs.control.write(CONTROL::LOW_POWER_HINT::CLEAR);
+        if regs.reset_en_regwen.read(RESET_EN_REGWEN::EN) == 0 {
+            return false;
+        }
+        regs.reset_en.write(RESET_EN::EN.val(requests));
 
         // Propagate changes to slow clock domain
         regs.cfg_cdc_sync.write(CFG_CDC_SYNC::SYNC::SET);
+        true
+    }
+
+    /// Returns the wakeup requests that caused the last low power exit.
+    pub fn wake_reasons(&self) -> u32 {
+        self.registers.wake_info.read(WAKE_INFO::REASONS)
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = self.registers;
+
+        // Clear the wakeup reasons, they are write-1-to-clear
+        regs.wake_info.set(0xFFFF_FFFF);
+
+        self.disable_low_power();
     }
 
-    pub fn enable_low_power(&self) {
+    /// Prepares light sleep, which is entered on the next WFI.
+    ///
+    /// Only the core clock is gated. The main power domain and the IO clock
+    /// stay on, so peripherals keep their state and can still raise
+    /// interrupts. The core is woken up by the enabled wakeup sources. If an
+    /// interrupt is already pending on WFI, the low power entry is aborted.
+    pub fn enable_light_sleep(&self) {
         let regs = self.registers;
 
         if regs.control.read(CONTROL::LOW_POWER_HINT) != 1 {
-            // Next WFI should trigger low power entry
-            // Leave the IO clock enabled as we need to get interrupts
             regs.control.write(
                 CONTROL::LOW_POWER_HINT::SET
                     + CONTROL::CORE_CLK_EN::CLEAR
                     + CONTROL::IO_CLK_EN::SET
-                    + CONTROL::MAIN_PD_N::CLEAR,
+                    + CONTROL::MAIN_PD_N::SET,
             );
 
             // Propagate changes to slow clock domain
             regs.cfg_cdc_sync.write(CFG_CDC_SYNC::SYNC::SET);
         }
     }
+
+    /// Restores the active power configuration after a wakeup or an aborted
+    /// low power entry.
+    pub fn disable_low_power(&self) {
+        let regs = self.registers;
+
+        if !regs.control.matches_all(
+            CONTROL::LOW_POWER_HINT::CLEAR
+                + CONTROL::CORE_CLK_EN::SET
+                + CONTROL::IO_CLK_EN::SET
+                + CONTROL::MAIN_PD_N::SET,
+        ) {
+            regs.control.write(
+                CONTROL::LOW_POWER_HINT::CLEAR
+                    + CONTROL::CORE_CLK_EN::SET
+                    + CONTROL::IO_CLK_EN::SET
+                    + CONTROL::MAIN_PD_N::SET,
+            );
+
+            // Propagate changes to slow clock domain
+            regs.cfg_cdc_sync.write(CFG_CDC_SYNC::SYNC::SET);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::fake_registers::{fake_registers, set_word, word};
+    use std::boxed::Box;
+
+    #[test]
+    fn light_sleep_keeps_main_power() {
+        let (memory, registers) = fake_registers(Box::leak(Box::new([0u32; 0x2C / 4])));
+        let pwrmgr = PwrMgr::new(registers);
+
+        pwrmgr.enable_light_sleep();
+        // Low power hint, IO clock and main power domain on, core clock off
+        assert_eq!(word(memory, 0x04), 0b110_0001);
+        assert_eq!(word(memory, 0x08), 1);
+        assert!(!pwrmgr.check_clock_propagation());
+
+        set_word(memory, 0x08, 0);
+        set_word(memory, 0x28, 1 << 3);
+        assert_eq!(pwrmgr.wake_reasons(), 1 << 3);
+        pwrmgr.handle_interrupt();
+        assert_eq!(word(memory, 0x04), 0b111_0000);
+        assert_eq!(word(memory, 0x08), 1);
+    }
+
+    #[test]
+    fn wakeup_sources() {
+        let (memory, registers) = fake_registers(Box::leak(Box::new([0u32; 0x2C / 4])));
+        let pwrmgr = PwrMgr::new(registers);
+
+        assert!(!pwrmgr.set_wakeup_sources(0b11));
+        assert_eq!(word(memory, 0x10), 0);
+
+        set_word(memory, 0x0C, 1);
+        assert!(pwrmgr.set_wakeup_sources(0b11));
+        assert_eq!(word(memory, 0x10), 0b11);
+        assert_eq!(pwrmgr.wakeup_sources(), 0b11);
+        assert_eq!(word(memory, 0x08), 1);
+    }
+
+    #[test]
+    fn reset_requests() {
+        let (memory, registers) = fake_registers(Box::leak(Box::new([0u32; 0x2C / 4])));
+        let pwrmgr = PwrMgr::new(registers);
+
+        assert!(!pwrmgr.set_reset_requests(0b10));
+        assert_eq!(word(memory, 0x1C), 0);
+
+        set_word(memory, 0x18, 1);
+        assert!(pwrmgr.set_reset_requests(0b10));
+        assert_eq!(word(memory, 0x1C), 0b10);
+        assert_eq!(word(memory, 0x08), 1);
+    }
 }
@@ -9,6 +9,7 @@ use kernel::common::registers::{
 };
 use kernel::common::StaticRef;
 use kernel::hil;
+use kernel::hil::gpio;
 use kernel::hil::uart;
 use kernel::ReturnCode;
 
@@ -98,6 +99,11 @@ register_bitfields![u32,
     ]
 ];
 
+/// RX FIFO level (in bytes, out of 32) at which RTS is deasserted when flow
+/// control is enabled. Half the FIFO leaves room for the bytes the sender has
+/// already started transmitting when it notices the deassertion.
+const RTS_RX_LEVEL: u32 = 16;
+
 pub struct Uart<'a> {
     registers: StaticRef<UartRegisters>,
     clock_frequency: u32,
@@ -110,6 +116,12 @@ pub struct Uart<'a> {
 
     rx_buffer: TakeCell<'static, [u8]>,
     rx_len: Cell<usize>,
+    rx_index: Cell<usize>,
+
+    // Optional RTS/CTS flow control. The UART block has no flow control
+    // signals, so they are driven by GPIOs. Both signals are active low.
+    rts: OptionalCell<&'a dyn gpio::Pin>,
+    cts: OptionalCell<&'a dyn gpio::InterruptPin<'a>>,
 }
 
 #[derive(Copy, Clone)]
@@ -129,9 +141,65 @@ impl<'a> Uart<'a> {
             tx_index: Cell::new(0),
             rx_buffer: TakeCell::empty(),
             rx_len: Cell::new(0),
+            rx_index: Cell::new(0),
+            rts: OptionalCell::empty(),
+            cts: OptionalCell::empty(),
+        }
+    }
+
+    /// Enables RTS/CTS flow control using the given GPIOs.
+    ///
+    /// RTS is asserted while a receive buffer is pending and the RX FIFO holds
+    /// less than `RTS_RX_LEVEL` bytes. Transmission pauses while CTS is
+    /// deasserted and resumes on its falling edge, so the caller must also
+    /// register this UART as the client of the CTS pin.
+    ///
+    /// Flow control is disabled unless this function is called.
+    pub fn set_flow_control(&self, rts: &'a dyn gpio::Pin, cts: &'a dyn gpio::InterruptPin<'a>) {
+        rts.make_output();
+        cts.make_input();
+        cts.enable_interrupts(gpio::InterruptEdge::FallingEdge);
+        self.rts.set(rts);
+        self.cts.set(cts);
+        self.update_rts();
+    }
+
+    /// Sets the RX idle timeout in bit-times, or disables it with 0.
+    ///
+    /// When enabled, `receive_buffer` completes once the buffer is full or
+    /// once the line stays idle for that many bit-times after at least one
+    /// byte was received. In the latter case the client gets `ESIZE` with the
+    /// number of bytes received. When disabled, `receive_buffer` completes as
+    /// soon as any byte is received.
+    pub fn set_rx_timeout(&self, bit_times: u32) {
+        if bit_times == 0 {
+            self.registers.timeout_ctrl.write(timeout_ctrl::en::CLEAR);
+        } else {
+            self.registers
+                .timeout_ctrl
+                .write(timeout_ctrl::val.val(bit_times) + timeout_ctrl::en::SET);
         }
     }
 
+    fn is_rx_timeout_enabled(&self) -> bool {
+        self.registers.timeout_ctrl.is_set(timeout_ctrl::en)
+    }
+
+    fn update_rts(&self) {
+        self.rts.map(|rts| {
+            let level = self.registers.fifo_status.read(fifo_status::rxlvl);
+            if self.rx_buffer.is_some() && level < RTS_RX_LEVEL {
+                rts.clear();
+            } else {
+                rts.set();
+            }
+        });
+    }
+
+    fn is_clear_to_send(&self) -> bool {
+        self.cts.map_or(true, |cts| !cts.read())
+    }
+
     fn set_baud_rate(&self, baud_rate: u32) {
         let regs = self.registers;
         let uart_ctrl_nco = ((baud_rate as u64) << 20) / self.clock_frequency as u64;
@@ -161,19 +229,62 @@ impl<'a> Uart<'a> {
     fn enable_rx_interrupt(&self) {
         let regs = self.registers;
 
-        // Generate an interrupt if we get any value in the RX buffer
-        regs.intr_enable.modify(intr::rx_watermark::SET);
-        regs.fifo_ctrl.write(fifo_ctrl::rxilvl.val(0 as u32));
+        if self.is_rx_timeout_enabled() {
+            // Generate an interrupt when the RX buffer is half full, and rely
+            // on the timeout for the remaining bytes. The timeout only fires
+            // if the RX buffer is not empty, so we can't drain it on every
+            // byte.
+            regs.intr_enable
+                .modify(intr::rx_watermark::SET + intr::rx_timeout::SET);
+            regs.fifo_ctrl.write(fifo_ctrl::rxilvl.val(3 as u32));
+        } else {
+            // Generate an interrupt if we get any value in the RX buffer
+            regs.intr_enable.modify(intr::rx_watermark::SET);
+            regs.fifo_ctrl.write(fifo_ctrl::rxilvl.val(0 as u32));
+        }
     }
 
     fn disable_rx_interrupt(&self) {
         let regs = self.registers;
 
-        // Generate an interrupt if we get any value in the RX buffer
-        regs.intr_enable.modify(intr::rx_watermark::CLEAR);
+        regs.intr_enable
+            .modify(intr::rx_watermark::CLEAR + intr::rx_timeout::CLEAR);
 
-        // Clear the interrupt bit (by writing 1), if it happens to be set
-        regs.intr_state.write(intr::rx_watermark::SET);
+        // Clear the interrupt bits (by writing 1), if they happen to be set
+        regs.intr_state
+            .write(intr::rx_watermark::SET + intr::rx_timeout::SET);
+    }
+
+    /// Moves received bytes to the receive buffer and completes the receive
+    /// once the buffer is full, or once the line is idle if `idle` is set.
+    fn rx_progress(&self, idle: bool) {
+        let regs = self.registers;
+        let len = self.rx_len.get();
+        let idx = self.rx_buffer.map_or(0, |rx_buf| {
+            let mut idx = self.rx_index.get();
+            while idx < len && !regs.status.is_set(status::rxempty) {
+                rx_buf[idx] = regs.rdata.get() as u8;
+                idx += 1;
+            }
+            idx
+        });
+        self.rx_index.set(idx);
+
+        let return_code = if idx == len {
+            ReturnCode::SUCCESS
+        } else if idle && idx > 0 {
+            ReturnCode::ESIZE
+        } else {
+            // Nothing to report yet, in particular when the line went idle
+            // without any byte received.
+            self.enable_rx_interrupt();
+            return;
+        };
+        self.rx_client.map(|client| {
+            self.rx_buffer.take().map(|rx_buf| {
+                client.received_buffer(rx_buf, idx, return_code, uart::Error::None);
+            });
+        });
     }
 
     fn tx_progress(&self) {
@@ -194,7 +305,7 @@ impl<'a> Uart<'a> {
                 let tx_len = len - idx;
 
                 for i in 0..tx_len {
-                    if regs.status.is_set(status::txfull) {
+                    if regs.status.is_set(status::txfull) || !self.is_clear_to_send() {
                         break;
                     }
                     let tx_idx = idx + i;
@@ -224,9 +335,15 @@ impl<'a> Uart<'a> {
                 // We have more to transmit, so continue in tx_progress().
                 self.tx_progress();
             }
-        } else if intrs.is_set(intr::rx_watermark) {
+        } else if intrs.is_set(intr::rx_watermark) || intrs.is_set(intr::rx_timeout) {
             self.disable_rx_interrupt();
 
+            if self.is_rx_timeout_enabled() {
+                self.rx_progress(intrs.is_set(intr::rx_timeout));
+                self.update_rts();
+                return;
+            }
+
             self.rx_client.map(|client| {
                 self.rx_buffer.take().map(|rx_buf| {
                     let mut len = 0;
@@ -246,6 +363,7 @@ impl<'a> Uart<'a> {
                     client.received_buffer(rx_buf, len, return_code, uart::Error::None);
                 });
             });
+            self.update_rts();
         }
     }
 
@@ -258,6 +376,15 @@ impl<'a> Uart<'a> {
     }
 }
 
+impl gpio::Client for Uart<'_> {
+    // CTS was asserted, continue any paused transmission.
+    fn fired(&self) {
+        if self.tx_buffer.is_some() {
+            self.tx_progress();
+        }
+    }
+}
+
 impl<'a> hil::uart::UartData<'a> for Uart<'a> {}
 impl<'a> hil::uart::Uart<'a> for Uart<'a> {}
 
@@ -330,6 +457,8 @@ impl<'a> hil::uart::Receive<'a> for Uart<'a> {
 
         self.rx_buffer.replace(rx_buffer);
         self.rx_len.set(rx_len);
+        self.rx_index.set(0);
+        self.update_rts();
 
         (ReturnCode::SUCCESS, None)
     }
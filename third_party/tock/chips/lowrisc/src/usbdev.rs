@@ -358,6 +358,7 @@ pub struct Usb<'a> {
     state: OptionalCell<State>,
     bufs: Cell<[Buffer; N_BUFFERS]>,
     addr: Cell<u16>,
+    suspended: Cell<bool>,
 }
 
 impl<'a> Usb<'a> {
@@ -415,6 +416,7 @@ impl<'a> Usb<'a> {
                 Buffer::new(31),
             ]),
             addr: Cell::new(0),
+            suspended: Cell::new(false),
         }
     }
 
@@ -902,6 +904,21 @@ impl<'a> Usb<'a> {
             }
         }
 
+        if irqs.is_set(INTR::LINK_SUSPEND) && !self.suspended.get() {
+            // The host suspended the bus
+            self.suspended.set(true);
+            self.client.map(|client| client.bus_suspend());
+        }
+
+        if irqs.is_set(INTR::LINK_RESUME) || irqs.is_set(INTR::LINK_RESET) {
+            // Both a resume signal and a reset end the suspended state. The
+            // endpoint configuration is kept by the hardware while suspended.
+            if self.suspended.get() {
+                self.suspended.set(false);
+                self.client.map(|client| client.bus_resume());
+            }
+        }
+
         if irqs.is_set(INTR::LINK_RESET) {
             // The link was reset
 
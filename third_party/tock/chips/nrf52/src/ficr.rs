@@ -373,6 +373,26 @@ impl Ficr {
         }
     }
 
+    /// Return the 64 bit identifier that is unique to this chip.
+    pub fn device_id(&self) -> u64 {
+        let lo = self.registers.deviceid0.read(DeviceId0::DEVICEID);
+        let hi = self.registers.deviceid1.read(DeviceId1::DEVICEID);
+        ((hi as u64) << 32) | lo as u64
+    }
+
+    /// Return the unique identifier of this chip as 16 uppercase hexadecimal
+    /// digits, most significant first, e.g. `0123456789ABCDEF`.
+    pub fn device_id_str(&self, buf: &'static mut [u8; 16]) -> &'static str {
+        let id = self.device_id();
+        for (i, digit) in buf.iter_mut().enumerate() {
+            let nibble = (id >> (60 - 4 * i)) & 0xf;
+            *digit = b"0123456789ABCDEF"[nibble as usize];
+        }
+
+        // Safe because we use only ascii characters in this buffer.
+        unsafe { &*(buf as *const [u8] as *const str) }
+    }
+
     pub fn address(&self) -> [u8; 6] {
         let lo = self
             .registers
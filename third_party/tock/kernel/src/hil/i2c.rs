@@ -25,6 +25,10 @@ pub enum Error {
     /// The requested operation wasn't supported.
     NotSupported,
 
+    /// The bus was held low by a slave for too long, for example because it
+    /// stretched the clock or got stuck in the middle of a transfer.
+    BusTimeout,
+
     /// No error occured and the command completed successfully.
     CommandComplete,
 }
@@ -37,6 +41,7 @@ impl Display for Error {
             Error::ArbitrationLost => "I2C Bus Arbitration Lost",
             Error::Overrun => "I2C receive overrun",
             Error::NotSupported => "I2C/SMBus command not supported",
+            Error::BusTimeout => "I2C bus timeout",
             Error::CommandComplete => "I2C Command Completed",
         };
         write!(fmt, "{}", display_str)
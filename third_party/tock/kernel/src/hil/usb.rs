@@ -53,6 +53,12 @@ pub trait Client<'a> {
     fn attach(&'a self);
     fn bus_reset(&'a self);
 
+    /// Called when the host suspends the bus. Does nothing by default.
+    fn bus_suspend(&'a self) {}
+    /// Called when the bus leaves the suspended state, either through a
+    /// resume signal or a bus reset. Does nothing by default.
+    fn bus_resume(&'a self) {}
+
     fn ctrl_setup(&'a self, endpoint: usize) -> CtrlSetupResult;
     fn ctrl_in(&'a self, endpoint: usize) -> CtrlInResult;
     fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> CtrlOutResult;
@@ -0,0 +1,24 @@
+//! Interface for a watchdog resetting the chip when software hangs.
+//!
+//! Unlike `platform::watchdog::WatchDog`, which the kernel loop tickles, this
+//! interface lets a capsule hand the watchdog to an application, so that the
+//! chip also resets when the application stops making progress.
+
+use crate::returncode::ReturnCode;
+
+pub trait Watchdog {
+    /// Starts the watchdog, or restarts it with a new timeout.
+    ///
+    /// The chip resets if `kick` isn't called for `timeout_ms` milliseconds.
+    /// Returns `EINVAL` if the timeout is not supported, or `FAIL` if the
+    /// watchdog configuration is locked.
+    fn enable(&self, timeout_ms: usize) -> ReturnCode;
+
+    /// Restarts the countdown.
+    fn kick(&self);
+
+    /// Stops the watchdog.
+    ///
+    /// Returns `FAIL` if the watchdog configuration is locked.
+    fn disable(&self) -> ReturnCode;
+}
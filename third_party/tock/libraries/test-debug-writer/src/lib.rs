@@ -0,0 +1,65 @@
+//! Debug writer for host tests.
+//!
+//! Capsules forbid unsafe code, so they can't install a debug writer in their
+//! tests like a board does. This crate installs one whose output is dropped,
+//! such that the code under test can call `debug!`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let _debug = test_debug_writer::lock_debug_writer();
+//! // The code under test may call `debug!` while the guard is held.
+//! ```
+
+use kernel::common::RingBuffer;
+use kernel::debug::{DebugWriter, DebugWriterWrapper};
+use kernel::hil::uart::{Transmit, TransmitClient};
+use kernel::ReturnCode;
+use std::sync::{Mutex, MutexGuard, Once};
+
+/// UART dropping the debug output.
+struct FakeUart;
+
+impl<'a> Transmit<'a> for FakeUart {
+    fn set_transmit_client(&self, _client: &'a dyn TransmitClient) {}
+    fn transmit_buffer(
+        &self,
+        _tx_buffer: &'static mut [u8],
+        _tx_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        (ReturnCode::SUCCESS, None)
+    }
+    fn transmit_word(&self, _word: u32) -> ReturnCode {
+        ReturnCode::FAIL
+    }
+    fn transmit_abort(&self) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+}
+
+/// Installs the debug writer on first use, and locks it.
+///
+/// The writer is not thread-safe, so tests hold the returned guard while they
+/// run.
+pub fn lock_debug_writer() -> MutexGuard<'static, ()> {
+    static INSTALL: Once = Once::new();
+    static mut LOCK: Option<&'static Mutex<()>> = None;
+    INSTALL.call_once(|| {
+        let ring_buffer = Box::leak(Box::new(RingBuffer::new(Box::leak(Box::new([0; 64])))));
+        let debug_writer = Box::leak(Box::new(DebugWriter::new(
+            Box::leak(Box::new(FakeUart)),
+            Box::leak(Box::new([0; 64])),
+            ring_buffer,
+        )));
+        unsafe {
+            kernel::debug::set_debug_writer_wrapper(Box::leak(Box::new(DebugWriterWrapper::new(
+                debug_writer,
+            ))));
+            LOCK = Some(Box::leak(Box::new(Mutex::new(()))));
+        }
+    });
+    // LOCK is only written once, before any read.
+    let lock = unsafe { LOCK }.unwrap();
+    lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
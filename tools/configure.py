@@ -87,6 +87,8 @@ def main(args):
   # We need either both the certificate and the key or none
   if bool(args.priv_key) ^ bool(args.certificate):
     fatal("Certificate and private key must be set together or both omitted.")
+  if args.intermediate_certificates and not args.certificate:
+    fatal("Intermediate certificates need a certificate.")
 
   cbor_data = {1: args.lock}
 
@@ -126,6 +128,12 @@ def main(args):
             priv_key.private_numbers().private_value.to_bytes(
                 length=32, byteorder="big", signed=False)
     }
+    if args.intermediate_certificates:
+      cbor_data[2][3] = [
+          x509.load_pem_x509_certificate(intermediate.read()).public_bytes(
+              serialization.Encoding.DER)
+          for intermediate in args.intermediate_certificates
+      ]
 
   patcher = None
   if args.use_vendor_hid:
@@ -194,6 +202,17 @@ if __name__ == "__main__":
       help=("PEM file containing the certificate to inject into "
             "the OpenSK authenticator."),
   )
+  parser.add_argument(
+      "--intermediate-certificate",
+      type=argparse.FileType("rb"),
+      action="append",
+      default=[],
+      metavar="PEM_FILE",
+      dest="intermediate_certificates",
+      help=("PEM file containing an intermediate certificate of the "
+            "attestation chain. Repeat it for each certificate, starting with "
+            "the issuer of the certificate."),
+  )
   parser.add_argument(
       "--private-key",
       type=argparse.FileType("rb"),
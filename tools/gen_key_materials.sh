@@ -33,6 +33,10 @@ generate_crypto_materials () {
   local opensk_upgrade=crypto_data/opensk_upgrade.key
   local opensk_upgrade_pub=crypto_data/opensk_upgrade_pub.pem
 
+  # The secret of the store encryption keys, embedded into the firmware. It is
+  # never regenerated, since devices could not read their store anymore.
+  local opensk_store_secret=crypto_data/opensk_store_secret.bin
+
   # Allow invoker to override the command with a full path.
   local openssl=${OPENSSL:-$(which openssl)}
 
@@ -111,6 +115,11 @@ generate_crypto_materials () {
   then
     uuidgen > "${aaguid_file}"
   fi
+
+  if [ ! -f "${opensk_store_secret}" ]
+  then
+    "${openssl}" rand -out "${opensk_store_secret}" 32
+  fi
 }
 
 generate_crypto_materials "$1"